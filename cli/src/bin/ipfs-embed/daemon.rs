@@ -0,0 +1,326 @@
+//! The long running node and its control socket. Commands are exchanged as
+//! one json object per line over a tcp connection to localhost, the address
+//! of the socket is written to the `api` file of the repository.
+use anyhow::{anyhow, Result};
+use async_std::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    prelude::*,
+    stream::Stream,
+};
+use futures::future;
+use ipfs_embed::{
+    multiaddr::Protocol, Cid, DefaultParams, Error, GossipEvent, Ipfs, Key, Multiaddr, PeerId,
+    Quorum, Record,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    Id,
+    Peers,
+    Connect {
+        addr: String,
+    },
+    Pin {
+        cid: String,
+    },
+    Publish {
+        topic: String,
+        data: String,
+    },
+    /// Answered with `null` once subscribed, followed by the messages.
+    Subscribe {
+        topic: String,
+    },
+    DhtGet {
+        key: String,
+    },
+    DhtPut {
+        key: String,
+        value: String,
+    },
+    DhtProviders {
+        cid: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Response {
+    Ok(Value),
+    NotFound(String),
+    Error(String),
+}
+
+impl From<Result<Value>> for Response {
+    fn from(result: Result<Value>) -> Self {
+        match result {
+            Ok(value) => Self::Ok(value),
+            Err(err) if err.is::<NotFound>() => Self::NotFound(format!("{:#}", err)),
+            Err(err) => Self::Error(format!("{:#}", err)),
+        }
+    }
+}
+
+impl From<Response> for Result<Value> {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::Ok(value) => Ok(value),
+            Response::NotFound(msg) => Err(NotFound(msg).into()),
+            Response::Error(msg) => Err(anyhow!("{}", msg)),
+        }
+    }
+}
+
+/// The requested record or providers weren't found in the dht.
+#[derive(Debug)]
+pub struct NotFound(String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// The file containing the address of the control socket.
+fn api_file(repo: &Path) -> PathBuf {
+    repo.join("api")
+}
+
+/// Splits the trailing `/p2p` protocol off an address.
+pub fn split_peer_id(addr: &Multiaddr) -> Result<(PeerId, Multiaddr)> {
+    let mut prefix = addr.clone();
+    match prefix.pop() {
+        Some(Protocol::P2p(hash)) => {
+            let peer =
+                PeerId::from_multihash(hash).map_err(|_| anyhow!("invalid peer id in {}", addr))?;
+            Ok((peer, prefix))
+        }
+        _ => Err(anyhow!("address {} doesn't end with /p2p", addr)),
+    }
+}
+
+/// Serves the control socket until SIGINT or SIGTERM is received, then
+/// shuts the node down, which flushes the store.
+pub async fn run(repo: &Path, ipfs: Ipfs<DefaultParams>) -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    std::fs::write(api_file(repo), listener.local_addr()?.to_string())?;
+    println!("peer id {}", ipfs.local_peer_id());
+    for addr in ipfs.listeners() {
+        println!("listening on {}", addr);
+    }
+    println!("api listening on {}", listener.local_addr()?);
+
+    let serve = async {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let ipfs = ipfs.clone();
+            match stream {
+                Ok(stream) => {
+                    async_std::task::spawn(async move {
+                        if let Err(err) = serve_connection(ipfs, stream).await {
+                            tracing::warn!("control connection failed: {:#}", err);
+                        }
+                    });
+                }
+                Err(err) => tracing::warn!("failed to accept control connection: {}", err),
+            }
+        }
+    };
+    {
+        futures::pin_mut!(serve);
+        future::select(serve, interrupted()?).await;
+    }
+    std::fs::remove_file(api_file(repo)).ok();
+    ipfs.shutdown().await?;
+    Ok(())
+}
+
+/// Resolves once SIGINT or SIGTERM was received.
+fn interrupted() -> Result<impl Future<Output = ()> + Unpin> {
+    let flag = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, flag.clone())?;
+    signal_hook::flag::register(signal_hook::consts::SIGTERM, flag.clone())?;
+    Ok(Box::pin(async move {
+        while !flag.load(Ordering::Relaxed) {
+            async_std::task::sleep(Duration::from_millis(100)).await;
+        }
+    }))
+}
+
+async fn serve_connection(ipfs: Ipfs<DefaultParams>, mut stream: TcpStream) -> Result<()> {
+    let mut lines = BufReader::new(stream.clone()).lines();
+    while let Some(line) = lines.next().await {
+        let response = match serde_json::from_str(&line?) {
+            // the connection is used for the messages until it is closed
+            Ok(Request::Subscribe { topic }) => match subscribe(&ipfs, topic).await {
+                Ok(mut messages) => {
+                    send(&mut stream, &Response::Ok(Value::Null)).await?;
+                    while let Some(msg) = messages.next().await {
+                        send(&mut stream, &Response::Ok(msg)).await?;
+                    }
+                    return Ok(());
+                }
+                Err(err) => Response::Error(format!("{:#}", err)),
+            },
+            Ok(request) => handle(&ipfs, request).await.into(),
+            Err(err) => Response::Error(format!("invalid request: {}", err)),
+        };
+        send(&mut stream, &response).await?;
+    }
+    Ok(())
+}
+
+async fn send(stream: &mut TcpStream, response: &Response) -> Result<()> {
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    Ok(())
+}
+
+/// Subscribes to `topic` and returns its messages as `{from, data}`.
+pub async fn subscribe(
+    ipfs: &Ipfs<DefaultParams>,
+    topic: String,
+) -> Result<Pin<Box<dyn Stream<Item = Value> + Send>>> {
+    let events = ipfs.clone().subscribe(topic).await?;
+    Ok(Box::pin(events.filter_map(|event| match event {
+        GossipEvent::Message(peer, data, _) => Some(json!({
+            "from": peer.to_string(),
+            "data": String::from_utf8_lossy(&data),
+        })),
+        _ => None,
+    })))
+}
+
+/// Handles every request except `Subscribe`.
+pub async fn handle(ipfs: &Ipfs<DefaultParams>, request: Request) -> Result<Value> {
+    let mut ipfs = ipfs.clone();
+    Ok(match request {
+        Request::Id => json!({
+            "id": ipfs.local_peer_id().to_string(),
+            "addresses": ipfs
+                .listeners()
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>(),
+        }),
+        Request::Peers => ipfs
+            .connections()
+            .into_iter()
+            .map(|(peer, addr, _, _)| json!({ "peer": peer.to_string(), "addr": addr.to_string() }))
+            .collect(),
+        Request::Connect { addr } => {
+            let (peer, addr) = split_peer_id(&addr.parse()?)?;
+            ipfs.add_address(peer, addr);
+            ipfs.connect(peer).await?;
+            Value::Null
+        }
+        Request::Pin { cid } => {
+            ipfs.pin(&cid.parse::<Cid>()?)?;
+            Value::Null
+        }
+        Request::Publish { topic, data } => {
+            let id = ipfs.publish(topic, data.into_bytes()).await?;
+            json!({ "id": id.to_string() })
+        }
+        Request::Subscribe { .. } => return Err(anyhow!("subscribe is a stream")),
+        Request::DhtGet { key } => {
+            let records = match ipfs
+                .get_record(Key::from(key.into_bytes()), Quorum::One)
+                .await
+            {
+                Ok(records) => records,
+                Err(err @ Error::RecordNotFound(_)) => return Err(NotFound(err.to_string()).into()),
+                Err(err) => return Err(err.into()),
+            };
+            records
+                .into_iter()
+                .map(|record| {
+                    json!({
+                        "value": String::from_utf8_lossy(&record.record.value),
+                        "peer": record.peer.map(|peer| peer.to_string()),
+                    })
+                })
+                .collect()
+        }
+        Request::DhtPut { key, value } => {
+            let record = Record::new(Key::from(key.into_bytes()), value.into_bytes());
+            ipfs.put_record(record, Quorum::One).await?;
+            Value::Null
+        }
+        Request::DhtProviders { cid } => {
+            let cid = cid.parse::<Cid>()?;
+            let providers = ipfs.providers(Key::new(&cid.to_bytes())).await?;
+            if providers.is_empty() {
+                return Err(NotFound(format!("No providers found for block {}.", cid)).into());
+            }
+            providers
+                .into_iter()
+                .map(|peer| json!({ "peer": peer.to_string() }))
+                .collect()
+        }
+    })
+}
+
+/// Sends a request to the daemon running on `repo`. Fails if no daemon is
+/// running.
+pub async fn request(repo: &Path, request: &Request) -> Result<Value> {
+    let mut responses = connect(repo, request).await?;
+    parse(responses.next().await)
+}
+
+/// Subscribes to `topic` on the daemon running on `repo` and returns the
+/// messages once subscribed.
+pub async fn subscribe_remote(
+    repo: &Path,
+    topic: String,
+) -> Result<impl Stream<Item = Result<Value>>> {
+    let mut responses = connect(repo, &Request::Subscribe { topic }).await?;
+    parse(responses.next().await)?;
+    Ok(responses.map(|line| parse(Some(line))))
+}
+
+/// Parses a response line, `None` if the daemon closed the connection.
+fn parse(line: Option<std::io::Result<String>>) -> Result<Value> {
+    let line = line.ok_or_else(|| anyhow!("the daemon closed the connection"))??;
+    serde_json::from_str::<Response>(&line)?.into()
+}
+
+/// Connects to the daemon running on `repo`, sends `request` and returns
+/// the response lines.
+async fn connect(
+    repo: &Path,
+    request: &Request,
+) -> Result<async_std::io::Lines<BufReader<TcpStream>>> {
+    let addr = std::fs::read_to_string(api_file(repo))
+        .map_err(|_| anyhow!("no daemon is running on {}", repo.display()))?;
+    let mut stream = TcpStream::connect(addr.trim())
+        .await
+        .map_err(|err| anyhow!("failed to connect to the daemon at {}: {}", addr, err))?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    Ok(BufReader::new(stream).lines())
+}
+
+/// Returns whether a daemon is running on `repo`.
+pub fn is_running(repo: &Path) -> bool {
+    api_file(repo).exists()
+}
@@ -0,0 +1,156 @@
+//! Adding files to the store and reading them back, fetching missing blocks
+//! from providers.
+use crate::daemon::split_peer_id;
+use anyhow::{anyhow, Context as _, Result};
+use async_std::prelude::*;
+use futures::{io::AsyncRead, ready};
+use ipfs_embed::{AddOptions, Cid, DefaultParams, Error, Ipfs, Multiaddr, SyncEvent};
+use std::{
+    io::Write,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Transfers of at least this many bytes report their progress on stderr
+/// every time another step is done.
+const PROGRESS_STEP: u64 = 16 * 1024 * 1024;
+/// Number of bytes read from the store at a time.
+const READ_SIZE: u64 = 1024 * 1024;
+
+/// Adds the file at `path` and returns its root. If `pin` is given the root
+/// is aliased with it, otherwise it is collected by the next gc run.
+pub async fn add(ipfs: &Ipfs<DefaultParams>, path: &Path, pin: Option<&str>) -> Result<Cid> {
+    let file = async_std::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let total = file.metadata().await?.len();
+    let reader = ProgressReader {
+        reader: file,
+        progress: Progress::new("added", total),
+    };
+    let cid = ipfs.add_file(reader, AddOptions::default()).await?;
+    if let Some(alias) = pin {
+        ipfs.alias(alias, Some(&cid))?;
+    }
+    Ok(cid)
+}
+
+/// Writes the file `cid` to `writer`. Missing blocks are fetched from
+/// `providers` first.
+pub async fn get(
+    ipfs: &mut Ipfs<DefaultParams>,
+    cid: &Cid,
+    providers: &[Multiaddr],
+    mut writer: impl Write,
+) -> Result<()> {
+    if !providers.is_empty() {
+        fetch(ipfs, cid, providers).await?;
+    }
+    let fetched = !providers.is_empty();
+    let size = ipfs
+        .file_size(cid)
+        .map_err(|err| missing(err, fetched))?
+        .ok_or_else(|| anyhow!("{} is not a file", cid))?;
+    let mut progress = Progress::new("wrote", size);
+    let mut offset = 0;
+    while offset < size {
+        let end = size.min(offset.saturating_add(READ_SIZE));
+        let data = ipfs
+            .read_file(cid, offset..end)
+            .map_err(|err| missing(err, fetched))?;
+        writer.write_all(&data)?;
+        progress.advance(end - offset);
+        offset = end;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Syncs the dag of `cid` from `providers`, the addresses have to end with
+/// `/p2p`.
+async fn fetch(ipfs: &mut Ipfs<DefaultParams>, cid: &Cid, providers: &[Multiaddr]) -> Result<()> {
+    let mut peers = Vec::with_capacity(providers.len());
+    for addr in providers {
+        let (peer, addr) = split_peer_id(addr)?;
+        ipfs.add_address(peer, addr);
+        peers.push(peer);
+    }
+    let mut query = ipfs.sync(cid, peers).await?;
+    let mut reported = 0;
+    while let Some(event) = query.next().await {
+        match event {
+            SyncEvent::Progress { blocks, bytes, .. } if bytes >= reported + PROGRESS_STEP => {
+                eprintln!("fetched {} blocks, {} MiB", blocks, bytes >> 20);
+                reported = bytes;
+            }
+            SyncEvent::Complete(result) => return result.context("failed to fetch the file"),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Points out `--provider` when a block is missing and nothing was fetched.
+fn missing(err: Error, fetched: bool) -> anyhow::Error {
+    match err {
+        Error::BlockNotFound(cid) if !fetched => anyhow!(
+            "block {} is not in the store, use --provider to fetch it",
+            cid
+        ),
+        err => err.into(),
+    }
+}
+
+/// Progress of a transfer of `total` bytes.
+struct Progress {
+    verb: &'static str,
+    total: u64,
+    done: u64,
+    reported: u64,
+}
+
+impl Progress {
+    fn new(verb: &'static str, total: u64) -> Self {
+        Self {
+            verb,
+            total,
+            done: 0,
+            reported: 0,
+        }
+    }
+
+    fn advance(&mut self, n: u64) {
+        self.done += n;
+        if self.total < PROGRESS_STEP || self.done == self.reported {
+            return;
+        }
+        if self.done >= self.reported + PROGRESS_STEP || self.done >= self.total {
+            eprintln!(
+                "{} {} of {} MiB",
+                self.verb,
+                self.done >> 20,
+                self.total >> 20
+            );
+            self.reported = self.done;
+        }
+    }
+}
+
+/// Reports the progress of reading from `reader`.
+struct ProgressReader<R> {
+    reader: R,
+    progress: Progress,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
+        self.progress.advance(n as u64);
+        Poll::Ready(Ok(n))
+    }
+}
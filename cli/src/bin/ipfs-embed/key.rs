@@ -0,0 +1,70 @@
+//! Management of the node key of a repository. Keys are stored and
+//! exchanged in the protobuf encoding of the go-ipfs keystore.
+use anyhow::{anyhow, Result};
+use ipfs_embed::{identity, PeerId};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The file containing the node key of the repository.
+pub fn key_file(repo: &Path) -> PathBuf {
+    repo.join("key")
+}
+
+fn peer_id(keypair: &identity::ed25519::Keypair) -> PeerId {
+    PeerId::from(identity::PublicKey::Ed25519(keypair.public()))
+}
+
+/// Generates the node key of a new repository.
+pub fn gen(repo: &Path) -> Result<PeerId> {
+    if key_file(repo).exists() {
+        return Err(anyhow!("the repository {} has a key", repo.display()));
+    }
+    let keypair = identity::ed25519::Keypair::generate();
+    identity::save(&key_file(repo), &keypair)?;
+    Ok(peer_id(&keypair))
+}
+
+pub fn id(repo: &Path) -> Result<PeerId> {
+    Ok(peer_id(&identity::load(&key_file(repo))?))
+}
+
+/// Writes the key to `output`, readable only by the owner, or to stdout.
+/// Printing the key to a terminal requires `force`.
+pub fn export(repo: &Path, output: Option<&Path>, force: bool) -> Result<()> {
+    let keypair = identity::load(&key_file(repo))?;
+    match output {
+        Some(output) => identity::save(output, &keypair)?,
+        None if atty::is(atty::Stream::Stdout) && !force => {
+            return Err(anyhow!(
+                "refusing to print the secret key to a terminal, use --output or --force"
+            ));
+        }
+        None => {
+            let bytes = identity::Keypair::Ed25519(keypair).to_protobuf_encoding()?;
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&bytes)?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Makes the key at `path` the node key. Replacing an existing key requires
+/// `force`.
+pub fn import(repo: &Path, path: &Path, force: bool) -> Result<PeerId> {
+    let keypair = identity::load(path)?;
+    let key_file = key_file(repo);
+    if key_file.exists() {
+        if !force {
+            return Err(anyhow!(
+                "the repository {} has a key, use --force to replace it",
+                repo.display()
+            ));
+        }
+        std::fs::remove_file(&key_file)?;
+    }
+    identity::save(&key_file, &keypair)?;
+    Ok(peer_id(&keypair))
+}
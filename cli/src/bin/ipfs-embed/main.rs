@@ -0,0 +1,399 @@
+//! Command line interface to an ipfs-embed repository.
+use crate::{
+    daemon::{NotFound, Request},
+    node::Node,
+};
+use anyhow::{anyhow, Context, Result};
+use fs2::FileExt;
+use futures::StreamExt;
+use ipfs_embed::{identity, Cid, Config, DefaultParams, Ipfs, Multiaddr};
+use libipld::{codec::Codec, json::DagJsonCodec};
+use serde_json::Value;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+mod daemon;
+mod files;
+mod key;
+mod maintenance;
+mod node;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "ipfs-embed",
+    after_help = "Exits with 2 if a dht record or the providers of a block weren't found \
+                  and with 1 on any other error."
+)]
+struct Opts {
+    /// Directory of the repository, created if it doesn't exist.
+    #[structopt(long, default_value = ".ipfs-embed")]
+    path: PathBuf,
+    /// Prints the output of pubsub and dht commands as one json object per
+    /// line.
+    #[structopt(long, global = true)]
+    json: bool,
+    #[structopt(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Debug, StructOpt)]
+enum Cmd {
+    /// Lists the blocks in the store followed by the aliases retaining them.
+    Ls,
+    /// Prints a block as dag-json.
+    Cat { cid: Cid },
+    /// Removes an alias. Its blocks are garbage collected unless they are
+    /// retained otherwise.
+    Unpin { alias: String },
+    /// Adds a file and prints its cid.
+    Add {
+        path: PathBuf,
+        /// Alias keeping the file from being garbage collected.
+        #[structopt(long)]
+        pin: Option<String>,
+    },
+    /// Writes a file to stdout or to the output path.
+    Get {
+        cid: Cid,
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+        /// Peer to fetch missing blocks from, the address has to end with
+        /// `/p2p`.
+        #[structopt(long)]
+        provider: Vec<Multiaddr>,
+    },
+    /// Runs a node until it is interrupted. The `id`, `peers`, `connect` and
+    /// `pin` commands are sent to the running daemon.
+    Daemon {
+        /// Address to listen on.
+        #[structopt(long)]
+        listen: Vec<Multiaddr>,
+        /// Node to bootstrap from, the address has to end with `/p2p`.
+        #[structopt(long)]
+        bootstrap: Vec<Multiaddr>,
+    },
+    /// Prints the peer id and the listen addresses of the daemon.
+    Id,
+    /// Lists the peers the daemon is connected to.
+    Peers,
+    /// Connects the daemon to a peer, the address has to end with `/p2p`.
+    Connect { addr: Multiaddr },
+    /// Pins a dag, keeping it from being garbage collected.
+    Pin { cid: Cid },
+    /// Manages the node key.
+    Key(KeyCmd),
+    /// Maintains the store.
+    Repo(RepoCmd),
+    /// Imports and exports dags as car files.
+    Dag(DagCmd),
+    /// Publishes and subscribes to gossipsub topics.
+    Pubsub {
+        /// Runs an ephemeral node bootstrapped from these nodes instead of
+        /// using the daemon, the addresses have to end with `/p2p`. Takes
+        /// one address per flag, so that the subcommand isn't consumed.
+        #[structopt(long, number_of_values = 1)]
+        bootstrap: Vec<Multiaddr>,
+        #[structopt(subcommand)]
+        cmd: PubsubCmd,
+    },
+    /// Queries the dht.
+    Dht {
+        /// Runs an ephemeral node bootstrapped from these nodes instead of
+        /// using the daemon, the addresses have to end with `/p2p`. Takes
+        /// one address per flag, so that the subcommand isn't consumed.
+        #[structopt(long, number_of_values = 1)]
+        bootstrap: Vec<Multiaddr>,
+        #[structopt(subcommand)]
+        cmd: DhtCmd,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum KeyCmd {
+    /// Generates the node key of a new repository and prints its peer id.
+    Gen,
+    /// Prints the peer id of the repository.
+    Id,
+    /// Exports the node key in the protobuf encoding of go-ipfs.
+    Export {
+        /// File to write the key to, readable only by the owner.
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+        /// Prints the key even if stdout is a terminal.
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Imports a node key exported by ipfs-embed or go-ipfs and prints its
+    /// peer id.
+    Import {
+        path: PathBuf,
+        /// Replaces the existing key.
+        #[structopt(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum RepoCmd {
+    /// Runs the garbage collector and prints what it removed.
+    Gc,
+    /// Prints the number of blocks, bytes, pins and aliases.
+    Stat,
+    /// Rehashes every block, fails if a block is corrupt or an alias points
+    /// to a missing block.
+    Verify,
+}
+
+#[derive(Debug, StructOpt)]
+enum DagCmd {
+    /// Exports the dag rooted at a block as a car file.
+    Export {
+        cid: Cid,
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+    /// Imports a car file and prints its roots.
+    Import {
+        path: PathBuf,
+        /// Alias keeping the imported dag from being garbage collected.
+        #[structopt(long)]
+        pin: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum PubsubCmd {
+    /// Prints the messages of a topic until interrupted.
+    Sub { topic: String },
+    /// Publishes a message and prints its id.
+    Pub { topic: String, data: String },
+}
+
+#[derive(Debug, StructOpt)]
+enum DhtCmd {
+    /// Prints the values of a record.
+    Get { key: String },
+    /// Puts a record.
+    Put { key: String, value: String },
+    /// Prints the providers of a block.
+    Providers { cid: Cid },
+}
+
+#[async_std::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+    if let Err(err) = run(Opts::from_args()).await {
+        eprintln!("error: {:#}", err);
+        std::process::exit(if err.is::<NotFound>() { 2 } else { 1 });
+    }
+}
+
+async fn run(opts: Opts) -> Result<()> {
+    let repo = &opts.path;
+    let json = opts.json;
+    let mut stdout = std::io::stdout();
+    match opts.cmd {
+        Cmd::Pubsub { bootstrap, cmd } => {
+            let node = Node::new(repo, &bootstrap).await?;
+            match cmd {
+                PubsubCmd::Sub { topic } => {
+                    let mut messages = node.subscribe(topic.clone()).await?;
+                    eprintln!("subscribed to {}", topic);
+                    while let Some(msg) = messages.next().await {
+                        print(&mut stdout, &msg?, json, "data")?;
+                    }
+                }
+                PubsubCmd::Pub { topic, data } => {
+                    let id = node.request(Request::Publish { topic, data }).await?;
+                    print(&mut stdout, &id, json, "id")?;
+                }
+            }
+            return Ok(());
+        }
+        Cmd::Dht { bootstrap, cmd } => {
+            let node = Node::new(repo, &bootstrap).await?;
+            let (request, field) = match cmd {
+                DhtCmd::Get { key } => (Request::DhtGet { key }, "value"),
+                DhtCmd::Put { key, value } => (Request::DhtPut { key, value }, ""),
+                DhtCmd::Providers { cid } => {
+                    let cid = cid.to_string();
+                    (Request::DhtProviders { cid }, "peer")
+                }
+            };
+            let values = node.request(request).await?;
+            for value in values.as_array().into_iter().flatten() {
+                print(&mut stdout, value, json, field)?;
+            }
+            return Ok(());
+        }
+        Cmd::Daemon { listen, bootstrap } => {
+            let ipfs = open(repo, |config| {
+                config.network.listen_addresses = listen;
+                config.network.bootstrap = bootstrap
+                    .iter()
+                    .map(daemon::split_peer_id)
+                    .collect::<Result<_>>()?;
+                Ok(())
+            })
+            .await?;
+            return daemon::run(repo, ipfs).await;
+        }
+        Cmd::Key(cmd) => {
+            match cmd {
+                KeyCmd::Gen => {
+                    lock(repo)?;
+                    writeln!(stdout, "{}", key::gen(repo)?)?;
+                }
+                KeyCmd::Id => writeln!(stdout, "{}", key::id(repo)?)?,
+                KeyCmd::Export { output, force } => key::export(repo, output.as_deref(), force)?,
+                KeyCmd::Import { path, force } => {
+                    lock(repo)?;
+                    writeln!(stdout, "{}", key::import(repo, &path, force)?)?;
+                }
+            }
+            return Ok(());
+        }
+        Cmd::Id => {
+            let id = daemon::request(repo, &Request::Id).await?;
+            writeln!(stdout, "peer id {}", id["id"].as_str().unwrap_or_default())?;
+            for addr in id["addresses"].as_array().into_iter().flatten() {
+                writeln!(stdout, "listening on {}", addr.as_str().unwrap_or_default())?;
+            }
+            return Ok(());
+        }
+        Cmd::Peers => {
+            let peers = daemon::request(repo, &Request::Peers).await?;
+            for peer in peers.as_array().into_iter().flatten() {
+                writeln!(
+                    stdout,
+                    "{} {}",
+                    peer["peer"].as_str().unwrap_or_default(),
+                    peer["addr"].as_str().unwrap_or_default()
+                )?;
+            }
+            return Ok(());
+        }
+        Cmd::Connect { addr } => {
+            let addr = addr.to_string();
+            daemon::request(repo, &Request::Connect { addr }).await?;
+            return Ok(());
+        }
+        Cmd::Pin { cid } if daemon::is_running(repo) => {
+            let cid = cid.to_string();
+            daemon::request(repo, &Request::Pin { cid }).await?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    let mut ipfs = open(repo, |_| Ok(())).await?;
+    match opts.cmd {
+        Cmd::Ls => {
+            for cid in ipfs.iter()? {
+                let mut aliases = ipfs
+                    .reverse_alias(&cid)?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|alias| String::from_utf8_lossy(&alias).into_owned())
+                    .collect::<Vec<_>>();
+                aliases.sort();
+                write!(stdout, "{}", cid)?;
+                for alias in aliases {
+                    write!(stdout, " {}", alias)?;
+                }
+                writeln!(stdout)?;
+            }
+        }
+        Cmd::Cat { cid } => {
+            let ipld = ipfs.get(&cid)?.ipld()?;
+            stdout.write_all(&DagJsonCodec.encode(&ipld)?)?;
+            writeln!(stdout)?;
+        }
+        Cmd::Unpin { alias } => {
+            if ipfs.resolve(&alias)?.is_none() {
+                return Err(anyhow!("no alias {}", alias));
+            }
+            ipfs.alias(&alias, None)?;
+        }
+        Cmd::Add { path, pin } => {
+            let cid = files::add(&ipfs, &path, pin.as_deref()).await?;
+            writeln!(stdout, "{}", cid)?;
+        }
+        Cmd::Get {
+            cid,
+            output,
+            provider,
+        } => match output {
+            Some(output) => {
+                let file = std::fs::File::create(&output)
+                    .with_context(|| format!("failed to create {}", output.display()))?;
+                files::get(&mut ipfs, &cid, &provider, std::io::BufWriter::new(file)).await?;
+            }
+            None => files::get(&mut ipfs, &cid, &provider, stdout.lock()).await?,
+        },
+        Cmd::Pin { cid } => ipfs.pin(&cid)?,
+        Cmd::Repo(RepoCmd::Gc) => maintenance::gc(&ipfs, &mut stdout, json).await?,
+        Cmd::Repo(RepoCmd::Stat) => maintenance::stat(&ipfs, &mut stdout, json)?,
+        Cmd::Repo(RepoCmd::Verify) => maintenance::verify(&ipfs, &mut stdout, json).await?,
+        Cmd::Dag(DagCmd::Export { cid, output }) => {
+            maintenance::export(&ipfs, &cid, &output, &mut stdout, json).await?
+        }
+        Cmd::Dag(DagCmd::Import { path, pin }) => {
+            maintenance::import(&ipfs, &path, pin.as_deref(), &mut stdout, json).await?
+        }
+        Cmd::Daemon { .. }
+        | Cmd::Id
+        | Cmd::Peers
+        | Cmd::Connect { .. }
+        | Cmd::Key(_)
+        | Cmd::Pubsub { .. }
+        | Cmd::Dht { .. } => unreachable!(),
+    }
+    ipfs.shutdown().await?;
+    Ok(())
+}
+
+/// Prints `value` as json, or only its `field` unless `json` is set.
+fn print(out: &mut impl Write, value: &Value, json: bool, field: &str) -> Result<()> {
+    if json {
+        writeln!(out, "{}", value)?;
+    } else {
+        writeln!(out, "{}", value[field].as_str().unwrap_or_default())?;
+    }
+    Ok(())
+}
+
+/// Opens the repository at `path` with the node key stored in it. The node
+/// only listens if `configure` adds listen addresses. Fails if another
+/// process opened the repository.
+async fn open(
+    path: &Path,
+    configure: impl FnOnce(&mut Config) -> Result<()>,
+) -> Result<Ipfs<DefaultParams>> {
+    lock(path)?;
+    let keypair = identity::load_or_generate(&key::key_file(path))?;
+    let mut config = Config::new(path, keypair);
+    config.network.mdns = None;
+    configure(&mut config)?;
+    Ok(Ipfs::new(config).await?)
+}
+
+/// Locks the repository until the process exits.
+fn lock(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path)?;
+    let file = std::fs::File::create(path.join("lock"))?;
+    file.try_lock_exclusive().map_err(|_| {
+        anyhow!(
+            "the repository {} is in use by another process",
+            path.display()
+        )
+    })?;
+    // the lock is released when the file is closed
+    std::mem::forget(file);
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! Maintenance of the store: garbage collection, statistics, verification
+//! and car files.
+use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
+use ipfs_embed::{Cid, DefaultParams, Ipfs, VerifyConfig, VerifyEvent};
+use serde_json::{json, Value};
+use std::{io::Write, path::Path};
+
+/// Runs the garbage collector to completion.
+pub async fn gc(ipfs: &Ipfs<DefaultParams>, out: &mut impl Write, json: bool) -> Result<()> {
+    let stats = ipfs.evict().await?;
+    let value = json!({
+        "blocks": stats.blocks_evicted,
+        "bytes": stats.bytes_evicted,
+    });
+    let text = format!(
+        "removed {} blocks, {} bytes",
+        stats.blocks_evicted, stats.bytes_evicted
+    );
+    print(out, &value, json, text)
+}
+
+/// Prints the number of blocks, bytes, pins and aliases of the store.
+pub fn stat(ipfs: &Ipfs<DefaultParams>, out: &mut impl Write, json: bool) -> Result<()> {
+    let stats = ipfs.store_stats()?;
+    let pins = ipfs.list_pins()?.len();
+    let aliases = ipfs.aliases()?.len();
+    let value = json!({
+        "blocks": stats.blocks,
+        "bytes": stats.bytes,
+        "logical_bytes": stats.logical_bytes,
+        "pins": pins,
+        "aliases": aliases,
+    });
+    let text = format!(
+        "blocks {}\nbytes {}\nlogical bytes {}\npins {}\naliases {}",
+        stats.blocks, stats.bytes, stats.logical_bytes, pins, aliases
+    );
+    print(out, &value, json, text)
+}
+
+/// Rehashes every block and checks that aliases point to stored blocks.
+/// Fails if a problem was found.
+pub async fn verify(ipfs: &Ipfs<DefaultParams>, out: &mut impl Write, json: bool) -> Result<()> {
+    let events = ipfs.verify(VerifyConfig::default());
+    futures::pin_mut!(events);
+    let mut problems = 0;
+    let mut checked = 0;
+    while let Some(event) = events.next().await {
+        let (value, text) = match event {
+            VerifyEvent::Progress { checked: n, .. } => {
+                checked = n;
+                continue;
+            }
+            VerifyEvent::Corrupt(cid) => {
+                problems += 1;
+                let value = json!({ "corrupt": cid.to_string() });
+                (value, format!("corrupt {}", cid))
+            }
+            VerifyEvent::Removed(cid) => {
+                let value = json!({ "removed": cid.to_string() });
+                (value, format!("removed {}", cid))
+            }
+            VerifyEvent::Dangling { alias, cid } => {
+                problems += 1;
+                let alias = String::from_utf8_lossy(&alias).into_owned();
+                let text = format!("dangling {} {}", alias, cid);
+                (json!({ "dangling": alias, "cid": cid.to_string() }), text)
+            }
+            VerifyEvent::Failed(err) => return Err(anyhow!("verification failed: {}", err)),
+        };
+        print(out, &value, json, text)?;
+    }
+    let text = format!("checked {} blocks", checked);
+    print(out, &json!({ "checked": checked }), json, text)?;
+    if problems > 0 {
+        return Err(anyhow!("found {} problems", problems));
+    }
+    Ok(())
+}
+
+/// Exports the dag rooted at `cid` as a car file.
+pub async fn export(
+    ipfs: &Ipfs<DefaultParams>,
+    cid: &Cid,
+    path: &Path,
+    out: &mut impl Write,
+    json: bool,
+) -> Result<()> {
+    let file = async_std::fs::File::create(path)
+        .await
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    let stats = ipfs.export_car(cid, file).await?;
+    let value = json!({ "blocks": stats.blocks, "bytes": stats.bytes });
+    let text = format!("exported {} blocks, {} bytes", stats.blocks, stats.bytes);
+    print(out, &value, json, text)
+}
+
+/// Imports a car file and prints its roots. With an `alias` the roots are
+/// kept from being garbage collected.
+pub async fn import(
+    ipfs: &Ipfs<DefaultParams>,
+    path: &Path,
+    alias: Option<&str>,
+    out: &mut impl Write,
+    json: bool,
+) -> Result<()> {
+    let file = async_std::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let roots = ipfs
+        .import_car(file, alias.map(|alias| alias.as_bytes()))
+        .await?;
+    for root in roots {
+        print(
+            out,
+            &json!({ "root": root.to_string() }),
+            json,
+            root.to_string(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints `value` as json if `json` is set, `text` otherwise.
+fn print(out: &mut impl Write, value: &Value, json: bool, text: String) -> Result<()> {
+    if json {
+        writeln!(out, "{}", value)?;
+    } else {
+        writeln!(out, "{}", text)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! The node serving the pubsub and dht commands.
+use crate::daemon::{self, split_peer_id, Request};
+use anyhow::Result;
+use async_std::{prelude::*, stream::Stream};
+use ipfs_embed::{Config, DefaultParams, Ipfs, Multiaddr};
+use serde_json::Value;
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+/// How long an ephemeral node retries publishing while the subscriptions of
+/// its peers are still unknown.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long an ephemeral node keeps running after publishing. Gossipsub
+/// doesn't acknowledge messages, exiting right away drops them unsent.
+const PUBLISH_LINGER: Duration = Duration::from_millis(500);
+
+/// Where the requests of a command are sent.
+pub enum Node {
+    /// The daemon running on the repository.
+    Daemon(PathBuf),
+    /// An in-memory node bootstrapped for a single command.
+    Ephemeral(Box<Ipfs<DefaultParams>>),
+}
+
+impl Node {
+    /// Uses the daemon running on `repo` unless `bootstrap` nodes are given,
+    /// the addresses have to end with `/p2p`.
+    pub async fn new(repo: &Path, bootstrap: &[Multiaddr]) -> Result<Self> {
+        if bootstrap.is_empty() {
+            return Ok(Self::Daemon(repo.to_path_buf()));
+        }
+        let nodes = bootstrap
+            .iter()
+            .map(split_peer_id)
+            .collect::<Result<Vec<_>>>()?;
+        let mut config = Config::default();
+        config.storage.path = None;
+        config.network.mdns = None;
+        let mut ipfs = Ipfs::new(config).await?;
+        ipfs.bootstrap(nodes).await?;
+        Ok(Self::Ephemeral(Box::new(ipfs)))
+    }
+
+    /// Sends `request`, an ephemeral node retries publishing for a while.
+    pub async fn request(&self, request: Request) -> Result<Value> {
+        let ipfs = match self {
+            Self::Daemon(repo) => return daemon::request(repo, &request).await,
+            Self::Ephemeral(ipfs) => ipfs,
+        };
+        // the subscriptions of the bootstrap nodes arrive after connecting
+        let retry = matches!(request, Request::Publish { .. });
+        let deadline = Instant::now() + PUBLISH_TIMEOUT;
+        loop {
+            match daemon::handle(ipfs, request.clone()).await {
+                Err(_) if retry && Instant::now() < deadline => {
+                    async_std::task::sleep(Duration::from_millis(100)).await
+                }
+                Ok(value) if retry => {
+                    async_std::task::sleep(PUBLISH_LINGER).await;
+                    return Ok(value);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Subscribes to `topic` and returns its messages once subscribed.
+    pub async fn subscribe(
+        &self,
+        topic: String,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Value>> + Send>>> {
+        let messages: Pin<Box<dyn Stream<Item = Result<Value>> + Send>> = match self {
+            Self::Daemon(repo) => Box::pin(daemon::subscribe_remote(repo, topic).await?),
+            Self::Ephemeral(ipfs) => Box::pin(
+                daemon::subscribe(ipfs, topic)
+                    .await?
+                    .map(Ok::<_, anyhow::Error>),
+            ),
+        };
+        Ok(messages)
+    }
+}
@@ -26,6 +26,8 @@ pub struct Config {
     pub external: Vec<Multiaddr>,
     #[structopt(long)]
     pub disable_port_reuse: bool,
+    #[structopt(long)]
+    pub sync_in_flight: Option<usize>,
 }
 
 impl Config {
@@ -39,6 +41,7 @@ impl Config {
             external: vec![],
             enable_mdns: false,
             disable_port_reuse: false,
+            sync_in_flight: None,
         }
     }
 }
@@ -79,6 +82,9 @@ impl From<Config> for async_process::Command {
         if config.disable_port_reuse {
             cmd.arg("--disable-port-reuse");
         }
+        if let Some(sync_in_flight) = config.sync_in_flight {
+            cmd.arg("--sync-in-flight").arg(sync_in_flight.to_string());
+        }
         cmd
     }
 }
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use async_std::stream::StreamExt;
 use futures::TryFutureExt;
-use ipfs_embed::{DefaultParams, Ipfs, NetworkConfig, StorageConfig};
+use ipfs_embed::{identity, DefaultParams, Ipfs, NetworkConfig, StorageConfig, SyncPipelineConfig};
 use ipfs_embed_cli::{keypair, Command, Config, Event};
 use parking_lot::Mutex;
 use std::{io::Write, sync::Arc, time::Duration};
@@ -29,7 +29,7 @@ async fn run() -> Result<()> {
     let storage = StorageConfig::new(config.path, None, 10, sweep_interval);
 
     let mut network = NetworkConfig {
-        node_key: keypair(config.keypair),
+        node_key: identity::Keypair::Ed25519(keypair(config.keypair)),
         mdns: if config.enable_mdns {
             Some(Default::default())
         } else {
@@ -37,6 +37,12 @@ async fn run() -> Result<()> {
         },
         kad: None,
         port_reuse: !config.disable_port_reuse,
+        sync_pipeline: config
+            .sync_in_flight
+            .map(|max_in_flight| SyncPipelineConfig {
+                max_in_flight,
+                ..Default::default()
+            }),
         ..Default::default()
     };
     let node_name = if let Some(node_name) = config.node_name {
@@ -79,6 +85,8 @@ async fn run() -> Result<()> {
                     Some(Event::ExpiredExternalAddr(addr))
                 }
                 ipfs_embed::Event::Discovered(peer_id) => Some(Event::Discovered(peer_id)),
+                ipfs_embed::Event::MdnsDiscovered(_, _) => None,
+                ipfs_embed::Event::MdnsExpired(_) => None,
                 ipfs_embed::Event::Unreachable(peer_id) => Some(Event::Unreachable(peer_id)),
                 ipfs_embed::Event::Connected(peer_id) => Some(Event::Connected(peer_id)),
                 ipfs_embed::Event::Disconnected(peer_id) => Some(Event::Disconnected(peer_id)),
@@ -88,13 +96,19 @@ async fn run() -> Result<()> {
                 ipfs_embed::Event::Unsubscribed(peer_id, topic) => {
                     Some(Event::Unsubscribed(peer_id, topic))
                 }
+                ipfs_embed::Event::GossipPruned(_) => None,
+                ipfs_embed::Event::GossipGraylisted(_) => None,
                 ipfs_embed::Event::Bootstrapped => Some(Event::Bootstrapped),
+                ipfs_embed::Event::RebootstrapStarted => None,
+                ipfs_embed::Event::RebootstrapSucceeded => None,
+                ipfs_embed::Event::RebootstrapFailed(_) => None,
                 ipfs_embed::Event::NewInfo(peer) => match ipfs2.lock().peer_info(&peer) {
                     Some(info) => Some(Event::PeerInfo(peer, info.into())),
                     None => Some(Event::PeerRemoved(peer)),
                 },
                 ipfs_embed::Event::ListenerError(_, _) => None,
                 ipfs_embed::Event::DialFailure(p, a, e) => Some(Event::DialFailure(p, a, e)),
+                ipfs_embed::Event::PeerUnresponsive(_) => None,
                 ipfs_embed::Event::ConnectionEstablished(p, a) => Some(
                     Event::ConnectionEstablished(p, a.get_remote_address().clone()),
                 ),
@@ -102,6 +116,16 @@ async fn run() -> Result<()> {
                     Some(Event::ConnectionClosed(p, a.get_remote_address().clone()))
                 }
                 ipfs_embed::Event::AddressChanged(_, _, _) => None,
+                ipfs_embed::Event::RelayReservation(_, _) => None,
+                ipfs_embed::Event::NatStatusChanged(_) => None,
+                ipfs_embed::Event::ConnectionLimitReached(_, _, _, _) => None,
+                ipfs_embed::Event::ConnectionGated(_, _, _, _) => None,
+                ipfs_embed::Event::DirectConnectionUpgraded(_) => None,
+                ipfs_embed::Event::DirectConnectionUpgradeFailed(_, _) => None,
+                ipfs_embed::Event::QueryStarted(_) => None,
+                ipfs_embed::Event::QueryFinished(_) => None,
+                ipfs_embed::Event::BlockReceived(_, _) => None,
+                ipfs_embed::Event::BlockSent(_, _) => None,
             };
             if let Some(event) = event {
                 println!("{}", event);
@@ -136,11 +160,11 @@ async fn run() -> Result<()> {
                 let f = ipfs
                     .lock()
                     .sync(&cid, providers)
-                    .and_then(|f| f)
+                    .and_then(|f| f.err_into())
                     .inspect_ok(|_| writeln!(stdout, "{}", Event::Synced).expect("print"));
                 f.await
             }
-            Err(err) => Err(err),
+            Err(err) => Err(err.into()),
         };
         if let Err(err) = result {
             eprintln!("main loop error (line = {}): {}", line, err);
@@ -0,0 +1,464 @@
+use anyhow::Result;
+use assert_cmd::Command;
+use ipfs_embed::{identity, Block, Cid, Config, DefaultParams, Ipfs};
+use libipld::{cbor::DagCborCodec, ipld, multihash::Code, raw::RawCodec};
+use predicates::{boolean::PredicateBooleanExt, str::contains};
+use std::{
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Child, Stdio},
+};
+use tempdir::TempDir;
+
+fn ipfs_embed(repo: &Path) -> Command {
+    let mut cmd = Command::cargo_bin("ipfs-embed").unwrap();
+    cmd.arg("--path").arg(repo);
+    cmd
+}
+
+/// Creates a repository containing a dag-cbor block aliased as `root` and a
+/// raw block without alias.
+fn create_repo(path: &Path) -> Result<(Cid, Cid)> {
+    async_std::task::block_on(async {
+        let keypair = identity::load_or_generate(&path.join("key"))?;
+        let mut config = Config::new(path, keypair);
+        config.network.mdns = None;
+        let ipfs = Ipfs::<DefaultParams>::new(config).await?;
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "name": "ipfs-embed" }),
+        )?;
+        let raw = Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &b"raw"[..])?;
+        ipfs.insert(root.clone())?;
+        ipfs.alias("root", Some(root.cid()))?;
+        ipfs.insert(raw.clone())?;
+        ipfs.shutdown().await?;
+        Ok((*root.cid(), *raw.cid()))
+    })
+}
+
+#[test]
+fn test_ls() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (root, raw) = create_repo(tmp.path())?;
+    ipfs_embed(tmp.path())
+        .arg("ls")
+        .assert()
+        .success()
+        .stdout(contains(format!("{} root\n", root)))
+        .stdout(contains(format!("{}\n", raw)));
+    Ok(())
+}
+
+#[test]
+fn test_cat() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (root, _) = create_repo(tmp.path())?;
+    ipfs_embed(tmp.path())
+        .arg("cat")
+        .arg(root.to_string())
+        .assert()
+        .success()
+        .stdout("{\"name\":\"ipfs-embed\"}\n");
+
+    let missing = Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &b"missing"[..])?;
+    ipfs_embed(tmp.path())
+        .arg("cat")
+        .arg(missing.cid().to_string())
+        .assert()
+        .failure()
+        .stderr(contains(missing.cid().to_string()));
+    Ok(())
+}
+
+#[test]
+fn test_unpin() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (root, _) = create_repo(tmp.path())?;
+    ipfs_embed(tmp.path())
+        .args(["unpin", "root"])
+        .assert()
+        .success();
+    ipfs_embed(tmp.path())
+        .arg("ls")
+        .assert()
+        .success()
+        .stdout(contains(format!("{} root", root)).not());
+    ipfs_embed(tmp.path())
+        .args(["unpin", "root"])
+        .assert()
+        .failure()
+        .stderr(contains("no alias root"));
+    Ok(())
+}
+
+/// Starts a daemon listening on localhost and returns the lines it printed
+/// on startup.
+fn spawn_daemon(repo: &Path) -> Result<(Child, Vec<String>)> {
+    let mut daemon = std::process::Command::new(assert_cmd::cargo::cargo_bin("ipfs-embed"))
+        .arg("--path")
+        .arg(repo)
+        .args(["daemon", "--listen", "/ip4/127.0.0.1/tcp/0"])
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut lines = vec![];
+    for line in BufReader::new(daemon.stdout.take().unwrap()).lines() {
+        let line = line?;
+        let done = line.starts_with("api listening on ");
+        lines.push(line);
+        if done {
+            break;
+        }
+    }
+    Ok((daemon, lines))
+}
+
+/// Returns the `/p2p` address of a daemon from its startup lines.
+fn daemon_addr(lines: &[String]) -> String {
+    let id = lines[0].strip_prefix("peer id ").unwrap();
+    let addr = lines[1].strip_prefix("listening on ").unwrap();
+    format!("{}/p2p/{}", addr, id)
+}
+
+#[test]
+fn test_daemon() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (mut daemon, lines) = spawn_daemon(tmp.path())?;
+    let id = &lines[0];
+    assert!(id.starts_with("peer id "));
+    ipfs_embed(tmp.path())
+        .arg("id")
+        .assert()
+        .success()
+        .stdout(contains(format!("{}\n", id)))
+        .stdout(contains("listening on /ip4/127.0.0.1/tcp/"));
+    ipfs_embed(tmp.path())
+        .arg("peers")
+        .assert()
+        .success()
+        .stdout("");
+
+    #[cfg(unix)]
+    {
+        std::process::Command::new("kill")
+            .args(["-INT", &daemon.id().to_string()])
+            .status()?;
+        assert!(daemon.wait()?.success());
+        assert!(!tmp.path().join("api").exists());
+        ipfs_embed(tmp.path())
+            .arg("id")
+            .assert()
+            .failure()
+            .stderr(contains("no daemon is running"));
+    }
+    #[cfg(not(unix))]
+    daemon.kill()?;
+    Ok(())
+}
+
+#[test]
+fn test_add_get() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    // several chunks and progress reports
+    let data = (0..20 * 1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+    std::fs::write(tmp.path().join("input"), &data)?;
+    let repo = tmp.path().join("repo");
+    let output = ipfs_embed(&repo)
+        .arg("add")
+        .arg(tmp.path().join("input"))
+        .args(["--pin", "input"])
+        .assert()
+        .success()
+        .stderr(contains("added 20 of 20 MiB"))
+        .get_output()
+        .stdout
+        .clone();
+    let cid = String::from_utf8(output)?.trim().to_string();
+
+    ipfs_embed(&repo)
+        .args(["get", &cid, "-o"])
+        .arg(tmp.path().join("output"))
+        .assert()
+        .success();
+    assert!(std::fs::read(tmp.path().join("output"))? == data);
+    ipfs_embed(&repo)
+        .arg("ls")
+        .assert()
+        .success()
+        .stdout(contains(format!("{} input\n", cid)));
+    Ok(())
+}
+
+#[test]
+fn test_get_from_provider() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let data = b"fetched over loopback";
+    std::fs::write(tmp.path().join("input"), data)?;
+    let provider = tmp.path().join("provider");
+    let output = ipfs_embed(&provider)
+        .arg("add")
+        .arg(tmp.path().join("input"))
+        .args(["--pin", "input"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let cid = String::from_utf8(output)?.trim().to_string();
+
+    let repo = tmp.path().join("repo");
+    ipfs_embed(&repo)
+        .args(["get", &cid])
+        .assert()
+        .failure()
+        .stderr(contains("use --provider to fetch it"));
+
+    let (mut daemon, lines) = spawn_daemon(&provider)?;
+    ipfs_embed(&repo)
+        .args(["get", &cid, "--provider", &daemon_addr(&lines)])
+        .assert()
+        .success()
+        .stdout(data.to_vec());
+    daemon.kill()?;
+    daemon.wait()?;
+    Ok(())
+}
+
+#[test]
+fn test_pubsub() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (mut daemon, lines) = spawn_daemon(&tmp.path().join("a"))?;
+    let mut sub = std::process::Command::new(assert_cmd::cargo::cargo_bin("ipfs-embed"))
+        .arg("--path")
+        .arg(tmp.path().join("a"))
+        .args(["pubsub", "sub", "topic"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stderr = BufReader::new(sub.stderr.take().unwrap());
+    for line in stderr.lines() {
+        if line? == "subscribed to topic" {
+            break;
+        }
+    }
+
+    // an ephemeral node publishes to the subscriber of the daemon
+    ipfs_embed(&tmp.path().join("b"))
+        .args(["pubsub", "--bootstrap", &daemon_addr(&lines)])
+        .args(["pub", "topic", "hello"])
+        .assert()
+        .success();
+    let mut stdout = BufReader::new(sub.stdout.take().unwrap()).lines();
+    assert_eq!(stdout.next().unwrap()?, "hello");
+
+    sub.kill()?;
+    daemon.kill()?;
+    daemon.wait()?;
+    Ok(())
+}
+
+#[test]
+fn test_dht() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (mut daemon, lines) = spawn_daemon(&tmp.path().join("a"))?;
+    let bootstrap = daemon_addr(&lines);
+    ipfs_embed(&tmp.path().join("b"))
+        .args(["dht", "--bootstrap", &bootstrap, "put", "key", "value"])
+        .assert()
+        .success();
+    ipfs_embed(&tmp.path().join("c"))
+        .args(["dht", "--bootstrap", &bootstrap, "get", "key"])
+        .assert()
+        .success()
+        .stdout("value\n");
+    ipfs_embed(&tmp.path().join("c"))
+        .args(["--json", "dht", "--bootstrap", &bootstrap, "get", "key"])
+        .assert()
+        .success()
+        .stdout(contains("\"value\":\"value\""));
+
+    // not found and other errors have different exit codes
+    ipfs_embed(&tmp.path().join("c"))
+        .args(["dht", "--bootstrap", &bootstrap, "get", "missing"])
+        .assert()
+        .code(2);
+    let missing = Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &b"missing"[..])?;
+    ipfs_embed(&tmp.path().join("c"))
+        .args(["dht", "--bootstrap", &bootstrap, "providers"])
+        .arg(missing.cid().to_string())
+        .assert()
+        .code(2)
+        .stderr(contains("No providers found"));
+    ipfs_embed(&tmp.path().join("c"))
+        .args(["dht", "get", "key"])
+        .assert()
+        .code(1)
+        .stderr(contains("no daemon is running"));
+
+    daemon.kill()?;
+    daemon.wait()?;
+    Ok(())
+}
+
+#[test]
+fn test_repo_gc() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (root, raw) = create_repo(tmp.path())?;
+    ipfs_embed(tmp.path())
+        .args(["repo", "gc"])
+        .assert()
+        .success()
+        .stdout("removed 1 blocks, 3 bytes\n");
+    ipfs_embed(tmp.path())
+        .arg("ls")
+        .assert()
+        .success()
+        .stdout(contains(root.to_string()))
+        .stdout(contains(raw.to_string()).not());
+    ipfs_embed(tmp.path())
+        .args(["--json", "repo", "gc"])
+        .assert()
+        .success()
+        .stdout("{\"blocks\":0,\"bytes\":0}\n");
+    Ok(())
+}
+
+#[test]
+fn test_repo_stat() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    create_repo(tmp.path())?;
+    ipfs_embed(tmp.path())
+        .args(["repo", "stat"])
+        .assert()
+        .success()
+        .stdout(contains("blocks 2\n"))
+        .stdout(contains("pins 0\n"))
+        .stdout(contains("aliases 1\n"));
+    ipfs_embed(tmp.path())
+        .args(["--json", "repo", "stat"])
+        .assert()
+        .success()
+        .stdout(contains("\"blocks\":2"));
+    Ok(())
+}
+
+#[test]
+fn test_repo_verify() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    create_repo(tmp.path())?;
+    ipfs_embed(tmp.path())
+        .args(["repo", "verify"])
+        .assert()
+        .success()
+        .stdout("checked 2 blocks\n");
+    Ok(())
+}
+
+#[test]
+fn test_repo_locked() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    create_repo(tmp.path())?;
+    let (mut daemon, _) = spawn_daemon(tmp.path())?;
+    ipfs_embed(tmp.path())
+        .args(["repo", "stat"])
+        .assert()
+        .failure()
+        .stderr(contains("is in use by another process"));
+    daemon.kill()?;
+    daemon.wait()?;
+    ipfs_embed(tmp.path())
+        .args(["repo", "stat"])
+        .assert()
+        .success();
+    Ok(())
+}
+
+#[test]
+fn test_dag_export_import() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let (root, _) = create_repo(&tmp.path().join("a"))?;
+    let car = tmp.path().join("root.car");
+    ipfs_embed(&tmp.path().join("a"))
+        .args(["dag", "export", &root.to_string(), "-o"])
+        .arg(&car)
+        .assert()
+        .success()
+        .stdout(contains("exported 1 blocks"));
+    ipfs_embed(&tmp.path().join("b"))
+        .args(["dag", "import"])
+        .arg(&car)
+        .args(["--pin", "imported"])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", root));
+    ipfs_embed(&tmp.path().join("b"))
+        .arg("ls")
+        .assert()
+        .success()
+        .stdout(format!("{} imported\n", root));
+    Ok(())
+}
+
+#[test]
+fn test_key() -> Result<()> {
+    let tmp = TempDir::new("ipfs-embed-cli")?;
+    let a = tmp.path().join("a");
+    let output = ipfs_embed(&a)
+        .args(["key", "gen"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let peer = String::from_utf8(output)?;
+    ipfs_embed(&a)
+        .args(["key", "id"])
+        .assert()
+        .success()
+        .stdout(peer.clone());
+    ipfs_embed(&a)
+        .args(["key", "gen"])
+        .assert()
+        .failure()
+        .stderr(contains("has a key"));
+
+    let exported = tmp.path().join("exported");
+    ipfs_embed(&a)
+        .args(["key", "export", "-o"])
+        .arg(&exported)
+        .assert()
+        .success();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&exported)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    // stdout isn't a terminal
+    ipfs_embed(&a)
+        .args(["key", "export"])
+        .assert()
+        .success()
+        .stdout(std::fs::read(&exported)?);
+
+    let b = tmp.path().join("b");
+    ipfs_embed(&b)
+        .args(["key", "import"])
+        .arg(&exported)
+        .assert()
+        .success()
+        .stdout(peer.clone());
+    ipfs_embed(&b)
+        .args(["key", "import"])
+        .arg(&exported)
+        .assert()
+        .failure()
+        .stderr(contains("use --force"));
+    ipfs_embed(&b)
+        .args(["key", "id"])
+        .assert()
+        .success()
+        .stdout(peer);
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Compares the bitswap sync query with the pipelined sync. Each tree is
+//! synced over the simulated network with and without `--sync-in-flight`,
+//! the median of the runs is reported.
+//!
+//! Run with `cargo bench -p harness --bench sync`, like the netsim tests this
+//! needs the permissions to create network namespaces.
+#[cfg(target_os = "linux")]
+fn main() -> anyhow::Result<()> {
+    use escargot::CargoBuild;
+    use std::time::Duration;
+
+    const RUNS: usize = 5;
+    // (tree width, tree depth)
+    const TREES: [(u64, u64); 3] = [(4, 3), (10, 2), (1, 50)];
+    // one way delay of the link
+    const DELAYS_MS: [u64; 2] = [0, 25];
+    const IN_FLIGHT: usize = 32;
+
+    let bin = CargoBuild::new()
+        .manifest_path(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        .bin("bitswap")
+        .release()
+        .run()?;
+
+    let sync = |width: u64, depth: u64, delay_ms: u64, in_flight: Option<usize>| {
+        let mut times = Vec::with_capacity(RUNS);
+        for _ in 0..RUNS {
+            let mut cmd = bin.command();
+            cmd.arg("--enable-mdns")
+                .arg(format!("--tree-width={}", width))
+                .arg(format!("--tree-depth={}", depth))
+                .arg(format!("--delay-ms={}", delay_ms));
+            if let Some(in_flight) = in_flight {
+                cmd.arg(format!("--sync-in-flight={}", in_flight));
+            }
+            let out = cmd.output()?;
+            anyhow::ensure!(
+                out.status.success(),
+                "bitswap failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            let ms = String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .find_map(|line| line.strip_prefix("tree sync complete in "))
+                .and_then(|line| line.split(' ').next())
+                .ok_or_else(|| anyhow::anyhow!("no sync time in the output"))?
+                .parse()?;
+            times.push(Duration::from_millis(ms));
+        }
+        times.sort();
+        Ok::<_, anyhow::Error>(times[RUNS / 2])
+    };
+
+    println!(
+        "{:>6} {:>6} {:>9} {:>12} {:>12} {:>7}",
+        "width", "depth", "delay ms", "query", "pipelined", "ratio"
+    );
+    for &(width, depth) in &TREES {
+        for &delay_ms in &DELAYS_MS {
+            let query = sync(width, depth, delay_ms, None)?;
+            let pipelined = sync(width, depth, delay_ms, Some(IN_FLIGHT))?;
+            println!(
+                "{:>6} {:>6} {:>9} {:>12?} {:>12?} {:>7.2}",
+                width,
+                depth,
+                delay_ms,
+                query,
+                pipelined,
+                query.as_secs_f64() / pipelined.as_secs_f64().max(f64::EPSILON),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
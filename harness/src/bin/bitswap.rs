@@ -94,12 +94,13 @@ fn main() -> anyhow::Result<()> {
         }
 
         println!(
-            "tree sync complete in {} ms {} blocks {} bytes {} providers {} consumers",
+            "tree sync complete in {} ms {} blocks {} bytes {} providers {} consumers {:?} in flight",
             t0.elapsed().as_millis(),
             blocks.len(),
             size,
             opts.n_providers,
             opts.n_consumers,
+            opts.sync_in_flight,
         );
 
         let started = Instant::now();
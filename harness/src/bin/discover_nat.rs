@@ -34,6 +34,7 @@ fn main() -> anyhow::Result<()> {
                 external: vec![],
                 enable_mdns: opts.enable_mdns,
                 disable_port_reuse: opts.disable_port_reuse,
+                sync_in_flight: opts.sync_in_flight,
             };
             let cmd = async_process::Command::from(cfg);
             let machine = sim.spawn_machine(cmd, None).await;
@@ -46,6 +46,9 @@ pub struct HarnessOpts {
 
     #[structopt(long)]
     pub disable_port_reuse: bool,
+
+    #[structopt(long)]
+    pub sync_in_flight: Option<usize>,
 }
 
 pub trait MachineExt {
@@ -147,6 +150,7 @@ where
                 external: vec![],
                 enable_mdns: opts.enable_mdns,
                 disable_port_reuse: opts.disable_port_reuse,
+                sync_in_flight: opts.sync_in_flight,
             };
             let mut delay = DelayBuffer::new();
             delay.set_delay(Duration::from_millis(opts.delay_ms));
@@ -71,6 +71,38 @@ fn netsim_discover_nat_forward_no_reuse() -> anyhow::Result<()> {
     run("discover_nat_forward", ["--disable-port-reuse"])
 }
 
+// Syncs a tree over a link with 50ms round trip time, with the bitswap sync
+// query and with the pipelined sync. The time both take is compared by the
+// `sync` bench.
+#[cfg(target_os = "linux")]
+#[test]
+fn netsim_bitswap_delay() -> anyhow::Result<()> {
+    run(
+        "bitswap",
+        [
+            "--enable-mdns",
+            "--tree-depth=3",
+            "--tree-width=4",
+            "--delay-ms=25",
+        ],
+    )
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn netsim_bitswap_delay_pipelined() -> anyhow::Result<()> {
+    run(
+        "bitswap",
+        [
+            "--enable-mdns",
+            "--tree-depth=3",
+            "--tree-width=4",
+            "--delay-ms=25",
+            "--sync-in-flight=32",
+        ],
+    )
+}
+
 #[cfg(target_os = "linux")]
 #[test]
 fn netsim_discover_nat() -> anyhow::Result<()> {
@@ -0,0 +1,330 @@
+//! A subset of the Kubo HTTP RPC API, so that tools written against go-ipfs
+//! can talk to an embedded node.
+use crate::{executor::JoinHandle, Ipfs, PeerId};
+use anyhow::Result;
+use libipld::{
+    codec::References,
+    multihash::{Code, MultihashDigest},
+    store::StoreParams,
+    Block, Cid, Ipld,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use std::{
+    net::{SocketAddr, TcpListener},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tide::{convert::json, Body, Request, Response, StatusCode};
+
+/// Time `block/get` and `pin/add` wait for missing blocks from connected
+/// peers.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time `swarm/connect` waits for the connection to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A running RPC API server. The server is stopped when the handle is
+/// dropped.
+pub struct ApiHandle {
+    addr: SocketAddr,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ApiHandle {
+    /// The address the API is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for ApiHandle {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// An invalid or missing argument, answered with `400 Bad Request`.
+#[derive(Debug, Error)]
+#[error("{0}")]
+struct BadRequest(String);
+
+pub(crate) fn serve<P: StoreParams>(ipfs: Ipfs<P>, addr: SocketAddr) -> Result<ApiHandle>
+where
+    Ipld: References<P::Codecs>,
+{
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let executor = ipfs.executor.clone();
+    let mut app = tide::with_state(ipfs);
+    app.at("/api/v0/id")
+        .post(|req| async move { respond(id::<P>(req).await) });
+    app.at("/api/v0/block/get")
+        .post(|req| async move { respond(block_get::<P>(req).await) });
+    app.at("/api/v0/block/put")
+        .post(|req| async move { respond(block_put::<P>(req).await) });
+    app.at("/api/v0/pin/add")
+        .post(|req| async move { respond(pin_add::<P>(req).await) });
+    app.at("/api/v0/swarm/peers")
+        .post(|req| async move { respond(swarm_peers::<P>(req).await) });
+    app.at("/api/v0/swarm/connect")
+        .post(|req| async move { respond(swarm_connect::<P>(req).await) });
+    app.at("/api/v0/*command").all(not_implemented::<P>);
+    let task = executor.spawn(async move {
+        if let Err(err) = app.listen(listener).await {
+            tracing::warn!("api server stopped: {}", err);
+        }
+    });
+    Ok(ApiHandle {
+        addr,
+        task: Some(task),
+    })
+}
+
+/// Error in the shape returned by Kubo. `code` is the Kubo error type, 0 for
+/// normal and 1 for client errors.
+fn error(status: StatusCode, code: u8, msg: impl std::fmt::Display) -> Response {
+    Response::builder(status)
+        .body(json!({
+            "Message": msg.to_string(),
+            "Code": code,
+            "Type": "error",
+        }))
+        .build()
+}
+
+fn ok(body: impl Into<Body>) -> Response {
+    Response::builder(StatusCode::Ok).body(body).build()
+}
+
+fn respond(res: Result<Response>) -> tide::Result {
+    Ok(match res {
+        Ok(res) => res,
+        Err(err) if err.is::<BadRequest>() => error(StatusCode::BadRequest, 1, err),
+        Err(err) => error(StatusCode::InternalServerError, 0, err),
+    })
+}
+
+async fn not_implemented<P: StoreParams>(req: Request<Ipfs<P>>) -> tide::Result {
+    let command = req.param("command").unwrap_or_default();
+    Ok(error(
+        StatusCode::NotImplemented,
+        0,
+        format!("command not implemented: {}", command),
+    ))
+}
+
+/// Returns the value of the first `arg` query parameter.
+fn arg<S>(req: &Request<S>) -> Result<String> {
+    req.url()
+        .query_pairs()
+        .find(|(key, _)| key == "arg")
+        .map(|(_, value)| value.into_owned())
+        .ok_or_else(|| BadRequest("argument \"arg\" is required".into()).into())
+}
+
+/// Returns the value of the query parameter `key`.
+fn opt<S>(req: &Request<S>, key: &str) -> Option<String> {
+    req.url()
+        .query_pairs()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn parse_cid(arg: &str) -> Result<Cid> {
+    let cid = arg.strip_prefix("/ipfs/").unwrap_or(arg);
+    cid.parse()
+        .map_err(|err| BadRequest(format!("invalid cid {}: {}", cid, err)).into())
+}
+
+async fn id<P: StoreParams>(req: Request<Ipfs<P>>) -> Result<Response>
+where
+    Ipld: References<P::Codecs>,
+{
+    let ipfs = req.state();
+    let peer_id = ipfs.local_peer_id();
+    let addresses = ipfs
+        .listeners()
+        .into_iter()
+        .chain(
+            ipfs.external_addresses()
+                .into_iter()
+                .map(|record| record.addr),
+        )
+        .map(|addr| format!("{}/p2p/{}", addr, peer_id))
+        .collect::<Vec<_>>();
+    let public_key = base64(&ipfs.local_public_key().to_protobuf_encoding());
+    Ok(ok(json!({
+        "ID": peer_id.to_string(),
+        "PublicKey": public_key,
+        "Addresses": addresses,
+        "AgentVersion": ipfs.local_node_name(),
+        "ProtocolVersion": "ipfs/0.1.0",
+    })))
+}
+
+async fn block_get<P: StoreParams>(req: Request<Ipfs<P>>) -> Result<Response>
+where
+    Ipld: References<P::Codecs>,
+{
+    let cid = parse_cid(&arg(&req)?)?;
+    let ipfs = req.state();
+    let block = ipfs
+        .fetch_with_timeout(&cid, ipfs.peers(), FETCH_TIMEOUT)
+        .await?;
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("application/octet-stream")
+        .body(block.data().to_vec())
+        .build())
+}
+
+async fn block_put<P: StoreParams>(mut req: Request<Ipfs<P>>) -> Result<Response>
+where
+    Ipld: References<P::Codecs>,
+{
+    let codec = match opt(&req, "cid-codec")
+        .or_else(|| opt(&req, "format"))
+        .as_deref()
+    {
+        None | Some("raw") => 0x55,
+        Some("dag-pb") | Some("v0") | Some("protobuf") => 0x70,
+        Some("dag-cbor") | Some("cbor") => 0x71,
+        Some("dag-json") => 0x0129,
+        Some(codec) => return Err(BadRequest(format!("unsupported codec {}", codec)).into()),
+    };
+    match opt(&req, "mhtype").as_deref() {
+        None | Some("sha2-256") => {}
+        Some(mhtype) => {
+            return Err(BadRequest(format!("unsupported hash function {}", mhtype)).into())
+        }
+    }
+    let pin = opt(&req, "pin").as_deref() == Some("true");
+    let content_type = req
+        .header("content-type")
+        .map(|value| value.as_str().to_string())
+        .unwrap_or_default();
+    let body = req.body_bytes().await.map_err(|err| err.into_inner())?;
+    let data = multipart_file(&content_type, &body)
+        .ok_or_else(|| BadRequest("expected a multipart file".into()))?
+        .to_vec();
+    let hash = Code::Sha2_256.digest(&data);
+    let cid = if codec == 0x70 && opt(&req, "format").is_some() {
+        // the deprecated `format` option produced v0 cids for dag-pb
+        Cid::new_v0(hash)?
+    } else {
+        Cid::new_v1(codec, hash)
+    };
+    let size = data.len();
+    let ipfs = req.state();
+    ipfs.insert(Block::new_unchecked(cid, data))?;
+    if pin {
+        ipfs.pin(&cid)?;
+    }
+    Ok(ok(json!({
+        "Key": cid.to_string(),
+        "Size": size,
+    })))
+}
+
+async fn pin_add<P: StoreParams>(req: Request<Ipfs<P>>) -> Result<Response>
+where
+    Ipld: References<P::Codecs>,
+{
+    let cid = parse_cid(&arg(&req)?)?;
+    let ipfs = req.state();
+    let sync = async { ipfs.sync(&cid, ipfs.peers()).await?.await };
+    futures::pin_mut!(sync);
+    match futures::future::select(sync, futures_timer::Delay::new(FETCH_TIMEOUT)).await {
+        futures::future::Either::Left((res, _)) => res?,
+        futures::future::Either::Right(_) => {
+            return Err(anyhow::anyhow!("timed out fetching {}", cid));
+        }
+    }
+    ipfs.pin(&cid)?;
+    Ok(ok(json!({ "Pins": [cid.to_string()] })))
+}
+
+async fn swarm_peers<P: StoreParams>(req: Request<Ipfs<P>>) -> Result<Response>
+where
+    Ipld: References<P::Codecs>,
+{
+    let peers = req
+        .state()
+        .connections()
+        .into_iter()
+        .map(|(peer, addr, _, _)| {
+            json!({
+                "Addr": addr.to_string(),
+                "Peer": peer.to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(ok(json!({ "Peers": peers })))
+}
+
+async fn swarm_connect<P: StoreParams>(req: Request<Ipfs<P>>) -> Result<Response>
+where
+    Ipld: References<P::Codecs>,
+{
+    let arg = arg(&req)?;
+    let mut addr: Multiaddr = arg
+        .parse()
+        .map_err(|err| BadRequest(format!("invalid multiaddr {}: {}", arg, err)))?;
+    let peer = match addr.pop() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+            .map_err(|_| BadRequest(format!("invalid peer id in {}", arg)))?,
+        _ => return Err(BadRequest(format!("{} doesn't end with a peer id", arg)).into()),
+    };
+    let mut ipfs = req.state().clone();
+    ipfs.dial_address(peer, addr);
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    while !ipfs.is_connected(&peer) {
+        if Instant::now() > deadline {
+            return Err(anyhow::anyhow!("connect {} failure: timed out", peer));
+        }
+        futures_timer::Delay::new(Duration::from_millis(50)).await;
+    }
+    Ok(ok(json!({
+        "Strings": [format!("connect {} success", peer)],
+    })))
+}
+
+/// Returns the content of the first part of a `multipart/form-data` body.
+fn multipart_file<'a>(content_type: &str, body: &'a [u8]) -> Option<&'a [u8]> {
+    let boundary = content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary);
+    let start = find(body, delimiter.as_bytes())? + delimiter.len();
+    // skip the part headers
+    let start = start + find(&body[start..], b"\r\n\r\n")? + 4;
+    let end = start + find(&body[start..], format!("\r\n{}", delimiter).as_bytes())?;
+    Some(&body[start..end])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Standard base64 with padding, as used by Kubo for public keys.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,1337 @@
+//! Block store implementations a `StorageService` can be backed by.
+use crate::db::{GcMode, ReadOnly, StorageConfig};
+use chrono::{DateTime, Utc};
+use fnv::{FnvHashMap, FnvHashSet};
+use ipfs_sqlite_block_store::{
+    cache::{CacheTracker, InMemCacheTracker, NoopCacheTracker, SqliteCacheTracker},
+    BlockStore, Config, Synchronous,
+};
+use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld, Result};
+use parking_lot::Mutex;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashSet},
+    marker::PhantomData,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Protects blocks from the garbage collector until it is dropped.
+///
+/// The pin is created by a backend, which can store whatever state it needs
+/// to release the pin in it.
+pub struct TempPin(Box<dyn Any + Send>);
+
+impl TempPin {
+    /// Creates a temp pin from backend specific state.
+    pub fn new<T: Any + Send>(pin: T) -> Self {
+        Self(Box::new(pin))
+    }
+
+    /// Returns the backend specific state if it is of type `T`.
+    pub fn downcast_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.0.downcast_mut()
+    }
+}
+
+impl std::fmt::Debug for TempPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TempPin").finish()
+    }
+}
+
+/// Number of blocks and bytes in a block store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BackendStats {
+    /// Number of stored blocks.
+    pub blocks: u64,
+    /// Total size of the stored blocks as written to the store.
+    pub bytes: u64,
+    /// Total size of the stored blocks before compression. Equal to `bytes`
+    /// unless blocks are compressed.
+    pub logical_bytes: u64,
+}
+
+/// Space used by a block store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DiskUsage {
+    /// Total size of the store.
+    pub total: u64,
+    /// Space left behind by removed blocks, which is reclaimed by compacting
+    /// the store.
+    pub free: u64,
+}
+
+/// Metadata of a stored block as reported by a backend.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BackendBlockInfo {
+    /// Cid of the block.
+    pub cid: Cid,
+    /// Size of the block data in bytes.
+    pub size: usize,
+    /// Whether the block is in the dag of an alias accepted by the filter.
+    pub retained: bool,
+    /// Whether the block is in the dag of a temp pin, or `None` if the
+    /// backend can't tell.
+    pub temp_pinned: Option<bool>,
+    /// When the block was last inserted, or `None` if the backend doesn't
+    /// know.
+    pub inserted_at: Option<DateTime<Utc>>,
+}
+
+/// How durable written data is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum Durability {
+    /// The data was handed to the operating system. It survives a crash of
+    /// the process, but not a power loss.
+    Os,
+    /// The data was synced to stable storage.
+    Sync,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Self::Os
+    }
+}
+
+/// Selects one of the bundled backends.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BackendKind {
+    /// A sqlite block store, in memory if `StorageConfig::path` is `None`.
+    Sqlite,
+    /// A `MemBackend`. `StorageConfig::path` is ignored.
+    Memory,
+}
+
+impl Default for BackendKind {
+    fn default() -> Self {
+        Self::Sqlite
+    }
+}
+
+/// A block store a `StorageService` can be backed by.
+///
+/// Blocks are retained by aliases and temp pins, including all blocks
+/// reachable from them. Unretained blocks may be evicted by the garbage
+/// collector.
+pub trait BlockStoreBackend<S: StoreParams>: Send {
+    /// Starts a transaction. How uncommitted writes are handled depends on
+    /// the backend: a `MemBackend` rolls them back when the transaction is
+    /// dropped, the sqlite backend applies every write right away, so its
+    /// transactions can't be rolled back.
+    fn transaction(&mut self) -> Box<dyn BackendTransaction<S> + '_>;
+
+    /// Evicts unretained blocks until the size targets are met. Returns
+    /// `false` if it stopped early because it exceeded `target_duration`.
+    fn incremental_gc(&mut self, min_blocks: usize, target_duration: Duration) -> Result<bool>;
+
+    /// Returns the number of blocks and bytes in the store.
+    fn stats(&mut self) -> Result<BackendStats>;
+
+    /// Makes all committed transactions at least as durable as `durability`.
+    fn flush(&mut self, durability: Durability) -> Result<()>;
+
+    /// Returns a handle the garbage collector can use without contending with
+    /// queries, or `None` if it has to share this one.
+    fn gc_handle(&mut self) -> Result<Option<Box<dyn BlockStoreBackend<S>>>>;
+
+    /// Returns the space used by the store, or `None` if it doesn't leave
+    /// free space behind.
+    fn disk_usage(&mut self) -> Result<Option<DiskUsage>> {
+        Ok(None)
+    }
+
+    /// Reclaims the free space of the store.
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Changes the number of blocks and bytes `incremental_gc` evicts down
+    /// to, `None` removes a target. Backends that take their size targets
+    /// when they are opened fail.
+    fn set_size_targets(&mut self, _blocks: Option<u64>, _bytes: Option<u64>) -> Result<()> {
+        Err(anyhow::anyhow!("the backend can't change its size targets"))
+    }
+}
+
+/// A transaction on a `BlockStoreBackend`.
+pub trait BackendTransaction<S: StoreParams> {
+    /// Creates an empty temp pin.
+    fn create_temp_pin(&mut self) -> Result<TempPin>;
+
+    /// Adds the dag rooted at `cid` to a temp pin.
+    fn extend_temp_pin(&mut self, pin: &mut TempPin, cid: &Cid) -> Result<()>;
+
+    /// Returns the `Cid`s of all stored blocks.
+    fn iter(&mut self) -> Result<Vec<Cid>>;
+
+    /// Returns whether a block is stored.
+    fn contains(&mut self, cid: &Cid) -> Result<bool>;
+
+    /// Returns the data of a block.
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>>;
+
+    /// Stores a block.
+    fn insert(&mut self, block: Block<S>) -> Result<()>;
+
+    /// Stores `data` in place of the data of `block`, the links are taken
+    /// from `block`. Used to store compressed blocks, backends that don't
+    /// implement it store the block data as is.
+    fn insert_encoded(&mut self, block: Block<S>, _data: Vec<u8>) -> Result<()> {
+        self.insert(block)
+    }
+
+    /// Removes a block, returning whether it was stored. Backends that can't
+    /// remove individual blocks fail.
+    fn remove(&mut self, cid: &Cid) -> Result<bool>;
+
+    /// Returns whether a block is in the dag of a temp pin, or `None` if the
+    /// backend can't tell.
+    fn temp_pinned(&mut self, _cid: &Cid) -> Result<Option<bool>> {
+        Ok(None)
+    }
+
+    /// Returns the blocks of the dag rooted at `cid` that are not stored.
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>>;
+
+    /// Points `alias` to `cid` or removes it if `cid` is `None`.
+    fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()>;
+
+    /// Returns the `Cid` an alias points to.
+    fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>>;
+
+    /// Returns the aliases whose dag contains `cid`, or `None` if the block
+    /// isn't stored.
+    fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>>;
+
+    /// Returns all aliases.
+    fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>>;
+
+    /// Returns when a block was last inserted, or `None` if the backend
+    /// doesn't know.
+    fn inserted_at(&mut self, _cid: &Cid) -> Result<Option<DateTime<Utc>>> {
+        Ok(None)
+    }
+
+    /// Returns the metadata of all stored blocks. A block is retained if it
+    /// is in the dag of an alias for which `retains` returns `true`. The
+    /// default implementation reads every block and its aliases, backends
+    /// should compute it in a single pass.
+    fn block_infos(&mut self, retains: &dyn Fn(&[u8]) -> bool) -> Result<Vec<BackendBlockInfo>> {
+        let mut infos = vec![];
+        for cid in self.iter()? {
+            let size = if let Some(data) = self.get(&cid)? {
+                data.len()
+            } else {
+                continue;
+            };
+            let retained = self
+                .reverse_alias(&cid)?
+                .map(|aliases| aliases.iter().any(|alias| retains(alias)))
+                .unwrap_or_default();
+            infos.push(BackendBlockInfo {
+                cid,
+                size,
+                retained,
+                temp_pinned: None,
+                inserted_at: self.inserted_at(&cid)?,
+            });
+        }
+        Ok(infos)
+    }
+
+    /// Returns the metadata stored under `key`, kept apart from the blocks.
+    fn metadata(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Stores `value` under `key`, or removes `key` if it is `None`.
+    fn set_metadata(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()>;
+
+    /// Returns the metadata whose keys start with `prefix`, ordered by key.
+    fn metadata_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Applies the writes of the transaction.
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+/// Codec of raw blocks, which have no links.
+const RAW: u64 = 0x55;
+
+/// The default backend using `ipfs-sqlite-block-store`.
+pub struct SqliteBackend<S: StoreParams> {
+    store: BlockStore<S>,
+    metadata: Arc<Mutex<SqliteMetadata>>,
+    is_memory: bool,
+    cache_size_blocks: u64,
+    cache_size_bytes: u64,
+}
+
+impl<S: StoreParams> SqliteBackend<S>
+where
+    Ipld: References<S::Codecs>,
+{
+    pub fn open(config: &StorageConfig) -> Result<Self> {
+        // the block store takes its size targets when it is opened, so it
+        // evicts everything it is asked to and `incremental_gc` tells it how
+        // many blocks that are
+        let store_config = Config::default()
+            .with_size_targets(0, 0)
+            .with_pragma_synchronous(match config.default_durability {
+                Durability::Os => Synchronous::Normal,
+                Durability::Sync => Synchronous::Full,
+            });
+        let tracker: Arc<dyn CacheTracker> = match &config.access_db_path {
+            // nothing is evicted, so accesses don't need to be tracked
+            _ if config.gc == GcMode::Disabled => Arc::new(NoopCacheTracker),
+            Some(path) if !config.read_only => {
+                let path = if path.is_file() {
+                    path.clone()
+                } else {
+                    std::fs::create_dir_all(path)?;
+                    path.join("access")
+                };
+                Arc::new(SqliteCacheTracker::open(&path, |access, _| Some(access))?)
+            }
+            _ => Arc::new(InMemCacheTracker::new(|access, _| Some(access))),
+        };
+
+        // create DB connection
+        let (store, metadata) = match &config.path {
+            Some(path) if config.read_only => {
+                let path = if path.is_dir() {
+                    path.join("db")
+                } else {
+                    path.clone()
+                };
+                // opening a store on disk initializes its schema, which
+                // writes to it, so the store is copied into memory instead
+                let store = BlockStore::open_test(
+                    read_only_uri(&path),
+                    store_config.with_cache_tracker(tracker),
+                )?;
+                (store, SqliteMetadata::open_read_only(&path)?)
+            }
+            Some(path) => {
+                let path = if path.is_file() {
+                    path.clone()
+                } else {
+                    std::fs::create_dir_all(path)?;
+                    path.join("db")
+                };
+                let store = BlockStore::open(&path, store_config.with_cache_tracker(tracker))?;
+                (store, SqliteMetadata::open(&path)?)
+            }
+            None => (
+                BlockStore::memory(store_config.with_cache_tracker(tracker))?,
+                SqliteMetadata::Memory(Default::default()),
+            ),
+        };
+        Ok(Self {
+            store,
+            metadata: Arc::new(Mutex::new(metadata)),
+            is_memory: config.path.is_none() || config.read_only,
+            cache_size_blocks: config.cache_size_blocks,
+            cache_size_bytes: config.cache_size_bytes.unwrap_or(u64::MAX),
+        })
+    }
+}
+
+impl<S: StoreParams> BlockStoreBackend<S> for SqliteBackend<S>
+where
+    Ipld: References<S::Codecs>,
+{
+    fn transaction(&mut self) -> Box<dyn BackendTransaction<S> + '_> {
+        Box::new(SqliteTransaction {
+            txn: self.store.transaction(),
+            metadata: &self.metadata,
+        })
+    }
+
+    fn incremental_gc(&mut self, min_blocks: usize, target_duration: Duration) -> Result<bool> {
+        let start = Instant::now();
+        let mut evicted = 0;
+        loop {
+            let stats = self.store.get_store_stats()?;
+            let (blocks, bytes) = (stats.count(), stats.size());
+            if blocks <= self.cache_size_blocks && bytes <= self.cache_size_bytes {
+                return Ok(true);
+            }
+            if evicted >= min_blocks && start.elapsed() > target_duration {
+                return Ok(false);
+            }
+            // the blocks over the byte target are estimated from the average
+            // block size, the next round evicts more if that wasn't enough
+            let average = (bytes / blocks.max(1)).max(1);
+            let excess_bytes = bytes.saturating_sub(self.cache_size_bytes);
+            let excess = blocks
+                .saturating_sub(self.cache_size_blocks)
+                .max(excess_bytes / average + (excess_bytes % average).min(1))
+                .max(1);
+            // without time left the store stops after evicting `excess` blocks
+            self.store.incremental_gc(excess as usize, Duration::ZERO)?;
+            let remaining = self.store.get_store_stats()?.count();
+            if remaining >= blocks {
+                // only retained blocks are left
+                return Ok(true);
+            }
+            evicted += (blocks - remaining) as usize;
+        }
+    }
+
+    fn stats(&mut self) -> Result<BackendStats> {
+        let stats = self.store.get_store_stats()?;
+        Ok(BackendStats {
+            blocks: stats.count(),
+            bytes: stats.size(),
+            logical_bytes: stats.size(),
+        })
+    }
+
+    fn flush(&mut self, durability: Durability) -> Result<()> {
+        match durability {
+            // committed transactions are written to the wal before the commit
+            // returns
+            Durability::Os => Ok(()),
+            Durability::Sync => Ok(self.store.flush()?),
+        }
+    }
+
+    fn set_size_targets(&mut self, blocks: Option<u64>, bytes: Option<u64>) -> Result<()> {
+        self.cache_size_blocks = blocks.unwrap_or(u64::MAX);
+        self.cache_size_bytes = bytes.unwrap_or(u64::MAX);
+        Ok(())
+    }
+
+    fn gc_handle(&mut self) -> Result<Option<Box<dyn BlockStoreBackend<S>>>> {
+        if self.is_memory {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(Self {
+            store: self.store.additional_connection()?,
+            metadata: self.metadata.clone(),
+            is_memory: false,
+            cache_size_blocks: self.cache_size_blocks,
+            cache_size_bytes: self.cache_size_bytes,
+        })))
+    }
+
+    fn disk_usage(&mut self) -> Result<Option<DiskUsage>> {
+        let stats = self.store.get_store_stats()?;
+        let page_size = stats.page_size();
+        Ok(Some(DiskUsage {
+            total: (stats.used_pages() + stats.free_pages()) * page_size,
+            free: stats.free_pages() * page_size,
+        }))
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        // readers on other connections keep seeing the store as it was
+        // before, writers wait
+        Ok(self.store.vacuum()?)
+    }
+}
+
+/// Returns a sqlite uri opening the database at `path` read only. It isn't
+/// opened as immutable, which would ignore writes still in the wal.
+fn read_only_uri(path: &Path) -> String {
+    let path = path
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    format!("file:{}?mode=ro", path)
+}
+
+/// Name of the table holding the metadata of a `SqliteBackend`.
+const METADATA_TABLE: &str = "ipfs_embed_metadata";
+
+/// Metadata of a `SqliteBackend`, kept in a table of its own next to the
+/// tables of the block store, or in memory if the store is.
+enum SqliteMetadata {
+    Table(Connection),
+    Memory(BTreeMap<Vec<u8>, Vec<u8>>),
+}
+
+impl SqliteMetadata {
+    fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID",
+            METADATA_TABLE
+        ))?;
+        Ok(Self::Table(conn))
+    }
+
+    /// Opens the metadata of a store that is opened read only. Stores that
+    /// were never opened by this version have no metadata table.
+    fn open_read_only(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            read_only_uri(path),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        )?;
+        let tables: i64 = conn.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+            [METADATA_TABLE],
+            |row| row.get(0),
+        )?;
+        Ok(if tables > 0 {
+            Self::Table(conn)
+        } else {
+            Self::Memory(Default::default())
+        })
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Table(conn) => Ok(conn
+                .query_row(
+                    &format!("SELECT value FROM {} WHERE key = ?", METADATA_TABLE),
+                    [key],
+                    |row| row.get(0),
+                )
+                .optional()?),
+            Self::Memory(map) => Ok(map.get(key).cloned()),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        match (self, value) {
+            (Self::Table(conn), Some(value)) => {
+                conn.execute(
+                    &format!(
+                        "INSERT OR REPLACE INTO {} (key, value) VALUES (?, ?)",
+                        METADATA_TABLE
+                    ),
+                    [key, value],
+                )?;
+            }
+            (Self::Table(conn), None) => {
+                conn.execute(
+                    &format!("DELETE FROM {} WHERE key = ?", METADATA_TABLE),
+                    [key],
+                )?;
+            }
+            (Self::Memory(map), Some(value)) => {
+                map.insert(key.to_vec(), value.to_vec());
+            }
+            (Self::Memory(map), None) => {
+                map.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn with_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self {
+            Self::Table(conn) => {
+                // blobs are compared bytewise, so the keys with the prefix
+                // follow it
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT key, value FROM {} WHERE key >= ? ORDER BY key",
+                    METADATA_TABLE
+                ))?;
+                let mut rows = stmt.query([prefix])?;
+                let mut entries = vec![];
+                while let Some(row) = rows.next()? {
+                    let key: Vec<u8> = row.get(0)?;
+                    if !key.starts_with(prefix) {
+                        break;
+                    }
+                    entries.push((key, row.get(1)?));
+                }
+                Ok(entries)
+            }
+            Self::Memory(map) => Ok(map
+                .range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()),
+        }
+    }
+}
+
+/// A transaction on a `SqliteBackend`. Like the writes of the block store,
+/// metadata writes are applied immediately.
+struct SqliteTransaction<'a, S> {
+    txn: ipfs_sqlite_block_store::Transaction<'a, S>,
+    metadata: &'a Mutex<SqliteMetadata>,
+}
+
+fn sqlite_temp_pin(pin: &mut TempPin) -> Result<&mut ipfs_sqlite_block_store::TempPin> {
+    pin.downcast_mut()
+        .ok_or_else(|| anyhow::anyhow!("temp pin was created by a different backend"))
+}
+
+impl<'a, S: StoreParams> BackendTransaction<S> for SqliteTransaction<'a, S>
+where
+    Ipld: References<S::Codecs>,
+{
+    fn create_temp_pin(&mut self) -> Result<TempPin> {
+        Ok(TempPin::new(self.txn.temp_pin()))
+    }
+
+    fn extend_temp_pin(&mut self, pin: &mut TempPin, cid: &Cid) -> Result<()> {
+        Ok(self.txn.extend_temp_pin(sqlite_temp_pin(pin)?, cid)?)
+    }
+
+    fn iter(&mut self) -> Result<Vec<Cid>> {
+        Ok(self.txn.get_block_cids()?)
+    }
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        Ok(self.txn.has_block(cid)?)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.txn.get_block(cid)?)
+    }
+
+    fn insert(&mut self, block: Block<S>) -> Result<()> {
+        Ok(self.txn.put_block(block, None)?)
+    }
+
+    fn insert_encoded(&mut self, block: Block<S>, data: Vec<u8>) -> Result<()> {
+        // the store takes the links from the stored data, so only blocks
+        // without links can be stored encoded
+        if block.cid().codec() != RAW {
+            return self.insert(block);
+        }
+        Ok(self
+            .txn
+            .put_block(Block::new_unchecked(*block.cid(), data), None)?)
+    }
+
+    fn remove(&mut self, _cid: &Cid) -> Result<bool> {
+        Err(anyhow::anyhow!(
+            "the sqlite block store can't remove individual blocks"
+        ))
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        Ok(self.txn.get_missing_blocks(cid)?)
+    }
+
+    fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        Ok(self.txn.alias(alias, cid)?)
+    }
+
+    fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
+        Ok(self.txn.resolve(alias)?)
+    }
+
+    fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        Ok(self.txn.reverse_alias(cid)?)
+    }
+
+    fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        Ok(self.txn.aliases()?)
+    }
+
+    fn block_infos(&mut self, retains: &dyn Fn(&[u8]) -> bool) -> Result<Vec<BackendBlockInfo>> {
+        let mut retained = FnvHashSet::default();
+        for (alias, root) in self.txn.aliases::<Vec<_>>()? {
+            if retains(&alias) {
+                retained.extend(self.txn.get_descendants::<Vec<_>>(&root)?);
+            }
+        }
+        // the store neither records block sizes nor exposes its temp pins,
+        // so the sizes are taken from the data
+        let mut infos = vec![];
+        for cid in self.txn.get_block_cids::<Vec<_>>()? {
+            if let Some(data) = self.txn.get_block(&cid)? {
+                infos.push(BackendBlockInfo {
+                    cid,
+                    size: data.len(),
+                    retained: retained.contains(&cid),
+                    temp_pinned: None,
+                    inserted_at: None,
+                });
+            }
+        }
+        Ok(infos)
+    }
+
+    fn metadata(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.metadata.lock().get(key)
+    }
+
+    fn set_metadata(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        self.metadata.lock().set(key, value)
+    }
+
+    fn metadata_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.metadata.lock().with_prefix(prefix)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        Ok(self.txn.commit()?)
+    }
+}
+
+/// Wraps a backend, rejecting all writes with `ReadOnly`.
+pub(crate) struct ReadOnlyBackend<S: StoreParams>(pub Box<dyn BlockStoreBackend<S>>);
+
+impl<S: StoreParams> BlockStoreBackend<S> for ReadOnlyBackend<S> {
+    fn transaction(&mut self) -> Box<dyn BackendTransaction<S> + '_> {
+        Box::new(ReadOnlyTransaction(self.0.transaction()))
+    }
+
+    fn incremental_gc(&mut self, _min_blocks: usize, _target_duration: Duration) -> Result<bool> {
+        Err(ReadOnly.into())
+    }
+
+    fn stats(&mut self) -> Result<BackendStats> {
+        self.0.stats()
+    }
+
+    fn flush(&mut self, _durability: Durability) -> Result<()> {
+        Ok(())
+    }
+
+    fn gc_handle(&mut self) -> Result<Option<Box<dyn BlockStoreBackend<S>>>> {
+        Ok(None)
+    }
+
+    fn disk_usage(&mut self) -> Result<Option<DiskUsage>> {
+        self.0.disk_usage()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn set_size_targets(&mut self, _blocks: Option<u64>, _bytes: Option<u64>) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+}
+
+struct ReadOnlyTransaction<'a, S: StoreParams>(Box<dyn BackendTransaction<S> + 'a>);
+
+impl<'a, S: StoreParams> BackendTransaction<S> for ReadOnlyTransaction<'a, S> {
+    fn create_temp_pin(&mut self) -> Result<TempPin> {
+        Err(ReadOnly.into())
+    }
+
+    fn extend_temp_pin(&mut self, _pin: &mut TempPin, _cid: &Cid) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn iter(&mut self) -> Result<Vec<Cid>> {
+        self.0.iter()
+    }
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.0.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.0.get(cid)
+    }
+
+    fn insert(&mut self, _block: Block<S>) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn remove(&mut self, _cid: &Cid) -> Result<bool> {
+        Err(ReadOnly.into())
+    }
+
+    fn temp_pinned(&mut self, cid: &Cid) -> Result<Option<bool>> {
+        self.0.temp_pinned(cid)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.0.missing_blocks(cid)
+    }
+
+    fn alias(&mut self, _alias: &[u8], _cid: Option<&Cid>) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
+        self.0.resolve(alias)
+    }
+
+    fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        self.0.reverse_alias(cid)
+    }
+
+    fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.0.aliases()
+    }
+
+    fn inserted_at(&mut self, cid: &Cid) -> Result<Option<DateTime<Utc>>> {
+        self.0.inserted_at(cid)
+    }
+
+    fn block_infos(&mut self, retains: &dyn Fn(&[u8]) -> bool) -> Result<Vec<BackendBlockInfo>> {
+        self.0.block_infos(retains)
+    }
+
+    fn metadata(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.0.metadata(key)
+    }
+
+    fn set_metadata(&mut self, _key: &[u8], _value: Option<&[u8]>) -> Result<()> {
+        Err(ReadOnly.into())
+    }
+
+    fn metadata_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.0.metadata_with_prefix(prefix)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        // nothing was written, ending the transaction is enough
+        Ok(())
+    }
+}
+
+/// Insertion times of blocks, shared by all handles of a store.
+type InsertTimes = Arc<Mutex<FnvHashMap<Cid, DateTime<Utc>>>>;
+
+/// Wraps a backend, recording when blocks are inserted. The times are only
+/// kept in memory, blocks stored before the store was opened have none.
+pub(crate) struct InsertLogBackend<S: StoreParams> {
+    inner: Box<dyn BlockStoreBackend<S>>,
+    times: InsertTimes,
+}
+
+impl<S: StoreParams> InsertLogBackend<S> {
+    pub fn new(inner: Box<dyn BlockStoreBackend<S>>) -> Self {
+        Self {
+            inner,
+            times: Default::default(),
+        }
+    }
+}
+
+impl<S: StoreParams> BlockStoreBackend<S> for InsertLogBackend<S> {
+    fn transaction(&mut self) -> Box<dyn BackendTransaction<S> + '_> {
+        Box::new(InsertLogTransaction {
+            inner: self.inner.transaction(),
+            times: self.times.clone(),
+            inserted: vec![],
+        })
+    }
+
+    fn incremental_gc(&mut self, min_blocks: usize, target_duration: Duration) -> Result<bool> {
+        let complete = self.inner.incremental_gc(min_blocks, target_duration)?;
+        // forget the evicted blocks
+        let stored = self.inner.transaction().iter()?;
+        let stored = stored.into_iter().collect::<FnvHashSet<_>>();
+        self.times.lock().retain(|cid, _| stored.contains(cid));
+        Ok(complete)
+    }
+
+    fn stats(&mut self) -> Result<BackendStats> {
+        self.inner.stats()
+    }
+
+    fn flush(&mut self, durability: Durability) -> Result<()> {
+        self.inner.flush(durability)
+    }
+
+    fn gc_handle(&mut self) -> Result<Option<Box<dyn BlockStoreBackend<S>>>> {
+        let times = self.times.clone();
+        Ok(self.inner.gc_handle()?.map(|inner| {
+            Box::new(InsertLogBackend { inner, times }) as Box<dyn BlockStoreBackend<S>>
+        }))
+    }
+
+    fn disk_usage(&mut self) -> Result<Option<DiskUsage>> {
+        self.inner.disk_usage()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    fn set_size_targets(&mut self, blocks: Option<u64>, bytes: Option<u64>) -> Result<()> {
+        self.inner.set_size_targets(blocks, bytes)
+    }
+}
+
+struct InsertLogTransaction<'a, S: StoreParams> {
+    inner: Box<dyn BackendTransaction<S> + 'a>,
+    times: InsertTimes,
+    /// Blocks inserted by this transaction, recorded on commit.
+    inserted: Vec<Cid>,
+}
+
+impl<'a, S: StoreParams> BackendTransaction<S> for InsertLogTransaction<'a, S> {
+    fn create_temp_pin(&mut self) -> Result<TempPin> {
+        self.inner.create_temp_pin()
+    }
+
+    fn extend_temp_pin(&mut self, pin: &mut TempPin, cid: &Cid) -> Result<()> {
+        self.inner.extend_temp_pin(pin, cid)
+    }
+
+    fn iter(&mut self) -> Result<Vec<Cid>> {
+        self.inner.iter()
+    }
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.inner.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.inner.get(cid)
+    }
+
+    fn insert(&mut self, block: Block<S>) -> Result<()> {
+        let cid = *block.cid();
+        self.inner.insert(block)?;
+        self.inserted.push(cid);
+        Ok(())
+    }
+
+    fn insert_encoded(&mut self, block: Block<S>, data: Vec<u8>) -> Result<()> {
+        let cid = *block.cid();
+        self.inner.insert_encoded(block, data)?;
+        self.inserted.push(cid);
+        Ok(())
+    }
+
+    fn remove(&mut self, cid: &Cid) -> Result<bool> {
+        self.inner.remove(cid)
+    }
+
+    fn temp_pinned(&mut self, cid: &Cid) -> Result<Option<bool>> {
+        self.inner.temp_pinned(cid)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.inner.missing_blocks(cid)
+    }
+
+    fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        self.inner.alias(alias, cid)
+    }
+
+    fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
+        self.inner.resolve(alias)
+    }
+
+    fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        self.inner.reverse_alias(cid)
+    }
+
+    fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.inner.aliases()
+    }
+
+    fn inserted_at(&mut self, cid: &Cid) -> Result<Option<DateTime<Utc>>> {
+        Ok(self.times.lock().get(cid).copied())
+    }
+
+    fn block_infos(&mut self, retains: &dyn Fn(&[u8]) -> bool) -> Result<Vec<BackendBlockInfo>> {
+        let mut infos = self.inner.block_infos(retains)?;
+        let times = self.times.lock();
+        for info in &mut infos {
+            info.inserted_at = times.get(&info.cid).copied();
+        }
+        Ok(infos)
+    }
+
+    fn metadata(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.metadata(key)
+    }
+
+    fn set_metadata(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        self.inner.set_metadata(key, value)
+    }
+
+    fn metadata_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.metadata_with_prefix(prefix)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        let Self {
+            inner,
+            times,
+            inserted,
+        } = *self;
+        inner.commit()?;
+        let now = Utc::now();
+        let mut times = times.lock();
+        for cid in inserted {
+            times.insert(cid, now);
+        }
+        Ok(())
+    }
+}
+
+/// A block store keeping everything in memory. Unretained blocks are evicted
+/// least recently used first once the size targets are exceeded.
+pub struct MemBackend<S> {
+    blocks: FnvHashMap<Cid, MemBlock>,
+    aliases: BTreeMap<Vec<u8>, Cid>,
+    metadata: BTreeMap<Vec<u8>, Vec<u8>>,
+    temp_pins: FnvHashMap<u64, Vec<Cid>>,
+    released: Arc<Mutex<Vec<u64>>>,
+    next_temp_pin: u64,
+    clock: u64,
+    cache_size_blocks: u64,
+    cache_size_bytes: u64,
+    _marker: PhantomData<S>,
+}
+
+struct MemBlock {
+    data: Vec<u8>,
+    accessed: u64,
+}
+
+/// Releases the temp pin with `id` when dropped.
+struct MemTempPin {
+    id: u64,
+    released: Arc<Mutex<Vec<u64>>>,
+}
+
+impl Drop for MemTempPin {
+    fn drop(&mut self) {
+        self.released.lock().push(self.id);
+    }
+}
+
+impl<S: StoreParams> MemBackend<S>
+where
+    Ipld: References<S::Codecs>,
+{
+    /// Creates an empty store with the given size targets.
+    pub fn new(cache_size_blocks: u64, cache_size_bytes: Option<u64>) -> Self {
+        Self {
+            blocks: Default::default(),
+            aliases: Default::default(),
+            metadata: Default::default(),
+            temp_pins: Default::default(),
+            released: Default::default(),
+            next_temp_pin: 0,
+            clock: 0,
+            cache_size_blocks,
+            cache_size_bytes: cache_size_bytes.unwrap_or(u64::MAX),
+            _marker: PhantomData,
+        }
+    }
+
+    fn remove_released_temp_pins(&mut self) {
+        for id in self.released.lock().drain(..) {
+            self.temp_pins.remove(&id);
+        }
+    }
+
+    /// Returns the stored blocks reachable from `roots`, and the missing ones.
+    fn reachable(
+        &self,
+        roots: impl IntoIterator<Item = Cid>,
+    ) -> Result<(FnvHashSet<Cid>, Vec<Cid>)> {
+        let mut found = FnvHashSet::default();
+        let mut missing = vec![];
+        let mut visited = FnvHashSet::default();
+        let mut stack = roots.into_iter().collect::<Vec<_>>();
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            if let Some(block) = self.blocks.get(&cid) {
+                found.insert(cid);
+                let mut refs = vec![];
+                Block::<S>::new_unchecked(cid, block.data.clone()).references(&mut refs)?;
+                stack.extend(refs);
+            } else {
+                missing.push(cid);
+            }
+        }
+        Ok((found, missing))
+    }
+
+    fn is_over_target(&self, bytes: u64) -> bool {
+        self.blocks.len() as u64 > self.cache_size_blocks || bytes > self.cache_size_bytes
+    }
+}
+
+impl<S: StoreParams> BlockStoreBackend<S> for MemBackend<S>
+where
+    Ipld: References<S::Codecs>,
+{
+    fn transaction(&mut self) -> Box<dyn BackendTransaction<S> + '_> {
+        self.remove_released_temp_pins();
+        Box::new(MemTransaction {
+            store: self,
+            undo: vec![],
+        })
+    }
+
+    fn incremental_gc(&mut self, _min_blocks: usize, _target_duration: Duration) -> Result<bool> {
+        self.remove_released_temp_pins();
+        let mut bytes = self.stats()?.bytes;
+        if !self.is_over_target(bytes) {
+            return Ok(true);
+        }
+        let roots = self
+            .aliases
+            .values()
+            .chain(self.temp_pins.values().flatten())
+            .copied()
+            .collect::<Vec<_>>();
+        let (retained, _) = self.reachable(roots)?;
+        let mut candidates = self
+            .blocks
+            .iter()
+            .filter(|(cid, _)| !retained.contains(cid))
+            .map(|(cid, block)| (block.accessed, *cid))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        for (_, cid) in candidates {
+            if !self.is_over_target(bytes) {
+                break;
+            }
+            if let Some(block) = self.blocks.remove(&cid) {
+                bytes -= block.data.len() as u64;
+            }
+        }
+        Ok(true)
+    }
+
+    fn stats(&mut self) -> Result<BackendStats> {
+        let bytes = self.blocks.values().map(|b| b.data.len() as u64).sum();
+        Ok(BackendStats {
+            blocks: self.blocks.len() as u64,
+            bytes,
+            logical_bytes: bytes,
+        })
+    }
+
+    fn flush(&mut self, _durability: Durability) -> Result<()> {
+        Ok(())
+    }
+
+    fn gc_handle(&mut self) -> Result<Option<Box<dyn BlockStoreBackend<S>>>> {
+        Ok(None)
+    }
+
+    fn set_size_targets(&mut self, blocks: Option<u64>, bytes: Option<u64>) -> Result<()> {
+        self.cache_size_blocks = blocks.unwrap_or(u64::MAX);
+        self.cache_size_bytes = bytes.unwrap_or(u64::MAX);
+        Ok(())
+    }
+}
+
+enum Undo {
+    Insert(Cid),
+    Remove(Cid, MemBlock),
+    Alias(Vec<u8>, Option<Cid>),
+    Metadata(Vec<u8>, Option<Vec<u8>>),
+    TempPin(u64, usize),
+}
+
+/// A transaction on a `MemBackend`. Writes are applied immediately and
+/// rolled back on drop unless the transaction is committed.
+struct MemTransaction<'a, S> {
+    store: &'a mut MemBackend<S>,
+    undo: Vec<Undo>,
+}
+
+impl<'a, S> Drop for MemTransaction<'a, S> {
+    fn drop(&mut self) {
+        for undo in self.undo.drain(..).rev() {
+            match undo {
+                Undo::Insert(cid) => {
+                    self.store.blocks.remove(&cid);
+                }
+                Undo::Remove(cid, block) => {
+                    self.store.blocks.insert(cid, block);
+                }
+                Undo::Alias(alias, Some(cid)) => {
+                    self.store.aliases.insert(alias, cid);
+                }
+                Undo::Alias(alias, None) => {
+                    self.store.aliases.remove(&alias);
+                }
+                Undo::Metadata(key, Some(value)) => {
+                    self.store.metadata.insert(key, value);
+                }
+                Undo::Metadata(key, None) => {
+                    self.store.metadata.remove(&key);
+                }
+                Undo::TempPin(id, len) => {
+                    if let Some(cids) = self.store.temp_pins.get_mut(&id) {
+                        cids.truncate(len);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn mem_temp_pin(pin: &mut TempPin) -> Result<&mut MemTempPin> {
+    pin.downcast_mut()
+        .ok_or_else(|| anyhow::anyhow!("temp pin was created by a different backend"))
+}
+
+impl<'a, S: StoreParams> BackendTransaction<S> for MemTransaction<'a, S>
+where
+    Ipld: References<S::Codecs>,
+{
+    fn create_temp_pin(&mut self) -> Result<TempPin> {
+        let id = self.store.next_temp_pin;
+        self.store.next_temp_pin += 1;
+        self.store.temp_pins.insert(id, vec![]);
+        Ok(TempPin::new(MemTempPin {
+            id,
+            released: self.store.released.clone(),
+        }))
+    }
+
+    fn extend_temp_pin(&mut self, pin: &mut TempPin, cid: &Cid) -> Result<()> {
+        let id = mem_temp_pin(pin)?.id;
+        if let Some(cids) = self.store.temp_pins.get_mut(&id) {
+            self.undo.push(Undo::TempPin(id, cids.len()));
+            cids.push(*cid);
+        }
+        Ok(())
+    }
+
+    fn iter(&mut self) -> Result<Vec<Cid>> {
+        Ok(self.store.blocks.keys().copied().collect())
+    }
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        Ok(self.store.blocks.contains_key(cid))
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.store.clock += 1;
+        let clock = self.store.clock;
+        Ok(self.store.blocks.get_mut(cid).map(|block| {
+            block.accessed = clock;
+            block.data.clone()
+        }))
+    }
+
+    fn insert(&mut self, block: Block<S>) -> Result<()> {
+        if self.store.blocks.contains_key(block.cid()) {
+            return Ok(());
+        }
+        self.store.clock += 1;
+        let (cid, data) = block.into_inner();
+        self.store.blocks.insert(
+            cid,
+            MemBlock {
+                data,
+                accessed: self.store.clock,
+            },
+        );
+        self.undo.push(Undo::Insert(cid));
+        Ok(())
+    }
+
+    fn remove(&mut self, cid: &Cid) -> Result<bool> {
+        if let Some(block) = self.store.blocks.remove(cid) {
+            self.undo.push(Undo::Remove(*cid, block));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn temp_pinned(&mut self, cid: &Cid) -> Result<Option<bool>> {
+        let roots = self.store.temp_pins.values().flatten().copied();
+        Ok(Some(self.store.reachable(roots)?.0.contains(cid)))
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        Ok(self.store.reachable(Some(*cid))?.1)
+    }
+
+    fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        let prev = if let Some(cid) = cid {
+            self.store.aliases.insert(alias.to_vec(), *cid)
+        } else {
+            self.store.aliases.remove(alias)
+        };
+        self.undo.push(Undo::Alias(alias.to_vec(), prev));
+        Ok(())
+    }
+
+    fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
+        Ok(self.store.aliases.get(alias).copied())
+    }
+
+    fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        if !self.store.blocks.contains_key(cid) {
+            return Ok(None);
+        }
+        let mut aliases = HashSet::new();
+        for (alias, root) in &self.store.aliases {
+            if self.store.reachable(Some(*root))?.0.contains(cid) {
+                aliases.insert(alias.clone());
+            }
+        }
+        Ok(Some(aliases))
+    }
+
+    fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        Ok(self
+            .store
+            .aliases
+            .iter()
+            .map(|(alias, cid)| (alias.clone(), *cid))
+            .collect())
+    }
+
+    fn block_infos(&mut self, retains: &dyn Fn(&[u8]) -> bool) -> Result<Vec<BackendBlockInfo>> {
+        let roots = self
+            .store
+            .aliases
+            .iter()
+            .filter(|(alias, _)| retains(alias))
+            .map(|(_, cid)| *cid);
+        let (retained, _) = self.store.reachable(roots)?;
+        let roots = self.store.temp_pins.values().flatten().copied();
+        let (temp_pinned, _) = self.store.reachable(roots)?;
+        Ok(self
+            .store
+            .blocks
+            .iter()
+            .map(|(cid, block)| BackendBlockInfo {
+                cid: *cid,
+                size: block.data.len(),
+                retained: retained.contains(cid),
+                temp_pinned: Some(temp_pinned.contains(cid)),
+                inserted_at: None,
+            })
+            .collect())
+    }
+
+    fn metadata(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.store.metadata.get(key).cloned())
+    }
+
+    fn set_metadata(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let prev = if let Some(value) = value {
+            self.store.metadata.insert(key.to_vec(), value.to_vec())
+        } else {
+            self.store.metadata.remove(key)
+        };
+        self.undo.push(Undo::Metadata(key.to_vec(), prev));
+        Ok(())
+    }
+
+    fn metadata_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .store
+            .metadata
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn commit(mut self: Box<Self>) -> Result<()> {
+        self.undo.clear();
+        Ok(())
+    }
+}
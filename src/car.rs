@@ -0,0 +1,483 @@
+//! Import and export of CAR files.
+use crate::{backend::TempPin, db::StorageService};
+use fnv::FnvHashSet;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, References},
+    error::BlockNotFound,
+    ipld,
+    store::StoreParams,
+    Block, Cid, Ipld, Result,
+};
+use std::{convert::TryInto, io::Cursor};
+use thiserror::Error;
+
+/// Number of blocks inserted in a single transaction when importing.
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Statistics of a written CAR file.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CarStats {
+    /// Number of blocks written.
+    pub blocks: usize,
+    /// Number of bytes written including the header.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Error)]
+#[error("Dag is incomplete, missing blocks {0:?}.")]
+pub struct MissingBlocks(pub Vec<Cid>);
+
+#[derive(Debug, Error)]
+#[error("Invalid car file: {0}.")]
+pub struct InvalidCar(&'static str);
+
+#[derive(Debug, Error)]
+#[error("Block {0} exceeds the maximum block size.")]
+pub struct BlockTooLarge(pub Cid);
+
+/// Writes the dag rooted at `root` as a CARv1 file. Blocks are written in
+/// depth first order, so exporting the same dag always yields the same file.
+pub(crate) async fn export<P, W>(
+    storage: &StorageService<P>,
+    root: &Cid,
+    mut writer: W,
+) -> Result<CarStats>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+    W: AsyncWrite + Unpin,
+{
+    // keep the dag around while it is written
+    let mut tmp = storage.create_temp_pin()?;
+    storage.temp_pin(&mut tmp, std::iter::once(*root))?;
+
+    // walk the dag first so that an incomplete dag doesn't produce a truncated file
+    let mut order = vec![];
+    let mut missing = vec![];
+    let mut visited = FnvHashSet::default();
+    let mut stack = vec![*root];
+    while let Some(cid) = stack.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        if let Some(data) = storage.get(&cid)? {
+            let block = Block::<P>::new_unchecked(cid, data);
+            let mut links = vec![];
+            block.references(&mut links)?;
+            stack.extend(links.into_iter().rev());
+            order.push(cid);
+        } else {
+            missing.push(cid);
+        }
+    }
+    if !missing.is_empty() {
+        return Err(MissingBlocks(missing).into());
+    }
+
+    let header = DagCborCodec.encode(&ipld!({
+        "roots": [Ipld::Link(*root)],
+        "version": 1,
+    }))?;
+    let mut stats = CarStats {
+        bytes: write_section(&mut writer, &[&header]).await?,
+        ..Default::default()
+    };
+    for cid in order {
+        let data = storage.get(&cid)?.ok_or(BlockNotFound(cid))?;
+        stats.bytes += write_section(&mut writer, &[&cid.to_bytes(), &data]).await?;
+        stats.blocks += 1;
+    }
+    writer.flush().await?;
+    Ok(stats)
+}
+
+/// Reads a CARv1 or CARv2 file in to the store, verifying every block against
+/// its cid. The index of a CARv2 file is skipped. Returns the roots listed in
+/// the header, which are aliased if an `alias` is given.
+pub(crate) async fn import<P, R>(
+    storage: &StorageService<P>,
+    reader: R,
+    alias: Option<&[u8]>,
+) -> Result<Vec<Cid>>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let header = read_header::<P, _>(&mut reader).await?;
+    match header_field(&header, "version") {
+        Some(Ipld::Integer(1)) => import_v1(storage, &mut reader, header, alias).await,
+        Some(Ipld::Integer(2)) => {
+            // the pragma is followed by a fixed size header containing 16 bytes of
+            // characteristics, the data offset, data size and index offset.
+            let mut v2_header = [0u8; 40];
+            reader.read_exact(&mut v2_header).await?;
+            let data_offset = u64::from_le_bytes(v2_header[16..24].try_into()?);
+            let data_size = u64::from_le_bytes(v2_header[24..32].try_into()?);
+            let padding = data_offset
+                .checked_sub(V2_PRAGMA_LEN + v2_header.len() as u64)
+                .ok_or(InvalidCar("data offset overlaps header"))?;
+            futures::io::copy((&mut reader).take(padding), &mut futures::io::sink()).await?;
+            let mut data = (&mut reader).take(data_size);
+            let header = read_header::<P, _>(&mut data).await?;
+            if header_field(&header, "version") != Some(&Ipld::Integer(1)) {
+                return Err(InvalidCar("unsupported inner version").into());
+            }
+            import_v1(storage, &mut data, header, alias).await
+        }
+        _ => Err(InvalidCar("unsupported version").into()),
+    }
+}
+
+/// Length of the CARv2 pragma including its length prefix.
+const V2_PRAGMA_LEN: u64 = 11;
+
+async fn import_v1<P, R>(
+    storage: &StorageService<P>,
+    reader: &mut R,
+    header: Ipld,
+    alias: Option<&[u8]>,
+) -> Result<Vec<Cid>>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+    R: AsyncRead + Unpin,
+{
+    let roots = match header_field(&header, "roots") {
+        Some(Ipld::List(roots)) => roots
+            .iter()
+            .map(|root| match root {
+                Ipld::Link(cid) => Ok(*cid),
+                _ => Err(InvalidCar("root is not a link")),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?,
+        _ => return Err(InvalidCar("missing roots").into()),
+    };
+
+    // keep the imported blocks around until the roots are aliased
+    let mut tmp = storage.create_temp_pin()?;
+    let mut blocks = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    while let Some((cid, data)) = read_block_section::<P, _>(reader).await? {
+        blocks.push(Block::<P>::new(cid, data)?);
+        if blocks.len() == IMPORT_BATCH_SIZE {
+            insert_blocks(storage, &mut tmp, &mut blocks)?;
+        }
+    }
+    insert_blocks(storage, &mut tmp, &mut blocks)?;
+
+    if let Some(alias) = alias {
+        storage.rw("import_car", |batch| {
+            if let [root] = &roots[..] {
+                batch.alias(alias, Some(root))?;
+            } else {
+                for (i, root) in roots.iter().enumerate() {
+                    let alias = [alias, format!("/{}", i).as_bytes()].concat();
+                    batch.alias(&alias, Some(root))?;
+                }
+            }
+            Ok(())
+        })?;
+    }
+    Ok(roots)
+}
+
+fn insert_blocks<P>(
+    storage: &StorageService<P>,
+    tmp: &mut TempPin,
+    blocks: &mut Vec<Block<P>>,
+) -> Result<()>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    if blocks.is_empty() {
+        return Ok(());
+    }
+    storage.rw("import_car", |batch| {
+        let cids = blocks.iter().map(|block| *block.cid()).collect::<Vec<_>>();
+        for block in blocks.drain(..) {
+            batch.insert(block)?;
+        }
+        batch.temp_pin(tmp, cids)
+    })
+}
+
+async fn read_header<P: StoreParams, R: AsyncRead + Unpin>(reader: &mut R) -> Result<Ipld> {
+    let header = read_section(reader, max_section_len::<P>())
+        .await?
+        .ok_or(InvalidCar("missing header"))?;
+    DagCborCodec.decode(&header)
+}
+
+fn header_field<'a>(header: &'a Ipld, key: &str) -> Option<&'a Ipld> {
+    if let Ipld::Map(map) = header {
+        map.get(key)
+    } else {
+        None
+    }
+}
+
+/// Upper bound on the encoded length of a cid.
+const MAX_CID_LEN: usize = 1024;
+
+/// Sections contain a cid and a block, so allow some room for the cid.
+fn max_section_len<P: StoreParams>() -> usize {
+    P::MAX_BLOCK_SIZE + MAX_CID_LEN
+}
+
+/// Reads a block section. The cid is read before the block, so that an
+/// oversized block is reported as [`BlockTooLarge`] without buffering it.
+async fn read_block_section<P: StoreParams, R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<(Cid, Vec<u8>)>> {
+    let len = if let Some(len) = read_varint(reader).await? {
+        len
+    } else {
+        return Ok(None);
+    };
+    // a short block can end the file before `MAX_CID_LEN` bytes are read
+    let mut prefix = vec![];
+    (&mut *reader)
+        .take(len.min(MAX_CID_LEN as u64))
+        .read_to_end(&mut prefix)
+        .await?;
+    let mut cursor = Cursor::new(&prefix);
+    let cid = Cid::read_bytes(&mut cursor)?;
+    let cid_len = cursor.position() as usize;
+    let data_len = len - cid_len as u64;
+    if data_len > P::MAX_BLOCK_SIZE as u64 {
+        return Err(BlockTooLarge(cid).into());
+    }
+    let mut data = Vec::with_capacity(data_len as usize);
+    data.extend_from_slice(&prefix[cid_len..]);
+    (&mut *reader)
+        .take(data_len - data.len() as u64)
+        .read_to_end(&mut data)
+        .await?;
+    if data.len() as u64 != data_len {
+        return Err(InvalidCar("truncated section").into());
+    }
+    Ok(Some((cid, data)))
+}
+
+/// Reads a varint length prefixed section. Returns `None` at the end of the
+/// file.
+async fn read_section<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>> {
+    let len = if let Some(len) = read_varint(reader).await? {
+        len as usize
+    } else {
+        return Ok(None);
+    };
+    if len > max_len {
+        return Err(InvalidCar("section too large").into());
+    }
+    let mut section = vec![0; len];
+    reader.read_exact(&mut section).await?;
+    Ok(Some(section))
+}
+
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<u64>> {
+    let mut n = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        if reader.read(&mut byte).await? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(InvalidCar("truncated varint").into());
+        }
+        n |= u64::from(byte[0] & 0x7f) << (i * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+    }
+    Err(InvalidCar("varint overflow").into())
+}
+
+/// Writes a varint length prefixed section and returns the number of bytes
+/// written.
+async fn write_section<W: AsyncWrite + Unpin>(writer: &mut W, parts: &[&[u8]]) -> Result<u64> {
+    let len = parts.iter().map(|part| part.len()).sum::<usize>();
+    let mut prefix = Vec::with_capacity(10);
+    write_varint(len as u64, &mut prefix);
+    writer.write_all(&prefix).await?;
+    for part in parts {
+        writer.write_all(part).await?;
+    }
+    Ok((prefix.len() + len) as u64)
+}
+
+pub(crate) fn write_varint(mut n: u64, buf: &mut Vec<u8>) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::StorageConfig, executor::Executor};
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        raw::RawCodec,
+        store::DefaultParams,
+    };
+    use std::time::Duration;
+
+    fn create_store() -> StorageService<DefaultParams> {
+        let config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        StorageService::open(config, Executor::new()).unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_export_fixture() -> Result<()> {
+        // a single raw block as written by `ipfs dag export`
+        let fixture = include_bytes!("../tests/fixtures/hello.car");
+        let store = create_store();
+        let data = &b"hello ipfs-embed\n"[..];
+        let block = Block::<DefaultParams>::encode(RawCodec, Code::Sha2_256, data)?;
+        store.insert(block.clone())?;
+
+        let mut car = vec![];
+        let stats = export(&store, block.cid(), &mut car).await?;
+        assert_eq!(&car[..], &fixture[..]);
+        assert_eq!(
+            stats,
+            CarStats {
+                blocks: 1,
+                bytes: fixture.len() as u64
+            }
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_import_fixture() -> Result<()> {
+        let fixture = include_bytes!("../tests/fixtures/hello.car");
+        let store = create_store();
+        let roots = import(&store, &fixture[..], Some(&b"hello"[..])).await?;
+        assert_eq!(roots.len(), 1);
+        assert_eq!(store.resolve(b"hello")?, Some(roots[0]));
+        assert_eq!(store.get(&roots[0])?.unwrap(), b"hello ipfs-embed\n");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_import_v2() -> Result<()> {
+        let fixture = include_bytes!("../tests/fixtures/hello.car");
+        let mut car = vec![0x0a, 0xa1, 0x67];
+        car.extend_from_slice(b"version");
+        car.push(0x02);
+        car.extend_from_slice(&[0; 16]);
+        car.extend_from_slice(&(V2_PRAGMA_LEN + 40).to_le_bytes());
+        car.extend_from_slice(&(fixture.len() as u64).to_le_bytes());
+        car.extend_from_slice(&0u64.to_le_bytes());
+        car.extend_from_slice(&fixture[..]);
+        let store = create_store();
+        let roots = import(&store, &car[..], None).await?;
+        assert!(store.contains(&roots[0])?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_car_roundtrip() -> Result<()> {
+        let (root, blocks) = crate::test_util::build_tree(5, 3)?;
+        let a = create_store();
+        for block in blocks.iter() {
+            a.insert(block.clone())?;
+        }
+        let mut car = vec![];
+        let stats = export(&a, &root, &mut car).await?;
+        assert_eq!(stats.blocks, blocks.len());
+
+        let b = create_store();
+        assert_eq!(import(&b, &car[..], Some(&b"root"[..])).await?, vec![root]);
+        b.evict().await?;
+        for block in blocks {
+            assert_eq!(b.get(block.cid())?.as_deref(), Some(block.data()));
+            assert!(!b.reverse_alias(block.cid())?.unwrap().is_empty());
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_import_invalid_block() -> Result<()> {
+        let mut car = include_bytes!("../tests/fixtures/hello.car").to_vec();
+        *car.last_mut().unwrap() = b'!';
+        let store = create_store();
+        assert!(import(&store, &car[..], None).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_import_block_too_large() -> Result<()> {
+        let data = vec![0; DefaultParams::MAX_BLOCK_SIZE + 1];
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+        let header = DagCborCodec.encode(&ipld!({
+            "roots": [Ipld::Link(cid)],
+            "version": 1,
+        }))?;
+        let mut car = vec![];
+        write_section(&mut car, &[&header]).await?;
+        write_section(&mut car, &[&cid.to_bytes(), &data]).await?;
+
+        let store = create_store();
+        let err = import(&store, &car[..], None).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<BlockTooLarge>().unwrap().0, cid);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_import_section_too_large() -> Result<()> {
+        // the block is rejected by its length prefix, before the data is read
+        let data = vec![0; 64];
+        let cid = Cid::new_v1(0x55, Code::Sha2_256.digest(&data));
+        let header = DagCborCodec.encode(&ipld!({
+            "roots": [Ipld::Link(cid)],
+            "version": 1,
+        }))?;
+        let mut car = vec![];
+        write_section(&mut car, &[&header]).await?;
+        write_varint(u64::MAX / 2, &mut car);
+        car.extend_from_slice(&cid.to_bytes());
+        car.extend_from_slice(&data);
+
+        let store = create_store();
+        let err = import(&store, &car[..], None).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<BlockTooLarge>().unwrap().0, cid);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_export_missing_blocks() -> Result<()> {
+        let store = create_store();
+        let a = Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!(0))?;
+        let b = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!([Ipld::Link(*a.cid())]),
+        )?;
+        store.insert(b.clone())?;
+
+        let mut car = vec![];
+        let err = export(&store, b.cid(), &mut car).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MissingBlocks>().unwrap().0,
+            vec![*a.cid()]
+        );
+        assert!(car.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Transparent compression of the data of stored blocks.
+//!
+//! A compressed block is stored as `MAGIC`, the length of the block data as
+//! a little endian `u32` and a zstd frame. Everything else is stored as is,
+//! so stores written without compression keep working. Since block data
+//! can start with `MAGIC` by chance, decompressed data is only used if it
+//! matches the `Cid`.
+use crate::backend::{
+    BackendBlockInfo, BackendStats, BackendTransaction, BlockStoreBackend, DiskUsage, Durability,
+    TempPin,
+};
+use chrono::{DateTime, Utc};
+use fnv::FnvHashMap;
+use libipld::{store::StoreParams, Block, Cid, Result};
+use std::{collections::HashSet, convert::TryInto, time::Duration};
+
+const MAGIC: [u8; 4] = [0xff, b'z', b's', b't'];
+const HEADER_LEN: usize = MAGIC.len() + 4;
+const RAW: u64 = 0x55;
+
+/// Compression of the block data written by a `StorageService`.
+///
+/// Only the sqlite backend stores compressed data, other backends store
+/// the block data as is. Since the sqlite store takes the links of a block
+/// from its stored data, only raw blocks are compressed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Compression {
+    /// zstd with a level from 1 to 22, 0 selects the zstd default.
+    Zstd { level: i32 },
+}
+
+/// Returns the length of the block data if `data` starts with a header.
+fn header(data: &[u8]) -> Option<usize> {
+    if data.len() < HEADER_LEN || data[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let len = u32::from_le_bytes(data[MAGIC.len()..HEADER_LEN].try_into().ok()?);
+    Some(len as usize)
+}
+
+/// Returns the compressed form of `data`, or `None` if storing it as is
+/// is smaller.
+fn compress(compression: Compression, data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let Compression::Zstd { level } = compression;
+    let frame = zstd::bulk::compress(data, level)?;
+    // data looking like a header is always compressed, so it is never
+    // confused with a compressed block
+    if HEADER_LEN + frame.len() >= data.len() && header(data).is_none() {
+        return Ok(None);
+    }
+    let mut encoded = Vec::with_capacity(HEADER_LEN + frame.len());
+    encoded.extend_from_slice(&MAGIC);
+    encoded.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    encoded.extend_from_slice(&frame);
+    Ok(Some(encoded))
+}
+
+/// Returns the block data of the stored `data`.
+fn decompress<S: StoreParams>(cid: &Cid, data: Vec<u8>) -> Vec<u8> {
+    let len = match header(&data) {
+        Some(len) if len <= S::MAX_BLOCK_SIZE => len,
+        _ => return data,
+    };
+    match zstd::bulk::decompress(&data[HEADER_LEN..], len) {
+        Ok(decoded) if decoded.len() == len => match Block::<S>::new(*cid, decoded) {
+            Ok(block) => block.into_inner().1,
+            Err(_) => data,
+        },
+        _ => data,
+    }
+}
+
+/// Wraps a backend, compressing inserted blocks if `compression` is set
+/// and decompressing all blocks that were stored compressed.
+pub(crate) struct CompressedBackend<S: StoreParams> {
+    inner: Box<dyn BlockStoreBackend<S>>,
+    compression: Option<Compression>,
+    /// Logical sizes of the blocks counted by the last `stats`, so only new
+    /// blocks have to be read.
+    logical_sizes: FnvHashMap<Cid, u64>,
+}
+
+impl<S: StoreParams> CompressedBackend<S> {
+    pub fn new(inner: Box<dyn BlockStoreBackend<S>>, compression: Option<Compression>) -> Self {
+        Self {
+            inner,
+            compression,
+            logical_sizes: Default::default(),
+        }
+    }
+}
+
+impl<S: StoreParams> BlockStoreBackend<S> for CompressedBackend<S> {
+    fn transaction(&mut self) -> Box<dyn BackendTransaction<S> + '_> {
+        Box::new(CompressedTransaction {
+            inner: self.inner.transaction(),
+            compression: self.compression,
+        })
+    }
+
+    fn incremental_gc(&mut self, min_blocks: usize, target_duration: Duration) -> Result<bool> {
+        self.inner.incremental_gc(min_blocks, target_duration)
+    }
+
+    fn stats(&mut self) -> Result<BackendStats> {
+        let mut stats = self.inner.stats()?;
+        let mut logical_sizes = FnvHashMap::default();
+        let mut tx = self.inner.transaction();
+        for cid in tx.iter()? {
+            let size = match self.logical_sizes.get(&cid) {
+                Some(size) => *size,
+                None => match tx.get(&cid)? {
+                    Some(data) => header(&data).unwrap_or(data.len()) as u64,
+                    None => continue,
+                },
+            };
+            logical_sizes.insert(cid, size);
+        }
+        drop(tx);
+        stats.logical_bytes = logical_sizes.values().sum();
+        self.logical_sizes = logical_sizes;
+        Ok(stats)
+    }
+
+    fn flush(&mut self, durability: Durability) -> Result<()> {
+        self.inner.flush(durability)
+    }
+
+    fn gc_handle(&mut self) -> Result<Option<Box<dyn BlockStoreBackend<S>>>> {
+        // the handle is also used to read blocks, e.g. by backups
+        Ok(self
+            .inner
+            .gc_handle()?
+            .map(|handle| Box::new(Self::new(handle, self.compression)) as Box<_>))
+    }
+
+    fn disk_usage(&mut self) -> Result<Option<DiskUsage>> {
+        self.inner.disk_usage()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    fn set_size_targets(&mut self, blocks: Option<u64>, bytes: Option<u64>) -> Result<()> {
+        self.inner.set_size_targets(blocks, bytes)
+    }
+}
+
+struct CompressedTransaction<'a, S: StoreParams> {
+    inner: Box<dyn BackendTransaction<S> + 'a>,
+    compression: Option<Compression>,
+}
+
+impl<'a, S: StoreParams> BackendTransaction<S> for CompressedTransaction<'a, S> {
+    fn create_temp_pin(&mut self) -> Result<TempPin> {
+        self.inner.create_temp_pin()
+    }
+
+    fn extend_temp_pin(&mut self, pin: &mut TempPin, cid: &Cid) -> Result<()> {
+        self.inner.extend_temp_pin(pin, cid)
+    }
+
+    fn iter(&mut self) -> Result<Vec<Cid>> {
+        self.inner.iter()
+    }
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.inner.contains(cid)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(self.inner.get(cid)?.map(|data| decompress::<S>(cid, data)))
+    }
+
+    fn insert(&mut self, block: Block<S>) -> Result<()> {
+        let compressed = match self.compression {
+            Some(compression) if block.cid().codec() == RAW => compress(compression, block.data())?,
+            _ => None,
+        };
+        match compressed {
+            Some(data) => self.inner.insert_encoded(block, data),
+            None => self.inner.insert(block),
+        }
+    }
+
+    fn insert_encoded(&mut self, block: Block<S>, data: Vec<u8>) -> Result<()> {
+        self.inner.insert_encoded(block, data)
+    }
+
+    fn remove(&mut self, cid: &Cid) -> Result<bool> {
+        self.inner.remove(cid)
+    }
+
+    fn temp_pinned(&mut self, cid: &Cid) -> Result<Option<bool>> {
+        self.inner.temp_pinned(cid)
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.inner.missing_blocks(cid)
+    }
+
+    fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        self.inner.alias(alias, cid)
+    }
+
+    fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
+        self.inner.resolve(alias)
+    }
+
+    fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        self.inner.reverse_alias(cid)
+    }
+
+    fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        self.inner.aliases()
+    }
+
+    fn inserted_at(&mut self, cid: &Cid) -> Result<Option<DateTime<Utc>>> {
+        self.inner.inserted_at(cid)
+    }
+
+    fn block_infos(&mut self, retains: &dyn Fn(&[u8]) -> bool) -> Result<Vec<BackendBlockInfo>> {
+        let mut infos = self.inner.block_infos(retains)?;
+        for info in &mut infos {
+            // the header holds the size before compression
+            if let Some(size) = self.inner.get(&info.cid)?.as_deref().and_then(header) {
+                info.size = size;
+            }
+        }
+        Ok(infos)
+    }
+
+    fn metadata(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.metadata(key)
+    }
+
+    fn set_metadata(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        self.inner.set_metadata(key, value)
+    }
+
+    fn metadata_with_prefix(&mut self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.metadata_with_prefix(prefix)
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        self.inner.commit()
+    }
+}
@@ -0,0 +1,285 @@
+//! Loading and saving the configuration as toml.
+use crate::{config::PingConfig, identity, Config, NetworkConfig, StorageConfig};
+use anyhow::Context;
+use libipld::Result;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde_crate::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// The serializable part of a `Config`. Omitted fields take the defaults of
+/// `Config::new`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "serde_crate", default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub storage: StorageConfigFile,
+    pub network: NetworkConfigFile,
+}
+
+/// The serializable part of a `StorageConfig`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "serde_crate", default, deny_unknown_fields)]
+pub struct StorageConfigFile {
+    /// See `StorageConfig::path`.
+    pub path: Option<PathBuf>,
+    /// See `StorageConfig::access_db_path`.
+    pub access_db_path: Option<PathBuf>,
+    /// See `StorageConfig::cache_size_blocks`.
+    pub cache_size_blocks: u64,
+    /// See `StorageConfig::cache_size_bytes`.
+    pub cache_size_bytes: Option<u64>,
+    /// See `StorageConfig::gc_interval`.
+    pub gc_interval_ms: u64,
+    /// See `StorageConfig::max_bytes`.
+    pub max_bytes: Option<u64>,
+    /// See `StorageConfig::read_only`.
+    pub read_only: bool,
+}
+
+impl Default for StorageConfigFile {
+    fn default() -> Self {
+        Self {
+            path: None,
+            access_db_path: None,
+            cache_size_blocks: 0,
+            cache_size_bytes: None,
+            gc_interval_ms: 10000,
+            max_bytes: None,
+            read_only: false,
+        }
+    }
+}
+
+/// The serializable part of a `NetworkConfig`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(crate = "serde_crate", default, deny_unknown_fields)]
+pub struct NetworkConfigFile {
+    /// See `NetworkConfig::node_name`. A random name is generated if it is
+    /// omitted.
+    pub node_name: Option<String>,
+    /// File containing the node key, see `identity::load_or_generate`. A new
+    /// key is generated on every start if it is omitted.
+    pub key_file: Option<PathBuf>,
+    /// See `NetworkConfig::listen_addresses`.
+    pub listen_addresses: Vec<Multiaddr>,
+    /// See `NetworkConfig::bootstrap`. The addresses have to end with the
+    /// `/p2p` protocol containing the `PeerId`.
+    pub bootstrap: Vec<Multiaddr>,
+    /// See `NetworkConfig::enable_loopback`.
+    pub enable_loopback: bool,
+    /// See `NetworkConfig::port_reuse`.
+    pub port_reuse: bool,
+    /// Enables mdns with the default configuration.
+    pub mdns: bool,
+    /// Enables kademlia with the default configuration.
+    pub kad: bool,
+    /// Enables ping with the default configuration.
+    pub ping: bool,
+}
+
+impl Default for NetworkConfigFile {
+    fn default() -> Self {
+        Self {
+            node_name: None,
+            key_file: None,
+            listen_addresses: vec![],
+            bootstrap: vec![],
+            enable_loopback: true,
+            port_reuse: true,
+            mdns: true,
+            kad: true,
+            ping: false,
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Reads a configuration file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let toml = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&toml).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Writes a configuration file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Creates a `Config`, loading or generating the node key.
+    pub fn to_config(&self) -> Result<Config> {
+        let storage = &self.storage;
+        let mut storage_config = StorageConfig::new(
+            storage.path.clone(),
+            storage.access_db_path.clone(),
+            storage.cache_size_blocks,
+            Duration::from_millis(storage.gc_interval_ms),
+        );
+        storage_config.cache_size_bytes = storage.cache_size_bytes;
+        storage_config.max_bytes = storage.max_bytes;
+        storage_config.read_only = storage.read_only;
+
+        let network = &self.network;
+        let keypair = if let Some(path) = &network.key_file {
+            identity::load_or_generate(path)?
+        } else {
+            identity::ed25519::Keypair::generate()
+        };
+        let mut network_config = NetworkConfig::new(keypair);
+        if let Some(node_name) = &network.node_name {
+            network_config.node_name = node_name.clone();
+        }
+        network_config.listen_addresses = network.listen_addresses.clone();
+        network_config.bootstrap = network
+            .bootstrap
+            .iter()
+            .map(split_peer_id)
+            .collect::<Result<_>>()?;
+        network_config.enable_loopback = network.enable_loopback;
+        network_config.port_reuse = network.port_reuse;
+        if !network.mdns {
+            network_config.mdns = None;
+        }
+        if !network.kad {
+            network_config.kad = None;
+        }
+        if network.ping {
+            network_config.ping = Some(PingConfig::new());
+        }
+        Ok(Config {
+            storage: storage_config,
+            network: network_config,
+        })
+    }
+}
+
+impl From<&Config> for ConfigFile {
+    fn from(config: &Config) -> Self {
+        let storage = &config.storage;
+        let network = &config.network;
+        Self {
+            storage: StorageConfigFile {
+                path: storage.path.clone(),
+                access_db_path: storage.access_db_path.clone(),
+                cache_size_blocks: storage.cache_size_blocks,
+                cache_size_bytes: storage.cache_size_bytes,
+                gc_interval_ms: storage.gc_interval.as_millis() as u64,
+                max_bytes: storage.max_bytes,
+                read_only: storage.read_only,
+            },
+            network: NetworkConfigFile {
+                node_name: Some(network.node_name.clone()),
+                key_file: None,
+                listen_addresses: network.listen_addresses.clone(),
+                bootstrap: network
+                    .bootstrap
+                    .iter()
+                    .map(|(peer, addr)| addr.clone().with(Protocol::P2p((*peer).into())))
+                    .collect(),
+                enable_loopback: network.enable_loopback,
+                port_reuse: network.port_reuse,
+                mdns: network.mdns.is_some(),
+                kad: network.kad.is_some(),
+                ping: network.ping.is_some(),
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Loads a configuration from a toml file. See `ConfigFile` for the
+    /// supported fields.
+    pub fn from_toml(path: &Path) -> Result<Self> {
+        ConfigFile::load(path)?.to_config()
+    }
+
+    /// Saves the configuration as a toml file. Settings that can't be
+    /// serialized are not written, like the node key and the configuration
+    /// of enabled behaviours, which is reset to the defaults on load.
+    pub fn to_toml(&self, path: &Path) -> Result<()> {
+        ConfigFile::from(self).save(path)
+    }
+}
+
+/// Splits the trailing `/p2p` protocol off a bootstrap address.
+fn split_peer_id(addr: &Multiaddr) -> Result<(PeerId, Multiaddr)> {
+    let mut prefix = addr.clone();
+    match prefix.pop() {
+        Some(Protocol::P2p(hash)) => {
+            let peer = PeerId::from_multihash(hash)
+                .map_err(|_| anyhow::anyhow!("invalid peer id in {}", addr))?;
+            Ok((peer, prefix))
+        }
+        _ => Err(anyhow::anyhow!(
+            "bootstrap address {} doesn't end with /p2p",
+            addr
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let peer = PeerId::random();
+        let mut file = ConfigFile::default();
+        file.storage.path = Some(tmp.path().join("blocks"));
+        file.storage.cache_size_bytes = Some(1 << 30);
+        file.network.node_name = Some("node".into());
+        file.network.key_file = Some(tmp.path().join("key"));
+        file.network.listen_addresses = vec!["/ip4/0.0.0.0/tcp/4001".parse()?];
+        file.network.bootstrap = vec![format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer).parse()?];
+        file.network.mdns = false;
+        file.network.ping = true;
+        let path = tmp.path().join("config.toml");
+        file.save(&path)?;
+        assert_eq!(ConfigFile::load(&path)?, file);
+
+        let config = Config::from_toml(&path)?;
+        assert_eq!(config.storage.path, file.storage.path);
+        assert_eq!(config.network.node_name, "node");
+        assert_eq!(config.network.bootstrap[0].0, peer);
+        assert!(config.network.mdns.is_none());
+        assert!(config.network.ping.is_some());
+
+        // the key is persisted in the key file
+        let reloaded = Config::from_toml(&path)?;
+        assert_eq!(
+            reloaded.network.node_key.public(),
+            config.network.node_key.public()
+        );
+
+        let saved = tmp.path().join("saved.toml");
+        config.to_toml(&saved)?;
+        let mut expected = file;
+        expected.network.key_file = None;
+        assert_eq!(ConfigFile::load(&saved)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_minimal() -> Result<()> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let path = tmp.path().join("config.toml");
+        std::fs::write(&path, "[storage]\ncache_size_blocks = 1000\n")?;
+        let config = Config::from_toml(&path)?;
+        assert_eq!(config.storage.path, None);
+        assert_eq!(config.storage.cache_size_blocks, 1000);
+        assert_eq!(config.storage.gc_interval, Duration::from_secs(10));
+        assert!(config.network.mdns.is_some());
+        assert!(config.network.kad.is_some());
+        assert!(config.network.ping.is_none());
+        assert!(config.network.listen_addresses.is_empty());
+
+        std::fs::write(&path, "[storage]\ncache_size = 1000\n")?;
+        assert!(Config::from_toml(&path).is_err());
+        Ok(())
+    }
+}
@@ -1,21 +1,520 @@
-pub use ipfs_sqlite_block_store::TempPin;
-use ipfs_sqlite_block_store::{
-    cache::{CacheTracker, InMemCacheTracker, SqliteCacheTracker},
-    BlockStore, Config, Synchronous,
+#[cfg(feature = "compression")]
+use crate::compression::{CompressedBackend, Compression};
+use crate::{
+    backend::{
+        BackendBlockInfo, BackendKind, BackendStats, BackendTransaction, BlockStoreBackend,
+        Durability, InsertLogBackend, MemBackend, ReadOnlyBackend, SqliteBackend, TempPin,
+    },
+    net::AddressSource,
+};
+use anyhow::Context;
+use chrono::{DateTime, TimeZone, Utc};
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    FutureExt, Stream, StreamExt,
 };
 use lazy_static::lazy_static;
-use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld, Result};
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, References},
+    ipld,
+    multihash::{Code, MultihashDigest},
+    store::StoreParams,
+    Block, Cid, Ipld, Result,
+};
+use libp2p::{Multiaddr, PeerId};
 use parking_lot::Mutex;
 use prometheus::{
     core::{Collector, Desc},
     proto::MetricFamily,
     HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
 };
-use std::{future::Future, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    convert::TryFrom,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll, Waker},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
 use tracing::info;
 
-use crate::executor::{Executor, JoinHandle};
-use std::collections::HashSet;
+use crate::{
+    executor::{BackgroundTask, Executor, JoinHandle, TaskMonitor, TaskStatus},
+    variable::{Observer, Reader, Variable},
+};
+use std::collections::{BTreeSet, HashSet, VecDeque};
+
+/// Prefix of the aliases used internally. They are hidden from `aliases` and
+/// can't be set using `alias`.
+const RESERVED_ALIAS_PREFIX: &[u8] = b"\0ipfs-embed/";
+/// Pins are stored as one alias per pin of a `Cid`, so that a `Cid` pinned
+/// twice needs to be unpinned twice.
+const PIN_ALIAS_PREFIX: &[u8] = b"\0ipfs-embed/pin/";
+
+fn pin_alias(cid: &Cid, n: usize) -> Vec<u8> {
+    let mut alias = PIN_ALIAS_PREFIX.to_vec();
+    alias.extend_from_slice(&cid.to_bytes());
+    alias.extend_from_slice(format!("/{}", n).as_bytes());
+    alias
+}
+
+/// Leases are stored as an alias pointing to a block containing the expiry
+/// and the leased roots.
+const DAG_CBOR: u64 = 0x71;
+
+const LEASE_ALIAS_PREFIX: &[u8] = b"\0ipfs-embed/lease/";
+
+fn lease_alias(name: &[u8]) -> Vec<u8> {
+    [LEASE_ALIAS_PREFIX, name].concat()
+}
+
+fn encode_lease<S: StoreParams>(expires: DateTime<Utc>, roots: &[Cid]) -> Result<Block<S>> {
+    let roots = roots.iter().copied().map(Ipld::Link).collect::<Vec<_>>();
+    let data = DagCborCodec.encode(&ipld!({
+        "expires": expires.timestamp_millis(),
+        "roots": Ipld::List(roots),
+    }))?;
+    let cid = Cid::new_v1(DAG_CBOR, Code::Blake3_256.digest(&data));
+    Ok(Block::new_unchecked(cid, data))
+}
+
+fn decode_lease(data: &[u8]) -> Result<(DateTime<Utc>, Vec<Cid>)> {
+    let ipld: Ipld = DagCborCodec.decode(data)?;
+    let (expires, roots) = match &ipld {
+        Ipld::Map(map) => (map.get("expires"), map.get("roots")),
+        _ => (None, None),
+    };
+    let expires = match expires {
+        Some(Ipld::Integer(expires)) => Utc.timestamp_millis_opt(*expires as i64).single(),
+        _ => None,
+    };
+    let expires = expires.ok_or_else(|| anyhow::anyhow!("invalid lease"))?;
+    let roots = match roots {
+        Some(Ipld::List(roots)) => roots
+            .iter()
+            .filter_map(|root| match root {
+                Ipld::Link(cid) => Some(*cid),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    Ok((expires, roots))
+}
+
+/// Bans are stored in the backend metadata, one dag-cbor encoded entry per
+/// peer containing the expiry, which is null for permanent bans.
+const BAN_KEY_PREFIX: &[u8] = b"ban/";
+
+fn ban_key(peer: &PeerId) -> Vec<u8> {
+    [BAN_KEY_PREFIX, &peer.to_bytes()].concat()
+}
+
+fn encode_ban(expires: Option<DateTime<Utc>>) -> Result<Vec<u8>> {
+    let expires = match expires {
+        Some(expires) => Ipld::Integer(expires.timestamp_millis() as i128),
+        None => Ipld::Null,
+    };
+    DagCborCodec.encode(&ipld!({ "expires": expires }))
+}
+
+fn decode_ban(data: &[u8]) -> Result<Option<DateTime<Utc>>> {
+    let ipld: Ipld = DagCborCodec.decode(data)?;
+    match &ipld {
+        Ipld::Map(map) => match map.get("expires") {
+            Some(Ipld::Integer(expires)) => Utc
+                .timestamp_millis_opt(*expires as i64)
+                .single()
+                .map(Some)
+                .ok_or_else(|| anyhow::anyhow!("invalid ban")),
+            Some(Ipld::Null) => Ok(None),
+            _ => Err(anyhow::anyhow!("invalid ban")),
+        },
+        _ => Err(anyhow::anyhow!("invalid ban")),
+    }
+}
+
+/// The address book is stored in the backend metadata as a single dag-cbor
+/// encoded entry with the addresses of all peers, replaced on every save.
+const ADDRESS_BOOK_KEY: &[u8] = b"address-book";
+
+/// The addresses of a peer with their source and when they were last seen.
+pub type PeerAddresses = (PeerId, Vec<(Multiaddr, AddressSource, DateTime<Utc>)>);
+
+fn encode_source(source: AddressSource) -> &'static str {
+    match source {
+        AddressSource::Incoming => "incoming",
+        AddressSource::Listen => "listen",
+        AddressSource::Kad => "kad",
+        AddressSource::Mdns => "mdns",
+        AddressSource::Candidate => "candidate",
+        AddressSource::User => "user",
+        AddressSource::Dial => "dial",
+    }
+}
+
+fn decode_source(source: &str) -> Option<AddressSource> {
+    Some(match source {
+        "incoming" => AddressSource::Incoming,
+        "listen" => AddressSource::Listen,
+        "kad" => AddressSource::Kad,
+        "mdns" => AddressSource::Mdns,
+        "candidate" => AddressSource::Candidate,
+        "user" => AddressSource::User,
+        "dial" => AddressSource::Dial,
+        _ => return None,
+    })
+}
+
+fn encode_address_book(book: &[PeerAddresses]) -> Result<Vec<u8>> {
+    let peers = book
+        .iter()
+        .map(|(peer, addrs)| {
+            let addrs = addrs
+                .iter()
+                .map(|(addr, source, seen)| {
+                    ipld!({
+                        "addr": addr.to_vec(),
+                        "source": encode_source(*source),
+                        "seen": seen.timestamp_millis(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            ipld!({
+                "peer": peer.to_bytes(),
+                "addrs": Ipld::List(addrs),
+            })
+        })
+        .collect::<Vec<_>>();
+    DagCborCodec.encode(&Ipld::List(peers))
+}
+
+fn field<'a>(ipld: &'a Ipld, key: &str) -> Option<&'a Ipld> {
+    match ipld {
+        Ipld::Map(map) => map.get(key),
+        _ => None,
+    }
+}
+
+/// Decodes the address book, skipping entries that can't be parsed.
+fn decode_address_book(data: &[u8]) -> Result<Vec<PeerAddresses>> {
+    let ipld: Ipld = DagCborCodec.decode(data)?;
+    let peers = match ipld {
+        Ipld::List(peers) => peers,
+        _ => return Err(anyhow::anyhow!("invalid address book")),
+    };
+    let mut book = vec![];
+    for entry in &peers {
+        let peer = match field(entry, "peer") {
+            Some(Ipld::Bytes(peer)) => match PeerId::from_bytes(peer) {
+                Ok(peer) => peer,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        let addrs = match field(entry, "addrs") {
+            Some(Ipld::List(addrs)) => addrs
+                .iter()
+                .filter_map(|entry| {
+                    match (
+                        field(entry, "addr"),
+                        field(entry, "source"),
+                        field(entry, "seen"),
+                    ) {
+                        (
+                            Some(Ipld::Bytes(addr)),
+                            Some(Ipld::String(source)),
+                            Some(Ipld::Integer(seen)),
+                        ) => Some((
+                            Multiaddr::try_from(addr.clone()).ok()?,
+                            decode_source(source)?,
+                            Utc.timestamp_millis_opt(*seen as i64).single()?,
+                        )),
+                        _ => None,
+                    }
+                })
+                .collect(),
+            _ => continue,
+        };
+        book.push((peer, addrs));
+    }
+    Ok(book)
+}
+
+fn parse_pin_alias(alias: &[u8]) -> Option<Cid> {
+    if alias.starts_with(PIN_ALIAS_PREFIX) {
+        Cid::read_bytes(&alias[PIN_ALIAS_PREFIX.len()..]).ok()
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Aliases starting with a nul byte are reserved.")]
+pub struct ReservedAlias;
+
+#[derive(Debug, Error)]
+#[error("The block store is read only.")]
+pub struct ReadOnly;
+
+#[derive(Debug, Error)]
+#[error("The ipfs node was shut down.")]
+pub struct Shutdown;
+
+#[derive(Debug, Error)]
+#[error("The garbage collector is disabled.")]
+pub struct GcDisabled;
+
+#[derive(Debug, Error)]
+#[error("The block {0} is retained by an alias, a pin or a temp pin.")]
+pub struct BlockRetained(pub Cid);
+
+#[derive(Debug, Error)]
+#[error(
+    "The alias {} points to {cid}, which is neither stored nor inserted by the transaction.",
+    String::from_utf8_lossy(.alias)
+)]
+pub struct MissingAliasTarget {
+    pub alias: Vec<u8>,
+    pub cid: Cid,
+}
+
+#[derive(Debug, Error)]
+#[error("Inserting the block would exceed the storage quota of {max_bytes} bytes.")]
+pub struct QuotaExceeded {
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Error)]
+#[error(
+    "The alias {} points to {ours} in this store and to {theirs} in the imported one.",
+    String::from_utf8_lossy(.alias)
+)]
+pub struct ConflictingAlias {
+    pub alias: Vec<u8>,
+    pub ours: Cid,
+    pub theirs: Cid,
+}
+
+/// How `StorageService::import_store` handles aliases that point to
+/// different roots in both stores.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AliasConflict {
+    /// Fails with `ConflictingAlias` before anything is imported.
+    Fail,
+    /// Keeps the root of this store.
+    KeepOurs,
+    /// Points the alias to the root of the imported store.
+    KeepTheirs,
+}
+
+/// Summary of `StorageService::import_store`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ImportStats {
+    /// Number of blocks copied to this store.
+    pub blocks_copied: u64,
+    /// Number of blocks that were in both stores.
+    pub blocks_skipped: u64,
+    /// Total size of the copied blocks.
+    pub bytes_copied: u64,
+    /// Number of aliases that were added or changed.
+    pub aliases_imported: u64,
+}
+
+/// How blocks received over bitswap are handled when they exceed
+/// `StorageConfig::max_bytes`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QuotaPolicy {
+    /// Rejects the block like a local insert.
+    Reject,
+    /// Accepts the block and runs the garbage collector, which evicts
+    /// unpinned blocks until the cache size targets are met. The targets
+    /// should be below `max_bytes` for this to make room.
+    Evict,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> Self {
+        Self::Reject
+    }
+}
+
+/// Whether the garbage collector runs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GcMode {
+    /// Evicts unpinned blocks as configured by the `gc_*` and `cache_size_*`
+    /// settings of `StorageConfig`.
+    Enabled,
+    /// Never evicts blocks. The garbage collector task isn't spawned,
+    /// `StorageService::evict` fails with `GcDisabled` and block accesses
+    /// aren't tracked. Temp pins can still be created, but don't protect
+    /// anything that wouldn't be kept anyway.
+    Disabled,
+}
+
+impl Default for GcMode {
+    fn default() -> Self {
+        Self::Enabled
+    }
+}
+
+/// Rules protecting unpinned blocks from the garbage collector.
+///
+/// The rules are evaluated for every stored block at the start of a sweep.
+/// Like a pin, a kept block also keeps the blocks it links to.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GcPolicy {
+    /// Never evicts blocks smaller than this many bytes.
+    pub keep_smaller_than: Option<usize>,
+    /// Never evicts blocks with one of these codecs.
+    pub keep_codecs: BTreeSet<u64>,
+    /// Never evicts these blocks. Can be changed at runtime with
+    /// `StorageService::gc_exclude` and `StorageService::gc_include`.
+    pub exclude: HashSet<Cid>,
+}
+
+impl GcPolicy {
+    fn is_empty(&self) -> bool {
+        self.keep_smaller_than.is_none() && self.keep_codecs.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns whether the block `cid` is kept. `size` is only called if
+    /// the decision depends on it.
+    fn keeps(&self, cid: &Cid, size: impl FnOnce() -> Result<Option<usize>>) -> Result<bool> {
+        if self.exclude.contains(cid) || self.keep_codecs.contains(&cid.codec()) {
+            return Ok(true);
+        }
+        Ok(match self.keep_smaller_than {
+            Some(limit) => matches!(size()?, Some(size) if size < limit),
+            None => false,
+        })
+    }
+}
+
+/// Bytes used by the store and the limit enforced on inserts.
+#[derive(Clone, Copy, Debug)]
+struct Quota {
+    max_bytes: u64,
+    used: u64,
+}
+
+/// Describes what keeps a block from being garbage collected. Temp pins are
+/// not tracked by the block store and are therefore not included.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PinStatus {
+    /// Aliases whose dag contains the block.
+    pub aliases: HashSet<Vec<u8>>,
+    /// Pinned roots whose dag contains the block.
+    pub pins: HashSet<Cid>,
+}
+
+impl PinStatus {
+    /// Returns if the block is retained by an alias or a pin.
+    pub fn is_retained(&self) -> bool {
+        !self.aliases.is_empty() || !self.pins.is_empty()
+    }
+}
+
+/// Metadata of a stored block.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlockInfo {
+    /// Cid of the block.
+    pub cid: Cid,
+    /// Size of the block data in bytes.
+    pub size: usize,
+    /// Whether the block is retained by an alias or a pin.
+    pub pinned: bool,
+    /// Whether the block is retained by a temp pin. The sqlite backend
+    /// doesn't expose its temp pins, so this is always `false` for it.
+    pub temp_pinned: bool,
+    /// When the block was inserted since the store was opened.
+    pub inserted_at: Option<DateTime<Utc>>,
+}
+
+impl From<BackendBlockInfo> for BlockInfo {
+    fn from(info: BackendBlockInfo) -> Self {
+        Self {
+            cid: info.cid,
+            size: info.size,
+            pinned: info.retained,
+            temp_pinned: info.temp_pinned.unwrap_or_default(),
+            inserted_at: info.inserted_at,
+        }
+    }
+}
+
+/// Selects blocks returned by `StorageService::iter_filtered`. Blocks have
+/// to match all predicates that are set.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BlockFilter {
+    /// Only blocks with this codec.
+    pub codec: Option<u64>,
+    /// Only blocks hashed with this multihash code.
+    pub hash: Option<u64>,
+    /// Only blocks of at least this many bytes.
+    pub min_size: Option<usize>,
+    /// Only blocks of at most this many bytes.
+    pub max_size: Option<usize>,
+    /// Only blocks inserted after this time. Insertion times are tracked
+    /// while the store is open, blocks stored before it was opened never
+    /// match.
+    pub inserted_after: Option<DateTime<Utc>>,
+    /// Only blocks that are or aren't retained by an alias or a pin.
+    pub pinned: Option<bool>,
+    /// Orders the blocks by the time they were last inserted, starting with
+    /// the blocks stored before the store was opened. Otherwise they are
+    /// returned in the order of the backend.
+    pub sort_by_insertion: bool,
+}
+
+impl BlockFilter {
+    fn needs_size(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some()
+    }
+
+    fn matches_cid(&self, cid: &Cid) -> bool {
+        self.codec.map(|codec| cid.codec() == codec).unwrap_or(true)
+            && self
+                .hash
+                .map(|hash| cid.hash().code() == hash)
+                .unwrap_or(true)
+    }
+
+    fn matches_size(&self, size: usize) -> bool {
+        self.min_size.map(|min| size >= min).unwrap_or(true)
+            && self.max_size.map(|max| size <= max).unwrap_or(true)
+    }
+}
+
+/// References of a dag found by walking it breadth first.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Refs {
+    /// The deduplicated references in breadth first order, not including the
+    /// root.
+    pub refs: Vec<Cid>,
+    /// References whose blocks are not in the store. Their children could not
+    /// be visited.
+    pub missing: Vec<Cid>,
+}
+
+/// Size of a dag in the local store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DagStat {
+    /// Number of blocks in the store.
+    pub blocks: u64,
+    /// Total size of the blocks in the store.
+    pub size: u64,
+    /// Blocks of the dag that are not in the store.
+    pub missing: Vec<Cid>,
+}
 
 /// Storage configuration.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -40,14 +539,16 @@ pub struct StorageConfig {
     /// To completely disable storing of non-pinned blocks, set this to 0. Even
     /// then, the store will never delete pinned blocks.
     pub cache_size_blocks: u64,
-    /// The target store size.
+    /// The target store size in bytes. `None` means there is no size target.
     ///
     /// Up to this size, the store will retain everything even if not pinned.
     /// Once this size is exceeded, the store will run garbage collection of
-    /// all unpinned blocks until the size criterion is met again.
+    /// all unpinned blocks until the size criterion is met again. If both
+    /// `cache_size_blocks` and `cache_size_bytes` are set, garbage collection
+    /// runs until both are met.
     ///
     /// The store will never delete pinned blocks.
-    pub cache_size_bytes: u64,
+    pub cache_size_bytes: Option<u64>,
     /// The interval at which the garbage collector is run.
     ///
     /// Note that this is implemented as delays between gcs, so it will not run
@@ -66,6 +567,45 @@ pub struct StorageConfig {
     /// `gc_min_blocks`. But as soon as this duration is exceeded, the
     /// incremental gc will stop doing additional work.
     pub gc_target_duration: Duration,
+    /// The block store implementation. Other implementations can be injected
+    /// using `StorageService::open_with_backend`.
+    pub backend: BackendKind,
+    /// Opens the block store read only. Blocks can be read and served to
+    /// other peers, but inserting blocks, changing aliases, creating temp
+    /// pins and garbage collection fail with `ReadOnly`.
+    ///
+    /// A sqlite store is copied into memory when it is opened, because
+    /// opening it in place writes to it.
+    pub read_only: bool,
+    /// The durability of every committed write. `Durability::Sync` makes
+    /// each write wait for stable storage, which is slow on many devices.
+    pub default_durability: Durability,
+    /// Hard limit of the store size in bytes, including pinned blocks.
+    /// Inserts that would exceed it fail with `QuotaExceeded` instead of
+    /// relying on the garbage collector.
+    pub max_bytes: Option<u64>,
+    /// How blocks received over bitswap are handled when they exceed
+    /// `max_bytes`.
+    pub bitswap_quota_policy: QuotaPolicy,
+    /// Compresses the data of inserted blocks. Reads decompress blocks
+    /// regardless of this setting, so it can be changed between runs.
+    /// `Cid`s and blocks served to other peers refer to the uncompressed
+    /// data, while the size targets and `max_bytes` apply to the stored
+    /// data.
+    #[cfg(feature = "compression")]
+    pub compression: Option<Compression>,
+    /// Compacts the store after a garbage collector sweep once the space
+    /// left behind by removed blocks exceeds this percentage of the store.
+    /// `None` only compacts on `StorageService::compact`.
+    pub auto_compact_threshold: Option<u8>,
+    /// Unpinned blocks the garbage collector never evicts.
+    pub gc_policy: GcPolicy,
+    /// Disables the garbage collector, for archival nodes that keep every
+    /// block.
+    pub gc: GcMode,
+    /// Number of events queued for a `StoreEvents` subscriber. Once it lags
+    /// behind by more, the oldest events are dropped.
+    pub store_events_buffer: usize,
 }
 
 impl StorageConfig {
@@ -80,27 +620,474 @@ impl StorageConfig {
             path,
             access_db_path,
             cache_size_blocks: cache_size,
-            cache_size_bytes: u64::MAX,
+            cache_size_bytes: None,
             gc_interval,
             gc_min_blocks: usize::MAX,
             gc_target_duration: Duration::new(u64::MAX, 1_000_000_000 - 1),
+            backend: BackendKind::default(),
+            read_only: false,
+            default_durability: Durability::default(),
+            max_bytes: None,
+            bitswap_quota_policy: QuotaPolicy::default(),
+            #[cfg(feature = "compression")]
+            compression: None,
+            auto_compact_threshold: None,
+            gc_policy: GcPolicy::default(),
+            gc: GcMode::default(),
+            store_events_buffer: 1024,
+        }
+    }
+}
+
+/// An event of the garbage collector.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GcEvent {
+    /// A sweep started. Counts the blocks and bytes in the store before the
+    /// sweep.
+    Started { blocks: u64, bytes: u64 },
+    /// A sweep finished.
+    Finished(GcStats),
+}
+
+/// Summary of one or more garbage collector sweeps.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    /// Number of evicted blocks.
+    pub blocks_evicted: u64,
+    /// Number of evicted bytes.
+    pub bytes_evicted: u64,
+    /// Time spent collecting garbage.
+    pub duration: Duration,
+    /// `false` if the sweep stopped early because it exceeded the
+    /// `gc_target_duration`.
+    pub complete: bool,
+}
+
+/// Summary of a compaction of the store.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompactStats {
+    /// Number of bytes the store shrank by.
+    pub bytes_reclaimed: u64,
+    /// Time spent compacting.
+    pub duration: Duration,
+}
+
+/// Options of a store verification.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifyConfig {
+    /// Number of blocks checked while holding the store lock.
+    pub batch_size: usize,
+    /// Pause between two batches, giving other queries and the garbage
+    /// collector a chance to run.
+    pub pause: Duration,
+    /// Removes corrupt blocks from the store. Fails for backends that can't
+    /// remove individual blocks, like the sqlite store.
+    pub remove_corrupt: bool,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            pause: Duration::from_millis(10),
+            remove_corrupt: false,
+        }
+    }
+}
+
+/// An event of a store verification.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VerifyEvent {
+    /// A batch of blocks was checked. Blocks evicted while verifying are
+    /// counted as checked.
+    Progress { checked: u64, total: u64 },
+    /// The data of a block doesn't match the hash of its `Cid`.
+    Corrupt(Cid),
+    /// A corrupt block was removed.
+    Removed(Cid),
+    /// An alias points to a block that isn't stored. Pins and leases are
+    /// reported with their reserved alias.
+    Dangling { alias: Vec<u8>, cid: Cid },
+    /// The verification stopped because of a store error.
+    Failed(String),
+}
+
+/// The values of the watched aliases, removed once the last watcher of an
+/// alias is dropped.
+type AliasWatchers = Arc<Mutex<FnvHashMap<Vec<u8>, Variable<Option<Cid>>>>>;
+
+/// Block and byte targets set with `StorageService::set_cache_size`.
+type SizeTargets = Arc<Mutex<Option<(Option<u64>, Option<u64>)>>>;
+
+/// A stream of the values of an alias, see `StorageService::watch_alias`.
+pub struct AliasWatcher {
+    alias: Vec<u8>,
+    observer: Observer<Option<Cid>>,
+    watchers: AliasWatchers,
+}
+
+impl Stream for AliasWatcher {
+    type Item = Option<Cid>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Option<Self::Item>> {
+        self.observer.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for AliasWatcher {
+    fn drop(&mut self) {
+        let mut watchers = self.watchers.lock();
+        // only the map and this watcher are left
+        if let Some(value) = watchers.get(&self.alias) {
+            if value.reader_count() == 1 {
+                watchers.remove(&self.alias);
+            }
+        }
+    }
+}
+
+/// A change of the local store, see `StorageService::store_events`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StoreEvent {
+    /// A block of the given size was inserted. Inserting a block that is
+    /// already stored reports it again.
+    Inserted(Cid, usize),
+    /// A block was removed by the garbage collector, a verification or
+    /// `Batch::remove`.
+    Evicted(Cid),
+    /// An alias was changed, `None` if it was removed. Pins and other
+    /// reserved aliases aren't reported.
+    AliasChanged { alias: Vec<u8>, cid: Option<Cid> },
+}
+
+#[derive(Debug, Default)]
+struct StoreEventQueue {
+    events: VecDeque<StoreEvent>,
+    dropped: u64,
+    waker: Option<Waker>,
+    /// Set once the store is dropped.
+    closed: bool,
+}
+
+/// The subscribers of `StorageService::store_events`.
+#[derive(Clone)]
+struct StoreEventSubscribers {
+    buffer: usize,
+    queues: Arc<Mutex<Vec<Arc<Mutex<StoreEventQueue>>>>>,
+}
+
+impl StoreEventSubscribers {
+    fn new(buffer: usize) -> Self {
+        Self {
+            buffer: buffer.max(1),
+            queues: Default::default(),
+        }
+    }
+
+    fn subscribe(&self) -> StoreEvents {
+        let queue = Arc::new(Mutex::new(StoreEventQueue::default()));
+        self.queues.lock().push(queue.clone());
+        StoreEvents(queue)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.lock().is_empty()
+    }
+
+    /// Queues `events` for every subscriber. The oldest queued events of a
+    /// subscriber are dropped once it lags behind by more than the buffer,
+    /// so writers never wait for subscribers.
+    fn notify(&self, events: &[StoreEvent]) {
+        let mut queues = self.queues.lock();
+        // only the list is left of dropped subscribers
+        queues.retain(|queue| Arc::strong_count(queue) > 1);
+        for queue in queues.iter() {
+            let waker = {
+                let mut queue = queue.lock();
+                for event in events {
+                    if queue.events.len() >= self.buffer {
+                        queue.events.pop_front();
+                        queue.dropped += 1;
+                    }
+                    queue.events.push_back(event.clone());
+                }
+                queue.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Ends the streams of all subscribers after the queued events.
+    fn close(&self) {
+        for queue in self.queues.lock().drain(..) {
+            let waker = {
+                let mut queue = queue.lock();
+                queue.closed = true;
+                queue.waker.take()
+            };
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A stream of the changes of the local store, see
+/// `StorageService::store_events`.
+#[derive(Debug)]
+pub struct StoreEvents(Arc<Mutex<StoreEventQueue>>);
+
+impl StoreEvents {
+    /// Returns the number of events dropped because the subscriber lagged
+    /// behind by more than `StorageConfig::store_events_buffer`.
+    pub fn dropped_events(&self) -> u64 {
+        self.0.lock().dropped
+    }
+}
+
+impl Stream for StoreEvents {
+    type Item = StoreEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Option<Self::Item>> {
+        let mut queue = self.0.lock();
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[derive(Clone, Default)]
+struct GcEvents(Arc<Mutex<Vec<mpsc::UnboundedSender<GcEvent>>>>);
+
+impl GcEvents {
+    fn subscribe(&self) -> mpsc::UnboundedReceiver<GcEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.0.lock().push(tx);
+        rx
+    }
+
+    fn notify(&self, event: GcEvent) {
+        self.0.lock().retain(|tx| tx.unbounded_send(event).is_ok());
+    }
+}
+
+/// Runs a single incremental gc sweep and reports it to the subscribers.
+fn gc_sweep<S: StoreParams>(
+    store: &mut dyn BlockStoreBackend<S>,
+    gc_min_blocks: usize,
+    gc_target_duration: Duration,
+    policy: &Mutex<GcPolicy>,
+    events: &GcEvents,
+    store_events: &StoreEventSubscribers,
+) -> Result<GcStats>
+where
+    Ipld: References<S::Codecs>,
+{
+    let (expired, _kept) = {
+        let mut txn = Batch(store.transaction(), None, vec![]);
+        let expired = txn.expire_leases(Utc::now())?;
+        // released once the sweep is done
+        let kept = txn.keep_blocks(&policy.lock().clone())?;
+        txn.0.commit()?;
+        (expired, kept)
+    };
+    if expired > 0 {
+        tracing::debug!("removed {} expired leases", expired);
+    }
+    let before = store.stats()?;
+    events.notify(GcEvent::Started {
+        blocks: before.blocks,
+        bytes: before.bytes,
+    });
+    // the evicted blocks are only listed for subscribers
+    let stored = if store_events.is_empty() {
+        None
+    } else {
+        Some(store.transaction().iter()?)
+    };
+    let start = Instant::now();
+    let complete = store.incremental_gc(gc_min_blocks, gc_target_duration)?;
+    let duration = start.elapsed();
+    if let Some(stored) = stored {
+        let kept = store
+            .transaction()
+            .iter()?
+            .into_iter()
+            .collect::<FnvHashSet<_>>();
+        let evicted = stored
+            .into_iter()
+            .filter(|cid| !kept.contains(cid))
+            .map(StoreEvent::Evicted)
+            .collect::<Vec<_>>();
+        store_events.notify(&evicted);
+    }
+    let after = store.stats()?;
+    let stats = GcStats {
+        blocks_evicted: before.blocks.saturating_sub(after.blocks),
+        bytes_evicted: before.bytes.saturating_sub(after.bytes),
+        duration,
+        complete,
+    };
+    events.notify(GcEvent::Finished(stats));
+    Ok(stats)
+}
+
+/// Compacts `store`, measuring the reclaimed space.
+fn compact<S: StoreParams>(store: &mut dyn BlockStoreBackend<S>) -> Result<CompactStats> {
+    let before = store.disk_usage()?.unwrap_or_default();
+    let start = Instant::now();
+    store.compact()?;
+    let duration = start.elapsed();
+    let after = store.disk_usage()?.unwrap_or_default();
+    Ok(CompactStats {
+        bytes_reclaimed: before.total.saturating_sub(after.total),
+        duration,
+    })
+}
+
+/// Number of blocks copied per transaction of a backup.
+const BACKUP_BATCH_SIZE: usize = 1000;
+
+/// Copies the blocks, aliases and metadata of `store` to a new sqlite store
+/// at `dest`. Writes to the source aren't isolated from the copy, so the
+/// aliases are read before the blocks: aliased blocks aren't evicted, and
+/// blocks are written before they are aliased, so every copied alias has its
+/// dag copied too.
+fn backup<S: StoreParams>(store: &mut dyn BlockStoreBackend<S>, dest: &Path) -> Result<()>
+where
+    Ipld: References<S::Codecs>,
+{
+    if dest.exists() {
+        return Err(anyhow::anyhow!(
+            "backup destination {} already exists",
+            dest.display()
+        ));
+    }
+    let config = StorageConfig::new(Some(dest.into()), None, u64::MAX, Duration::MAX);
+    let mut target = SqliteBackend::<S>::open(&config)?;
+    let mut source = store.transaction();
+    let aliases = source.aliases()?;
+    let cids = source.iter()?;
+    for cids in cids.chunks(BACKUP_BATCH_SIZE) {
+        let mut txn = target.transaction();
+        for cid in cids {
+            // blocks evicted before the transaction started aren't listed
+            if let Some(data) = source.get(cid)? {
+                txn.insert(Block::new_unchecked(*cid, data))?;
+            }
+        }
+        txn.commit()?;
+    }
+    let mut txn = target.transaction();
+    for (alias, cid) in aliases {
+        txn.alias(&alias, Some(&cid))?;
+    }
+    for (key, value) in source.metadata_with_prefix(&[])? {
+        txn.set_metadata(&key, Some(&value))?;
+    }
+    txn.commit()?;
+    target.flush(Durability::Sync)
+}
+
+/// Number of blocks copied per transaction of an import.
+const IMPORT_BATCH_SIZE: usize = 256;
+
+/// Compacts `store` if its free space exceeds `threshold` percent.
+fn auto_compact<S: StoreParams>(
+    store: &mut dyn BlockStoreBackend<S>,
+    threshold: Option<u8>,
+) -> Result<()> {
+    let threshold = match threshold {
+        Some(threshold) => threshold as u64,
+        None => return Ok(()),
+    };
+    match store.disk_usage()? {
+        Some(usage) if usage.free * 100 > usage.total * threshold => {
+            let stats = compact(store)?;
+            tracing::info!(
+                "compacted the store, reclaimed {} bytes in {:?}",
+                stats.bytes_reclaimed,
+                stats.duration
+            );
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Runs `sweep` every `interval`. A message on `wakeup` makes it pick up a
+/// changed interval without waiting out the old one, `true` also sweeps
+/// right away.
+async fn gc_loop(
+    mut sweep: impl FnMut() -> Result<GcStats>,
+    interval: Reader<Duration>,
+    mut wakeup: mpsc::UnboundedReceiver<bool>,
+) {
+    let mut last_sweep = Instant::now();
+    loop {
+        let delay = interval
+            .get()
+            .checked_sub(last_sweep.elapsed())
+            .unwrap_or_default();
+        let sweep_now = match future::select(futures_timer::Delay::new(delay), wakeup.next()).await
+        {
+            Either::Left(_) => true,
+            Either::Right((Some(sweep_now), _)) => sweep_now,
+            Either::Right((None, _)) => return,
+        };
+        if sweep_now {
+            info!("going for gc!");
+            sweep()
+                .map_err(|e| {
+                    tracing::warn!("failure during incremental gc: {:#}", e);
+                    e
+                })
+                .ok();
+            last_sweep = Instant::now();
         }
     }
 }
 
 struct StorageServiceInner<S: StoreParams> {
     executor: Executor,
-    store: Arc<Mutex<BlockStore<S>>>,
+    store: Arc<Mutex<Box<dyn BlockStoreBackend<S>>>>,
     gc_target_duration: Duration,
     gc_min_blocks: usize,
-    gc_task: Option<JoinHandle<()>>,
+    gc_interval: Variable<Duration>,
+    gc_wakeup: mpsc::UnboundedSender<bool>,
+    /// Size targets not yet applied to the handle of the gc task.
+    gc_size_targets: SizeTargets,
+    gc_events: GcEvents,
+    gc_task: Mutex<Option<JoinHandle<()>>>,
+    gc_monitor: TaskMonitor,
+    shut_down: AtomicBool,
+    read_only: bool,
+    max_bytes: Option<u64>,
+    used_bytes: Arc<AtomicU64>,
+    bitswap_quota_policy: QuotaPolicy,
+    evicting: AtomicBool,
+    auto_compact_threshold: Option<u8>,
+    gc_policy: Arc<Mutex<GcPolicy>>,
+    gc_mode: GcMode,
+    alias_watchers: AliasWatchers,
+    store_events: StoreEventSubscribers,
 }
 
 impl<S: StoreParams> Drop for StorageServiceInner<S> {
     fn drop(&mut self) {
-        if let Some(t) = self.gc_task.take() {
+        // dropping `gc_wakeup` ends the loop before the abort is noticed
+        self.gc_monitor.stop();
+        if let Some(t) = self.gc_task.get_mut().take() {
             t.abort()
         }
+        self.store_events.close();
     }
 }
 
@@ -114,7 +1101,25 @@ where
     Ipld: References<S::Codecs>,
 {
     pub fn open(config: StorageConfig, executor: Executor) -> Result<Self> {
-        let inner = StorageServiceInner::open(config, executor)?;
+        let backend: Box<dyn BlockStoreBackend<S>> = match config.backend {
+            BackendKind::Sqlite => Box::new(SqliteBackend::open(&config)?),
+            BackendKind::Memory => Box::new(MemBackend::new(
+                config.cache_size_blocks,
+                config.cache_size_bytes,
+            )),
+        };
+        Self::open_with_backend(config, backend, executor)
+    }
+
+    /// Opens a storage service backed by `backend`. The path and backend
+    /// settings of `config` are ignored, `read_only` and `compression` are
+    /// applied on top of `backend`.
+    pub fn open_with_backend(
+        config: StorageConfig,
+        backend: Box<dyn BlockStoreBackend<S>>,
+        executor: Executor,
+    ) -> Result<Self> {
+        let inner = StorageServiceInner::open(config, backend, executor)?;
         Ok(Self {
             inner: Arc::new(inner),
         })
@@ -125,81 +1130,159 @@ impl<S: StoreParams> StorageServiceInner<S>
 where
     Ipld: References<S::Codecs>,
 {
-    pub fn open(config: StorageConfig, executor: Executor) -> Result<Self> {
-        let store_config = Config::default()
-            .with_size_targets(config.cache_size_blocks, config.cache_size_bytes)
-            .with_pragma_synchronous(Synchronous::Normal);
-        let tracker: Arc<dyn CacheTracker> = if let Some(path) = config.access_db_path {
-            let path = if path.is_file() {
-                path
-            } else {
-                std::fs::create_dir_all(&path)?;
-                path.join("access")
-            };
-            Arc::new(SqliteCacheTracker::open(&path, |access, _| Some(access))?)
-        } else {
-            Arc::new(InMemCacheTracker::new(|access, _| Some(access)))
-        };
-
-        let is_memory = config.path.is_none();
-        // create DB connection
-        let store = if let Some(path) = config.path {
-            let path = if path.is_file() {
-                path
-            } else {
-                std::fs::create_dir_all(&path)?;
-                path.join("db")
-            };
-            BlockStore::open(path, store_config.with_cache_tracker(tracker))?
+    pub fn open(
+        config: StorageConfig,
+        mut backend: Box<dyn BlockStoreBackend<S>>,
+        executor: Executor,
+    ) -> Result<Self> {
+        #[cfg(feature = "compression")]
+        {
+            backend = Box::new(CompressedBackend::new(backend, config.compression));
+        }
+        backend = Box::new(InsertLogBackend::new(backend));
+        if config.read_only {
+            backend = Box::new(ReadOnlyBackend(backend));
+        }
+        let gc_handle = backend.gc_handle()?;
+        let used_bytes = if config.max_bytes.is_some() {
+            backend.stats()?.bytes
         } else {
-            BlockStore::memory(store_config.with_cache_tracker(tracker))?
+            0
         };
-        let store = Arc::new(Mutex::new(store));
+        let used_bytes = Arc::new(AtomicU64::new(used_bytes));
+        let store = Arc::new(Mutex::new(backend));
 
         // spawn GC task
-        let gc_interval = config.gc_interval;
+        let gc_interval = Variable::new(config.gc_interval);
+        let (gc_wakeup, wakeup) = mpsc::unbounded();
+        let gc_size_targets = Arc::new(Mutex::new(None));
+        let size_targets = gc_size_targets.clone();
         let gc_min_blocks = config.gc_min_blocks;
         let gc_target_duration = config.gc_target_duration;
-        let gc_task = if is_memory {
-            let gc = store.clone();
-            executor.spawn(async move {
-                loop {
-                    futures_timer::Delay::new(gc_interval).await;
-                    info!("going for gc!");
-                    gc.lock()
-                        .incremental_gc(gc_min_blocks, gc_target_duration)
-                        .map_err(|e| {
-                            tracing::warn!("failure during incremental gc: {:#}", e);
-                            e
-                        })
-                        .ok();
-                }
-            })
+        let auto_compact_threshold = config.auto_compact_threshold;
+        let gc_policy = Arc::new(Mutex::new(config.gc_policy.clone()));
+        let policy = gc_policy.clone();
+        let gc_events = GcEvents::default();
+        let events = gc_events.clone();
+        let store_events = StoreEventSubscribers::new(config.store_events_buffer);
+        let subscribers = store_events.clone();
+        if config.max_bytes.is_some() {
+            let used_bytes = used_bytes.clone();
+            let mut gc_events = gc_events.subscribe();
+            executor
+                .spawn(async move {
+                    while let Some(event) = gc_events.next().await {
+                        if let GcEvent::Finished(stats) = event {
+                            used_bytes
+                                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                                    Some(used.saturating_sub(stats.bytes_evicted))
+                                })
+                                .ok();
+                        }
+                    }
+                })
+                .detach();
+        }
+        let gc_monitor = TaskMonitor::new(BackgroundTask::Gc);
+        let gc_task = if config.read_only || config.gc == GcMode::Disabled {
+            None
+        } else if let Some(mut gc) = gc_handle {
+            Some(executor.spawn_monitored(
+                &gc_monitor,
+                gc_loop(
+                    move || {
+                        if let Some((blocks, bytes)) = size_targets.lock().take() {
+                            gc.set_size_targets(blocks, bytes)?;
+                        }
+                        let stats = gc_sweep(
+                            &mut *gc,
+                            gc_min_blocks,
+                            gc_target_duration,
+                            &policy,
+                            &events,
+                            &subscribers,
+                        )?;
+                        auto_compact(&mut *gc, auto_compact_threshold)?;
+                        Ok(stats)
+                    },
+                    gc_interval.reader(),
+                    wakeup,
+                ),
+            ))
         } else {
-            let mut gc = store.lock().additional_connection()?;
-            executor.spawn(async move {
-                loop {
-                    futures_timer::Delay::new(gc_interval).await;
-                    info!("going for gc!");
-                    gc.incremental_gc(gc_min_blocks, gc_target_duration)
-                        .map_err(|e| {
-                            tracing::warn!("failure during incremental gc: {:#}", e);
-                            e
-                        })
-                        .ok();
-                }
-            })
+            let gc = store.clone();
+            Some(executor.spawn_monitored(
+                &gc_monitor,
+                gc_loop(
+                    move || {
+                        let mut gc = gc.lock();
+                        let stats = gc_sweep(
+                            &mut **gc,
+                            gc_min_blocks,
+                            gc_target_duration,
+                            &policy,
+                            &events,
+                            &subscribers,
+                        )?;
+                        auto_compact(&mut **gc, auto_compact_threshold)?;
+                        Ok(stats)
+                    },
+                    gc_interval.reader(),
+                    wakeup,
+                ),
+            ))
         };
         Ok(Self {
             executor,
             gc_target_duration: config.gc_target_duration,
             gc_min_blocks: config.gc_min_blocks,
             store,
-            gc_task: Some(gc_task),
+            gc_interval,
+            gc_wakeup,
+            gc_size_targets,
+            gc_events,
+            gc_task: Mutex::new(gc_task),
+            gc_monitor,
+            shut_down: AtomicBool::new(false),
+            read_only: config.read_only,
+            max_bytes: config.max_bytes,
+            used_bytes,
+            bitswap_quota_policy: config.bitswap_quota_policy,
+            evicting: AtomicBool::new(false),
+            auto_compact_threshold: config.auto_compact_threshold,
+            gc_policy,
+            gc_mode: config.gc,
+            alias_watchers: Default::default(),
+            store_events,
         })
     }
 }
 
+/// Returns the changes of a failed batch that are still in the store. An
+/// alias change is kept if the alias has the value it was changed to.
+fn kept_changes<S: StoreParams>(
+    txn: &mut dyn BackendTransaction<S>,
+    changes: Vec<StoreEvent>,
+) -> Result<Vec<StoreEvent>> {
+    let mut kept = vec![];
+    let mut aliases = FnvHashSet::default();
+    // only the last change of an alias can still be in effect
+    for change in changes.into_iter().rev() {
+        let is_kept = match &change {
+            StoreEvent::Inserted(cid, _) => txn.contains(cid)?,
+            StoreEvent::Evicted(cid) => !txn.contains(cid)?,
+            StoreEvent::AliasChanged { alias, cid } => {
+                aliases.insert(alias.clone()) && txn.resolve(alias)? == *cid
+            }
+        };
+        if is_kept {
+            kept.push(change);
+        }
+    }
+    kept.reverse();
+    Ok(kept)
+}
+
 impl<S: StoreParams> StorageService<S>
 where
     Ipld: References<S::Codecs>,
@@ -209,6 +1292,9 @@ where
         op: &'static str,
         f: F,
     ) -> Result<R> {
+        if self.inner.shut_down.load(Ordering::Relaxed) {
+            return Err(Shutdown.into());
+        }
         QUERIES_TOTAL.with_label_values(&[op]).inc();
         let timer = QUERY_DURATION
             .with_label_values(&["lock_wait"])
@@ -219,18 +1305,199 @@ where
             tracing::warn!(op, "very long storage lock wait time of {:.1}s", t);
         }
         let _timer = QUERY_DURATION.with_label_values(&[op]).start_timer();
-        let mut txn = Batch(lock.transaction());
-        let res = f(&mut txn);
-        if res.is_ok() {
-            txn.0.commit()?;
-        }
-        res
-    }
-
+        let used = self.inner.used_bytes.load(Ordering::Relaxed);
+        let quota = self
+            .inner
+            .max_bytes
+            .map(|max_bytes| Quota { max_bytes, used });
+        let (res, txn, quota, changes) = {
+            let mut batch = Batch(lock.transaction(), quota, vec![]);
+            let res = f(&mut batch);
+            let Batch(txn, quota, changes) = batch;
+            (res, txn, quota, changes)
+        };
+        let changes = if res.is_ok() {
+            txn.commit()?;
+            if let Some(quota) = quota {
+                // removed blocks can shrink the store within a batch
+                self.inner
+                    .used_bytes
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                        Some((current + quota.used).saturating_sub(used))
+                    })
+                    .ok();
+            }
+            changes
+        } else {
+            drop(txn);
+            // backends that can't roll back, like the sqlite store, keep the
+            // writes made before the failure
+            if quota.is_some() {
+                match lock.stats() {
+                    Ok(stats) => self.inner.used_bytes.store(stats.bytes, Ordering::Relaxed),
+                    Err(err) => tracing::warn!("failed to read the store size: {}", err),
+                }
+            }
+            match kept_changes(&mut *lock.transaction(), changes) {
+                Ok(changes) => changes,
+                Err(err) => {
+                    tracing::warn!("failed to read the changes of a failed batch: {}", err);
+                    vec![]
+                }
+            }
+        };
+        if !changes.is_empty() {
+            self.notify_alias_watchers(&changes);
+            self.inner.store_events.notify(&changes);
+        }
+        res
+    }
+
+    /// Updates the watched aliases among the aliases changed by a commit.
+    /// Called with the store locked, so watchers see the changes in order.
+    fn notify_alias_watchers(&self, changes: &[StoreEvent]) {
+        let watchers = self.inner.alias_watchers.lock();
+        if watchers.is_empty() {
+            return;
+        }
+        for change in changes {
+            if let StoreEvent::AliasChanged { alias, cid } = change {
+                if let Some(value) = watchers.get(alias) {
+                    if *value.read() != *cid {
+                        *value.write() = *cid;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Subscribes to the blocks inserted in and evicted from the store and
+    /// the changed aliases. Events are queued once the write is committed,
+    /// all events of a batch at once. The writes of a failed batch that a
+    /// backend can't roll back, like the sqlite store, are reported too.
+    /// Evictions are reported after the garbage collector sweep that evicted
+    /// them. A subscriber lagging behind by more than
+    /// `StorageConfig::store_events_buffer` loses the oldest events, see
+    /// `StoreEvents::dropped_events`.
+    pub fn store_events(&self) -> StoreEvents {
+        self.inner.store_events.subscribe()
+    }
+
+    /// Returns a stream yielding the current value of `alias` and then every
+    /// change, with `None` once it is removed. Changes made before the stream
+    /// is polled again are coalesced, only the latest value is yielded.
+    /// Watchers of the same alias share one variable, which is removed when
+    /// the last of them is dropped.
+    pub fn watch_alias(&self, alias: &[u8]) -> Result<AliasWatcher> {
+        if alias.starts_with(&RESERVED_ALIAS_PREFIX[..1]) {
+            return Err(ReservedAlias.into());
+        }
+        let watchers = self.inner.alias_watchers.clone();
+        // registering under the store lock, so no change can be missed
+        let observer = self.rw("watch_alias", |x| {
+            let mut watchers = watchers.lock();
+            if let Some(value) = watchers.get(alias) {
+                return Ok(value.observe());
+            }
+            let value = Variable::new(x.resolve(alias)?);
+            let observer = value.observe();
+            watchers.insert(alias.to_vec(), value);
+            Ok(observer)
+        })?;
+        Ok(AliasWatcher {
+            alias: alias.to_vec(),
+            observer,
+            watchers,
+        })
+    }
+
+    /// Runs `f` in a transaction. The writes of `f` are only applied if it
+    /// returns `Ok` and they pass the checks of `AtomicBatch`.
+    pub fn transaction<F: FnOnce(&mut AtomicBatch<'_, '_, S>) -> Result<R>, R>(
+        &self,
+        f: F,
+    ) -> Result<R> {
+        self.rw("transaction", |batch| {
+            let mut tx = AtomicBatch::new(batch);
+            let res = f(&mut tx)?;
+            tx.apply()?;
+            Ok(res)
+        })
+    }
+
     pub fn create_temp_pin(&self) -> Result<TempPin> {
         self.rw("create_temp_pin", |x| x.create_temp_pin())
     }
 
+    /// Creates a lease or resumes an existing one with the same name. The
+    /// lease is renewed with the new `ttl`.
+    pub fn create_lease(&self, name: &[u8], ttl: Duration) -> Result<Lease<S>> {
+        let lease = Lease {
+            storage: self.clone(),
+            name: name.to_vec(),
+            ttl,
+        };
+        lease.renew()?;
+        Ok(lease)
+    }
+
+    /// Lists the leases with their expiry.
+    pub fn leases(&self) -> Result<Vec<(Vec<u8>, DateTime<Utc>)>> {
+        self.rw("leases", |x| x.leases())
+    }
+
+    /// Persists a ban of `peer`, replacing an earlier ban. `None` bans the
+    /// peer permanently.
+    pub fn ban(&self, peer: &PeerId, expires: Option<DateTime<Utc>>) -> Result<()> {
+        let ban = encode_ban(expires)?;
+        self.rw("ban", |x| x.0.set_metadata(&ban_key(peer), Some(&ban)))
+    }
+
+    /// Removes a persisted ban, returning whether `peer` was banned.
+    pub fn unban(&self, peer: &PeerId) -> Result<bool> {
+        self.rw("unban", |x| {
+            let banned = x.0.metadata(&ban_key(peer))?.is_some();
+            x.0.set_metadata(&ban_key(peer), None)?;
+            Ok(banned)
+        })
+    }
+
+    /// Replaces the persisted address book.
+    pub fn save_address_book(&self, book: &[PeerAddresses]) -> Result<()> {
+        let data = encode_address_book(book)?;
+        self.rw("save_address_book", |x| {
+            x.0.set_metadata(ADDRESS_BOOK_KEY, Some(&data))
+        })
+    }
+
+    /// Returns the persisted address book.
+    pub fn address_book(&self) -> Result<Vec<PeerAddresses>> {
+        self.rw("address_book", |x| {
+            match x.0.metadata(ADDRESS_BOOK_KEY)? {
+                Some(data) => decode_address_book(&data),
+                None => Ok(vec![]),
+            }
+        })
+    }
+
+    /// Lists the banned peers with the expiry of their ban. Expired bans are
+    /// removed, unless the store is read only.
+    pub fn bans(&self) -> Result<Vec<(PeerId, Option<DateTime<Utc>>)>> {
+        let read_only = self.inner.read_only;
+        self.rw("bans", |x| {
+            let now = Utc::now();
+            let mut bans = vec![];
+            for (peer, expires) in x.bans()? {
+                if expires.map(|expires| expires > now).unwrap_or(true) {
+                    bans.push((peer, expires));
+                } else if !read_only {
+                    x.0.set_metadata(&ban_key(&peer), None)?;
+                }
+            }
+            Ok(bans)
+        })
+    }
+
     pub fn temp_pin(
         &self,
         temp: &mut TempPin,
@@ -243,22 +1510,92 @@ where
         self.rw("iter", |x| x.iter())
     }
 
+    /// Iterates over all blocks and their metadata. The metadata is read in a
+    /// single pass over the store, without keeping the block data around.
+    pub fn iter_with_metadata(&self) -> Result<impl Iterator<Item = Result<BlockInfo>>> {
+        let infos = self.rw("iter_with_metadata", |x| x.block_infos())?;
+        Ok(infos.into_iter().map(Ok))
+    }
+
+    /// Returns the blocks matching `filter`. The filter is evaluated in a
+    /// single transaction, reading block data only for size predicates.
+    pub fn iter_filtered(&self, filter: &BlockFilter) -> Result<Vec<Cid>> {
+        self.rw("iter_filtered", |x| x.iter_filtered(filter))
+    }
+
     pub fn contains(&self, cid: &Cid) -> Result<bool> {
         self.rw("contains", |x| x.contains(cid))
     }
 
+    pub fn refs(&self, cid: &Cid) -> Result<Option<Vec<Cid>>> {
+        self.rw("refs", |x| x.refs(cid))
+    }
+
+    pub fn refs_recursive(&self, cid: &Cid, max_depth: Option<usize>) -> Result<Refs> {
+        self.rw("refs_recursive", |x| x.refs_recursive(cid, max_depth))
+    }
+
+    pub fn dag_stat(&self, cid: &Cid) -> Result<DagStat> {
+        self.rw("dag_stat", |x| x.dag_stat(cid))
+    }
+
     pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
         self.rw("get", |x| x.get(cid))
     }
 
+    pub fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.rw("get_many", |x| x.get_many(cids))
+    }
+
+    pub fn contains_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        self.rw("contains_many", |x| x.contains_many(cids))
+    }
+
     pub fn insert(&self, block: Block<S>) -> Result<()> {
         self.rw("insert", |x| x.insert(block))
     }
 
+    /// Inserts a block received from a peer, applying the
+    /// `bitswap_quota_policy`. Without a garbage collector blocks over the
+    /// quota are always rejected.
+    pub(crate) fn insert_received(&self, block: Block<S>) -> Result<()> {
+        if self.inner.bitswap_quota_policy == QuotaPolicy::Reject
+            || self.inner.gc_mode == GcMode::Disabled
+        {
+            return self.insert(block);
+        }
+        let over_quota = self.rw("insert", |x| {
+            x.insert0(block, false)?;
+            Ok(x.is_over_quota())
+        })?;
+        if over_quota && !self.inner.evicting.swap(true, Ordering::Relaxed) {
+            let storage = self.clone();
+            self.inner
+                .executor
+                .spawn(async move {
+                    if let Err(err) = storage.evict().await {
+                        tracing::warn!("failure during quota eviction: {:#}", err);
+                    }
+                    storage.inner.evicting.store(false, Ordering::Relaxed);
+                })
+                .detach();
+        }
+        Ok(())
+    }
+
     pub fn alias(&self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
         self.rw("alias", |x| x.alias(alias, cid))
     }
 
+    pub fn alias_cas(
+        &self,
+        alias: &[u8],
+        expected: Option<&Cid>,
+        new: Option<&Cid>,
+    ) -> Result<std::result::Result<(), Option<Cid>>> {
+        self.rw("alias_cas", |x| x.alias_cas(alias, expected, new))
+    }
+
     pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
         self.rw("aliases", |x| x.aliases())
     }
@@ -275,29 +1612,345 @@ where
         self.rw("missing_blocks", |x| x.missing_blocks(cid))
     }
 
-    pub fn evict(&self) -> impl Future<Output = Result<()>> {
+    pub fn pin(&self, cid: &Cid) -> Result<()> {
+        self.rw("pin", |x| x.pin(cid))
+    }
+
+    pub fn unpin(&self, cid: &Cid) -> Result<bool> {
+        self.rw("unpin", |x| x.unpin(cid))
+    }
+
+    pub fn pins(&self) -> Result<Vec<(Cid, usize)>> {
+        self.rw("pins", |x| x.pins())
+    }
+
+    pub fn pin_status(&self, cid: &Cid) -> Result<Option<PinStatus>> {
+        self.rw("pin_status", |x| x.pin_status(cid))
+    }
+
+    pub fn stats(&self) -> Result<BackendStats> {
+        if self.inner.shut_down.load(Ordering::Relaxed) {
+            return Err(Shutdown.into());
+        }
+        self.inner.store.lock().stats()
+    }
+
+    pub fn evict(&self) -> impl Future<Output = Result<GcStats>> {
+        if self.inner.shut_down.load(Ordering::Relaxed) {
+            return future::ready(Err(Shutdown.into())).left_future();
+        }
+        if self.inner.read_only {
+            return future::ready(Err(ReadOnly.into())).left_future();
+        }
+        if self.inner.gc_mode == GcMode::Disabled {
+            return future::ready(Err(GcDisabled.into())).left_future();
+        }
         let store = self.inner.store.clone();
         let gc_min_blocks = self.inner.gc_min_blocks;
         let gc_target_duration = self.inner.gc_target_duration;
+        let events = self.inner.gc_events.clone();
+        let auto_compact_threshold = self.inner.auto_compact_threshold;
+        let policy = self.inner.gc_policy.clone();
+        let subscribers = self.inner.store_events.clone();
         let evict = self.inner.executor.spawn_blocking(move || {
-            while !store
-                .lock()
-                .incremental_gc(gc_min_blocks, gc_target_duration)?
-            {
+            let mut summary = GcStats::default();
+            loop {
+                let stats = gc_sweep(
+                    &mut **store.lock(),
+                    gc_min_blocks,
+                    gc_target_duration,
+                    &policy,
+                    &events,
+                    &subscribers,
+                )?;
+                summary.blocks_evicted += stats.blocks_evicted;
+                summary.bytes_evicted += stats.bytes_evicted;
+                summary.duration += stats.duration;
+                summary.complete = stats.complete;
+                if stats.complete {
+                    auto_compact(&mut **store.lock(), auto_compact_threshold)?;
+                    return Ok(summary);
+                }
                 tracing::trace!("x");
             }
+        });
+        async { evict.await? }.right_future()
+    }
+
+    /// Compacts the store, reclaiming the space left behind by removed
+    /// blocks. The sqlite store is compacted on a separate connection, so
+    /// reads keep working while writes wait for the compaction. In memory
+    /// stores are locked until it finishes.
+    pub fn compact(&self) -> impl Future<Output = Result<CompactStats>> {
+        if self.inner.shut_down.load(Ordering::Relaxed) {
+            return future::ready(Err(Shutdown.into())).left_future();
+        }
+        if self.inner.read_only {
+            return future::ready(Err(ReadOnly.into())).left_future();
+        }
+        let store = self.inner.store.clone();
+        let compact = self.inner.executor.spawn_blocking(move || {
+            let handle = store.lock().gc_handle()?;
+            match handle {
+                Some(mut handle) => compact(&mut *handle),
+                None => compact(&mut **store.lock()),
+            }
+        });
+        async { compact.await? }.right_future()
+    }
+
+    /// Copies the blocks of the sqlite store at `path` that are missing from
+    /// this store in batches, and merges its aliases if `aliases` is given.
+    /// Pins and leases of the other store aren't imported. The copied blocks
+    /// are temp pinned until the aliases are merged.
+    pub fn import_store(&self, path: &Path, aliases: Option<AliasConflict>) -> Result<ImportStats> {
+        if !path.exists() {
+            return Err(anyhow::anyhow!("there is no store at {}", path.display()));
+        }
+        let mut config = StorageConfig::new(Some(path.into()), None, u64::MAX, Duration::MAX);
+        config.read_only = true;
+        let mut source: Box<dyn BlockStoreBackend<S>> =
+            Box::new(SqliteBackend::open(&config).with_context(|| {
+                format!(
+                    "failed to open the store at {}, stores written by an incompatible \
+                     version can't be imported",
+                    path.display()
+                )
+            })?);
+        #[cfg(feature = "compression")]
+        {
+            source = Box::new(CompressedBackend::new(source, None));
+        }
+        let mut source = Batch(source.transaction(), None, vec![]);
+
+        // conflicts are detected before anything is written
+        let mut updates = vec![];
+        if let Some(policy) = aliases {
+            for (alias, theirs) in source.aliases()? {
+                match self.resolve(&alias)? {
+                    Some(ours) if ours == theirs => continue,
+                    Some(ours) => match policy {
+                        AliasConflict::Fail => {
+                            return Err(ConflictingAlias {
+                                alias,
+                                ours,
+                                theirs,
+                            }
+                            .into())
+                        }
+                        AliasConflict::KeepOurs => continue,
+                        AliasConflict::KeepTheirs => {}
+                    },
+                    None => {}
+                }
+                updates.push((alias, theirs));
+            }
+        }
+
+        let mut tmp = self.create_temp_pin()?;
+        let mut stats = ImportStats::default();
+        let cids = source.iter()?.collect::<Vec<_>>();
+        for cids in cids.chunks(IMPORT_BATCH_SIZE) {
+            let mut blocks = Vec::with_capacity(cids.len());
+            for cid in cids {
+                if let Some(data) = source.get(cid)? {
+                    blocks.push(Block::new_unchecked(*cid, data));
+                }
+            }
+            self.rw("import_store", |batch| {
+                for block in blocks {
+                    if batch.contains(block.cid())? {
+                        stats.blocks_skipped += 1;
+                    } else {
+                        stats.blocks_copied += 1;
+                        stats.bytes_copied += block.data().len() as u64;
+                        batch.insert(block)?;
+                    }
+                }
+                batch.temp_pin(&mut tmp, cids.iter().copied())
+            })?;
+        }
+        self.rw("import_store", |batch| {
+            for (alias, cid) in &updates {
+                batch.alias(alias, Some(cid))?;
+            }
             Ok(())
+        })?;
+        stats.aliases_imported = updates.len() as u64;
+        Ok(stats)
+    }
+
+    /// Copies the blocks, aliases, pins, bans and address book to a new
+    /// sqlite store at `dest`, which can be opened like any other store.
+    /// Every copied alias has its whole dag copied, blocks written during the
+    /// backup may or may not be included. Temp pins aren't included. Writes
+    /// continue while an on disk store is backed up, in memory stores are
+    /// locked until the backup finishes.
+    pub fn backup(&self, dest: &Path) -> impl Future<Output = Result<()>> {
+        if self.inner.shut_down.load(Ordering::Relaxed) {
+            return future::ready(Err(Shutdown.into())).left_future();
+        }
+        let store = self.inner.store.clone();
+        let dest = dest.to_path_buf();
+        let backup = self.inner.executor.spawn_blocking(move || {
+            // a separate connection keeps the store writable while copying
+            let handle = store.lock().gc_handle()?;
+            match handle {
+                Some(mut handle) => backup(&mut *handle, &dest),
+                None => backup(&mut **store.lock(), &dest),
+            }
         });
-        async { evict.await? }
+        async { backup.await? }.right_future()
+    }
+
+    /// Verifies the store in the background. Every block is rehashed and
+    /// compared to its `Cid`, then the aliases are checked for missing roots.
+    /// Dropping the receiver stops the verification.
+    pub fn verify(&self, config: VerifyConfig) -> mpsc::UnboundedReceiver<VerifyEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        let storage = self.clone();
+        self.inner
+            .executor
+            .spawn_blocking(move || {
+                if let Err(err) = storage.verify0(&config, &tx) {
+                    tx.unbounded_send(VerifyEvent::Failed(format!("{:#}", err)))
+                        .ok();
+                }
+            })
+            .detach();
+        rx
+    }
+
+    fn verify0(
+        &self,
+        config: &VerifyConfig,
+        tx: &mpsc::UnboundedSender<VerifyEvent>,
+    ) -> Result<()> {
+        let cids = self.iter()?.collect::<Vec<_>>();
+        let total = cids.len() as u64;
+        let mut checked = 0;
+        for chunk in cids.chunks(config.batch_size.max(1)) {
+            let events = self.rw("verify", |x| x.verify(chunk, config.remove_corrupt))?;
+            checked += chunk.len() as u64;
+            for event in events
+                .into_iter()
+                .chain(Some(VerifyEvent::Progress { checked, total }))
+            {
+                if tx.unbounded_send(event).is_err() {
+                    return Ok(());
+                }
+            }
+            std::thread::sleep(config.pause);
+        }
+        let aliases: Vec<(Vec<u8>, Cid)> = self.rw("verify", |x| x.0.aliases())?;
+        for (alias, cid) in aliases {
+            if !self.contains(&cid)? {
+                let event = VerifyEvent::Dangling { alias, cid };
+                if tx.unbounded_send(event).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn gc_events(&self) -> mpsc::UnboundedReceiver<GcEvent> {
+        self.inner.gc_events.subscribe()
+    }
+
+    /// Returns the status of the garbage collector task.
+    pub fn gc_status(&self) -> TaskStatus {
+        self.inner.gc_monitor.status()
+    }
+
+    pub(crate) fn gc_monitor(&self) -> &TaskMonitor {
+        &self.inner.gc_monitor
+    }
+
+    pub fn set_sweep_interval(&self, interval: Duration) {
+        *self.inner.gc_interval.write() = interval;
+        self.inner.gc_wakeup.unbounded_send(false).ok();
+    }
+
+    /// Changes the size targets of the garbage collector, `None` removes a
+    /// target. Sweeps right away if the store exceeds the new targets. Fails
+    /// for backends that take their size targets when they are opened.
+    pub fn set_cache_size(&self, blocks: Option<u64>, bytes: Option<u64>) -> Result<()> {
+        let stats = {
+            let mut store = self.inner.store.lock();
+            store.set_size_targets(blocks, bytes)?;
+            store.stats()?
+        };
+        *self.inner.gc_size_targets.lock() = Some((blocks, bytes));
+        let exceeded = blocks.map_or(false, |blocks| stats.blocks > blocks)
+            || bytes.map_or(false, |bytes| stats.bytes > bytes);
+        self.inner.gc_wakeup.unbounded_send(exceeded).ok();
+        Ok(())
+    }
+
+    /// Adds a block to `GcPolicy::exclude`, so the garbage collector never
+    /// evicts it. The exclusion list is not persisted.
+    pub fn gc_exclude(&self, cid: &Cid) {
+        self.inner.gc_policy.lock().exclude.insert(*cid);
+    }
+
+    /// Removes a block from `GcPolicy::exclude`. Returns `false` if it
+    /// wasn't excluded.
+    pub fn gc_include(&self, cid: &Cid) -> bool {
+        self.inner.gc_policy.lock().exclude.remove(cid)
+    }
+
+    /// Returns the current garbage collector policy.
+    pub fn gc_policy(&self) -> GcPolicy {
+        self.inner.gc_policy.lock().clone()
     }
 
     pub fn flush(&self) -> impl Future<Output = Result<()>> {
+        self.flush_with(Durability::Sync)
+    }
+
+    /// Makes all writes that completed before the call at least as durable as
+    /// `durability`. Writes are committed while holding the store lock, which
+    /// the flush acquires after them.
+    pub fn flush_with(&self, durability: Durability) -> impl Future<Output = Result<()>> {
+        if self.inner.shut_down.load(Ordering::Relaxed) {
+            return future::ready(Err(Shutdown.into())).left_future();
+        }
+        self.flush0(durability).right_future()
+    }
+
+    /// Stops the garbage collector and flushes the store. All later
+    /// operations fail with `Shutdown`.
+    pub fn shutdown(&self) -> impl Future<Output = Result<()>> {
+        if self.inner.shut_down.swap(true, Ordering::Relaxed) {
+            return future::ready(Err(Shutdown.into())).left_future();
+        }
+        self.inner.gc_monitor.stop();
+        if let Some(task) = self.inner.gc_task.lock().take() {
+            task.abort();
+        }
+        self.flush0(Durability::Sync).right_future()
+    }
+
+    fn flush0(&self, durability: Durability) -> impl Future<Output = Result<()>> {
         let store = self.inner.store.clone();
         let flush = self
             .inner
             .executor
-            .spawn_blocking(move || store.lock().flush());
-        async { Ok(observe_future("flush", flush).await??) }
+            .spawn_blocking(move || store.lock().flush(durability));
+        async { observe_future("flush", flush).await? }
+    }
+
+    /// Runs `f` on the blocking thread pool of the executor, so the disk io
+    /// doesn't stall the calling task.
+    pub fn spawn_blocking<T, F>(&self, f: F) -> impl Future<Output = Result<T>>
+    where
+        F: FnOnce(&Self) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let storage = self.clone();
+        let task = self.inner.executor.spawn_blocking(move || f(&storage));
+        async move { task.await? }
     }
 
     pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
@@ -349,7 +2002,7 @@ where
 }
 
 struct SqliteStoreCollector<S: StoreParams> {
-    store: Arc<Mutex<BlockStore<S>>>,
+    store: Arc<Mutex<Box<dyn BlockStoreBackend<S>>>>,
     desc: Desc,
 }
 
@@ -364,15 +2017,15 @@ where
     fn collect(&self) -> Vec<MetricFamily> {
         let mut family = vec![];
 
-        if let Ok(stats) = self.store.lock().get_store_stats() {
+        if let Ok(stats) = self.store.lock().stats() {
             let store_block_count =
                 IntGauge::new("block_store_block_count", "Number of stored blocks").unwrap();
-            store_block_count.set(stats.count() as _);
+            store_block_count.set(stats.blocks as _);
             family.push(store_block_count.collect()[0].clone());
 
             let store_size =
                 IntGauge::new("block_store_size", "Size in bytes of stored blocks").unwrap();
-            store_size.set(stats.size() as _);
+            store_size.set(stats.bytes as _);
             family.push(store_size.collect()[0].clone());
         }
 
@@ -381,7 +2034,7 @@ where
 }
 
 impl<S: StoreParams> SqliteStoreCollector<S> {
-    pub fn new(store: Arc<Mutex<BlockStore<S>>>) -> Self {
+    pub fn new(store: Arc<Mutex<Box<dyn BlockStoreBackend<S>>>>) -> Self {
         let desc = Desc::new(
             "block_store_stats".into(),
             ".".into(),
@@ -394,7 +2047,12 @@ impl<S: StoreParams> SqliteStoreCollector<S> {
 }
 
 /// A handle for performing batch operations on an ipfs storage
-pub struct Batch<'a, S>(ipfs_sqlite_block_store::Transaction<'a, S>);
+pub struct Batch<'a, S: StoreParams>(
+    Box<dyn BackendTransaction<S> + 'a>,
+    Option<Quota>,
+    /// Changes made by the batch, reported once it is committed.
+    Vec<StoreEvent>,
+);
 
 impl<'a, S: StoreParams> Batch<'a, S>
 where
@@ -402,13 +2060,16 @@ where
     Ipld: References<S::Codecs>,
 {
     pub fn create_temp_pin(&mut self) -> Result<TempPin> {
-        Ok(self.0.temp_pin())
+        self.0.create_temp_pin()
     }
 
+    /// Adds roots to a temporary pin. Pinning blocks inserted in the same
+    /// batch protects them from the garbage collector without a window in
+    /// between.
     pub fn temp_pin(
         &mut self,
         temp: &mut TempPin,
-        iter: impl IntoIterator<Item = Cid> + Send + 'static,
+        iter: impl IntoIterator<Item = Cid>,
     ) -> Result<()> {
         for link in iter {
             self.0.extend_temp_pin(temp, &link)?;
@@ -417,106 +2078,684 @@ where
     }
 
     pub fn iter(&mut self) -> Result<impl Iterator<Item = Cid>> {
-        let cids = self.0.get_block_cids::<Vec<Cid>>()?;
+        let cids = self.0.iter()?;
         Ok(cids.into_iter())
     }
 
     pub fn contains(&mut self, cid: &Cid) -> Result<bool> {
-        Ok(self.0.has_block(cid)?)
+        self.0.contains(cid)
     }
 
-    pub fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        Ok(self.0.get_block(cid)?)
+    /// Returns the blocks matching `filter`.
+    pub fn iter_filtered(&mut self, filter: &BlockFilter) -> Result<Vec<Cid>> {
+        let mut blocks = Vec::new();
+        for cid in self.0.iter()? {
+            if !filter.matches_cid(&cid) {
+                continue;
+            }
+            if filter.needs_size() {
+                match self.0.get(&cid)? {
+                    Some(data) if filter.matches_size(data.len()) => {}
+                    _ => continue,
+                }
+            }
+            let inserted = if filter.inserted_after.is_some() || filter.sort_by_insertion {
+                self.0.inserted_at(&cid)?
+            } else {
+                None
+            };
+            if let Some(after) = filter.inserted_after {
+                if !matches!(inserted, Some(inserted) if inserted > after) {
+                    continue;
+                }
+            }
+            if let Some(pinned) = filter.pinned {
+                let is_pinned = self
+                    .pin_status(&cid)?
+                    .map(|status| status.is_retained())
+                    .unwrap_or_default();
+                if is_pinned != pinned {
+                    continue;
+                }
+            }
+            blocks.push((inserted, cid));
+        }
+        if filter.sort_by_insertion {
+            // stable, so blocks without a time keep the order of the backend
+            blocks.sort_by_key(|(inserted, _)| *inserted);
+        }
+        Ok(blocks.into_iter().map(|(_, cid)| cid).collect())
     }
 
-    pub fn insert(&mut self, block: Block<S>) -> Result<()> {
-        Ok(self.0.put_block(block, None)?)
+    /// Returns the direct references of a block in the order they are
+    /// encountered, or `None` if the block isn't stored.
+    pub fn refs(&mut self, cid: &Cid) -> Result<Option<Vec<Cid>>> {
+        if let Some(data) = self.0.get(cid)? {
+            let mut refs = vec![];
+            Block::<S>::new_unchecked(*cid, data).references(&mut refs)?;
+            Ok(Some(refs))
+        } else {
+            Ok(None)
+        }
     }
 
-    pub fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
-        Ok(self.0.resolve(alias)?)
+    /// Walks the dag rooted at `cid` breadth first up to `max_depth` levels
+    /// below the root. Blocks missing from the store are reported in
+    /// `Refs::missing` instead of failing the walk.
+    pub fn refs_recursive(&mut self, cid: &Cid, max_depth: Option<usize>) -> Result<Refs> {
+        self.walk(cid, max_depth, |_, _| {})
     }
 
-    pub fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
-        Ok(self.0.alias(alias, cid)?)
+    /// Counts the blocks and bytes of the dag rooted at `cid` that are in the
+    /// store. Shared subtrees are only counted once.
+    pub fn dag_stat(&mut self, cid: &Cid) -> Result<DagStat> {
+        let mut stat = DagStat::default();
+        let refs = self.walk(cid, None, |_, size| {
+            stat.blocks += 1;
+            stat.size += size as u64;
+        })?;
+        stat.missing = refs.missing;
+        Ok(stat)
     }
 
-    pub fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>> {
-        Ok(self.0.aliases()?)
+    /// Walks a dag breadth first, calling `visit` with the `Cid` and size of
+    /// every block found in the store.
+    fn walk(
+        &mut self,
+        cid: &Cid,
+        max_depth: Option<usize>,
+        mut visit: impl FnMut(&Cid, usize),
+    ) -> Result<Refs> {
+        let mut result = Refs::default();
+        let mut visited = FnvHashSet::default();
+        visited.insert(*cid);
+        let mut level = vec![*cid];
+        let mut depth = 0;
+        while !level.is_empty() {
+            let mut next = vec![];
+            for cid in level {
+                let data = if let Some(data) = self.0.get(&cid)? {
+                    data
+                } else {
+                    result.missing.push(cid);
+                    continue;
+                };
+                visit(&cid, data.len());
+                if max_depth.map(|max| depth >= max).unwrap_or_default() {
+                    continue;
+                }
+                let mut refs = vec![];
+                Block::<S>::new_unchecked(cid, data).references(&mut refs)?;
+                for cid in refs {
+                    if visited.insert(cid) {
+                        result.refs.push(cid);
+                        next.push(cid);
+                    }
+                }
+            }
+            level = next;
+            depth += 1;
+        }
+        Ok(result)
     }
 
-    pub fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
-        Ok(self.0.reverse_alias(cid)?)
+    fn lease(&mut self, name: &[u8]) -> Result<Option<(DateTime<Utc>, Vec<Cid>)>> {
+        if let Some(cid) = self.0.resolve(&lease_alias(name))? {
+            if let Some(data) = self.0.get(&cid)? {
+                return Ok(Some(decode_lease(&data)?));
+            }
+        }
+        Ok(None)
     }
 
-    pub fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
-        Ok(self.0.get_missing_blocks(cid)?)
+    fn put_lease(&mut self, name: &[u8], expires: DateTime<Utc>, roots: &[Cid]) -> Result<()> {
+        let block = encode_lease(expires, roots)?;
+        let cid = *block.cid();
+        self.0.insert(block)?;
+        self.0.alias(&lease_alias(name), Some(&cid))?;
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::executor::Executor;
+    fn leases(&mut self) -> Result<Vec<(Vec<u8>, DateTime<Utc>)>> {
+        let aliases: Vec<(Vec<u8>, Cid)> = self.0.aliases()?;
+        let mut leases = vec![];
+        for (alias, cid) in aliases {
+            if let Some(name) = alias.strip_prefix(LEASE_ALIAS_PREFIX) {
+                if let Some(data) = self.0.get(&cid)? {
+                    leases.push((name.to_vec(), decode_lease(&data)?.0));
+                }
+            }
+        }
+        Ok(leases)
+    }
 
-    use super::*;
-    use libipld::{alias, cbor::DagCborCodec, ipld, multihash::Code, store::DefaultParams};
+    fn bans(&mut self) -> Result<Vec<(PeerId, Option<DateTime<Utc>>)>> {
+        let mut bans = vec![];
+        for (key, data) in self.0.metadata_with_prefix(BAN_KEY_PREFIX)? {
+            let peer = PeerId::from_bytes(&key[BAN_KEY_PREFIX.len()..])?;
+            bans.push((peer, decode_ban(&data)?));
+        }
+        Ok(bans)
+    }
 
-    fn create_block(ipld: &Ipld) -> Block<DefaultParams> {
-        Block::encode(DagCborCodec, Code::Blake3_256, ipld).unwrap()
+    /// Removes the leases that expired before `now`, returning how many were
+    /// removed.
+    fn expire_leases(&mut self, now: DateTime<Utc>) -> Result<usize> {
+        let mut expired = 0;
+        for (name, expires) in self.leases()? {
+            if expires < now {
+                self.0.alias(&lease_alias(&name), None)?;
+                expired += 1;
+            }
+        }
+        Ok(expired)
     }
 
-    macro_rules! assert_evicted {
-        ($store:expr, $block:expr) => {
-            assert_eq!($store.reverse_alias($block.cid()).unwrap(), None);
-        };
+    /// Temp pins the blocks kept by `policy`, or returns `None` if it
+    /// doesn't keep any.
+    fn keep_blocks(&mut self, policy: &GcPolicy) -> Result<Option<TempPin>> {
+        if policy.is_empty() {
+            return Ok(None);
+        }
+        let mut kept = Vec::new();
+        for cid in self.0.iter()? {
+            let txn = &mut self.0;
+            if policy.keeps(&cid, || Ok(txn.get(&cid)?.map(|data| data.len())))? {
+                kept.push(cid);
+            }
+        }
+        tracing::debug!("gc policy keeps {} blocks", kept.len());
+        let mut pin = self.create_temp_pin()?;
+        self.temp_pin(&mut pin, kept)?;
+        Ok(Some(pin))
     }
 
-    macro_rules! assert_pinned {
-        ($store:expr, $block:expr) => {
-            assert_eq!(
-                $store
-                    .reverse_alias($block.cid())
-                    .unwrap()
-                    .map(|a| !a.is_empty()),
-                Some(true)
-            );
-        };
+    /// Rehashes the blocks in `cids`, reporting and optionally removing the
+    /// corrupt ones. Blocks that aren't stored are skipped.
+    fn verify(&mut self, cids: &[Cid], remove_corrupt: bool) -> Result<Vec<VerifyEvent>> {
+        let mut events = vec![];
+        for cid in cids {
+            let data = if let Some(data) = self.0.get(cid)? {
+                data
+            } else {
+                continue;
+            };
+            if Block::<S>::new(*cid, data).is_ok() {
+                continue;
+            }
+            events.push(VerifyEvent::Corrupt(*cid));
+            // corrupt blocks are removed even if they are retained, the dags
+            // are incomplete either way
+            if remove_corrupt && self.remove0(cid)? {
+                events.push(VerifyEvent::Removed(*cid));
+            }
+        }
+        Ok(events)
     }
 
-    macro_rules! assert_unpinned {
-        ($store:expr, $block:expr) => {
-            assert_eq!(
-                $store
-                    .reverse_alias($block.cid())
-                    .unwrap()
-                    .map(|a| !a.is_empty()),
-                Some(false)
-            );
+    /// Returns the metadata of all stored blocks. Blocks are pinned if they
+    /// are retained by a user alias or a pin, like in `pin_status`.
+    pub fn block_infos(&mut self) -> Result<Vec<BlockInfo>> {
+        let retains = |alias: &[u8]| {
+            !alias.starts_with(RESERVED_ALIAS_PREFIX) || alias.starts_with(PIN_ALIAS_PREFIX)
         };
+        let infos = self.0.block_infos(&retains)?;
+        Ok(infos.into_iter().map(BlockInfo::from).collect())
     }
 
-    fn tracing_try_init() {
-        tracing_subscriber::fmt()
-            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-            .try_init()
-            .ok();
+    pub fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.0.get(cid)
     }
 
-    fn create_store() -> StorageService<DefaultParams> {
-        let config = StorageConfig::new(None, None, 2, Duration::from_secs(100));
-        StorageService::open(config, Executor::new()).unwrap()
+    /// Returns the data of the blocks in the order of `cids`, `None` for
+    /// blocks that aren't stored.
+    pub fn get_many(&mut self, cids: &[Cid]) -> Result<Vec<Option<Vec<u8>>>> {
+        cids.iter().map(|cid| self.get(cid)).collect()
     }
 
-    #[async_std::test]
-    async fn test_store_evict() {
-        tracing_try_init();
-        let store = create_store();
-        let a = create_block(&ipld!(0));
-        let b = create_block(&ipld!(1));
-        let c = create_block(&ipld!(2));
-        let d = create_block(&ipld!(3));
-        store.insert(a.clone()).unwrap();
-        store.insert(b.clone()).unwrap();
+    /// Returns whether the blocks are stored in the order of `cids`.
+    pub fn contains_many(&mut self, cids: &[Cid]) -> Result<Vec<bool>> {
+        cids.iter().map(|cid| self.contains(cid)).collect()
+    }
+
+    /// Inserts a block. Fails with `QuotaExceeded` if the block would grow
+    /// the store beyond `StorageConfig::max_bytes`.
+    pub fn insert(&mut self, block: Block<S>) -> Result<()> {
+        self.insert0(block, true)
+    }
+
+    fn insert0(&mut self, block: Block<S>, enforce_quota: bool) -> Result<()> {
+        if let Some(quota) = self.1 {
+            if !self.0.contains(block.cid())? {
+                let used = quota.used + block.data().len() as u64;
+                if enforce_quota && used > quota.max_bytes {
+                    return Err(QuotaExceeded {
+                        max_bytes: quota.max_bytes,
+                    }
+                    .into());
+                }
+                self.1 = Some(Quota { used, ..quota });
+            }
+        }
+        let event = StoreEvent::Inserted(*block.cid(), block.data().len());
+        self.0.insert(block)?;
+        self.2.push(event);
+        Ok(())
+    }
+
+    /// Removes a block, returning whether it was stored. Fails with
+    /// `BlockRetained` if the block is in the dag of an alias, including pins
+    /// and leases, or of a temp pin, or if the backend can't tell whether it
+    /// is temp pinned. Fails for backends that can't remove individual
+    /// blocks, like the sqlite store.
+    pub fn remove(&mut self, cid: &Cid) -> Result<bool> {
+        if !self.check_removable(cid)? {
+            return Ok(false);
+        }
+        self.remove0(cid)
+    }
+
+    /// Returns whether the block is stored, failing if it is retained.
+    fn check_removable(&mut self, cid: &Cid) -> Result<bool> {
+        let aliases = if let Some(aliases) = self.0.reverse_alias(cid)? {
+            aliases
+        } else {
+            return Ok(false);
+        };
+        if !aliases.is_empty() || self.0.temp_pinned(cid)? != Some(false) {
+            return Err(BlockRetained(*cid).into());
+        }
+        Ok(true)
+    }
+
+    fn remove0(&mut self, cid: &Cid) -> Result<bool> {
+        let len = match self.1 {
+            Some(_) => self.0.get(cid)?.map(|data| data.len() as u64),
+            None => None,
+        };
+        if !self.0.remove(cid)? {
+            return Ok(false);
+        }
+        if let (Some(quota), Some(len)) = (&mut self.1, len) {
+            quota.used = quota.used.saturating_sub(len);
+        }
+        self.2.push(StoreEvent::Evicted(*cid));
+        Ok(true)
+    }
+
+    fn is_over_quota(&self) -> bool {
+        self.1
+            .map(|quota| quota.used > quota.max_bytes)
+            .unwrap_or_default()
+    }
+
+    pub fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
+        self.0.resolve(alias)
+    }
+
+    pub fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        if alias.starts_with(&RESERVED_ALIAS_PREFIX[..1]) {
+            return Err(ReservedAlias.into());
+        }
+        self.0.alias(alias, cid)?;
+        self.2.push(StoreEvent::AliasChanged {
+            alias: alias.to_vec(),
+            cid: cid.copied(),
+        });
+        Ok(())
+    }
+
+    /// Updates an alias to `new` if it currently points to `expected`.
+    /// Otherwise the current value is returned.
+    pub fn alias_cas(
+        &mut self,
+        alias: &[u8],
+        expected: Option<&Cid>,
+        new: Option<&Cid>,
+    ) -> Result<std::result::Result<(), Option<Cid>>> {
+        let current = self.resolve(alias)?;
+        if current.as_ref() != expected {
+            return Ok(Err(current));
+        }
+        self.alias(alias, new)?;
+        Ok(Ok(()))
+    }
+
+    pub fn aliases(&mut self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        let aliases: Vec<(Vec<u8>, Cid)> = self.0.aliases()?;
+        Ok(aliases
+            .into_iter()
+            .filter(|(alias, _)| !alias.starts_with(RESERVED_ALIAS_PREFIX))
+            .collect())
+    }
+
+    /// Pins the dag rooted at `cid`. Pinning a `Cid` multiple times requires
+    /// unpinning it the same number of times.
+    pub fn pin(&mut self, cid: &Cid) -> Result<()> {
+        let count = self.pin_count(cid)?;
+        self.0.alias(&pin_alias(cid, count), Some(cid))
+    }
+
+    /// Removes a pin of `cid`. Returns `false` if `cid` wasn't pinned.
+    pub fn unpin(&mut self, cid: &Cid) -> Result<bool> {
+        let count = self.pin_count(cid)?;
+        if count == 0 {
+            return Ok(false);
+        }
+        self.0.alias(&pin_alias(cid, count - 1), None)?;
+        Ok(true)
+    }
+
+    /// Returns the number of times `cid` is pinned.
+    pub fn pin_count(&mut self, cid: &Cid) -> Result<usize> {
+        let mut count = 0;
+        while self.0.resolve(&pin_alias(cid, count))?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Lists the pinned roots together with their pin count.
+    pub fn pins(&mut self) -> Result<Vec<(Cid, usize)>> {
+        let aliases: Vec<(Vec<u8>, Cid)> = self.0.aliases()?;
+        let mut pins = FnvHashMap::<Cid, usize>::default();
+        for (alias, cid) in aliases {
+            if alias.starts_with(PIN_ALIAS_PREFIX) {
+                *pins.entry(cid).or_default() += 1;
+            }
+        }
+        Ok(pins.into_iter().collect())
+    }
+
+    /// Returns the aliases and pins retaining `cid`, or `None` if the block
+    /// is unknown.
+    pub fn pin_status(&mut self, cid: &Cid) -> Result<Option<PinStatus>> {
+        let aliases = if let Some(aliases) = self.0.reverse_alias(cid)? {
+            aliases
+        } else {
+            return Ok(None);
+        };
+        let mut status = PinStatus::default();
+        for alias in aliases {
+            if let Some(root) = parse_pin_alias(&alias) {
+                status.pins.insert(root);
+            } else if !alias.starts_with(RESERVED_ALIAS_PREFIX) {
+                status.aliases.insert(alias);
+            }
+        }
+        Ok(Some(status))
+    }
+
+    /// Returns the aliases whose dag contains `cid`, or `None` if the block is
+    /// unknown. Like `aliases`, pins and other reserved aliases are omitted.
+    pub fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        Ok(self.0.reverse_alias(cid)?.map(|aliases| {
+            aliases
+                .into_iter()
+                .filter(|alias| !alias.starts_with(RESERVED_ALIAS_PREFIX))
+                .collect()
+        }))
+    }
+
+    pub fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.0.missing_blocks(cid)
+    }
+}
+
+/// A named temporary pin that is persisted in the store. Unlike a `TempPin`
+/// it survives restarts, and is released when it isn't renewed within its
+/// ttl.
+pub struct Lease<S: StoreParams> {
+    storage: StorageService<S>,
+    name: Vec<u8>,
+    ttl: Duration,
+}
+
+impl<S: StoreParams> Lease<S>
+where
+    Ipld: References<S::Codecs>,
+{
+    /// Returns the name of the lease.
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Adds a root to the lease and renews it.
+    pub fn add(&self, cid: &Cid) -> Result<DateTime<Utc>> {
+        let expires = self.expires()?;
+        self.storage.rw("lease", |x| {
+            let mut roots = x
+                .lease(&self.name)?
+                .map(|(_, roots)| roots)
+                .unwrap_or_default();
+            if !roots.contains(cid) {
+                roots.push(*cid);
+            }
+            x.put_lease(&self.name, expires, &roots)
+        })?;
+        Ok(expires)
+    }
+
+    /// Extends the lease by its ttl, returning the new expiry.
+    pub fn renew(&self) -> Result<DateTime<Utc>> {
+        let expires = self.expires()?;
+        self.storage.rw("lease", |x| {
+            let roots = x
+                .lease(&self.name)?
+                .map(|(_, roots)| roots)
+                .unwrap_or_default();
+            x.put_lease(&self.name, expires, &roots)
+        })?;
+        Ok(expires)
+    }
+
+    /// Releases the lease, making its roots eligible for garbage collection.
+    pub fn release(self) -> Result<()> {
+        self.storage
+            .rw("lease", |x| x.0.alias(&lease_alias(&self.name), None))
+    }
+
+    fn expires(&self) -> Result<DateTime<Utc>> {
+        // leases are stored with millisecond precision
+        let expires = Utc::now() + chrono::Duration::from_std(self.ttl)?;
+        Ok(Utc
+            .timestamp_millis_opt(expires.timestamp_millis())
+            .unwrap())
+    }
+}
+
+/// A handle for performing atomic storage operations. Writes are buffered
+/// and only applied when the transaction completes successfully, after
+/// checking the quota and that the aliases point to blocks that will be
+/// stored. Backends that can't roll back, like the sqlite store, keep the
+/// writes applied before a write fails nonetheless, e.g. on an I/O error.
+pub struct AtomicBatch<'a, 'b, S: StoreParams> {
+    batch: &'b mut Batch<'a, S>,
+    blocks: FnvHashMap<Cid, Block<S>>,
+    removed: FnvHashSet<Cid>,
+    aliases: Vec<(Vec<u8>, Option<Cid>)>,
+}
+
+impl<'a, 'b, S: StoreParams> AtomicBatch<'a, 'b, S>
+where
+    Ipld: References<S::Codecs>,
+{
+    fn new(batch: &'b mut Batch<'a, S>) -> Self {
+        Self {
+            batch,
+            blocks: Default::default(),
+            removed: Default::default(),
+            aliases: Default::default(),
+        }
+    }
+
+    pub fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        if self.removed.contains(cid) {
+            return Ok(false);
+        }
+        Ok(self.blocks.contains_key(cid) || self.batch.contains(cid)?)
+    }
+
+    pub fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        if self.removed.contains(cid) {
+            return Ok(None);
+        }
+        if let Some(block) = self.blocks.get(cid) {
+            return Ok(Some(block.data().to_vec()));
+        }
+        self.batch.get(cid)
+    }
+
+    pub fn insert(&mut self, block: Block<S>) -> Result<()> {
+        self.removed.remove(block.cid());
+        self.blocks.insert(*block.cid(), block);
+        Ok(())
+    }
+
+    /// Removes a block like `Batch::remove`, retained blocks are refused
+    /// right away. Backends that can't remove individual blocks fail the
+    /// transaction when it is applied.
+    pub fn remove(&mut self, cid: &Cid) -> Result<bool> {
+        let stored = if self.removed.contains(cid) {
+            false
+        } else {
+            self.batch.check_removable(cid)? || self.blocks.contains_key(cid)
+        };
+        self.blocks.remove(cid);
+        self.removed.insert(*cid);
+        Ok(stored)
+    }
+
+    pub fn resolve(&mut self, alias: &[u8]) -> Result<Option<Cid>> {
+        if let Some((_, cid)) = self.aliases.iter().rev().find(|(name, _)| name == alias) {
+            return Ok(*cid);
+        }
+        self.batch.resolve(alias)
+    }
+
+    pub fn alias(&mut self, alias: &[u8], cid: Option<&Cid>) -> Result<()> {
+        if alias.starts_with(&RESERVED_ALIAS_PREFIX[..1]) {
+            return Err(ReservedAlias.into());
+        }
+        self.aliases.push((alias.to_vec(), cid.copied()));
+        Ok(())
+    }
+
+    /// Checks that the writes fit into the quota and that the aliases point
+    /// to blocks that will be stored.
+    fn check(&mut self) -> Result<()> {
+        let targets = self
+            .aliases
+            .iter()
+            .filter_map(|(alias, cid)| Some((alias.clone(), (*cid)?)))
+            .collect::<Vec<_>>();
+        for (alias, cid) in targets {
+            if !self.contains(&cid)? {
+                return Err(MissingAliasTarget { alias, cid }.into());
+            }
+        }
+        if let Some(quota) = self.batch.1 {
+            let mut used = quota.used;
+            for cid in &self.removed {
+                if let Some(data) = self.batch.0.get(cid)? {
+                    used = used.saturating_sub(data.len() as u64);
+                }
+            }
+            for (cid, block) in &self.blocks {
+                if !self.batch.0.contains(cid)? {
+                    used += block.data().len() as u64;
+                }
+            }
+            if used > quota.max_bytes {
+                return Err(QuotaExceeded {
+                    max_bytes: quota.max_bytes,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(mut self) -> Result<()> {
+        // the sqlite store commits every write on its own, so everything
+        // that can fail is checked before the first one
+        self.check()?;
+        for cid in &self.removed {
+            self.batch.remove(cid)?;
+        }
+        for (_, block) in self.blocks {
+            self.batch.insert(block)?;
+        }
+        for (alias, cid) in self.aliases {
+            self.batch.alias(&alias, cid.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::Executor;
+
+    use super::*;
+    use libipld::{alias, raw::RawCodec, store::DefaultParams};
+
+    fn create_block(ipld: &Ipld) -> Block<DefaultParams> {
+        Block::encode(DagCborCodec, Code::Blake3_256, ipld).unwrap()
+    }
+
+    macro_rules! assert_evicted {
+        ($store:expr, $block:expr) => {
+            assert_eq!($store.reverse_alias($block.cid()).unwrap(), None);
+        };
+    }
+
+    macro_rules! assert_pinned {
+        ($store:expr, $block:expr) => {
+            assert_eq!(
+                $store
+                    .pin_status($block.cid())
+                    .unwrap()
+                    .map(|status| status.is_retained()),
+                Some(true)
+            );
+        };
+    }
+
+    macro_rules! assert_unpinned {
+        ($store:expr, $block:expr) => {
+            assert_eq!(
+                $store
+                    .pin_status($block.cid())
+                    .unwrap()
+                    .map(|status| status.is_retained()),
+                Some(false)
+            );
+        };
+    }
+
+    fn tracing_try_init() {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .try_init()
+            .ok();
+    }
+
+    fn create_store() -> StorageService<DefaultParams> {
+        let config = StorageConfig::new(None, None, 2, Duration::from_secs(100));
+        StorageService::open(config, Executor::new()).unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_store_evict() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        let c = create_block(&ipld!(2));
+        let d = create_block(&ipld!(3));
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
         store.flush().await.unwrap();
         store.evict().await.unwrap();
         assert_unpinned!(&store, &a);
@@ -537,44 +2776,338 @@ mod tests {
     }
 
     #[async_std::test]
-    #[allow(clippy::many_single_char_names)]
-    async fn test_store_unpin() {
+    async fn test_watch_alias() {
         tracing_try_init();
         let store = create_store();
         let a = create_block(&ipld!({ "a": [] }));
-        let b = create_block(&ipld!({ "b": [a.cid()] }));
-        let c = create_block(&ipld!({ "c": [a.cid()] }));
-        let x = alias!(x).as_bytes().to_vec();
-        let y = alias!(y).as_bytes().to_vec();
+        let b = create_block(&ipld!({ "b": [] }));
         store.insert(a.clone()).unwrap();
         store.insert(b.clone()).unwrap();
-        store.insert(c.clone()).unwrap();
-        store.alias(&x, Some(b.cid())).unwrap();
-        store.alias(&y, Some(c.cid())).unwrap();
-        store.flush().await.unwrap();
-        assert_pinned!(&store, &a);
-        assert_pinned!(&store, &b);
-        assert_pinned!(&store, &c);
-        store.alias(&x, None).unwrap();
-        store.flush().await.unwrap();
-        assert_pinned!(&store, &a);
-        assert_unpinned!(&store, &b);
-        assert_pinned!(&store, &c);
-        store.alias(&y, None).unwrap();
-        store.flush().await.unwrap();
-        assert_unpinned!(&store, &a);
-        assert_unpinned!(&store, &b);
-        assert_unpinned!(&store, &c);
+        store.alias(b"doc", Some(a.cid())).unwrap();
+
+        let mut watcher = store.watch_alias(b"doc").unwrap();
+        let mut other = store.watch_alias(b"other").unwrap();
+        assert_eq!(watcher.next().await, Some(Some(*a.cid())));
+        assert_eq!(other.next().await, Some(None));
+        for cid in [Some(b.cid()), None, Some(a.cid())] {
+            store.alias(b"doc", cid).unwrap();
+            assert_eq!(watcher.next().await, Some(cid.copied()));
+        }
+        // other aliases and unchanged values aren't reported
+        store.alias(b"doc", Some(a.cid())).unwrap();
+        assert!(watcher.next().now_or_never().is_none());
+        assert!(other.next().now_or_never().is_none());
+
+        let second = store.watch_alias(b"doc").unwrap();
+        drop(watcher);
+        assert_eq!(store.inner.alias_watchers.lock().len(), 2);
+        drop(second);
+        drop(other);
+        assert!(store.inner.alias_watchers.lock().is_empty());
     }
 
     #[async_std::test]
-    #[allow(clippy::many_single_char_names)]
-    async fn test_store_unpin2() {
+    async fn test_store_events() {
+        tracing_try_init();
+        let config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let mut events = store.store_events();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store
+            .rw("batch", |batch| {
+                batch.insert(a.clone())?;
+                batch.insert(b.clone())?;
+                batch.alias(b"a", Some(a.cid()))
+            })
+            .unwrap();
+        // reserved aliases aren't reported
+        store.pin(b.cid()).unwrap();
+        store.unpin(b.cid()).unwrap();
+        store.alias(b"a", None).unwrap();
+        let expected = vec![
+            StoreEvent::Inserted(*a.cid(), a.data().len()),
+            StoreEvent::Inserted(*b.cid(), b.data().len()),
+            StoreEvent::AliasChanged {
+                alias: b"a".to_vec(),
+                cid: Some(*a.cid()),
+            },
+            StoreEvent::AliasChanged {
+                alias: b"a".to_vec(),
+                cid: None,
+            },
+        ];
+        for event in expected {
+            assert_eq!(events.next().await, Some(event));
+        }
+        store.evict().await.unwrap();
+        let mut evicted = vec![];
+        for _ in 0..2 {
+            match events.next().await.unwrap() {
+                StoreEvent::Evicted(cid) => evicted.push(cid),
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        evicted.sort();
+        let mut expected = vec![*a.cid(), *b.cid()];
+        expected.sort();
+        assert_eq!(evicted, expected);
+        assert!(events.next().now_or_never().is_none());
+        drop(store);
+        assert_eq!(events.next().await, None);
+    }
+
+    #[async_std::test]
+    async fn test_store_events_failed_batch() {
+        tracing_try_init();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        for backend in [BackendKind::Sqlite, BackendKind::Memory] {
+            let mut config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            config.backend = backend;
+            let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+            let mut events = store.store_events();
+            let res: Result<()> = store.rw("batch", |batch| {
+                batch.insert(a.clone())?;
+                batch.alias(b"b", Some(b.cid()))?;
+                batch.alias(b"a", Some(a.cid()))?;
+                anyhow::bail!("nope!");
+            });
+            assert!(res.is_err());
+            store.insert(b.clone()).unwrap();
+            // the sqlite store keeps the writes of the failed batch
+            if backend == BackendKind::Sqlite {
+                assert_eq!(
+                    events.next().await,
+                    Some(StoreEvent::Inserted(*a.cid(), a.data().len()))
+                );
+                assert_eq!(
+                    events.next().await,
+                    Some(StoreEvent::AliasChanged {
+                        alias: b"b".to_vec(),
+                        cid: Some(*b.cid()),
+                    })
+                );
+                assert_eq!(
+                    events.next().await,
+                    Some(StoreEvent::AliasChanged {
+                        alias: b"a".to_vec(),
+                        cid: Some(*a.cid()),
+                    })
+                );
+            }
+            assert_eq!(
+                events.next().await,
+                Some(StoreEvent::Inserted(*b.cid(), b.data().len()))
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn test_store_events_slow_consumer() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 100, Duration::from_secs(100));
+        config.store_events_buffer = 4;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let mut slow = store.store_events();
+        let mut fast = store.store_events();
+        let blocks = (0..10)
+            .map(|i| create_block(&ipld!({ "i": i })))
+            .collect::<Vec<_>>();
+        for block in &blocks {
+            store.insert(block.clone()).unwrap();
+            let event = fast.next().await.unwrap();
+            assert_eq!(
+                event,
+                StoreEvent::Inserted(*block.cid(), block.data().len())
+            );
+        }
+        assert_eq!(fast.dropped_events(), 0);
+        // the slow consumer keeps the newest events
+        assert_eq!(slow.dropped_events(), 6);
+        for block in &blocks[6..] {
+            assert_eq!(
+                slow.next().await,
+                Some(StoreEvent::Inserted(*block.cid(), block.data().len()))
+            );
+        }
+        assert!(slow.next().now_or_never().is_none());
+        // dropped subscribers are removed by the next write
+        drop(slow);
+        store.alias(b"x", Some(blocks[0].cid())).unwrap();
+        assert_eq!(store.inner.store_events.queues.lock().len(), 1);
+        assert_eq!(
+            fast.next().await,
+            Some(StoreEvent::AliasChanged {
+                alias: b"x".to_vec(),
+                cid: Some(*blocks[0].cid()),
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn test_iter_filtered() {
         tracing_try_init();
         let store = create_store();
+        let raw =
+            |data: &[u8], code: Code| Block::<DefaultParams>::encode(RawCodec, code, data).unwrap();
         let a = create_block(&ipld!({ "a": [] }));
-        let b = create_block(&ipld!({ "b": [a.cid()] }));
-        let x = alias!(x).as_bytes().to_vec();
+        let b = raw(&[0; 100], Code::Blake3_256);
+        let c = create_block(&ipld!({ "c": [a.cid()] }));
+        let d = raw(&[1; 10], Code::Sha2_256);
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        futures_timer::Delay::new(Duration::from_millis(10)).await;
+        let t = Utc::now();
+        futures_timer::Delay::new(Duration::from_millis(10)).await;
+        store.insert(d.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.alias(b"c", Some(c.cid())).unwrap();
+
+        let filtered = |filter: BlockFilter| {
+            let mut cids = store.iter_filtered(&filter).unwrap();
+            if !filter.sort_by_insertion {
+                cids.sort();
+            }
+            cids
+        };
+        let sorted = |mut cids: Vec<Cid>| {
+            cids.sort();
+            cids
+        };
+        let cbor = filtered(BlockFilter {
+            codec: Some(DAG_CBOR),
+            ..Default::default()
+        });
+        assert_eq!(cbor, sorted(vec![*a.cid(), *c.cid()]));
+        let sha2 = filtered(BlockFilter {
+            hash: Some(Code::Sha2_256.into()),
+            ..Default::default()
+        });
+        assert_eq!(sha2, vec![*d.cid()]);
+        let large = filtered(BlockFilter {
+            min_size: Some(50),
+            ..Default::default()
+        });
+        assert_eq!(large, vec![*b.cid()]);
+        let small_raw = filtered(BlockFilter {
+            codec: Some(0x55),
+            max_size: Some(50),
+            ..Default::default()
+        });
+        assert_eq!(small_raw, vec![*d.cid()]);
+        let new = filtered(BlockFilter {
+            inserted_after: Some(t),
+            ..Default::default()
+        });
+        assert_eq!(new, sorted(vec![*c.cid(), *d.cid()]));
+        let pinned = filtered(BlockFilter {
+            pinned: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(pinned, sorted(vec![*a.cid(), *c.cid()]));
+        let unpinned = filtered(BlockFilter {
+            pinned: Some(false),
+            ..Default::default()
+        });
+        assert_eq!(unpinned, sorted(vec![*b.cid(), *d.cid()]));
+        let ordered = filtered(BlockFilter {
+            inserted_after: Some(t),
+            sort_by_insertion: true,
+            ..Default::default()
+        });
+        assert_eq!(ordered, vec![*d.cid(), *c.cid()]);
+    }
+
+    #[async_std::test]
+    async fn test_gc_policy() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        config.gc_policy.keep_smaller_than = Some(8);
+        config.gc_policy.keep_codecs.insert(0x55);
+        let store = StorageService::open(config, Executor::new()).unwrap();
+        let small = create_block(&ipld!(0));
+        let raw = Block::encode(RawCodec, Code::Blake3_256, &[1u8; 64][..]).unwrap();
+        let excluded = create_block(&ipld!({ "excluded": [] }));
+        let other = create_block(&ipld!({ "other": [] }));
+        for block in [&small, &raw, &excluded, &other] {
+            store.insert(block.clone()).unwrap();
+        }
+        store.gc_exclude(excluded.cid());
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert_unpinned!(&store, &small);
+        assert_unpinned!(&store, &raw);
+        assert_unpinned!(&store, &excluded);
+        assert_evicted!(&store, &other);
+
+        assert!(store.gc_include(excluded.cid()));
+        assert!(!store.gc_include(excluded.cid()));
+        store.evict().await.unwrap();
+        assert_evicted!(&store, &excluded);
+        assert_unpinned!(&store, &small);
+    }
+
+    #[async_std::test]
+    async fn test_gc_disabled() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 0, Duration::from_millis(1));
+        config.gc = GcMode::Disabled;
+        let store = StorageService::open(config, Executor::new()).unwrap();
+        assert_eq!(store.gc_status(), TaskStatus::Stopped);
+        let blocks = (0..10).map(|i| create_block(&ipld!(i))).collect::<Vec<_>>();
+        let _tmp = store.create_temp_pin().unwrap();
+        for block in &blocks {
+            store.insert(block.clone()).unwrap();
+        }
+        store.flush().await.unwrap();
+        futures_timer::Delay::new(Duration::from_millis(50)).await;
+        let err = store.evict().await.unwrap_err();
+        assert!(err.is::<GcDisabled>());
+        for block in &blocks {
+            assert_unpinned!(&store, block);
+        }
+    }
+
+    #[async_std::test]
+    #[allow(clippy::many_single_char_names)]
+    async fn test_store_unpin() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [a.cid()] }));
+        let x = alias!(x).as_bytes().to_vec();
+        let y = alias!(y).as_bytes().to_vec();
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.alias(&x, Some(b.cid())).unwrap();
+        store.alias(&y, Some(c.cid())).unwrap();
+        store.flush().await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_pinned!(&store, &b);
+        assert_pinned!(&store, &c);
+        store.alias(&x, None).unwrap();
+        store.flush().await.unwrap();
+        assert_pinned!(&store, &a);
+        assert_unpinned!(&store, &b);
+        assert_pinned!(&store, &c);
+        store.alias(&y, None).unwrap();
+        store.flush().await.unwrap();
+        assert_unpinned!(&store, &a);
+        assert_unpinned!(&store, &b);
+        assert_unpinned!(&store, &c);
+    }
+
+    #[async_std::test]
+    #[allow(clippy::many_single_char_names)]
+    async fn test_store_unpin2() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let x = alias!(x).as_bytes().to_vec();
         let y = alias!(y).as_bytes().to_vec();
         store.insert(a.clone()).unwrap();
         store.insert(b.clone()).unwrap();
@@ -592,4 +3125,1023 @@ mod tests {
         assert_unpinned!(&store, &a);
         assert_unpinned!(&store, &b);
     }
+
+    #[async_std::test]
+    async fn test_store_pin() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let d = create_block(&ipld!({ "d": [] }));
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.insert(d.clone()).unwrap();
+        store.pin(b.cid()).unwrap();
+        store.pin(b.cid()).unwrap();
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(store.contains(b.cid()).unwrap());
+        assert!(store.aliases().unwrap().is_empty());
+        assert_eq!(store.pins().unwrap(), vec![(*b.cid(), 2)]);
+        let status = store.pin_status(a.cid()).unwrap().unwrap();
+        assert!(status.aliases.is_empty());
+        assert_eq!(status.pins, vec![*b.cid()].into_iter().collect());
+
+        assert!(store.unpin(b.cid()).unwrap());
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(store.contains(b.cid()).unwrap());
+
+        assert!(store.unpin(b.cid()).unwrap());
+        assert!(!store.unpin(b.cid()).unwrap());
+        assert!(!store.pin_status(a.cid()).unwrap().unwrap().is_retained());
+        assert!(store.pins().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reserved_alias() {
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let err = store.alias(b"\0x", Some(a.cid())).unwrap_err();
+        assert!(err.downcast_ref::<ReservedAlias>().is_some());
+    }
+
+    fn iter_with_metadata(store: &StorageService<DefaultParams>) -> Vec<BlockInfo> {
+        let mut infos = store
+            .iter_with_metadata()
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        infos.sort_by_key(|info| info.cid);
+        infos
+    }
+
+    #[async_std::test]
+    async fn test_iter_with_metadata() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": "unpinned" }));
+        let start = Utc::now();
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.pin(b.cid()).unwrap();
+
+        let infos = iter_with_metadata(&store);
+        assert!(infos
+            .iter()
+            .all(|info| matches!(info.inserted_at, Some(at) if at >= start)));
+        let infos = infos
+            .into_iter()
+            .map(|info| (info.cid, info.size, info.pinned, info.temp_pinned))
+            .collect::<Vec<_>>();
+        let mut expected = vec![
+            (*a.cid(), a.data().len(), true, false),
+            (*b.cid(), b.data().len(), true, false),
+            (*c.cid(), c.data().len(), false, false),
+        ];
+        expected.sort_by_key(|info| info.0);
+        assert_eq!(infos, expected);
+    }
+
+    #[test]
+    fn test_iter_with_metadata_temp_pinned() {
+        let mut config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        config.backend = BackendKind::Memory;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        let mut tmp = store.create_temp_pin().unwrap();
+        store.temp_pin(&mut tmp, std::iter::once(*b.cid())).unwrap();
+
+        let temp_pinned = |infos: Vec<BlockInfo>| {
+            infos
+                .into_iter()
+                .filter(|info| info.temp_pinned)
+                .map(|info| info.cid)
+                .collect::<FnvHashSet<_>>()
+        };
+        let expected = [*a.cid(), *b.cid()].iter().copied().collect();
+        assert_eq!(temp_pinned(iter_with_metadata(&store)), expected);
+        drop(tmp);
+        assert!(temp_pinned(iter_with_metadata(&store)).is_empty());
+    }
+
+    #[test]
+    fn test_reverse_alias_reserved() {
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        store.insert(a.clone()).unwrap();
+        let lease = store
+            .create_lease(b"lease", Duration::from_secs(3600))
+            .unwrap();
+        lease.add(a.cid()).unwrap();
+        store.pin(a.cid()).unwrap();
+        assert_eq!(store.reverse_alias(a.cid()).unwrap(), Some(HashSet::new()));
+        let infos = store.rw("block_infos", |x| x.block_infos()).unwrap();
+        assert!(infos.iter().any(|info| info.cid == *a.cid() && info.pinned));
+
+        store.unpin(a.cid()).unwrap();
+        assert_eq!(store.reverse_alias(a.cid()).unwrap(), Some(HashSet::new()));
+        let infos = store.rw("block_infos", |x| x.block_infos()).unwrap();
+        assert!(infos
+            .iter()
+            .any(|info| info.cid == *a.cid() && !info.pinned));
+        store.alias(b"a", Some(a.cid())).unwrap();
+        let aliases = store.reverse_alias(a.cid()).unwrap().unwrap();
+        assert_eq!(aliases, std::iter::once(b"a".to_vec()).collect());
+    }
+
+    #[async_std::test]
+    async fn test_gc_events() {
+        tracing_try_init();
+        let store = create_store();
+        let mut events = store.gc_events();
+        let blocks = (0..4).map(|i| create_block(&ipld!(i))).collect::<Vec<_>>();
+        for block in blocks.iter() {
+            store.insert(block.clone()).unwrap();
+        }
+        store.flush().await.unwrap();
+        let stats = store.evict().await.unwrap();
+        assert_eq!(stats.blocks_evicted, 2);
+        let bytes = blocks[..2]
+            .iter()
+            .map(|b| b.data().len() as u64)
+            .sum::<u64>();
+        assert_eq!(stats.bytes_evicted, bytes);
+        assert!(stats.complete);
+
+        assert_eq!(
+            events.next().await,
+            Some(GcEvent::Started {
+                blocks: 4,
+                bytes: blocks.iter().map(|b| b.data().len() as u64).sum(),
+            })
+        );
+        assert_eq!(events.next().await, Some(GcEvent::Finished(stats)));
+    }
+
+    #[async_std::test]
+    async fn test_cache_size_bytes() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 1_000_000, Duration::from_secs(100));
+        config.cache_size_bytes = Some(1024 * 1024);
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        for i in 0..40u8 {
+            let data = vec![i; 256 * 1024];
+            let block = Block::encode(RawCodec, Code::Blake3_256, &data[..]).unwrap();
+            store.insert(block).unwrap();
+        }
+        store.flush().await.unwrap();
+        store.evict().await.unwrap();
+        let size = store
+            .iter_with_metadata()
+            .unwrap()
+            .map(|info| info.unwrap().size as u64)
+            .sum::<u64>();
+        assert!(size <= 1024 * 1024);
+        assert!(size > 0);
+    }
+
+    #[async_std::test]
+    async fn test_set_sweep_interval() {
+        tracing_try_init();
+        let store = create_store();
+        let mut events = store.gc_events();
+        store.set_sweep_interval(Duration::from_millis(10));
+        let event = async_std::future::timeout(Duration::from_secs(1), events.next())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(GcEvent::Started { .. })));
+    }
+
+    #[async_std::test]
+    async fn test_set_cache_size() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        for (backend, path) in [
+            (BackendKind::Memory, None),
+            (BackendKind::Sqlite, None),
+            (BackendKind::Sqlite, Some(tmp.path().join("db"))),
+        ] {
+            let mut config = StorageConfig::new(path, None, 10, Duration::from_secs(100));
+            config.backend = backend;
+            let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+            for i in 0..5 {
+                store.insert(create_block(&ipld!({ "i": i }))).unwrap();
+            }
+            store.flush().await.unwrap();
+            let mut events = store.gc_events();
+            // a target above the usage doesn't sweep
+            store.set_cache_size(Some(5), None).unwrap();
+            let event = async_std::future::timeout(Duration::from_millis(100), events.next()).await;
+            assert!(event.is_err());
+            store.set_cache_size(Some(2), None).unwrap();
+            loop {
+                let event = async_std::future::timeout(Duration::from_secs(1), events.next())
+                    .await
+                    .unwrap();
+                if let Some(GcEvent::Finished(_)) = event {
+                    break;
+                }
+            }
+            assert_eq!(store.iter().unwrap().count(), 2);
+        }
+    }
+
+    #[test]
+    fn test_alias_cas() {
+        tracing_try_init();
+        let store = create_store();
+        let x = alias!(x).as_bytes();
+        let zero = create_block(&ipld!(0));
+        store.insert(zero.clone()).unwrap();
+        store.alias(x, Some(zero.cid())).unwrap();
+
+        let threads = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        loop {
+                            let current = store.resolve(x).unwrap().unwrap();
+                            let data = store.get(&current).unwrap().unwrap();
+                            let block = Block::<DefaultParams>::new_unchecked(current, data);
+                            let n = match block.ipld().unwrap() {
+                                Ipld::Integer(n) => n,
+                                ipld => panic!("unexpected {:?}", ipld),
+                            };
+                            let next = create_block(&ipld!(n + 1));
+                            store.insert(next.clone()).unwrap();
+                            if store
+                                .alias_cas(x, Some(&current), Some(next.cid()))
+                                .unwrap()
+                                .is_ok()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let current = store.resolve(x).unwrap().unwrap();
+        assert_eq!(current, *create_block(&ipld!(80)).cid());
+        assert_eq!(
+            store.alias_cas(x, Some(zero.cid()), None).unwrap(),
+            Err(Some(current))
+        );
+    }
+
+    #[test]
+    fn test_refs() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let d = create_block(&ipld!({ "d": [b.cid(), c.cid(), a.cid()] }));
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(d.clone()).unwrap();
+
+        assert_eq!(
+            store.refs(d.cid()).unwrap(),
+            Some(vec![*b.cid(), *c.cid(), *a.cid()])
+        );
+        assert_eq!(store.refs(c.cid()).unwrap(), None);
+        assert_eq!(
+            store.refs_recursive(d.cid(), None).unwrap(),
+            Refs {
+                refs: vec![*b.cid(), *c.cid(), *a.cid()],
+                missing: vec![*c.cid()],
+            }
+        );
+        assert_eq!(
+            store.refs_recursive(b.cid(), Some(0)).unwrap(),
+            Refs::default()
+        );
+    }
+
+    #[test]
+    fn test_dag_stat() {
+        tracing_try_init();
+        let store = create_store();
+        let (left, blocks) = crate::test_util::build_tree(2, 2).unwrap();
+        let right = create_block(&ipld!({ "right": [left] }));
+        // the subtree `left` is reachable through both sides of the diamond
+        let root = create_block(&ipld!({ "root": [left, right.cid()] }));
+        for block in blocks.iter().chain(vec![&right, &root]) {
+            store.insert(block.clone()).unwrap();
+        }
+        let size = blocks
+            .iter()
+            .chain(vec![&right, &root])
+            .map(|block| block.data().len() as u64)
+            .sum::<u64>();
+
+        let stat = store.dag_stat(root.cid()).unwrap();
+        assert_eq!(stat.blocks, blocks.len() as u64 + 2);
+        assert_eq!(stat.size, size);
+        assert!(stat.missing.is_empty());
+
+        let missing = create_block(&ipld!({ "missing": [] }));
+        let partial = create_block(&ipld!({ "partial": [missing.cid(), root.cid()] }));
+        store.insert(partial.clone()).unwrap();
+        let stat = store.dag_stat(partial.cid()).unwrap();
+        assert_eq!(stat.blocks, blocks.len() as u64 + 3);
+        assert_eq!(stat.missing, vec![*missing.cid()]);
+        assert_eq!(stat.missing, store.missing_blocks(partial.cid()).unwrap());
+    }
+
+    #[test]
+    fn test_get_many() {
+        tracing_try_init();
+        let store = create_store();
+        let a = create_block(&ipld!(0));
+        let b = create_block(&ipld!(1));
+        let c = create_block(&ipld!(2));
+        store.insert(a.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        let cids = [*c.cid(), *b.cid(), *a.cid()];
+
+        let queries = QUERIES_TOTAL.with_label_values(&["get_many"]).get();
+        let blocks = store.get_many(&cids).unwrap();
+        assert_eq!(
+            QUERIES_TOTAL.with_label_values(&["get_many"]).get(),
+            queries + 1
+        );
+        assert_eq!(
+            blocks,
+            vec![Some(c.data().to_vec()), None, Some(a.data().to_vec())]
+        );
+        assert_eq!(store.contains_many(&cids).unwrap(), vec![true, false, true]);
+    }
+
+    #[async_std::test]
+    async fn test_leases() {
+        tracing_try_init();
+        let config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+
+        let lease = store
+            .create_lease(b"ingest", Duration::from_secs(3600))
+            .unwrap();
+        let expires = lease.add(b.cid()).unwrap();
+        let expired = store
+            .create_lease(b"expired", Duration::from_secs(0))
+            .unwrap();
+        expired.add(c.cid()).unwrap();
+        store.evict().await.unwrap();
+
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(store.contains(b.cid()).unwrap());
+        assert!(!store.contains(c.cid()).unwrap());
+        assert_eq!(store.leases().unwrap(), vec![(b"ingest".to_vec(), expires)]);
+        assert!(store.aliases().unwrap().is_empty());
+
+        // resuming a lease keeps its roots
+        let lease = store
+            .create_lease(lease.name(), Duration::from_secs(3600))
+            .unwrap();
+        store.evict().await.unwrap();
+        assert!(store.contains(b.cid()).unwrap());
+        lease.release().unwrap();
+        store.evict().await.unwrap();
+        assert!(!store.contains(b.cid()).unwrap());
+        assert!(store.leases().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_bans() {
+        tracing_try_init();
+        let store = create_store();
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let c = PeerId::random();
+        let expires = Utc::now() + chrono::Duration::hours(1);
+        let expires = Utc
+            .timestamp_millis_opt(expires.timestamp_millis())
+            .unwrap();
+        store.ban(&a, None).unwrap();
+        store.ban(&b, Some(expires)).unwrap();
+        store
+            .ban(&c, Some(Utc::now() - chrono::Duration::seconds(1)))
+            .unwrap();
+        let mut bans = store.bans().unwrap();
+        bans.sort_by_key(|(_, expires)| *expires);
+        assert_eq!(bans, vec![(a, None), (b, Some(expires))]);
+        // bans are kept apart from the blocks
+        assert!(store.aliases().unwrap().is_empty());
+        assert_eq!(store.iter().unwrap().count(), 0);
+
+        // the expired ban was removed
+        assert!(!store.unban(&c).unwrap());
+        assert!(store.unban(&a).unwrap());
+        assert_eq!(store.bans().unwrap(), vec![(b, Some(expires))]);
+    }
+
+    #[async_std::test]
+    async fn test_address_book() {
+        tracing_try_init();
+        let store = create_store();
+        assert!(store.address_book().unwrap().is_empty());
+        let seen = Utc
+            .timestamp_millis_opt(Utc::now().timestamp_millis())
+            .unwrap();
+        let book = vec![
+            (
+                PeerId::random(),
+                vec![
+                    (
+                        "/ip4/10.0.0.1/tcp/4001".parse().unwrap(),
+                        AddressSource::Dial,
+                        seen,
+                    ),
+                    (
+                        "/dns4/example.com/tcp/4001".parse().unwrap(),
+                        AddressSource::Kad,
+                        seen,
+                    ),
+                ],
+            ),
+            (PeerId::random(), vec![]),
+        ];
+        store.save_address_book(&book).unwrap();
+        assert_eq!(store.address_book().unwrap(), book);
+        store.save_address_book(&book[1..]).unwrap();
+        assert_eq!(store.address_book().unwrap(), &book[1..]);
+        assert!(store.aliases().unwrap().is_empty());
+        assert_eq!(store.iter().unwrap().count(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_metadata_reopen() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let config = StorageConfig::new(Some(tmp.path().into()), None, 0, Duration::from_secs(100));
+        let peer = PeerId::random();
+        let book = vec![(peer, vec![])];
+        {
+            let store =
+                StorageService::<DefaultParams>::open(config.clone(), Executor::new()).unwrap();
+            store.ban(&peer, None).unwrap();
+            store.save_address_book(&book).unwrap();
+            assert_eq!(store.stats().unwrap().blocks, 0);
+        }
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        assert_eq!(store.bans().unwrap(), vec![(peer, None)]);
+        assert_eq!(store.address_book().unwrap(), book);
+    }
+
+    #[async_std::test]
+    async fn test_mem_backend() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 2, Duration::from_secs(100));
+        config.backend = BackendKind::Memory;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [a.cid()] }));
+        let c = create_block(&ipld!({ "c": [] }));
+        let d = create_block(&ipld!({ "d": [] }));
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.alias(b"x", Some(b.cid())).unwrap();
+        assert_pinned!(&store, &a);
+        assert_eq!(store.missing_blocks(b.cid()).unwrap(), vec![]);
+
+        // failed batches are rolled back
+        let res = store.rw("test", |x| {
+            x.insert(c.clone())?;
+            x.alias(b"x", None)?;
+            Err::<(), _>(anyhow::anyhow!("rollback"))
+        });
+        assert!(res.is_err());
+        assert!(!store.contains(c.cid()).unwrap());
+        assert_eq!(store.resolve(b"x").unwrap(), Some(*b.cid()));
+
+        // unretained blocks are evicted least recently used first
+        let mut tmp = store.create_temp_pin().unwrap();
+        store.insert(c.clone()).unwrap();
+        store.insert(d.clone()).unwrap();
+        store.temp_pin(&mut tmp, std::iter::once(*d.cid())).unwrap();
+        store.evict().await.unwrap();
+        assert!(!store.contains(c.cid()).unwrap());
+        assert!(store.contains(d.cid()).unwrap());
+        drop(tmp);
+        store.alias(b"x", None).unwrap();
+        store.evict().await.unwrap();
+        assert_eq!(store.iter().unwrap().count(), 2);
+        assert!(store.contains(d.cid()).unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_verify() {
+        tracing_try_init();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        let corrupt = Block::new_unchecked(*b.cid(), b"garbage".to_vec());
+        let mut config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        config.backend = BackendKind::Memory;
+        config.max_bytes = Some((a.data().len() + corrupt.data().len()) as u64);
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let missing = create_block(&ipld!({ "missing": [] }));
+        store.insert(a.clone()).unwrap();
+        store.insert(corrupt).unwrap();
+        store.alias(b"x", Some(missing.cid())).unwrap();
+
+        let config = VerifyConfig {
+            remove_corrupt: true,
+            ..Default::default()
+        };
+        let events = store.verify(config).collect::<Vec<_>>().await;
+        assert_eq!(
+            events,
+            vec![
+                VerifyEvent::Corrupt(*b.cid()),
+                VerifyEvent::Removed(*b.cid()),
+                VerifyEvent::Progress {
+                    checked: 2,
+                    total: 2
+                },
+                VerifyEvent::Dangling {
+                    alias: b"x".to_vec(),
+                    cid: *missing.cid()
+                },
+            ]
+        );
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(!store.contains(b.cid()).unwrap());
+        // the removed block no longer counts towards the quota
+        store.insert(b).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_transaction_remove() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        config.backend = BackendKind::Memory;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store.insert(a.clone()).unwrap();
+
+        let res: Result<()> = store.transaction(|tx| {
+            assert!(tx.remove(a.cid())?);
+            tx.insert(b.clone())?;
+            assert!(!tx.contains(a.cid())?);
+            assert_eq!(tx.get(a.cid())?, None);
+            anyhow::bail!("nope!");
+        });
+        assert!(res.is_err());
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(!store.contains(b.cid()).unwrap());
+
+        store
+            .transaction(|tx| {
+                tx.insert(b.clone())?;
+                assert!(tx.remove(b.cid())?);
+                assert!(tx.remove(a.cid())?);
+                Ok(())
+            })
+            .unwrap();
+        assert!(!store.contains(a.cid()).unwrap());
+        assert!(!store.contains(b.cid()).unwrap());
+    }
+
+    #[test]
+    fn test_transaction_checks() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let blocks = (0..3)
+            .map(|i| create_block(&ipld!({ "i": i })))
+            .collect::<Vec<_>>();
+        let size = blocks[0].data().len() as u64;
+        config.max_bytes = Some(2 * size);
+        // the sqlite store commits every write on its own
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let missing = create_block(&ipld!({ "missing": [] }));
+
+        let err = store
+            .transaction(|tx| {
+                for block in &blocks {
+                    tx.insert(block.clone())?;
+                }
+                tx.alias(b"a", Some(blocks[0].cid()))
+            })
+            .unwrap_err();
+        assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+        let err = store
+            .transaction(|tx| {
+                tx.insert(blocks[0].clone())?;
+                tx.alias(b"a", Some(blocks[0].cid()))?;
+                tx.alias(b"b", Some(missing.cid()))
+            })
+            .unwrap_err();
+        assert!(err.downcast_ref::<MissingAliasTarget>().is_some());
+        assert_eq!(store.iter().unwrap().count(), 0);
+        assert_eq!(store.resolve(b"a").unwrap(), None);
+
+        store
+            .transaction(|tx| {
+                tx.insert(blocks[0].clone())?;
+                tx.insert(blocks[1].clone())?;
+                tx.alias(b"a", Some(blocks[0].cid()))
+            })
+            .unwrap();
+        assert_eq!(store.iter().unwrap().count(), 2);
+        assert_eq!(store.resolve(b"a").unwrap(), Some(*blocks[0].cid()));
+    }
+
+    #[test]
+    fn test_remove_retained() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        config.backend = BackendKind::Memory;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.alias(b"a", Some(a.cid())).unwrap();
+        let mut pin = store.create_temp_pin().unwrap();
+        store.temp_pin(&mut pin, Some(*b.cid())).unwrap();
+        let retained = |err: anyhow::Error| err.downcast_ref::<BlockRetained>().is_some();
+
+        let err = store.rw("test", |x| x.remove(a.cid())).unwrap_err();
+        assert!(retained(err));
+        let err = store.rw("test", |x| x.remove(b.cid())).unwrap_err();
+        assert!(retained(err));
+        let err = store.transaction(|tx| tx.remove(b.cid())).unwrap_err();
+        assert!(retained(err));
+        assert!(store.contains(a.cid()).unwrap());
+        assert!(store.contains(b.cid()).unwrap());
+
+        store.alias(b"a", None).unwrap();
+        drop(pin);
+        assert!(store.rw("test", |x| x.remove(a.cid())).unwrap());
+        assert!(store.transaction(|tx| tx.remove(b.cid())).unwrap());
+        assert!(!store.contains(b.cid()).unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_flush_with() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let mut config =
+            StorageConfig::new(Some(tmp.path().into()), None, 0, Duration::from_secs(100));
+        config.default_durability = Durability::Sync;
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        {
+            let store =
+                StorageService::<DefaultParams>::open(config.clone(), Executor::new()).unwrap();
+            store.insert(a.clone()).unwrap();
+            store.alias(b"a", Some(a.cid())).unwrap();
+            store.flush_with(Durability::Os).await.unwrap();
+            store.insert(b.clone()).unwrap();
+            store.alias(b"b", Some(b.cid())).unwrap();
+            store.flush_with(Durability::Sync).await.unwrap();
+        }
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        assert_eq!(store.resolve(b"a").unwrap(), Some(*a.cid()));
+        assert_eq!(store.resolve(b"b").unwrap(), Some(*b.cid()));
+        assert!(store.contains(b.cid()).unwrap());
+    }
+
+    #[cfg(feature = "compression")]
+    #[async_std::test]
+    async fn test_compression_reopen() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let mut config =
+            StorageConfig::new(Some(tmp.path().into()), None, 0, Duration::from_secs(100));
+        let a =
+            Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &[1u8; 4096][..]).unwrap();
+        let b =
+            Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &[2u8; 4096][..]).unwrap();
+        let c = create_block(&ipld!({ "a": *a.cid(), "b": *b.cid() }));
+        {
+            let store =
+                StorageService::<DefaultParams>::open(config.clone(), Executor::new()).unwrap();
+            store.insert(a.clone()).unwrap();
+            store.alias(b"a", Some(a.cid())).unwrap();
+            let stats = store.stats().unwrap();
+            assert_eq!(stats.bytes, 4096);
+            assert_eq!(stats.logical_bytes, 4096);
+        }
+        config.compression = Some(Compression::Zstd { level: 3 });
+        {
+            let store =
+                StorageService::<DefaultParams>::open(config.clone(), Executor::new()).unwrap();
+            store.insert(b.clone()).unwrap();
+            store.insert(c.clone()).unwrap();
+            store.alias(b"c", Some(c.cid())).unwrap();
+            let stats = store.stats().unwrap();
+            assert_eq!(stats.blocks, 3);
+            assert_eq!(stats.logical_bytes, 8192 + c.data().len() as u64);
+            assert!(stats.bytes < 4096 + c.data().len() as u64 + 100);
+            assert_eq!(store.get(a.cid()).unwrap(), Some(a.data().to_vec()));
+            assert_eq!(store.get(b.cid()).unwrap(), Some(b.data().to_vec()));
+        }
+        config.compression = None;
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        assert_eq!(store.get(a.cid()).unwrap(), Some(a.data().to_vec()));
+        assert_eq!(store.get(b.cid()).unwrap(), Some(b.data().to_vec()));
+        assert_eq!(store.get(c.cid()).unwrap(), Some(c.data().to_vec()));
+        // the links of compressed blocks are still known
+        assert!(store.missing_blocks(c.cid()).unwrap().is_empty());
+        assert_pinned!(&store, &b);
+        assert_eq!(
+            store.stats().unwrap().logical_bytes,
+            8192 + c.data().len() as u64
+        );
+    }
+
+    /// Inserts and evicts `n` blocks of 1 KiB.
+    async fn churn(store: &StorageService<DefaultParams>, n: u64) {
+        store
+            .rw("churn", |batch| {
+                for i in 0..n {
+                    let data = [i.to_le_bytes(); 128].concat();
+                    batch.insert(Block::encode(RawCodec, Code::Blake3_256, &data[..])?)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        store.evict().await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_compact() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let config = StorageConfig::new(Some(tmp.path().into()), None, 0, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let a = create_block(&ipld!({ "a": [] }));
+        store.insert(a.clone()).unwrap();
+        store.alias(b"a", Some(a.cid())).unwrap();
+        churn(&store, 10_000).await;
+        store.flush_with(Durability::Sync).await.unwrap();
+        let db = tmp.path().join("db");
+        let before = std::fs::metadata(&db).unwrap().len();
+
+        let compact = store.compact();
+        // reads don't wait for the compaction
+        for _ in 0..100 {
+            assert_eq!(store.get(a.cid()).unwrap(), Some(a.data().to_vec()));
+        }
+        let stats = compact.await.unwrap();
+        assert!(stats.bytes_reclaimed > 5_000_000, "{:?}", stats);
+        store.flush_with(Durability::Sync).await.unwrap();
+        assert!(std::fs::metadata(&db).unwrap().len() < before);
+        assert_eq!(store.get(a.cid()).unwrap(), Some(a.data().to_vec()));
+    }
+
+    #[async_std::test]
+    async fn test_auto_compact() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let mut config =
+            StorageConfig::new(Some(tmp.path().into()), None, 0, Duration::from_secs(100));
+        config.auto_compact_threshold = Some(10);
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        churn(&store, 10_000).await;
+        // the eviction already compacted the store
+        let stats = store.compact().await.unwrap();
+        assert!(stats.bytes_reclaimed < 100_000, "{:?}", stats);
+    }
+
+    #[async_std::test]
+    async fn test_backup() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let config = StorageConfig::new(
+            Some(tmp.path().join("store")),
+            None,
+            0,
+            Duration::from_secs(100),
+        );
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let pinned = create_block(&ipld!({ "pinned": [] }));
+        store.insert(pinned.clone()).unwrap();
+        store.pin(pinned.cid()).unwrap();
+        let peer = PeerId::random();
+        store.ban(&peer, None).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let store = store.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut written = 0u64;
+                while !stop.load(Ordering::Relaxed) {
+                    let leaf = create_block(&ipld!({ "leaf": written }));
+                    let root = create_block(&ipld!({ "root": written, "leaf": *leaf.cid() }));
+                    let alias = format!("dag/{}", written);
+                    store
+                        .rw("writer", |batch| {
+                            batch.insert(leaf)?;
+                            batch.insert(root.clone())?;
+                            batch.alias(alias.as_bytes(), Some(root.cid()))
+                        })
+                        .unwrap();
+                    written += 1;
+                }
+                written
+            })
+        };
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        let dest = tmp.path().join("backup");
+        store.backup(&dest).await.unwrap();
+        // a backup never overwrites anything
+        assert!(store.backup(&dest).await.is_err());
+        stop.store(true, Ordering::Relaxed);
+        let written = writer.join().unwrap();
+
+        let config = StorageConfig::new(Some(dest), None, 0, Duration::from_secs(100));
+        let backup = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        let aliases = backup.aliases().unwrap();
+        assert!(!aliases.is_empty());
+        assert!(aliases.len() as u64 <= written);
+        for (_, root) in aliases {
+            assert!(backup.missing_blocks(&root).unwrap().is_empty());
+        }
+        assert_eq!(backup.pins().unwrap(), vec![(*pinned.cid(), 1)]);
+        assert_eq!(backup.bans().unwrap(), vec![(peer, None)]);
+    }
+
+    /// Creates a store at `path` with the blocks `a` and `b`, aliased as `x`
+    /// and `y`, and closes it.
+    fn create_source(path: &Path) -> (Block<DefaultParams>, Block<DefaultParams>) {
+        let a = create_block(&ipld!({ "a": [] }));
+        let b = create_block(&ipld!({ "b": [] }));
+        let config = StorageConfig::new(Some(path.into()), None, 0, Duration::from_secs(100));
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        store.insert(a.clone()).unwrap();
+        store.insert(b.clone()).unwrap();
+        store.alias(b"x", Some(a.cid())).unwrap();
+        store.alias(b"y", Some(b.cid())).unwrap();
+        (a, b)
+    }
+
+    /// Returns a store containing `a` and the block `c` aliased as `x`.
+    fn create_target(
+        a: &Block<DefaultParams>,
+    ) -> (StorageService<DefaultParams>, Block<DefaultParams>) {
+        let c = create_block(&ipld!({ "c": [] }));
+        let store = create_store();
+        store.insert(a.clone()).unwrap();
+        store.insert(c.clone()).unwrap();
+        store.alias(b"x", Some(c.cid())).unwrap();
+        (store, c)
+    }
+
+    #[test]
+    fn test_import_store_keep_ours() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let (a, b) = create_source(tmp.path());
+        let (store, c) = create_target(&a);
+        let stats = store
+            .import_store(tmp.path(), Some(AliasConflict::KeepOurs))
+            .unwrap();
+        assert_eq!(
+            stats,
+            ImportStats {
+                blocks_copied: 1,
+                blocks_skipped: 1,
+                bytes_copied: b.data().len() as u64,
+                aliases_imported: 1,
+            }
+        );
+        assert_eq!(store.resolve(b"x").unwrap(), Some(*c.cid()));
+        assert_eq!(store.resolve(b"y").unwrap(), Some(*b.cid()));
+        assert!(store.contains(b.cid()).unwrap());
+    }
+
+    #[test]
+    fn test_import_store_keep_theirs() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let (a, b) = create_source(tmp.path());
+        let (store, _) = create_target(&a);
+        let stats = store
+            .import_store(tmp.path(), Some(AliasConflict::KeepTheirs))
+            .unwrap();
+        assert_eq!(stats.aliases_imported, 2);
+        assert_eq!(store.resolve(b"x").unwrap(), Some(*a.cid()));
+        assert_eq!(store.resolve(b"y").unwrap(), Some(*b.cid()));
+    }
+
+    #[test]
+    fn test_import_store_conflict() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let (a, b) = create_source(tmp.path());
+        let (store, c) = create_target(&a);
+        let err = store
+            .import_store(tmp.path(), Some(AliasConflict::Fail))
+            .unwrap_err();
+        let err = err.downcast_ref::<ConflictingAlias>().unwrap();
+        assert_eq!(err.alias, b"x");
+        assert_eq!((err.ours, err.theirs), (*c.cid(), *a.cid()));
+        // nothing was imported
+        assert_eq!(store.resolve(b"y").unwrap(), None);
+        assert!(!store.contains(b.cid()).unwrap());
+
+        // without aliases there is no conflict
+        let stats = store.import_store(tmp.path(), None).unwrap();
+        assert_eq!((stats.blocks_copied, stats.aliases_imported), (1, 0));
+        assert_eq!(store.resolve(b"x").unwrap(), Some(*c.cid()));
+    }
+
+    #[test]
+    fn test_import_store_missing() {
+        tracing_try_init();
+        let tmp = tempdir::TempDir::new("ipfs-embed").unwrap();
+        let store = create_store();
+        assert!(store
+            .import_store(&tmp.path().join("missing"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 1000, Duration::from_secs(100));
+        let blocks = (0..4)
+            .map(|i| create_block(&ipld!({ "i": i })))
+            .collect::<Vec<_>>();
+        let size = blocks[0].data().len() as u64;
+        config.max_bytes = Some(3 * size);
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+        store.insert(blocks[0].clone()).unwrap();
+
+        // the batch crosses the limit at its last block
+        let inserted = store
+            .rw("test", |x| {
+                for (i, block) in blocks.iter().enumerate() {
+                    if let Err(err) = x.insert(block.clone()) {
+                        assert_eq!(
+                            err.downcast_ref::<QuotaExceeded>().unwrap().max_bytes,
+                            3 * size
+                        );
+                        return Ok(i);
+                    }
+                }
+                Ok(blocks.len())
+            })
+            .unwrap();
+        assert_eq!(inserted, 3);
+        assert_eq!(store.iter().unwrap().count(), 3);
+
+        // inserting a stored block doesn't count against the quota
+        store.insert(blocks[1].clone()).unwrap();
+        let err = store.insert(blocks[3].clone()).unwrap_err();
+        assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+    }
+
+    #[test]
+    fn test_max_bytes_failed_batch() {
+        tracing_try_init();
+        let mut config = StorageConfig::new(None, None, 1000, Duration::from_secs(100));
+        let blocks = (0..3)
+            .map(|i| create_block(&ipld!({ "i": i })))
+            .collect::<Vec<_>>();
+        let size = blocks[0].data().len() as u64;
+        config.max_bytes = Some(2 * size);
+        let store = StorageService::<DefaultParams>::open(config, Executor::new()).unwrap();
+
+        // the sqlite store keeps the blocks of a failed batch
+        let res: Result<()> = store.rw("test", |x| {
+            x.insert(blocks[0].clone())?;
+            x.insert(blocks[1].clone())?;
+            anyhow::bail!("nope!");
+        });
+        assert!(res.is_err());
+        assert_eq!(store.iter().unwrap().count(), 2);
+        let err = store.insert(blocks[2].clone()).unwrap_err();
+        assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+    }
 }
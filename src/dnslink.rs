@@ -0,0 +1,256 @@
+//! Resolution of DNSLink TXT records, see https://dnslink.dev.
+use crate::{executor::Executor, net::DnsConfig};
+use async_trait::async_trait;
+use futures::Future;
+use libipld::{Cid, Result};
+use libp2p::PeerId;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use thiserror::Error;
+use trust_dns_resolver::{error::ResolveErrorKind, Resolver};
+
+#[derive(Debug, Error)]
+pub enum DnsLinkError {
+    #[error("No dnslink TXT record found for {0}.")]
+    NotFound(String),
+    #[error("Multiple dnslink TXT records found for {0}.")]
+    Multiple(String),
+    #[error("Malformed dnslink value {0:?}.")]
+    Malformed(String),
+    #[error("Dnslink of {0} points to another dnslink.")]
+    TooManyIndirections(String),
+}
+
+/// Looks up DNS TXT records. Implemented by the resolver configured with
+/// `NetworkConfig::dns`.
+#[async_trait]
+pub(crate) trait TxtResolver: Send + Sync {
+    /// Returns the TXT records of `name`, or none if it doesn't have any.
+    async fn txt_lookup(&self, name: &str) -> Result<Vec<String>>;
+}
+
+/// Resolves TXT records with the resolver configured by `DnsConfig`, or the
+/// system configuration if there is none. The resolver is blocking and runs
+/// on the blocking threads of the executor.
+pub(crate) struct DnsResolver {
+    config: Option<DnsConfig>,
+    executor: Executor,
+    resolver: Arc<Mutex<Option<Arc<Resolver>>>>,
+}
+
+impl DnsResolver {
+    pub fn new(config: Option<DnsConfig>, executor: Executor) -> Self {
+        Self {
+            config,
+            executor,
+            resolver: Default::default(),
+        }
+    }
+}
+
+fn create_resolver(config: Option<DnsConfig>) -> Result<Resolver> {
+    Ok(match config {
+        Some(DnsConfig::Custom { config, opts }) => Resolver::new(config, opts)?,
+        Some(DnsConfig::SystemWithFallback { config, opts }) => {
+            match trust_dns_resolver::system_conf::read_system_conf() {
+                Ok((config, opts)) => Resolver::new(config, opts)?,
+                Err(_) => Resolver::new(config, opts)?,
+            }
+        }
+        None => Resolver::from_system_conf()?,
+    })
+}
+
+#[async_trait]
+impl TxtResolver for DnsResolver {
+    async fn txt_lookup(&self, name: &str) -> Result<Vec<String>> {
+        let (config, cache, name) = (self.config.clone(), self.resolver.clone(), name.to_string());
+        self.executor
+            .spawn_blocking(move || {
+                let resolver = {
+                    let mut cache = cache.lock();
+                    if cache.is_none() {
+                        *cache = Some(Arc::new(create_resolver(config)?));
+                    }
+                    cache.clone().unwrap()
+                };
+                match resolver.txt_lookup(name.as_str()) {
+                    Ok(lookup) => Ok(lookup
+                        .iter()
+                        .map(|txt| {
+                            txt.txt_data()
+                                .iter()
+                                .map(|data| String::from_utf8_lossy(data))
+                                .collect()
+                        })
+                        .collect()),
+                    Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                        Ok(vec![])
+                    }
+                    Err(err) => Err(err.into()),
+                }
+            })
+            .await?
+    }
+}
+
+/// The target of a dnslink.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum DnsLink {
+    Ipfs(Cid),
+    Ipns(String),
+}
+
+/// Looks up the dnslink of `domain` in the TXT records of
+/// `_dnslink.<domain>`. TXT records not starting with `dnslink=` are ignored.
+pub(crate) async fn lookup(resolver: &dyn TxtResolver, domain: &str) -> Result<DnsLink> {
+    let domain = domain.trim_end_matches('.');
+    let mut values = resolver
+        .txt_lookup(&format!("_dnslink.{}", domain))
+        .await?
+        .into_iter()
+        .filter_map(|txt| {
+            txt.strip_prefix("dnslink=")
+                .map(|value| value.trim().to_string())
+        })
+        .collect::<Vec<_>>();
+    let value = match values.len() {
+        0 => return Err(DnsLinkError::NotFound(domain.into()).into()),
+        1 => values.pop().unwrap(),
+        _ => return Err(DnsLinkError::Multiple(domain.into()).into()),
+    };
+    let mut segments = value.trim_end_matches('/').split('/');
+    let link = match (segments.next(), segments.next(), segments.next()) {
+        (Some(""), Some("ipfs"), Some(cid)) => cid.parse().ok().map(DnsLink::Ipfs),
+        (Some(""), Some("ipns"), Some(name)) if !name.is_empty() => {
+            Some(DnsLink::Ipns(name.into()))
+        }
+        _ => None,
+    };
+    match link {
+        Some(link) if segments.next().is_none() => Ok(link),
+        _ => Err(DnsLinkError::Malformed(value).into()),
+    }
+}
+
+/// Resolves the dnslink of `domain` to a cid. An `/ipns/` link is followed
+/// once, either with `resolve_ipns` if it names a peer or by looking up the
+/// dnslink of the named domain.
+pub(crate) async fn resolve<F, Fut>(
+    resolver: &dyn TxtResolver,
+    domain: &str,
+    resolve_ipns: F,
+) -> Result<Cid>
+where
+    F: FnOnce(PeerId) -> Fut,
+    Fut: Future<Output = Result<Cid>>,
+{
+    match lookup(resolver, domain).await? {
+        DnsLink::Ipfs(cid) => Ok(cid),
+        DnsLink::Ipns(name) => {
+            if let Ok(peer) = name.parse() {
+                return resolve_ipns(peer).await;
+            }
+            match lookup(resolver, &name).await? {
+                DnsLink::Ipfs(cid) => Ok(cid),
+                DnsLink::Ipns(_) => Err(DnsLinkError::TooManyIndirections(domain.into()).into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const CID: &str = "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq";
+
+    struct MockResolver(HashMap<String, Vec<String>>);
+
+    impl MockResolver {
+        fn new(records: &[(&str, &str)]) -> Self {
+            let mut map = HashMap::<_, Vec<_>>::new();
+            for (name, txt) in records {
+                map.entry(name.to_string())
+                    .or_default()
+                    .push(txt.to_string());
+            }
+            Self(map)
+        }
+    }
+
+    #[async_trait]
+    impl TxtResolver for MockResolver {
+        async fn txt_lookup(&self, name: &str) -> Result<Vec<String>> {
+            Ok(self.0.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    fn no_ipns(_: PeerId) -> futures::future::Ready<Result<Cid>> {
+        panic!("unexpected ipns lookup")
+    }
+
+    #[async_std::test]
+    async fn test_dnslink() -> Result<()> {
+        let cid: Cid = CID.parse()?;
+        let ipfs = format!("dnslink=/ipfs/{}", cid);
+        let resolver = MockResolver::new(&[
+            ("_dnslink.example.com", "v=spf1 -all"),
+            ("_dnslink.example.com", &ipfs),
+            ("_dnslink.alias.com", "dnslink=/ipns/example.com"),
+            ("_dnslink.loop.com", "dnslink=/ipns/alias.com"),
+        ]);
+        assert_eq!(resolve(&resolver, "example.com", no_ipns).await?, cid);
+        assert_eq!(resolve(&resolver, "example.com.", no_ipns).await?, cid);
+        assert_eq!(resolve(&resolver, "alias.com", no_ipns).await?, cid);
+        let err = resolve(&resolver, "loop.com", no_ipns).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DnsLinkError>(),
+            Some(DnsLinkError::TooManyIndirections(_))
+        ));
+
+        let peer = PeerId::random();
+        let resolver =
+            MockResolver::new(&[("_dnslink.peer.com", &format!("dnslink=/ipns/{}", peer))]);
+        let resolved = resolve(&resolver, "peer.com", |p| async move {
+            assert_eq!(p, peer);
+            Ok(cid)
+        })
+        .await?;
+        assert_eq!(resolved, cid);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dnslink_errors() -> Result<()> {
+        let ipfs = format!("dnslink=/ipfs/{}", CID);
+        let resolver = MockResolver::new(&[
+            ("_dnslink.multiple.com", &ipfs),
+            ("_dnslink.multiple.com", &ipfs),
+            ("_dnslink.nocid.com", "dnslink=/ipfs/notacid"),
+            ("_dnslink.scheme.com", "dnslink=ipfs://foo"),
+        ]);
+        let err = |domain: &'static str| {
+            let resolver = &resolver;
+            async move {
+                let err = resolve(resolver, domain, no_ipns).await.unwrap_err();
+                err.downcast::<DnsLinkError>().unwrap()
+            }
+        };
+        assert!(matches!(
+            err("missing.com").await,
+            DnsLinkError::NotFound(_)
+        ));
+        assert!(matches!(
+            err("multiple.com").await,
+            DnsLinkError::Multiple(_)
+        ));
+        assert!(matches!(err("nocid.com").await, DnsLinkError::Malformed(_)));
+        assert!(matches!(
+            err("scheme.com").await,
+            DnsLinkError::Malformed(_)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! The error returned by the methods of `Ipfs`.
+use crate::{
+    db::{QuotaExceeded, ReadOnly, Shutdown},
+    ipns::IpnsRecordNotFound,
+    net::{
+        ConnectFailed, ConnectTimeout, FetchTimeout, KadAddProviderError, KadBootstrapError,
+        KadGetClosestPeersError, KadGetProvidersError, KadGetRecordError, KadPutRecordError,
+        NoKnownPeers, NotBootstrapped, RequestTimeout,
+    },
+};
+use ipfs_sqlite_block_store::BlockStoreError;
+use libipld::{error::BlockNotFound, Cid};
+use libp2p::kad::{record::Key, GetRecordError};
+use rusqlite::ErrorCode;
+use thiserror::Error;
+
+pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The classes of failures of `Ipfs` methods. Variants holding an
+/// `anyhow::Error` keep the original error, which can be downcast for
+/// details like the failed dial attempts of a `ConnectFailed`.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The block is not in the store and couldn't be fetched from the
+    /// providers.
+    #[error("{}", block_not_found(.0))]
+    BlockNotFound(Cid),
+    /// A `FetchTimeout`, `ConnectTimeout` or `RequestTimeout`.
+    #[error(transparent)]
+    Timeout(anyhow::Error),
+    /// No providers of the block were found in the dht.
+    #[error("No providers found for block {0}.")]
+    NoProviders(Cid),
+    /// No record with the key was found in the dht.
+    #[error("No record found for key {}.", record_key(.0))]
+    RecordNotFound(Key),
+    /// The block store reported a damaged database.
+    #[error(transparent)]
+    StorageCorruption(anyhow::Error),
+    /// Inserting a block would exceed `StorageConfig::max_bytes`.
+    #[error(transparent)]
+    QuotaExceeded(QuotaExceeded),
+    /// The store was opened with `StorageConfig::read_only`.
+    #[error(transparent)]
+    ReadOnly(ReadOnly),
+    /// A `ConnectFailed`, no connection to the peer could be established.
+    #[error(transparent)]
+    NotConnected(anyhow::Error),
+    /// A dht query failed or couldn't be started because the node isn't
+    /// bootstrapped.
+    #[error(transparent)]
+    DhtQueryFailed(anyhow::Error),
+    /// The node was shut down.
+    #[error(transparent)]
+    Shutdown(Shutdown),
+    /// Any other failure.
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        if let Some(BlockNotFound(cid)) = err.downcast_ref() {
+            return Self::BlockNotFound(*cid);
+        }
+        if let Some(KadGetRecordError(GetRecordError::NotFound { key, .. })) = err.downcast_ref() {
+            return Self::RecordNotFound(key.clone());
+        }
+        if let Some(QuotaExceeded { max_bytes }) = err.downcast_ref() {
+            return Self::QuotaExceeded(QuotaExceeded {
+                max_bytes: *max_bytes,
+            });
+        }
+        if err.is::<ReadOnly>() {
+            Self::ReadOnly(ReadOnly)
+        } else if err.is::<Shutdown>() {
+            Self::Shutdown(Shutdown)
+        } else if err.is::<FetchTimeout>()
+            || err.is::<ConnectTimeout>()
+            || err.is::<RequestTimeout>()
+        {
+            Self::Timeout(err)
+        } else if err.is::<ConnectFailed>() {
+            Self::NotConnected(err)
+        } else if err.is::<NotBootstrapped>()
+            || err.is::<NoKnownPeers>()
+            || err.is::<KadBootstrapError>()
+            || err.is::<KadGetClosestPeersError>()
+            || err.is::<KadGetProvidersError>()
+            || err.is::<KadAddProviderError>()
+            || err.is::<KadGetRecordError>()
+            || err.is::<KadPutRecordError>()
+            || err.is::<IpnsRecordNotFound>()
+        {
+            Self::DhtQueryFailed(err)
+        } else if err.chain().any(is_corruption) {
+            Self::StorageCorruption(err)
+        } else {
+            Self::Other(err)
+        }
+    }
+}
+
+/// Whether `err` is a sqlite error reporting a damaged database file. The
+/// block store doesn't expose the sqlite error as a source, so it is
+/// unwrapped here.
+fn is_corruption(err: &(dyn std::error::Error + 'static)) -> bool {
+    let err = match err.downcast_ref::<BlockStoreError>() {
+        Some(BlockStoreError::SqliteError(err, _)) => err,
+        Some(_) => return false,
+        None => match err.downcast_ref::<rusqlite::Error>() {
+            Some(err) => err,
+            None => return false,
+        },
+    };
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(err, _)
+            if err.code == ErrorCode::DatabaseCorrupt || err.code == ErrorCode::NotADatabase
+    )
+}
+
+/// Formats like the `BlockNotFound` error of libipld.
+fn block_not_found(cid: &Cid) -> BlockNotFound {
+    BlockNotFound(*cid)
+}
+
+/// Formats record keys, which are usually text, lossily as utf8.
+fn record_key(key: &Key) -> String {
+    String::from_utf8_lossy(&key.to_vec()).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{multihash::Code, raw::RawCodec, store::DefaultParams, Block};
+
+    #[test]
+    fn test_classify() {
+        let block =
+            Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &b"classify"[..]).unwrap();
+        let cid = *block.cid();
+        let err = Error::from(anyhow::Error::from(BlockNotFound(cid)));
+        assert!(matches!(err, Error::BlockNotFound(c) if c == cid));
+        assert_eq!(err.to_string(), BlockNotFound(cid).to_string());
+
+        let err = Error::from(anyhow::Error::from(FetchTimeout(cid)).context("fetching"));
+        assert!(matches!(err, Error::Timeout(_)));
+        assert_eq!(err.to_string(), "fetching");
+
+        let err = Error::from(anyhow::Error::from(NotBootstrapped));
+        assert!(matches!(err, Error::DhtQueryFailed(_)));
+
+        let key = Key::new(b"key");
+        let err = Error::from(anyhow::Error::from(KadGetRecordError(
+            GetRecordError::NotFound {
+                key: key.clone(),
+                closest_peers: vec![],
+            },
+        )));
+        assert!(matches!(err, Error::RecordNotFound(ref k) if *k == key));
+        assert_eq!(err.to_string(), "No record found for key key.");
+
+        let corrupt = |code| {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code,
+                    extended_code: 0,
+                },
+                None,
+            )
+        };
+        let err = Error::from(anyhow::Error::from(BlockStoreError::SqliteError(
+            corrupt(ErrorCode::DatabaseCorrupt),
+            "loading",
+        )));
+        assert!(matches!(err, Error::StorageCorruption(_)));
+        let err =
+            Error::from(anyhow::Error::from(corrupt(ErrorCode::NotADatabase)).context("open"));
+        assert!(matches!(err, Error::StorageCorruption(_)));
+        let err = Error::from(anyhow::Error::from(corrupt(ErrorCode::DatabaseBusy)));
+        assert!(matches!(err, Error::Other(_)));
+        // the message alone doesn't make it a corruption
+        let err = Error::from(anyhow::anyhow!("database disk image is malformed"));
+        assert!(matches!(err, Error::Other(_)));
+
+        // errors passed through anyhow keep their class
+        let err = Error::from(anyhow::Error::from(Error::NoProviders(cid)));
+        assert!(matches!(err, Error::NoProviders(c) if c == cid));
+        assert!(matches!(
+            Error::from(anyhow::anyhow!("other")),
+            Error::Other(_)
+        ));
+    }
+}
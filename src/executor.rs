@@ -8,9 +8,10 @@
 //! for this to work `ipfs-embed` needs to be executed within the context of a
 //! tokio runtime. ipfs-embed won't spawn any on its own.
 
-use futures::{Future, FutureExt};
+use futures::{channel::mpsc, Future, FutureExt};
+use parking_lot::Mutex;
 use pin_project::pin_project;
-use std::{pin::Pin, task::Poll};
+use std::{any::Any, panic::AssertUnwindSafe, pin::Pin, sync::Arc, task::Poll};
 
 #[derive(Clone)]
 pub enum Executor {
@@ -46,6 +47,24 @@ impl Executor {
         }
     }
 
+    /// Spawns a task that is expected to run until it is stopped. Its exit or
+    /// panic is recorded in `monitor`.
+    pub(crate) fn spawn_monitored<F: Future<Output = ()> + Send + 'static>(
+        &self,
+        monitor: &TaskMonitor,
+        future: F,
+    ) -> JoinHandle<()> {
+        monitor.set(TaskStatus::Running);
+        let monitor = monitor.clone();
+        self.spawn(async move {
+            let status = match AssertUnwindSafe(future).catch_unwind().await {
+                Ok(()) => TaskStatus::Exited,
+                Err(panic) => TaskStatus::Panicked(panic_message(&*panic)),
+            };
+            monitor.set(status);
+        })
+    }
+
     pub fn spawn_blocking<Fun: FnOnce() -> T + Send + 'static, T: Send + 'static>(
         &self,
         f: Fun,
@@ -71,6 +90,128 @@ impl Default for Executor {
     }
 }
 
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".into()
+    }
+}
+
+/// A background task of a node.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BackgroundTask {
+    /// Drives the swarm and handles the network commands.
+    Swarm,
+    /// Runs the garbage collector.
+    Gc,
+}
+
+/// Status of a background task.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TaskStatus {
+    /// The task is running.
+    Running,
+    /// The task was stopped or is not needed, like the garbage collector of a
+    /// read only store or of a store with `GcMode::Disabled`.
+    Stopped,
+    /// The task returned before it was stopped.
+    Exited,
+    /// The task panicked with the contained message.
+    Panicked(String),
+}
+
+impl TaskStatus {
+    /// Returns `true` if the task exited or panicked.
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Exited | Self::Panicked(_))
+    }
+}
+
+/// Reported when a background task exits or panics.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TaskEvent {
+    pub task: BackgroundTask,
+    pub status: TaskStatus,
+}
+
+/// Status of the background tasks of a node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NodeHealth {
+    pub swarm: TaskStatus,
+    pub gc: TaskStatus,
+}
+
+impl NodeHealth {
+    /// Returns `true` if no background task exited or panicked.
+    pub fn is_healthy(&self) -> bool {
+        !self.swarm.is_failed() && !self.gc.is_failed()
+    }
+}
+
+struct MonitorState {
+    status: TaskStatus,
+    subscribers: Vec<mpsc::UnboundedSender<TaskEvent>>,
+}
+
+/// Tracks the status of a task spawned with `Executor::spawn_monitored`.
+#[derive(Clone)]
+pub(crate) struct TaskMonitor {
+    task: BackgroundTask,
+    state: Arc<Mutex<MonitorState>>,
+}
+
+impl TaskMonitor {
+    pub fn new(task: BackgroundTask) -> Self {
+        Self {
+            task,
+            state: Arc::new(Mutex::new(MonitorState {
+                status: TaskStatus::Stopped,
+                subscribers: vec![],
+            })),
+        }
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.state.lock().status.clone()
+    }
+
+    /// Returns a stream of the exits and panics of the task.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<TaskEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.state.lock().subscribers.push(tx);
+        rx
+    }
+
+    /// Marks the task as stopped, so that its exit isn't reported. A failed
+    /// task keeps its status.
+    pub fn stop(&self) {
+        self.set(TaskStatus::Stopped);
+    }
+
+    fn set(&self, status: TaskStatus) {
+        let mut state = self.state.lock();
+        if (state.status == TaskStatus::Stopped && status.is_failed())
+            || (state.status.is_failed() && status == TaskStatus::Stopped)
+        {
+            return;
+        }
+        if status.is_failed() {
+            tracing::error!("{:?} task failed: {:?}", self.task, status);
+            let event = TaskEvent {
+                task: self.task,
+                status: status.clone(),
+            };
+            state
+                .subscribers
+                .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+        }
+        state.status = status;
+    }
+}
+
 #[pin_project(project = EnumProj)]
 pub enum JoinHandle<T> {
     #[cfg(feature = "tokio")]
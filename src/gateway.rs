@@ -0,0 +1,311 @@
+//! A minimal read-only HTTP gateway serving blocks and UnixFS files from the
+//! local store.
+use crate::{
+    db::StorageService,
+    executor::{Executor, JoinHandle},
+};
+use anyhow::Result;
+use libipld::{
+    codec::{Decode, References},
+    error::BlockNotFound,
+    store::StoreParams,
+    Block, Cid, Ipld,
+};
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tide::{Next, Request, Response, StatusCode};
+
+/// Maximum number of requests handled at the same time. Further requests are
+/// answered with `503 Service Unavailable`.
+const GATEWAY_CONCURRENCY: usize = 64;
+
+/// A running gateway. The server is stopped when the handle is dropped.
+pub struct GatewayHandle {
+    addr: SocketAddr,
+    task: Option<JoinHandle<()>>,
+}
+
+impl GatewayHandle {
+    /// The address the gateway is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for GatewayHandle {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+pub(crate) fn serve<P: StoreParams>(
+    storage: StorageService<P>,
+    executor: &Executor,
+    addr: SocketAddr,
+) -> Result<GatewayHandle>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let mut app = tide::with_state(storage);
+    app.with(ConcurrencyLimit::default());
+    app.at("/ipfs/:cid").get(get_path::<P>);
+    app.at("/ipfs/:cid/*path").get(get_path::<P>);
+    let task = executor.spawn(async move {
+        if let Err(err) = app.listen(listener).await {
+            tracing::warn!("gateway stopped: {}", err);
+        }
+    });
+    Ok(GatewayHandle {
+        addr,
+        task: Some(task),
+    })
+}
+
+/// Rejects requests while `GATEWAY_CONCURRENCY` requests are in flight.
+#[derive(Clone, Default)]
+struct ConcurrencyLimit(Arc<AtomicUsize>);
+
+#[tide::utils::async_trait]
+impl<S: Clone + Send + Sync + 'static> tide::Middleware<S> for ConcurrencyLimit {
+    async fn handle(&self, req: Request<S>, next: Next<'_, S>) -> tide::Result {
+        if self.0.fetch_add(1, Ordering::SeqCst) >= GATEWAY_CONCURRENCY {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+            return Ok(Response::new(StatusCode::ServiceUnavailable));
+        }
+        let res = next.run(req).await;
+        self.0.fetch_sub(1, Ordering::SeqCst);
+        Ok(res)
+    }
+}
+
+async fn get_path<P: StoreParams>(req: Request<StorageService<P>>) -> tide::Result
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    let cid: Cid = match req.param("cid")?.parse() {
+        Ok(cid) => cid,
+        Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+    };
+    let path = req.param("path").unwrap_or_default();
+    let range = req.header("range").map(|range| range.as_str().to_string());
+    let storage = req.state();
+    let res = resolve_path(storage, cid, path).and_then(|cid| match cid {
+        Some(cid) => respond(storage, &cid, range.as_deref()),
+        None => Ok(Response::new(StatusCode::NotFound)),
+    });
+    match res {
+        Ok(res) => Ok(res),
+        Err(err) if err.downcast_ref::<BlockNotFound>().is_some() => {
+            Ok(Response::new(StatusCode::NotFound))
+        }
+        Err(err) => Err(tide::Error::new(StatusCode::InternalServerError, err)),
+    }
+}
+
+/// Follows `path` starting at `cid`. UnixFS directories are resolved by entry
+/// name, other blocks by the keys and indices of their ipld.
+fn resolve_path<P: StoreParams>(
+    storage: &StorageService<P>,
+    mut cid: Cid,
+    path: &str,
+) -> Result<Option<Cid>>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        #[cfg(feature = "unixfs")]
+        {
+            if let Ok(entries) = crate::unixfs::ls(storage, &cid) {
+                match entries.into_iter().find(|entry| entry.name == segment) {
+                    Some(entry) => cid = entry.cid,
+                    None => return Ok(None),
+                }
+                continue;
+            }
+        }
+        let data = storage.get(&cid)?.ok_or(BlockNotFound(cid))?;
+        let ipld = Block::<P>::new_unchecked(cid, data).ipld()?;
+        let child = match &ipld {
+            Ipld::Map(map) => map.get(segment),
+            Ipld::List(list) => segment.parse::<usize>().ok().and_then(|i| list.get(i)),
+            _ => None,
+        };
+        match child {
+            Some(Ipld::Link(link)) => cid = *link,
+            _ => return Ok(None),
+        }
+    }
+    Ok(Some(cid))
+}
+
+/// Serves UnixFS files with range support and any other block as is.
+fn respond<P: StoreParams>(
+    storage: &StorageService<P>,
+    cid: &Cid,
+    range: Option<&str>,
+) -> Result<Response>
+where
+    Ipld: References<P::Codecs> + Decode<P::Codecs>,
+{
+    #[cfg(feature = "unixfs")]
+    {
+        if let Some(size) = crate::unixfs::file_size(storage, cid)? {
+            let (status, start, end) = match range.map(|range| parse_range(range, size)) {
+                None => (StatusCode::Ok, 0, size),
+                Some(Some((start, end))) => (StatusCode::PartialContent, start, end),
+                Some(None) => {
+                    let mut res = Response::new(StatusCode::RequestedRangeNotSatisfiable);
+                    res.insert_header("content-range", format!("bytes */{}", size));
+                    return Ok(res);
+                }
+            };
+            let data = crate::unixfs::read_file(storage, cid, start, end)?;
+            let mut res = Response::builder(status)
+                .content_type("application/octet-stream")
+                .header("accept-ranges", "bytes")
+                .body(data)
+                .build();
+            if status == StatusCode::PartialContent {
+                res.insert_header(
+                    "content-range",
+                    format!("bytes {}-{}/{}", start, end - 1, size),
+                );
+            }
+            return Ok(res);
+        }
+    }
+    #[cfg(not(feature = "unixfs"))]
+    let _ = range;
+    let data = storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type("application/octet-stream")
+        .body(data)
+        .build())
+}
+
+/// Parses a single `bytes=` range into a half open interval. Returns `None`
+/// if the range can't be satisfied.
+#[cfg(feature = "unixfs")]
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix = suffix.parse::<u64>().ok()?;
+            (size.saturating_sub(suffix), size)
+        }
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1),
+        ),
+    };
+    let end = end.min(size);
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::StorageConfig;
+    use async_std::{io::prelude::*, net::TcpStream};
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code, raw::RawCodec, store::DefaultParams};
+    use std::time::Duration;
+
+    fn create_store() -> StorageService<DefaultParams> {
+        let config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        StorageService::open(config, Executor::new()).unwrap()
+    }
+
+    /// Sends a GET request and returns the status line and body.
+    async fn get(addr: SocketAddr, path: &str, headers: &str) -> Result<(String, Vec<u8>)> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+            path, headers
+        );
+        stream.write_all(req.as_bytes()).await?;
+        let mut res = vec![];
+        stream.read_to_end(&mut res).await?;
+        let split = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(res[..split].to_vec())?;
+        let status = head.lines().next().unwrap().to_string();
+        Ok((status, res[split + 4..].to_vec()))
+    }
+
+    #[async_std::test]
+    async fn test_gateway_blocks() -> Result<()> {
+        let store = create_store();
+        let leaf = Block::<DefaultParams>::encode(RawCodec, Code::Sha2_256, &b"leaf"[..])?;
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Sha2_256,
+            &ipld!({ "child": *leaf.cid() }),
+        )?;
+        store.insert(leaf.clone())?;
+        store.insert(root.clone())?;
+        let handle = serve(store, &Executor::new(), "127.0.0.1:0".parse()?)?;
+        let addr = handle.local_addr();
+
+        let (status, body) = get(addr, &format!("/ipfs/{}", leaf.cid()), "").await?;
+        assert!(status.contains("200"));
+        assert_eq!(body, b"leaf");
+        let path = format!("/ipfs/{}/child", root.cid());
+        let (status, body) = get(addr, &path, "").await?;
+        assert!(status.contains("200"));
+        assert_eq!(body, b"leaf");
+
+        let path = format!("/ipfs/{}/missing", root.cid());
+        assert!(get(addr, &path, "").await?.0.contains("404"));
+        assert!(get(addr, "/ipfs/notacid", "").await?.0.contains("400"));
+
+        // the server stops with the handle
+        drop(handle);
+        futures_timer::Delay::new(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "unixfs")]
+    #[async_std::test]
+    async fn test_gateway_unixfs() -> Result<()> {
+        use crate::unixfs::{add_dir, AddEntry, AddOptions};
+        let store = create_store();
+        let data = (0..100u8).collect::<Vec<_>>();
+        let opts = AddOptions {
+            chunk_size: 16,
+            ..Default::default()
+        };
+        let entries = vec![AddEntry::File("dir/file".into(), &data[..])];
+        let root = add_dir(&store, entries, opts).await?;
+        let handle = serve(store, &Executor::new(), "127.0.0.1:0".parse()?)?;
+        let addr = handle.local_addr();
+
+        let path = format!("/ipfs/{}/dir/file", root);
+        let (status, body) = get(addr, &path, "").await?;
+        assert!(status.contains("200"));
+        assert_eq!(body, data);
+        let (status, body) = get(addr, &path, "Range: bytes=10-40\r\n").await?;
+        assert!(status.contains("206"));
+        assert_eq!(body, &data[10..41]);
+        let (status, body) = get(addr, &path, "Range: bytes=-5\r\n").await?;
+        assert!(status.contains("206"));
+        assert_eq!(body, &data[95..]);
+        let (status, _) = get(addr, &path, "Range: bytes=100-\r\n").await?;
+        assert!(status.contains("416"));
+        Ok(())
+    }
+}
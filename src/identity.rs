@@ -0,0 +1,125 @@
+//! Node identities. Re-exports `libp2p::identity` and adds helpers to persist
+//! the node key.
+pub use libp2p::identity::*;
+
+use anyhow::Context;
+use libipld::Result;
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+/// Loads the node key from `path`, or generates one and writes it to `path`
+/// if the file doesn't exist.
+///
+/// Keys are written protobuf encoded like the keys in the go-ipfs keystore,
+/// readable only by the owner. Files containing the 64 byte encoding of an
+/// ed25519 keypair are read as well.
+pub fn load_or_generate(path: &Path) -> Result<ed25519::Keypair> {
+    if path.exists() {
+        return load(path);
+    }
+    let keypair = ed25519::Keypair::generate();
+    save(path, &keypair)?;
+    Ok(keypair)
+}
+
+/// Writes a key to `path` like `load_or_generate`. Fails if the file
+/// exists.
+pub fn save(path: &Path, keypair: &ed25519::Keypair) -> Result<()> {
+    let bytes = Keypair::Ed25519(keypair.clone()).to_protobuf_encoding()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create key file {}", path.display()))?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Loads a key written by `save` or the 64 byte encoding of an ed25519
+/// keypair.
+pub fn load(path: &Path) -> Result<ed25519::Keypair> {
+    let mut bytes = std::fs::read(path)
+        .with_context(|| format!("failed to read key file {}", path.display()))?;
+    if bytes.len() == 64 {
+        if let Ok(keypair) = ed25519::Keypair::decode(&mut bytes) {
+            return Ok(keypair);
+        }
+    }
+    let keypair = Keypair::from_protobuf_encoding(&bytes)
+        .with_context(|| format!("invalid key file {}", path.display()))?;
+    #[allow(unreachable_patterns)]
+    match keypair {
+        Keypair::Ed25519(keypair) => Ok(keypair),
+        _ => Err(anyhow::anyhow!(
+            "key file {} doesn't contain an ed25519 key",
+            path.display()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, PeerId};
+    use tempdir::TempDir;
+
+    fn peer_id(keypair: &ed25519::Keypair) -> PeerId {
+        PeerId::from(PublicKey::Ed25519(keypair.public()))
+    }
+
+    #[test]
+    fn test_load_or_generate() -> Result<()> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let path = tmp.path().join("keys").join("node");
+        let keypair = load_or_generate(&path)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // restarting keeps the identity
+        assert_eq!(peer_id(&load_or_generate(&path)?), peer_id(&keypair));
+        let config = Config::with_key_file(&path)?;
+        assert_eq!(
+            PeerId::from(config.network.node_key.public()),
+            peer_id(&keypair)
+        );
+
+        // go-ipfs keystore files are protobuf encoded
+        let go_path = tmp.path().join("go-ipfs");
+        let go_keypair = ed25519::Keypair::generate();
+        let bytes = Keypair::Ed25519(go_keypair.clone()).to_protobuf_encoding()?;
+        std::fs::write(&go_path, bytes)?;
+        assert_eq!(peer_id(&load_or_generate(&go_path)?), peer_id(&go_keypair));
+
+        let raw_path = tmp.path().join("raw");
+        std::fs::write(&raw_path, keypair.encode())?;
+        assert_eq!(peer_id(&load_or_generate(&raw_path)?), peer_id(&keypair));
+
+        let corrupt_path = tmp.path().join("corrupt");
+        std::fs::write(&corrupt_path, b"not a key")?;
+        let err = load_or_generate(&corrupt_path).unwrap_err();
+        assert!(err.to_string().contains("invalid key file"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_load() -> Result<()> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let path = tmp.path().join("key");
+        let keypair = ed25519::Keypair::generate();
+        save(&path, &keypair)?;
+        assert_eq!(peer_id(&load(&path)?), peer_id(&keypair));
+        // existing keys are never overwritten
+        assert!(save(&path, &ed25519::Keypair::generate()).is_err());
+        assert_eq!(peer_id(&load(&path)?), peer_id(&keypair));
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+//! IPNS records as specified in https://specs.ipfs.tech/ipns/ipns-record/,
+//! signed with the node key and published in the dht.
+use crate::protobuf::{read_field, write_bytes_field, write_varint_field, Field};
+use chrono::{DateTime, SecondsFormat, Utc};
+use libipld::{cbor::DagCborCodec, codec::Codec, Cid, Ipld, Result};
+use libp2p::{identity::PublicKey, kad::record::Key, multihash::Multihash, PeerId};
+use std::{collections::BTreeMap, time::Duration};
+use thiserror::Error;
+
+/// Validity type of records valid until an end of life.
+const VALIDITY_EOL: u64 = 0;
+/// Multihash code of peer ids that inline the public key.
+const IDENTITY: u64 = 0x00;
+/// Prefix of the signed data of version 2 signatures.
+const SIGNATURE_V2_PREFIX: &[u8] = b"ipns-signature:";
+/// How long resolvers may cache a record, the go-ipfs default.
+pub(crate) const DEFAULT_IPNS_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Error)]
+#[error("Invalid IPNS record: {0}.")]
+pub struct InvalidIpnsRecord(pub &'static str);
+
+#[derive(Debug, Error)]
+#[error("No valid IPNS record found for {0}.")]
+pub struct IpnsRecordNotFound(pub PeerId);
+
+#[derive(Debug, Error)]
+#[error("IPNS record of {peer} has sequence number {sequence}, older than the known {latest}.")]
+pub struct StaleIpnsRecord {
+    pub peer: PeerId,
+    pub sequence: u64,
+    pub latest: u64,
+}
+
+/// A validated IPNS record.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct IpnsRecord {
+    pub value: Vec<u8>,
+    pub eol: DateTime<Utc>,
+    pub sequence: u64,
+    pub ttl: u64,
+}
+
+impl IpnsRecord {
+    /// Returns the cid of an `/ipfs/<cid>` value.
+    pub fn cid(&self) -> Result<Cid> {
+        let value = std::str::from_utf8(&self.value)?;
+        let cid = value
+            .strip_prefix("/ipfs/")
+            .ok_or(InvalidIpnsRecord("value is not an /ipfs/ path"))?;
+        Ok(cid.parse()?)
+    }
+}
+
+/// Returns the dht key of the records of `peer`.
+pub(crate) fn record_key(peer: &PeerId) -> Key {
+    let mut key = b"/ipns/".to_vec();
+    key.extend_from_slice(&peer.to_bytes());
+    key.into()
+}
+
+/// Creates a record pointing to `cid` with both signature versions. `sign`
+/// signs with the key of `public_key`, which is only included in the record
+/// if it can't be extracted from the peer id.
+pub(crate) fn create_record(
+    cid: &Cid,
+    sequence: u64,
+    eol: DateTime<Utc>,
+    ttl: Duration,
+    public_key: &PublicKey,
+    sign: impl Fn(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    let value = format!("/ipfs/{}", cid).into_bytes();
+    let validity = eol.to_rfc3339_opts(SecondsFormat::Nanos, true).into_bytes();
+    let ttl = ttl.as_nanos() as u64;
+    let data = signed_data(&value, &validity, sequence, ttl)?;
+
+    let mut v1 = value.clone();
+    v1.extend_from_slice(&validity);
+    v1.extend_from_slice(b"EOL");
+    let signature_v1 = sign(&v1)?;
+    let mut v2 = SIGNATURE_V2_PREFIX.to_vec();
+    v2.extend_from_slice(&data);
+    let signature_v2 = sign(&v2)?;
+
+    let mut buf = vec![];
+    write_bytes_field(1, &value, &mut buf);
+    write_bytes_field(2, &signature_v1, &mut buf);
+    write_varint_field(3, VALIDITY_EOL, &mut buf);
+    write_bytes_field(4, &validity, &mut buf);
+    write_varint_field(5, sequence, &mut buf);
+    write_varint_field(6, ttl, &mut buf);
+    if Multihash::from(public_key.to_peer_id()).code() != IDENTITY {
+        write_bytes_field(7, &public_key.to_protobuf_encoding(), &mut buf);
+    }
+    write_bytes_field(8, &signature_v2, &mut buf);
+    write_bytes_field(9, &data, &mut buf);
+    Ok(buf)
+}
+
+/// The dag-cbor document covered by the version 2 signature.
+fn signed_data(value: &[u8], validity: &[u8], sequence: u64, ttl: u64) -> Result<Vec<u8>> {
+    let mut map = BTreeMap::new();
+    map.insert("Value".to_string(), Ipld::Bytes(value.to_vec()));
+    map.insert("Validity".to_string(), Ipld::Bytes(validity.to_vec()));
+    map.insert("ValidityType".to_string(), Ipld::Integer(VALIDITY_EOL as _));
+    map.insert("Sequence".to_string(), Ipld::Integer(sequence as _));
+    map.insert("TTL".to_string(), Ipld::Integer(ttl as _));
+    DagCborCodec.encode(&Ipld::Map(map))
+}
+
+/// Decodes and validates a record of `peer` that is valid at `now`. Records
+/// with a version 2 signature are checked against their signed data, records
+/// with only a version 1 signature are accepted for compatibility with older
+/// implementations.
+pub(crate) fn validate_record(
+    peer: &PeerId,
+    bytes: &[u8],
+    now: DateTime<Utc>,
+) -> Result<IpnsRecord> {
+    let mut value = None;
+    let mut signature_v1 = None;
+    let mut validity_type = VALIDITY_EOL;
+    let mut validity = None;
+    let mut sequence = 0;
+    let mut ttl = 0;
+    let mut public_key = None;
+    let mut signature_v2 = None;
+    let mut data = None;
+    let mut buf = bytes;
+    while !buf.is_empty() {
+        match read_field(&mut buf)? {
+            (1, Field::Bytes(bytes)) => value = Some(bytes),
+            (2, Field::Bytes(bytes)) => signature_v1 = Some(bytes),
+            (3, Field::Varint(n)) => validity_type = n,
+            (4, Field::Bytes(bytes)) => validity = Some(bytes),
+            (5, Field::Varint(n)) => sequence = n,
+            (6, Field::Varint(n)) => ttl = n,
+            (7, Field::Bytes(bytes)) => public_key = Some(bytes),
+            (8, Field::Bytes(bytes)) => signature_v2 = Some(bytes),
+            (9, Field::Bytes(bytes)) => data = Some(bytes),
+            _ => {}
+        }
+    }
+
+    let public_key = match public_key {
+        Some(bytes) => PublicKey::from_protobuf_encoding(bytes)?,
+        None => {
+            let hash = Multihash::from(*peer);
+            if hash.code() != IDENTITY {
+                return Err(InvalidIpnsRecord("missing public key").into());
+            }
+            PublicKey::from_protobuf_encoding(hash.digest())?
+        }
+    };
+    if public_key.to_peer_id() != *peer {
+        return Err(InvalidIpnsRecord("public key doesn't match the peer id").into());
+    }
+
+    let record = match (signature_v2, data) {
+        (Some(signature), Some(data)) => {
+            let mut signed = SIGNATURE_V2_PREFIX.to_vec();
+            signed.extend_from_slice(data);
+            if !public_key.verify(&signed, signature) {
+                return Err(InvalidIpnsRecord("invalid signature").into());
+            }
+            let record = decode_signed_data(data)?;
+            // the protobuf fields are unsigned and must match the signed data
+            if value.map_or(false, |value| value != &record.0[..])
+                || validity.map_or(false, |validity| validity != &record.1[..])
+            {
+                return Err(InvalidIpnsRecord("fields don't match the signed data").into());
+            }
+            record
+        }
+        _ => {
+            let value = value.ok_or(InvalidIpnsRecord("missing value"))?;
+            let validity = validity.ok_or(InvalidIpnsRecord("missing validity"))?;
+            let signature = signature_v1.ok_or(InvalidIpnsRecord("missing signature"))?;
+            let mut signed = value.to_vec();
+            signed.extend_from_slice(validity);
+            signed.extend_from_slice(b"EOL");
+            if !public_key.verify(&signed, signature) {
+                return Err(InvalidIpnsRecord("invalid signature").into());
+            }
+            (
+                value.to_vec(),
+                validity.to_vec(),
+                validity_type,
+                sequence,
+                ttl,
+            )
+        }
+    };
+    let (value, validity, validity_type, sequence, ttl) = record;
+    if validity_type != VALIDITY_EOL {
+        return Err(InvalidIpnsRecord("unsupported validity type").into());
+    }
+    let eol = DateTime::parse_from_rfc3339(std::str::from_utf8(&validity)?)?.with_timezone(&Utc);
+    if eol < now {
+        return Err(InvalidIpnsRecord("expired").into());
+    }
+    Ok(IpnsRecord {
+        value,
+        eol,
+        sequence,
+        ttl,
+    })
+}
+
+type SignedData = (Vec<u8>, Vec<u8>, u64, u64, u64);
+
+fn decode_signed_data(data: &[u8]) -> Result<SignedData> {
+    let map = match DagCborCodec.decode::<Ipld>(data)? {
+        Ipld::Map(map) => map,
+        _ => return Err(InvalidIpnsRecord("signed data is not a map").into()),
+    };
+    let bytes = |key: &str| match map.get(key) {
+        Some(Ipld::Bytes(bytes)) => Ok(bytes.clone()),
+        _ => Err(InvalidIpnsRecord("invalid signed data")),
+    };
+    let int = |key: &str| match map.get(key) {
+        Some(Ipld::Integer(n)) if *n >= 0 => Ok(*n as u64),
+        _ => Err(InvalidIpnsRecord("invalid signed data")),
+    };
+    Ok((
+        bytes("Value")?,
+        bytes("Validity")?,
+        int("ValidityType")?,
+        int("Sequence")?,
+        int("TTL")?,
+    ))
+}
+
+/// Returns the newest of the valid `records`, the one with the highest
+/// sequence number and, among those, the latest end of life.
+pub(crate) fn select_record<'a>(
+    peer: &PeerId,
+    records: impl IntoIterator<Item = &'a [u8]>,
+    now: DateTime<Utc>,
+) -> Option<IpnsRecord> {
+    records
+        .into_iter()
+        .filter_map(|bytes| match validate_record(peer, bytes, now) {
+            Ok(record) => Some(record),
+            Err(err) => {
+                tracing::debug!("ignoring IPNS record of {}: {}", peer, err);
+                None
+            }
+        })
+        .max_by_key(|record| (record.sequence, record.eol))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        raw::RawCodec,
+    };
+    use libp2p::identity::Keypair;
+
+    fn record(key: &Keypair, sequence: u64, lifetime: chrono::Duration) -> Vec<u8> {
+        let cid = Cid::new_v1(RawCodec.into(), Code::Sha2_256.digest(b"ipns"));
+        create_record(
+            &cid,
+            sequence,
+            Utc::now() + lifetime,
+            DEFAULT_IPNS_TTL,
+            &key.public(),
+            |msg| Ok(key.sign(msg)?),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_record() {
+        let key = Keypair::generate_ed25519();
+        let peer = key.public().to_peer_id();
+        let bytes = record(&key, 3, chrono::Duration::hours(1));
+        let record = validate_record(&peer, &bytes, Utc::now()).unwrap();
+        assert_eq!(record.sequence, 3);
+        assert_eq!(record.ttl, DEFAULT_IPNS_TTL.as_nanos() as u64);
+        assert!(record.cid().is_ok());
+
+        // signed by another key
+        let other = Keypair::generate_ed25519().public().to_peer_id();
+        assert!(validate_record(&other, &bytes, Utc::now()).is_err());
+
+        // tampered with
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(validate_record(&peer, &tampered, Utc::now()).is_err());
+
+        // expired
+        let expired = Utc::now() + chrono::Duration::hours(2);
+        assert!(validate_record(&peer, &bytes, expired).is_err());
+    }
+
+    #[test]
+    fn test_select_newest_record() {
+        let key = Keypair::generate_ed25519();
+        let peer = key.public().to_peer_id();
+        let old = record(&key, 1, chrono::Duration::hours(2));
+        let new = record(&key, 2, chrono::Duration::hours(1));
+        let expired = record(&key, 3, chrono::Duration::hours(-1));
+        let records = vec![&new[..], &old[..], &expired[..]];
+        let record = select_record(&peer, records, Utc::now()).unwrap();
+        assert_eq!(record.sequence, 2);
+    }
+}
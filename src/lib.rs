@@ -5,69 +5,155 @@
 //! # #[async_std::main]
 //! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
 //! # use ipfs_embed::{Config, DefaultParams, Ipfs};
-//! let mut ipfs = Ipfs::<DefaultParams>::new(Config::default()).await?;
-//! ipfs.listen_on("/ip4/0.0.0.0/tcp/0".parse()?);
+//! let mut config = Config::default();
+//! config.network.listen_addresses = vec!["/ip4/0.0.0.0/tcp/0".parse()?];
+//! let ipfs = Ipfs::<DefaultParams>::new(config).await?;
 //! # Ok(()) }
 //! ```
 
+#[cfg(feature = "http-api")]
+mod api;
+mod backend;
+mod car;
+#[cfg(feature = "compression")]
+mod compression;
+#[cfg(feature = "serde")]
+mod config_file;
 mod db;
+mod dnslink;
+mod error;
 mod executor;
+#[cfg(feature = "http-gateway")]
+mod gateway;
+pub mod identity;
+mod ipns;
+#[cfg(feature = "metrics-server")]
+mod metrics;
 mod net;
+mod protobuf;
+mod reprovider;
+mod stream;
 #[cfg(feature = "telemetry")]
 mod telemetry;
 #[cfg(test)]
 mod test_util;
-mod variable;
+#[cfg(feature = "unixfs")]
+mod unixfs;
+pub mod variable;
 
 /// convenience re-export of configuration types from libp2p
 pub mod config {
+    pub use ipnet::IpNet;
     pub use libp2p::{
+        autonat::Config as AutonatConfig,
         dns::{ResolverConfig, ResolverOpts},
-        gossipsub::GossipsubConfig,
+        gossipsub::{GossipsubConfig, PeerScoreParams, PeerScoreThresholds, TopicScoreParams},
         identify::Config as IdentifyConfig,
         kad::record::store::MemoryStoreConfig as KadConfig,
         mdns::MdnsConfig,
         ping::Config as PingConfig,
+        websocket::tls::Config as TlsConfig,
     };
     pub use libp2p_bitswap::BitswapConfig;
     pub use libp2p_broadcast::BroadcastConfig;
 }
 
+#[cfg(feature = "http-api")]
+pub use crate::api::ApiHandle;
+#[cfg(feature = "compression")]
+pub use crate::compression::Compression;
+#[cfg(feature = "serde")]
+pub use crate::config_file::{ConfigFile, NetworkConfigFile, StorageConfigFile};
+#[cfg(feature = "http-gateway")]
+pub use crate::gateway::GatewayHandle;
+#[cfg(feature = "metrics-server")]
+pub use crate::metrics::MetricsHandle;
 #[cfg(feature = "telemetry")]
 pub use crate::telemetry::telemetry;
+#[cfg(feature = "unixfs")]
+pub use crate::unixfs::{
+    AddEntry, AddOptions, DirEntry, DuplicateEntry, EntryKind, InvalidChunkSize, InvalidPath,
+    InvalidRange, InvalidUnixFs, NotADirectory,
+};
 pub use crate::{
-    db::{Batch, StorageConfig, StorageService, TempPin},
-    executor::Executor,
+    backend::{
+        BackendBlockInfo, BackendKind, BackendStats, BackendTransaction, BlockStoreBackend,
+        DiskUsage, Durability, MemBackend, SqliteBackend, TempPin,
+    },
+    car::{BlockTooLarge, CarStats, InvalidCar, MissingBlocks},
+    db::{
+        AliasConflict, AliasWatcher, AtomicBatch, Batch, BlockFilter, BlockInfo, BlockRetained,
+        CompactStats, ConflictingAlias, DagStat, GcDisabled, GcEvent, GcMode, GcPolicy, GcStats,
+        ImportStats, Lease, MissingAliasTarget, PeerAddresses, PinStatus, QuotaExceeded,
+        QuotaPolicy, ReadOnly, Refs, ReservedAlias, Shutdown, StorageConfig, StorageService,
+        StoreEvent, StoreEvents, VerifyConfig, VerifyEvent,
+    },
+    dnslink::DnsLinkError,
+    error::Error,
+    executor::{BackgroundTask, Executor, NodeHealth, TaskEvent, TaskStatus},
+    ipns::{InvalidIpnsRecord, IpnsRecordNotFound, StaleIpnsRecord},
     net::{
-        AddressSource, ConnectionFailure, Direction, DnsConfig, Event, GossipEvent, ListenerEvent,
-        NetworkConfig, PeerInfo, Rtt, SwarmEvents, SyncEvent, SyncQuery,
+        custom_transport, parse_psk, read_psk_file, AddressSource, BandwidthStats, BitswapStats,
+        Budget, ConnectFailed, ConnectTimeout, ConnectionCounts, ConnectionFailure,
+        ConnectionFailureKind, ConnectionGate, ConnectionGated, ConnectionInfo, ConnectionLimits,
+        CustomTransport, DhtFetchConfig, DhtSyncConfig, Direction, DnsConfig, Event, EventFilter,
+        FetchTimeout, GossipEvent, InboundRequest, InvalidNetworkConfig, ListenFailed,
+        ListenerEvent, NetworkConfig, NetworkConfigBuilder, PayloadTooLarge, PeerBandwidth,
+        PeerInfo, PskMismatch, PublishedRecord, PutOptions, QueryId, RateLimit, RebootstrapConfig,
+        RelayServerConfig, ReprovidePolicy, RequestFailed, RequestResponseConfig, RequestTimeout,
+        ReservationStatus, Rtt, ServePolicy, Socket, SwarmEvents, SyncEvent, SyncPipelineConfig,
+        SyncQuery, SyncSchedulerConfig, SyncTraversal, Throttle, UnsupportedProtocol,
+        ValidationResult, WebsocketConfig,
     },
+    stream::{BlockReader, BlockWriter},
 };
 
 pub use libipld::{store::DefaultParams, Block, Cid};
 pub use libp2p::{
+    autonat::NatStatus,
     core::{transport::ListenerId, ConnectedPoint, Multiaddr, PeerId},
-    identity,
+    gossipsub::MessageId,
     kad::{kbucket::Key as BucketKey, record::Key, PeerRecord, Quorum, Record},
     multiaddr,
+    pnet::PreSharedKey,
     swarm::{AddressRecord, AddressScore},
 };
 
-use crate::net::NetworkService;
+use crate::{
+    dnslink::{DnsResolver, TxtResolver},
+    error::Result,
+    executor::JoinHandle,
+    net::{MessageValidator, NetworkService},
+    reprovider::Reprovider,
+};
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
-use futures::{stream::Stream, Future};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either},
+    io::{AsyncRead, AsyncWrite},
+    stream::{FuturesUnordered, Stream, StreamExt},
+    Future, FutureExt, TryFutureExt,
+};
 use libipld::{
     codec::References,
-    error::BlockNotFound,
-    store::{ StoreParams, Store},
-    Ipld, Result,
+    store::{Store, StoreParams},
+    Ipld,
+};
+use libp2p::{
+    identity::{ed25519::Keypair, PublicKey},
+    mdns::MdnsConfig,
 };
-use libp2p::identity::ed25519::{Keypair, PublicKey};
 use libp2p_bitswap::BitswapStore;
 use parking_lot::Mutex;
 use prometheus::Registry;
-use std::{collections::HashSet, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tracing::Instrument;
 
 /// Ipfs configuration.
 #[derive(Debug)]
@@ -82,11 +168,25 @@ impl Config {
     /// Creates a default configuration from a `path` and a `cache_size`. If the
     /// `path` is `None`, ipfs will use an in-memory block store.
     pub fn new(path: &Path, keypair: Keypair) -> Self {
+        Self::with_keypair(path, identity::Keypair::Ed25519(keypair))
+    }
+
+    /// Creates a default configuration like `Config::new` with a node key of
+    /// any supported type.
+    pub fn with_keypair(path: &Path, keypair: identity::Keypair) -> Self {
         let sweep_interval = std::time::Duration::from_millis(10000);
         let storage = StorageConfig::new(Some(path.join("blocks")), None, 0, sweep_interval);
-        let network = NetworkConfig::new(keypair);
+        let network = NetworkConfig::with_keypair(keypair);
         Self { storage, network }
     }
+
+    /// Creates the default configuration with the node key loaded from
+    /// `path`. If there is no key file, a key is generated and written to
+    /// `path`.
+    pub fn with_key_file(path: &Path) -> anyhow::Result<Self> {
+        let keypair = identity::load_or_generate(path)?;
+        Ok(Self::new(Path::new("."), keypair))
+    }
 }
 
 impl Default for Config {
@@ -100,6 +200,12 @@ impl Default for Config {
 pub struct Ipfs<P: StoreParams> {
     storage: StorageService<P>,
     network: NetworkService,
+    address_book: Option<Arc<AddressBookSaver>>,
+    reprovider: Arc<Reprovider>,
+    dns: Arc<dyn TxtResolver>,
+    /// Highest IPNS sequence number published or resolved per peer.
+    ipns_sequences: Arc<Mutex<HashMap<PeerId, u64>>>,
+    executor: Executor,
 }
 
 impl<P: StoreParams> std::fmt::Debug for Ipfs<P> {
@@ -108,7 +214,55 @@ impl<P: StoreParams> std::fmt::Debug for Ipfs<P> {
     }
 }
 
-struct BitswapStorage<P: StoreParams>(StorageService<P>);
+/// Saves the address book periodically and when the last `Ipfs` handle is
+/// dropped.
+struct AddressBookSaver {
+    save: Box<dyn Fn() + Send + Sync>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AddressBookSaver {
+    /// Stops the periodic saves and saves the address book a last time.
+    fn stop(&self) {
+        if let Some(task) = self.task.lock().take() {
+            task.abort();
+            (self.save)();
+        }
+    }
+}
+
+impl Drop for AddressBookSaver {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Answers bitswap requests of peers from the store, hiding the blocks not
+/// allowed by the `ServePolicy`.
+struct BitswapStorage<P: StoreParams> {
+    storage: StorageService<P>,
+    policy: ServePolicy,
+}
+
+impl<P: StoreParams> BitswapStorage<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    fn serves(&self, cid: &Cid) -> anyhow::Result<bool> {
+        let serves = match &self.policy {
+            ServePolicy::All => true,
+            ServePolicy::AliasedOnly => self
+                .storage
+                .pin_status(cid)?
+                .map_or(false, |status| status.is_retained()),
+            ServePolicy::Custom(f) => f(cid),
+        };
+        if !serves {
+            net::BITSWAP_SERVE_DENIED.inc();
+        }
+        Ok(serves)
+    }
+}
 
 impl<P: StoreParams> BitswapStore for BitswapStorage<P>
 where
@@ -116,20 +270,23 @@ where
 {
     type Params = P;
 
-    fn contains(&mut self, cid: &Cid) -> Result<bool> {
-        self.0.contains(cid)
+    fn contains(&mut self, cid: &Cid) -> anyhow::Result<bool> {
+        Ok(self.storage.contains(cid)? && self.serves(cid)?)
     }
 
-    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        self.0.get(cid)
+    fn get(&mut self, cid: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.storage.get(cid)? {
+            Some(data) if self.serves(cid)? => Ok(Some(data)),
+            _ => Ok(None),
+        }
     }
 
-    fn insert(&mut self, block: &Block<P>) -> Result<()> {
-        self.0.insert(block.clone())
+    fn insert(&mut self, block: &Block<P>) -> anyhow::Result<()> {
+        self.storage.insert_received(block.clone())
     }
 
-    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
-        self.0.missing_blocks(cid)
+    fn missing_blocks(&mut self, cid: &Cid) -> anyhow::Result<Vec<Cid>> {
+        self.storage.missing_blocks(cid)
     }
 }
 
@@ -143,13 +300,93 @@ where
     /// dht cleanup tasks run in the background.
     pub async fn new(config: Config) -> Result<Self> {
         let executor = Executor::new();
-        Self::new0(config, executor).await
+        // building the swarm takes a lot of stack in debug builds, boxing
+        // keeps the future small enough to be awaited on a default stack
+        Box::pin(Self::new0(config, executor)).await
+    }
+
+    /// Creates a new `Ipfs` storing blocks in `backend` instead of the backend
+    /// selected by the storage configuration.
+    pub async fn new_with_backend(
+        config: Config,
+        backend: Box<dyn BlockStoreBackend<P>>,
+    ) -> Result<Self> {
+        let executor = Executor::new();
+        let storage = StorageService::open_with_backend(config.storage, backend, executor.clone())?;
+        Box::pin(Self::with_storage(config.network, storage, executor)).await
     }
+
     async fn new0(config: Config, executor: Executor) -> Result<Self> {
         let storage = StorageService::open(config.storage, executor.clone())?;
-        let bitswap = BitswapStorage(storage.clone());
-        let network = NetworkService::new(config.network, bitswap, executor).await?;
-        Ok(Self { storage, network })
+        Self::with_storage(config.network, storage, executor).await
+    }
+
+    async fn with_storage(
+        network: NetworkConfig,
+        storage: StorageService<P>,
+        executor: Executor,
+    ) -> Result<Self> {
+        let bitswap = BitswapStorage {
+            storage: storage.clone(),
+            policy: network.serve_policy.clone(),
+        };
+        let banned = storage.bans()?;
+        let interval = network.address_book_interval;
+        let (reprovide, reprovide_interval) = (network.reprovide, network.reprovide_interval);
+        let max_age = chrono::Duration::from_std(network.address_book_max_age).ok();
+        let cutoff = max_age.and_then(|max_age| Utc::now().checked_sub_signed(max_age));
+        let mut addresses = storage.address_book()?;
+        if let Some(cutoff) = cutoff {
+            for (_, addrs) in &mut addresses {
+                addrs.retain(|(_, _, seen)| *seen >= cutoff);
+            }
+        }
+        let network =
+            NetworkService::new(network, bitswap, banned, addresses, executor.clone()).await?;
+        let dns_config = network.dns_config();
+        let address_book = interval.map(|interval| {
+            let (storage, network) = (storage.clone(), network.clone());
+            let save = move || {
+                if let Err(err) = storage.save_address_book(&network.address_book()) {
+                    tracing::warn!("failed to save address book: {}", err);
+                }
+            };
+            let periodic = save.clone();
+            let task = executor.spawn(async move {
+                loop {
+                    futures_timer::Delay::new(interval).await;
+                    periodic();
+                }
+            });
+            Arc::new(AddressBookSaver {
+                save: Box::new(save),
+                task: Mutex::new(Some(task)),
+            })
+        });
+        let reprovider = Arc::new(Reprovider::new(
+            reprovide,
+            reprovide_interval,
+            storage.clone(),
+            network.clone(),
+            &executor,
+        ));
+        Ok(Self {
+            storage,
+            network,
+            address_book,
+            reprovider,
+            dns: Arc::new(DnsResolver::new(dns_config, executor.clone())),
+            ipns_sequences: Default::default(),
+            executor,
+        })
+    }
+
+    /// Saves the known peer addresses to the store. They are loaded again
+    /// by `Ipfs::new`, so that known peers can be dialed right away.
+    pub fn save_address_book(&self) -> Result<()> {
+        Ok(self
+            .storage
+            .save_address_book(&self.network.address_book())?)
     }
 
     /// Returns the local `PublicKey`.
@@ -172,6 +409,21 @@ where
         self.network.listen_on(addr)
     }
 
+    /// Listens on a new `Multiaddr`, returning the `ListenerId` once the
+    /// listener was started. The id can be passed to `remove_listener`.
+    pub fn add_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> impl Future<Output = Result<(ListenerId, impl Stream<Item = ListenerEvent>)>> {
+        self.network.add_listener(addr).err_into()
+    }
+
+    /// Stops a listener. Its event stream yields the expired addresses and
+    /// ends. Returns `false` if the listener is unknown or already closed.
+    pub fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.network.remove_listener(id)
+    }
+
     /// Returns the currently active listener addresses.
     pub fn listeners(&self) -> Vec<Multiaddr> {
         self.network.listeners()
@@ -187,6 +439,27 @@ where
         self.network.external_addresses()
     }
 
+    /// Returns the configured connection limits.
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        self.network.connection_limits()
+    }
+
+    /// Returns the current number of connections.
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.network.connection_counts()
+    }
+
+    /// Returns the reachability of this node as detected by AutoNAT.
+    pub fn nat_status(&self) -> NatStatus {
+        self.network.nat_status()
+    }
+
+    /// Returns a stream yielding the nat status and then every change, with
+    /// the semantics of a `variable::Observer`.
+    pub fn watch_nat_status(&self) -> impl Stream<Item = NatStatus> {
+        self.network.watch_nat_status()
+    }
+
     /// Adds a known `Multiaddr` for a `PeerId`.
     pub fn add_address(&mut self, peer: PeerId, addr: Multiaddr) {
         self.network.add_address(peer, addr)
@@ -213,15 +486,105 @@ where
         self.network.dial_address(peer, addr);
     }
 
+    /// Closes all connections to a `PeerId` without preventing new ones. The
+    /// future resolves once the connections are closed.
+    pub fn disconnect(&mut self, peer: PeerId) -> impl Future<Output = Result<()>> {
+        self.network.disconnect(peer, None).err_into()
+    }
+
+    /// Closes the connections to a `PeerId` using the given `Multiaddr`. The
+    /// future resolves once the connections are closed.
+    pub fn disconnect_address(
+        &mut self,
+        peer: PeerId,
+        addr: Multiaddr,
+    ) -> impl Future<Output = Result<()>> {
+        self.network.disconnect(peer, Some(addr)).err_into()
+    }
+
     /// Bans a `PeerId` from the swarm, dropping all existing connections and
-    /// preventing new connections from the peer.
-    pub fn ban(&mut self, peer: PeerId) {
-        self.network.ban(peer)
+    /// preventing new connections from the peer. The ban is persisted in the
+    /// store and applied again on restart.
+    pub fn ban(&mut self, peer: PeerId) -> Result<()> {
+        self.storage.ban(&peer, None)?;
+        self.network.ban(peer);
+        Ok(())
+    }
+
+    /// Bans a `PeerId` like `ban`, lifting the ban after `duration`.
+    pub fn ban_for(&mut self, peer: PeerId, duration: Duration) -> Result<()> {
+        let expires =
+            Utc::now() + chrono::Duration::from_std(duration).map_err(anyhow::Error::from)?;
+        // bans are stored with millisecond precision
+        let expires = Utc
+            .timestamp_millis_opt(expires.timestamp_millis())
+            .unwrap();
+        self.storage.ban(&peer, Some(expires))?;
+        self.network.ban_until(peer, expires);
+        Ok(())
     }
 
     /// Unbans a previously banned `PeerId`.
-    pub fn unban(&mut self, peer: PeerId) {
-        self.network.unban(peer)
+    pub fn unban(&mut self, peer: PeerId) -> Result<()> {
+        self.storage.unban(&peer)?;
+        self.network.unban(peer);
+        Ok(())
+    }
+
+    /// Returns the banned peers with the expiry of their ban, `None` for
+    /// permanent bans.
+    pub fn banned_peers(&self) -> Vec<(PeerId, Option<DateTime<Utc>>)> {
+        self.network.banned_peers()
+    }
+
+    /// Returns the current connection gate.
+    pub fn connection_gate(&self) -> ConnectionGate {
+        self.network.connection_gate()
+    }
+
+    /// Replaces the rules for accepting connections. The new rules apply to
+    /// connections opened afterwards, use `ban` to drop existing ones.
+    pub fn set_connection_gate(&mut self, gate: ConnectionGate) {
+        self.network.set_connection_gate(gate)
+    }
+
+    /// Returns the limits of serving blocks to peers over bitswap.
+    pub fn serve_rate_limit(&self) -> Option<RateLimit> {
+        self.network.serve_rate_limit()
+    }
+
+    /// Replaces the limits of serving blocks to peers over bitswap, `None`
+    /// removes them. Requests that are already delayed keep their delay.
+    /// Fails with `InvalidNetworkConfig::ZeroRateLimit` if a budget allows
+    /// nothing.
+    pub fn set_serve_rate_limit(&mut self, limit: Option<RateLimit>) -> Result<()> {
+        Ok(self.network.set_serve_rate_limit(limit)?)
+    }
+
+    /// Starts mdns with `config` or stops it if `None`. Discoveries are
+    /// reported as `Event::MdnsDiscovered` and dialed if
+    /// `NetworkConfig::mdns_auto_dial` is set.
+    pub fn set_mdns(&mut self, config: Option<MdnsConfig>) -> impl Future<Output = Result<()>> {
+        self.network.set_mdns(config).err_into()
+    }
+
+    /// Dials the known addresses of `peer` and resolves once a connection is
+    /// established. Fails with `Error::NotConnected` wrapping a
+    /// `ConnectFailed`, carrying the failures of the dial attempts, once all
+    /// of them failed.
+    pub fn connect(&mut self, peer: PeerId) -> impl Future<Output = Result<()>> {
+        self.network.connect(peer).err_into()
+    }
+
+    /// Waits for a connection to `peer` without dialing. Fails with
+    /// `Error::Timeout` wrapping a `ConnectTimeout`, carrying the failures
+    /// recorded meanwhile, after `timeout`.
+    pub fn wait_connected(
+        &self,
+        peer: PeerId,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<()>> {
+        self.network.wait_connected(peer, timeout).err_into()
     }
 
     /// Returns the known peers.
@@ -244,13 +607,32 @@ where
         self.network.peer_info(peer)
     }
 
+    /// Returns the open connections to a peer with their endpoints, the
+    /// identify info of the peer and its ping rtt.
+    pub fn connection_info(&self, peer: &PeerId) -> Vec<ConnectionInfo> {
+        self.network.connection_info(peer)
+    }
+
     /// Bootstraps the dht using a set of bootstrap nodes. After bootstrap
-    /// completes it provides all blocks in the block store.
+    /// completes the blocks selected by `NetworkConfig::reprovide` are
+    /// provided.
     pub fn bootstrap(
         &mut self,
         nodes: Vec<(PeerId, Multiaddr)>,
     ) -> impl Future<Output = Result<()>> {
-        self.network.bootstrap(nodes)
+        self.network.bootstrap(nodes).err_into()
+    }
+
+    /// Bootstraps the dht from addresses like `/dnsaddr/bootstrap.libp2p.io`,
+    /// which don't need to end with `/p2p`. `/dnsaddr` addresses are resolved
+    /// with the dns config of `NetworkConfig::dns` and addresses without a
+    /// peer id are dialed to learn it. Returns the peer id or the error of
+    /// each address, fails only if the bootstrap itself fails.
+    pub fn bootstrap_addrs(
+        &mut self,
+        addrs: Vec<Multiaddr>,
+    ) -> impl Future<Output = Result<Vec<(Multiaddr, anyhow::Result<PeerId>)>>> {
+        self.network.bootstrap_addrs(addrs).err_into()
     }
 
     /// Returns true if the dht was bootstrapped.
@@ -258,38 +640,62 @@ where
         self.network.is_bootstrapped()
     }
 
-    /// Gets the closest peer to a key. Useful for finding the `Multiaddr` of a
-    /// `PeerId`.
-    // pub async fn get_closest_peers<K>(&self, key: K) -> Result<()>
-    // where
-    //     K: Into<BucketKey<K>> + Into<Vec<u8>> + Clone,
-    // {
-    //     self.network.get_closest_peers(key).await?;
-    //     Ok(())
-    // }
+    /// Returns a stream yielding whether the dht is bootstrapped and then
+    /// every change, with the semantics of a `variable::Observer`.
+    pub fn watch_bootstrapped(&self) -> impl Stream<Item = bool> {
+        self.network.watch_bootstrapped()
+    }
+
+    /// Gets the closest peers to a `PeerId` from the dht together with their
+    /// known addresses. Useful for finding the `Multiaddr` of a `PeerId`.
+    ///
+    /// If the query times out the peers found so far are returned, an error is
+    /// only returned if no peers were found at all.
+    pub fn get_closest_peers(
+        &mut self,
+        peer: PeerId,
+    ) -> impl Future<Output = Result<Vec<(PeerId, Vec<Multiaddr>)>>> {
+        self.network.get_closest_peers(peer).err_into()
+    }
 
     /// Gets providers of a key from the dht.
     pub fn providers(&mut self, key: Key) -> impl Future<Output = Result<HashSet<PeerId>>> {
-        self.network.providers(key)
+        self.network.providers(key).err_into()
+    }
+
+    /// Gets providers of a key from the dht as a `Stream`, yielding each
+    /// provider as soon as it is discovered. The local peer is never yielded
+    /// and every provider is only yielded once. The stream ends when the query
+    /// completes; dropping it early aborts the query.
+    pub fn providers_stream(&mut self, key: Key) -> impl Stream<Item = PeerId> {
+        self.network.providers_stream(key)
     }
 
     /// Provides a key in the dht.
     pub fn provide(&mut self, key: Key) -> impl Future<Output = Result<()>> {
-        self.network.provide(key)
+        self.network.provide(key).err_into()
+    }
+
+    /// Announces the blocks selected by `NetworkConfig::reprovide` on the
+    /// dht right away and returns the number of successful announcements.
+    /// Does nothing if the policy is `ReprovidePolicy::None`.
+    pub async fn reprovide_now(&self) -> Result<usize> {
+        Ok(reprovider::reprovide(self.reprovider.policy(), &self.storage, &self.network).await?)
     }
 
     /// Stops providing a key in the dht.
     pub fn unprovide(&mut self, key: Key) -> Result<()> {
-        self.network.unprovide(key)
+        Ok(self.network.unprovide(key)?)
     }
 
-    /// Gets a record from the dht.
+    /// Gets a record from the dht. Fails with `Error::RecordNotFound` if no
+    /// peer has it.
     pub fn get_record(
         &mut self,
         key: Key,
         quorum: Quorum,
     ) -> impl Future<Output = Result<Vec<PeerRecord>>> {
-        self.network.get_record(key, quorum)
+        self.network.get_record(key, quorum).err_into()
     }
 
     /// Puts a new record in the dht.
@@ -298,12 +704,112 @@ where
         record: Record,
         quorum: Quorum,
     ) -> impl Future<Output = Result<()>> {
-        self.network.put_record(record, quorum)
+        self.network
+            .put_record(record, quorum, PutOptions::default())
+            .err_into()
+    }
+
+    /// Puts a new record in the dht with a time to live on remote nodes,
+    /// optionally republishing it until it is removed or expires.
+    pub fn put_record_with(
+        &mut self,
+        record: Record,
+        quorum: Quorum,
+        options: PutOptions,
+    ) -> impl Future<Output = Result<()>> {
+        self.network.put_record(record, quorum, options).err_into()
+    }
+
+    /// Returns the records that are republished by this node.
+    pub fn published_records(&self) -> Vec<PublishedRecord> {
+        self.network.published_records()
     }
 
-    /// Removes a record from the dht.
+    /// Removes a record from the local dht store and stops republishing it.
     pub fn remove_record(&mut self, key: Key) -> Result<()> {
-        self.network.remove_record(key)
+        Ok(self.network.remove_record(key)?)
+    }
+
+    /// Publishes a signed IPNS record pointing to `cid` under the peer id of
+    /// this node, valid for `lifetime`. The record is republished in the dht
+    /// until it expires or the next record is published.
+    pub async fn name_publish(&self, cid: &Cid, lifetime: Duration) -> Result<()> {
+        let peer = self.local_peer_id();
+        let key = ipns::record_key(&peer);
+        let now = Utc::now();
+        let published = self
+            .published_records()
+            .into_iter()
+            .find(|published| published.record.key == key)
+            .and_then(|published| ipns::validate_record(&peer, &published.record.value, now).ok())
+            .map(|record| record.sequence);
+        let sequence = {
+            let mut sequences = self.ipns_sequences.lock();
+            let sequence = match sequences.get(&peer).copied().max(published) {
+                Some(sequence) => sequence + 1,
+                None => 0,
+            };
+            sequences.insert(peer, sequence);
+            sequence
+        };
+        let value = ipns::create_record(
+            cid,
+            sequence,
+            now + chrono::Duration::from_std(lifetime).map_err(anyhow::Error::from)?,
+            lifetime.min(ipns::DEFAULT_IPNS_TTL),
+            &self.local_public_key(),
+            |msg| self.network.sign(msg),
+        )?;
+        let mut record = Record::new(key, value);
+        record.expires = Some(std::time::Instant::now() + lifetime);
+        let options = PutOptions {
+            ttl: Some(lifetime.min(net::DEFAULT_RECORD_TTL)),
+            republish: true,
+        };
+        Ok(self
+            .network
+            .clone()
+            .put_record(record, Quorum::One, options)
+            .await?)
+    }
+
+    /// Resolves the IPNS record of `peer` in the dht and returns the cid of
+    /// the newest valid record. Fails with `StaleIpnsRecord` if a newer record
+    /// was published or resolved before.
+    pub async fn name_resolve(&self, peer: &PeerId) -> Result<Cid> {
+        let records = self
+            .network
+            .clone()
+            .get_record(ipns::record_key(peer), Quorum::One)
+            .await?;
+        let values = records.iter().map(|record| &record.record.value[..]);
+        let record = ipns::select_record(peer, values, Utc::now())
+            .ok_or_else(|| anyhow::Error::from(IpnsRecordNotFound(*peer)))?;
+        let mut sequences = self.ipns_sequences.lock();
+        let latest = sequences.entry(*peer).or_insert(record.sequence);
+        if *latest > record.sequence {
+            return Err(Error::Other(
+                StaleIpnsRecord {
+                    peer: *peer,
+                    sequence: record.sequence,
+                    latest: *latest,
+                }
+                .into(),
+            ));
+        }
+        *latest = record.sequence;
+        Ok(record.cid()?)
+    }
+
+    /// Resolves the dnslink of `domain` using the resolver configured with
+    /// `NetworkConfig::dns`. One level of `/ipns/` indirection is followed,
+    /// either to the IPNS record of a peer or to the dnslink of another
+    /// domain.
+    pub async fn resolve_dnslink(&self, domain: &str) -> Result<Cid> {
+        Ok(dnslink::resolve(&*self.dns, domain, |peer| async move {
+            self.name_resolve(&peer).await.map_err(anyhow::Error::from)
+        })
+        .await?)
     }
 
     /// Subscribes to a `topic` returning a `Stream` of messages. If all
@@ -312,40 +818,186 @@ where
         &mut self,
         topic: String,
     ) -> impl Future<Output = Result<impl Stream<Item = GossipEvent>>> {
-        self.network.subscribe(topic)
+        self.network.subscribe(topic, None).err_into()
+    }
+
+    /// Subscribes to a gossipsub `topic` like `subscribe`, but only delivers
+    /// and forwards messages accepted by the `validator`. The validator is
+    /// called with the author of the message and its data. Rejected messages
+    /// penalize the peer that forwarded them if peer scoring is enabled.
+    ///
+    /// A topic has at most one validator, subscribing again with a validator
+    /// replaces it. Messages received via `broadcast` are not validated.
+    pub fn subscribe_with_validator(
+        &mut self,
+        topic: String,
+        validator: impl Fn(&PeerId, &[u8]) -> ValidationResult + Send + Sync + 'static,
+    ) -> impl Future<Output = Result<impl Stream<Item = GossipEvent>>> {
+        let validator = MessageValidator(Arc::new(validator));
+        self.network.subscribe(topic, Some(validator)).err_into()
+    }
+
+    /// Returns the peers known to be subscribed to a gossipsub `topic`.
+    pub fn topic_peers(&self, topic: &str) -> Vec<PeerId> {
+        self.network.topic_peers(topic)
+    }
+
+    /// Returns the gossipsub score of a `peer`, if peer scoring is enabled in
+    /// `NetworkConfig::gossipsub_scoring`. The scores are sampled once a
+    /// second.
+    pub fn peer_score(&self, peer: &PeerId) -> Option<f64> {
+        self.network.peer_score(peer)
+    }
+
+    /// Returns the topics this node is subscribed to.
+    pub fn subscribed_topics(&self) -> Vec<String> {
+        self.network.subscribed_topics()
     }
 
     /// Publishes a new message in a `topic`, sending the message to all
     /// subscribed peers.
-    pub fn publish(&mut self, topic: String, msg: Vec<u8>) -> impl Future<Output = Result<()>> {
-        self.network.publish(topic, msg)
+    ///
+    /// Resolves with the id of the published message, which is also the id
+    /// reported in the `GossipEvent::Message` of receiving peers. Fails if no
+    /// peer is subscribed to the `topic`.
+    pub fn publish(
+        &mut self,
+        topic: String,
+        msg: Vec<u8>,
+    ) -> impl Future<Output = Result<MessageId>> {
+        self.network.publish(topic, msg).err_into()
     }
 
     /// Publishes a new message in a `topic`, sending the message to all
     /// subscribed connected peers.
     pub fn broadcast(&mut self, topic: String, msg: Vec<u8>) -> impl Future<Output = Result<()>> {
-        self.network.broadcast(topic, msg)
+        self.network.broadcast(topic, msg).err_into()
+    }
+
+    /// Sends a request of an application `protocol` to `peer`, dialing it if
+    /// needed, and resolves with the response.
+    ///
+    /// Needs the request response protocol, which is enabled by
+    /// `NetworkConfig::register_protocol`. Fails with `RequestTimeout` after
+    /// `RequestResponseConfig::request_timeout` and with
+    /// `UnsupportedProtocol` if the peer doesn't answer the `protocol`.
+    pub fn request(
+        &mut self,
+        peer: PeerId,
+        protocol: &str,
+        data: Vec<u8>,
+    ) -> impl Future<Output = Result<Vec<u8>>> {
+        self.network
+            .request(peer, protocol.into(), data, None)
+            .err_into()
+    }
+
+    /// Like `request`, but fails with `RequestTimeout` after `timeout` if
+    /// that is shorter than the configured timeout.
+    pub fn request_with_timeout(
+        &mut self,
+        peer: PeerId,
+        protocol: &str,
+        data: Vec<u8>,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Vec<u8>>> {
+        self.network
+            .request(peer, protocol.into(), data, Some(timeout))
+            .err_into()
+    }
+
+    /// Returns a stream of the requests of a `protocol` registered with
+    /// `NetworkConfig::register_protocol`.
+    ///
+    /// Only the latest stream of a protocol receives requests, requests
+    /// arriving while there is none are answered as unsupported. The stream
+    /// of an unregistered protocol ends right away.
+    pub fn requests(&mut self, protocol: &str) -> impl Stream<Item = InboundRequest> {
+        self.network.requests(protocol.into())
     }
 
     /// Creates a temporary pin in the block store. A temporary pin is not
     /// persisted to disk and is released once it is dropped.
     pub fn create_temp_pin(&self) -> Result<TempPin> {
-        self.storage.create_temp_pin()
+        Ok(self.storage.create_temp_pin()?)
     }
 
     /// Adds a new root to a temporary pin.
     pub fn temp_pin(&self, tmp: &mut TempPin, cid: &Cid) -> Result<()> {
-        self.storage.temp_pin(tmp, std::iter::once(*cid))
+        Ok(self.storage.temp_pin(tmp, std::iter::once(*cid))?)
+    }
+
+    /// Creates a named lease, or resumes an existing one. Leases keep their
+    /// roots from being garbage collected like a `TempPin`, but are persisted
+    /// in the store and expire after `ttl` unless renewed.
+    pub fn create_lease(&self, name: &[u8], ttl: Duration) -> Result<Lease<P>> {
+        Ok(self.storage.create_lease(name, ttl)?)
+    }
+
+    /// Lists the active leases with their expiry.
+    pub fn leases(&self) -> Result<Vec<(Vec<u8>, DateTime<Utc>)>> {
+        Ok(self.storage.leases()?)
     }
 
     /// Returns an `Iterator` of `Cid`s stored in the block store.
     pub fn iter(&self) -> Result<impl Iterator<Item = Cid>> {
-        self.storage.iter()
+        Ok(self.storage.iter()?)
+    }
+
+    /// Like `iter`, but reads the store on the blocking thread pool of the
+    /// executor instead of the calling task.
+    pub fn iter_async(&self) -> impl Future<Output = Result<Vec<Cid>>> {
+        let iter = self
+            .storage
+            .spawn_blocking(|storage| Ok(storage.iter()?.collect()));
+        async move { Ok(iter.await?) }
+    }
+
+    /// Returns the `Cid`s of the blocks matching `filter`, for example the
+    /// dag-cbor blocks inserted since some time. The filter is evaluated by
+    /// the store, so only the matching `Cid`s are returned.
+    pub fn iter_filtered(&self, filter: BlockFilter) -> Result<Vec<Cid>> {
+        Ok(self.storage.iter_filtered(&filter)?)
+    }
+
+    /// Iterates over the blocks in the store together with their size, pin
+    /// state and insertion time. The metadata is read in a single pass over
+    /// the store.
+    pub fn iter_with_metadata(&self) -> Result<impl Iterator<Item = anyhow::Result<BlockInfo>>> {
+        Ok(self.storage.iter_with_metadata()?)
     }
 
     /// Checks if the block is in the block store.
     pub fn contains(&self, cid: &Cid) -> Result<bool> {
-        self.storage.contains(cid)
+        Ok(self.storage.contains(cid)?)
+    }
+
+    /// Like `contains`, but runs on the blocking thread pool of the executor.
+    pub fn contains_async(&self, cid: &Cid) -> impl Future<Output = Result<bool>> {
+        let cid = *cid;
+        let contains = self
+            .storage
+            .spawn_blocking(move |storage| storage.contains(&cid));
+        async move { Ok(contains.await?) }
+    }
+
+    /// Returns the direct references of a stored block in the order they are
+    /// encountered.
+    pub fn refs(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        self.storage.refs(cid)?.ok_or(Error::BlockNotFound(*cid))
+    }
+
+    /// Walks the dag rooted at `cid` breadth first, returning all deduplicated
+    /// references up to `max_depth` levels below `cid`. Blocks missing from the
+    /// store are reported instead of aborting the walk.
+    pub fn refs_recursive(&self, cid: &Cid, max_depth: Option<usize>) -> Result<Refs> {
+        Ok(self.storage.refs_recursive(cid, max_depth)?)
+    }
+
+    /// Counts the blocks and bytes of the dag rooted at `cid` in the local
+    /// store and lists the blocks missing from it.
+    pub fn dag_stat(&self, cid: &Cid) -> Result<DagStat> {
+        Ok(self.storage.dag_stat(cid)?)
     }
 
     /// Returns a block from the block store.
@@ -354,150 +1006,935 @@ where
             let block = Block::new_unchecked(*cid, data);
             Ok(block)
         } else {
-            Err(BlockNotFound(*cid).into())
+            Err(Error::BlockNotFound(*cid))
+        }
+    }
+
+    /// Like `get`, but reads the block on the blocking thread pool of the
+    /// executor instead of the calling task.
+    pub fn get_async(&self, cid: &Cid) -> impl Future<Output = Result<Block<P>>> {
+        let cid = *cid;
+        let get = self
+            .storage
+            .spawn_blocking(move |storage| storage.get(&cid));
+        async move {
+            match get.await? {
+                Some(data) => Ok(Block::new_unchecked(cid, data)),
+                None => Err(Error::BlockNotFound(cid)),
+            }
         }
     }
 
+    /// Returns the blocks in the order of `cids` using a single store
+    /// transaction. Blocks that aren't in the store are returned as `None`.
+    pub fn get_many(&self, cids: &[Cid]) -> Result<Vec<Option<Block<P>>>> {
+        Ok(self
+            .storage
+            .get_many(cids)?
+            .into_iter()
+            .zip(cids)
+            .map(|(data, cid)| data.map(|data| Block::new_unchecked(*cid, data)))
+            .collect())
+    }
+
+    /// Checks which of `cids` are in the store using a single store
+    /// transaction.
+    pub fn contains_many(&self, cids: &[Cid]) -> Result<Vec<bool>> {
+        Ok(self.storage.contains_many(cids)?)
+    }
+
     /// Either returns a block if it's in the block store or tries to retrieve
-    /// it from a peer.
+    /// it from a peer. If no providers are given and `fetch_via_dht` is
+    /// configured, providers are discovered in the dht.
     pub async fn fetch(&self, cid: &Cid, providers: Vec<PeerId>) -> Result<Block<P>> {
+        let span = tracing::debug_span!("fetch", cid = %cid);
+        async move {
+            if let Some(data) = self.storage.get(cid)? {
+                let block = Block::new_unchecked(*cid, data);
+                return Ok(block);
+            }
+            if providers.is_empty() {
+                if let Some(config) = self.network.fetch_via_dht() {
+                    return self
+                        .fetch_from_dht(cid, config.max_providers, config.timeout)
+                        .await;
+                }
+            }
+            self.fetch_from(cid, providers).await
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Looks up providers of a block in the dht and retrieves it from at most
+    /// `max_providers` of them. Each provider is asked as soon as it is
+    /// found, the first one sending the block wins. Returns a `NoProviders`
+    /// error if the dht has no providers of the block and a `Timeout` error
+    /// if the block wasn't retrieved within `timeout`.
+    pub async fn fetch_from_dht(
+        &self,
+        cid: &Cid,
+        max_providers: usize,
+        timeout: Duration,
+    ) -> Result<Block<P>> {
         if let Some(data) = self.storage.get(cid)? {
             let block = Block::new_unchecked(*cid, data);
             return Ok(block);
         }
-        if !providers.is_empty() {
-            self.network.get(*cid, providers).await?.await?;
-            if let Some(data) = self.storage.get(cid)? {
-                let block = Block::new_unchecked(*cid, data);
-                return Ok(block);
+        let mut network = self.network.clone();
+        let fetch = async move {
+            // keeps the garbage collector from evicting the block before it
+            // is read back
+            let _pin = self.pin_root(cid)?;
+            let mut inserted = Some(self.storage.store_events());
+            let mut providers = network
+                .providers_stream(Key::new(&cid.to_bytes()))
+                .take(max_providers)
+                .fuse();
+            let mut gets = FuturesUnordered::new();
+            let mut found = false;
+            loop {
+                let peer = if gets.is_empty() {
+                    match providers.next().await {
+                        Some(peer) => peer,
+                        None => break,
+                    }
+                } else {
+                    match future::select(providers.next(), gets.next()).await {
+                        Either::Left((Some(peer), _)) => peer,
+                        Either::Left((None, _)) => break,
+                        Either::Right((Some(Ok(())), _)) => {
+                            return self.read_fetched(cid, inserted.take().unwrap()).await
+                        }
+                        // the provider didn't have the block
+                        Either::Right(_) => continue,
+                    }
+                };
+                found = true;
+                let (network, cid) = (self.network.clone(), *cid);
+                gets.push(async move { network.get(cid, vec![peer]).await?.await });
+            }
+            while let Some(result) = gets.next().await {
+                if result.is_ok() {
+                    return self.read_fetched(cid, inserted.take().unwrap()).await;
+                }
             }
-            tracing::error!("block evicted too soon. use a temp pin to keep the block around.");
+            if found {
+                Err(Error::BlockNotFound(*cid))
+            } else {
+                Err(Error::NoProviders(*cid))
+            }
+        };
+        futures::pin_mut!(fetch);
+        match future::select(fetch, futures_timer::Delay::new(timeout)).await {
+            Either::Left((res, _)) => res,
+            Either::Right(((), _)) => Err(Error::Timeout(FetchTimeout(*cid).into())),
         }
-        Err(BlockNotFound(*cid).into())
     }
 
-    /// Inserts a block in to the block store.
-    pub fn insert(&self, block: Block<P>) -> Result<()> {
-        self.storage.insert(block)?;
-        Ok(())
+    async fn fetch_from(&self, cid: &Cid, providers: Vec<PeerId>) -> Result<Block<P>> {
+        if providers.is_empty() {
+            return Err(Error::BlockNotFound(*cid));
+        }
+        // keeps the garbage collector from evicting the block before it is
+        // read back
+        let _pin = self.pin_root(cid)?;
+        let inserted = self.storage.store_events();
+        self.network.get(*cid, providers).await?.await?;
+        self.read_fetched(cid, inserted).await
     }
 
-    /// Manually runs garbage collection to completion. This is mainly useful
-    /// for testing and administrative interfaces. During normal operation,
-    /// the garbage collector automatically runs in the background.
-    pub fn evict(&self) -> impl Future<Output = Result<()>> {
-        self.storage.evict()
+    /// Reads a block that was just fetched from the store. Bitswap reports a
+    /// block as received before its database thread inserted it, so the
+    /// insertion is awaited on `inserted`, which has to be subscribed before
+    /// the block is requested.
+    async fn read_fetched(&self, cid: &Cid, mut inserted: StoreEvents) -> Result<Block<P>> {
+        if let Some(data) = self.storage.get(cid)? {
+            return Ok(Block::new_unchecked(*cid, data));
+        }
+        let insert = async {
+            while let Some(event) = inserted.next().await {
+                if matches!(event, StoreEvent::Inserted(inserted, _) if inserted == *cid) {
+                    break;
+                }
+            }
+        };
+        futures::pin_mut!(insert);
+        // the event is dropped if the subscriber lags behind, the insertion
+        // is queued right behind the response so it doesn't take long
+        future::select(insert, futures_timer::Delay::new(Duration::from_secs(5))).await;
+        if let Some(data) = self.storage.get(cid)? {
+            return Ok(Block::new_unchecked(*cid, data));
+        }
+        tracing::error!("fetched block {} is missing from the store", cid);
+        Err(Error::BlockNotFound(*cid))
     }
 
-    pub fn sync(
+    /// Creates a temp pin protecting the dag rooted at `cid`, including
+    /// blocks inserted later.
+    fn pin_root(&self, cid: &Cid) -> Result<TempPin> {
+        let mut pin = self.storage.create_temp_pin()?;
+        self.storage.temp_pin(&mut pin, std::iter::once(*cid))?;
+        Ok(pin)
+    }
+
+    /// Like `fetch` but gives up after `timeout`, returning an
+    /// `Error::Timeout` wrapping a `FetchTimeout`. On timeout the want is
+    /// removed from bitswap.
+    pub async fn fetch_with_timeout(
         &self,
         cid: &Cid,
         providers: Vec<PeerId>,
-    ) -> impl Future<Output = anyhow::Result<SyncQuery>> {
-        let missing = self.storage.missing_blocks(cid).ok().unwrap_or_default();
-        tracing::trace!(cid = %cid, missing = %missing.len(), "sync");
-        self.network.sync(*cid, providers, missing)
-    }
-
-    /// Creates, updates or removes an alias with a new root `Cid`.
-    pub fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
-        self.storage.alias(alias.as_ref(), cid)
+        timeout: Duration,
+    ) -> Result<Block<P>> {
+        let fetch = self.fetch(cid, providers);
+        futures::pin_mut!(fetch);
+        // dropping the fetch future drops the `GetQuery`, which cancels the want
+        match future::select(fetch, futures_timer::Delay::new(timeout)).await {
+            Either::Left((res, _)) => res,
+            Either::Right(((), _)) => Err(Error::Timeout(FetchTimeout(*cid).into())),
+        }
     }
 
-    /// List all known aliases.
-    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
-        self.storage.aliases()
+    /// Inserts a block in to the block store.
+    pub fn insert(&self, block: Block<P>) -> Result<()> {
+        self.storage.insert(block)?;
+        Ok(())
     }
 
-    /// Returns the root of an alias.
-    pub fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
-        self.storage.resolve(alias.as_ref())
+    /// Like `insert`, but writes the block on the blocking thread pool of the
+    /// executor instead of the calling task.
+    pub fn insert_async(&self, block: Block<P>) -> impl Future<Output = Result<()>> {
+        let insert = self
+            .storage
+            .spawn_blocking(move |storage| storage.insert(block));
+        async move { Ok(insert.await?) }
     }
 
-    /// Returns a list of aliases preventing a `Cid` from being garbage
-    /// collected.
-    pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
-        self.storage.reverse_alias(cid)
+    /// Returns a writer storing content of any size as chunks of the maximum
+    /// block size with the multicodec `codec`, linked by dag-cbor index
+    /// nodes. All blocks are hashed with `hasher`. The blocks are temp pinned
+    /// until the writer is dropped, dropping it without `finish` aborts the
+    /// write.
+    pub fn insert_stream(&self, codec: u64, hasher: P::Hashes) -> Result<BlockWriter<P>> {
+        Ok(BlockWriter::new(self.storage.clone(), codec, hasher)?)
     }
 
-    /// Flushes the block store. After `flush` completes successfully it is
-    /// guaranteed that all writes have been persisted to disk.
-    pub fn flush(&self) -> impl Future<Output = Result<()>> {
-        self.storage.flush()
+    /// Reads the content written with `insert_stream` chunk by chunk. Blocks
+    /// are read from the store as the iteration reaches them, missing blocks
+    /// end it with an error.
+    pub fn read_stream(&self, root: &Cid) -> Result<BlockReader<P>> {
+        Ok(BlockReader::new(self.storage.clone(), root)?)
     }
 
-    /// Perform a set of storage operations in a batch
-    ///
-    /// The batching concerns only the CacheTracker, it implies no atomicity
-    /// guarantees!
-    pub fn batch_ops<R>(&self, f: impl FnOnce(&mut Batch<'_, P>) -> Result<R>) -> Result<R> {
-        self.storage.rw("batch_ops", f)
+    /// Manually runs garbage collection to completion. This is mainly useful
+    /// for testing and administrative interfaces. During normal operation,
+    /// the garbage collector automatically runs in the background. Fails
+    /// with `GcDisabled` if the store was opened with `GcMode::Disabled`.
+    pub fn evict(&self) -> impl Future<Output = Result<GcStats>> {
+        self.storage.evict().err_into()
     }
 
-    /// Registers prometheus metrics in a registry.
-    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
-        self.storage.register_metrics(registry)?;
-        net::register_metrics(registry)?;
-        Ok(())
+    /// Compacts the block store, reclaiming the disk space left behind by
+    /// evicted blocks. Reads keep working while compacting an on disk store,
+    /// writes wait until it finishes. See also
+    /// `StorageConfig::auto_compact_threshold`.
+    pub fn compact(&self) -> impl Future<Output = Result<CompactStats>> {
+        self.storage.compact().err_into()
     }
 
-    /// Subscribes to the swarm event stream.
-    pub fn swarm_events(&mut self) -> impl Future<Output = Result<SwarmEvents>> {
-        self.network.swarm_events()
+    /// Writes a consistent snapshot of the block store including aliases
+    /// and pins to `dest`, which must not exist yet. Writes continue while
+    /// backing up an on disk store. The backup is a sqlite store that is
+    /// opened by pointing `StorageConfig::path` to it. Temp pins aren't
+    /// included.
+    pub fn backup(&self, dest: &Path) -> impl Future<Output = Result<()>> {
+        self.storage.backup(dest).err_into()
     }
-}
-
-#[async_trait]
-impl<P: StoreParams> Store for Ipfs<P>
-where
-    Ipld: References<P::Codecs>,
-{
-    type Params = P;
-    type TempPin = Arc<Mutex<TempPin>>;
-    
 
-    fn create_temp_pin(&self) -> Result<Self::TempPin> {
-        Ok(Arc::new(Mutex::new(Ipfs::create_temp_pin(self)?)))
+    /// Copies the blocks of another store at `path` that are missing from
+    /// this one, for example to migrate an old repository. Its aliases are
+    /// merged using the given conflict policy, or skipped if `aliases` is
+    /// `None`. The other store is opened read only and copied in batches.
+    pub fn import_store(&self, path: &Path, aliases: Option<AliasConflict>) -> Result<ImportStats> {
+        Ok(self.storage.import_store(path, aliases)?)
     }
 
-    fn temp_pin(&self, tmp: &Self::TempPin, cid: &Cid) -> Result<()> {
-        Ipfs::temp_pin(self, &mut tmp.lock(), cid)
+    /// Changes the interval of the background garbage collector. The new
+    /// interval takes effect immediately, if it already elapsed since the last
+    /// sweep a sweep is started right away.
+    pub fn set_sweep_interval(&self, interval: Duration) {
+        self.storage.set_sweep_interval(interval)
     }
 
-    fn contains(&self, cid: &Cid) -> Result<bool> {
-        Ipfs::contains(self, cid)
+    /// Changes the number of blocks and bytes the background garbage
+    /// collector evicts down to, `None` removes a limit. If the store exceeds
+    /// the new size a sweep is started right away. The targets replace
+    /// `StorageConfig::cache_size_blocks` and `StorageConfig::cache_size_bytes`
+    /// until the store is opened again.
+    pub fn set_cache_size(&self, blocks: Option<u64>, bytes: Option<u64>) -> Result<()> {
+        Ok(self.storage.set_cache_size(blocks, bytes)?)
     }
 
-    fn get(&self, cid: &Cid) -> Result<Block<P>> {
-        Ipfs::get(self, cid)
+    /// Protects an unpinned block from the garbage collector without
+    /// pinning it. See `GcPolicy::exclude`.
+    pub fn gc_exclude(&self, cid: &Cid) {
+        self.storage.gc_exclude(cid)
     }
 
-    fn insert(&self, block: &Block<P>) -> Result<()> {
-        Ipfs::insert(self, block.clone())?;
-        Ok(())
+    /// Lets the garbage collector evict a block excluded with `gc_exclude`
+    /// again. Returns `false` if it wasn't excluded.
+    pub fn gc_include(&self, cid: &Cid) -> bool {
+        self.storage.gc_include(cid)
     }
 
-    fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
-        Ipfs::alias(self, alias, cid)
+    /// Verifies the integrity of the store in the background, reporting
+    /// corrupt blocks and aliases pointing to missing blocks. Dropping the
+    /// stream stops the verification.
+    pub fn verify(&self, config: VerifyConfig) -> impl Stream<Item = VerifyEvent> {
+        self.storage.verify(config)
     }
 
-    fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
-        Ipfs::resolve(self, alias)
+    /// Subscribes to garbage collector events of both the background and the
+    /// manually triggered sweeps.
+    pub fn gc_events(&self) -> impl Stream<Item = GcEvent> {
+        self.storage.gc_events()
     }
 
-    fn reverse_alias(&self, cid: &Cid) -> Result<Option<Vec<Vec<u8>>>> {
-        Ipfs::reverse_alias(self, cid).map(|x| x.map(|x| x.into_iter().collect()))
+    /// Subscribes to the blocks inserted in and evicted from the local store
+    /// and the changed aliases, see `StorageService::store_events`. Blocks
+    /// inserted by `batch_ops` are reported once the batch is committed.
+    pub fn store_events(&self) -> StoreEvents {
+        self.storage.store_events()
     }
 
-    async fn flush(&self) -> Result<()> {
-        Ipfs::flush(self).await
+    /// Returns the status of the background tasks.
+    pub fn health(&self) -> NodeHealth {
+        NodeHealth {
+            swarm: self.network.swarm_status(),
+            gc: self.storage.gc_status(),
+        }
     }
 
-    async fn fetch(&self, cid: &Cid) -> Result<Block<Self::Params>> {
-        Ipfs::fetch(self, cid, self.peers()).await
+    /// Subscribes to the exits and panics of the background tasks. A node
+    /// whose swarm task failed rejects all network commands.
+    pub fn task_events(&self) -> impl Stream<Item = TaskEvent> {
+        futures::stream::select(
+            self.network.swarm_monitor().subscribe(),
+            self.storage.gc_monitor().subscribe(),
+        )
     }
 
-    async fn sync(&self, cid: &Cid) -> Result<()> {
+    /// Syncs the dag rooted at `cid` from `providers`. If `sync_via_dht` is
+    /// configured, providers of blocks the `providers` don't have are looked
+    /// up in the dht and reported as `SyncEvent::ProvidersAdded`. With
+    /// `sync_pipeline` or `sync_scheduler` the blocks are requested by
+    /// ipfs-embed instead of the bitswap sync query. The dag is protected
+    /// from the garbage collector until the query is dropped.
+    pub fn sync(
+        &self,
+        cid: &Cid,
+        providers: Vec<PeerId>,
+    ) -> impl Future<Output = Result<SyncQuery>> {
+        let pin = match self.pin_root(cid) {
+            Ok(pin) => pin,
+            Err(err) => return future::ready(Err(err)).left_future(),
+        };
+        let (tx, rx) = mpsc::unbounded();
+        let sync = match (self.network.sync_pipeline(), self.network.sync_via_dht()) {
+            (Some(pipeline), dht) => self
+                .clone()
+                .sync_pipelined(*cid, providers, pipeline, dht, tx)
+                .boxed(),
+            (None, Some(dht)) => self.clone().sync_via_dht(*cid, providers, dht, tx).boxed(),
+            (None, None) => {
+                let missing = self.storage.missing_blocks(cid).ok().unwrap_or_default();
+                tracing::trace!(cid = %cid, missing = %missing.len(), "sync");
+                return self
+                    .network
+                    .sync(*cid, providers, missing)
+                    .map(|query| Ok::<_, Error>(query?.with_temp_pin(pin)))
+                    .right_future();
+            }
+        };
+        let (stop_tx, stop_rx) = oneshot::channel();
+        // the queries of the task are part of the trace of the call
+        let span = tracing::debug_span!("sync", cid = %cid);
+        self.executor
+            .spawn(
+                async move {
+                    future::select(sync, stop_rx).await;
+                }
+                .instrument(span),
+            )
+            .detach();
+        let query = SyncQuery::from_task(rx, stop_tx).with_temp_pin(pin);
+        future::ready(Ok(query)).left_future()
+    }
+
+    /// Runs sync queries until the dag is complete. Whenever a query fails,
+    /// providers of the first missing block are looked up in the dht and a
+    /// new query is started with them added.
+    async fn sync_via_dht(
+        self,
+        cid: Cid,
+        mut providers: Vec<PeerId>,
+        config: DhtSyncConfig,
+        tx: mpsc::UnboundedSender<SyncEvent>,
+    ) {
+        let mut lookups = 0;
+        let result = loop {
+            let missing = match self.storage.missing_blocks(&cid) {
+                Ok(missing) => missing,
+                Err(err) => break Err(err),
+            };
+            tracing::trace!(cid = %cid, missing = %missing.len(), "sync");
+            let mut query = match self.network.sync(cid, providers.clone(), missing).await {
+                Ok(query) => query,
+                Err(err) => break Err(err),
+            };
+            let mut result = Err(anyhow::anyhow!("sync query dropped"));
+            while let Some(event) = query.next().await {
+                match event {
+                    SyncEvent::Complete(res) => {
+                        result = res;
+                        break;
+                    }
+                    event => {
+                        tx.unbounded_send(event).ok();
+                    }
+                }
+            }
+            let err = match result {
+                Ok(()) => break Ok(()),
+                Err(err) => err,
+            };
+            let block = match self.storage.missing_blocks(&cid) {
+                Ok(missing) if !missing.is_empty() => missing[0],
+                Ok(_) => break Ok(()),
+                Err(err) => break Err(err),
+            };
+            if lookups >= config.max_lookups {
+                break Err(err);
+            }
+            lookups += 1;
+            let found = self.discover_providers(&block, &providers, config).await;
+            if found.is_empty() {
+                break Err(err);
+            }
+            providers.extend(found.iter().copied());
+            tx.unbounded_send(SyncEvent::ProvidersAdded {
+                cid: block,
+                providers: found,
+            })
+            .ok();
+        };
+        tx.unbounded_send(SyncEvent::Complete(result)).ok();
+    }
+
+    async fn sync_pipelined(
+        self,
+        cid: Cid,
+        mut providers: Vec<PeerId>,
+        config: SyncPipelineConfig,
+        dht: Option<DhtSyncConfig>,
+        tx: mpsc::UnboundedSender<SyncEvent>,
+    ) {
+        let result = self
+            .run_pipeline(cid, &mut providers, config, dht, &tx)
+            .await;
+        tx.unbounded_send(SyncEvent::Complete(result)).ok();
+    }
+
+    /// Requests the missing blocks of the dag rooted at `cid` keeping up to
+    /// `max_in_flight` requests in flight. The missing children of a block
+    /// are scheduled as soon as it arrives. With `dht`, providers of blocks
+    /// the `providers` don't have are looked up like in `sync_via_dht`. With
+    /// the sync scheduler, each request also waits for its turn.
+    async fn run_pipeline(
+        &self,
+        cid: Cid,
+        providers: &mut Vec<PeerId>,
+        config: SyncPipelineConfig,
+        dht: Option<DhtSyncConfig>,
+        tx: &mpsc::UnboundedSender<SyncEvent>,
+    ) -> anyhow::Result<()> {
+        let mut queue: VecDeque<Cid> = self.storage.missing_blocks(&cid)?.into();
+        let mut requested = HashSet::new();
+        let ticket = self.network.sync_ticket();
+        let mut in_flight = FuturesUnordered::new();
+        let (mut blocks, mut bytes, mut lookups) = (0, 0, 0);
+        tracing::trace!(cid = %cid, missing = %queue.len(), "sync");
+        loop {
+            while in_flight.len() < config.max_in_flight {
+                let next = match config.traversal {
+                    SyncTraversal::DepthFirst => queue.pop_back(),
+                    SyncTraversal::BreadthFirst => queue.pop_front(),
+                };
+                let block = match next {
+                    Some(block) => block,
+                    None => break,
+                };
+                if requested.insert(block) {
+                    let (network, providers) = (self.network.clone(), providers.clone());
+                    let ticket = ticket.as_ref();
+                    let inserted = self.storage.store_events();
+                    in_flight.push(async move {
+                        let permit = match ticket {
+                            Some(ticket) => Some(ticket.acquire(providers.clone()).await),
+                            None => None,
+                        };
+                        let result = async { network.get(block, providers).await?.await }.await;
+                        if let Some(permit) = permit {
+                            permit.complete(result.is_ok());
+                        }
+                        // the children are looked up in the store, so the block has to be
+                        // inserted first
+                        let result = match result {
+                            Ok(()) => self
+                                .read_fetched(&block, inserted)
+                                .await
+                                .map_err(Into::into),
+                            Err(err) => Err(err),
+                        };
+                        (block, result)
+                    });
+                }
+            }
+            let (block, result) = match in_flight.next().await {
+                Some(next) => next,
+                None => return Ok(()),
+            };
+            let fetched = match result {
+                Ok(fetched) => fetched,
+                Err(err) => {
+                    let dht = match dht {
+                        Some(dht) if lookups < dht.max_lookups => dht,
+                        _ => return Err(err),
+                    };
+                    lookups += 1;
+                    let found = self.discover_providers(&block, providers, dht).await;
+                    if found.is_empty() {
+                        return Err(err);
+                    }
+                    providers.extend(found.iter().copied());
+                    tx.unbounded_send(SyncEvent::ProvidersAdded {
+                        cid: block,
+                        providers: found,
+                    })
+                    .ok();
+                    // retry the block next
+                    requested.remove(&block);
+                    match config.traversal {
+                        SyncTraversal::DepthFirst => queue.push_back(block),
+                        SyncTraversal::BreadthFirst => queue.push_front(block),
+                    }
+                    continue;
+                }
+            };
+            blocks += 1;
+            bytes += fetched.data().len() as u64;
+            queue.extend(self.storage.missing_blocks(&block)?);
+            tx.unbounded_send(SyncEvent::Progress {
+                missing: queue.len() + in_flight.len(),
+                blocks,
+                bytes,
+                providers: providers
+                    .iter()
+                    .filter(|peer| self.network.is_connected(peer))
+                    .count(),
+            })
+            .ok();
+        }
+    }
+
+    /// Looks up providers of `cid` in the dht that are not in `known`.
+    async fn discover_providers(
+        &self,
+        cid: &Cid,
+        known: &[PeerId],
+        config: DhtSyncConfig,
+    ) -> Vec<PeerId> {
+        let mut network = self.network.clone();
+        let found: Vec<PeerId> = network
+            .providers_stream(Key::new(&cid.to_bytes()))
+            .filter(|peer| future::ready(!known.contains(peer)))
+            .take(config.max_providers)
+            .take_until(futures_timer::Delay::new(config.timeout))
+            .collect()
+            .await;
+        tracing::debug!(cid = %cid, providers = %found.len(), "sync discovered providers");
+        found
+    }
+
+    /// Returns the bitswap transfer statistics of this node. The bitswap
+    /// version in use doesn't report which peer a block was exchanged
+    /// with, so only totals are kept, see `peer_bandwidth` for the traffic
+    /// with each peer.
+    pub fn bitswap_stats(&self) -> BitswapStats {
+        self.network.bitswap_stats()
+    }
+
+    /// Returns the bytes transferred over the connections of this node and
+    /// the current transfer rates.
+    pub fn bandwidth(&self) -> BandwidthStats {
+        self.network.bandwidth()
+    }
+
+    /// Returns the bytes transferred over the connections to each connected
+    /// peer.
+    pub fn peer_bandwidth(&self) -> Vec<(PeerId, PeerBandwidth)> {
+        self.network.peer_bandwidth()
+    }
+
+    /// Exports the dag rooted at `root` as a CARv1 file. Fails with a
+    /// `MissingBlocks` error without writing anything if the dag is
+    /// incomplete.
+    pub async fn export_car<W: AsyncWrite + Unpin>(
+        &self,
+        root: &Cid,
+        writer: W,
+    ) -> Result<CarStats> {
+        Ok(car::export(&self.storage, root, writer).await?)
+    }
+
+    /// Imports a CARv1 or CARv2 file, verifying every block before inserting
+    /// it. Returns the roots of the file. If an `alias` is given the root is
+    /// aliased with it, if the file has multiple roots they are aliased as
+    /// `{alias}/{index}`.
+    pub async fn import_car<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+        alias: Option<&[u8]>,
+    ) -> Result<Vec<Cid>> {
+        Ok(car::import(&self.storage, reader, alias).await?)
+    }
+
+    /// Adds the contents of `reader` as a UnixFS file and returns its root.
+    /// The cids match those of `ipfs add` with the same settings. The blocks
+    /// are only protected from garbage collection while adding, so the root
+    /// needs to be aliased to keep the file.
+    #[cfg(feature = "unixfs")]
+    pub async fn add_file<R: AsyncRead + Unpin>(&self, reader: R, opts: AddOptions) -> Result<Cid> {
+        Ok(unixfs::add_file(&self.storage, reader, opts).await?)
+    }
+
+    /// Adds a directory with the given files and subdirectories and returns
+    /// its root. Directories with more than `opts.shard_threshold` entries are
+    /// stored as HAMT shards. Like with `add_file` the root needs to be
+    /// aliased to keep the directory.
+    #[cfg(feature = "unixfs")]
+    pub async fn add_dir<R, I>(&self, entries: I, opts: AddOptions) -> Result<Cid>
+    where
+        R: AsyncRead + Unpin,
+        I: IntoIterator<Item = AddEntry<R>>,
+    {
+        Ok(unixfs::add_dir(&self.storage, entries, opts).await?)
+    }
+
+    /// Lists the entries of a UnixFS directory like `ipfs ls`. Sharded
+    /// directories need to be fully available in the store.
+    #[cfg(feature = "unixfs")]
+    pub fn ls(&self, cid: &Cid) -> Result<Vec<DirEntry>> {
+        Ok(unixfs::ls(&self.storage, cid)?)
+    }
+
+    /// Returns the size of a UnixFS file or `None` if `cid` is not a file.
+    #[cfg(feature = "unixfs")]
+    pub fn file_size(&self, cid: &Cid) -> Result<Option<u64>> {
+        Ok(unixfs::file_size(&self.storage, cid)?)
+    }
+
+    /// Reads the bytes in `range` of a UnixFS file like `ipfs cat`. The range
+    /// is clamped to the size of the file and fails with [`InvalidRange`] if
+    /// it is reversed. Only the blocks overlapping the range are read, they
+    /// have to be in the store.
+    #[cfg(feature = "unixfs")]
+    pub fn read_file(&self, cid: &Cid, range: std::ops::Range<u64>) -> Result<Vec<u8>> {
+        Ok(unixfs::read_file(
+            &self.storage,
+            cid,
+            range.start,
+            range.end,
+        )?)
+    }
+
+    /// Starts a read-only HTTP gateway on `addr` serving `GET /ipfs/<cid>`
+    /// and `GET /ipfs/<cid>/<path>` from the local store. Blocks are served
+    /// as is, UnixFS files with range requests when the `unixfs` feature is
+    /// enabled. The gateway stops when the handle is dropped.
+    #[cfg(feature = "http-gateway")]
+    pub fn serve_gateway(&self, addr: std::net::SocketAddr) -> Result<GatewayHandle>
+    where
+        Ipld: libipld::codec::Decode<P::Codecs>,
+    {
+        Ok(gateway::serve(self.storage.clone(), &self.executor, addr)?)
+    }
+
+    /// Starts an HTTP server on `addr` implementing a subset of the Kubo RPC
+    /// API: `id`, `block/get`, `block/put`, `pin/add`, `swarm/peers` and
+    /// `swarm/connect`. Other commands are answered with `501 Not
+    /// Implemented`. The server stops when the handle is dropped.
+    #[cfg(feature = "http-api")]
+    pub fn serve_api(&self, addr: std::net::SocketAddr) -> Result<ApiHandle> {
+        Ok(api::serve(self.clone(), addr)?)
+    }
+
+    /// Creates, updates or removes an alias with a new root `Cid`.
+    pub fn alias<T: AsRef<[u8]> + Send + Sync>(&self, alias: T, cid: Option<&Cid>) -> Result<()> {
+        Ok(self.storage.alias(alias.as_ref(), cid)?)
+    }
+
+    /// Like `alias`, but runs on the blocking thread pool of the executor.
+    pub fn alias_async<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: Option<&Cid>,
+    ) -> impl Future<Output = Result<()>> {
+        let alias = alias.as_ref().to_vec();
+        let cid = cid.copied();
+        let update = self
+            .storage
+            .spawn_blocking(move |storage| storage.alias(&alias, cid.as_ref()));
+        async move { Ok(update.await?) }
+    }
+
+    /// Atomically updates an alias to `new` if it currently points to
+    /// `expected`. Otherwise the current root is returned, so that the caller
+    /// can merge and retry.
+    pub fn alias_cas<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        expected: Option<&Cid>,
+        new: Option<&Cid>,
+    ) -> Result<std::result::Result<(), Option<Cid>>> {
+        Ok(self.storage.alias_cas(alias.as_ref(), expected, new)?)
+    }
+
+    /// List all known aliases.
+    pub fn aliases(&self) -> Result<Vec<(Vec<u8>, Cid)>> {
+        Ok(self.storage.aliases()?)
+    }
+
+    /// Returns the root of an alias.
+    pub fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> Result<Option<Cid>> {
+        Ok(self.storage.resolve(alias.as_ref())?)
+    }
+
+    /// Returns a stream yielding the current root of an alias and then
+    /// every change, `None` once it is removed, with the semantics of a
+    /// `variable::Observer`.
+    pub fn watch_alias<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+    ) -> Result<impl Stream<Item = Option<Cid>>> {
+        Ok(self.storage.watch_alias(alias.as_ref())?)
+    }
+
+    /// Like `resolve`, but runs on the blocking thread pool of the executor.
+    pub fn resolve_async<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+    ) -> impl Future<Output = Result<Option<Cid>>> {
+        let alias = alias.as_ref().to_vec();
+        let resolve = self
+            .storage
+            .spawn_blocking(move |storage| storage.resolve(&alias));
+        async move { Ok(resolve.await?) }
+    }
+
+    /// Returns a list of aliases preventing a `Cid` from being garbage
+    /// collected. Pins are not included, use `pin_status` for those.
+    pub fn reverse_alias(&self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
+        Ok(self.storage.reverse_alias(cid)?)
+    }
+
+    /// Pins the dag rooted at `cid`, keeping it from being garbage collected
+    /// without giving it a name. Pinning a `Cid` multiple times requires
+    /// unpinning it the same number of times.
+    pub fn pin(&self, cid: &Cid) -> Result<()> {
+        Ok(self.storage.pin(cid)?)
+    }
+
+    /// Removes a pin of `cid`. Returns `false` if `cid` wasn't pinned.
+    pub fn unpin(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.storage.unpin(cid)?)
+    }
+
+    /// Lists the pinned roots together with their pin count.
+    pub fn list_pins(&self) -> Result<Vec<(Cid, usize)>> {
+        Ok(self.storage.pins()?)
+    }
+
+    /// Returns the number and the total size of the stored blocks.
+    pub fn store_stats(&self) -> Result<BackendStats> {
+        Ok(self.storage.stats()?)
+    }
+
+    /// Returns the aliases and pins preventing a `Cid` from being garbage
+    /// collected.
+    pub fn pin_status(&self, cid: &Cid) -> Result<Option<PinStatus>> {
+        Ok(self.storage.pin_status(cid)?)
+    }
+
+    /// Flushes the block store. After `flush` completes successfully it is
+    /// guaranteed that all writes have been persisted to disk.
+    pub fn flush(&self) -> impl Future<Output = Result<()>> {
+        self.storage.flush().err_into()
+    }
+
+    /// Flushes the block store to the requested durability. All `insert` and
+    /// `alias` calls that returned before `flush_with` was called are covered.
+    pub fn flush_with(&self, durability: Durability) -> impl Future<Output = Result<()>> {
+        self.storage.flush_with(durability).err_into()
+    }
+
+    /// Shuts the node down. New commands are rejected, the listeners and
+    /// connections are closed, the background tasks are stopped and the
+    /// store is flushed. Afterwards all clones of this `Ipfs` fail with
+    /// `Shutdown`.
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.network.shutdown().await?;
+        if let Some(address_book) = &self.address_book {
+            address_book.stop();
+        }
+        self.reprovider.stop();
+        Ok(self.storage.shutdown().await?)
+    }
+
+    /// Perform a set of storage operations in a batch
+    ///
+    /// The batching concerns only the CacheTracker, it implies no atomicity
+    /// guarantees!
+    pub fn batch_ops<R>(
+        &self,
+        f: impl FnOnce(&mut Batch<'_, P>) -> anyhow::Result<R>,
+    ) -> Result<R> {
+        Ok(self.storage.rw("batch_ops", f)?)
+    }
+
+    /// Like `batch_ops`, but runs `f` on the blocking thread pool of the
+    /// executor instead of the calling task.
+    pub fn batch_ops_async<R, F>(&self, f: F) -> impl Future<Output = Result<R>>
+    where
+        F: FnOnce(&mut Batch<'_, P>) -> anyhow::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let batch = self
+            .storage
+            .spawn_blocking(move |storage| storage.rw("batch_ops", f));
+        async move { Ok(batch.await?) }
+    }
+
+    /// Perform a set of storage operations atomically
+    ///
+    /// Writes, including block removals, are buffered and applied together if
+    /// the closure returns `Ok`, otherwise none of them are applied. Before
+    /// the first write, the transaction fails with `QuotaExceeded` if the
+    /// blocks don't fit into the quota and with `MissingAliasTarget` if an
+    /// alias points to a block that is neither stored nor inserted by it.
+    /// The sqlite store can't roll back, a write failing after the checks,
+    /// e.g. on an I/O error, leaves the writes before it in place.
+    pub fn transaction<R>(
+        &self,
+        f: impl FnOnce(&mut AtomicBatch<'_, '_, P>) -> anyhow::Result<R>,
+    ) -> Result<R> {
+        Ok(self.storage.transaction(f)?)
+    }
+
+    /// Registers prometheus metrics in a registry.
+    pub fn register_metrics(&self, registry: &Registry) -> Result<()> {
+        self.storage.register_metrics(registry)?;
+        net::register_metrics(registry)?;
+        reprovider::register_metrics(registry)?;
+        Ok(())
+    }
+
+    /// Starts an HTTP server on `addr` answering `GET /metrics` with the
+    /// metrics of the node in the prometheus text format and `GET /health`
+    /// with the status of the background tasks. The metrics are registered
+    /// in a registry of the server. The server stops when the handle is
+    /// dropped.
+    #[cfg(feature = "metrics-server")]
+    pub fn serve_metrics(&self, addr: std::net::SocketAddr) -> Result<MetricsHandle> {
+        Ok(metrics::serve(self.clone(), addr)?)
+    }
+
+    /// Subscribes to the swarm event stream.
+    pub fn swarm_events(&mut self) -> impl Future<Output = Result<SwarmEvents>> {
+        self.network.swarm_events().err_into()
+    }
+
+    /// Subscribes to the swarm events selected by `filter`. Other events are
+    /// not delivered to the subscription, and events are dropped while the
+    /// subscriber lags behind by more than the buffer of the filter, see
+    /// `SwarmEvents::dropped_events`.
+    pub fn swarm_events_filtered(
+        &mut self,
+        filter: EventFilter,
+    ) -> impl Future<Output = Result<SwarmEvents>> {
+        self.network.swarm_events_filtered(filter).err_into()
+    }
+}
+
+#[async_trait]
+impl<P: StoreParams> Store for Ipfs<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    type Params = P;
+    type TempPin = Arc<Mutex<TempPin>>;
+
+    fn create_temp_pin(&self) -> libipld::Result<Self::TempPin> {
+        Ok(Arc::new(Mutex::new(Ipfs::create_temp_pin(self)?)))
+    }
+
+    fn temp_pin(&self, tmp: &Self::TempPin, cid: &Cid) -> libipld::Result<()> {
+        Ok(Ipfs::temp_pin(self, &mut tmp.lock(), cid)?)
+    }
+
+    fn contains(&self, cid: &Cid) -> libipld::Result<bool> {
+        Ok(Ipfs::contains(self, cid)?)
+    }
+
+    fn get(&self, cid: &Cid) -> libipld::Result<Block<P>> {
+        Ok(Ipfs::get(self, cid)?)
+    }
+
+    fn insert(&self, block: &Block<P>) -> libipld::Result<()> {
+        Ipfs::insert(self, block.clone())?;
+        Ok(())
+    }
+
+    fn alias<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: Option<&Cid>,
+    ) -> libipld::Result<()> {
+        Ok(Ipfs::alias(self, alias, cid)?)
+    }
+
+    fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> libipld::Result<Option<Cid>> {
+        Ok(Ipfs::resolve(self, alias)?)
+    }
+
+    fn reverse_alias(&self, cid: &Cid) -> libipld::Result<Option<Vec<Vec<u8>>>> {
+        Ok(Ipfs::reverse_alias(self, cid)?.map(|x| x.into_iter().collect()))
+    }
+
+    async fn flush(&self) -> libipld::Result<()> {
+        Ok(Ipfs::flush(self).await?)
+    }
+
+    async fn fetch(&self, cid: &Cid) -> libipld::Result<Block<Self::Params>> {
+        if let Some(timeout) = self.network.fetch_timeout() {
+            Ok(Ipfs::fetch_with_timeout(self, cid, self.peers(), timeout).await?)
+        } else {
+            Ok(Ipfs::fetch(self, cid, self.peers()).await?)
+        }
+    }
+
+    async fn sync(&self, cid: &Cid) -> libipld::Result<()> {
         Ipfs::sync(self, cid, self.peers()).await?.await
     }
 }
@@ -505,11 +1942,13 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use anyhow::Result;
     use async_std::future::timeout;
     use futures::{join, stream::StreamExt};
     use libipld::{
         alias, cbor::DagCborCodec, ipld, multihash::Code, raw::RawCodec, store::DefaultParams,
     };
+    use libp2p::core::transport::MemoryTransport;
     use std::time::Duration;
     use tempdir::TempDir;
 
@@ -538,6 +1977,25 @@ mod tests {
         Ok((ipfs, tmp))
     }
 
+    #[async_std::test]
+    async fn test_invalid_config() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.sync_pipeline = Some(SyncPipelineConfig {
+            max_in_flight: 0,
+            ..Default::default()
+        });
+        let err = Box::pin(Ipfs::<DefaultParams>::new(Config { storage, network }))
+            .await
+            .err()
+            .unwrap();
+        let expected = InvalidNetworkConfig::NoSyncRequestsInFlight;
+        assert!(matches!(err, Error::Other(err) if err.downcast_ref() == Some(&expected)));
+        Ok(())
+    }
+
     fn create_block(bytes: &[u8]) -> Result<Block<DefaultParams>> {
         Block::encode(RawCodec, Code::Blake3_256, bytes)
     }
@@ -616,117 +2074,1983 @@ mod tests {
         tracing_try_init();
         let (store1, _tmp) = create_store(true).await?;
         let block = create_block(b"test_provider_not_found")?;
-        if store1
+        let err = store1
             .fetch(block.cid(), vec![store1.local_peer_id()])
             .await
-            .unwrap_err()
-            .downcast_ref::<BlockNotFound>()
-            .is_none()
+            .unwrap_err();
+        assert!(matches!(err, Error::BlockNotFound(cid) if cid == *block.cid()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_fetch_timeout() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        // concurrent dials from the reused listen port to the same address collide
+        a.connect(b.local_peer_id()).await?;
+        let block = create_block(b"test_fetch_timeout")?;
+        let err = a
+            .fetch_with_timeout(
+                block.cid(),
+                vec![b.local_peer_id()],
+                Duration::from_millis(0),
+            )
+            .await
+            .unwrap_err();
+        match err {
+            Error::Timeout(err) => assert!(err.is::<FetchTimeout>()),
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        // b doesn't answer, so the want is outstanding until the timeout
+        b.network.suspend(Duration::from_secs(10));
+        let fetch = a.fetch_with_timeout(
+            block.cid(),
+            vec![b.local_peer_id()],
+            Duration::from_millis(500),
+        );
+        let wants = async {
+            async_std::task::sleep(Duration::from_millis(200)).await;
+            a.bitswap_stats().running_queries
+        };
+        let (res, wants) = join!(fetch, wants);
+        assert_eq!(wants, 1);
+        match res.unwrap_err() {
+            Error::Timeout(err) => assert!(err.is::<FetchTimeout>()),
+            err => panic!("unexpected error {:?}", err),
+        }
+        // the timeout cancels the want instead of just dropping the future
+        timeout(Duration::from_secs(1), async {
+            while a.bitswap_stats().running_queries > 0 {
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_fetch_from_dht() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let (mut c, _tmp) = create_store(false).await?;
+        let node_a = (a.local_peer_id(), a.listeners()[0].clone());
+        let node_b = (b.local_peer_id(), b.listeners()[0].clone());
+        let node_c = (c.local_peer_id(), c.listeners()[0].clone());
+
+        b.bootstrap(vec![node_a, node_c]).await?;
+        a.bootstrap(vec![node_b.clone()]).await?;
+        c.bootstrap(vec![node_b]).await?;
+
+        let block = create_block(b"test_fetch_from_dht")?;
+        let mut tmp = a.create_temp_pin()?;
+        a.temp_pin(&mut tmp, block.cid())?;
+        a.insert(block.clone())?;
+        a.provide(Key::new(&block.cid().to_bytes())).await?;
+
+        let fetched = c
+            .fetch_from_dht(block.cid(), 8, Duration::from_secs(10))
+            .await?;
+        assert_eq!(fetched, block);
+
+        let missing = create_block(b"test_fetch_from_dht_missing")?;
+        let err = c
+            .fetch_from_dht(missing.cid(), 8, Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::NoProviders(cid) if cid == *missing.cid()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[cfg(feature = "secp256k1")]
+    async fn test_mixed_key_types() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(3600));
+        let mut network = NetworkConfig::with_keypair(identity::Keypair::generate_secp256k1());
+        network.mdns = None;
+        let mut b = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        b.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+        assert!(matches!(b.local_public_key(), PublicKey::Secp256k1(_)));
+        assert_eq!(b.local_public_key().to_peer_id(), b.local_peer_id());
+
+        let block = create_block(b"test_mixed_key_types")?;
+        let mut tmp = b.create_temp_pin()?;
+        b.temp_pin(&mut tmp, block.cid())?;
+        b.insert(block.clone())?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        let fetched = a.fetch(block.cid(), vec![b.local_peer_id()]).await?;
+        assert_eq!(fetched, block);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_read_only() -> Result<()> {
+        tracing_try_init();
+        let tmp = TempDir::new("ipfs-embed")?;
+        let block = create_block(b"test_read_only")?;
+        let mut storage =
+            StorageConfig::new(Some(tmp.path().into()), None, 0, Duration::from_secs(3600));
         {
-            panic!("expected block not found error");
+            let store = StorageService::<DefaultParams>::open(storage.clone(), Executor::new())?;
+            store.insert(block.clone())?;
+            store.alias(b"root", Some(block.cid()))?;
+            store.flush().await?;
         }
+
+        storage.read_only = true;
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        let mut a = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+        assert_eq!(a.resolve(b"root")?, Some(*block.cid()));
+        let err = a.insert(create_block(b"rejected")?).unwrap_err();
+        assert!(matches!(err, Error::ReadOnly(_)));
+        let err = a.alias(b"other", Some(block.cid())).unwrap_err();
+        assert!(matches!(err, Error::ReadOnly(_)));
+        let err = a.create_temp_pin().unwrap_err();
+        assert!(matches!(err, Error::ReadOnly(_)));
+        let err = a.evict().await.unwrap_err();
+        assert!(matches!(err, Error::ReadOnly(_)));
+
+        let (mut b, _tmp) = create_store(false).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let fetched = b.fetch(block.cid(), vec![a.local_peer_id()]).await?;
+        assert_eq!(fetched, block);
         Ok(())
     }
 
-    macro_rules! assert_pinned {
-        ($store:expr, $block:expr) => {
-            assert_eq!(
-                $store
-                    .reverse_alias($block.cid())
-                    .unwrap()
-                    .map(|a| !a.is_empty()),
-                Some(true)
-            );
-        };
+    #[async_std::test]
+    async fn test_bitswap_stats() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let block = create_block(b"test_bitswap_stats")?;
+        a.insert(block.clone())?;
+        assert_eq!(a.bitswap_stats(), BitswapStats::default());
+
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let mut tmp = b.create_temp_pin()?;
+        b.temp_pin(&mut tmp, block.cid())?;
+        b.fetch(block.cid(), vec![a.local_peer_id()]).await?;
+        let received = b.bitswap_stats();
+        assert_eq!(received.blocks_received, 1);
+        assert_eq!(received.bytes_received, block.data().len() as u64);
+        let sent = a.bitswap_stats();
+        assert_eq!(sent.blocks_sent, 1);
+        assert_eq!(sent.bytes_sent, block.data().len() as u64);
+        assert!(sent.requests_served >= 1);
+        Ok(())
     }
 
-    macro_rules! assert_unpinned {
-        ($store:expr, $block:expr) => {
-            assert_eq!(
-                $store
-                    .reverse_alias($block.cid())
-                    .unwrap()
-                    .map(|a| !a.is_empty()),
-                Some(false)
-            );
-        };
+    #[async_std::test]
+    async fn test_bandwidth() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let data = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let block = create_block(&data)?;
+        a.insert(block.clone())?;
+        assert_eq!(a.bandwidth().total_out, 0);
+
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let mut tmp = b.create_temp_pin()?;
+        b.temp_pin(&mut tmp, block.cid())?;
+        b.fetch(block.cid(), vec![a.local_peer_id()]).await?;
+        let size = data.len() as u64;
+        let sent = a.bandwidth();
+        assert!(sent.total_out >= size);
+        assert!(sent.rate_out > 0.0);
+        let received = b.bandwidth();
+        assert!(received.total_in >= size);
+        assert!(received.rate_in > 0.0);
+
+        let peers = b.peer_bandwidth();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].0, a.local_peer_id());
+        assert!(peers[0].1.total_in >= size);
+        assert!(peers[0].1.total_in <= received.total_in);
+        Ok(())
     }
 
-    fn create_ipld_block(ipld: &Ipld) -> Result<Block<DefaultParams>> {
-        Block::encode(DagCborCodec, Code::Blake3_256, ipld)
+    async fn fetch_blocks(
+        a: &Ipfs<DefaultParams>,
+        b: &Ipfs<DefaultParams>,
+        tag: u8,
+    ) -> Result<Duration> {
+        let blocks = (0..10u8)
+            .map(|i| create_block(&[vec![tag, i], vec![0; 10_000]].concat()))
+            .collect::<Result<Vec<_>>>()?;
+        for block in &blocks {
+            a.insert(block.clone())?;
+        }
+        let start = std::time::Instant::now();
+        for block in &blocks {
+            b.fetch(block.cid(), vec![a.local_peer_id()]).await?;
+        }
+        Ok(start.elapsed())
+    }
+
+    #[async_std::test]
+    async fn test_serve_rate_limit() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let unlimited = fetch_blocks(&a, &b, 0).await?;
+
+        // one second worth of burst, then 80 KB at 20 KB/s
+        let mut limit = RateLimit::default();
+        limit.global.bytes_per_sec = Some(20_000);
+        a.set_serve_rate_limit(Some(limit))?;
+        let limited = fetch_blocks(&a, &b, 1).await?;
+        assert!(limited >= Duration::from_secs(3));
+        assert!(limited > unlimited * 2);
+
+        limit.global.bytes_per_sec = Some(0);
+        assert!(a.set_serve_rate_limit(Some(limit)).is_err());
+        a.set_serve_rate_limit(None)?;
+        assert_eq!(a.serve_rate_limit(), None);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_fetch_pins_block() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let storage = StorageConfig::new(None, None, 0, Duration::from_millis(1));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        let mut b = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        for i in 0..10u8 {
+            let block = create_block(&[i])?;
+            a.insert(block.clone())?;
+            let fetched = b.fetch(block.cid(), vec![a.local_peer_id()]).await?;
+            assert_eq!(fetched, block);
+        }
+
+        let (cid, blocks) = test_util::build_tree(2, 2)?;
+        for block in &blocks {
+            a.insert(block.clone())?;
+        }
+        let mut query = b.sync(&cid, vec![a.local_peer_id()]).await?;
+        while let Some(event) = query.next().await {
+            if let SyncEvent::Complete(res) = event {
+                res?;
+            }
+        }
+        for block in &blocks {
+            assert!(b.contains(block.cid())?);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_coalesce_fetch() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let block = create_block(b"test_coalesce_fetch")?;
+        a.insert(block.clone())?;
+
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let mut tmp = b.create_temp_pin()?;
+        b.temp_pin(&mut tmp, block.cid())?;
+        let fetches = (0..10).map(|_| b.fetch(block.cid(), vec![a.local_peer_id()]));
+        for fetched in futures::future::join_all(fetches).await {
+            assert_eq!(fetched?, block);
+        }
+        assert_eq!(b.bitswap_stats().blocks_received, 1);
+        assert_eq!(a.bitswap_stats().blocks_sent, 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_serve_policy_aliased_only() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.serve_policy = ServePolicy::AliasedOnly;
+        let mut a = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)
+            .next()
+            .await
+            .unwrap();
+        let public = create_block(b"test_serve_policy_public")?;
+        let private = create_block(b"test_serve_policy_private")?;
+        a.insert(public.clone())?;
+        a.insert(private.clone())?;
+        a.alias(b"public", Some(public.cid()))?;
+
+        let (mut b, _tmp) = create_store(false).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let mut tmp = b.create_temp_pin()?;
+        b.temp_pin(&mut tmp, private.cid())?;
+        let err = b
+            .fetch(private.cid(), vec![a.local_peer_id()])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::BlockNotFound(_)));
+        b.temp_pin(&mut tmp, public.cid())?;
+        let fetched = b.fetch(public.cid(), vec![a.local_peer_id()]).await?;
+        assert_eq!(fetched, public);
+        // local operations are not affected
+        assert_eq!(a.get(private.cid())?, private);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_remove_listener() -> Result<()> {
+        tracing_try_init();
+        let (mut store, _tmp) = create_store(false).await?;
+        let (id1, mut events1) = store.add_listener("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        let (id2, mut events2) = store.add_listener("/ip4/127.0.0.1/tcp/0".parse()?).await?;
+        let addr1 = match events1.next().await {
+            Some(ListenerEvent::NewListenAddr(addr)) => addr,
+            e => panic!("unexpected listener event {:?}", e),
+        };
+        let addr2 = match events2.next().await {
+            Some(ListenerEvent::NewListenAddr(addr)) => addr,
+            e => panic!("unexpected listener event {:?}", e),
+        };
+        assert!(store.listeners().contains(&addr1));
+        assert!(store.listeners().contains(&addr2));
+
+        assert!(store.remove_listener(id1));
+        assert_eq!(
+            events1.next().await,
+            Some(ListenerEvent::ExpiredListenAddr(addr1.clone()))
+        );
+        assert_eq!(events1.next().await, None);
+        assert!(!store.listeners().contains(&addr1));
+        assert!(store.listeners().contains(&addr2));
+        assert!(!store.remove_listener(id1));
+
+        let socket_addr = |addr: &Multiaddr| {
+            let mut ip = None;
+            let mut port = None;
+            for protocol in addr.iter() {
+                match protocol {
+                    multiaddr::Protocol::Ip4(addr) => ip = Some(addr),
+                    multiaddr::Protocol::Tcp(p) => port = Some(p),
+                    _ => {}
+                }
+            }
+            std::net::SocketAddr::from((ip.unwrap(), port.unwrap()))
+        };
+        // libp2p-tcp 0.37.0 closes the first listener with a different id instead of
+        // the removed one, so whether `addr1` still accepts connections isn't checked
+        assert!(async_std::net::TcpStream::connect(socket_addr(&addr2))
+            .await
+            .is_ok());
+        assert!(store.remove_listener(id2));
+        Ok(())
+    }
+
+    async fn create_ws_store() -> Result<(Ipfs<DefaultParams>, TempDir, Multiaddr)> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.websocket = Some(WebsocketConfig::default());
+        let mut ipfs = Ipfs::new(Config { storage, network }).await?;
+        let addr = match ipfs
+            .listen_on("/ip4/127.0.0.1/tcp/0/ws".parse()?)
+            .next()
+            .await
+        {
+            Some(ListenerEvent::NewListenAddr(addr)) => addr,
+            e => panic!("unexpected listener event {:?}", e),
+        };
+        Ok((ipfs, tmp, addr))
+    }
+
+    #[async_std::test]
+    async fn test_websocket_gossip() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp, _) = create_ws_store().await?;
+        let (mut b, _tmp, addr_b) = create_ws_store().await?;
+        assert!(addr_b
+            .iter()
+            .any(|p| matches!(p, multiaddr::Protocol::Ws(_))));
+        a.dial_address(b.local_peer_id(), addr_b);
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        assert!(a.is_connected(&b.local_peer_id()));
+
+        let topic = "topic".to_owned();
+        let _subscription_a = a.subscribe(topic.clone()).await?;
+        let mut subscription_b = b.subscribe(topic.clone()).await?;
+        async_std::task::sleep(Duration::from_millis(1000)).await;
+        let id = a.publish(topic, b"hello websocket".to_vec()).await?;
+        loop {
+            let event = timeout(Duration::from_secs(5), subscription_b.next())
+                .await?
+                .unwrap();
+            if let GossipEvent::Message(peer, data, message_id) = event {
+                assert_eq!(peer, a.local_peer_id());
+                assert_eq!(&*data, b"hello websocket");
+                assert_eq!(message_id, Some(id));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_wss_requires_tls() -> Result<()> {
+        tracing_try_init();
+        let (mut store, _tmp, _) = create_ws_store().await?;
+        assert!(store
+            .add_listener("/ip4/127.0.0.1/tcp/0/wss".parse()?)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_relay() -> Result<()> {
+        tracing_try_init();
+        async fn create_node(
+            f: impl FnOnce(&mut NetworkConfig),
+        ) -> Result<(Ipfs<DefaultParams>, TempDir, Multiaddr)> {
+            let tmp = TempDir::new("ipfs-embed")?;
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            // b has no direct address to offer, which makes DCUtR close the
+            // relayed connection instead of just giving up on the upgrade.
+            network.dcutr = false;
+            f(&mut network);
+            let mut ipfs = Ipfs::new(Config { storage, network }).await?;
+            let addr = match ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await {
+                Some(ListenerEvent::NewListenAddr(addr)) => addr,
+                e => panic!("unexpected listener event {:?}", e),
+            };
+            Ok((ipfs, tmp, addr))
+        }
+
+        let (mut r, _tmp, r_addr) = create_node(|network| {
+            network.relay_server = Some(RelayServerConfig::default());
+        })
+        .await?;
+        r.add_external_address(r_addr.clone());
+        let relay = r_addr.with(multiaddr::Protocol::P2p(r.local_peer_id().into()));
+        let (b, _tmp, _) = create_node(|network| network.relays = vec![relay]).await?;
+        let (mut a, _tmp, _) = create_node(|_| {}).await?;
+
+        let circuit = timeout(Duration::from_secs(10), async {
+            loop {
+                let circuit = b.external_addresses().into_iter().find(|record| {
+                    record
+                        .addr
+                        .iter()
+                        .any(|p| matches!(p, multiaddr::Protocol::P2pCircuit))
+                });
+                if let Some(record) = circuit {
+                    break record.addr;
+                }
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+
+        let block = create_block(b"test_relay")?;
+        b.insert(block.clone())?;
+        a.add_address(b.local_peer_id(), circuit);
+        let fetched = a.fetch(block.cid(), vec![b.local_peer_id()]).await?;
+        assert_eq!(fetched, block);
+        assert!(a.is_connected(&b.local_peer_id()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_autonat() -> Result<()> {
+        use crate::config::AutonatConfig;
+        tracing_try_init();
+        async fn create_node(listen: bool) -> Result<(Ipfs<DefaultParams>, TempDir, Multiaddr)> {
+            let tmp = TempDir::new("ipfs-embed")?;
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            // the dial-back would reuse the 4-tuple of the existing connection
+            network.port_reuse = false;
+            network.autonat = Some(AutonatConfig {
+                boot_delay: Duration::from_millis(100),
+                retry_interval: Duration::from_millis(500),
+                refresh_interval: Duration::from_millis(500),
+                throttle_server_period: Duration::ZERO,
+                only_global_ips: false,
+                ..Default::default()
+            });
+            let mut ipfs = Ipfs::new(Config { storage, network }).await?;
+            let addr = if listen {
+                match ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await {
+                    Some(ListenerEvent::NewListenAddr(addr)) => addr,
+                    e => panic!("unexpected listener event {:?}", e),
+                }
+            } else {
+                // the address of a socket that is closed again right away
+                let port = std::net::TcpListener::bind("127.0.0.1:0")?
+                    .local_addr()?
+                    .port();
+                format!("/ip4/127.0.0.1/tcp/{}", port).parse()?
+            };
+            Ok((ipfs, tmp, addr))
+        }
+
+        let (b, _tmp, b_addr) = create_node(true).await?;
+        let (c, _tmp, c_addr) = create_node(true).await?;
+        let (mut reachable, _tmp, addr) = create_node(true).await?;
+        reachable.add_external_address(addr);
+        // the unreachable node doesn't listen and advertises a closed port
+        let (mut unreachable, _tmp, closed) = create_node(false).await?;
+        unreachable.add_external_address(closed);
+        for node in [&mut reachable, &mut unreachable] {
+            node.dial_address(b.local_peer_id(), b_addr.clone());
+            node.dial_address(c.local_peer_id(), c_addr.clone());
+        }
+
+        timeout(Duration::from_secs(20), async {
+            while !matches!(reachable.nat_status(), NatStatus::Public(_))
+                || unreachable.nat_status() != NatStatus::Private
+            {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_connection_limits() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.connection_limits.max_established_incoming = Some(2);
+        let mut node = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        node.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+        let addr = node.listeners()[0].clone();
+        assert_eq!(node.connection_limits().max_established_incoming, Some(2));
+        let mut events = node.swarm_events().await?;
+
+        let mut dialers = vec![];
+        for _ in 0..4 {
+            let (mut dialer, tmp) = create_store(false).await?;
+            dialer.dial_address(node.local_peer_id(), addr.clone());
+            dialers.push((dialer, tmp));
+            async_std::task::sleep(Duration::from_millis(200)).await;
+        }
+
+        let mut rejected = 0;
+        while let Ok(Some(event)) = timeout(Duration::from_secs(1), events.next()).await {
+            if let Event::ConnectionLimitReached(Direction::Inbound, _, _, limit) = event {
+                assert_eq!(limit, 2);
+                rejected += 1;
+            }
+        }
+        assert_eq!(rejected, 2);
+        assert_eq!(node.connection_counts().established_incoming, 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_connection_gate() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+        let a_addr = a.listeners()[0].clone();
+        let mut a_events = a.swarm_events().await?;
+
+        // incoming connections from a denied peer are rejected after the handshake
+        a.set_connection_gate(ConnectionGate::new().deny_peer(b.local_peer_id()));
+        b.dial_address(a.local_peer_id(), a_addr.clone());
+        loop {
+            match timeout(Duration::from_secs(5), a_events.next()).await? {
+                Some(Event::ConnectionGated(Direction::Inbound, peer, _, _)) => {
+                    assert_eq!(peer, Some(b.local_peer_id()));
+                    break;
+                }
+                Some(Event::Connected(peer)) => panic!("denied peer {} connected", peer),
+                Some(_) => {}
+                None => panic!("event stream closed"),
+            }
+        }
+        assert!(!a.is_connected(&b.local_peer_id()));
+
+        // outgoing connections to denied addresses are rejected before the
+        // handshake. `c` is dialed so that the port reused by the dial isn't
+        // still taken by the gated connection from `b`.
+        let (mut c, _tmp) = create_store(false).await?;
+        c.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+        let c_addr = c.listeners()[0].clone();
+        a.set_connection_gate(ConnectionGate::new().deny_ip("127.0.0.0/8".parse()?));
+        a.dial_address(c.local_peer_id(), c_addr);
+        loop {
+            match timeout(Duration::from_secs(5), a_events.next()).await? {
+                Some(Event::ConnectionGated(Direction::Outbound, None, _, reason)) => {
+                    assert!(reason.contains("127.0.0.1"));
+                    break;
+                }
+                Some(Event::Connected(peer)) => panic!("denied address of {} connected", peer),
+                Some(_) => {}
+                None => panic!("event stream closed"),
+            }
+        }
+        assert!(!a.is_connected(&c.local_peer_id()));
+
+        // the rules are updated at runtime
+        a.set_connection_gate(ConnectionGate::new());
+        assert!(a.connection_gate().is_open());
+        b.dial_address(a.local_peer_id(), a_addr);
+        timeout(Duration::from_secs(5), async {
+            while !a.is_connected(&b.local_peer_id()) {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_persistent_ban() -> Result<()> {
+        tracing_try_init();
+        let tmp = TempDir::new("ipfs-embed")?;
+        let open = || {
+            let storage = StorageConfig::new(
+                Some(tmp.path().join("blocks")),
+                None,
+                10,
+                Duration::from_secs(100),
+            );
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            Box::pin(Ipfs::<DefaultParams>::new(Config { storage, network }))
+        };
+        let (mut peer, _tmp) = create_store(false).await?;
+        let other = PeerId::random();
+
+        let mut node = open().await?;
+        node.ban(peer.local_peer_id())?;
+        node.ban_for(other, Duration::from_secs(3600))?;
+        node.ban_for(PeerId::random(), Duration::from_millis(100))?;
+        async_std::task::sleep(Duration::from_millis(200)).await;
+        assert_eq!(node.banned_peers().len(), 2);
+        drop(node);
+
+        let mut node = open().await?;
+        let mut banned = node.banned_peers();
+        banned.sort_by_key(|(_, expires)| *expires);
+        assert_eq!(banned[0], (peer.local_peer_id(), None));
+        assert_eq!(banned[1].0, other);
+        assert_eq!(banned.len(), 2);
+
+        node.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+        let addr = node.listeners()[0].clone();
+        peer.dial_address(node.local_peer_id(), addr.clone());
+        async_std::task::sleep(Duration::from_secs(1)).await;
+        assert!(!node.is_connected(&peer.local_peer_id()));
+
+        node.unban(peer.local_peer_id())?;
+        peer.dial_address(node.local_peer_id(), addr);
+        timeout(Duration::from_secs(5), async {
+            while !node.is_connected(&peer.local_peer_id()) {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_address_book() -> Result<()> {
+        tracing_try_init();
+        let tmp = TempDir::new("ipfs-embed")?;
+        let open = |max_age: Duration| {
+            let storage = StorageConfig::new(
+                Some(tmp.path().join("blocks")),
+                None,
+                10,
+                Duration::from_secs(100),
+            );
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            network.kad = None;
+            network.address_book_max_age = max_age;
+            Box::pin(Ipfs::<DefaultParams>::new(Config { storage, network }))
+        };
+        let (a, _tmp) = create_store(false).await?;
+        let a_addr = a.listeners()[0].clone();
+
+        let mut node = open(Duration::from_secs(3600)).await?;
+        node.dial_address(a.local_peer_id(), a_addr);
+        timeout(Duration::from_secs(5), async {
+            while !node.is_connected(&a.local_peer_id()) {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        node.save_address_book()?;
+        drop(node);
+
+        // the address of a is known right away
+        let mut node = open(Duration::from_secs(3600)).await?;
+        assert!(node.peer_info(&a.local_peer_id()).is_some());
+        node.dial(a.local_peer_id());
+        timeout(Duration::from_secs(5), async {
+            while !node.is_connected(&a.local_peer_id()) {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        drop(node);
+
+        // outdated addresses are dropped
+        async_std::task::sleep(Duration::from_millis(10)).await;
+        let node = open(Duration::from_millis(1)).await?;
+        assert!(node.peer_info(&a.local_peer_id()).is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_disconnect() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let b_addr = b.listeners()[0].clone();
+        async fn connected(a: &Ipfs<DefaultParams>, b: &Ipfs<DefaultParams>) -> Result<()> {
+            let peer = b.local_peer_id();
+            timeout(Duration::from_secs(5), async {
+                while !a.is_connected(&peer) {
+                    async_std::task::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await?;
+            Ok(())
+        }
+
+        a.dial_address(b.local_peer_id(), b_addr.clone());
+        connected(&a, &b).await?;
+        a.disconnect(b.local_peer_id()).await?;
+        assert!(!a.is_connected(&b.local_peer_id()));
+
+        // the peer can reconnect right away
+        b.dial_address(a.local_peer_id(), a.listeners()[0].clone());
+        connected(&a, &b).await?;
+
+        // only the connections using the address are closed
+        let (_, addr, _, _) = a
+            .connections()
+            .into_iter()
+            .find(|(peer, _, _, _)| *peer == b.local_peer_id())
+            .unwrap();
+        a.disconnect_address(b.local_peer_id(), "/ip4/127.0.0.1/tcp/1".parse()?)
+            .await?;
+        assert!(a.is_connected(&b.local_peer_id()));
+        a.disconnect_address(b.local_peer_id(), addr).await?;
+        assert!(!a.is_connected(&b.local_peer_id()));
+
+        // disconnecting an unconnected peer completes immediately
+        a.disconnect(b.local_peer_id()).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_shutdown() -> Result<()> {
+        tracing_try_init();
+        let config = || {
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            // with port reuse the port could be bound twice
+            network.port_reuse = false;
+            Config { storage, network }
+        };
+        let mut a = Ipfs::<DefaultParams>::new(config()).await?;
+        let addr = match a.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await {
+            Some(ListenerEvent::NewListenAddr(addr)) => addr,
+            event => panic!("unexpected listener event {:?}", event),
+        };
+        let block = create_block(b"shutdown")?;
+        a.insert(block.clone())?;
+        let mut b = a.clone();
+        a.shutdown().await?;
+
+        // other clones fail instead of hanging
+        let err = b.get(block.cid()).unwrap_err();
+        assert!(matches!(err, Error::Shutdown(_)));
+        let err = b
+            .providers(Key::new(&block.cid().to_bytes()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Shutdown(_)));
+        assert!(matches!(
+            b.shutdown().await.unwrap_err(),
+            Error::Shutdown(_)
+        ));
+
+        // the port is free again
+        let mut c = Ipfs::<DefaultParams>::new(config()).await?;
+        let event = c.listen_on(addr.clone()).next().await;
+        assert_eq!(event, Some(ListenerEvent::NewListenAddr(addr)));
+        Ok(())
+    }
+
+    /// A backend whose garbage collector panics.
+    struct PanickingGc(MemBackend<DefaultParams>);
+
+    impl BlockStoreBackend<DefaultParams> for PanickingGc {
+        fn transaction(&mut self) -> Box<dyn BackendTransaction<DefaultParams> + '_> {
+            self.0.transaction()
+        }
+
+        fn incremental_gc(&mut self, _: usize, _: Duration) -> Result<bool> {
+            panic!("gc failed")
+        }
+
+        fn stats(&mut self) -> Result<BackendStats> {
+            self.0.stats()
+        }
+
+        fn flush(&mut self, durability: Durability) -> Result<()> {
+            self.0.flush(durability)
+        }
+
+        fn gc_handle(&mut self) -> Result<Option<Box<dyn BlockStoreBackend<DefaultParams>>>> {
+            Ok(None)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_gc_panic_reported() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(3600));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        let backend = Box::new(PanickingGc(MemBackend::new(10, None)));
+        let ipfs =
+            Ipfs::<DefaultParams>::new_with_backend(Config { storage, network }, backend).await?;
+        assert!(ipfs.health().is_healthy());
+        let mut events = ipfs.task_events();
+        ipfs.set_sweep_interval(Duration::from_millis(1));
+
+        let event = timeout(Duration::from_secs(5), events.next()).await?;
+        let failed = TaskStatus::Panicked("gc failed".into());
+        assert_eq!(
+            event,
+            Some(TaskEvent {
+                task: BackgroundTask::Gc,
+                status: failed.clone(),
+            })
+        );
+        let health = ipfs.health();
+        assert_eq!(health.gc, failed);
+        assert_eq!(health.swarm, TaskStatus::Running);
+        assert!(!health.is_healthy());
+
+        // the store is still usable
+        let block = create_block(b"after gc panic")?;
+        ipfs.insert(block.clone())?;
+        assert_eq!(ipfs.get(block.cid())?, block);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_connect() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (b, _tmp) = create_store(false).await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        a.connect(b.local_peer_id()).await?;
+        assert!(a.is_connected(&b.local_peer_id()));
+        b.wait_connected(a.local_peer_id(), Duration::from_secs(5))
+            .await?;
+
+        // nothing listens on the port of a dropped node
+        let (c, tmp) = create_store(false).await?;
+        let (peer, addr) = (c.local_peer_id(), c.listeners()[0].clone());
+        drop((c, tmp));
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        a.add_address(peer, addr);
+        let err = match a.connect(peer).await.unwrap_err() {
+            Error::NotConnected(err) => err.downcast::<ConnectFailed>().unwrap(),
+            err => panic!("unexpected error {:?}", err),
+        };
+        assert_eq!(err.peer, peer);
+        assert!(!err.failures.is_empty());
+
+        match a.wait_connected(peer, Duration::from_millis(100)).await {
+            Err(Error::Timeout(err)) => assert!(err.is::<ConnectTimeout>()),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        match a.connect(PeerId::random()).await {
+            Err(Error::NotConnected(err)) => {
+                assert!(err.downcast::<ConnectFailed>().unwrap().failures.is_empty())
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        Ok(())
+    }
+
+    async fn create_private_store(psk: PreSharedKey) -> Result<(Ipfs<DefaultParams>, TempDir)> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.psk = Some(psk);
+        // with port reuse the redials of a rejected connection reuse its address pair
+        network.port_reuse = false;
+        let mut ipfs = Ipfs::new(Config { storage, network }).await?;
+        ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)
+            .next()
+            .await
+            .unwrap();
+        Ok((ipfs, tmp))
+    }
+
+    #[async_std::test]
+    async fn test_private_network() -> Result<()> {
+        tracing_try_init();
+        let fleet = PreSharedKey::new([1; 32]);
+        let (mut a, _tmp) = create_private_store(fleet).await?;
+        let (b, _tmp) = create_private_store(fleet).await?;
+        let (mut c, _tmp) = create_private_store(PreSharedKey::new([2; 32])).await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        a.connect(b.local_peer_id()).await?;
+        assert!(a.is_connected(&b.local_peer_id()));
+
+        let assert_rejected = |err: Error| {
+            let err = match err {
+                Error::NotConnected(err) => err.downcast::<ConnectFailed>().unwrap(),
+                err => panic!("unexpected error {:?}", err),
+            };
+            assert!(!err.failures.is_empty());
+            for failure in &err.failures {
+                assert_eq!(failure.kind(), ConnectionFailureKind::PskMismatch);
+            }
+        };
+        for peer in [&a, &b] {
+            c.add_address(peer.local_peer_id(), peer.listeners()[0].clone());
+            assert_rejected(c.connect(peer.local_peer_id()).await.unwrap_err());
+            assert!(!peer.is_connected(&c.local_peer_id()));
+        }
+        a.add_address(c.local_peer_id(), c.listeners()[0].clone());
+        assert_rejected(a.connect(c.local_peer_id()).await.unwrap_err());
+        Ok(())
+    }
+
+    async fn create_request_store(
+        config: RequestResponseConfig,
+    ) -> Result<(Ipfs<DefaultParams>, TempDir)> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.request_response = Some(config);
+        let mut ipfs = Ipfs::new(Config { storage, network }).await?;
+        ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)
+            .next()
+            .await
+            .unwrap();
+        Ok((ipfs, tmp))
+    }
+
+    #[async_std::test]
+    async fn test_request_echo() -> Result<()> {
+        tracing_try_init();
+        let mut config = RequestResponseConfig::default();
+        config.protocols.insert("echo".into());
+        let max_request_size = config.max_request_size;
+        let (mut a, _tmp) = create_request_store(config.clone()).await?;
+        let (mut b, _tmp) = create_request_store(config).await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        let a_id = a.local_peer_id();
+        let mut requests = b.requests("echo");
+        async_std::task::spawn(async move {
+            while let Some(request) = requests.next().await {
+                assert_eq!(request.peer(), a_id);
+                assert_eq!(request.protocol(), "echo");
+                let data = request.data().to_vec();
+                request.respond(data).unwrap();
+            }
+        });
+
+        let response = a
+            .request(b.local_peer_id(), "echo", b"hello".to_vec())
+            .await?;
+        assert_eq!(response, b"hello");
+        let response = a.request(b.local_peer_id(), "echo", vec![]).await?;
+        assert!(response.is_empty());
+
+        // b only answers the protocols it registered
+        let err = a
+            .request(b.local_peer_id(), "other", vec![])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Other(err) if err.is::<UnsupportedProtocol>()));
+        let err = a
+            .request(b.local_peer_id(), "echo", vec![0; max_request_size + 1])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Other(err) if err.is::<PayloadTooLarge>()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_request_timeout() -> Result<()> {
+        tracing_try_init();
+        let mut config = RequestResponseConfig {
+            request_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        config.protocols.insert("silent".into());
+        let (mut a, _tmp) = create_request_store(config.clone()).await?;
+        let (mut b, _tmp) = create_request_store(config).await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        let mut requests = b.requests("silent");
+
+        let err = a
+            .request(b.local_peer_id(), "silent", b"hello".to_vec())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout(err) if err.is::<RequestTimeout>()));
+        // the request was received, but is never answered
+        let request = requests.next().await.unwrap();
+        assert_eq!(request.data(), b"hello");
+
+        let err = timeout(
+            Duration::from_millis(800),
+            a.request_with_timeout(
+                b.local_peer_id(),
+                "silent",
+                vec![],
+                Duration::from_millis(100),
+            ),
+        )
+        .await?
+        .unwrap_err();
+        assert!(matches!(err, Error::Timeout(err) if err.is::<RequestTimeout>()));
+        Ok(())
+    }
+
+    async fn create_memory_store() -> Result<(Ipfs<DefaultParams>, TempDir)> {
+        let tmp = TempDir::new("ipfs-embed")?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_millis(10000));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.transport = Some(custom_transport(MemoryTransport::default()));
+        let mut ipfs = Ipfs::new(Config { storage, network }).await?;
+        ipfs.listen_on("/memory/0".parse()?).next().await.unwrap();
+        Ok((ipfs, tmp))
+    }
+
+    #[async_std::test]
+    async fn test_custom_transport() -> Result<()> {
+        tracing_try_init();
+        let (store1, _tmp) = create_memory_store().await?;
+        let (mut store2, _tmp) = create_memory_store().await?;
+        let addr = store1.listeners()[0].clone();
+        assert!(matches!(
+            addr.iter().next(),
+            Some(multiaddr::Protocol::Memory(_))
+        ));
+        store2.add_address(store1.local_peer_id(), addr);
+
+        let block = create_block(b"test_custom_transport")?;
+        let mut tmp1 = store1.create_temp_pin()?;
+        store1.temp_pin(&mut tmp1, block.cid())?;
+        store1.insert(block.clone())?;
+        let mut tmp2 = store2.create_temp_pin()?;
+        store2.temp_pin(&mut tmp2, block.cid())?;
+        let block2 = store2
+            .fetch(block.cid(), vec![store1.local_peer_id()])
+            .await?;
+        assert_eq!(block.data(), block2.data());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_connection_info() -> Result<()> {
+        use crate::config::PingConfig;
+        tracing_try_init();
+        let create_node = |name: &str| {
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            network.node_name = name.into();
+            network.ping = Some(PingConfig::new().with_interval(Duration::from_millis(100)));
+            async move {
+                let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+                let mut ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+                ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+                Result::<_>::Ok(ipfs)
+            }
+        };
+        let mut a = create_node("node-a").await?;
+        let b = create_node("node-b").await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        a.connect(b.local_peer_id()).await?;
+
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let identified = b
+                    .peer_info(&a.local_peer_id())
+                    .map(|info| info.agent_version() == Some("node-a"))
+                    .unwrap_or_default();
+                let pinged = a
+                    .connection_info(&b.local_peer_id())
+                    .iter()
+                    .all(|info| info.rtt.is_some());
+                if identified && pinged {
+                    break;
+                }
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+
+        let info = a.connection_info(&b.local_peer_id());
+        assert_eq!(info.len(), 1);
+        assert!(info[0].endpoint.is_dialer());
+        assert_eq!(info[0].agent_version.as_deref(), Some("node-b"));
+        assert_eq!(info[0].protocol_version.as_deref(), Some("/ipfs-embed/1.0"));
+        assert!(info[0]
+            .protocols
+            .iter()
+            .any(|protocol| protocol == "/ipfs/ping/1.0.0"));
+        let info = b.connection_info(&a.local_peer_id());
+        assert_eq!(info.len(), 1);
+        assert!(!info[0].endpoint.is_dialer());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_unresponsive_peer() -> Result<()> {
+        use crate::config::PingConfig;
+        tracing_try_init();
+        let create_node = || async {
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            network.ping = Some(
+                PingConfig::new()
+                    .with_interval(Duration::from_millis(100))
+                    .with_timeout(Duration::from_millis(200)),
+            );
+            network.ping_max_failures = Some(2);
+            let mut ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+            ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+            Result::<_>::Ok(ipfs)
+        };
+        let mut a = create_node().await?;
+        let mut b = create_node().await?;
+        let mut events = a
+            .swarm_events_filtered(EventFilter::none().connections())
+            .await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        a.connect(b.local_peer_id()).await?;
+
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let rtt = a
+                    .peer_info(&b.local_peer_id())
+                    .and_then(|info| info.full_rtt());
+                if matches!(rtt, Some(rtt) if rtt.recent().len() > 1) {
+                    assert!(rtt.unwrap().min() <= rtt.unwrap().avg());
+                    break;
+                }
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+
+        b.network.suspend(Duration::from_secs(10));
+        timeout(Duration::from_secs(5), async {
+            while let Some(event) = events.next().await {
+                if event == Event::PeerUnresponsive(b.local_peer_id()) {
+                    break;
+                }
+            }
+        })
+        .await?;
+        timeout(Duration::from_secs(5), async {
+            while a.is_connected(&b.local_peer_id()) {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_swarm_events_filtered() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (b, _tmp) = create_store(false).await?;
+        let mut connections = a
+            .swarm_events_filtered(EventFilter::none().connections())
+            .await?;
+        let mut listeners = a
+            .swarm_events_filtered(EventFilter::none().listeners())
+            .await?;
+        let mut lagging = a
+            .swarm_events_filtered(EventFilter::all().buffer(1))
+            .await?;
+
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+        a.dial_address(b.local_peer_id(), b.listeners()[0].clone());
+
+        let mut connected = false;
+        while let Ok(Some(event)) = timeout(Duration::from_secs(1), connections.next()).await {
+            assert!(EventFilter::none().connections().matches(&event));
+            connected |= matches!(event, Event::Connected(peer) if peer == b.local_peer_id());
+        }
+        assert!(connected);
+        assert_eq!(connections.dropped_events(), 0);
+
+        let mut listened = false;
+        while let Ok(Some(event)) = timeout(Duration::from_secs(1), listeners.next()).await {
+            assert!(EventFilter::none().listeners().matches(&event));
+            listened |= matches!(event, Event::NewListenAddr(_, _));
+        }
+        assert!(listened);
+
+        assert!(lagging.next().await.is_some());
+        assert!(lagging.dropped_events() > 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[ignore] // test is too unreliable for ci
+    async fn test_set_mdns() -> Result<()> {
+        tracing_try_init();
+        let interval = Duration::from_secs(1);
+        let create_node = || async move {
+            let tmp = TempDir::new("ipfs-embed")?;
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = Some(MdnsConfig {
+                query_interval: interval,
+                ..Default::default()
+            });
+            network.mdns_auto_dial = false;
+            let mut ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+            ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+            Result::<_>::Ok((ipfs, tmp))
+        };
+        let (mut a, _tmp) = create_node().await?;
+        let mut events = a
+            .swarm_events_filtered(EventFilter::none().discovery())
+            .await?;
+        let (b, _tmp) = create_node().await?;
+        timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.next().await {
+                if matches!(event, Event::MdnsDiscovered(peer, _) if peer == b.local_peer_id()) {
+                    break;
+                }
+            }
+        })
+        .await?;
+        // discoveries are left to the application
+        assert!(!a.is_connected(&b.local_peer_id()));
+
+        a.set_mdns(None).await?;
+        let (c, _tmp) = create_node().await?;
+        while let Ok(Some(event)) = timeout(interval * 2, events.next()).await {
+            assert!(!matches!(event, Event::MdnsDiscovered(peer, _) if peer == c.local_peer_id()));
+        }
+
+        a.set_mdns(Some(MdnsConfig {
+            query_interval: interval,
+            ..Default::default()
+        }))
+        .await?;
+        timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.next().await {
+                if matches!(event, Event::MdnsDiscovered(peer, _) if peer == c.local_peer_id()) {
+                    break;
+                }
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gossip_metrics() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let registry = Registry::new();
+        a.register_metrics(&registry)?;
+        let value = |name: &str, topic: &str| {
+            registry
+                .gather()
+                .iter()
+                .filter(|family| family.get_name() == name)
+                .flat_map(|family| family.get_metric())
+                .filter(|metric| metric.get_label().iter().any(|l| l.get_value() == topic))
+                .map(|metric| metric.get_counter().get_value() + metric.get_gauge().get_value())
+                .sum::<f64>()
+        };
+
+        let topic = "test_gossip_metrics".to_owned();
+        let _subscription_a = a.subscribe(topic.clone()).await?;
+        let mut subscription_b = b.subscribe(topic.clone()).await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        a.connect(b.local_peer_id()).await?;
+        timeout(Duration::from_secs(5), async {
+            while !a.topic_peers(&topic).contains(&b.local_peer_id()) {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+
+        a.publish(topic.clone(), b"metrics".to_vec()).await?;
+        timeout(Duration::from_secs(5), async {
+            while let Some(event) = subscription_b.next().await {
+                if matches!(event, GossipEvent::Message(..)) {
+                    break;
+                }
+            }
+        })
+        .await?;
+        b.publish(topic.clone(), b"metrics".to_vec()).await?;
+        timeout(Duration::from_secs(5), async {
+            while value("gossipsub_messages_received_total", &topic) == 0.0 {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        assert!(value("gossipsub_messages_published_total", &topic) > 0.0);
+        timeout(Duration::from_secs(5), async {
+            while value("gossipsub_mesh_peers", &topic) == 0.0 {
+                async_std::task::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_topic_peers() -> Result<()> {
+        tracing_try_init();
+        let mut stores = [
+            create_store(false).await?,
+            create_store(false).await?,
+            create_store(false).await?,
+            create_store(false).await?,
+        ];
+        let topic = "topic".to_owned();
+        let observer = stores[0].0.local_peer_id();
+        let addr = stores[0].0.listeners()[0].clone();
+        for (store, _) in &mut stores[1..] {
+            store.dial_address(observer, addr.clone());
+        }
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        assert!(stores[0].0.subscribed_topics().is_empty());
+
+        let mut subscriptions = vec![];
+        for (store, _) in &mut stores[1..] {
+            subscriptions.push(store.subscribe(topic.clone()).await?);
+            assert_eq!(store.subscribed_topics(), vec![topic.clone()]);
+        }
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        let mut peers = stores[0].0.topic_peers(&topic);
+        peers.sort();
+        let mut expected = stores[1..]
+            .iter()
+            .map(|(store, _)| store.local_peer_id())
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(peers, expected);
+        assert!(stores[0].0.topic_peers("other").is_empty());
+
+        stores[1].0.disconnect(observer).await?;
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert!(!stores[0]
+            .0
+            .topic_peers(&topic)
+            .contains(&stores[1].0.local_peer_id()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gossip_validation() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let (mut c, _tmp) = create_store(false).await?;
+        a.dial_address(b.local_peer_id(), b.listeners()[0].clone());
+        c.dial_address(b.local_peer_id(), b.listeners()[0].clone());
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        assert!(!a.is_connected(&c.local_peer_id()));
+
+        let rejected = "rejected".to_owned();
+        let accepted = "accepted".to_owned();
+        let _subscription_a = a.subscribe(rejected.clone()).await?;
+        let _subscription_a2 = a.subscribe(accepted.clone()).await?;
+        let subscription_b = b
+            .subscribe_with_validator(rejected.clone(), |_, _| ValidationResult::Reject)
+            .await?;
+        let _subscription_b2 = b
+            .subscribe_with_validator(accepted.clone(), |_, data| {
+                if data == b"valid" {
+                    ValidationResult::Accept
+                } else {
+                    ValidationResult::Ignore
+                }
+            })
+            .await?;
+        let subscription_c = c.subscribe(rejected.clone()).await?;
+        let mut subscription_c2 = c.subscribe(accepted.clone()).await?;
+        async_std::task::sleep(Duration::from_millis(1000)).await;
+
+        a.publish(rejected, b"invalid".to_vec()).await?;
+        a.publish(accepted, b"valid".to_vec()).await?;
+        loop {
+            let event = timeout(Duration::from_secs(5), subscription_c2.next())
+                .await?
+                .unwrap();
+            if let GossipEvent::Message(peer, data, _) = event {
+                assert_eq!(peer, a.local_peer_id());
+                assert_eq!(&*data, b"valid");
+                break;
+            }
+        }
+        for mut subscription in [subscription_b.boxed(), subscription_c.boxed()] {
+            while let Ok(event) = timeout(Duration::from_millis(500), subscription.next()).await {
+                assert!(
+                    !matches!(event, Some(GossipEvent::Message(..))),
+                    "received {:?}",
+                    event
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_gossip_peer_score() -> Result<()> {
+        use crate::config::{PeerScoreParams, PeerScoreThresholds, TopicScoreParams};
+        use libp2p::gossipsub::IdentTopic;
+        tracing_try_init();
+        let topic = "topic".to_owned();
+        let mut stores = vec![];
+        for _ in 0..2 {
+            let mut params = PeerScoreParams::default();
+            params.topics.insert(
+                IdentTopic::new(&topic).hash(),
+                TopicScoreParams {
+                    topic_weight: 1.0,
+                    // the default rewards every millisecond in the mesh
+                    time_in_mesh_weight: 0.0,
+                    invalid_message_deliveries_weight: -100.0,
+                    // the default decays the penalty before it is checked
+                    invalid_message_deliveries_decay: 0.99,
+                    ..Default::default()
+                },
+            );
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            network.gossipsub_scoring = Some((params, PeerScoreThresholds::default()));
+            let mut ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+            ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+            stores.push(ipfs);
+        }
+        let mut b = stores.pop().unwrap();
+        let mut a = stores.pop().unwrap();
+        let mut events = b
+            .swarm_events_filtered(EventFilter::none().gossip())
+            .await?;
+        a.dial_address(b.local_peer_id(), b.listeners()[0].clone());
+        let _subscription_a = a.subscribe(topic.clone()).await?;
+        let _subscription_b = b
+            .subscribe_with_validator(topic.clone(), |_, _| ValidationResult::Reject)
+            .await?;
+        async_std::task::sleep(Duration::from_millis(1500)).await;
+        assert!(b.peer_score(&a.local_peer_id()).unwrap() >= 0.0);
+
+        a.publish(topic, b"invalid".to_vec()).await?;
+        async_std::task::sleep(Duration::from_millis(1500)).await;
+        assert!(b.peer_score(&a.local_peer_id()).unwrap() < -80.0);
+        let mut graylisted = false;
+        while let Ok(Some(event)) = timeout(Duration::from_millis(100), events.next()).await {
+            graylisted |= event == Event::GossipGraylisted(a.local_peer_id());
+        }
+        assert!(graylisted);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_verify_corrupt_block() -> Result<()> {
+        tracing_try_init();
+        let tmp = TempDir::new("ipfs-embed")?;
+        let block = create_block(b"test_verify_corrupt_block")?;
+        let storage =
+            StorageConfig::new(Some(tmp.path().into()), None, 0, Duration::from_secs(3600));
+        {
+            let store = StorageService::<DefaultParams>::open(storage.clone(), Executor::new())?;
+            store.insert(block.clone())?;
+            store.alias(b"root", Some(block.cid()))?;
+            store.flush().await?;
+        }
+
+        // flip a byte of the block data in the database file
+        let path = tmp.path().join("db");
+        let mut db = std::fs::read(&path)?;
+        let pos = db
+            .windows(block.data().len())
+            .position(|data| data == block.data())
+            .unwrap();
+        db[pos] ^= 0xff;
+        std::fs::write(&path, db)?;
+
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        let ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let events = ipfs
+            .verify(VerifyConfig::default())
+            .collect::<Vec<_>>()
+            .await;
+        assert!(events.contains(&VerifyEvent::Corrupt(*block.cid())));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, VerifyEvent::Failed(_))));
+        Ok(())
+    }
+
+    macro_rules! assert_pinned {
+        ($store:expr, $block:expr) => {
+            assert_eq!(
+                $store
+                    .pin_status($block.cid())
+                    .unwrap()
+                    .map(|status| status.is_retained()),
+                Some(true)
+            );
+        };
+    }
+
+    macro_rules! assert_unpinned {
+        ($store:expr, $block:expr) => {
+            assert_eq!(
+                $store
+                    .pin_status($block.cid())
+                    .unwrap()
+                    .map(|status| status.is_retained()),
+                Some(false)
+            );
+        };
+    }
+
+    fn create_ipld_block(ipld: &Ipld) -> Result<Block<DefaultParams>> {
+        Block::encode(DagCborCodec, Code::Blake3_256, ipld)
+    }
+
+    #[async_std::test]
+    async fn test_sync() -> Result<()> {
+        tracing_try_init();
+        let (mut local1, _tmp) = create_store(false).await?;
+        let (mut local2, _tmp) = create_store(false).await?;
+        local1.add_address(local2.local_peer_id(), local2.listeners()[0].clone());
+        local2.add_address(local1.local_peer_id(), local1.listeners()[0].clone());
+
+        let a1 = create_ipld_block(&ipld!({ "a": 0 }))?;
+        let b1 = create_ipld_block(&ipld!({ "b": 0 }))?;
+        let c1 = create_ipld_block(&ipld!({ "c": [a1.cid(), b1.cid()] }))?;
+        let b2 = create_ipld_block(&ipld!({ "b": 1 }))?;
+        let c2 = create_ipld_block(&ipld!({ "c": [a1.cid(), b2.cid()] }))?;
+        let x = alias!(x);
+
+        local1.insert(a1.clone())?;
+        local1.insert(b1.clone())?;
+        local1.insert(c1.clone())?;
+        local1.alias(x, Some(c1.cid()))?;
+        local1.flush().await?;
+        assert_pinned!(&local1, &a1);
+        assert_pinned!(&local1, &b1);
+        assert_pinned!(&local1, &c1);
+
+        local2.alias(&x, Some(c1.cid()))?;
+        local2
+            .sync(c1.cid(), vec![local1.local_peer_id()])
+            .await?
+            .await?;
+        local2.flush().await?;
+        assert_pinned!(&local2, &a1);
+        assert_pinned!(&local2, &b1);
+        assert_pinned!(&local2, &c1);
+
+        local2.insert(b2.clone())?;
+        local2.insert(c2.clone())?;
+        local2.alias(x, Some(c2.cid()))?;
+        local2.flush().await?;
+        assert_pinned!(&local2, &a1);
+        assert_unpinned!(&local2, &b1);
+        assert_unpinned!(&local2, &c1);
+        assert_pinned!(&local2, &b2);
+        assert_pinned!(&local2, &c2);
+
+        local1.alias(x, Some(c2.cid()))?;
+        local1
+            .sync(c2.cid(), vec![local2.local_peer_id()])
+            .await?
+            .await?;
+        local1.flush().await?;
+        assert_pinned!(&local1, &a1);
+        assert_unpinned!(&local1, &b1);
+        assert_unpinned!(&local1, &c1);
+        assert_pinned!(&local1, &b2);
+        assert_pinned!(&local1, &c2);
+
+        local2.alias(x, None)?;
+        local2.flush().await?;
+        assert_unpinned!(&local2, &a1);
+        assert_unpinned!(&local2, &b1);
+        assert_unpinned!(&local2, &c1);
+        assert_unpinned!(&local2, &b2);
+        assert_unpinned!(&local2, &c2);
+
+        local1.alias(x, None)?;
+        local2.flush().await?;
+        assert_unpinned!(&local1, &a1);
+        assert_unpinned!(&local1, &b1);
+        assert_unpinned!(&local1, &c1);
+        assert_unpinned!(&local1, &b2);
+        assert_unpinned!(&local1, &c2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_block_events() -> Result<()> {
+        tracing_try_init();
+        let (mut local1, _tmp) = create_store(false).await?;
+        let (mut local2, _tmp) = create_store(false).await?;
+        local1.add_address(local2.local_peer_id(), local2.listeners()[0].clone());
+        local2.add_address(local1.local_peer_id(), local1.listeners()[0].clone());
+        let mut sent = local1
+            .swarm_events_filtered(EventFilter::none().bitswap())
+            .await?;
+        let mut received = local2
+            .swarm_events_filtered(EventFilter::none().bitswap())
+            .await?;
+
+        let a = create_ipld_block(&ipld!({ "a": 0 }))?;
+        let b = create_ipld_block(&ipld!({ "b": [a.cid()] }))?;
+        local1.insert(a.clone())?;
+        local1.insert(b.clone())?;
+        local1.alias(alias!(x), Some(b.cid()))?;
+        local2.alias(alias!(x), Some(b.cid()))?;
+        local2
+            .sync(b.cid(), vec![local1.local_peer_id()])
+            .await?
+            .await?;
+
+        let mut cids = HashSet::new();
+        while let Ok(Some(event)) = timeout(Duration::from_millis(100), received.next()).await {
+            if let Event::BlockReceived(cid, size) = event {
+                let block = if cid == *a.cid() { &a } else { &b };
+                assert_eq!(size, block.data().len());
+                cids.insert(cid);
+            }
+        }
+        assert_eq!(cids, vec![*a.cid(), *b.cid()].into_iter().collect());
+        let mut cids = HashSet::new();
+        while let Ok(Some(event)) = timeout(Duration::from_millis(100), sent.next()).await {
+            if let Event::BlockSent(cid, _) = event {
+                cids.insert(cid);
+            }
+        }
+        assert_eq!(cids, vec![*a.cid(), *b.cid()].into_iter().collect());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_abort_on_drop() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        // without a cache, only the temp pin of the sync retains the blocks
+        let storage = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        let mut b = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let root = alias!(root);
+
+        let (cid, blocks) = test_util::build_tree(1, 1000)?;
+        a.alias(root, Some(&cid))?;
+        for block in blocks.iter() {
+            a.insert(block.clone())?;
+        }
+        a.flush().await?;
+
+        let mut query = b.sync(&cid, vec![a.local_peer_id()]).await?;
+        query.next().await.unwrap();
+        query.abort();
+
+        // the bitswap query is removed
+        timeout(Duration::from_secs(5), async {
+            while b.bitswap_stats().running_queries > 0 {
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        // let responses that were already in flight settle
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        // the temp pin of the sync is released
+        b.evict().await?;
+        assert_eq!(b.iter()?.count(), 0);
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        assert_eq!(b.iter()?.count(), 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_progress() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        a.add_address(b.local_peer_id(), b.listeners()[0].clone());
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        let root = alias!(root);
+
+        let (cid, blocks) = test_util::build_tree(1, 10)?;
+        a.alias(root, Some(&cid))?;
+        b.alias(root, Some(&cid))?;
+        for block in blocks.iter() {
+            a.insert(block.clone())?;
+        }
+        a.flush().await?;
+
+        let size: u64 = blocks.iter().map(|block| block.data().len() as u64).sum();
+        let mut last = None;
+        let mut query = b.sync(&cid, vec![a.local_peer_id()]).await?;
+        while let Some(event) = query.next().await {
+            match event {
+                SyncEvent::Progress {
+                    missing,
+                    blocks,
+                    bytes,
+                    providers,
+                } => {
+                    assert_eq!(providers, 1);
+                    last = Some((blocks, bytes, missing));
+                }
+                SyncEvent::ProvidersAdded { .. } => panic!("sync via dht is disabled"),
+                SyncEvent::Complete(res) => res?,
+            }
+        }
+        assert_eq!(last, Some((blocks.len(), size, 0)));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_progress_concurrent() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+        // concurrent dials from the reused listen port to the same address collide
+        b.connect(a.local_peer_id()).await?;
+
+        // two chains without common blocks, the root is the last block
+        let chain = |name: &str, len: u64| -> Result<Vec<Block<DefaultParams>>> {
+            let mut blocks: Vec<Block<DefaultParams>> = vec![];
+            for i in 0..len {
+                let ipld = match blocks.last() {
+                    Some(prev) => ipld!({ "name": name, "i": i, "prev": *prev.cid() }),
+                    None => ipld!({ "name": name, "i": i }),
+                };
+                blocks.push(create_ipld_block(&ipld)?);
+            }
+            Ok(blocks)
+        };
+        let chains = [chain("a", 20)?, chain("b", 30)?];
+        for block in chains.iter().flatten() {
+            a.insert(block.clone())?;
+        }
+        a.flush().await?;
+
+        let progress = |blocks: &Vec<Block<DefaultParams>>| {
+            let root = *blocks.last().unwrap().cid();
+            let b = b.clone();
+            let provider = a.local_peer_id();
+            async move {
+                let mut last = None;
+                let mut query = b.sync(&root, vec![provider]).await?;
+                while let Some(event) = query.next().await {
+                    match event {
+                        SyncEvent::Progress {
+                            missing,
+                            blocks,
+                            bytes,
+                            ..
+                        } => last = Some((blocks, bytes, missing)),
+                        SyncEvent::ProvidersAdded { .. } => panic!("sync via dht is disabled"),
+                        SyncEvent::Complete(res) => res?,
+                    }
+                }
+                Ok::<_, anyhow::Error>(last)
+            }
+        };
+        let (first, second) = join!(progress(&chains[0]), progress(&chains[1]));
+        // the bytes of each block are credited to the sync that requested it
+        for (last, blocks) in [first?, second?].iter().zip(chains.iter()) {
+            let size: u64 = blocks.iter().map(|block| block.data().len() as u64).sum();
+            assert_eq!(*last, Some((blocks.len(), size, 0)));
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_pipelined() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.sync_pipeline = Some(SyncPipelineConfig {
+            max_in_flight: 8,
+            traversal: SyncTraversal::BreadthFirst,
+        });
+        let mut b = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+
+        let (cid, blocks) = test_util::build_tree(3, 3)?;
+        a.alias(b"root", Some(&cid))?;
+        b.alias(b"root", Some(&cid))?;
+        for block in &blocks {
+            a.insert(block.clone())?;
+        }
+        let size: u64 = blocks.iter().map(|block| block.data().len() as u64).sum();
+        let mut last = None;
+        let mut query = b.sync(&cid, vec![a.local_peer_id()]).await?;
+        while let Some(event) = query.next().await {
+            match event {
+                SyncEvent::Progress {
+                    missing,
+                    blocks,
+                    bytes,
+                    ..
+                } => last = Some((missing, blocks, bytes)),
+                SyncEvent::ProvidersAdded { .. } => panic!("sync via dht is disabled"),
+                SyncEvent::Complete(res) => res?,
+            }
+        }
+        assert_eq!(last, Some((0, blocks.len(), size)));
+        for block in &blocks {
+            assert!(b.contains(block.cid())?);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_scheduler_fairness() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.sync_scheduler = Some(SyncSchedulerConfig {
+            max_outstanding: 2,
+            ..Default::default()
+        });
+        let mut b = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+
+        let mut roots = vec![];
+        for i in 0..2u8 {
+            let (cid, blocks) = test_util::build_tree(3, 3)?;
+            a.alias([b'a' + i], Some(&cid))?;
+            b.alias([b'a' + i], Some(&cid))?;
+            for block in &blocks {
+                a.insert(block.clone())?;
+            }
+            roots.push(cid);
+        }
+        let mut queries = vec![];
+        for (i, cid) in roots.iter().enumerate() {
+            let query = b.sync(cid, vec![a.local_peer_id()]).await?;
+            queries.push(StreamExt::map(query, move |event| (i, event)));
+        }
+        let mut events = futures::stream::select_all(queries);
+        let mut progress = [0; 2];
+        let mut complete = 0;
+        while let Some((i, event)) = events.next().await {
+            match event {
+                SyncEvent::Progress { .. } => progress[i] += 1,
+                SyncEvent::ProvidersAdded { .. } => panic!("sync via dht is disabled"),
+                SyncEvent::Complete(res) => {
+                    res?;
+                    // the other sync made progress before this one completed
+                    assert!(complete > 0 || progress[1 - i] > 0, "{:?}", progress);
+                    complete += 1;
+                }
+            }
+            assert!(b.bitswap_stats().outstanding_wants <= 2);
+        }
+        assert_eq!(complete, 2);
+        Ok(())
     }
 
     #[async_std::test]
-    async fn test_sync() -> Result<()> {
+    async fn test_sync_via_dht() -> Result<()> {
         tracing_try_init();
-        let (mut local1, _tmp) = create_store(false).await?;
-        let (mut local2, _tmp) = create_store(false).await?;
-        local1.add_address(local2.local_peer_id(), local2.listeners()[0].clone());
-        local2.add_address(local1.local_peer_id(), local1.listeners()[0].clone());
-
-        let a1 = create_ipld_block(&ipld!({ "a": 0 }))?;
-        let b1 = create_ipld_block(&ipld!({ "b": 0 }))?;
-        let c1 = create_ipld_block(&ipld!({ "c": [a1.cid(), b1.cid()] }))?;
-        let b2 = create_ipld_block(&ipld!({ "b": 1 }))?;
-        let c2 = create_ipld_block(&ipld!({ "c": [a1.cid(), b2.cid()] }))?;
-        let x = alias!(x);
-
-        local1.insert(a1.clone())?;
-        local1.insert(b1.clone())?;
-        local1.insert(c1.clone())?;
-        local1.alias(x, Some(c1.cid()))?;
-        local1.flush().await?;
-        assert_pinned!(&local1, &a1);
-        assert_pinned!(&local1, &b1);
-        assert_pinned!(&local1, &c1);
-
-        local2.alias(&x, Some(c1.cid()))?;
-        local2
-            .sync(c1.cid(), vec![local1.local_peer_id()])
-            .await?
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.sync_via_dht = Some(DhtSyncConfig {
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        });
+        let mut c = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        c.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)
+            .next()
+            .await
+            .unwrap();
+        a.bootstrap(vec![(b.local_peer_id(), b.listeners()[0].clone())])
             .await?;
-        local2.flush().await?;
-        assert_pinned!(&local2, &a1);
-        assert_pinned!(&local2, &b1);
-        assert_pinned!(&local2, &c1);
-
-        local2.insert(b2.clone())?;
-        local2.insert(c2.clone())?;
-        local2.alias(x, Some(c2.cid()))?;
-        local2.flush().await?;
-        assert_pinned!(&local2, &a1);
-        assert_unpinned!(&local2, &b1);
-        assert_unpinned!(&local2, &c1);
-        assert_pinned!(&local2, &b2);
-        assert_pinned!(&local2, &c2);
-
-        local1.alias(x, Some(c2.cid()))?;
-        local1
-            .sync(c2.cid(), vec![local2.local_peer_id()])
-            .await?
+        b.bootstrap(vec![(a.local_peer_id(), a.listeners()[0].clone())])
+            .await?;
+        c.bootstrap(vec![(a.local_peer_id(), a.listeners()[0].clone())])
             .await?;
-        local1.flush().await?;
-        assert_pinned!(&local1, &a1);
-        assert_unpinned!(&local1, &b1);
-        assert_unpinned!(&local1, &c1);
-        assert_pinned!(&local1, &b2);
-        assert_pinned!(&local1, &c2);
 
-        local2.alias(x, None)?;
-        local2.flush().await?;
-        assert_unpinned!(&local2, &a1);
-        assert_unpinned!(&local2, &b1);
-        assert_unpinned!(&local2, &c1);
-        assert_unpinned!(&local2, &b2);
-        assert_unpinned!(&local2, &c2);
+        // the root is on a, the leaves only on b
+        let (cid, blocks) = test_util::build_tree(2, 1)?;
+        let (root, leaves) = blocks.split_last().unwrap();
+        a.insert(root.clone())?;
+        for leaf in leaves {
+            b.insert(leaf.clone())?;
+            b.provide(Key::new(&leaf.cid().to_bytes())).await?;
+        }
+        a.alias(b"root", Some(&cid))?;
+        b.alias(b"root", Some(&cid))?;
+        c.alias(b"root", Some(&cid))?;
 
-        local1.alias(x, None)?;
-        local2.flush().await?;
-        assert_unpinned!(&local1, &a1);
-        assert_unpinned!(&local1, &b1);
-        assert_unpinned!(&local1, &c1);
-        assert_unpinned!(&local1, &b2);
-        assert_unpinned!(&local1, &c2);
+        let mut query = c.sync(&cid, vec![a.local_peer_id()]).await?;
+        let mut added = vec![];
+        while let Some(event) = timeout(Duration::from_secs(30), query.next()).await? {
+            match event {
+                SyncEvent::Progress { .. } => {}
+                SyncEvent::ProvidersAdded { providers, .. } => added.extend(providers),
+                SyncEvent::Complete(res) => res?,
+            }
+        }
+        assert!(added.contains(&b.local_peer_id()));
+        for block in &blocks {
+            assert!(c.contains(block.cid())?);
+        }
         Ok(())
     }
 
@@ -765,6 +4089,310 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_dht_record_republish() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        a.bootstrap(vec![(b.local_peer_id(), b.listeners()[0].clone())])
+            .await?;
+        b.bootstrap(vec![(a.local_peer_id(), a.listeners()[0].clone())])
+            .await?;
+        async_std::task::sleep(Duration::from_millis(500)).await;
+
+        let options = PutOptions {
+            ttl: Some(Duration::from_secs(2)),
+            republish: true,
+        };
+        let key: Key = b"republished".to_vec().into();
+        let record = Record::new(key.clone(), b"hello world".to_vec());
+        a.put_record_with(record, Quorum::One, options).await?;
+        let expiring: Key = b"expiring".to_vec().into();
+        let mut record = Record::new(expiring.clone(), b"hello world".to_vec());
+        record.expires = Some(std::time::Instant::now() + Duration::from_secs(3));
+        a.put_record_with(record, Quorum::One, options).await?;
+        assert_eq!(a.published_records().len(), 2);
+
+        async_std::task::sleep(Duration::from_secs(5)).await;
+        let records = b.get_record(key.clone(), Quorum::One).await?;
+        assert_eq!(records.len(), 1);
+        let published = a.published_records();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].record.key, key);
+        assert!(b.get_record(expiring, Quorum::One).await.is_err());
+
+        a.remove_record(key)?;
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert!(a.published_records().is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_ipns() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        a.bootstrap(vec![(b.local_peer_id(), b.listeners()[0].clone())])
+            .await?;
+        b.bootstrap(vec![(a.local_peer_id(), a.listeners()[0].clone())])
+            .await?;
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        let peer = a.local_peer_id();
+        let first = *create_block(b"first")?.cid();
+        let second = *create_block(b"second")?.cid();
+
+        a.name_publish(&first, Duration::from_secs(60)).await?;
+        assert_eq!(b.name_resolve(&peer).await?, first);
+        let old = a.published_records()[0].record.clone();
+        a.name_publish(&second, Duration::from_secs(60)).await?;
+        assert_eq!(a.published_records().len(), 1);
+        assert_eq!(b.name_resolve(&peer).await?, second);
+
+        // the record with the older sequence number is rejected once the
+        // newer one was seen
+        a.put_record(old, Quorum::One).await?;
+        let err = match b.name_resolve(&peer).await.unwrap_err() {
+            Error::Other(err) => err,
+            err => panic!("unexpected error {:?}", err),
+        };
+        let stale = err.downcast_ref::<StaleIpnsRecord>().unwrap();
+        assert_eq!((stale.sequence, stale.latest), (0, 1));
+
+        // records expire with their lifetime
+        a.name_publish(&first, Duration::from_secs(2)).await?;
+        assert_eq!(b.name_resolve(&peer).await?, first);
+        async_std::task::sleep(Duration::from_secs(3)).await;
+        assert!(b.name_resolve(&peer).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[ignore]
+    async fn test_resolve_dnslink() -> Result<()> {
+        tracing_try_init();
+        let (store, _tmp) = create_store(false).await?;
+        let cid = store.resolve_dnslink("docs.ipfs.tech").await?;
+        tracing::info!("docs.ipfs.tech resolves to {}", cid);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_reprovide_roots() -> Result<()> {
+        tracing_try_init();
+        let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        let mut network = NetworkConfig::new(Keypair::generate());
+        network.mdns = None;
+        network.reprovide = ReprovidePolicy::Roots;
+        network.reprovide_interval = Duration::from_secs(1);
+        let mut a = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        a.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)
+            .next()
+            .await
+            .unwrap();
+        let (mut b, _tmp) = create_store(false).await?;
+        a.bootstrap(vec![(b.local_peer_id(), b.listeners()[0].clone())])
+            .await?;
+        b.bootstrap(vec![(a.local_peer_id(), a.listeners()[0].clone())])
+            .await?;
+
+        let root = create_block(b"test_reprovide_roots")?;
+        let other = create_block(b"test_reprovide_roots_other")?;
+        a.insert(root.clone())?;
+        a.insert(other.clone())?;
+        a.alias(b"root", Some(root.cid()))?;
+        async_std::task::sleep(Duration::from_millis(2500)).await;
+
+        let providers = b.providers(Key::new(&root.cid().to_bytes())).await?;
+        assert!(providers.contains(&a.local_peer_id()));
+        let providers = b
+            .providers(Key::new(&other.cid().to_bytes()))
+            .await
+            .unwrap_or_default();
+        assert!(!providers.contains(&a.local_peer_id()));
+        assert_eq!(a.reprovide_now().await?, 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_rebootstrap() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let rebootstrap = RebootstrapConfig {
+            check_interval: Duration::from_millis(100),
+            initial_backoff: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut stores = vec![];
+        for bootstrap in [
+            (a.local_peer_id(), a.listeners()[0].clone()),
+            (PeerId::random(), "/ip4/127.0.0.1/tcp/1".parse()?),
+        ] {
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            network.bootstrap = vec![bootstrap];
+            network.rebootstrap = Some(rebootstrap);
+            let mut ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+            let events = ipfs
+                .swarm_events_filtered(EventFilter::none().dht())
+                .await?;
+            stores.push((ipfs, events));
+        }
+
+        // the startup bootstrap runs in the background, losing the
+        // connection to the bootstrap node triggers a rebootstrap
+        let (b, events) = &mut stores[0];
+        while !b.is_connected(&a.local_peer_id()) {
+            async_std::task::sleep(Duration::from_millis(50)).await;
+        }
+        b.disconnect(a.local_peer_id()).await?;
+        let mut expected = vec![Event::RebootstrapStarted, Event::RebootstrapSucceeded];
+        while !expected.is_empty() {
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await?
+                .unwrap();
+            expected.retain(|e| *e != event);
+        }
+        assert!(b.is_bootstrapped());
+        assert!(b.is_connected(&a.local_peer_id()));
+
+        let (_, events) = &mut stores[1];
+        let mut failures = 0;
+        while failures < 2 {
+            let event = timeout(Duration::from_secs(5), events.next())
+                .await?
+                .unwrap();
+            if let Event::RebootstrapFailed(_) = event {
+                failures += 1;
+            }
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_startup_listen_and_bootstrap() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let config = |listen: &str| -> Result<Config> {
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            network.rebootstrap = None;
+            network.listen_addresses = vec![listen.parse()?];
+            network.bootstrap = vec![(a.local_peer_id(), a.listeners()[0].clone())];
+            Ok(Config { storage, network })
+        };
+
+        let b = Ipfs::<DefaultParams>::new(config("/ip4/127.0.0.1/tcp/0")?).await?;
+        assert!(!b.listeners().is_empty());
+        timeout(Duration::from_secs(5), async {
+            while !b.is_bootstrapped() {
+                async_std::task::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await?;
+        assert!(b.is_connected(&a.local_peer_id()));
+
+        let err = Ipfs::<DefaultParams>::new(config("/ip4/127.0.0.1/udp/0/quic")?)
+            .await
+            .err()
+            .unwrap();
+        match err {
+            Error::Other(err) => {
+                let err = err.downcast::<ListenFailed>().unwrap();
+                assert_eq!(err.addr, "/ip4/127.0.0.1/udp/0/quic".parse::<Multiaddr>()?);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_bootstrap_addrs() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let (b, _tmp) = create_store(false).await?;
+        let (mut c, _tmp) = create_store(false).await?;
+        let mut addr_b = b.listeners()[0].clone();
+        addr_b.push(multiaddr::Protocol::P2p(b.local_peer_id().into()));
+        let unreachable: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse()?;
+        let results = c
+            .bootstrap_addrs(vec![
+                a.listeners()[0].clone(),
+                addr_b.clone(),
+                unreachable.clone(),
+            ])
+            .await?;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, a.listeners()[0]);
+        assert_eq!(*results[0].1.as_ref().unwrap(), a.local_peer_id());
+        assert_eq!(results[1].0, addr_b);
+        assert_eq!(*results[1].1.as_ref().unwrap(), b.local_peer_id());
+        assert_eq!(results[2].0, unreachable);
+        assert!(results[2].1.is_err());
+        assert!(c.is_bootstrapped());
+        assert!(c.is_connected(&a.local_peer_id()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_get_closest_peers() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let (mut c, _tmp) = create_store(false).await?;
+        let node_a = (a.local_peer_id(), a.listeners()[0].clone());
+        let node_b = (b.local_peer_id(), b.listeners()[0].clone());
+        let node_c = (c.local_peer_id(), c.listeners()[0].clone());
+
+        // concurrent dials from the reused listen port to the same address collide
+        for (peer, addr) in [&node_a, &node_c] {
+            b.add_address(*peer, addr.clone());
+            b.connect(*peer).await?;
+        }
+        b.bootstrap(vec![node_a, node_c.clone()]).await?;
+        a.bootstrap(vec![node_b.clone()]).await?;
+        c.bootstrap(vec![node_b]).await?;
+
+        let peers = a.get_closest_peers(c.local_peer_id()).await?;
+        let (_, addrs) = peers
+            .into_iter()
+            .find(|(peer, _)| *peer == c.local_peer_id())
+            .expect("node c was not found");
+        let expected = node_c.1.to_string();
+        assert!(
+            addrs
+                .iter()
+                .any(|addr| addr.to_string().starts_with(&expected)),
+            "{:?}",
+            addrs
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_providers_stream() -> Result<()> {
+        tracing_try_init();
+        let (mut a, _tmp) = create_store(false).await?;
+        let (mut b, _tmp) = create_store(false).await?;
+        let (mut c, _tmp) = create_store(false).await?;
+        let node_a = (a.local_peer_id(), a.listeners()[0].clone());
+        let node_b = (b.local_peer_id(), b.listeners()[0].clone());
+        let node_c = (c.local_peer_id(), c.listeners()[0].clone());
+
+        b.bootstrap(vec![node_a, node_c]).await?;
+        a.bootstrap(vec![node_b.clone()]).await?;
+        c.bootstrap(vec![node_b]).await?;
+
+        let key: Key = b"providers_stream".to_vec().into();
+        a.provide(key.clone()).await?;
+        c.provide(key.clone()).await?;
+
+        let providers = c.providers_stream(key).collect::<Vec<_>>().await;
+        assert_eq!(providers, vec![a.local_peer_id()]);
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_gossip_and_broadcast() -> Result<()> {
         tracing_try_init();
@@ -796,10 +4424,9 @@ mod tests {
         }
 
         // TCP sim open redials may take a second
-        async_std::task::sleep(Duration::from_millis(1500)).await;
         for (store, _) in &stores {
             for (peer, _) in &others {
-                assert!(store.is_connected(peer));
+                store.wait_connected(*peer, Duration::from_secs(5)).await?;
             }
         }
         for (store, _) in &mut stores {
@@ -807,7 +4434,7 @@ mod tests {
         }
         async_std::task::sleep(Duration::from_millis(500)).await;
 
-        stores[0]
+        let id = stores[0]
             .0
             .publish(topic.clone(), b"hello gossip".to_vec())
             .await
@@ -839,6 +4466,7 @@ mod tests {
                     Box::new(std::iter::once(GossipEvent::Message(
                         stores[0].0.local_peer_id(),
                         b"hello gossip".to_vec().into(),
+                        Some(id.clone()),
                     ))) as Box<dyn Iterator<Item = GossipEvent>>
                 } else {
                     Box::new(std::iter::empty())
@@ -876,9 +4504,10 @@ mod tests {
 
         for subscription in &mut subscriptions[1..] {
             match subscription.next().await.unwrap() {
-                GossipEvent::Message(p, data) => {
+                GossipEvent::Message(p, data, id) => {
                     assert_eq!(p, stores[0].0.local_peer_id());
                     assert_eq!(data[..], b"hello broadcast"[..]);
+                    assert_eq!(id, None);
                 }
                 x => {
                     panic!("received unexpected message: {:?}", x);
@@ -955,7 +4584,7 @@ mod tests {
         })?;
         assert!(ipfs.contains(a.cid())? && ipfs.contains(b.cid())?);
         #[allow(unreachable_code)]
-        let _: anyhow::Result<()> = ipfs.batch_ops(|db| {
+        let _: Result<(), Error> = ipfs.batch_ops(|db| {
             db.insert(c.clone())?;
             anyhow::bail!("nope!");
             db.insert(d.clone())?;
@@ -964,6 +4593,105 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_batch_temp_pin() -> Result<()> {
+        tracing_try_init();
+        let network = NetworkConfig::new(Keypair::generate());
+        let storage = StorageConfig::new(None, None, 0, Duration::from_secs(3600));
+        let ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let a = create_block(b"a")?;
+        let b = create_block(b"b")?;
+        let mut tmp = ipfs.create_temp_pin()?;
+        ipfs.batch_ops(|db| {
+            db.insert(a.clone())?;
+            db.insert(b.clone())?;
+            db.temp_pin(&mut tmp, vec![*a.cid(), *b.cid()])
+        })?;
+        ipfs.evict().await?;
+        assert!(ipfs.contains(a.cid())? && ipfs.contains(b.cid())?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_store_stats() -> Result<()> {
+        tracing_try_init();
+        let network = NetworkConfig::new(Keypair::generate());
+        let storage = StorageConfig::new(None, None, 0, Duration::from_secs(3600));
+        let ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        ipfs.insert(create_block(b"a")?)?;
+        ipfs.insert(create_block(b"bc")?)?;
+        let stats = ipfs.store_stats()?;
+        assert_eq!((stats.blocks, stats.bytes), (2, 3));
+        ipfs.evict().await?;
+        let stats = ipfs.store_stats()?;
+        assert_eq!((stats.blocks, stats.bytes), (0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_async_storage_ops() -> Result<()> {
+        tracing_try_init();
+        // a single threaded executor, a blocking storage call would delay
+        // the timer
+        futures::executor::block_on(async {
+            let mut network = NetworkConfig::new(Keypair::generate());
+            network.mdns = None;
+            let storage = StorageConfig::new(None, None, 10, Duration::from_secs(3600));
+            let ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+            let block = create_block(b"async")?;
+            ipfs.insert_async(block.clone()).await?;
+            ipfs.alias_async(b"a", Some(block.cid())).await?;
+            assert_eq!(ipfs.get_async(block.cid()).await?, block);
+            assert!(ipfs.contains_async(block.cid()).await?);
+            assert_eq!(ipfs.iter_async().await?, vec![*block.cid()]);
+            assert_eq!(ipfs.resolve_async(b"a").await?, Some(*block.cid()));
+
+            let start = std::time::Instant::now();
+            let cid = *block.cid();
+            let slow = ipfs.batch_ops_async(move |batch| {
+                std::thread::sleep(Duration::from_millis(500));
+                batch.contains(&cid)
+            });
+            let timer = async {
+                futures_timer::Delay::new(Duration::from_millis(50)).await;
+                start.elapsed()
+            };
+            let (contains, elapsed) = futures::future::join(slow, timer).await;
+            assert!(contains?);
+            assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+            Ok(())
+        })
+    }
+
+    #[async_std::test]
+    async fn test_transaction() -> Result<()> {
+        tracing_try_init();
+        let network = NetworkConfig::new(Keypair::generate());
+        let storage = StorageConfig::new(None, None, 1000000, Duration::from_secs(3600));
+        let ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+        let a = create_block(b"a")?;
+        let b = create_block(b"b")?;
+        let c = create_block(b"c")?;
+        let d = create_block(b"d")?;
+        ipfs.transaction(|db| {
+            db.insert(a.clone())?;
+            db.insert(b.clone())?;
+            assert!(db.contains(a.cid())?);
+            Ok(())
+        })?;
+        assert!(ipfs.contains(a.cid())? && ipfs.contains(b.cid())?);
+        #[allow(unreachable_code)]
+        let _: Result<(), Error> = ipfs.transaction(|db| {
+            db.insert(c.clone())?;
+            db.alias(b"c", Some(c.cid()))?;
+            anyhow::bail!("nope!");
+            db.insert(d.clone())?;
+        });
+        assert!(!ipfs.contains(d.cid())? && !ipfs.contains(c.cid())? && ipfs.contains(b.cid())?);
+        assert_eq!(ipfs.resolve(b"c")?, None);
+        Ok(())
+    }
+
     #[async_std::test]
     #[ignore]
     async fn test_bitswap_sync_chain() -> Result<()> {
@@ -1042,4 +4770,138 @@ mod tests {
         }
         Ok(())
     }
+
+    /// Sends a POST request and returns the status code and body.
+    #[cfg(feature = "http-api")]
+    async fn post(
+        addr: std::net::SocketAddr,
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>)> {
+        use async_std::{io::prelude::*, net::TcpStream};
+        let mut stream = TcpStream::connect(addr).await?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            path,
+            content_type,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut res = vec![];
+        stream.read_to_end(&mut res).await?;
+        let split = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = std::str::from_utf8(&res[9..12])?.parse()?;
+        Ok((status, res[split + 4..].to_vec()))
+    }
+
+    #[cfg(feature = "http-api")]
+    #[async_std::test]
+    async fn test_http_api() -> Result<()> {
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let (b, _tmp) = create_store(false).await?;
+        let handle = a.serve_api("127.0.0.1:0".parse()?)?;
+        let addr = handle.local_addr();
+        let json = |body: &[u8]| String::from_utf8(body.to_vec()).unwrap();
+
+        let (status, body) = post(addr, "/api/v0/id", "text/plain", b"").await?;
+        assert_eq!(status, 200);
+        let body = json(&body);
+        assert!(body.contains(&format!("\"ID\":\"{}\"", a.local_peer_id())));
+        for field in &["PublicKey", "Addresses", "AgentVersion"] {
+            assert!(body.contains(&format!("\"{}\"", field)));
+        }
+
+        // `ipfs block put` sends the block as a multipart file
+        let multipart = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"file\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            hello\r\n--XyZ--\r\n";
+        let (status, body) = post(
+            addr,
+            "/api/v0/block/put?pin=true",
+            "multipart/form-data; boundary=XyZ",
+            multipart,
+        )
+        .await?;
+        assert_eq!(status, 200);
+        // `echo -n hello | ipfs block put`
+        let cid = "bafkreibm6jg3ux5qumhcn2b3flc3tyu6dmlb4xa7u5bf44yegnrjhc4yeq";
+        assert_eq!(json(&body), format!("{{\"Key\":\"{}\",\"Size\":5}}", cid));
+        assert!(a.pin_status(&cid.parse()?)?.is_some());
+
+        let path = format!("/api/v0/block/get?arg={}", cid);
+        let (status, body) = post(addr, &path, "text/plain", b"").await?;
+        assert_eq!(status, 200);
+        assert_eq!(body, b"hello");
+
+        let path = format!("/api/v0/pin/add?arg=/ipfs/{}", cid);
+        let (status, body) = post(addr, &path, "text/plain", b"").await?;
+        assert_eq!(status, 200);
+        assert_eq!(json(&body), format!("{{\"Pins\":[\"{}\"]}}", cid));
+
+        let peer = b.listeners()[0]
+            .clone()
+            .with(multiaddr::Protocol::P2p(b.local_peer_id().into()));
+        let path = format!("/api/v0/swarm/connect?arg={}", peer);
+        let (status, body) = post(addr, &path, "text/plain", b"").await?;
+        assert_eq!(status, 200);
+        assert_eq!(
+            json(&body),
+            format!(
+                "{{\"Strings\":[\"connect {} success\"]}}",
+                b.local_peer_id()
+            )
+        );
+        let (status, body) = post(addr, "/api/v0/swarm/peers", "text/plain", b"").await?;
+        assert_eq!(status, 200);
+        let body = json(&body);
+        assert!(body.contains(&format!("\"Peer\":\"{}\"", b.local_peer_id())));
+        assert!(body.contains("\"Addr\""));
+
+        let (status, body) = post(addr, "/api/v0/block/get", "text/plain", b"").await?;
+        assert_eq!(status, 400);
+        assert!(json(&body).contains("\"Type\":\"error\""));
+        let (status, body) = post(addr, "/api/v0/dag/get", "text/plain", b"").await?;
+        assert_eq!(status, 501);
+        assert!(json(&body).contains("\"Message\""));
+        assert!(json(&body).contains("\"Code\":0"));
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics-server")]
+    #[async_std::test]
+    async fn test_metrics_server() -> Result<()> {
+        use async_std::{io::prelude::*, net::TcpStream};
+        tracing_try_init();
+        let (a, _tmp) = create_store(false).await?;
+        let handle = a.serve_metrics("127.0.0.1:0".parse()?)?;
+        let addr = handle.local_addr();
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await?;
+            let head = format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            );
+            stream.write_all(head.as_bytes()).await?;
+            let mut res = String::new();
+            stream.read_to_string(&mut res).await?;
+            Result::<_>::Ok(res)
+        };
+
+        let res = get("/metrics").await?;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains("peers_connected"));
+        let res = get("/health").await?;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains("\"swarm\":\"running\""));
+
+        drop(handle);
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+        Ok(())
+    }
 }
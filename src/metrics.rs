@@ -0,0 +1,96 @@
+//! An HTTP endpoint serving the metrics and the health of a node.
+use crate::{executor::JoinHandle, Ipfs, TaskStatus};
+use anyhow::Result;
+use libipld::{codec::References, store::StoreParams, Ipld};
+use prometheus::{Encoder, Registry, TextEncoder};
+use std::net::{SocketAddr, TcpListener};
+use tide::{convert::json, Request, Response, StatusCode};
+
+/// A running metrics server. The server is stopped when the handle is
+/// dropped.
+pub struct MetricsHandle {
+    addr: SocketAddr,
+    task: Option<JoinHandle<()>>,
+}
+
+impl MetricsHandle {
+    /// The address the metrics server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsHandle {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Clone)]
+struct State<P: StoreParams> {
+    ipfs: Ipfs<P>,
+    registry: Registry,
+}
+
+pub(crate) fn serve<P: StoreParams>(ipfs: Ipfs<P>, addr: SocketAddr) -> Result<MetricsHandle>
+where
+    Ipld: References<P::Codecs>,
+{
+    let registry = Registry::new();
+    ipfs.register_metrics(&registry)?;
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let executor = ipfs.executor.clone();
+    let mut app = tide::with_state(State { ipfs, registry });
+    app.at("/metrics").get(metrics::<P>);
+    app.at("/health").get(health::<P>);
+    let task = executor.spawn(async move {
+        if let Err(err) = app.listen(listener).await {
+            tracing::warn!("metrics server stopped: {}", err);
+        }
+    });
+    Ok(MetricsHandle {
+        addr,
+        task: Some(task),
+    })
+}
+
+/// Returns the metrics in the prometheus text exposition format.
+async fn metrics<P: StoreParams>(req: Request<State<P>>) -> tide::Result {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder.encode(&req.state().registry.gather(), &mut buffer)?;
+    Ok(Response::builder(StatusCode::Ok)
+        .content_type(encoder.format_type())
+        .body(buffer)
+        .build())
+}
+
+/// Returns the status of the background tasks, with `503 Service
+/// Unavailable` if one of them failed.
+async fn health<P: StoreParams>(req: Request<State<P>>) -> tide::Result {
+    let health = req.state().ipfs.health();
+    let status = if health.is_healthy() {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    };
+    Ok(Response::builder(status)
+        .body(json!({
+            "healthy": health.is_healthy(),
+            "swarm": task_status(&health.swarm),
+            "gc": task_status(&health.gc),
+        }))
+        .build())
+}
+
+fn task_status(status: &TaskStatus) -> String {
+    match status {
+        TaskStatus::Running => "running".into(),
+        TaskStatus::Stopped => "stopped".into(),
+        TaskStatus::Exited => "exited".into(),
+        TaskStatus::Panicked(msg) => format!("panicked: {}", msg),
+    }
+}
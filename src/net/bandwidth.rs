@@ -0,0 +1,405 @@
+//! Bandwidth accounting of the connections.
+//!
+//! Bytes are counted on the substreams of the multiplexed connections, where
+//! the remote peer is known, so the overhead of noise and the multiplexers
+//! isn't included. Rates are averaged over a sliding window of samples taken
+//! by the swarm task and on every read of the stats.
+//!
+//! The inbound bitswap substreams also enforce the serve rate limit, their
+//! protocol is recognized from the negotiation. Each of them is one request,
+//! the response is delayed once the peer or all peers are over budget.
+use crate::net::{
+    peers::{NETWORK_BYTES_RECEIVED, NETWORK_BYTES_SENT},
+    rate_limit::{Buckets, ServeLimiter},
+};
+use fnv::FnvHashMap;
+use futures::{
+    future::FutureExt,
+    io::{AsyncRead, AsyncWrite},
+};
+use futures_timer::Delay;
+use libp2p::{
+    core::{muxing::StreamMuxerEvent, StreamMuxer},
+    PeerId,
+};
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Minimum time between two samples.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Time the rates are averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// Protocols of the bitswap requests limited by the serve rate limit.
+const BITSWAP_PROTOCOLS: &[&[u8]] = &[b"/ipfs-embed/bitswap/", b"/ipfs/bitswap/"];
+/// Bytes read from an inbound substream that are searched for the protocol
+/// proposed by the remote.
+const NEGOTIATION_BYTES: usize = 256;
+
+/// Bytes transferred over the network, see `Ipfs::bandwidth`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BandwidthStats {
+    /// Bytes received since the node was started.
+    pub total_in: u64,
+    /// Bytes sent since the node was started.
+    pub total_out: u64,
+    /// Bytes received per second, averaged over the last ten seconds.
+    pub rate_in: f64,
+    /// Bytes sent per second, averaged over the last ten seconds.
+    pub rate_out: f64,
+}
+
+/// Bytes transferred over the connections to a peer since it connected,
+/// see `Ipfs::peer_bandwidth`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PeerBandwidth {
+    pub total_in: u64,
+    pub total_out: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+    /// Per peer budget of the serve rate limit, unused for the total.
+    buckets: Mutex<Buckets>,
+}
+
+impl Counters {
+    fn get(&self) -> (u64, u64) {
+        (
+            self.inbound.load(Ordering::Relaxed),
+            self.outbound.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counters shared between the transport, the swarm task and the
+/// `NetworkService`.
+#[derive(Debug)]
+pub(crate) struct Bandwidth {
+    total: Arc<Counters>,
+    /// Counters of the peers, shared with the muxers of their connections.
+    peers: Mutex<FnvHashMap<PeerId, Arc<Counters>>>,
+    /// Totals at the sample times, the first one is the last sample from
+    /// before the window.
+    samples: Mutex<VecDeque<(Instant, u64, u64)>>,
+    limiter: Arc<ServeLimiter>,
+}
+
+impl Bandwidth {
+    pub fn new(limiter: Arc<ServeLimiter>) -> Self {
+        Self {
+            total: Default::default(),
+            peers: Default::default(),
+            samples: Mutex::new(vec![(Instant::now(), 0, 0)].into()),
+            limiter,
+        }
+    }
+
+    /// Wraps the muxer of a connection to `peer`.
+    pub fn count<M>(&self, peer: PeerId, muxer: M) -> CountingMuxer<M> {
+        let peer = self.peers.lock().entry(peer).or_default().clone();
+        CountingMuxer {
+            inner: muxer,
+            counters: StreamCounters {
+                total: self.total.clone(),
+                peer,
+                limiter: self.limiter.clone(),
+            },
+        }
+    }
+
+    /// Records the totals, at most once per `SAMPLE_INTERVAL`, and forgets
+    /// the peers without connections.
+    pub fn sample(&self) {
+        let now = Instant::now();
+        let mut samples = self.samples.lock();
+        if matches!(samples.back(), Some((at, _, _)) if now.duration_since(*at) < SAMPLE_INTERVAL) {
+            return;
+        }
+        let (inbound, outbound) = self.total.get();
+        samples.push_back((now, inbound, outbound));
+        while samples.len() > 1 && now.duration_since(samples[1].0) >= RATE_WINDOW {
+            samples.pop_front();
+        }
+        drop(samples);
+        // the muxers and their substreams hold the other references
+        self.peers
+            .lock()
+            .retain(|_, counters| Arc::strong_count(counters) > 1);
+    }
+
+    pub fn stats(&self) -> BandwidthStats {
+        self.sample();
+        let (total_in, total_out) = self.total.get();
+        let mut stats = BandwidthStats {
+            total_in,
+            total_out,
+            ..Default::default()
+        };
+        if let Some((at, inbound, outbound)) = self.samples.lock().front() {
+            let secs = at.elapsed().as_secs_f64();
+            if secs > 0.0 {
+                stats.rate_in = (total_in - inbound) as f64 / secs;
+                stats.rate_out = (total_out - outbound) as f64 / secs;
+            }
+        }
+        stats
+    }
+
+    pub fn peers(&self) -> Vec<(PeerId, PeerBandwidth)> {
+        self.peers
+            .lock()
+            .iter()
+            .filter(|(_, counters)| Arc::strong_count(counters) > 1)
+            .map(|(peer, counters)| {
+                let (total_in, total_out) = counters.get();
+                (
+                    *peer,
+                    PeerBandwidth {
+                        total_in,
+                        total_out,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct StreamCounters {
+    total: Arc<Counters>,
+    peer: Arc<Counters>,
+    limiter: Arc<ServeLimiter>,
+}
+
+impl StreamCounters {
+    fn received(&self, n: usize) {
+        self.total.inbound.fetch_add(n as u64, Ordering::Relaxed);
+        self.peer.inbound.fetch_add(n as u64, Ordering::Relaxed);
+        NETWORK_BYTES_RECEIVED.inc_by(n as u64);
+    }
+
+    fn sent(&self, n: usize) {
+        self.total.outbound.fetch_add(n as u64, Ordering::Relaxed);
+        self.peer.outbound.fetch_add(n as u64, Ordering::Relaxed);
+        NETWORK_BYTES_SENT.inc_by(n as u64);
+    }
+
+    /// Charges bitswap requests and the bytes of their responses to the
+    /// serve rate limit, returns the delay before the next write.
+    fn served(&self, requests: u64, bytes: usize) -> Option<Delay> {
+        let wait = self
+            .limiter
+            .serve(&self.peer.buckets, requests, bytes as u64);
+        if wait > Duration::ZERO {
+            Some(Delay::new(wait))
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a substream answers bitswap requests.
+enum Serve {
+    /// An outbound substream or an inbound one of another protocol.
+    No,
+    /// An inbound substream being negotiated, with the bytes read so far.
+    Negotiating(Vec<u8>),
+    Bitswap,
+}
+
+impl Serve {
+    /// Looks for a bitswap protocol in the bytes read from the remote,
+    /// returns whether it was found.
+    fn read(&mut self, bytes: &[u8]) -> bool {
+        let read = match self {
+            Self::Negotiating(read) => read,
+            _ => return false,
+        };
+        let n = bytes.len().min(NEGOTIATION_BYTES - read.len());
+        read.extend_from_slice(&bytes[..n]);
+        let bitswap = BITSWAP_PROTOCOLS.iter().any(|protocol| {
+            read.windows(protocol.len())
+                .any(|window| window == *protocol)
+        });
+        if bitswap {
+            *self = Self::Bitswap;
+        } else if read.len() == NEGOTIATION_BYTES {
+            *self = Self::No;
+        }
+        bitswap
+    }
+}
+
+/// Waits for the delay imposed by the serve rate limit, if any.
+fn poll_throttle(throttle: &mut Option<Delay>, cx: &mut Context) -> Poll<()> {
+    if let Some(delay) = throttle {
+        futures::ready!(delay.poll_unpin(cx));
+        *throttle = None;
+    }
+    Poll::Ready(())
+}
+
+/// A muxer counting the bytes of its substreams.
+#[pin_project]
+pub(crate) struct CountingMuxer<M> {
+    #[pin]
+    inner: M,
+    counters: StreamCounters,
+}
+
+impl<M: StreamMuxer> StreamMuxer for CountingMuxer<M> {
+    type Substream = CountingStream<M::Substream>;
+    type Error = M::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.project();
+        let inner = futures::ready!(this.inner.poll_inbound(cx))?;
+        Poll::Ready(Ok(CountingStream {
+            inner,
+            counters: this.counters.clone(),
+            serve: Serve::Negotiating(vec![]),
+            throttle: None,
+        }))
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.project();
+        let inner = futures::ready!(this.inner.poll_outbound(cx))?;
+        Poll::Ready(Ok(CountingStream {
+            inner,
+            counters: this.counters.clone(),
+            serve: Serve::No,
+            throttle: None,
+        }))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        self.project().inner.poll(cx)
+    }
+}
+
+#[pin_project]
+pub(crate) struct CountingStream<S> {
+    #[pin]
+    inner: S,
+    counters: StreamCounters,
+    serve: Serve,
+    /// Delay imposed by the serve rate limit.
+    throttle: Option<Delay>,
+}
+
+impl<S: AsyncRead> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let n = futures::ready!(this.inner.poll_read(cx, buf))?;
+        this.counters.received(n);
+        if this.serve.read(&buf[..n]) {
+            *this.throttle = this.counters.served(1, 0);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for CountingStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        futures::ready!(poll_throttle(this.throttle, cx));
+        let n = futures::ready!(this.inner.poll_write(cx, buf))?;
+        this.counters.sent(n);
+        if let Serve::Bitswap = this.serve {
+            *this.throttle = this.counters.served(0, n);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+    };
+
+    #[test]
+    fn test_counting_stream() {
+        let bandwidth = Bandwidth::new(Arc::new(ServeLimiter::new(None)));
+        let peer = PeerId::random();
+        let muxer = bandwidth.count(peer, ());
+        let mut reader = CountingStream {
+            inner: &b"hello"[..],
+            counters: muxer.counters.clone(),
+            serve: Serve::No,
+            throttle: None,
+        };
+        let mut buf = vec![];
+        block_on(reader.read_to_end(&mut buf)).unwrap();
+        let mut writer = CountingStream {
+            inner: vec![],
+            counters: muxer.counters.clone(),
+            serve: Serve::No,
+            throttle: None,
+        };
+        block_on(writer.write_all(b"hi")).unwrap();
+
+        let stats = bandwidth.stats();
+        assert_eq!((stats.total_in, stats.total_out), (5, 2));
+        let expected = PeerBandwidth {
+            total_in: 5,
+            total_out: 2,
+        };
+        assert_eq!(bandwidth.peers(), vec![(peer, expected)]);
+        // peers are forgotten once their connections are closed
+        drop((muxer, reader, writer));
+        assert!(bandwidth.peers().is_empty());
+    }
+
+    #[test]
+    fn test_serve_negotiation() {
+        let mut serve = Serve::Negotiating(vec![]);
+        assert!(!serve.read(b"\x13/multistream/1.0.0\n\x1a/ipfs-embed/"));
+        assert!(serve.read(b"bitswap/1.0.0\n"));
+        assert!(matches!(serve, Serve::Bitswap));
+
+        let mut serve = Serve::Negotiating(vec![]);
+        assert!(!serve.read(b"\x13/multistream/1.0.0\n\x10/ipfs/kad/1.0.0\n"));
+        assert!(!serve.read(&[0; NEGOTIATION_BYTES]));
+        assert!(matches!(serve, Serve::No));
+        assert!(!serve.read(b"/ipfs/bitswap/1.2.0\n"));
+    }
+}
@@ -1,9 +1,20 @@
 use crate::{
     net::{
         config::NetworkConfig,
-        peers::{AddressBook, Event},
+        peers::{
+            normalize_addr, AddressBook, Event, EventFilter, ReservationStatus, SwarmEvents,
+            BITSWAP_BLOCKS_RECEIVED, BITSWAP_BLOCKS_SENT, BITSWAP_BYTES_RECEIVED,
+            BITSWAP_BYTES_SENT, BITSWAP_REQUESTS_SERVED, BITSWAP_RUNNING_QUERIES,
+            GOSSIP_MESH_PEERS, GOSSIP_MESSAGES_PUBLISHED, GOSSIP_MESSAGES_RECEIVED,
+            GOSSIP_MESSAGES_REJECTED, GOSSIP_PEER_SCORES, RELAY_CIRCUITS, RELAY_CIRCUITS_TOTAL,
+            RELAY_RESERVATIONS,
+        },
+        rate_limit::ServeLimiter,
+        request::{
+            PendingRequests, Request, RequestCodec, RequestFailure, RequestProtocol, Response,
+        },
     },
-    variable::Writer,
+    variable::Variable,
     AddressSource, PeerInfo,
 };
 use fnv::{FnvHashMap, FnvHashSet};
@@ -11,22 +22,36 @@ use futures::channel::{
     mpsc::{self, UnboundedSender},
     oneshot,
 };
-use libipld::{store::StoreParams, Cid, DefaultParams, Result};
+use libipld::{error::BlockNotFound, store::StoreParams, Cid, DefaultParams, Result};
 #[cfg(feature = "async_global")]
 use libp2p::mdns::Mdns;
 #[cfg(all(feature = "tokio", not(feature = "async_global")))]
 use libp2p::mdns::TokioMdns as Mdns;
 use libp2p::{
+    autonat::{self, NatStatus},
     core::ConnectedPoint,
-    gossipsub::{Gossipsub, GossipsubEvent, GossipsubMessage, IdentTopic, MessageAuthenticity},
+    dcutr,
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic,
+        MessageAcceptance, MessageAuthenticity, MessageId,
+    },
     identify,
     kad::{
         record::{store::MemoryStore, Key, Record},
-        AddProviderOk, BootstrapOk, GetClosestPeersOk, GetProvidersOk, GetRecordOk, Kademlia,
-        KademliaEvent, PeerRecord, PutRecordOk, QueryResult, Quorum,
+        AddProviderOk, BootstrapOk, GetClosestPeersError, GetClosestPeersOk, GetProvidersError,
+        GetProvidersOk, GetRecordOk, Kademlia, KademliaEvent, PeerRecord, PutRecordOk, QueryInfo,
+        QueryResult, Quorum,
     },
-    mdns::MdnsEvent,
+    mdns::{MdnsConfig, MdnsEvent},
     ping,
+    relay::v2::{
+        client::{Client as RelayClient, Event as RelayClientEvent},
+        relay::{Config as RelayConfig, Event as RelayServerEvent, Relay},
+    },
+    request_response::{
+        self, OutboundFailure, ProtocolSupport, RequestResponse, RequestResponseEvent,
+        RequestResponseMessage,
+    },
     swarm::{
         behaviour::toggle::Toggle, AddressRecord, ConnectionError, ConnectionHandler,
         IntoConnectionHandler, NetworkBehaviour,
@@ -35,7 +60,17 @@ use libp2p::{
 };
 use libp2p_bitswap::{Bitswap, BitswapEvent, BitswapStore};
 use libp2p_broadcast::{Broadcast, BroadcastEvent, Topic};
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use parking_lot::Mutex;
+use std::{
+    collections::{hash_map::Entry, HashSet},
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 use thiserror::Error;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -47,6 +82,13 @@ enum InnerQueryId {
     Kad(libp2p::kad::QueryId),
 }
 
+impl QueryId {
+    /// Returns true for bitswap queries, false for dht queries.
+    pub(crate) fn is_bitswap(&self) -> bool {
+        matches!(self.0, InnerQueryId::Bitswap(_))
+    }
+}
+
 impl From<libp2p_bitswap::QueryId> for QueryId {
     fn from(id: libp2p_bitswap::QueryId) -> Self {
         Self(InnerQueryId::Bitswap(id))
@@ -62,25 +104,316 @@ impl From<libp2p::kad::QueryId> for QueryId {
 /// An event of a sync query.
 #[derive(Debug)]
 pub enum SyncEvent {
-    /// Signals that the sync query made progress and counts the amount of
-    /// subtrees to sync. If it is syncing a linked list, it will always be
-    /// 1.
-    Progress { missing: usize },
+    /// Signals that the sync query made progress. All counts are cumulative
+    /// for the lifetime of the query.
+    Progress {
+        /// The number of blocks known to be missing. The children of a
+        /// block are only known once it is received.
+        missing: usize,
+        /// The number of blocks received so far.
+        blocks: usize,
+        /// The number of bytes received so far.
+        bytes: u64,
+        /// The number of connected providers blocks are requested from.
+        providers: usize,
+    },
+    /// Signals that providers discovered in the dht were added because the
+    /// previous ones didn't have `cid`. The sync continues with a new query,
+    /// its progress counts start over.
+    ProvidersAdded {
+        /// The block the providers were looked up for.
+        cid: Cid,
+        /// The added providers.
+        providers: Vec<PeerId>,
+    },
     /// Signals completion of the sync query and if it was completed
     /// successfully.
     Complete(Result<()>),
 }
 
+/// Copies the result of a shared query for each caller, keeping
+/// `BlockNotFound` errors downcastable.
+fn clone_result(result: &Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => match err.downcast_ref::<BlockNotFound>() {
+            Some(BlockNotFound(cid)) => Err(BlockNotFound(*cid).into()),
+            None => Err(anyhow::anyhow!("{:#}", err)),
+        },
+    }
+}
+
+/// Progress counters of a running sync query.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SyncProgress {
+    blocks: usize,
+    bytes: u64,
+    /// Blocks the query waits for.
+    missing: usize,
+}
+
+/// Credits the blocks inserted by bitswap to the sync queries waiting for
+/// them. Once bitswap looked up the missing children of a received block,
+/// the queries wait for those.
+#[derive(Debug, Default)]
+struct SyncTracker {
+    syncs: FnvHashMap<QueryId, SyncProgress>,
+    wanted: FnvHashMap<Cid, Vec<QueryId>>,
+    /// Received blocks and the queries waiting for their children.
+    received: FnvHashMap<Cid, Vec<QueryId>>,
+}
+
+impl SyncTracker {
+    fn start(&mut self, id: QueryId, missing: &[Cid]) {
+        if let Entry::Vacant(entry) = self.syncs.entry(id) {
+            entry.insert(SyncProgress::default());
+            self.want(id, missing);
+        }
+    }
+
+    fn want(&mut self, id: QueryId, cids: &[Cid]) {
+        let progress = match self.syncs.get_mut(&id) {
+            Some(progress) => progress,
+            None => return,
+        };
+        for cid in cids {
+            let ids = self.wanted.entry(*cid).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+                progress.missing += 1;
+            }
+        }
+    }
+
+    fn received(&mut self, cid: &Cid, len: u64) {
+        if let Some(ids) = self.wanted.remove(cid) {
+            for id in &ids {
+                if let Some(progress) = self.syncs.get_mut(id) {
+                    progress.blocks += 1;
+                    progress.bytes += len;
+                    progress.missing -= 1;
+                }
+            }
+            self.received.insert(*cid, ids);
+        }
+    }
+
+    fn children(&mut self, cid: &Cid, missing: &[Cid]) {
+        if let Some(ids) = self.received.remove(cid) {
+            for id in ids {
+                self.want(id, missing);
+            }
+        }
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&QueryId) -> bool) {
+        let len = self.syncs.len();
+        self.syncs.retain(|id, _| f(id));
+        if self.syncs.len() == len {
+            return;
+        }
+        let syncs = &self.syncs;
+        for ids in self.wanted.values_mut().chain(self.received.values_mut()) {
+            ids.retain(|id| syncs.contains_key(id));
+        }
+        self.wanted.retain(|_, ids| !ids.is_empty());
+        self.received.retain(|_, ids| !ids.is_empty());
+    }
+}
+
+/// Bitswap transfer statistics of this node since it started.
+///
+/// These are totals over all peers. libp2p-bitswap hands the store neither
+/// the peer a block came from nor the peer a request is answered for, so
+/// there is no per-peer breakdown. `Ipfs::peer_bandwidth` has the traffic
+/// with each peer over all protocols.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BitswapStats {
+    /// Blocks received from peers.
+    pub blocks_received: u64,
+    /// Bytes of the blocks received from peers.
+    pub bytes_received: u64,
+    /// Blocks sent to peers.
+    pub blocks_sent: u64,
+    /// Bytes of the blocks sent to peers.
+    pub bytes_sent: u64,
+    /// Requests of peers answered, including requests for blocks this node
+    /// doesn't have.
+    pub requests_served: u64,
+    /// Number of running fetch and sync queries. A query can want many
+    /// blocks at once, so this is not the size of the wantlist.
+    pub running_queries: usize,
+    /// Block requests of syncs outstanding in the sync scheduler. Fetches
+    /// bypass the scheduler and don't count.
+    pub outstanding_wants: usize,
+}
+
+/// Counters shared between the bitswap store and the swarm task.
+#[derive(Debug, Default)]
+pub(crate) struct BitswapCounters {
+    syncs: Mutex<SyncTracker>,
+    blocks_received: AtomicU64,
+    bytes_received: AtomicU64,
+    blocks_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    requests_served: AtomicU64,
+    running_queries: AtomicUsize,
+}
+
+impl BitswapCounters {
+    pub fn stats(&self) -> BitswapStats {
+        BitswapStats {
+            blocks_received: self.blocks_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            blocks_sent: self.blocks_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            requests_served: self.requests_served.load(Ordering::Relaxed),
+            running_queries: self.running_queries.load(Ordering::Relaxed),
+            outstanding_wants: 0,
+        }
+    }
+
+    pub fn set_running_queries(&self, queries: usize) {
+        self.running_queries.store(queries, Ordering::Relaxed);
+        BITSWAP_RUNNING_QUERIES.set(queries as i64);
+    }
+
+    /// Starts counting the progress of the sync query `id` waiting for the
+    /// blocks `missing`. Joining a running sync keeps its counts.
+    pub fn start_sync(&self, id: QueryId, missing: &[Cid]) {
+        self.syncs.lock().start(id, missing);
+    }
+
+    fn sync_progress(&self, id: &QueryId) -> Option<SyncProgress> {
+        self.syncs.lock().syncs.get(id).copied()
+    }
+
+    /// Forgets the progress of the syncs that are no longer running.
+    pub fn retain_syncs(&self, f: impl FnMut(&QueryId) -> bool) {
+        self.syncs.lock().retain(f);
+    }
+}
+
+/// Number of block events queued for the swarm task. Further events are
+/// dropped until it catches up.
+pub(crate) const BLOCK_EVENTS_BUFFER: usize = 1024;
+
+/// Wraps a `BitswapStore` counting the blocks received from and sent to the
+/// network. Bitswap only calls `contains` and `get` to answer requests of
+/// peers.
+///
+/// The received and sent blocks are also reported as `Event`s, which the
+/// swarm task forwards to the `swarm_events` subscribers.
+///
+/// With `Throttle::DontHave`, requests over the global budget of the serve
+/// rate limit are answered as if the block was missing. The responses are
+/// delayed by the connections otherwise, the store never blocks the bitswap
+/// thread.
+pub(crate) struct CountingStore<S> {
+    store: S,
+    counters: Arc<BitswapCounters>,
+    events: mpsc::Sender<Event>,
+    limiter: Arc<ServeLimiter>,
+}
+
+impl<S> CountingStore<S> {
+    pub fn new(
+        store: S,
+        counters: Arc<BitswapCounters>,
+        events: mpsc::Sender<Event>,
+        limiter: Arc<ServeLimiter>,
+    ) -> Self {
+        Self {
+            store,
+            counters,
+            events,
+            limiter,
+        }
+    }
+}
+
+impl<S: BitswapStore> BitswapStore for CountingStore<S> {
+    type Params = S::Params;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        self.counters
+            .requests_served
+            .fetch_add(1, Ordering::Relaxed);
+        BITSWAP_REQUESTS_SERVED.inc();
+        Ok(!self.limiter.refuses() && self.store.contains(cid)?)
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.counters
+            .requests_served
+            .fetch_add(1, Ordering::Relaxed);
+        BITSWAP_REQUESTS_SERVED.inc();
+        if self.limiter.refuses() {
+            return Ok(None);
+        }
+        let data = self.store.get(cid)?;
+        if let Some(data) = &data {
+            let len = data.len() as u64;
+            self.counters.blocks_sent.fetch_add(1, Ordering::Relaxed);
+            self.counters.bytes_sent.fetch_add(len, Ordering::Relaxed);
+            BITSWAP_BLOCKS_SENT.inc();
+            BITSWAP_BYTES_SENT.inc_by(len);
+            // never blocks the bitswap store, events are dropped when full
+            self.events
+                .try_send(Event::BlockSent(*cid, data.len()))
+                .ok();
+        }
+        Ok(data)
+    }
+
+    fn insert(&mut self, block: &libipld::Block<Self::Params>) -> Result<()> {
+        self.store.insert(block)?;
+        let len = block.data().len() as u64;
+        self.counters.syncs.lock().received(block.cid(), len);
+        self.counters
+            .blocks_received
+            .fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_received
+            .fetch_add(len, Ordering::Relaxed);
+        BITSWAP_BLOCKS_RECEIVED.inc();
+        BITSWAP_BYTES_RECEIVED.inc_by(len);
+        self.events
+            .try_send(Event::BlockReceived(*block.cid(), block.data().len()))
+            .ok();
+        Ok(())
+    }
+
+    /// Bitswap syncs look up the missing children of every block they
+    /// receive, the syncs then wait for those.
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        let missing = self.store.missing_blocks(cid)?;
+        self.counters.syncs.lock().children(cid, &missing);
+        Ok(missing)
+    }
+}
+
 pub type GetChannel = oneshot::Receiver<Result<()>>;
 pub type SyncChannel = mpsc::UnboundedReceiver<SyncEvent>;
+pub type ClosestPeersChannel = oneshot::Sender<Result<Vec<(PeerId, Vec<Multiaddr>)>>>;
 
+/// Bitswap gets and syncs are shared by all callers asking for the same
+/// `cid` from the same `providers`, which are kept sorted.
 pub enum QueryChannel {
-    Get(oneshot::Sender<Result<()>>),
-    Sync(mpsc::UnboundedSender<SyncEvent>),
+    Get {
+        cid: Cid,
+        providers: Vec<PeerId>,
+        txs: Vec<oneshot::Sender<Result<()>>>,
+    },
+    Sync {
+        cid: Cid,
+        providers: Vec<PeerId>,
+        txs: Vec<mpsc::UnboundedSender<SyncEvent>>,
+    },
     Bootstrap(oneshot::Sender<Result<()>>),
-    #[allow(dead_code)]
-    GetClosestPeers(oneshot::Sender<Result<Vec<PeerId>>>),
+    GetClosestPeers(ClosestPeersChannel),
     GetProviders(oneshot::Sender<Result<HashSet<PeerId>>>),
+    GetProvidersStream(mpsc::UnboundedSender<PeerId>, FnvHashSet<PeerId>),
     StartProviding(oneshot::Sender<Result<()>>),
     GetRecord(oneshot::Sender<Result<Vec<PeerRecord>>>),
     PutRecord(oneshot::Sender<Result<()>>),
@@ -88,10 +421,45 @@ pub enum QueryChannel {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum GossipEvent {
     Subscribed(PeerId),
-    Message(PeerId, Arc<[u8]>),
+    /// A message from the given peer. The `MessageId` is only set for messages
+    /// received via gossipsub, messages sent with `broadcast` have none.
+    Message(PeerId, Arc<[u8]>, Option<MessageId>),
     Unsubscribed(PeerId),
 }
 
+/// Outcome of validating a gossipsub message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValidationResult {
+    /// The message is delivered to subscribers and forwarded to other peers.
+    Accept,
+    /// The message is dropped and counts against the score of the peer that
+    /// forwarded it.
+    Reject,
+    /// The message is dropped without penalizing the peer.
+    Ignore,
+}
+
+impl From<ValidationResult> for MessageAcceptance {
+    fn from(result: ValidationResult) -> Self {
+        match result {
+            ValidationResult::Accept => Self::Accept,
+            ValidationResult::Reject => Self::Reject,
+            ValidationResult::Ignore => Self::Ignore,
+        }
+    }
+}
+
+type ValidateFn = dyn Fn(&PeerId, &[u8]) -> ValidationResult + Send + Sync;
+
+#[derive(Clone)]
+pub struct MessageValidator(pub(crate) Arc<ValidateFn>);
+
+impl std::fmt::Debug for MessageValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MessageValidator").finish()
+    }
+}
+
 pub(crate) type MyHandlerError = <<<NetworkBackendBehaviour<DefaultParams> as NetworkBehaviour>
     ::ConnectionHandler as IntoConnectionHandler>::Handler as ConnectionHandler>::Error;
 
@@ -105,20 +473,48 @@ pub struct NetworkBackendBehaviour<P: StoreParams> {
     bitswap: Toggle<Bitswap<P>>,
     gossipsub: Toggle<Gossipsub>,
     broadcast: Toggle<Broadcast>,
+    relay_client: RelayClient,
+    relay_server: Toggle<Relay>,
+    autonat: Toggle<autonat::Behaviour>,
+    dcutr: Toggle<dcutr::behaviour::Behaviour>,
+    request_response: Toggle<RequestResponse<RequestCodec>>,
 }
 
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
-    pub fn inject_mdns_event(&mut self, event: MdnsEvent) {
+    /// Reports mdns discoveries and, if `auto_dial` is set, adds them to the
+    /// address book which dials them.
+    pub fn inject_mdns_event(&mut self, event: MdnsEvent, auto_dial: bool) {
         match event {
             MdnsEvent::Discovered(list) => {
                 for (peer_id, addr) in list {
-                    self.add_address(&peer_id, addr, AddressSource::Mdns);
+                    self.notify(Event::MdnsDiscovered(peer_id, addr.clone()));
+                    if auto_dial {
+                        self.add_address(&peer_id, addr, AddressSource::Mdns);
+                    }
                 }
             }
-            MdnsEvent::Expired(_) => {
-                // Ignore expired addresses
+            MdnsEvent::Expired(list) => {
+                // addresses stay in the address book until they fail
+                for (peer_id, _) in list {
+                    self.notify(Event::MdnsExpired(peer_id));
+                }
+            }
+        }
+    }
+
+    /// Starts mdns with `config` or stops it if `None`, replacing a running
+    /// instance. Peers discovered by a stopped instance are reported as
+    /// expired.
+    pub fn set_mdns(&mut self, config: Option<MdnsConfig>) -> Result<()> {
+        let mdns = config.map(Mdns::new).transpose()?;
+        if let Some(old) = self.mdns.as_ref() {
+            let expired = old.discovered_nodes().copied().collect::<FnvHashSet<_>>();
+            for peer_id in expired {
+                self.notify(Event::MdnsExpired(peer_id));
             }
         }
+        self.mdns = mdns.into();
+        Ok(())
     }
 }
 
@@ -130,6 +526,14 @@ pub struct DisabledProtocol(&'static str);
 #[error("Trying to use kad before bootstrap completed successfully.")]
 pub struct NotBootstrapped;
 
+#[derive(Debug, Error)]
+#[error("The dht routing table contains no peers to query.")]
+pub struct NoKnownPeers;
+
+#[derive(Debug, Error)]
+#[error("Fetching block {0} timed out.")]
+pub struct FetchTimeout(pub Cid);
+
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadStoreError(pub libp2p::kad::record::store::Error);
@@ -162,7 +566,7 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub fn inject_kad_event(
         &mut self,
         event: KademliaEvent,
-        bootstrap_complete: &mut bool,
+        bootstrapped: &Variable<bool>,
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
     ) {
         tracing::trace!("kademlia event {:?}", event);
@@ -171,7 +575,11 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                 QueryResult::Bootstrap(Ok(BootstrapOk { num_remaining, .. })) => {
                     tracing::trace!("remaining {}", num_remaining);
                     if num_remaining == 0 {
-                        *bootstrap_complete = true;
+                        // set before replying so that `is_bootstrapped` holds once the
+                        // bootstrap future resolved
+                        if !*bootstrapped.read() {
+                            *bootstrapped.write() = true;
+                        }
                         if let Some(QueryChannel::Bootstrap(ch)) = queries.remove(&id.into()) {
                             ch.send(Ok(())).ok();
                         }
@@ -186,24 +594,45 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                 }
                 QueryResult::GetClosestPeers(Ok(GetClosestPeersOk { peers, .. })) => {
                     if let Some(QueryChannel::GetClosestPeers(ch)) = queries.remove(&id.into()) {
-                        ch.send(Ok(peers)).ok();
+                        ch.send(Ok(self.with_addresses(peers))).ok();
                     }
                 }
                 QueryResult::GetClosestPeers(Err(err)) => {
                     tracing::trace!("{:?}", err);
                     if let Some(QueryChannel::GetClosestPeers(ch)) = queries.remove(&id.into()) {
-                        ch.send(Err(KadGetClosestPeersError(err).into())).ok();
+                        // a timed out query may still have found some peers, which is more
+                        // useful to the caller than an error
+                        let GetClosestPeersError::Timeout { peers, .. } = &err;
+                        if peers.is_empty() {
+                            ch.send(Err(KadGetClosestPeersError(err).into())).ok();
+                        } else {
+                            let peers = peers.clone();
+                            ch.send(Ok(self.with_addresses(peers))).ok();
+                        }
                     }
                 }
                 QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. })) => {
-                    if let Some(QueryChannel::GetProviders(ch)) = queries.remove(&id.into()) {
-                        ch.send(Ok(providers)).ok();
+                    match queries.remove(&id.into()) {
+                        Some(QueryChannel::GetProviders(ch)) => {
+                            ch.send(Ok(providers)).ok();
+                        }
+                        Some(QueryChannel::GetProvidersStream(tx, mut seen)) => {
+                            self.stream_providers(&tx, &mut seen, &providers);
+                        }
+                        _ => {}
                     }
                 }
                 QueryResult::GetProviders(Err(err)) => {
                     tracing::trace!("{:?}", err);
-                    if let Some(QueryChannel::GetProviders(ch)) = queries.remove(&id.into()) {
-                        ch.send(Err(KadGetProvidersError(err).into())).ok();
+                    match queries.remove(&id.into()) {
+                        Some(QueryChannel::GetProviders(ch)) => {
+                            ch.send(Err(KadGetProvidersError(err).into())).ok();
+                        }
+                        Some(QueryChannel::GetProvidersStream(tx, mut seen)) => {
+                            let GetProvidersError::Timeout { providers, .. } = &err;
+                            self.stream_providers(&tx, &mut seen, providers);
+                        }
+                        _ => {}
                     }
                 }
                 QueryResult::StartProviding(Ok(AddProviderOk { .. })) => {
@@ -253,56 +682,118 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
 }
 
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    /// The progress of syncs is taken from `counters`, which credits the
+    /// received blocks to the syncs waiting for them.
     pub fn inject_bitswap_event(
         &mut self,
         event: BitswapEvent,
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        counters: &BitswapCounters,
     ) {
         match event {
-            BitswapEvent::Progress(id, missing) => {
-                if let Some(QueryChannel::Sync(ch)) = queries.get(&id.into()) {
-                    ch.unbounded_send(SyncEvent::Progress { missing }).ok();
+            BitswapEvent::Progress(id, _) => {
+                let id = id.into();
+                if let Some(QueryChannel::Sync { txs, providers, .. }) = queries.get(&id) {
+                    self.send_sync_progress(&id, txs, providers, counters);
                 }
             }
-            BitswapEvent::Complete(id, result) => match queries.remove(&id.into()) {
-                Some(QueryChannel::Get(ch)) => {
-                    ch.send(result).ok();
-                }
-                Some(QueryChannel::Sync(ch)) => {
-                    ch.unbounded_send(SyncEvent::Complete(result)).ok();
+            BitswapEvent::Complete(id, result) => {
+                let id = id.into();
+                match queries.remove(&id) {
+                    Some(QueryChannel::Get { txs, .. }) => {
+                        for tx in txs {
+                            tx.send(clone_result(&result)).ok();
+                        }
+                    }
+                    Some(QueryChannel::Sync { txs, providers, .. }) => {
+                        // progress events are emitted before the database thread
+                        // inserted the block, by completion it inserted all of them
+                        if result.is_ok() {
+                            self.send_sync_progress(&id, &txs, &providers, counters);
+                        }
+                        for tx in txs {
+                            tx.unbounded_send(SyncEvent::Complete(clone_result(&result)))
+                                .ok();
+                        }
+                    }
+                    _ => {}
                 }
-                _ => {}
-            },
+            }
+        }
+    }
+
+    fn send_sync_progress(
+        &self,
+        id: &QueryId,
+        txs: &[mpsc::UnboundedSender<SyncEvent>],
+        providers: &[PeerId],
+        counters: &BitswapCounters,
+    ) {
+        let progress = match counters.sync_progress(id) {
+            Some(progress) => progress,
+            None => return,
+        };
+        let providers = providers
+            .iter()
+            .filter(|peer| self.peers.is_connected(peer))
+            .count();
+        for tx in txs {
+            tx.unbounded_send(SyncEvent::Progress {
+                missing: progress.missing,
+                blocks: progress.blocks,
+                bytes: progress.bytes,
+                providers,
+            })
+            .ok();
         }
     }
 }
 
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
-    pub fn inject_ping_event(&mut self, event: ping::Event) {
-        // Don't really need to do anything here as ping handles disconnecting
-        // automatically.
+    /// Records the rtt and closes the connections to peers that failed
+    /// `max_failures` pings in a row.
+    pub fn inject_ping_event(&mut self, event: ping::Event, max_failures: Option<u32>) {
         let peer = event.peer;
-        match event.result {
+        let failures = match event.result {
             Ok(ping::Success::Ping { rtt }) => {
                 //tracing::trace!("ping: rtt to {} is {} ms", peer, rtt.as_millis());
                 self.peers.set_rtt(&peer, Some(rtt));
+                return;
             }
             Ok(ping::Success::Pong) => {
                 //tracing::trace!("ping: pong from {}", peer);
+                return;
             }
             Err(ping::Failure::Timeout) => {
                 tracing::debug!("ping: timeout to {}", peer);
-                self.peers.set_rtt(&peer, None);
+                self.peers.set_rtt(&peer, None)
             }
             Err(ping::Failure::Other { error }) => {
                 tracing::info!("ping: failure with {}: {}", peer, error);
-                self.peers.set_rtt(&peer, None);
+                self.peers.set_rtt(&peer, None)
             }
             Err(ping::Failure::Unsupported) => {
                 tracing::warn!("ping: {} does not support the ping protocol", peer);
+                return;
             }
+        };
+        if max_failures.map_or(false, |max| failures >= max) {
+            tracing::debug!("ping: {} failed {} pings, disconnecting", peer, failures);
+            self.peers.notify(Event::PeerUnresponsive(peer));
+            self.peers.close_connections(&peer);
         }
     }
+
+    /// Returns `providers` ordered by their average ping rtt, fastest first.
+    /// Peers without rtt come last.
+    fn by_rtt(&self, providers: &[PeerId]) -> Vec<PeerId> {
+        let mut providers = providers
+            .iter()
+            .map(|peer| (self.peers.rtt(peer).map(|rtt| rtt.avg()), *peer))
+            .collect::<Vec<_>>();
+        providers.sort_by_key(|(rtt, _)| (rtt.is_none(), *rtt));
+        providers.into_iter().map(|(_, peer)| peer).collect()
+    }
 }
 
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
@@ -316,6 +807,135 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     }
 }
 
+impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    /// Returns the relay whose reservation changed and whether it is active.
+    pub fn inject_relay_client_event(&mut self, event: RelayClientEvent) -> Option<(PeerId, bool)> {
+        match event {
+            RelayClientEvent::ReservationReqAccepted { relay_peer_id, .. } => {
+                tracing::debug!("relay: reservation on {} accepted", relay_peer_id);
+                self.peers.notify(Event::RelayReservation(
+                    relay_peer_id,
+                    ReservationStatus::Active,
+                ));
+                Some((relay_peer_id, true))
+            }
+            RelayClientEvent::ReservationReqFailed {
+                relay_peer_id,
+                error,
+                ..
+            } => {
+                tracing::info!(
+                    "relay: reservation on {} failed: {:?}",
+                    relay_peer_id,
+                    error
+                );
+                self.peers.notify(Event::RelayReservation(
+                    relay_peer_id,
+                    ReservationStatus::Failed(format!("{:?}", error)),
+                ));
+                Some((relay_peer_id, false))
+            }
+            event => {
+                tracing::trace!("relay: {:?}", event);
+                None
+            }
+        }
+    }
+
+    pub fn inject_relay_server_event(&mut self, event: RelayServerEvent) {
+        match event {
+            RelayServerEvent::ReservationReqAccepted {
+                src_peer_id,
+                renewed,
+            } => {
+                tracing::debug!("relay server: reservation of {} accepted", src_peer_id);
+                if !renewed {
+                    RELAY_RESERVATIONS.inc();
+                }
+            }
+            RelayServerEvent::ReservationTimedOut { src_peer_id } => {
+                tracing::debug!("relay server: reservation of {} timed out", src_peer_id);
+                RELAY_RESERVATIONS.dec();
+            }
+            RelayServerEvent::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                tracing::debug!("relay server: relaying {} to {}", src_peer_id, dst_peer_id);
+                RELAY_CIRCUITS.inc();
+                RELAY_CIRCUITS_TOTAL.inc();
+            }
+            RelayServerEvent::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            } => {
+                tracing::debug!(
+                    "relay server: circuit {} to {} closed: {:?}",
+                    src_peer_id,
+                    dst_peer_id,
+                    error
+                );
+                RELAY_CIRCUITS.dec();
+            }
+            event => {
+                tracing::trace!("relay server: {:?}", event);
+            }
+        }
+    }
+
+    /// Returns the old and new status if the status changed.
+    pub fn inject_autonat_event(
+        &mut self,
+        event: autonat::Event,
+        nat_status: &Variable<NatStatus>,
+    ) -> Option<(NatStatus, NatStatus)> {
+        match event {
+            autonat::Event::StatusChanged { old, new } => {
+                tracing::info!("autonat: status changed from {:?} to {:?}", old, new);
+                *nat_status.write() = new.clone();
+                self.peers.notify(Event::NatStatusChanged(new.clone()));
+                Some((old, new))
+            }
+            event => {
+                tracing::trace!("autonat: {:?}", event);
+                None
+            }
+        }
+    }
+
+    pub fn inject_dcutr_event(&mut self, event: dcutr::behaviour::Event) {
+        match event {
+            dcutr::behaviour::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                tracing::debug!("dcutr: upgraded connection to {}", remote_peer_id);
+                self.peers
+                    .notify(Event::DirectConnectionUpgraded(remote_peer_id));
+            }
+            dcutr::behaviour::Event::DirectConnectionUpgradeFailed {
+                remote_peer_id,
+                error,
+            } => {
+                tracing::debug!(
+                    "dcutr: upgrading connection to {} failed: {:?}",
+                    remote_peer_id,
+                    error
+                );
+                self.peers.notify(Event::DirectConnectionUpgradeFailed(
+                    remote_peer_id,
+                    format!("{:?}", error),
+                ));
+            }
+            event => {
+                tracing::trace!("dcutr: {:?}", event);
+            }
+        }
+    }
+
+    pub fn notify(&mut self, event: Event) {
+        self.peers.notify(event);
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct GossipsubPublishError(pub libp2p::gossipsub::error::PublishError);
@@ -325,6 +945,8 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         &mut self,
         event: GossipsubEvent,
         subscriptions: &mut FnvHashMap<String, Vec<mpsc::UnboundedSender<GossipEvent>>>,
+        validators: &FnvHashMap<String, MessageValidator>,
+        topics: &Variable<FnvHashMap<String, FnvHashSet<PeerId>>>,
     ) {
         match event {
             GossipsubEvent::Message {
@@ -336,15 +958,49 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                         ..
                     },
                 propagation_source,
-                ..
+                message_id,
             } => {
+                let peer = source.unwrap_or(propagation_source);
+                GOSSIP_MESSAGES_RECEIVED
+                    .with_label_values(&[topic.as_str()])
+                    .inc();
+                let result = validators
+                    .get(topic.as_str())
+                    .map(|validator| (validator.0)(&peer, &data))
+                    .unwrap_or(ValidationResult::Accept);
+                if let Some(gossipsub) = self.gossipsub.as_mut() {
+                    if let Err(err) = gossipsub.report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        result.into(),
+                    ) {
+                        tracing::trace!("reporting validation of {} failed: {:?}", message_id, err);
+                    }
+                }
+                if result != ValidationResult::Accept {
+                    GOSSIP_MESSAGES_REJECTED
+                        .with_label_values(&[topic.as_str()])
+                        .inc();
+                    tracing::debug!(
+                        "dropping gossip message {} from {}: {:?}",
+                        message_id,
+                        peer,
+                        result
+                    );
+                    return;
+                }
                 self.notify_subscribers(
                     &topic.to_string(),
-                    GossipEvent::Message(source.unwrap_or(propagation_source), data.into()),
+                    GossipEvent::Message(peer, data.into(), Some(message_id)),
                     subscriptions,
                 );
             }
             GossipsubEvent::Subscribed { peer_id, topic, .. } => {
+                topics
+                    .write()
+                    .entry(topic.to_string())
+                    .or_default()
+                    .insert(peer_id);
                 self.peers
                     .notify(Event::Subscribed(peer_id, topic.to_string()));
 
@@ -355,6 +1011,15 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
                 );
             }
             GossipsubEvent::Unsubscribed { peer_id, topic, .. } => {
+                {
+                    let mut topics = topics.write();
+                    if let Some(peers) = topics.get_mut(topic.as_str()) {
+                        peers.remove(&peer_id);
+                        if peers.is_empty() {
+                            topics.remove(topic.as_str());
+                        }
+                    }
+                }
                 self.peers
                     .notify(Event::Unsubscribed(peer_id, topic.to_string()));
                 self.notify_subscribers(
@@ -377,7 +1042,11 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         match event {
             BroadcastEvent::Received(peer_id, topic, data) => {
                 let topic = std::str::from_utf8(&topic).unwrap();
-                self.notify_subscribers(topic, GossipEvent::Message(peer_id, data), subscriptions);
+                self.notify_subscribers(
+                    topic,
+                    GossipEvent::Message(peer_id, data, None),
+                    subscriptions,
+                );
             }
             BroadcastEvent::Subscribed(peer_id, topic) => {
                 if let Ok(topic) = std::str::from_utf8(&topic) {
@@ -400,16 +1069,95 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     }
 }
 
+impl<P: StoreParams> NetworkBackendBehaviour<P> {
+    pub fn inject_request_event(
+        &mut self,
+        event: RequestResponseEvent<Request, Response>,
+        requests: &mut PendingRequests,
+    ) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                let protocol = request.protocol.clone();
+                if let Err(channel) = requests.dispatch(peer, request, channel) {
+                    tracing::debug!("rejecting request of {} from {}", protocol, peer);
+                    if let Some(rr) = self.request_response.as_mut() {
+                        rr.send_response(channel, Response::Unsupported).ok();
+                    }
+                }
+            }
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => requests.complete(&request_id, Ok(response)),
+            RequestResponseEvent::OutboundFailure {
+                request_id, error, ..
+            } => {
+                let failure = match error {
+                    OutboundFailure::Timeout => RequestFailure::Timeout,
+                    error => RequestFailure::Other(error.to_string()),
+                };
+                requests.complete(&request_id, Err(failure));
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                tracing::debug!("request from {} failed: {}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Sends the responses passed to `InboundRequest::respond`.
+    pub fn poll_responses(&mut self, cx: &mut Context, requests: &mut PendingRequests) {
+        while let Poll::Ready((channel, response)) = requests.poll_response(cx) {
+            if let Some(rr) = self.request_response.as_mut() {
+                // fails if the connection was closed in the meantime
+                rr.send_response(channel, response).ok();
+            }
+        }
+    }
+
+    pub fn request(
+        &mut self,
+        peer: PeerId,
+        protocol: String,
+        data: Vec<u8>,
+        tx: oneshot::Sender<Result<Vec<u8>>>,
+        requests: &mut PendingRequests,
+    ) {
+        if let Some(rr) = self.request_response.as_mut() {
+            let request = Request {
+                protocol: protocol.clone(),
+                data,
+            };
+            let id = rr.send_request(&peer, request);
+            requests.insert(id, peer, protocol, tx);
+        } else {
+            tx.send(Err(DisabledProtocol("request-response").into()))
+                .ok();
+        }
+    }
+}
+
 impl<P: StoreParams> NetworkBackendBehaviour<P> {
     /// Create a Kademlia behaviour with the IPFS bootstrap nodes.
     pub fn new<S: BitswapStore<Params = P>>(
         config: &mut NetworkConfig,
         store: S,
-        listeners: Writer<FnvHashSet<Multiaddr>>,
-        peers: Writer<FnvHashMap<PeerId, PeerInfo>>,
-        external: Writer<Vec<AddressRecord>>,
+        relay_client: RelayClient,
+        listeners: Variable<FnvHashSet<Multiaddr>>,
+        peers: Variable<FnvHashMap<PeerId, PeerInfo>>,
+        external: Variable<Vec<AddressRecord>>,
     ) -> Result<Self> {
-        let node_key = libp2p::identity::Keypair::Ed25519(config.node_key.clone());
+        let node_key = config.node_key.clone();
         let node_name = config.node_name.clone();
         let peer_id = node_key.public().to_peer_id();
         let mdns = if let Some(config) = config.mdns.take() {
@@ -423,7 +1171,17 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         } else {
             None
         };
-        let ping = config.ping.take().map(ping::Behaviour::new);
+        let ping = config
+            .ping
+            .take()
+            .map(|ping| match config.ping_max_failures {
+                // failures are counted by `inject_ping_event`, the handler would close the
+                // connection without reporting them
+                Some(_) => {
+                    ping::Behaviour::new(ping.with_max_failures(NonZeroU32::new(u32::MAX).unwrap()))
+                }
+                None => ping::Behaviour::new(ping),
+            });
         let identify = if let Some(mut config) = config.identify.take() {
             config.local_public_key = node_key.public();
             config.agent_version = node_name;
@@ -431,9 +1189,20 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         } else {
             None
         };
-        let gossipsub = if let Some(config) = config.gossipsub.take() {
-            let gossipsub = Gossipsub::new(MessageAuthenticity::Signed(node_key), config)
+        let gossipsub = if let Some(gossipsub_config) = config.gossipsub.take() {
+            // messages are forwarded once they are accepted in `inject_gossip_event`
+            let gossipsub_config = GossipsubConfigBuilder::from(gossipsub_config)
+                .validate_messages()
+                .build()
                 .map_err(|err| anyhow::anyhow!("{}", err))?;
+            let mut gossipsub =
+                Gossipsub::new(MessageAuthenticity::Signed(node_key), gossipsub_config)
+                    .map_err(|err| anyhow::anyhow!("{}", err))?;
+            if let Some((params, thresholds)) = config.gossipsub_scoring.take() {
+                gossipsub
+                    .with_peer_score(params, thresholds)
+                    .map_err(|err| anyhow::anyhow!("{}", err))?;
+            }
             Some(gossipsub)
         } else {
             None
@@ -443,6 +1212,35 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
             .bitswap
             .take()
             .map(|config| Bitswap::new(config, store));
+        let autonat = config
+            .autonat
+            .take()
+            .map(|config| autonat::Behaviour::new(peer_id, config));
+        let dcutr = if config.dcutr {
+            Some(dcutr::behaviour::Behaviour::new())
+        } else {
+            None
+        };
+        let request_response = config.request_response.as_ref().map(|config| {
+            let codec = RequestCodec::new(config.max_request_size, config.max_response_size);
+            let mut rr_config = request_response::RequestResponseConfig::default();
+            rr_config.set_request_timeout(config.request_timeout);
+            RequestResponse::new(
+                codec,
+                std::iter::once((RequestProtocol, ProtocolSupport::Full)),
+                rr_config,
+            )
+        });
+        let relay_server = config.relay_server.take().map(|config| {
+            let config = RelayConfig {
+                max_reservations: config.max_reservations,
+                max_circuits: config.max_circuits,
+                max_circuit_bytes: config.max_circuit_bytes,
+                max_circuit_duration: config.max_circuit_duration,
+                ..Default::default()
+            };
+            Relay::new(peer_id, config)
+        });
         Ok(Self {
             peers: AddressBook::new(
                 peer_id,
@@ -460,6 +1258,11 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
             bitswap: bitswap.into(),
             gossipsub: gossipsub.into(),
             broadcast: broadcast.into(),
+            relay_client,
+            relay_server: relay_server.into(),
+            autonat: autonat.into(),
+            dcutr: dcutr.into(),
+            request_response: request_response.into(),
         })
     }
 
@@ -485,10 +1288,27 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         self.peers.dial(peer_id);
     }
 
+    pub fn connect(&mut self, peer_id: &PeerId) -> usize {
+        self.peers.connect(peer_id)
+    }
+
+    pub fn peer_info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
+        self.peers.info(peer_id)
+    }
+
     pub fn dial_address(&mut self, peer_id: &PeerId, addr: Multiaddr) {
         self.peers.dial_address(peer_id, addr);
     }
 
+    pub fn disconnect(
+        &mut self,
+        peer_id: &PeerId,
+        addr: Option<&Multiaddr>,
+        tx: oneshot::Sender<()>,
+    ) {
+        self.peers.disconnect(peer_id, addr, tx);
+    }
+
     pub(crate) fn connection_closed(
         &mut self,
         peer: PeerId,
@@ -519,26 +1339,66 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         }
     }
 
-    // pub fn get_closest_peers<K>(
-    //     &mut self,
-    //     key: K,
-    //     bootstrap_complete: bool,
-    //     queries: &mut FnvHashMap<QueryId, QueryChannel>,
-    // ) -> GetClosestPeersChannel
-    // where
-    //     K: Into<BucketKey<K>> + Into<Vec<u8>> + Clone,
-    // {
-    //     let (tx, rx) = oneshot::channel();
-    //     if bootstrap_complete {
-    //         if let Some(kad) = self.kad.as_mut() {
-    //             let id = kad.get_closest_peers(key);
-    //             queries.insert(id.into(), QueryChannel::GetClosestPeers(tx));
-    //         }
-    //     } else {
-    //         tx.send(Err(NotBootstrapped.into())).ok();
-    //     }
-    //     rx
-    // }
+    /// Returns the number of peers in the kad routing table.
+    pub fn routing_table_size(&mut self) -> usize {
+        if let Some(kad) = self.kad.as_mut() {
+            kad.kbuckets().map(|bucket| bucket.num_entries()).sum()
+        } else {
+            0
+        }
+    }
+
+    /// Refreshes the kad routing table without reporting the result.
+    pub fn refresh_routing_table(&mut self) {
+        if let Some(kad) = self.kad.as_mut() {
+            if let Err(err) = kad.bootstrap() {
+                tracing::debug!("refreshing the routing table failed: {}", err);
+            }
+        }
+    }
+
+    pub fn get_closest_peers(
+        &mut self,
+        peer: PeerId,
+        bootstrap_complete: bool,
+        queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        tx: ClosestPeersChannel,
+    ) {
+        if !bootstrap_complete {
+            tx.send(Err(NotBootstrapped.into())).ok();
+            return;
+        }
+        if let Some(kad) = self.kad.as_mut() {
+            if kad.kbuckets().all(|bucket| bucket.num_entries() == 0) {
+                tx.send(Err(NoKnownPeers.into())).ok();
+                return;
+            }
+            let id = kad.get_closest_peers(peer);
+            queries.insert(id.into(), QueryChannel::GetClosestPeers(tx));
+        } else {
+            tx.send(Err(DisabledProtocol("kad").into())).ok();
+        }
+    }
+
+    /// Pairs each peer with the addresses known for it, both from the address
+    /// book and from the kademlia routing table.
+    fn with_addresses(&mut self, peers: Vec<PeerId>) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        peers
+            .into_iter()
+            .map(|peer| {
+                let mut addrs = self.peers.addresses_of_peer(&peer);
+                if let Some(kad) = self.kad.as_mut() {
+                    for mut addr in kad.addresses_of_peer(&peer) {
+                        normalize_addr(&mut addr, &peer);
+                        if !addrs.contains(&addr) {
+                            addrs.push(addr);
+                        }
+                    }
+                }
+                (peer, addrs)
+            })
+            .collect()
+    }
 
     pub fn provide(
         &mut self,
@@ -575,14 +1435,87 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         bootstrap_complete: bool,
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
         tx: oneshot::Sender<Result<HashSet<PeerId>>>,
-    ) {
+    ) -> Option<QueryId> {
         if bootstrap_complete {
-            if let Some(kad) = self.kad.as_mut() {
-                let id = kad.get_providers(key);
-                queries.insert(id.into(), QueryChannel::GetProviders(tx));
-            }
+            let kad = self.kad.as_mut()?;
+            let id = kad.get_providers(key);
+            queries.insert(id.into(), QueryChannel::GetProviders(tx));
+            Some(id.into())
         } else {
             tx.send(Err(NotBootstrapped.into())).ok();
+            None
+        }
+    }
+
+    pub fn providers_stream(
+        &mut self,
+        key: Key,
+        bootstrap_complete: bool,
+        queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        tx: mpsc::UnboundedSender<PeerId>,
+    ) {
+        if !bootstrap_complete {
+            tracing::debug!("{}", NotBootstrapped);
+            return;
+        }
+        if let Some(kad) = self.kad.as_mut() {
+            let id = kad.get_providers(key);
+            queries.insert(
+                id.into(),
+                QueryChannel::GetProvidersStream(tx, Default::default()),
+            );
+        }
+    }
+
+    /// Forwards the providers found so far by running provider queries to their
+    /// streams. Queries whose stream was dropped are finished early.
+    pub fn poll_providers_streams(&mut self, queries: &mut FnvHashMap<QueryId, QueryChannel>) {
+        let local_peer_id = *self.peers.local_peer_id();
+        let kad = if let Some(kad) = self.kad.as_mut() {
+            kad
+        } else {
+            return;
+        };
+        let mut finished = vec![];
+        for (id, channel) in queries.iter_mut() {
+            if let (
+                QueryId(InnerQueryId::Kad(kad_id)),
+                QueryChannel::GetProvidersStream(tx, seen),
+            ) = (id, channel)
+            {
+                if tx.is_closed() {
+                    if let Some(mut query) = kad.query_mut(kad_id) {
+                        query.finish();
+                    }
+                    finished.push(*id);
+                    continue;
+                }
+                if let Some(query) = kad.query(kad_id) {
+                    if let QueryInfo::GetProviders { providers, .. } = query.info() {
+                        for peer in providers {
+                            if *peer != local_peer_id && seen.insert(*peer) {
+                                tx.unbounded_send(*peer).ok();
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for id in finished {
+            queries.remove(&id);
+        }
+    }
+
+    fn stream_providers(
+        &self,
+        tx: &mpsc::UnboundedSender<PeerId>,
+        seen: &mut FnvHashSet<PeerId>,
+        providers: &HashSet<PeerId>,
+    ) {
+        for peer in providers {
+            if peer != self.peers.local_peer_id() && seen.insert(*peer) {
+                tx.unbounded_send(*peer).ok();
+            }
         }
     }
 
@@ -593,14 +1526,15 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         bootstrap_complete: bool,
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
         tx: oneshot::Sender<Result<Vec<PeerRecord>>>,
-    ) {
+    ) -> Option<QueryId> {
         if bootstrap_complete {
-            if let Some(kad) = self.kad.as_mut() {
-                let id = kad.get_record(key, quorum);
-                queries.insert(id.into(), QueryChannel::GetRecord(tx));
-            }
+            let kad = self.kad.as_mut()?;
+            let id = kad.get_record(key, quorum);
+            queries.insert(id.into(), QueryChannel::GetRecord(tx));
+            Some(id.into())
         } else {
             tx.send(Err(NotBootstrapped.into())).ok();
+            None
         }
     }
 
@@ -628,6 +1562,15 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         }
     }
 
+    /// Publishes a record again without reporting the result.
+    pub fn republish_record(&mut self, record: Record, quorum: Quorum) {
+        if let Some(kad) = self.kad.as_mut() {
+            if let Err(err) = kad.put_record(record, quorum) {
+                tracing::debug!("republishing record failed: {}", err);
+            }
+        }
+    }
+
     pub fn remove_record(&mut self, key: &Key) {
         if let Some(kad) = self.kad.as_mut() {
             kad.remove_record(key);
@@ -668,12 +1611,61 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
             if let Err(err) = gossipsub.unsubscribe(&gossip_topic) {
                 tracing::trace!("unsubscribing from topic {} failed with {:?}", topic, err);
             }
+            GOSSIP_MESH_PEERS.remove_label_values(&[topic]).ok();
         }
         if let Some(broadcast) = self.broadcast.as_mut() {
             broadcast.unsubscribe(&broadcast_topic);
         }
     }
 
+    /// Samples the gossipsub scores of all known peers, notifying about peers
+    /// crossing the pruning or graylist threshold.
+    pub fn update_peer_scores(
+        &mut self,
+        scores: &Variable<FnvHashMap<PeerId, f64>>,
+        graylist_threshold: f64,
+    ) {
+        let gossipsub = if let Some(gossipsub) = self.gossipsub.as_ref() {
+            gossipsub
+        } else {
+            return;
+        };
+        let new = gossipsub
+            .all_peers()
+            .filter_map(|(peer, _)| Some((*peer, gossipsub.peer_score(peer)?)))
+            .collect::<FnvHashMap<_, _>>();
+        let mut events = vec![];
+        {
+            let old = scores.read();
+            for (peer, score) in &new {
+                GOSSIP_PEER_SCORES.observe(*score);
+                let before = old.get(peer).copied().unwrap_or_default();
+                if *score < 0.0 && before >= 0.0 {
+                    events.push(Event::GossipPruned(*peer));
+                }
+                if *score < graylist_threshold && before >= graylist_threshold {
+                    events.push(Event::GossipGraylisted(*peer));
+                }
+            }
+        }
+        *scores.write() = new;
+        for event in events {
+            self.peers.notify(event);
+        }
+    }
+
+    /// Samples the mesh size of the subscribed gossipsub topics.
+    pub fn update_mesh_metrics(&self) {
+        if let Some(gossipsub) = self.gossipsub.as_ref() {
+            for topic in gossipsub.topics() {
+                let peers = gossipsub.mesh_peers(topic).count();
+                GOSSIP_MESH_PEERS
+                    .with_label_values(&[topic.as_str()])
+                    .set(peers as i64);
+            }
+        }
+    }
+
     fn notify_subscribers(
         &mut self,
         topic: &str,
@@ -689,18 +1681,14 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         }
     }
 
-    pub fn publish(&mut self, topic: &str, msg: Vec<u8>) -> Result<()> {
-        use libp2p::gossipsub::error::PublishError;
+    pub fn publish(&mut self, topic: &str, msg: Vec<u8>) -> Result<MessageId> {
         if let Some(gossipsub) = self.gossipsub.as_mut() {
             let gossip_topic = IdentTopic::new(topic);
-            match gossipsub.publish(gossip_topic, msg) {
-                Ok(_) => Ok(()),
-                Err(PublishError::InsufficientPeers) => {
-                    tracing::trace!("publish: insufficient peers.");
-                    Ok(())
-                }
-                Err(err) => Err(GossipsubPublishError(err).into()),
-            }
+            let id = gossipsub
+                .publish(gossip_topic, msg)
+                .map_err(GossipsubPublishError)?;
+            GOSSIP_MESSAGES_PUBLISHED.with_label_values(&[topic]).inc();
+            Ok(id)
         } else {
             Err(DisabledProtocol("gossipsub").into())
         }
@@ -717,34 +1705,102 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
         }
     }
 
+    /// Joins a running get of `cid` from the same providers or starts a new
+    /// one.
     pub fn get(
         &mut self,
         cid: Cid,
-        providers: impl Iterator<Item = PeerId>,
+        mut providers: Vec<PeerId>,
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
     ) -> (GetChannel, QueryId) {
-        let bitswap = self.bitswap.as_mut().expect("bitswap enabled");
         let (tx, rx) = oneshot::channel();
-        let id = bitswap.get(cid, providers);
-        queries.insert(id.into(), QueryChannel::Get(tx));
+        providers.sort_unstable();
+        providers.dedup();
+        for (id, query) in queries.iter_mut() {
+            if let QueryChannel::Get {
+                cid: c,
+                providers: p,
+                txs,
+            } = query
+            {
+                if *c == cid && *p == providers {
+                    txs.push(tx);
+                    return (rx, *id);
+                }
+            }
+        }
+        let ordered = self.by_rtt(&providers);
+        let bitswap = self.bitswap.as_mut().expect("bitswap enabled");
+        let id = bitswap.get(cid, ordered.into_iter());
+        queries.insert(
+            id.into(),
+            QueryChannel::Get {
+                cid,
+                providers,
+                txs: vec![tx],
+            },
+        );
         (rx, id.into())
     }
 
+    /// Joins a running sync of `cid` from the same providers or starts a new
+    /// one. Callers joining a running sync only see the progress from then
+    /// on.
     pub fn sync(
         &mut self,
         cid: Cid,
-        providers: Vec<PeerId>,
+        mut providers: Vec<PeerId>,
         missing: impl Iterator<Item = Cid>,
         queries: &mut FnvHashMap<QueryId, QueryChannel>,
     ) -> (SyncChannel, QueryId) {
-        let bitswap = self.bitswap.as_mut().expect("bitswap enabled");
         let (tx, rx) = mpsc::unbounded();
-        let id = bitswap.sync(cid, providers, missing);
-        queries.insert(id.into(), QueryChannel::Sync(tx));
+        providers.sort_unstable();
+        providers.dedup();
+        for (id, query) in queries.iter_mut() {
+            if let QueryChannel::Sync {
+                cid: c,
+                providers: p,
+                txs,
+                ..
+            } = query
+            {
+                if *c == cid && *p == providers {
+                    txs.push(tx);
+                    return (rx, *id);
+                }
+            }
+        }
+        let ordered = self.by_rtt(&providers);
+        let bitswap = self.bitswap.as_mut().expect("bitswap enabled");
+        let id = bitswap.sync(cid, ordered, missing);
+        queries.insert(
+            id.into(),
+            QueryChannel::Sync {
+                cid,
+                providers,
+                txs: vec![tx],
+            },
+        );
         (rx, id.into())
     }
 
+    /// Cancels a query. Shared gets and syncs are only canceled once all
+    /// callers dropped their receivers.
     pub fn cancel(&mut self, id: QueryId, queries: &mut FnvHashMap<QueryId, QueryChannel>) {
+        let in_use = match queries.get_mut(&id) {
+            Some(QueryChannel::Get { txs, .. }) => {
+                txs.retain(|tx| !tx.is_canceled());
+                !txs.is_empty()
+            }
+            Some(QueryChannel::Sync { txs, .. }) => {
+                txs.retain(|tx| !tx.is_closed());
+                !txs.is_empty()
+            }
+            _ => false,
+        };
+        if in_use {
+            return;
+        }
         queries.remove(&id);
         if let QueryId(InnerQueryId::Bitswap(id)) = id {
             self.bitswap.as_mut().unwrap().cancel(id);
@@ -754,4 +1810,8 @@ impl<P: StoreParams> NetworkBackendBehaviour<P> {
     pub fn swarm_events(&mut self, tx: UnboundedSender<Event>) {
         self.peers.swarm_events(tx)
     }
+
+    pub fn swarm_events_filtered(&mut self, filter: EventFilter) -> SwarmEvents {
+        self.peers.swarm_events_filtered(filter)
+    }
 }
@@ -1,7 +1,22 @@
+use super::gate::ConnectionGate;
 use crate::config::*;
-use libp2p::identity::ed25519::Keypair;
+use anyhow::Context;
+use futures::io::{AsyncRead, AsyncWrite};
+use libipld::{Cid, Result};
+use libp2p::{
+    core::transport::{Boxed, Transport},
+    identity::{ed25519, Keypair},
+    multiaddr::Protocol,
+    pnet::PreSharedKey,
+    websocket::tls::{Certificate, PrivateKey},
+    Multiaddr, PeerId,
+};
+use std::{collections::BTreeSet, path::Path, sync::Arc, time::Duration};
 
 /// Network configuration.
+///
+/// The fields are public for backwards compatibility, prefer using
+/// `NetworkConfigBuilder` which checks that the settings are consistent.
 #[derive(Debug)]
 pub struct NetworkConfig {
     /// Enable adding loopback addresses to the address book. Should be
@@ -17,33 +32,479 @@ pub struct NetworkConfig {
     pub node_name: String,
     /// Node key.
     pub node_key: Keypair,
-    /// Pre shared key.
-    pub psk: Option<[u8; 32]>,
+    /// Pre shared key of a private network. Connections to peers with a
+    /// different or no key fail during the handshake, see
+    /// `ConnectionFailureKind::PskMismatch`.
+    pub psk: Option<PreSharedKey>,
     /// Dns config. If no dns config is provided the system
     /// defaults will be used.
     pub dns: Option<DnsConfig>,
-    /// Mdns config.
+    /// Websocket config. If no websocket config is provided only plain tcp
+    /// is used.
+    pub websocket: Option<WebsocketConfig>,
+    /// Transport replacing the tcp, dns and websocket transports, see
+    /// `custom_transport`. Connections are still encrypted with noise and
+    /// multiplexed, the `psk` and circuit relays keep working.
+    pub transport: Option<CustomTransport>,
+    /// Mdns config. Can be changed at runtime with `Ipfs::set_mdns`.
     pub mdns: Option<MdnsConfig>,
+    /// Dial peers discovered by mdns. If disabled, discoveries are only
+    /// reported as `Event::MdnsDiscovered` and the application decides
+    /// whether to dial. Enabled by default.
+    pub mdns_auto_dial: bool,
     /// Kad config.
     pub kad: Option<KadConfig>,
     /// Ping config.
     pub ping: Option<PingConfig>,
+    /// Close the connections to a peer after this many pings failed in a
+    /// row, reporting it as `Event::PeerUnresponsive`. Requires `ping`.
+    /// Disabled by default.
+    pub ping_max_failures: Option<u32>,
+    /// AutoNAT config. Connected peers are asked to dial back our addresses
+    /// to detect whether this node is reachable. While enabled, observed
+    /// addresses are only reported as external addresses once confirmed.
+    pub autonat: Option<AutonatConfig>,
+    /// Try to upgrade relayed connections to direct connections by hole
+    /// punching (DCUtR). Enabled by default. If the remote has no direct
+    /// address to offer, the relayed connection is closed.
+    pub dcutr: bool,
     /// Identify config. Note that the `node_name` and
     /// `node_key` will overwrite the `local_public_key` and
     /// the `agent_version`.
     pub identify: Option<IdentifyConfig>,
     /// Gossipsub config.
     pub gossipsub: Option<GossipsubConfig>,
+    /// Gossipsub peer scoring, per topic parameters are set in
+    /// `PeerScoreParams::topics`. Disabled by default.
+    pub gossipsub_scoring: Option<(PeerScoreParams, PeerScoreThresholds)>,
     /// Broadcast config.
     pub broadcast: Option<BroadcastConfig>,
     /// Bitswap config.
     pub bitswap: Option<BitswapConfig>,
+    /// Blocks served to peers over bitswap, serves all blocks by default.
+    pub serve_policy: ServePolicy,
+    /// Limits of serving blocks to peers over bitswap, can be changed with
+    /// `Ipfs::set_serve_rate_limit`. Unlimited by default.
+    pub serve_rate_limit: Option<RateLimit>,
+    /// Limits on the number of connections.
+    pub connection_limits: ConnectionLimits,
+    /// Rules for accepting incoming and outgoing connections, can be changed
+    /// with `Ipfs::set_connection_gate`. Accepts all connections by default.
+    pub connection_gate: ConnectionGate,
     /// Keep explicitly dialed and incoming connections open indefinitely
     pub keep_alive: bool,
+    /// Default timeout used when fetching blocks through the `Store` trait.
+    /// If `None` fetching a block waits until it is found or all providers
+    /// failed.
+    pub fetch_timeout: Option<Duration>,
+    /// When fetching a block without providers, discover them in the dht.
+    /// Disabled by default.
+    pub fetch_via_dht: Option<DhtFetchConfig>,
+    /// When a sync can't get a block from its providers, discover providers
+    /// of the block in the dht and continue with them. Disabled by default.
+    pub sync_via_dht: Option<DhtSyncConfig>,
+    /// Sync dags with many block requests in flight instead of the bitswap
+    /// sync query, which requests the missing blocks level by level.
+    /// Disabled by default.
+    pub sync_pipeline: Option<SyncPipelineConfig>,
+    /// Schedule the block requests of all syncs together, bounding their
+    /// number and taking turns between the syncs. Syncs are pipelined with
+    /// the default `SyncPipelineConfig` if `sync_pipeline` isn't set.
+    /// The blocks requested by `get` and `fetch` bypass the scheduler.
+    /// Disabled by default.
+    pub sync_scheduler: Option<SyncSchedulerConfig>,
+    /// Addresses to listen on. `Ipfs::new` returns once they are bound and
+    /// fails with `ListenFailed` if one of them can't be bound.
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Interval at which the address book is saved to the store, it is also
+    /// saved when the `Ipfs` is dropped. `None` disables saving, a saved
+    /// address book is still loaded on startup.
+    pub address_book_interval: Option<Duration>,
+    /// Addresses that weren't seen for longer than this are dropped when
+    /// loading the address book.
+    pub address_book_max_age: Duration,
+    /// Nodes to bootstrap from. `Ipfs::new` starts bootstrapping from them
+    /// in the background, they are also used by the routing table
+    /// maintenance configured in `rebootstrap`.
+    pub bootstrap: Vec<(PeerId, Multiaddr)>,
+    /// Bootstrap again from the `bootstrap` nodes when the routing table
+    /// runs low and refresh it periodically. Needs kad, enabled by default.
+    pub rebootstrap: Option<RebootstrapConfig>,
+    /// Blocks to announce as provided on the dht once bootstrapped and again
+    /// every `reprovide_interval`. Needs kad, disabled by default.
+    pub reprovide: ReprovidePolicy,
+    /// Interval of reprovider runs, provider records expire on remote nodes
+    /// after 24 hours.
+    pub reprovide_interval: Duration,
+    /// Circuit relays to make a reservation on, so that peers can reach this
+    /// node through them. The addresses have to end with the `/p2p` protocol
+    /// containing the `PeerId` of the relay.
+    pub relays: Vec<Multiaddr>,
+    /// Relay server config. If a relay server config is provided other
+    /// nodes can make reservations on this node and relay connections
+    /// through it. Note that reservations only succeed if this node has an
+    /// external address.
+    pub relay_server: Option<RelayServerConfig>,
+    /// Request response protocol config, see `Ipfs::request`. Disabled by
+    /// default, `register_protocol` enables it with the default limits.
+    pub request_response: Option<RequestResponseConfig>,
+}
+
+/// A connection of a `CustomTransport`.
+pub trait Socket: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Socket for T {}
+
+/// A transport for `NetworkConfig::transport`.
+pub type CustomTransport = Boxed<Box<dyn Socket>>;
+
+/// Boxes a transport yielding raw connections, like a memory transport or
+/// a tcp transport connecting through a proxy, for
+/// `NetworkConfig::transport`.
+pub fn custom_transport<T>(transport: T) -> CustomTransport
+where
+    T: Transport + Send + Unpin + 'static,
+    T::Output: Socket + 'static,
+    T::Error: Send + Sync,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+{
+    transport
+        .map(|socket, _| Box::new(socket) as Box<dyn Socket>)
+        .boxed()
+}
+
+/// Limits on the number of connections, `None` means unlimited. Connections
+/// exceeding a limit are rejected and reported as
+/// `Event::ConnectionLimitReached`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionLimits {
+    /// Maximum number of established incoming connections.
+    pub max_established_incoming: Option<u32>,
+    /// Maximum number of established outgoing connections.
+    pub max_established_outgoing: Option<u32>,
+    /// Maximum number of established connections per peer.
+    pub max_established_per_peer: Option<u32>,
+    /// Maximum number of outgoing connections being dialed.
+    pub max_pending_outgoing: Option<u32>,
+}
+
+/// Current number of connections, see `Ipfs::connection_counts`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionCounts {
+    /// Number of incoming connections being negotiated.
+    pub pending_incoming: u32,
+    /// Number of outgoing connections being dialed.
+    pub pending_outgoing: u32,
+    /// Number of established incoming connections.
+    pub established_incoming: u32,
+    /// Number of established outgoing connections.
+    pub established_outgoing: u32,
+}
+
+/// Configuration of provider discovery when fetching blocks.
+#[derive(Clone, Copy, Debug)]
+pub struct DhtFetchConfig {
+    /// Maximum number of discovered providers to request the block from.
+    pub max_providers: usize,
+    /// Deadline for discovering providers and fetching the block.
+    pub timeout: Duration,
+}
+
+impl Default for DhtFetchConfig {
+    fn default() -> Self {
+        Self {
+            max_providers: 8,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Configuration of provider discovery when syncing dags.
+#[derive(Clone, Copy, Debug)]
+pub struct DhtSyncConfig {
+    /// Maximum number of provider lookups per sync.
+    pub max_lookups: usize,
+    /// Maximum number of providers added by a lookup.
+    pub max_providers: usize,
+    /// Deadline of each lookup.
+    pub timeout: Duration,
+}
+
+impl Default for DhtSyncConfig {
+    fn default() -> Self {
+        Self {
+            max_lookups: 8,
+            max_providers: 8,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Order in which a pipelined sync requests the missing blocks it found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncTraversal {
+    /// Children of the last received block first.
+    DepthFirst,
+    /// Blocks in the order they were found.
+    BreadthFirst,
+}
+
+/// Configuration of pipelined syncs.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncPipelineConfig {
+    /// Maximum number of block requests in flight. The missing children of
+    /// a block are requested as soon as it arrives.
+    pub max_in_flight: usize,
+    /// Order in which the missing blocks are requested.
+    pub traversal: SyncTraversal,
+}
+
+impl Default for SyncPipelineConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 32,
+            traversal: SyncTraversal::DepthFirst,
+        }
+    }
+}
+
+/// Configuration of the scheduler of the block requests of syncs.
+#[derive(Clone, Copy, Debug)]
+pub struct SyncSchedulerConfig {
+    /// Maximum number of block requests outstanding over all syncs. The
+    /// blocks requested by `get` and `fetch` are not counted.
+    pub max_outstanding: usize,
+    /// Maximum number of block requests outstanding to a provider. The
+    /// limit of a provider is halved after a slow or failed response and
+    /// grows by one after each timely response, up to this maximum.
+    pub max_per_peer: usize,
+    /// Responses taking longer than this are slow.
+    pub slow_response: Duration,
+}
+
+impl Default for SyncSchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_outstanding: 128,
+            max_per_peer: 32,
+            slow_response: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Configuration of the routing table maintenance.
+#[derive(Clone, Copy, Debug)]
+pub struct RebootstrapConfig {
+    /// How often the routing table and connectivity are checked.
+    pub check_interval: Duration,
+    /// Bootstrap again from `NetworkConfig::bootstrap` when the routing
+    /// table has fewer peers than this or no peer is connected.
+    pub min_peers: usize,
+    /// Delay before retrying a failed bootstrap, doubled after each failure.
+    pub initial_backoff: Duration,
+    /// Upper limit of the retry delay.
+    pub max_backoff: Duration,
+    /// Interval of routing table refreshes once bootstrapped, `None` disables
+    /// them.
+    pub refresh_interval: Option<Duration>,
+}
+
+impl Default for RebootstrapConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(10),
+            min_peers: 1,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            refresh_interval: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// Blocks served to peers over bitswap. Requests for other blocks are
+/// answered as if this node didn't have them, local operations are not
+/// affected.
+#[derive(Clone)]
+pub enum ServePolicy {
+    /// All blocks in the store.
+    All,
+    /// Blocks retained by an alias or a pin.
+    AliasedOnly,
+    /// Blocks the function returns `true` for.
+    Custom(Arc<dyn Fn(&Cid) -> bool + Send + Sync>),
+}
+
+impl Default for ServePolicy {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+impl std::fmt::Debug for ServePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::All => f.write_str("All"),
+            Self::AliasedOnly => f.write_str("AliasedOnly"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// Requests and bytes per second of a `RateLimit`, `None` doesn't limit
+/// them. Up to one second worth of budget can be used in a burst.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Budget {
+    pub requests_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl Budget {
+    fn is_zero(&self) -> bool {
+        self.requests_per_sec == Some(0) || self.bytes_per_sec == Some(0)
+    }
+}
+
+/// How bitswap requests over the global budget of a `RateLimit` are
+/// answered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Throttle {
+    /// Once the budget allows it.
+    Delay,
+    /// Right away, as if this node didn't have the block.
+    DontHave,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::Delay
+    }
+}
+
+/// Limits of serving blocks to peers over bitswap.
+///
+/// Both budgets count the bitswap requests of peers and the bytes of the
+/// responses, other protocols aren't limited. Responses over budget are
+/// delayed, except for requests over the global budget with
+/// `Throttle::DontHave`. The per peer budget always delays.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RateLimit {
+    pub global: Budget,
+    pub per_peer: Budget,
+    pub throttle: Throttle,
+}
+
+impl RateLimit {
+    /// Checks that the budgets allow at least one request and byte per
+    /// second.
+    pub fn validate(&self) -> Result<()> {
+        if self.global.is_zero() || self.per_peer.is_zero() {
+            return Err(InvalidNetworkConfig::ZeroRateLimit.into());
+        }
+        Ok(())
+    }
+}
+
+/// Blocks announced by the reprovider.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReprovidePolicy {
+    /// Nothing is announced.
+    None,
+    /// The blocks that aliases point to.
+    Roots,
+    /// All blocks in the store.
+    All,
+}
+
+impl Default for ReprovidePolicy {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+/// Limits of the circuit relay server.
+#[derive(Clone, Copy, Debug)]
+pub struct RelayServerConfig {
+    /// Maximum number of reservations.
+    pub max_reservations: usize,
+    /// Maximum number of circuits relayed at the same time.
+    pub max_circuits: usize,
+    /// Maximum number of bytes relayed per circuit and direction.
+    pub max_circuit_bytes: u64,
+    /// Maximum duration of a circuit.
+    pub max_circuit_duration: Duration,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            max_circuits: 16,
+            max_circuit_bytes: 1 << 17,
+            max_circuit_duration: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Limits of the request response protocol.
+#[derive(Clone, Debug)]
+pub struct RequestResponseConfig {
+    /// Protocols whose requests are answered by `Ipfs::requests`. Requests
+    /// of other protocols are rejected as unsupported.
+    pub protocols: BTreeSet<String>,
+    /// Maximum size of a request payload in bytes.
+    pub max_request_size: usize,
+    /// Maximum size of a response payload in bytes.
+    pub max_response_size: usize,
+    /// Time to wait for a response, `Ipfs::request_with_timeout` can wait
+    /// shorter.
+    pub request_timeout: Duration,
+}
+
+impl Default for RequestResponseConfig {
+    fn default() -> Self {
+        Self {
+            protocols: Default::default(),
+            max_request_size: 1 << 16,
+            max_response_size: 1 << 20,
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Configuration of the websocket transport, which is used for `/ws` and
+/// `/wss` addresses.
+#[derive(Clone, Debug, Default)]
+pub struct WebsocketConfig {
+    /// TLS config for `/wss` addresses. Listening on `/wss` fails without a
+    /// server certificate.
+    pub tls: Option<TlsConfig>,
+}
+
+impl WebsocketConfig {
+    /// Creates a websocket config supporting `/wss` listeners with the DER
+    /// encoded private key and certificate chain read from the given files.
+    pub fn with_tls_files(key: &Path, certs: &[&Path]) -> Result<Self> {
+        let key = PrivateKey::new(
+            std::fs::read(key)
+                .with_context(|| format!("failed to read tls key {}", key.display()))?,
+        );
+        let certs = certs
+            .iter()
+            .map(|cert| {
+                std::fs::read(cert)
+                    .with_context(|| format!("failed to read tls certificate {}", cert.display()))
+                    .map(Certificate::new)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            tls: Some(TlsConfig::new(key, certs)?),
+        })
+    }
 }
 
 /// `DNS` configuration.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum DnsConfig {
     Custom {
         /// Configures the nameservers to use.
@@ -60,15 +521,18 @@ pub enum DnsConfig {
 }
 
 impl NetworkConfig {
-    /// Creates a new network configuration.
-    pub fn new(node_key: Keypair) -> Self {
+    /// Creates a new network configuration with an ed25519 node key.
+    pub fn new(node_key: ed25519::Keypair) -> Self {
+        Self::with_keypair(Keypair::Ed25519(node_key))
+    }
+
+    /// Creates a new network configuration with a node key of any supported
+    /// type.
+    pub fn with_keypair(node_key: Keypair) -> Self {
         let node_name = names::Generator::with_naming(names::Name::Numbered)
             .next()
             .unwrap();
-        let identify = IdentifyConfig::new(
-            "/ipfs-embed/1.0".into(),
-            libp2p::identity::PublicKey::Ed25519(node_key.public()),
-        );
+        let identify = IdentifyConfig::new("/ipfs-embed/1.0".into(), node_key.public());
         Self {
             enable_loopback: true,
             port_reuse: true,
@@ -76,20 +540,596 @@ impl NetworkConfig {
             node_key,
             psk: None,
             dns: None,
+            websocket: None,
+            transport: None,
             mdns: Some(MdnsConfig::default()),
+            mdns_auto_dial: true,
             kad: Some(KadConfig::default()),
             ping: None,
+            ping_max_failures: None,
+            autonat: Some(AutonatConfig::default()),
+            dcutr: true,
             identify: Some(identify),
             gossipsub: Some(GossipsubConfig::default()),
+            gossipsub_scoring: None,
             broadcast: Some(BroadcastConfig::default()),
             bitswap: Some(BitswapConfig::default()),
+            serve_policy: ServePolicy::All,
+            serve_rate_limit: None,
+            connection_limits: ConnectionLimits::default(),
+            connection_gate: ConnectionGate::default(),
             keep_alive: false,
+            fetch_timeout: None,
+            fetch_via_dht: None,
+            sync_via_dht: None,
+            sync_pipeline: None,
+            sync_scheduler: None,
+            listen_addresses: vec![],
+            address_book_interval: Some(Duration::from_secs(60)),
+            address_book_max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            bootstrap: vec![],
+            rebootstrap: Some(RebootstrapConfig::default()),
+            reprovide: ReprovidePolicy::None,
+            reprovide_interval: Duration::from_secs(12 * 60 * 60),
+            relays: vec![],
+            relay_server: None,
+            request_response: None,
         }
     }
 }
 
+impl NetworkConfig {
+    /// Creates a builder for a network configuration with the given node
+    /// key.
+    pub fn builder(node_key: Keypair) -> NetworkConfigBuilder {
+        NetworkConfigBuilder::new(node_key)
+    }
+
+    /// Answers requests of `protocol` with `Ipfs::requests`, enabling the
+    /// request response protocol if needed.
+    pub fn register_protocol(&mut self, protocol: impl Into<String>) {
+        self.request_response
+            .get_or_insert_with(Default::default)
+            .protocols
+            .insert(protocol.into());
+    }
+
+    /// Checks that the settings don't contradict each other. `Ipfs::new`
+    /// runs this check too, so configurations built as struct literals are
+    /// rejected there.
+    pub fn validate(&self) -> Result<()> {
+        if self.node_name.is_empty() {
+            return Err(InvalidNetworkConfig::EmptyNodeName.into());
+        }
+        if !self.bootstrap.is_empty() && self.kad.is_none() {
+            return Err(InvalidNetworkConfig::BootstrapWithoutKad.into());
+        }
+        let peer_id = self.node_key.public().to_peer_id();
+        if self.bootstrap.iter().any(|(peer, _)| *peer == peer_id) {
+            return Err(InvalidNetworkConfig::BootstrapFromSelf(peer_id).into());
+        }
+        for addr in &self.relays {
+            if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                return Err(InvalidNetworkConfig::RelayWithoutPeerId(addr.clone()).into());
+            }
+        }
+        if let Some(config) = self.fetch_via_dht {
+            if self.kad.is_none() {
+                return Err(InvalidNetworkConfig::DhtFetchWithoutKad.into());
+            }
+            if config.max_providers == 0 {
+                return Err(InvalidNetworkConfig::NoDhtFetchProviders.into());
+            }
+        }
+        if let Some(config) = self.sync_via_dht {
+            if self.kad.is_none() {
+                return Err(InvalidNetworkConfig::DhtSyncWithoutKad.into());
+            }
+            if config.max_providers == 0 {
+                return Err(InvalidNetworkConfig::NoDhtSyncProviders.into());
+            }
+        }
+        if matches!(self.sync_pipeline, Some(config) if config.max_in_flight == 0) {
+            return Err(InvalidNetworkConfig::NoSyncRequestsInFlight.into());
+        }
+        if matches!(self.sync_scheduler, Some(config) if config.max_outstanding == 0 || config.max_per_peer == 0)
+        {
+            return Err(InvalidNetworkConfig::NoScheduledRequests.into());
+        }
+        if self.reprovide != ReprovidePolicy::None && self.kad.is_none() {
+            return Err(InvalidNetworkConfig::ReprovideWithoutKad.into());
+        }
+        if let Some(max_failures) = self.ping_max_failures {
+            if self.ping.is_none() {
+                return Err(InvalidNetworkConfig::PingFailuresWithoutPing.into());
+            }
+            if max_failures == 0 {
+                return Err(InvalidNetworkConfig::NoPingFailures.into());
+            }
+        }
+        if self.gossipsub.is_none() && self.gossipsub_scoring.is_some() {
+            return Err(InvalidNetworkConfig::ScoringWithoutGossipsub.into());
+        }
+        if self.bitswap.is_none() && (self.fetch_timeout.is_some() || self.fetch_via_dht.is_some())
+        {
+            return Err(InvalidNetworkConfig::FetchWithoutBitswap.into());
+        }
+        if self.transport.is_some() && (self.dns.is_some() || self.websocket.is_some()) {
+            return Err(InvalidNetworkConfig::CustomTransportConflict.into());
+        }
+        if let Some(limit) = &self.serve_rate_limit {
+            limit.validate()?;
+        }
+        if let Some(config) = &self.request_response {
+            if config.protocols.iter().any(|protocol| protocol.is_empty()) {
+                return Err(InvalidNetworkConfig::EmptyProtocolName.into());
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Default for NetworkConfig {
     fn default() -> Self {
-        Self::new(Keypair::generate())
+        Self::new(ed25519::Keypair::generate())
+    }
+}
+
+/// Settings in a `NetworkConfig` that contradict each other.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+pub enum InvalidNetworkConfig {
+    #[error("The node name must not be empty.")]
+    EmptyNodeName,
+    #[error("Bootstrap nodes are configured but kad is disabled.")]
+    BootstrapWithoutKad,
+    #[error("The bootstrap nodes contain the local peer id {0}.")]
+    BootstrapFromSelf(PeerId),
+    #[error("Fetching via the dht is enabled but kad is disabled.")]
+    DhtFetchWithoutKad,
+    #[error("Fetching via the dht needs at least one provider.")]
+    NoDhtFetchProviders,
+    #[error("Syncing via the dht is enabled but kad is disabled.")]
+    DhtSyncWithoutKad,
+    #[error("Syncing via the dht needs at least one provider per lookup.")]
+    NoDhtSyncProviders,
+    #[error("A pipelined sync needs at least one request in flight.")]
+    NoSyncRequestsInFlight,
+    #[error("The sync scheduler needs to allow at least one outstanding request.")]
+    NoScheduledRequests,
+    #[error("A fetch timeout or dht fetching is configured but bitswap is disabled.")]
+    FetchWithoutBitswap,
+    #[error("The relay address {0} doesn't end with /p2p.")]
+    RelayWithoutPeerId(Multiaddr),
+    #[error("Peer scoring is configured but gossipsub is disabled.")]
+    ScoringWithoutGossipsub,
+    #[error("Reproviding is enabled but kad is disabled.")]
+    ReprovideWithoutKad,
+    #[error("A maximum of ping failures is configured but ping is disabled.")]
+    PingFailuresWithoutPing,
+    #[error("The maximum of ping failures must be at least one.")]
+    NoPingFailures,
+    #[error("A request response protocol name is empty.")]
+    EmptyProtocolName,
+    #[error("A custom transport is set together with a dns or websocket config.")]
+    CustomTransportConflict,
+    #[error("A serve rate limit allows no requests or bytes.")]
+    ZeroRateLimit,
+}
+
+/// Builder for a `NetworkConfig`. Starts from the defaults of
+/// `NetworkConfig::new` and validates the result in `build`.
+#[derive(Debug)]
+pub struct NetworkConfigBuilder(NetworkConfig);
+
+impl NetworkConfigBuilder {
+    /// Creates a builder with the given node key.
+    pub fn new(node_key: Keypair) -> Self {
+        Self(NetworkConfig::with_keypair(node_key))
+    }
+
+    /// Sets the node name.
+    pub fn node_name(mut self, node_name: impl Into<String>) -> Self {
+        self.0.node_name = node_name.into();
+        self
+    }
+
+    /// Enables adding loopback addresses to the address book.
+    pub fn enable_loopback(mut self, enable_loopback: bool) -> Self {
+        self.0.enable_loopback = enable_loopback;
+        self
+    }
+
+    /// Enables binding to the listen port number when dialling peers.
+    pub fn port_reuse(mut self, port_reuse: bool) -> Self {
+        self.0.port_reuse = port_reuse;
+        self
+    }
+
+    /// Sets the pre shared key of a private network, see `read_psk_file`.
+    pub fn psk(mut self, psk: Option<PreSharedKey>) -> Self {
+        self.0.psk = psk;
+        self
+    }
+
+    /// Sets the dns config, `None` uses the system defaults.
+    pub fn dns(mut self, dns: Option<DnsConfig>) -> Self {
+        self.0.dns = dns;
+        self
+    }
+
+    /// Sets the websocket config, `None` disables websockets.
+    pub fn websocket(mut self, websocket: Option<WebsocketConfig>) -> Self {
+        self.0.websocket = websocket;
+        self
+    }
+
+    /// Replaces the tcp, dns and websocket transports, `None` uses them.
+    pub fn transport(mut self, transport: Option<CustomTransport>) -> Self {
+        self.0.transport = transport;
+        self
+    }
+
+    /// Sets the mdns config, `None` disables mdns.
+    pub fn mdns(mut self, mdns: Option<MdnsConfig>) -> Self {
+        self.0.mdns = mdns;
+        self
+    }
+
+    /// Sets whether peers discovered by mdns are dialed automatically.
+    pub fn mdns_auto_dial(mut self, mdns_auto_dial: bool) -> Self {
+        self.0.mdns_auto_dial = mdns_auto_dial;
+        self
+    }
+
+    /// Sets the kad config, `None` disables kad.
+    pub fn kad(mut self, kad: Option<KadConfig>) -> Self {
+        self.0.kad = kad;
+        self
+    }
+
+    /// Sets the ping config, `None` disables ping.
+    pub fn ping(mut self, ping: Option<PingConfig>) -> Self {
+        self.0.ping = ping;
+        self
+    }
+
+    /// Sets the number of failed pings in a row after which a peer is
+    /// disconnected, `None` keeps unresponsive peers connected.
+    pub fn ping_max_failures(mut self, ping_max_failures: Option<u32>) -> Self {
+        self.0.ping_max_failures = ping_max_failures;
+        self
+    }
+
+    /// Sets the AutoNAT config, `None` disables AutoNAT.
+    pub fn autonat(mut self, autonat: Option<AutonatConfig>) -> Self {
+        self.0.autonat = autonat;
+        self
+    }
+
+    /// Enables upgrading relayed connections by hole punching.
+    pub fn dcutr(mut self, dcutr: bool) -> Self {
+        self.0.dcutr = dcutr;
+        self
+    }
+
+    /// Sets the identify config, `None` disables identify.
+    pub fn identify(mut self, identify: Option<IdentifyConfig>) -> Self {
+        self.0.identify = identify;
+        self
+    }
+
+    /// Sets the gossipsub config, `None` disables gossipsub.
+    pub fn gossipsub(mut self, gossipsub: Option<GossipsubConfig>) -> Self {
+        self.0.gossipsub = gossipsub;
+        self
+    }
+
+    /// Enables gossipsub peer scoring with the given parameters.
+    pub fn gossipsub_scoring(
+        mut self,
+        params: PeerScoreParams,
+        thresholds: PeerScoreThresholds,
+    ) -> Self {
+        self.0.gossipsub_scoring = Some((params, thresholds));
+        self
+    }
+
+    /// Sets the broadcast config, `None` disables broadcast.
+    pub fn broadcast(mut self, broadcast: Option<BroadcastConfig>) -> Self {
+        self.0.broadcast = broadcast;
+        self
+    }
+
+    /// Sets the bitswap config, `None` disables bitswap.
+    pub fn bitswap(mut self, bitswap: Option<BitswapConfig>) -> Self {
+        self.0.bitswap = bitswap;
+        self
+    }
+
+    /// Sets the blocks served to peers over bitswap.
+    pub fn serve_policy(mut self, serve_policy: ServePolicy) -> Self {
+        self.0.serve_policy = serve_policy;
+        self
+    }
+
+    /// Sets the limits of serving blocks to peers, `None` removes them.
+    pub fn serve_rate_limit(mut self, serve_rate_limit: Option<RateLimit>) -> Self {
+        self.0.serve_rate_limit = serve_rate_limit;
+        self
+    }
+
+    /// Sets the limits on the number of connections.
+    pub fn connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.0.connection_limits = connection_limits;
+        self
+    }
+
+    /// Sets the interval at which the address book is saved, `None` disables
+    /// saving.
+    pub fn address_book_interval(mut self, interval: Option<Duration>) -> Self {
+        self.0.address_book_interval = interval;
+        self
+    }
+
+    /// Sets the age after which addresses are dropped when loading the
+    /// address book.
+    pub fn address_book_max_age(mut self, max_age: Duration) -> Self {
+        self.0.address_book_max_age = max_age;
+        self
+    }
+
+    /// Sets the rules for accepting connections.
+    pub fn connection_gate(mut self, connection_gate: ConnectionGate) -> Self {
+        self.0.connection_gate = connection_gate;
+        self
+    }
+
+    /// Keeps explicitly dialed and incoming connections open indefinitely.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.0.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets the default timeout used when fetching blocks.
+    pub fn fetch_timeout(mut self, fetch_timeout: Option<Duration>) -> Self {
+        self.0.fetch_timeout = fetch_timeout;
+        self
+    }
+
+    /// Enables discovering providers in the dht when fetching blocks.
+    pub fn fetch_via_dht(mut self, fetch_via_dht: Option<DhtFetchConfig>) -> Self {
+        self.0.fetch_via_dht = fetch_via_dht;
+        self
+    }
+
+    /// Enables discovering providers in the dht when syncing dags.
+    pub fn sync_via_dht(mut self, sync_via_dht: Option<DhtSyncConfig>) -> Self {
+        self.0.sync_via_dht = sync_via_dht;
+        self
+    }
+
+    /// Enables pipelined syncs.
+    pub fn sync_pipeline(mut self, sync_pipeline: Option<SyncPipelineConfig>) -> Self {
+        self.0.sync_pipeline = sync_pipeline;
+        self
+    }
+
+    /// Enables scheduling the block requests of all syncs together.
+    pub fn sync_scheduler(mut self, sync_scheduler: Option<SyncSchedulerConfig>) -> Self {
+        self.0.sync_scheduler = sync_scheduler;
+        self
+    }
+
+    /// Adds an address to listen on.
+    pub fn listen_address(mut self, addr: Multiaddr) -> Self {
+        self.0.listen_addresses.push(addr);
+        self
+    }
+
+    /// Adds a node to bootstrap from.
+    pub fn bootstrap(mut self, peer: PeerId, addr: Multiaddr) -> Self {
+        self.0.bootstrap.push((peer, addr));
+        self
+    }
+
+    /// Adds a relay to make a reservation on.
+    pub fn relay(mut self, addr: Multiaddr) -> Self {
+        self.0.relays.push(addr);
+        self
+    }
+
+    /// Sets the routing table maintenance config, `None` disables it.
+    pub fn rebootstrap(mut self, rebootstrap: Option<RebootstrapConfig>) -> Self {
+        self.0.rebootstrap = rebootstrap;
+        self
+    }
+
+    /// Sets the blocks to announce on the dht and the interval between
+    /// announcements.
+    pub fn reprovide(mut self, policy: ReprovidePolicy, interval: Duration) -> Self {
+        self.0.reprovide = policy;
+        self.0.reprovide_interval = interval;
+        self
+    }
+
+    /// Sets the relay server config, `None` disables the relay server.
+    pub fn relay_server(mut self, relay_server: Option<RelayServerConfig>) -> Self {
+        self.0.relay_server = relay_server;
+        self
+    }
+
+    /// Sets the request response config, `None` disables the protocol.
+    pub fn request_response(mut self, request_response: Option<RequestResponseConfig>) -> Self {
+        self.0.request_response = request_response;
+        self
+    }
+
+    /// Answers requests of `protocol`, see `NetworkConfig::register_protocol`.
+    pub fn register_protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.0.register_protocol(protocol);
+        self
+    }
+
+    /// Validates and returns the configuration. The error can be downcast
+    /// to `InvalidNetworkConfig`.
+    pub fn build(self) -> Result<NetworkConfig> {
+        self.0.validate()?;
+        Ok(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::transport::MemoryTransport;
+
+    fn builder() -> NetworkConfigBuilder {
+        NetworkConfig::builder(Keypair::generate_ed25519())
+    }
+
+    fn rejected(builder: NetworkConfigBuilder) -> InvalidNetworkConfig {
+        builder
+            .build()
+            .unwrap_err()
+            .downcast::<InvalidNetworkConfig>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_builder() -> Result<()> {
+        let peer = PeerId::random();
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse()?;
+        let config = builder()
+            .node_name("node")
+            .ping(Some(PingConfig::new()))
+            .mdns(None)
+            .fetch_via_dht(Some(DhtFetchConfig::default()))
+            .bootstrap(peer, addr.clone())
+            .build()?;
+        assert_eq!(config.node_name, "node");
+        assert!(config.ping.is_some());
+        assert!(config.mdns.is_none());
+        assert!(config.fetch_via_dht.is_some());
+        assert_eq!(config.bootstrap, vec![(peer, addr)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_combinations() {
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        assert_eq!(
+            rejected(builder().node_name("")),
+            InvalidNetworkConfig::EmptyNodeName
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .kad(None)
+                    .bootstrap(PeerId::random(), addr.clone())
+            ),
+            InvalidNetworkConfig::BootstrapWithoutKad
+        );
+        let node_key = Keypair::generate_ed25519();
+        let peer_id = node_key.public().to_peer_id();
+        assert_eq!(
+            rejected(NetworkConfig::builder(node_key).bootstrap(peer_id, addr)),
+            InvalidNetworkConfig::BootstrapFromSelf(peer_id)
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .kad(None)
+                    .fetch_via_dht(Some(DhtFetchConfig::default()))
+            ),
+            InvalidNetworkConfig::DhtFetchWithoutKad
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .kad(None)
+                    .sync_via_dht(Some(DhtSyncConfig::default()))
+            ),
+            InvalidNetworkConfig::DhtSyncWithoutKad
+        );
+        assert_eq!(
+            rejected(builder().sync_pipeline(Some(SyncPipelineConfig {
+                max_in_flight: 0,
+                ..Default::default()
+            }))),
+            InvalidNetworkConfig::NoSyncRequestsInFlight
+        );
+        assert_eq!(
+            rejected(builder().sync_scheduler(Some(SyncSchedulerConfig {
+                max_per_peer: 0,
+                ..Default::default()
+            }))),
+            InvalidNetworkConfig::NoScheduledRequests
+        );
+        assert_eq!(
+            rejected(builder().fetch_via_dht(Some(DhtFetchConfig {
+                max_providers: 0,
+                ..Default::default()
+            }))),
+            InvalidNetworkConfig::NoDhtFetchProviders
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .bitswap(None)
+                    .fetch_timeout(Some(Duration::from_secs(1)))
+            ),
+            InvalidNetworkConfig::FetchWithoutBitswap
+        );
+        assert_eq!(
+            rejected(builder().ping_max_failures(Some(3))),
+            InvalidNetworkConfig::PingFailuresWithoutPing
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .ping(Some(PingConfig::new()))
+                    .ping_max_failures(Some(0))
+            ),
+            InvalidNetworkConfig::NoPingFailures
+        );
+        let relay: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+        assert_eq!(
+            rejected(builder().relay(relay.clone())),
+            InvalidNetworkConfig::RelayWithoutPeerId(relay)
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .gossipsub(None)
+                    .gossipsub_scoring(PeerScoreParams::default(), PeerScoreThresholds::default())
+            ),
+            InvalidNetworkConfig::ScoringWithoutGossipsub
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .kad(None)
+                    .reprovide(ReprovidePolicy::Roots, Duration::from_secs(60))
+            ),
+            InvalidNetworkConfig::ReprovideWithoutKad
+        );
+        assert_eq!(
+            rejected(builder().register_protocol("")),
+            InvalidNetworkConfig::EmptyProtocolName
+        );
+        assert_eq!(
+            rejected(
+                builder()
+                    .transport(Some(custom_transport(MemoryTransport::default())))
+                    .websocket(Some(WebsocketConfig::default()))
+            ),
+            InvalidNetworkConfig::CustomTransportConflict
+        );
+        let mut limit = RateLimit::default();
+        limit.per_peer.bytes_per_sec = Some(0);
+        assert_eq!(
+            rejected(builder().serve_rate_limit(Some(limit))),
+            InvalidNetworkConfig::ZeroRateLimit
+        );
     }
 }
@@ -0,0 +1,129 @@
+use super::peer_info::{ConnectionFailure, PeerInfo};
+use chrono::{DateTime, Utc};
+use fnv::FnvHashMap;
+use futures::channel::oneshot;
+use libp2p::{swarm::SwarmEvent, PeerId};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Connecting to {peer} failed, {} dial attempts failed.", .failures.len())]
+pub struct ConnectFailed {
+    pub peer: PeerId,
+    /// The failures recorded since `Ipfs::connect` was called.
+    pub failures: Vec<ConnectionFailure>,
+}
+
+#[derive(Debug, Error)]
+#[error("Timed out waiting for a connection to {peer}.")]
+pub struct ConnectTimeout {
+    pub peer: PeerId,
+    /// The failures recorded while waiting.
+    pub failures: Vec<ConnectionFailure>,
+}
+
+/// Returns the failures of `info` recorded since `since`, oldest first.
+pub(crate) fn failures_since(
+    info: Option<PeerInfo>,
+    since: DateTime<Utc>,
+) -> Vec<ConnectionFailure> {
+    let mut failures = info
+        .iter()
+        .flat_map(|info| info.recent_failures())
+        .filter(|failure| failure.time() >= since)
+        .cloned()
+        .collect::<Vec<_>>();
+    failures.reverse();
+    failures
+}
+
+struct PendingConnect {
+    since: DateTime<Utc>,
+    /// Dial attempts that haven't failed yet, `None` for passive waiters.
+    dials: Option<usize>,
+    tx: oneshot::Sender<anyhow::Result<()>>,
+}
+
+/// Callers waiting for a connection to a peer, resolved from the swarm
+/// events.
+#[derive(Default)]
+pub(crate) struct PendingConnects(FnvHashMap<PeerId, Vec<PendingConnect>>);
+
+impl PendingConnects {
+    /// Waits for a connection to `peer`, failing once `dials` dial attempts
+    /// have failed.
+    pub fn push(&mut self, peer: PeerId, dials: usize, tx: oneshot::Sender<anyhow::Result<()>>) {
+        self.insert(peer, Some(dials), tx);
+    }
+
+    /// Waits for a connection to `peer` until the receiver is dropped.
+    pub fn wait(&mut self, peer: PeerId, tx: oneshot::Sender<anyhow::Result<()>>) {
+        self.insert(peer, None, tx);
+    }
+
+    fn insert(
+        &mut self,
+        peer: PeerId,
+        dials: Option<usize>,
+        tx: oneshot::Sender<anyhow::Result<()>>,
+    ) {
+        // drop the waiters that gave up
+        self.0.retain(|_, pending| {
+            pending.retain(|pending| !pending.tx.is_canceled());
+            !pending.is_empty()
+        });
+        self.0.entry(peer).or_default().push(PendingConnect {
+            since: Utc::now(),
+            dials,
+            tx,
+        });
+    }
+
+    /// Resolves the waiters of a peer that connected or whose dial failed.
+    /// `info` returns the peer info, which already contains the failure.
+    pub fn inject_swarm_event<B, E>(
+        &mut self,
+        event: &SwarmEvent<B, E>,
+        info: impl Fn(&PeerId) -> Option<PeerInfo>,
+    ) {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                for pending in self.0.remove(peer_id).unwrap_or_default() {
+                    pending.tx.send(Ok(())).ok();
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                ..
+            } => {
+                let pending = match self.0.get_mut(peer_id) {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                let mut waiting = Vec::with_capacity(pending.len());
+                for mut connect in pending.drain(..) {
+                    match connect.dials.as_mut() {
+                        Some(dials) if *dials <= 1 => {
+                            let failures = failures_since(info(peer_id), connect.since);
+                            let err = ConnectFailed {
+                                peer: *peer_id,
+                                failures,
+                            };
+                            connect.tx.send(Err(err.into())).ok();
+                        }
+                        Some(dials) => {
+                            *dials -= 1;
+                            waiting.push(connect);
+                        }
+                        None => waiting.push(connect),
+                    }
+                }
+                if waiting.is_empty() {
+                    self.0.remove(peer_id);
+                } else {
+                    *pending = waiting;
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use fnv::FnvHashSet;
+use ipnet::IpNet;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use std::net::IpAddr;
+use thiserror::Error;
+
+/// Allow and deny rules for incoming and outgoing connections.
+///
+/// Deny rules take precedence over allow rules. If there is at least one
+/// allow rule of a kind, only peers or addresses matching one of them are
+/// accepted. Address rules are checked before any handshake, peer rules
+/// as soon as the `PeerId` is known: before dialing addresses ending with
+/// `/p2p` and right after the noise handshake otherwise. Addresses without
+/// an ip, like relayed or not yet resolved dns addresses, are only subject
+/// to peer rules.
+///
+/// Rejected connections are reported as `Event::ConnectionGated`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionGate {
+    allowed_peers: FnvHashSet<PeerId>,
+    denied_peers: FnvHashSet<PeerId>,
+    allowed_ips: Vec<IpNet>,
+    denied_ips: Vec<IpNet>,
+}
+
+impl ConnectionGate {
+    /// Creates a gate accepting all connections.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only accepts the given peer and the other allowed peers.
+    pub fn allow_peer(mut self, peer: PeerId) -> Self {
+        self.allowed_peers.insert(peer);
+        self
+    }
+
+    /// Rejects the given peer.
+    pub fn deny_peer(mut self, peer: PeerId) -> Self {
+        self.denied_peers.insert(peer);
+        self
+    }
+
+    /// Only accepts addresses within the given prefix and the other allowed
+    /// prefixes.
+    pub fn allow_ip(mut self, net: IpNet) -> Self {
+        self.allowed_ips.push(net);
+        self
+    }
+
+    /// Rejects addresses within the given prefix.
+    pub fn deny_ip(mut self, net: IpNet) -> Self {
+        self.denied_ips.push(net);
+        self
+    }
+
+    /// Returns true if the gate has no rules.
+    pub fn is_open(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Checks a peer against the peer rules.
+    pub fn check_peer(&self, peer: &PeerId) -> Result<(), ConnectionGated> {
+        if self.denied_peers.contains(peer) {
+            return Err(ConnectionGated(format!("peer {} is denied", peer)));
+        }
+        if !self.allowed_peers.is_empty() && !self.allowed_peers.contains(peer) {
+            return Err(ConnectionGated(format!("peer {} is not allowed", peer)));
+        }
+        Ok(())
+    }
+
+    /// Checks an address against the ip rules, and the peer rules if it
+    /// contains a `/p2p` protocol.
+    pub fn check_address(&self, addr: &Multiaddr) -> Result<(), ConnectionGated> {
+        for proto in addr.iter() {
+            match proto {
+                Protocol::Ip4(ip) => self.check_ip(ip.into())?,
+                Protocol::Ip6(ip) => self.check_ip(ip.into())?,
+                Protocol::P2p(hash) => {
+                    if let Ok(peer) = PeerId::from_multihash(hash) {
+                        self.check_peer(&peer)?;
+                    }
+                }
+                // the remaining protocols describe the connection to the destination
+                Protocol::P2pCircuit => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), ConnectionGated> {
+        if self.denied_ips.iter().any(|net| net.contains(&ip)) {
+            return Err(ConnectionGated(format!("ip {} is denied", ip)));
+        }
+        if !self.allowed_ips.is_empty() && !self.allowed_ips.iter().any(|net| net.contains(&ip)) {
+            return Err(ConnectionGated(format!("ip {} is not allowed", ip)));
+        }
+        Ok(())
+    }
+}
+
+/// A connection was rejected by the `ConnectionGate`.
+#[derive(Clone, Debug, Error)]
+#[error("connection gated: {0}")]
+pub struct ConnectionGated(pub String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_rules() {
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let gate = ConnectionGate::new();
+        assert!(gate.is_open());
+        assert!(gate.check_peer(&a).is_ok());
+
+        let gate = ConnectionGate::new().deny_peer(a);
+        assert!(gate.check_peer(&a).is_err());
+        assert!(gate.check_peer(&b).is_ok());
+
+        let gate = ConnectionGate::new().allow_peer(a);
+        assert!(gate.check_peer(&a).is_ok());
+        assert!(gate.check_peer(&b).is_err());
+
+        let gate = ConnectionGate::new().allow_peer(a).deny_peer(a);
+        assert!(gate.check_peer(&a).is_err());
+    }
+
+    #[test]
+    fn test_address_rules() {
+        let gate = ConnectionGate::new()
+            .allow_ip("10.0.0.0/8".parse().unwrap())
+            .allow_ip("::1/128".parse().unwrap())
+            .deny_ip("10.0.1.0/24".parse().unwrap());
+        let check = |addr: &str| gate.check_address(&addr.parse().unwrap()).is_ok();
+        assert!(check("/ip4/10.0.0.1/tcp/4001"));
+        assert!(check("/ip6/::1/tcp/4001"));
+        assert!(!check("/ip4/10.0.1.1/tcp/4001"));
+        assert!(!check("/ip4/192.168.0.1/tcp/4001"));
+        assert!(check("/dns4/example.com/tcp/4001"));
+
+        let peer = PeerId::random();
+        let gate = ConnectionGate::new().deny_peer(peer);
+        assert!(gate
+            .check_address(
+                &format!("/ip4/10.0.0.1/tcp/4001/p2p/{}", peer)
+                    .parse()
+                    .unwrap()
+            )
+            .is_err());
+        // only the relay is checked before the handshake
+        let relay = PeerId::random();
+        assert!(gate
+            .check_address(
+                &format!(
+                    "/ip4/10.0.0.1/tcp/4001/p2p/{}/p2p-circuit/p2p/{}",
+                    relay, peer
+                )
+                .parse()
+                .unwrap()
+            )
+            .is_ok());
+    }
+}
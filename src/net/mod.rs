@@ -1,22 +1,72 @@
 mod address_handler;
+mod bandwidth;
 mod behaviour;
 mod config;
+mod connect;
+mod gate;
 mod peer_info;
 mod peers;
+mod psk;
+mod rate_limit;
+mod records;
+mod request;
+mod scheduler;
+mod spans;
 #[cfg(test)]
 mod tests;
 
 pub use self::{
-    behaviour::{GossipEvent, QueryId, SyncEvent},
-    config::{DnsConfig, NetworkConfig},
-    peer_info::{AddressSource, ConnectionFailure, Direction, PeerInfo, Rtt},
-    peers::{register_metrics, Event, SwarmEvents},
+    bandwidth::{BandwidthStats, PeerBandwidth},
+    behaviour::{BitswapStats, FetchTimeout, GossipEvent, QueryId, SyncEvent, ValidationResult},
+    config::{
+        custom_transport, Budget, ConnectionCounts, ConnectionLimits, CustomTransport,
+        DhtFetchConfig, DhtSyncConfig, DnsConfig, InvalidNetworkConfig, NetworkConfig,
+        NetworkConfigBuilder, RateLimit, RebootstrapConfig, RelayServerConfig, ReprovidePolicy,
+        RequestResponseConfig, ServePolicy, Socket, SyncPipelineConfig, SyncSchedulerConfig,
+        SyncTraversal, Throttle, WebsocketConfig,
+    },
+    connect::{ConnectFailed, ConnectTimeout},
+    gate::{ConnectionGate, ConnectionGated},
+    peer_info::{
+        AddressSource, ConnectionFailure, ConnectionFailureKind, ConnectionInfo, Direction,
+        PeerInfo, Rtt,
+    },
+    peers::{register_metrics, Event, EventFilter, ReservationStatus, SwarmEvents},
+    psk::{parse_psk, read_psk_file, PskMismatch},
+    records::{PublishedRecord, PutOptions},
+    request::{
+        InboundRequest, PayloadTooLarge, RequestFailed, RequestTimeout, UnsupportedProtocol,
+    },
 };
 
-use self::behaviour::{GetChannel, NetworkBackendBehaviour, QueryChannel, SyncChannel};
+pub(crate) use self::{
+    behaviour::{
+        KadAddProviderError, KadBootstrapError, KadGetClosestPeersError, KadGetProvidersError,
+        KadGetRecordError, KadPutRecordError, MessageValidator, NoKnownPeers, NotBootstrapped,
+    },
+    peers::BITSWAP_SERVE_DENIED,
+    records::DEFAULT_RECORD_TTL,
+    scheduler::SyncTicket,
+};
+
+use self::{
+    bandwidth::Bandwidth,
+    behaviour::{
+        BitswapCounters, ClosestPeersChannel, CountingStore, GetChannel, NetworkBackendBehaviour,
+        QueryChannel, SyncChannel, BLOCK_EVENTS_BUFFER,
+    },
+    connect::{failures_since, PendingConnects},
+    psk::PskChecked,
+    rate_limit::ServeLimiter,
+    request::PendingRequests,
+    scheduler::SyncScheduler,
+    spans::{bitswap_query, kad_query, QuerySpans},
+};
 use crate::{
-    executor::{Executor, JoinHandle},
-    variable::{Reader, Writer},
+    backend::TempPin,
+    db::{PeerAddresses, Shutdown},
+    executor::{BackgroundTask, Executor, JoinHandle, TaskMonitor, TaskStatus},
+    variable::{Observer, Reader, Variable},
 };
 use anyhow::anyhow;
 use chrono::{DateTime, Utc};
@@ -28,8 +78,9 @@ use futures::{
     },
     future::{self, Either},
     stream::{Stream, StreamExt},
-    FutureExt,
+    FutureExt, SinkExt, TryFutureExt,
 };
+use futures_timer::Delay;
 use libipld::{error::BlockNotFound, store::StoreParams, Cid, Result};
 #[cfg(feature = "async_global")]
 use libp2p::dns::DnsConfig as Dns;
@@ -40,31 +91,45 @@ use libp2p::tcp::TcpTransport;
 #[cfg(all(feature = "tokio", not(feature = "async_global")))]
 use libp2p::tcp::TokioTcpTransport as TcpTransport;
 use libp2p::{
+    autonat::NatStatus,
     core::{
         either::EitherTransport,
+        muxing::StreamMuxerBox,
         transport::{ListenerId, Transport},
         upgrade::{SelectUpgrade, Version},
+        ConnectedPoint,
     },
-    dns::DnsErr,
-    identity::ed25519::PublicKey,
+    gossipsub::MessageId,
+    identity::{Keypair, PublicKey},
     kad::{record::Key, PeerRecord, Quorum, Record},
+    mdns::MdnsConfig,
     mplex::MplexConfig,
+    multiaddr::Protocol,
     noise::{self, NoiseConfig, X25519Spec},
-    pnet::{PnetConfig, PreSharedKey},
-    swarm::{AddressRecord, AddressScore, Swarm, SwarmBuilder, SwarmEvent},
+    pnet::PnetConfig,
+    relay::v2::client::Client as RelayClient,
+    swarm::{
+        dial_opts::DialOpts, AddressRecord, AddressScore,
+        ConnectionLimits as SwarmConnectionLimits, DialError, PendingInboundConnectionError, Swarm,
+        SwarmBuilder, SwarmEvent,
+    },
     tcp::GenTcpConfig as TcpConfig,
+    websocket::WsConfig,
     yamux::YamuxConfig,
     Multiaddr, PeerId,
 };
 use libp2p_bitswap::BitswapStore;
+use parking_lot::RwLock;
 use std::{
     collections::HashSet,
     future::Future,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
+use thiserror::Error;
+use tracing::{Instrument, Span};
 use void::unreachable;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -74,41 +139,83 @@ pub enum ListenerEvent {
     ListenFailed(Multiaddr, String),
 }
 
+#[derive(Debug, Error)]
+#[error("Listening on {addr} failed: {reason}")]
+pub struct ListenFailed {
+    pub addr: Multiaddr,
+    pub reason: String,
+}
+
 #[derive(Debug)]
 pub enum NetworkCommand {
-    ListenOn(Multiaddr, UnboundedSender<ListenerEvent>),
+    ListenOn(
+        Multiaddr,
+        UnboundedSender<ListenerEvent>,
+        Option<oneshot::Sender<anyhow::Result<ListenerId>>>,
+    ),
+    RemoveListener(ListenerId),
     AddExternalAddress(Multiaddr),
     AddAddress(PeerId, Multiaddr),
     RemoveAddress(PeerId, Multiaddr),
     PrunePeers(Duration),
     Dial(PeerId),
+    Connect(PeerId, oneshot::Sender<anyhow::Result<()>>),
+    WaitConnected(PeerId, oneshot::Sender<anyhow::Result<()>>),
     DialAddress(PeerId, Multiaddr),
-    Ban(PeerId),
+    Disconnect(PeerId, Option<Multiaddr>, oneshot::Sender<()>),
+    Ban(PeerId, Option<DateTime<Utc>>),
+    BanExpired(PeerId, DateTime<Utc>),
     Unban(PeerId),
+    ConnectionGated(Direction, Option<PeerId>, Multiaddr, String),
     Bootstrap(
         Vec<(PeerId, Multiaddr)>,
         oneshot::Sender<anyhow::Result<()>>,
     ),
-    Providers(Key, oneshot::Sender<anyhow::Result<HashSet<PeerId>>>),
+    DialUnknown(Multiaddr, oneshot::Sender<anyhow::Result<PeerId>>),
+    GetClosestPeers(PeerId, ClosestPeersChannel),
+    Providers(Key, oneshot::Sender<anyhow::Result<HashSet<PeerId>>>, Span),
+    ProvidersStream(Key, UnboundedSender<PeerId>),
     Provide(Key, oneshot::Sender<anyhow::Result<()>>),
     Unprovide(Key),
     GetRecord(
         Key,
         Quorum,
         oneshot::Sender<anyhow::Result<Vec<PeerRecord>>>,
+        Span,
+    ),
+    PutRecord(
+        Record,
+        Quorum,
+        PutOptions,
+        oneshot::Sender<anyhow::Result<()>>,
     ),
-    PutRecord(Record, Quorum, oneshot::Sender<anyhow::Result<()>>),
+    RepublishRecord(Key, Instant),
+    RebootstrapDone(std::result::Result<(), String>),
     RemoveRecord(Key),
     Subscribe(
         String,
+        Option<MessageValidator>,
         oneshot::Sender<anyhow::Result<UnboundedReceiver<GossipEvent>>>,
     ),
-    Publish(String, Vec<u8>, oneshot::Sender<anyhow::Result<()>>),
+    Publish(String, Vec<u8>, oneshot::Sender<anyhow::Result<MessageId>>),
     Broadcast(String, Vec<u8>, oneshot::Sender<anyhow::Result<()>>),
-    Get(Cid, Vec<PeerId>, oneshot::Sender<GetQuery>),
-    Sync(Cid, Vec<PeerId>, Vec<Cid>, oneshot::Sender<SyncQuery>),
+    Request(
+        PeerId,
+        String,
+        Vec<u8>,
+        oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    ),
+    Requests(String, UnboundedSender<InboundRequest>),
+    Get(Cid, Vec<PeerId>, oneshot::Sender<GetQuery>, Span),
+    Sync(Cid, Vec<PeerId>, Vec<Cid>, oneshot::Sender<SyncQuery>, Span),
+    SetMdns(Option<MdnsConfig>, oneshot::Sender<anyhow::Result<()>>),
     SwarmEvents(oneshot::Sender<SwarmEvents>),
+    SwarmEventsFiltered(EventFilter, oneshot::Sender<SwarmEvents>),
     CancelQuery(QueryId),
+    /// Stops polling the swarm for the given time.
+    #[cfg(test)]
+    Suspend(Duration),
+    Shutdown(oneshot::Sender<()>),
 }
 
 #[derive(Clone)]
@@ -116,11 +223,34 @@ pub struct NetworkService {
     bootstrapped: Reader<bool>,
     peers: Reader<FnvHashMap<PeerId, PeerInfo>>,
     listeners: Reader<FnvHashSet<Multiaddr>>,
+    listener_ids: Reader<FnvHashSet<ListenerId>>,
     external: Reader<Vec<AddressRecord>>,
+    nat_status: Reader<NatStatus>,
+    autonat: bool,
+    connection_limits: ConnectionLimits,
+    connection_counts: Reader<ConnectionCounts>,
+    connection_gate: Arc<RwLock<ConnectionGate>>,
+    banned: Reader<FnvHashMap<PeerId, Option<DateTime<Utc>>>>,
+    topics: Reader<FnvHashMap<String, FnvHashSet<PeerId>>>,
+    subscribed: Reader<FnvHashSet<String>>,
+    scores: Reader<FnvHashMap<PeerId, f64>>,
+    published: Reader<FnvHashMap<Key, PublishedRecord>>,
+    bitswap_counters: Arc<BitswapCounters>,
+    bandwidth: Arc<Bandwidth>,
+    serve_limiter: Arc<ServeLimiter>,
     public_key: PublicKey,
+    node_key: Keypair,
     peer_id: PeerId,
     node_name: String,
+    fetch_timeout: Option<Duration>,
+    fetch_via_dht: Option<DhtFetchConfig>,
+    sync_via_dht: Option<DhtSyncConfig>,
+    sync_pipeline: Option<SyncPipelineConfig>,
+    sync_scheduler: Option<Arc<SyncScheduler>>,
+    dns_config: Option<DnsConfig>,
+    request_response: Option<RequestResponseConfig>,
     cmd: Sender<NetworkCommand>,
+    swarm_monitor: TaskMonitor,
     _swarm_task: Arc<JoinHandle<()>>,
 }
 
@@ -142,41 +272,197 @@ type TransportError = libp2p::core::transport::timeout::TransportTimeoutError<
 /// if this fails compilation, also change peers::is_sim_open()
 fn assert_transport_error_type<T: Transport<Error = U>, U>(_: &T) {}
 
+/// Reports a connection rejected by the `ConnectionGate` to `poll_swarm`.
+fn notify_gated(
+    mut tx: Sender<NetworkCommand>,
+    endpoint: &ConnectedPoint,
+    peer: Option<PeerId>,
+    err: &ConnectionGated,
+) {
+    let addr = endpoint.get_remote_address().clone();
+    let cmd = NetworkCommand::ConnectionGated(endpoint.into(), peer, addr, err.0.clone());
+    if let Err(err) = tx.try_send(cmd) {
+        tracing::warn!("cannot report gated connection: {}", err);
+    }
+}
+
+#[cfg(feature = "async_global")]
+async fn dns_transport<T>(transport: T, config: Option<DnsConfig>) -> Result<Dns<T>> {
+    Ok(match config {
+        Some(DnsConfig::Custom { config, opts }) => Dns::custom(transport, config, opts).await?,
+        Some(DnsConfig::SystemWithFallback { config, opts }) => {
+            match trust_dns_resolver::system_conf::read_system_conf() {
+                Ok((config, opts)) => Dns::custom(transport, config, opts).await?,
+                Err(e) => {
+                    tracing::warn!(
+                        "falling back to custom DNS config, system default yielded error `${:#}`",
+                        e
+                    );
+                    Dns::custom(transport, config, opts).await?
+                }
+            }
+        }
+        None => Dns::system(transport).await?,
+    })
+}
+
+#[cfg(all(feature = "tokio", not(feature = "async_global")))]
+async fn dns_transport<T>(transport: T, config: Option<DnsConfig>) -> Result<Dns<T>> {
+    Ok(match config {
+        Some(DnsConfig::Custom { config, opts }) => Dns::custom(transport, config, opts)?,
+        Some(DnsConfig::SystemWithFallback { config, opts }) => {
+            match trust_dns_resolver::system_conf::read_system_conf() {
+                Ok((config, opts)) => Dns::custom(transport, config, opts)?,
+                Err(e) => {
+                    tracing::warn!(
+                        "falling back to custom DNS config, system default yielded error `${:#}`",
+                        e
+                    );
+                    Dns::custom(transport, config, opts)?
+                }
+            }
+        }
+        None => Dns::system(transport)?,
+    })
+}
+
 impl NetworkService {
+    /// Creates the network service, `banned` are the peers to ban initially
+    /// together with the expiry of their ban and `addresses` the initial
+    /// contents of the address book. Fails if the configuration doesn't
+    /// validate.
     pub async fn new<S: BitswapStore>(
         mut config: NetworkConfig,
         store: S,
+        banned: Vec<(PeerId, Option<DateTime<Utc>>)>,
+        addresses: Vec<PeerAddresses>,
         executor: Executor,
     ) -> Result<Self> {
+        config.validate()?;
         let public_key = config.node_key.public();
-        let peer_id =
-            PeerId::from_public_key(&libp2p::core::PublicKey::Ed25519(public_key.clone()));
+        let node_key = config.node_key.clone();
+        let peer_id = public_key.to_peer_id();
         let node_name = config.node_name.clone();
 
-        let peers = Writer::new(FnvHashMap::default());
+        let mut address_book = FnvHashMap::<PeerId, PeerInfo>::default();
+        for (peer, addrs) in addresses {
+            if peer == peer_id || addrs.is_empty() {
+                continue;
+            }
+            let info = address_book.entry(peer).or_default();
+            for (mut addr, source, seen) in addrs {
+                if !matches!(addr.iter().last(), Some(Protocol::P2p(_))) {
+                    addr.push(Protocol::P2p(peer.into()));
+                }
+                info.addresses.insert(addr, (source, seen));
+            }
+        }
+        let peers = Variable::new(address_book);
         let peers2 = peers.reader();
-        let listeners = Writer::new(FnvHashSet::default());
+        let listeners = Variable::new(FnvHashSet::default());
         let listeners2 = listeners.reader();
-        let external = Writer::new(vec![]);
+        let listener_ids = Variable::new(FnvHashSet::default());
+        let listener_ids2 = listener_ids.reader();
+        let external = Variable::new(vec![]);
         let external2 = external.reader();
-        let behaviour =
-            NetworkBackendBehaviour::new(&mut config, store, listeners, peers, external)?;
+        let nat_status = Variable::new(NatStatus::Unknown);
+        let nat_status2 = nat_status.reader();
+        let autonat = config.autonat.is_some();
+        let mdns_auto_dial = config.mdns_auto_dial;
+        let ping_max_failures = config.ping_max_failures;
+        let connection_counts = Variable::new(ConnectionCounts::default());
+        let connection_counts2 = connection_counts.reader();
+        let connection_gate = Arc::new(RwLock::new(std::mem::take(&mut config.connection_gate)));
+        let (cmd_tx, cmd_rx) = mpsc::channel(100);
+        let bitswap_counters = Arc::new(BitswapCounters::default());
+        let (block_events_tx, block_events) = mpsc::channel(BLOCK_EVENTS_BUFFER);
+        let serve_limiter = Arc::new(ServeLimiter::new(config.serve_rate_limit));
+        let store = CountingStore::new(
+            store,
+            bitswap_counters.clone(),
+            block_events_tx,
+            serve_limiter.clone(),
+        );
+        let (relay_transport, relay_client) = RelayClient::new_transport_and_behaviour(peer_id);
+        let rebootstrap = match (config.rebootstrap, &config.kad) {
+            (Some(rebootstrap), Some(_)) => {
+                Some(Rebootstrap::new(rebootstrap, config.bootstrap.clone()))
+            }
+            _ => None,
+        };
+        let graylist_threshold = config
+            .gossipsub_scoring
+            .as_ref()
+            .map(|(_, thresholds)| thresholds.graylist_threshold);
+        let request_response = config.request_response.clone();
+        let requests = PendingRequests::new(
+            request_response
+                .as_ref()
+                .map(|config| config.max_response_size)
+                .unwrap_or_default(),
+        );
+        let behaviour = NetworkBackendBehaviour::new(
+            &mut config,
+            store,
+            relay_client,
+            listeners,
+            peers,
+            external,
+        )?;
 
+        let dns_config = config.dns.clone();
         let tcp = {
-            let transport =
-                TcpTransport::new(TcpConfig::new().nodelay(true).port_reuse(config.port_reuse));
+            let transport = if let Some(transport) = config.transport.take() {
+                EitherTransport::Left(transport)
+            } else {
+                let tcp_config = TcpConfig::new().nodelay(true).port_reuse(config.port_reuse);
+                let dns = config.dns.take();
+                let tcp = dns_transport(TcpTransport::new(tcp_config.clone()), dns.clone()).await?;
+                // websockets need to resolve dns names themselves, so dns is layered below them
+                EitherTransport::Right(if let Some(ws_config) = config.websocket.take() {
+                    let mut ws =
+                        WsConfig::new(dns_transport(TcpTransport::new(tcp_config), dns).await?);
+                    if let Some(tls) = ws_config.tls {
+                        ws.set_tls_config(tls);
+                    }
+                    // the dns transport accepts any address and only fails once it dialed
+                    // the resolved one, so websocket addresses are tried first
+                    EitherTransport::Left(ws.or_transport(tcp))
+                } else {
+                    EitherTransport::Right(tcp)
+                })
+            };
+            // relayed connections are upgraded like direct ones
+            let gate = connection_gate.clone();
+            let gated = cmd_tx.clone();
+            let transport = relay_transport
+                .or_transport(transport)
+                .and_then(move |socket, endpoint| {
+                    let addr = match &endpoint {
+                        ConnectedPoint::Dialer { address, .. } => address,
+                        ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+                    };
+                    let result = gate.read().check_address(addr);
+                    future::ready(match result {
+                        Ok(()) => Ok(socket),
+                        Err(err) => {
+                            notify_gated(gated, &endpoint, None, &err);
+                            Err(err)
+                        }
+                    })
+                })
+                .boxed();
             let transport = if let Some(psk) = config.psk {
-                let psk = PreSharedKey::new(psk);
-                EitherTransport::Left(
-                    transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
-                )
+                EitherTransport::Left(transport.and_then(move |socket, _| {
+                    PnetConfig::new(psk)
+                        .handshake(socket)
+                        .map_ok(PskChecked::new)
+                }))
             } else {
                 EitherTransport::Right(transport)
             };
             let dh_key = noise::Keypair::<X25519Spec>::new()
-                .into_authentic(&libp2p::core::identity::Keypair::Ed25519(
-                    config.node_key.clone(),
-                ))
+                .into_authentic(&config.node_key)
                 .unwrap();
             transport
                 .upgrade(Version::V1)
@@ -188,6 +474,20 @@ impl NetworkService {
                 .timeout(Duration::from_secs(5))
         };
         assert_transport_error_type::<_, TransportError>(&tcp);
+        let gate = connection_gate.clone();
+        let gated = cmd_tx.clone();
+        let bandwidth = Arc::new(Bandwidth::new(serve_limiter.clone()));
+        let counted = bandwidth.clone();
+        let tcp = tcp.and_then(move |(peer, muxer), endpoint| {
+            let result = gate.read().check_peer(&peer);
+            future::ready(match result {
+                Ok(()) => Ok((peer, StreamMuxerBox::new(counted.count(peer, muxer)))),
+                Err(err) => {
+                    notify_gated(gated, &endpoint, Some(peer), &err);
+                    Err(err)
+                }
+            })
+        });
         /*let quic = {
             QuicConfig {
                 keypair: config.node_key,
@@ -202,52 +502,46 @@ impl NetworkService {
             EitherOutput::First(first) => first,
             EitherOutput::Second(second) => second,
         });*/
-        let quic_or_tcp = tcp.boxed();
-        #[cfg(feature = "async_global")]
-        let transport = if let Some(config) = config.dns {
-            match config {
-                DnsConfig::Custom { config, opts } => {
-                    Dns::custom(quic_or_tcp, config, opts).await?
-                }
-                DnsConfig::SystemWithFallback { config, opts } => {
-                    match trust_dns_resolver::system_conf::read_system_conf() {
-                        Ok((config, opts)) => Dns::custom(quic_or_tcp, config, opts).await?,
-                        Err(e) => {
-                            tracing::warn!("falling back to custom DNS config, system default yielded error `${:#}`", e);
-                            Dns::custom(quic_or_tcp, config, opts).await?
-                        }
-                    }
-                }
-            }
-        } else {
-            Dns::system(quic_or_tcp).await?
-        };
-        #[cfg(all(feature = "tokio", not(feature = "async_global")))]
-        let transport = if let Some(config) = config.dns {
-            match config {
-                DnsConfig::Custom { config, opts } => Dns::custom(quic_or_tcp, config, opts)?,
-                DnsConfig::SystemWithFallback { config, opts } => {
-                    match trust_dns_resolver::system_conf::read_system_conf() {
-                        Ok((config, opts)) => Dns::custom(quic_or_tcp, config, opts)?,
-                        Err(e) => {
-                            tracing::warn!("falling back to custom DNS config, system default yielded error `${:#}`", e);
-                            Dns::custom(quic_or_tcp, config, opts)?
-                        }
-                    }
-                }
-            }
-        } else {
-            Dns::system(quic_or_tcp)?
-        };
-        assert_transport_error_type::<_, DnsErr<std::io::Error>>(&transport);
+        let transport = tcp;
 
         let exec = executor.clone();
-        let swarm = SwarmBuilder::new(transport.boxed(), behaviour, peer_id)
+        let mut swarm = SwarmBuilder::new(transport.boxed(), behaviour, peer_id)
             .executor(Box::new(move |fut| {
                 exec.spawn(fut).detach();
             }))
             .max_negotiating_inbound_streams(10000)
+            .connection_limits(
+                SwarmConnectionLimits::default()
+                    .with_max_established_incoming(
+                        config.connection_limits.max_established_incoming,
+                    )
+                    .with_max_established_outgoing(
+                        config.connection_limits.max_established_outgoing,
+                    )
+                    .with_max_established_per_peer(
+                        config.connection_limits.max_established_per_peer,
+                    )
+                    .with_max_pending_outgoing(config.connection_limits.max_pending_outgoing),
+            )
             .build();
+        let mut relays = FnvHashMap::default();
+        for addr in &config.relays {
+            let relay = match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+                _ => None,
+            }
+            .ok_or_else(|| InvalidNetworkConfig::RelayWithoutPeerId(addr.clone()))?;
+            let addr = addr.clone().with(Protocol::P2pCircuit);
+            let listener = swarm.listen_on(addr.clone())?;
+            relays.insert(
+                relay,
+                RelayListener {
+                    listener,
+                    addr,
+                    active: false,
+                },
+            );
+        }
         /*
         // Required for swarm book keeping.
         swarm
@@ -255,28 +549,121 @@ impl NetworkService {
             .unwrap();
         */
 
-        let bootstrapped = Writer::new(false);
+        let bootstrapped = Variable::new(false);
         let bootstrapped2 = bootstrapped.reader();
-        let (cmd_tx, cmd_rx) = mpsc::channel(100);
-        let swarm_task = executor.spawn(poll_swarm(
-            cmd_rx,
-            cmd_tx.clone(),
-            swarm,
-            executor.clone(),
-            bootstrapped,
-        ));
-
-        Ok(Self {
+        // the swarm task applies the initial bans, they are listed right away
+        let bans = Variable::new(banned.iter().copied().collect());
+        let bans2 = bans.reader();
+        let topics = Variable::new(FnvHashMap::default());
+        let topics2 = topics.reader();
+        let subscribed = Variable::new(FnvHashSet::default());
+        let subscribed2 = subscribed.reader();
+        let scores = Variable::new(FnvHashMap::default());
+        let scores2 = scores.reader();
+        let published = Variable::new(FnvHashMap::default());
+        let published2 = published.reader();
+        let listen_addresses = std::mem::take(&mut config.listen_addresses);
+        let bootstrap = config.bootstrap.clone();
+        let swarm_monitor = TaskMonitor::new(BackgroundTask::Swarm);
+        // boxed, spawning the swarm task doesn't copy its large future around
+        // on the stack
+        let swarm_task = executor.spawn_monitored(
+            &swarm_monitor,
+            Box::pin(poll_swarm(
+                cmd_rx,
+                cmd_tx.clone(),
+                swarm,
+                executor.clone(),
+                bootstrapped,
+                listener_ids,
+                relays,
+                nat_status,
+                connection_counts,
+                bans,
+                banned,
+                topics,
+                subscribed,
+                scores,
+                graylist_threshold,
+                published,
+                rebootstrap,
+                bitswap_counters.clone(),
+                bandwidth.clone(),
+                block_events,
+                requests,
+                mdns_auto_dial,
+                ping_max_failures,
+            )),
+        );
+
+        let mut network = Self {
             bootstrapped: bootstrapped2,
             peers: peers2,
             listeners: listeners2,
+            listener_ids: listener_ids2,
             external: external2,
+            nat_status: nat_status2,
+            autonat,
+            connection_limits: config.connection_limits,
+            connection_counts: connection_counts2,
+            connection_gate,
+            banned: bans2,
+            topics: topics2,
+            subscribed: subscribed2,
+            scores: scores2,
+            published: published2,
+            bitswap_counters,
+            bandwidth,
+            serve_limiter,
             public_key,
+            node_key,
             peer_id,
             node_name,
+            fetch_timeout: config.fetch_timeout,
+            fetch_via_dht: config.fetch_via_dht,
+            sync_via_dht: config.sync_via_dht,
+            // scheduled syncs are always pipelined
+            sync_pipeline: config
+                .sync_pipeline
+                .or_else(|| config.sync_scheduler.map(|_| Default::default())),
+            sync_scheduler: config
+                .sync_scheduler
+                .map(|config| Arc::new(SyncScheduler::new(config))),
+            dns_config,
+            request_response,
             cmd: cmd_tx,
+            swarm_monitor,
             _swarm_task: Arc::new(swarm_task),
-        })
+        };
+        // the listeners are bound before returning, so that `listeners` is
+        // populated right away
+        for addr in listen_addresses {
+            network.listen_startup(addr).await?;
+        }
+        if !bootstrap.is_empty() {
+            let mut network = network.clone();
+            executor
+                .spawn(async move {
+                    if let Err(err) = network.bootstrap(bootstrap).await {
+                        tracing::warn!("startup bootstrap failed: {:#}", err);
+                    }
+                })
+                .detach();
+        }
+        Ok(network)
+    }
+
+    /// Listens on `addr` and waits for its first listen address.
+    async fn listen_startup(&mut self, addr: Multiaddr) -> Result<()> {
+        let failed = |reason: String| ListenFailed {
+            addr: addr.clone(),
+            reason,
+        };
+        match self.listen_on(addr.clone()).next().await {
+            Some(ListenerEvent::NewListenAddr(_)) => Ok(()),
+            Some(ListenerEvent::ListenFailed(_, reason)) => Err(failed(reason).into()),
+            _ => Err(failed("listener closed".into()).into()),
+        }
     }
 
     pub fn local_public_key(&self) -> PublicKey {
@@ -287,28 +674,79 @@ impl NetworkService {
         self.peer_id
     }
 
+    /// Signs `msg` with the node key.
+    pub(crate) fn sign(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.node_key.sign(msg)?)
+    }
+
+    pub fn fetch_timeout(&self) -> Option<Duration> {
+        self.fetch_timeout
+    }
+
+    pub fn fetch_via_dht(&self) -> Option<DhtFetchConfig> {
+        self.fetch_via_dht
+    }
+
+    pub fn sync_via_dht(&self) -> Option<DhtSyncConfig> {
+        self.sync_via_dht
+    }
+
+    pub fn sync_pipeline(&self) -> Option<SyncPipelineConfig> {
+        self.sync_pipeline
+    }
+
+    /// Returns a place in the sync scheduler for a pipelined sync, if it is
+    /// enabled.
+    pub(crate) fn sync_ticket(&self) -> Option<SyncTicket> {
+        self.sync_scheduler
+            .as_ref()
+            .map(|scheduler| scheduler.ticket())
+    }
+
+    pub fn dns_config(&self) -> Option<DnsConfig> {
+        self.dns_config.clone()
+    }
+
     pub fn local_node_name(&self) -> String {
         self.node_name.clone()
     }
 
-    fn cmd(&mut self, msg: NetworkCommand) -> Option<(NetworkCommand, &'static str)> {
-        Self::handle_send_result(self.cmd.try_send(msg))
+    /// Returns the status of the swarm task.
+    pub fn swarm_status(&self) -> TaskStatus {
+        self.swarm_monitor.status()
+    }
+
+    pub(crate) fn swarm_monitor(&self) -> &TaskMonitor {
+        &self.swarm_monitor
     }
 
-    fn cmd_shared(&self, msg: NetworkCommand) -> Option<(NetworkCommand, &'static str)> {
-        Self::handle_send_result(self.cmd.clone().try_send(msg))
+    fn cmd(&mut self, msg: NetworkCommand) -> Option<(NetworkCommand, anyhow::Error)> {
+        let res = self.cmd.try_send(msg);
+        self.handle_send_result(res)
     }
 
+    fn cmd_shared(&self, msg: NetworkCommand) -> Option<(NetworkCommand, anyhow::Error)> {
+        self.handle_send_result(self.cmd.clone().try_send(msg))
+    }
+
+    /// The swarm closes the command channel when it shuts down, so a
+    /// disconnected channel is reported as `Shutdown` unless the swarm task
+    /// failed.
     fn handle_send_result(
+        &self,
         res: Result<(), TrySendError<NetworkCommand>>,
-    ) -> Option<(NetworkCommand, &'static str)> {
+    ) -> Option<(NetworkCommand, anyhow::Error)> {
         match res {
             Ok(_) => None,
             Err(err) => {
                 let reason = if err.is_disconnected() {
-                    "receiver went away"
+                    match self.swarm_monitor.status() {
+                        TaskStatus::Panicked(msg) => anyhow!("swarm task panicked: {}", msg),
+                        TaskStatus::Exited => anyhow!("swarm task exited"),
+                        _ => Shutdown.into(),
+                    }
                 } else {
-                    "channel is full"
+                    anyhow!("channel is full")
                 };
                 let val = err.into_inner();
                 tracing::warn!("failed IPFS swarm command {:?}: {}", val, reason);
@@ -319,8 +757,8 @@ impl NetworkService {
 
     pub fn listen_on(&mut self, addr: Multiaddr) -> impl Stream<Item = ListenerEvent> {
         let (tx, rx) = mpsc::unbounded();
-        if let Some((NetworkCommand::ListenOn(addr, tx), reason)) =
-            self.cmd(NetworkCommand::ListenOn(addr, tx))
+        if let Some((NetworkCommand::ListenOn(addr, tx, _), reason)) =
+            self.cmd(NetworkCommand::ListenOn(addr, tx, None))
         {
             tx.unbounded_send(ListenerEvent::ListenFailed(
                 addr,
@@ -331,6 +769,29 @@ impl NetworkService {
         rx
     }
 
+    pub fn add_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> impl Future<Output = Result<(ListenerId, impl Stream<Item = ListenerEvent>)>> {
+        let (tx, rx) = mpsc::unbounded();
+        let (id_tx, id_rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::ListenOn(addr, tx, Some(id_tx))) {
+            return future::ready(Err(err)).left_future();
+        }
+        async move {
+            let id = id_rx.await??;
+            Ok((id, rx))
+        }
+        .right_future()
+    }
+
+    pub fn remove_listener(&mut self, id: ListenerId) -> bool {
+        if !self.listener_ids.project(|ids| ids.contains(&id)) {
+            return false;
+        }
+        self.cmd(NetworkCommand::RemoveListener(id)).is_none()
+    }
+
     pub fn listeners(&self) -> Vec<Multiaddr> {
         self.listeners.project(|l| l.iter().cloned().collect())
     }
@@ -341,7 +802,34 @@ impl NetworkService {
     }
 
     pub fn external_addresses(&self) -> Vec<AddressRecord> {
-        self.external.get_cloned()
+        if self.autonat {
+            // observed addresses are only reported once AutoNAT confirmed them
+            self.external.project(|external| {
+                external
+                    .iter()
+                    .filter(|record| record.score == AddressScore::Infinite)
+                    .cloned()
+                    .collect()
+            })
+        } else {
+            self.external.get_cloned()
+        }
+    }
+
+    pub fn nat_status(&self) -> NatStatus {
+        self.nat_status.get_cloned()
+    }
+
+    pub fn watch_nat_status(&self) -> Observer<NatStatus> {
+        self.nat_status.observe()
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        self.connection_limits
+    }
+
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.connection_counts.get()
     }
 
     pub fn add_address(&mut self, peer: PeerId, addr: Multiaddr) {
@@ -364,14 +852,125 @@ impl NetworkService {
         self.cmd(NetworkCommand::DialAddress(peer, addr));
     }
 
+    /// Dials the known addresses of `peer` and resolves once connected, or
+    /// with `ConnectFailed` once all dial attempts failed.
+    pub fn connect(&mut self, peer: PeerId) -> impl Future<Output = Result<()>> {
+        if self.is_connected(&peer) {
+            return future::ready(Ok(())).left_future();
+        }
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::Connect(peer, tx)) {
+            return future::ready(Err(err)).left_future();
+        }
+        async { rx.await? }.right_future()
+    }
+
+    /// Resolves once connected to `peer`, or with `ConnectTimeout` after
+    /// `timeout`. Doesn't dial.
+    pub fn wait_connected(
+        &self,
+        peer: PeerId,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<()>> {
+        if self.is_connected(&peer) {
+            return future::ready(Ok(())).left_future();
+        }
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd_shared(NetworkCommand::WaitConnected(peer, tx)) {
+            return future::ready(Err(err)).left_future();
+        }
+        let since = Utc::now();
+        let peers = self.peers.clone();
+        async move {
+            match future::select(rx, Delay::new(timeout)).await {
+                Either::Left((res, _)) => res?,
+                Either::Right(_) => {
+                    let info = peers.project(|peers| peers.get(&peer).cloned());
+                    let failures = failures_since(info, since);
+                    Err(ConnectTimeout { peer, failures }.into())
+                }
+            }
+        }
+        .right_future()
+    }
+
+    pub fn disconnect(
+        &mut self,
+        peer: PeerId,
+        addr: Option<Multiaddr>,
+    ) -> impl Future<Output = Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::Disconnect(peer, addr, tx)) {
+            return future::ready(Err(err)).left_future();
+        }
+        async { Ok(rx.await?) }.right_future()
+    }
+
     pub fn ban(&mut self, peer: PeerId) {
-        self.cmd(NetworkCommand::Ban(peer));
+        self.cmd(NetworkCommand::Ban(peer, None));
+    }
+
+    pub fn ban_until(&mut self, peer: PeerId, expires: DateTime<Utc>) {
+        self.cmd(NetworkCommand::Ban(peer, Some(expires)));
+    }
+
+    pub fn address_book(&self) -> Vec<PeerAddresses> {
+        self.peers.project(|peers| {
+            peers
+                .iter()
+                .filter(|(_, info)| !info.addresses.is_empty())
+                .map(|(peer, info)| {
+                    let addrs = info
+                        .addresses()
+                        .map(|(addr, source, seen)| (addr.clone(), source, seen))
+                        .collect();
+                    (*peer, addrs)
+                })
+                .collect()
+        })
+    }
+
+    pub fn banned_peers(&self) -> Vec<(PeerId, Option<DateTime<Utc>>)> {
+        let now = Utc::now();
+        self.banned.project(|banned| {
+            banned
+                .iter()
+                .filter(|(_, expires)| expires.map(|expires| expires > now).unwrap_or(true))
+                .map(|(peer, expires)| (*peer, *expires))
+                .collect()
+        })
     }
 
     pub fn unban(&mut self, peer: PeerId) {
         self.cmd(NetworkCommand::Unban(peer));
     }
 
+    pub fn connection_gate(&self) -> ConnectionGate {
+        self.connection_gate.read().clone()
+    }
+
+    pub fn set_connection_gate(&self, gate: ConnectionGate) {
+        *self.connection_gate.write() = gate;
+    }
+
+    pub fn topic_peers(&self, topic: &str) -> Vec<PeerId> {
+        self.topics.project(|topics| {
+            topics
+                .get(topic)
+                .map(|peers| peers.iter().copied().collect())
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn peer_score(&self, peer: &PeerId) -> Option<f64> {
+        self.scores.project(|scores| scores.get(peer).copied())
+    }
+
+    pub fn subscribed_topics(&self) -> Vec<String> {
+        self.subscribed
+            .project(|subscribed| subscribed.iter().cloned().collect())
+    }
+
     pub fn peers(&self) -> Vec<PeerId> {
         self.peers.project(|peers| peers.keys().copied().collect())
     }
@@ -383,7 +982,7 @@ impl NetworkService {
                 .flat_map(|(peer, info)| {
                     info.connections
                         .iter()
-                        .map(move |(a, t)| (*peer, a.clone(), t.0, t.1))
+                        .map(move |(a, (dt, cp))| (*peer, a.clone(), *dt, Direction::from(cp)))
                 })
                 .collect()
         })
@@ -403,13 +1002,22 @@ impl NetworkService {
         self.peers.project(|peers| peers.get(peer).cloned())
     }
 
+    pub fn connection_info(&self, peer: &PeerId) -> Vec<ConnectionInfo> {
+        self.peers.project(|peers| {
+            peers
+                .get(peer)
+                .map(|info| info.connection_info())
+                .unwrap_or_default()
+        })
+    }
+
     pub fn bootstrap(
         &mut self,
         peers: Vec<(PeerId, Multiaddr)>,
     ) -> impl Future<Output = Result<()>> {
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd(NetworkCommand::Bootstrap(peers, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+            return future::ready(Err(err)).left_future();
         }
         tracing::debug!("started bootstrap");
         async {
@@ -424,37 +1032,112 @@ impl NetworkService {
         self.bootstrapped.get()
     }
 
-    // This weird function signature seems impossible to support. WTF.
-    // pub async fn get_closest_peers<K>(&self, key: K) -> Result<Vec<PeerId>>
-    // where
-    //     K: Into<BucketKey<K>> + Into<Vec<u8>> + Clone,
-    // {
-    //     let rx = {
-    //         let mut swarm = self.swarm.lock();
-    //         swarm.behaviour_mut().get_closest_peers(key)
-    //     };
-    //     Ok(rx.await??)
-    // }
+    pub fn watch_bootstrapped(&self) -> Observer<bool> {
+        self.bootstrapped.observe()
+    }
 
-    pub fn providers(&mut self, key: Key) -> impl Future<Output = Result<HashSet<PeerId>>> {
+    /// Bootstraps from addresses that may lack a `/p2p` component. `/dnsaddr`
+    /// addresses are resolved by the dns transport when dialing them, the
+    /// peer id of addresses without `/p2p` is learned by connecting to them.
+    pub fn bootstrap_addrs(
+        &mut self,
+        addrs: Vec<Multiaddr>,
+    ) -> impl Future<Output = Result<Vec<(Multiaddr, Result<PeerId>)>>> {
+        let mut this = self.clone();
+        async move {
+            let peers = future::join_all(addrs.iter().map(|addr| {
+                match addr.iter().last() {
+                    Some(Protocol::P2p(hash)) => future::ready(
+                        PeerId::from_multihash(hash)
+                            .map_err(|_| anyhow!("invalid peer id in {}", addr)),
+                    )
+                    .left_future(),
+                    _ => this.dial_unknown(addr.clone()).right_future(),
+                }
+            }))
+            .await;
+            let nodes = addrs
+                .iter()
+                .zip(peers.iter())
+                .filter_map(|(addr, peer)| Some((*peer.as_ref().ok()?, addr.clone())))
+                .collect::<Vec<_>>();
+            if !nodes.is_empty() {
+                this.bootstrap(nodes).await?;
+            }
+            Ok(addrs.into_iter().zip(peers).collect())
+        }
+    }
+
+    /// Connects to an address to learn the peer id behind it.
+    fn dial_unknown(&self, addr: Multiaddr) -> impl Future<Output = Result<PeerId>> {
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd(NetworkCommand::Providers(key, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+        if let Some((_, err)) = self.cmd_shared(NetworkCommand::DialUnknown(addr.clone(), tx)) {
+            return future::ready(Err(err)).left_future();
+        }
+        async move {
+            // not all dial errors carry the address, so don't wait forever
+            match future::select(rx, Delay::new(Duration::from_secs(60))).await {
+                Either::Left((res, _)) => res?,
+                Either::Right(_) => Err(anyhow!("dialing {} timed out", addr)),
+            }
+        }
+        .right_future()
+    }
+
+    pub fn get_closest_peers(
+        &mut self,
+        peer: PeerId,
+    ) -> impl Future<Output = Result<Vec<(PeerId, Vec<Multiaddr>)>>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::GetClosestPeers(peer, tx)) {
+            return future::ready(Err(err)).left_future();
         }
         async { rx.await? }.right_future()
     }
 
+    pub fn providers(&mut self, key: Key) -> impl Future<Output = Result<HashSet<PeerId>>> {
+        let span = tracing::debug_span!("providers");
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::Providers(key, tx, span.clone())) {
+            return future::ready(Err(err)).left_future();
+        }
+        async { rx.await? }.instrument(span).right_future()
+    }
+
+    pub fn providers_stream(&mut self, key: Key) -> impl Stream<Item = PeerId> {
+        let (tx, rx) = mpsc::unbounded();
+        // if sending fails the sender is dropped with the command, ending the stream
+        self.cmd(NetworkCommand::ProvidersStream(key, tx));
+        rx
+    }
+
     pub fn provide(&mut self, key: Key) -> impl Future<Output = Result<()>> {
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd(NetworkCommand::Provide(key, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+            return future::ready(Err(err)).left_future();
         }
         async { rx.await? }.right_future()
     }
 
+    /// Starts mdns with `config` or stops it if `None`.
+    pub fn set_mdns(&mut self, config: Option<MdnsConfig>) -> impl Future<Output = Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::SetMdns(config, tx)) {
+            return future::ready(Err(err)).left_future();
+        }
+        async { rx.await? }.right_future()
+    }
+
+    /// Stops polling the swarm for `duration`, so that the node becomes
+    /// unresponsive once the connection buffers are full.
+    #[cfg(test)]
+    pub(crate) fn suspend(&mut self, duration: Duration) {
+        self.cmd(NetworkCommand::Suspend(duration));
+    }
+
     pub fn unprovide(&mut self, key: Key) -> Result<()> {
         if let Some((_, err)) = self.cmd(NetworkCommand::Unprovide(key)) {
-            return Err(anyhow!("{}", err));
+            return Err(err);
         }
         Ok(())
     }
@@ -464,28 +1147,63 @@ impl NetworkService {
         key: Key,
         quorum: Quorum,
     ) -> impl Future<Output = Result<Vec<PeerRecord>>> {
+        let span = tracing::debug_span!("get_record");
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd(NetworkCommand::GetRecord(key, quorum, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+        if let Some((_, err)) = self.cmd(NetworkCommand::GetRecord(key, quorum, tx, span.clone())) {
+            return future::ready(Err(err)).left_future();
         }
-        async { rx.await? }.right_future()
+        async { rx.await? }.instrument(span).right_future()
     }
 
     pub fn put_record(
         &mut self,
         record: Record,
         quorum: Quorum,
+        options: PutOptions,
     ) -> impl Future<Output = Result<()>> {
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd(NetworkCommand::PutRecord(record, quorum, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+        if let Some((_, err)) = self.cmd(NetworkCommand::PutRecord(record, quorum, options, tx)) {
+            return future::ready(Err(err)).left_future();
         }
         async { rx.await? }.right_future()
     }
 
+    pub fn bitswap_stats(&self) -> BitswapStats {
+        let mut stats = self.bitswap_counters.stats();
+        if let Some(scheduler) = &self.sync_scheduler {
+            stats.outstanding_wants = scheduler.outstanding();
+        }
+        stats
+    }
+
+    pub fn bandwidth(&self) -> BandwidthStats {
+        self.bandwidth.stats()
+    }
+
+    pub fn peer_bandwidth(&self) -> Vec<(PeerId, PeerBandwidth)> {
+        self.bandwidth.peers()
+    }
+
+    pub fn serve_rate_limit(&self) -> Option<RateLimit> {
+        self.serve_limiter.get()
+    }
+
+    pub fn set_serve_rate_limit(&self, limit: Option<RateLimit>) -> Result<()> {
+        if let Some(limit) = &limit {
+            limit.validate()?;
+        }
+        self.serve_limiter.set(limit);
+        Ok(())
+    }
+
+    pub fn published_records(&self) -> Vec<PublishedRecord> {
+        self.published
+            .project(|published| published.values().cloned().collect())
+    }
+
     pub fn remove_record(&mut self, key: Key) -> Result<()> {
         if let Some((_, err)) = self.cmd(NetworkCommand::RemoveRecord(key)) {
-            return Err(anyhow!("{}", err));
+            return Err(err);
         }
         Ok(())
     }
@@ -493,18 +1211,23 @@ impl NetworkService {
     pub fn subscribe(
         &mut self,
         topic: String,
+        validator: Option<MessageValidator>,
     ) -> impl Future<Output = Result<impl Stream<Item = GossipEvent>>> {
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd(NetworkCommand::Subscribe(topic, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+        if let Some((_, err)) = self.cmd(NetworkCommand::Subscribe(topic, validator, tx)) {
+            return future::ready(Err(err)).left_future();
         }
         async { rx.await? }.right_future()
     }
 
-    pub fn publish(&mut self, topic: String, msg: Vec<u8>) -> impl Future<Output = Result<()>> {
+    pub fn publish(
+        &mut self,
+        topic: String,
+        msg: Vec<u8>,
+    ) -> impl Future<Output = Result<MessageId>> {
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd(NetworkCommand::Publish(topic, msg, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+            return future::ready(Err(err)).left_future();
         }
         async { rx.await? }.right_future()
     }
@@ -512,18 +1235,74 @@ impl NetworkService {
     pub fn broadcast(&mut self, topic: String, msg: Vec<u8>) -> impl Future<Output = Result<()>> {
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd(NetworkCommand::Broadcast(topic, msg, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+            return future::ready(Err(err)).left_future();
         }
         async { rx.await? }.right_future()
     }
 
+    /// Sends a request of `protocol` to `peer`, failing with `RequestTimeout`
+    /// after `timeout` or the `RequestResponseConfig::request_timeout`,
+    /// whichever is shorter.
+    pub fn request(
+        &mut self,
+        peer: PeerId,
+        protocol: String,
+        data: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = Result<Vec<u8>>> {
+        if let Some(config) = &self.request_response {
+            if data.len() > config.max_request_size {
+                let err = PayloadTooLarge {
+                    size: data.len(),
+                    max: config.max_request_size,
+                };
+                return future::ready(Err(err.into())).left_future();
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::Request(peer, protocol, data, tx)) {
+            return future::ready(Err(err)).left_future();
+        }
+        async move {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return rx.await?,
+            };
+            match future::select(rx, Delay::new(timeout)).await {
+                Either::Left((result, _)) => result?,
+                Either::Right(_) => Err(RequestTimeout(peer).into()),
+            }
+        }
+        .right_future()
+    }
+
+    /// Returns a stream of the requests of a registered `protocol`. The
+    /// stream ends right away if the protocol isn't registered.
+    pub fn requests(&mut self, protocol: String) -> impl Stream<Item = InboundRequest> {
+        let (tx, rx) = mpsc::unbounded();
+        let registered = self
+            .request_response
+            .as_ref()
+            .map(|config| config.protocols.contains(&protocol))
+            .unwrap_or_default();
+        if !registered {
+            tracing::warn!("requests of unregistered protocol {}", protocol);
+        } else if let Some((_, err)) = self.cmd(NetworkCommand::Requests(protocol, tx)) {
+            tracing::debug!("cannot receive requests: {:#}", err);
+        }
+        rx
+    }
+
     // This cannot take `&mut self` due to trait constraints, so it needs to use the less efficient cmd_shared.
     pub fn get(&self, cid: Cid, providers: Vec<PeerId>) -> impl Future<Output = Result<GetQuery>> {
+        let span = tracing::debug_span!("get", cid = %cid);
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd_shared(NetworkCommand::Get(cid, providers, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+        if let Some((_, err)) =
+            self.cmd_shared(NetworkCommand::Get(cid, providers, tx, span.clone()))
+        {
+            return future::ready(Err(err)).left_future();
         }
-        async { Ok(rx.await?) }.right_future()
+        async { Ok(rx.await?) }.instrument(span).right_future()
     }
 
     // This cannot take `&mut self` due to trait constraints, so it needs to use the less efficient cmd_shared.
@@ -540,9 +1319,22 @@ impl NetworkService {
             return future::ready(Ok(SyncQuery::ready(Err(BlockNotFound(missing[0]).into()))))
                 .left_future();
         }
+        let span = tracing::debug_span!("sync", cid = %cid);
+        let (tx, rx) = oneshot::channel();
+        let cmd = NetworkCommand::Sync(cid, providers, missing, tx, span.clone());
+        if let Some((_, err)) = self.cmd_shared(cmd) {
+            return future::ready(Err(err)).left_future();
+        }
+        async { Ok(rx.await?) }.instrument(span).right_future()
+    }
+
+    /// Closes all listeners and connections and stops the swarm. Commands
+    /// sent afterwards fail with `Shutdown`.
+    pub fn shutdown(&mut self) -> impl Future<Output = Result<()>> {
         let (tx, rx) = oneshot::channel();
-        if let Some((_, err)) = self.cmd_shared(NetworkCommand::Sync(cid, providers, missing, tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+        self.swarm_monitor.stop();
+        if let Some((_, err)) = self.cmd(NetworkCommand::Shutdown(tx)) {
+            return future::ready(Err(err)).left_future();
         }
         async { Ok(rx.await?) }.right_future()
     }
@@ -550,27 +1342,106 @@ impl NetworkService {
     pub fn swarm_events(&mut self) -> impl Future<Output = Result<SwarmEvents>> {
         let (tx, rx) = oneshot::channel();
         if let Some((_, err)) = self.cmd(NetworkCommand::SwarmEvents(tx)) {
-            return future::ready(Err(anyhow!("{}", err))).left_future();
+            return future::ready(Err(err)).left_future();
+        }
+        async { Ok(rx.await?) }.right_future()
+    }
+
+    pub fn swarm_events_filtered(
+        &mut self,
+        filter: EventFilter,
+    ) -> impl Future<Output = Result<SwarmEvents>> {
+        let (tx, rx) = oneshot::channel();
+        if let Some((_, err)) = self.cmd(NetworkCommand::SwarmEventsFiltered(filter, tx)) {
+            return future::ready(Err(err)).left_future();
         }
         async { Ok(rx.await?) }.right_future()
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn poll_swarm<P: libipld::store::StoreParams>(
     mut cmd_rx: Receiver<NetworkCommand>,
     cmd_tx: Sender<NetworkCommand>,
     mut swarm: Swarm<NetworkBackendBehaviour<P>>,
     executor: Executor,
-    bootstrapped: Writer<bool>,
+    bootstrapped: Variable<bool>,
+    listener_ids: Variable<FnvHashSet<ListenerId>>,
+    mut relays: FnvHashMap<PeerId, RelayListener>,
+    nat_status: Variable<NatStatus>,
+    connection_counts: Variable<ConnectionCounts>,
+    bans: Variable<FnvHashMap<PeerId, Option<DateTime<Utc>>>>,
+    banned: Vec<(PeerId, Option<DateTime<Utc>>)>,
+    topics: Variable<FnvHashMap<String, FnvHashSet<PeerId>>>,
+    subscribed: Variable<FnvHashSet<String>>,
+    scores: Variable<FnvHashMap<PeerId, f64>>,
+    graylist_threshold: Option<f64>,
+    published: Variable<FnvHashMap<Key, PublishedRecord>>,
+    mut rebootstrap: Option<Rebootstrap>,
+    bitswap_counters: Arc<BitswapCounters>,
+    bandwidth: Arc<Bandwidth>,
+    mut block_events: mpsc::Receiver<Event>,
+    mut requests: PendingRequests,
+    mdns_auto_dial: bool,
+    ping_max_failures: Option<u32>,
 ) {
+    let mut sample = Delay::new(Duration::from_secs(1));
     let mut subscriptions =
         FnvHashMap::<String, Vec<mpsc::UnboundedSender<GossipEvent>>>::default();
+    let mut validators = FnvHashMap::<String, MessageValidator>::default();
+    let mut unknown_dials =
+        FnvHashMap::<Multiaddr, Vec<oneshot::Sender<Result<PeerId>>>>::default();
     let mut queries = FnvHashMap::<QueryId, QueryChannel>::default();
+    let mut connects = PendingConnects::default();
+    let mut spans = QuerySpans::default();
+    for (peer, expires) in banned {
+        ban_peer(&mut swarm, &bans, &executor, &cmd_tx, peer, expires);
+    }
     loop {
+        let counters = swarm.network_info();
+        let counters = counters.connection_counters();
+        let counts = ConnectionCounts {
+            pending_incoming: counters.num_pending_incoming(),
+            pending_outgoing: counters.num_pending_outgoing(),
+            established_incoming: counters.num_established_incoming(),
+            established_outgoing: counters.num_established_outgoing(),
+        };
+        if *connection_counts.read() != counts {
+            *connection_counts.write() = counts;
+        }
         match future::select(
             future::poll_fn(|cx| {
                 tracing::trace!("polling swarm ({:?})", std::thread::current().id());
-                swarm.poll_next_unpin(cx)
+                // scores and meshes change with the gossipsub heartbeat, which doesn't emit
+                // a swarm event, so they are sampled on a timer
+                while sample.poll_unpin(cx).is_ready() {
+                    sample.reset(Duration::from_secs(1));
+                    if let Some(threshold) = graylist_threshold {
+                        swarm.behaviour_mut().update_peer_scores(&scores, threshold);
+                    }
+                    swarm.behaviour().update_mesh_metrics();
+                    bandwidth.sample();
+                }
+                if let Some(rebootstrap) = rebootstrap.as_mut() {
+                    let bootstrap_complete = *bootstrapped.read();
+                    rebootstrap.poll(
+                        cx,
+                        &mut swarm,
+                        bootstrap_complete,
+                        &mut queries,
+                        &executor,
+                        &cmd_tx,
+                    );
+                }
+                let poll = swarm.poll_next_unpin(cx);
+                // kademlia does not emit events for intermediate query results, so check
+                // whenever the swarm was woken up
+                swarm.behaviour_mut().poll_providers_streams(&mut queries);
+                while let Poll::Ready(Some(event)) = block_events.poll_next_unpin(cx) {
+                    swarm.behaviour_mut().notify(event);
+                }
+                swarm.behaviour_mut().poll_responses(cx, &mut requests);
+                poll
             }),
             cmd_rx.next(),
         )
@@ -580,75 +1451,207 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                 tracing::debug!("poll_swarm: swarm stream ended, terminating");
                 return;
             }
-            Either::Left((Some(cmd), _)) => match cmd {
-                SwarmEvent::ConnectionClosed {
-                    peer_id,
-                    endpoint,
-                    num_established,
-                    cause,
-                } => swarm.behaviour_mut().connection_closed(
-                    peer_id,
-                    endpoint,
-                    num_established,
-                    cause,
-                ),
-                SwarmEvent::Behaviour(event) => {
-                    let swarm = swarm.behaviour_mut();
-                    match event {
-                        behaviour::NetworkBackendBehaviourEvent::Peers(e) => unreachable(e),
-                        behaviour::NetworkBackendBehaviourEvent::Kad(e) => {
-                            let mut bootstrap_complete = *bootstrapped.read();
-                            let bootstrap_old = bootstrap_complete;
-                            // DO NOT HOLD bootstrapped LOCK ACROSS ARBITRARY CODE
-                            swarm.inject_kad_event(e, &mut bootstrap_complete, &mut queries);
-                            if bootstrap_complete != bootstrap_old {
-                                *bootstrapped.write() = bootstrap_complete;
-                            }
+            Either::Left((Some(cmd), _)) => {
+                connects.inject_swarm_event(&cmd, |peer| swarm.behaviour().peer_info(peer));
+                match cmd {
+                    SwarmEvent::ConnectionClosed {
+                        peer_id,
+                        endpoint,
+                        num_established,
+                        cause,
+                    } => {
+                        if num_established == 0 {
+                            // gossipsub forgets the subscriptions of disconnected peers silently
+                            topics.write().retain(|_, peers| {
+                                peers.remove(&peer_id);
+                                !peers.is_empty()
+                            });
                         }
-                        behaviour::NetworkBackendBehaviourEvent::Mdns(e) => {
-                            swarm.inject_mdns_event(e);
+                        swarm.behaviour_mut().connection_closed(
+                            peer_id,
+                            endpoint,
+                            num_established,
+                            cause,
+                        )
+                    }
+                    SwarmEvent::IncomingConnectionError {
+                        send_back_addr,
+                        error: PendingInboundConnectionError::ConnectionLimit(limit),
+                        ..
+                    } => {
+                        tracing::debug!("rejected connection from {}: {}", send_back_addr, limit);
+                        swarm.behaviour_mut().notify(Event::ConnectionLimitReached(
+                            Direction::Inbound,
+                            None,
+                            Some(send_back_addr),
+                            limit.limit,
+                        ));
+                    }
+                    SwarmEvent::OutgoingConnectionError {
+                        peer_id,
+                        error: DialError::ConnectionLimit(limit),
+                    } => {
+                        tracing::debug!("rejected dial to {:?}: {}", peer_id, limit);
+                        swarm.behaviour_mut().notify(Event::ConnectionLimitReached(
+                            Direction::Outbound,
+                            peer_id,
+                            None,
+                            limit.limit,
+                        ));
+                    }
+                    SwarmEvent::ConnectionEstablished {
+                        peer_id,
+                        endpoint: ConnectedPoint::Dialer { address, .. },
+                        ..
+                    } if unknown_dials.contains_key(&address) => {
+                        for tx in unknown_dials.remove(&address).unwrap_or_default() {
+                            tx.send(Ok(peer_id)).ok();
                         }
-                        behaviour::NetworkBackendBehaviourEvent::Ping(e) => {
-                            swarm.inject_ping_event(e);
+                    }
+                    SwarmEvent::OutgoingConnectionError {
+                        peer_id: None,
+                        error: DialError::Transport(errors),
+                    } => {
+                        for (addr, err) in errors {
+                            for tx in unknown_dials.remove(&addr).unwrap_or_default() {
+                                tx.send(Err(anyhow!("dialing {} failed: {}", addr, err)))
+                                    .ok();
+                            }
                         }
-                        behaviour::NetworkBackendBehaviourEvent::Identify(e) => {
-                            swarm.inject_id_event(e);
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, .. } => {
+                        listener_ids.write().remove(&listener_id);
+                        if let Some((relay, listener)) = relays
+                            .iter_mut()
+                            .find(|(_, listener)| listener.listener == listener_id)
+                        {
+                            if listener.active {
+                                listener.active = false;
+                                swarm.remove_external_address(&listener.addr);
+                                swarm.behaviour_mut().notify(Event::RelayReservation(
+                                    *relay,
+                                    ReservationStatus::Expired,
+                                ));
+                            }
                         }
-                        behaviour::NetworkBackendBehaviourEvent::Bitswap(e) => {
-                            swarm.inject_bitswap_event(e, &mut queries);
+                    }
+                    SwarmEvent::Behaviour(
+                        behaviour::NetworkBackendBehaviourEvent::RelayClient(e),
+                    ) => {
+                        let changed = swarm.behaviour_mut().inject_relay_client_event(e);
+                        if let Some((relay, active)) = changed {
+                            if let Some(listener) = relays.get_mut(&relay) {
+                                if active {
+                                    swarm.add_external_address(
+                                        listener.addr.clone(),
+                                        AddressScore::Infinite,
+                                    );
+                                } else if listener.active {
+                                    swarm.remove_external_address(&listener.addr);
+                                }
+                                listener.active = active;
+                            }
                         }
-                        behaviour::NetworkBackendBehaviourEvent::Gossipsub(e) => {
-                            swarm.inject_gossip_event(e, &mut subscriptions);
+                    }
+                    SwarmEvent::Behaviour(behaviour::NetworkBackendBehaviourEvent::Autonat(e)) => {
+                        let changed = swarm.behaviour_mut().inject_autonat_event(e, &nat_status);
+                        if let Some((old, new)) = changed {
+                            if let NatStatus::Public(addr) = old {
+                                swarm.remove_external_address(&addr);
+                            }
+                            if let NatStatus::Public(addr) = new {
+                                swarm.add_external_address(addr, AddressScore::Infinite);
+                            }
                         }
-                        behaviour::NetworkBackendBehaviourEvent::Broadcast(e) => {
-                            swarm.inject_broadcast_event(e, &mut subscriptions);
+                    }
+                    SwarmEvent::Behaviour(event) => {
+                        let swarm = swarm.behaviour_mut();
+                        match event {
+                            behaviour::NetworkBackendBehaviourEvent::Peers(e) => unreachable(e),
+                            behaviour::NetworkBackendBehaviourEvent::Kad(e) => {
+                                spans.get(kad_query(&e)).in_scope(|| {
+                                    swarm.inject_kad_event(e, &bootstrapped, &mut queries)
+                                });
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Mdns(e) => {
+                                swarm.inject_mdns_event(e, mdns_auto_dial);
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Ping(e) => {
+                                swarm.inject_ping_event(e, ping_max_failures);
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Identify(e) => {
+                                swarm.inject_id_event(e);
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Bitswap(e) => {
+                                spans.get(bitswap_query(&e)).in_scope(|| {
+                                    swarm.inject_bitswap_event(e, &mut queries, &bitswap_counters)
+                                });
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Gossipsub(e) => {
+                                swarm.inject_gossip_event(
+                                    e,
+                                    &mut subscriptions,
+                                    &validators,
+                                    &topics,
+                                );
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Broadcast(e) => {
+                                swarm.inject_broadcast_event(e, &mut subscriptions);
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::RelayClient(_) => {
+                                // handled above, as it needs to update the external addresses
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::RelayServer(e) => {
+                                swarm.inject_relay_server_event(e);
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Autonat(_) => {
+                                // handled above, as it needs to update the external addresses
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::Dcutr(e) => {
+                                swarm.inject_dcutr_event(e);
+                            }
+                            behaviour::NetworkBackendBehaviourEvent::RequestResponse(e) => {
+                                swarm.inject_request_event(e, &mut requests);
+                            }
                         }
                     }
+                    _ => {}
                 }
-                _ => {}
-            },
+            }
             Either::Right((None, _)) => {
                 tracing::debug!("poll_swarm: command sender dropped, terminating");
                 return;
             }
             Either::Right((Some(cmd), _)) => match cmd {
-                NetworkCommand::ListenOn(addr, response) => {
+                NetworkCommand::ListenOn(addr, response, id) => {
                     let (tx, rx) = mpsc::unbounded();
                     swarm.behaviour_mut().swarm_events(tx);
                     match swarm.listen_on(addr.clone()) {
-                        Ok(listener) => executor
-                            .spawn(forward_listener_events(listener, response, rx))
-                            .detach(),
+                        Ok(listener) => {
+                            listener_ids.write().insert(listener);
+                            executor
+                                .spawn(forward_listener_events(listener, response, rx))
+                                .detach();
+                            if let Some(id) = id {
+                                id.send(Ok(listener)).ok();
+                            }
+                        }
                         Err(error) => {
+                            let error = error.to_string();
                             response
-                                .unbounded_send(ListenerEvent::ListenFailed(
-                                    addr,
-                                    error.to_string(),
-                                ))
+                                .unbounded_send(ListenerEvent::ListenFailed(addr, error.clone()))
                                 .ok();
+                            if let Some(id) = id {
+                                id.send(Err(anyhow!("{}", error))).ok();
+                            }
                         }
                     };
                 }
+                NetworkCommand::RemoveListener(id) => {
+                    if !swarm.remove_listener(id) {
+                        listener_ids.write().remove(&id);
+                    }
+                }
                 NetworkCommand::AddExternalAddress(addr) => {
                     swarm.add_external_address(addr, AddressScore::Infinite);
                 }
@@ -666,15 +1669,54 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                 NetworkCommand::Dial(peer) => {
                     swarm.behaviour_mut().dial(&peer);
                 }
+                NetworkCommand::Connect(peer, tx) => {
+                    if swarm.is_connected(&peer) {
+                        tx.send(Ok(())).ok();
+                    } else {
+                        match swarm.behaviour_mut().connect(&peer) {
+                            0 => {
+                                let err = ConnectFailed {
+                                    peer,
+                                    failures: vec![],
+                                };
+                                tx.send(Err(err.into())).ok();
+                            }
+                            dials => connects.push(peer, dials, tx),
+                        }
+                    }
+                }
+                NetworkCommand::WaitConnected(peer, tx) => {
+                    if swarm.is_connected(&peer) {
+                        tx.send(Ok(())).ok();
+                    } else {
+                        connects.wait(peer, tx);
+                    }
+                }
                 NetworkCommand::DialAddress(peer, addr) => {
                     swarm.behaviour_mut().dial_address(&peer, addr);
                 }
-                NetworkCommand::Ban(peer) => {
-                    swarm.ban_peer_id(peer);
+                NetworkCommand::Disconnect(peer, addr, tx) => {
+                    swarm.behaviour_mut().disconnect(&peer, addr.as_ref(), tx);
+                }
+                NetworkCommand::Ban(peer, expires) => {
+                    ban_peer(&mut swarm, &bans, &executor, &cmd_tx, peer, expires);
+                }
+                NetworkCommand::BanExpired(peer, expires) => {
+                    // the peer may have been banned again in the meantime
+                    if bans.read().get(&peer) == Some(&Some(expires)) {
+                        bans.write().remove(&peer);
+                        swarm.unban_peer_id(peer);
+                    }
                 }
                 NetworkCommand::Unban(peer) => {
+                    bans.write().remove(&peer);
                     swarm.unban_peer_id(peer);
                 }
+                NetworkCommand::ConnectionGated(direction, peer, addr, reason) => {
+                    swarm
+                        .behaviour_mut()
+                        .notify(Event::ConnectionGated(direction, peer, addr, reason));
+                }
                 NetworkCommand::Bootstrap(initial, tx) => {
                     let swarm = swarm.behaviour_mut();
                     for (peer, addr) in initial {
@@ -683,11 +1725,49 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                     }
                     swarm.bootstrap(&mut queries, tx);
                 }
-                NetworkCommand::Providers(key, tx) => {
+                NetworkCommand::DialUnknown(addr, tx) => {
+                    if let Some(txs) = unknown_dials.get_mut(&addr) {
+                        txs.push(tx);
+                    } else {
+                        let opts = DialOpts::unknown_peer_id().address(addr.clone()).build();
+                        match swarm.dial(opts) {
+                            Ok(()) => {
+                                unknown_dials.insert(addr, vec![tx]);
+                            }
+                            Err(err) => {
+                                tx.send(Err(anyhow!("dialing {} failed: {}", addr, err)))
+                                    .ok();
+                            }
+                        }
+                    }
+                }
+                NetworkCommand::GetClosestPeers(peer, tx) => {
                     let bootstrap_complete = *bootstrapped.read();
-                    swarm
-                        .behaviour_mut()
-                        .providers(key, bootstrap_complete, &mut queries, tx);
+                    swarm.behaviour_mut().get_closest_peers(
+                        peer,
+                        bootstrap_complete,
+                        &mut queries,
+                        tx,
+                    );
+                }
+                NetworkCommand::Providers(key, tx, span) => {
+                    let bootstrap_complete = *bootstrapped.read();
+                    let id =
+                        swarm
+                            .behaviour_mut()
+                            .providers(key, bootstrap_complete, &mut queries, tx);
+                    if let Some(event) = id.and_then(|id| spans.start(id, &span)) {
+                        swarm.behaviour_mut().notify(event);
+                    }
+                }
+                NetworkCommand::ProvidersStream(key, tx) => {
+                    let bootstrap_complete = *bootstrapped.read();
+                    swarm.behaviour_mut().providers_stream(
+                        key,
+                        bootstrap_complete,
+                        &mut queries,
+                        tx,
+                    );
                 }
                 NetworkCommand::Provide(key, tx) => {
                     let bootstrap_complete = *bootstrapped.read();
@@ -695,21 +1775,37 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                         .behaviour_mut()
                         .provide(key, bootstrap_complete, &mut queries, tx);
                 }
+                NetworkCommand::SetMdns(config, tx) => {
+                    tx.send(swarm.behaviour_mut().set_mdns(config)).ok();
+                }
                 NetworkCommand::Unprovide(key) => {
                     swarm.behaviour_mut().unprovide(&key);
                 }
-                NetworkCommand::GetRecord(key, quorum, tx) => {
+                NetworkCommand::GetRecord(key, quorum, tx, span) => {
                     let bootstrap_complete = *bootstrapped.read();
-                    swarm.behaviour_mut().get_record(
+                    let id = swarm.behaviour_mut().get_record(
                         key,
                         quorum,
                         bootstrap_complete,
                         &mut queries,
                         tx,
                     );
+                    if let Some(event) = id.and_then(|id| spans.start(id, &span)) {
+                        swarm.behaviour_mut().notify(event);
+                    }
                 }
-                NetworkCommand::PutRecord(record, quorum, tx) => {
+                NetworkCommand::PutRecord(record, quorum, options, tx) => {
                     let bootstrap_complete = *bootstrapped.read();
+                    let published_record = PublishedRecord::new(
+                        record,
+                        quorum,
+                        options.ttl.unwrap_or(DEFAULT_RECORD_TTL),
+                    );
+                    let record = if options.ttl.is_some() || options.republish {
+                        published_record.copy(Instant::now())
+                    } else {
+                        published_record.record.clone()
+                    };
                     swarm.behaviour_mut().put_record(
                         record,
                         quorum,
@@ -717,13 +1813,49 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                         &mut queries,
                         tx,
                     );
+                    if options.republish && bootstrap_complete {
+                        schedule_republish(&published, &executor, &cmd_tx, published_record);
+                    } else {
+                        published.write().remove(&published_record.record.key);
+                    }
+                }
+                NetworkCommand::RepublishRecord(key, republish_at) => {
+                    let now = Instant::now();
+                    let record = published.read().get(&key).cloned();
+                    match record {
+                        // the record may have been put again in the meantime
+                        Some(record) if record.republish_at == republish_at => {
+                            // the ttl is sent in whole seconds and a ttl of zero means the
+                            // record never expires, so it isn't republished in its last second
+                            if record.record.is_expired(now + Duration::from_secs(1)) {
+                                tracing::debug!("record {:?} expired", key);
+                                published.write().remove(&key);
+                            } else {
+                                swarm
+                                    .behaviour_mut()
+                                    .republish_record(record.copy(now), record.quorum);
+                                schedule_republish(&published, &executor, &cmd_tx, record);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                NetworkCommand::RebootstrapDone(result) => {
+                    if let Some(rebootstrap) = rebootstrap.as_mut() {
+                        rebootstrap.done(result, &mut swarm);
+                    }
                 }
                 NetworkCommand::RemoveRecord(key) => {
+                    published.write().remove(&key);
                     swarm.behaviour_mut().remove_record(&key);
                 }
-                NetworkCommand::Subscribe(topic, tx) => {
-                    tx.send(swarm.behaviour_mut().subscribe(&topic, &mut subscriptions))
-                        .ok();
+                NetworkCommand::Subscribe(topic, validator, tx) => {
+                    let res = swarm.behaviour_mut().subscribe(&topic, &mut subscriptions);
+                    if let (Ok(_), Some(validator)) = (&res, validator) {
+                        validators.insert(topic, validator);
+                    }
+                    update_subscribed(&subscribed, &subscriptions);
+                    tx.send(res).ok();
                 }
                 NetworkCommand::Publish(topic, msg, tx) => {
                     tx.send(swarm.behaviour_mut().publish(&topic, msg)).ok();
@@ -731,11 +1863,19 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                 NetworkCommand::Broadcast(topic, msg, tx) => {
                     tx.send(swarm.behaviour_mut().broadcast(&topic, msg)).ok();
                 }
-                NetworkCommand::Get(cid, providers, tx) => {
-                    let (rx, id) =
-                        swarm
-                            .behaviour_mut()
-                            .get(cid, providers.into_iter(), &mut queries);
+                NetworkCommand::Request(peer, protocol, data, tx) => {
+                    swarm
+                        .behaviour_mut()
+                        .request(peer, protocol, data, tx, &mut requests);
+                }
+                NetworkCommand::Requests(protocol, tx) => {
+                    requests.register(protocol, tx);
+                }
+                NetworkCommand::Get(cid, providers, tx, span) => {
+                    let (rx, id) = swarm.behaviour_mut().get(cid, providers, &mut queries);
+                    if let Some(event) = spans.start(id, &span) {
+                        swarm.behaviour_mut().notify(event);
+                    }
                     tx.send(GetQuery {
                         swarm: cmd_tx.clone(),
                         id,
@@ -743,17 +1883,23 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                     })
                     .ok();
                 }
-                NetworkCommand::Sync(cid, providers, missing, tx) => {
+                NetworkCommand::Sync(cid, providers, missing, tx, span) => {
                     let (rx, id) = swarm.behaviour_mut().sync(
                         cid,
                         providers,
-                        missing.into_iter(),
+                        missing.iter().copied(),
                         &mut queries,
                     );
+                    bitswap_counters.start_sync(id, &missing);
+                    if let Some(event) = spans.start(id, &span) {
+                        swarm.behaviour_mut().notify(event);
+                    }
                     tx.send(SyncQuery {
                         swarm: Some(cmd_tx.clone()),
                         id: Some(id),
                         rx,
+                        stop: None,
+                        pin: None,
                     })
                     .ok();
                 }
@@ -762,14 +1908,252 @@ async fn poll_swarm<P: libipld::store::StoreParams>(
                     swarm.behaviour_mut().swarm_events(tx);
                     result.send(SwarmEvents::new(rx)).ok();
                 }
+                NetworkCommand::SwarmEventsFiltered(filter, result) => {
+                    let events = swarm.behaviour_mut().swarm_events_filtered(filter);
+                    result.send(events).ok();
+                }
+                #[cfg(test)]
+                NetworkCommand::Suspend(duration) => {
+                    Delay::new(duration).await;
+                }
                 NetworkCommand::CancelQuery(id) => {
                     swarm.behaviour_mut().cancel(id, &mut queries);
                 }
+                NetworkCommand::Shutdown(tx) => {
+                    tracing::debug!("poll_swarm: shutting down");
+                    // fail all commands sent from now on
+                    cmd_rx.close();
+                    let listeners = listener_ids.write().drain().collect::<Vec<_>>();
+                    for id in listeners {
+                        swarm.remove_listener(id);
+                    }
+                    let peers = swarm.connected_peers().copied().collect::<Vec<_>>();
+                    for peer in peers {
+                        swarm.disconnect_peer_id(peer).ok();
+                    }
+                    // dropping the swarm closes the listening sockets and the connections
+                    drop(swarm);
+                    *connection_counts.write() = ConnectionCounts::default();
+                    tx.send(()).ok();
+                    return;
+                }
             },
         }
+        for event in spans.finish(|id| queries.contains_key(id)) {
+            swarm.behaviour_mut().notify(event);
+        }
+        // topics are unsubscribed when their last subscriber is dropped
+        update_subscribed(&subscribed, &subscriptions);
+        bitswap_counters.retain_syncs(|id| queries.contains_key(id));
+        bitswap_counters.set_running_queries(
+            queries
+                .values()
+                .filter(|q| matches!(q, QueryChannel::Get { .. } | QueryChannel::Sync { .. }))
+                .count(),
+        );
+        if validators.len() > subscriptions.len() {
+            validators.retain(|topic, _| subscriptions.contains_key(topic));
+        }
+    }
+}
+
+fn update_subscribed(
+    subscribed: &Variable<FnvHashSet<String>>,
+    subscriptions: &FnvHashMap<String, Vec<mpsc::UnboundedSender<GossipEvent>>>,
+) {
+    let changed = {
+        let subscribed = subscribed.read();
+        subscribed.len() != subscriptions.len()
+            || subscriptions
+                .keys()
+                .any(|topic| !subscribed.contains(topic))
+    };
+    if changed {
+        *subscribed.write() = subscriptions.keys().cloned().collect();
+    }
+}
+
+/// Bans `peer`, scheduling the removal of the ban if it expires.
+fn ban_peer<P: StoreParams>(
+    swarm: &mut Swarm<NetworkBackendBehaviour<P>>,
+    bans: &Variable<FnvHashMap<PeerId, Option<DateTime<Utc>>>>,
+    executor: &Executor,
+    cmd_tx: &Sender<NetworkCommand>,
+    peer: PeerId,
+    expires: Option<DateTime<Utc>>,
+) {
+    swarm.ban_peer_id(peer);
+    bans.write().insert(peer, expires);
+    if let Some(expires) = expires {
+        let delay = (expires - Utc::now()).to_std().unwrap_or_default();
+        let mut cmd_tx = cmd_tx.clone();
+        executor
+            .spawn(async move {
+                Delay::new(delay).await;
+                cmd_tx
+                    .send(NetworkCommand::BanExpired(peer, expires))
+                    .await
+                    .ok();
+            })
+            .detach();
+    }
+}
+
+/// Tracks a record published with `PutOptions::republish` and schedules its
+/// next publication.
+fn schedule_republish(
+    published: &Variable<FnvHashMap<Key, PublishedRecord>>,
+    executor: &Executor,
+    cmd_tx: &Sender<NetworkCommand>,
+    mut record: PublishedRecord,
+) {
+    let delay = record.schedule(Instant::now());
+    let key = record.record.key.clone();
+    let republish_at = record.republish_at;
+    published.write().insert(key.clone(), record);
+    let mut cmd_tx = cmd_tx.clone();
+    executor
+        .spawn(async move {
+            Delay::new(delay).await;
+            cmd_tx
+                .send(NetworkCommand::RepublishRecord(key, republish_at))
+                .await
+                .ok();
+        })
+        .detach();
+}
+
+/// Bootstraps again from `NetworkConfig::bootstrap` when the routing table
+/// runs low, and refreshes the routing table periodically.
+struct Rebootstrap {
+    config: RebootstrapConfig,
+    nodes: Vec<(PeerId, Multiaddr)>,
+    timer: Delay,
+    backoff: Duration,
+    next_attempt: Instant,
+    in_progress: bool,
+    refreshed: Instant,
+}
+
+impl Rebootstrap {
+    fn new(config: RebootstrapConfig, nodes: Vec<(PeerId, Multiaddr)>) -> Self {
+        Self {
+            timer: Delay::new(config.check_interval),
+            backoff: config.initial_backoff,
+            next_attempt: Instant::now(),
+            in_progress: false,
+            refreshed: Instant::now(),
+            config,
+            nodes,
+        }
+    }
+
+    /// Checks the routing table whenever the check interval elapsed. Called
+    /// before polling the swarm, so that it picks up the started queries.
+    fn poll<P: StoreParams>(
+        &mut self,
+        cx: &mut Context,
+        swarm: &mut Swarm<NetworkBackendBehaviour<P>>,
+        bootstrapped: bool,
+        queries: &mut FnvHashMap<QueryId, QueryChannel>,
+        executor: &Executor,
+        cmd_tx: &Sender<NetworkCommand>,
+    ) {
+        if self.timer.poll_unpin(cx).is_pending() {
+            return;
+        }
+        self.timer.reset(self.config.check_interval);
+        // registers the waker with the new deadline
+        let _ = self.timer.poll_unpin(cx);
+        if self.in_progress {
+            return;
+        }
+        let now = Instant::now();
+        let connected = swarm.network_info().num_peers();
+        let swarm = swarm.behaviour_mut();
+        let size = swarm.routing_table_size();
+        if !self.nodes.is_empty() && (size < self.config.min_peers || connected == 0) {
+            if now < self.next_attempt {
+                return;
+            }
+            tracing::debug!(
+                "bootstrapping again with {} peers in the routing table",
+                size
+            );
+            self.in_progress = true;
+            swarm.notify(Event::RebootstrapStarted);
+            for (peer, addr) in &self.nodes {
+                swarm.add_address(peer, addr.clone(), AddressSource::User);
+                swarm.dial(peer);
+            }
+            let (tx, rx) = oneshot::channel();
+            swarm.bootstrap(queries, tx);
+            let mut cmd_tx = cmd_tx.clone();
+            executor
+                .spawn(async move {
+                    let result = match rx.await {
+                        Ok(Ok(())) => Ok(()),
+                        Ok(Err(err)) => Err(err.to_string()),
+                        Err(err) => Err(err.to_string()),
+                    };
+                    cmd_tx
+                        .send(NetworkCommand::RebootstrapDone(result))
+                        .await
+                        .ok();
+                })
+                .detach();
+        } else if let Some(interval) = self.config.refresh_interval {
+            if bootstrapped && now.duration_since(self.refreshed) >= interval {
+                self.refreshed = now;
+                swarm.refresh_routing_table();
+            }
+        }
+    }
+
+    fn done<P: StoreParams>(
+        &mut self,
+        result: std::result::Result<(), String>,
+        swarm: &mut Swarm<NetworkBackendBehaviour<P>>,
+    ) {
+        self.in_progress = false;
+        let connected = swarm.network_info().num_peers();
+        let swarm = swarm.behaviour_mut();
+        let size = swarm.routing_table_size();
+        // the bootstrap query also succeeds if none of the nodes responded
+        let result = result.and_then(|()| {
+            if connected == 0 || size < self.config.min_peers {
+                Err(format!(
+                    "{} peers in the routing table, {} connected",
+                    size, connected
+                ))
+            } else {
+                Ok(())
+            }
+        });
+        match result {
+            Ok(()) => {
+                self.backoff = self.config.initial_backoff;
+                self.refreshed = Instant::now();
+                swarm.notify(Event::RebootstrapSucceeded);
+            }
+            Err(err) => {
+                tracing::debug!("bootstrapping again failed: {}", err);
+                self.next_attempt = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(self.config.max_backoff);
+                swarm.notify(Event::RebootstrapFailed(err));
+            }
+        }
     }
 }
 
+/// Listener on a relay configured in `NetworkConfig::relays`.
+struct RelayListener {
+    listener: ListenerId,
+    /// The relayed address, advertised while the reservation is active.
+    addr: Multiaddr,
+    active: bool,
+}
+
 fn forward_listener_events(
     listener: ListenerId,
     response: UnboundedSender<ListenerEvent>,
@@ -813,6 +2197,8 @@ impl Future for GetQuery {
 
 impl Drop for GetQuery {
     fn drop(&mut self) {
+        // the shared query is only canceled if no other caller waits for it
+        self.rx.close();
         if let Err(err) = self.swarm.try_send(NetworkCommand::CancelQuery(self.id)) {
             if !err.is_disconnected() {
                 tracing::warn!("cannot cancel dropped GetQuery: {}", err.into_send_error());
@@ -827,9 +2213,30 @@ pub struct SyncQuery {
     swarm: Option<Sender<NetworkCommand>>,
     id: Option<QueryId>,
     rx: SyncChannel,
+    stop: Option<oneshot::Sender<()>>,
+    pin: Option<TempPin>,
 }
 
 impl SyncQuery {
+    /// Aborts the sync query. Outstanding wants are removed from the bitswap
+    /// behaviour and no more blocks are requested for this query. Dropping a
+    /// `SyncQuery` has the same effect.
+    pub fn abort(mut self) {
+        self.cancel();
+    }
+
+    fn cancel(&mut self) {
+        self.stop.take();
+        if let (Some(id), Some(mut swarm)) = (self.id.take(), self.swarm.take()) {
+            self.rx.close();
+            if let Err(err) = swarm.try_send(NetworkCommand::CancelQuery(id)) {
+                if !err.is_disconnected() {
+                    tracing::warn!("cannot cancel dropped SyncQuery: {}", err.into_send_error());
+                }
+            }
+        }
+    }
+
     fn ready(res: Result<()>) -> Self {
         let (tx, rx) = mpsc::unbounded();
         tx.unbounded_send(SyncEvent::Complete(res)).unwrap();
@@ -837,8 +2244,28 @@ impl SyncQuery {
             swarm: None,
             id: None,
             rx,
+            stop: None,
+            pin: None,
         }
     }
+
+    /// Creates a query receiving its events from a task driving the sync,
+    /// which is stopped through `stop` when the query is dropped.
+    pub(crate) fn from_task(rx: SyncChannel, stop: oneshot::Sender<()>) -> Self {
+        Self {
+            swarm: None,
+            id: None,
+            rx,
+            stop: Some(stop),
+            pin: None,
+        }
+    }
+
+    /// Keeps `pin` until the query is dropped.
+    pub(crate) fn with_temp_pin(mut self, pin: TempPin) -> Self {
+        self.pin = Some(pin);
+        self
+    }
 }
 
 impl Future for SyncQuery {
@@ -869,12 +2296,6 @@ impl Stream for SyncQuery {
 
 impl Drop for SyncQuery {
     fn drop(&mut self) {
-        if let (Some(id), Some(mut swarm)) = (self.id.take(), self.swarm.take()) {
-            if let Err(err) = swarm.try_send(NetworkCommand::CancelQuery(id)) {
-                if !err.is_disconnected() {
-                    tracing::warn!("cannot cancel dropped SyncQuery: {}", err.into_send_error());
-                }
-            }
-        }
+        self.cancel();
     }
 }
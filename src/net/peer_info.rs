@@ -1,3 +1,4 @@
+use super::psk::is_psk_mismatch;
 use chrono::{DateTime, Utc};
 use fnv::FnvHashMap;
 use libp2p::{
@@ -14,11 +15,30 @@ pub struct PeerInfo {
     pub(crate) protocols: Vec<String>,
     pub(crate) listeners: Vec<Multiaddr>,
     pub(crate) addresses: FnvHashMap<Multiaddr, (AddressSource, DateTime<Utc>)>,
-    pub(crate) connections: FnvHashMap<Multiaddr, (DateTime<Utc>, Direction)>,
+    pub(crate) connections: FnvHashMap<Multiaddr, (DateTime<Utc>, ConnectedPoint)>,
     failures: VecDeque<ConnectionFailure>,
     rtt: Option<Rtt>,
 }
 
+/// An open connection to a peer, see `Ipfs::connection_info`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    /// The local and remote address of the connection, the remote address
+    /// shows the transport, e.g. `/ws` or `/p2p-circuit`.
+    pub endpoint: ConnectedPoint,
+    /// When the connection was established.
+    pub established: DateTime<Utc>,
+    /// The agent version reported by identify, updated on identify pushes.
+    pub agent_version: Option<String>,
+    /// The protocol version reported by identify.
+    pub protocol_version: Option<String>,
+    /// The protocols supported by the peer as reported by identify.
+    pub protocols: Vec<String>,
+    /// The latest ping rtt. Ping results are reported per peer, so all
+    /// connections to a peer share it.
+    pub rtt: Option<Duration>,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Direction {
     Inbound,
@@ -60,7 +80,37 @@ impl PeerInfo {
     }
 
     pub fn connections(&self) -> impl Iterator<Item = (&Multiaddr, DateTime<Utc>, Direction)> {
-        self.connections.iter().map(|(a, (dt, dir))| (a, *dt, *dir))
+        self.connections
+            .iter()
+            .map(|(a, (dt, cp))| (a, *dt, Direction::from(cp)))
+    }
+
+    /// Returns the open connections together with the identify info and
+    /// ping rtt of the peer.
+    pub fn connection_info(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .values()
+            .map(|(established, endpoint)| ConnectionInfo {
+                endpoint: endpoint.clone(),
+                established: *established,
+                agent_version: self.agent_version.clone(),
+                protocol_version: self.protocol_version.clone(),
+                protocols: self.protocols.clone(),
+                rtt: self.rtt(),
+            })
+            .collect()
+    }
+
+    /// Returns the connections going through a circuit relay.
+    pub fn relayed_connections(&self) -> impl Iterator<Item = &Multiaddr> {
+        self.connections
+            .keys()
+            .filter(|addr| addr.iter().any(|p| p == Protocol::P2pCircuit))
+    }
+
+    /// Returns `true` if the peer is connected only via circuit relays.
+    pub fn is_relayed(&self) -> bool {
+        !self.connections.is_empty() && self.relayed_connections().count() == self.connections.len()
     }
 
     pub fn rtt(&self) -> Option<Duration> {
@@ -148,6 +198,9 @@ impl PeerInfo {
     }
 }
 
+/// Number of recent samples `Rtt::min` and `Rtt::avg` are computed from.
+const RTT_WINDOW: usize = 8;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct Rtt {
     current: Duration,
@@ -155,6 +208,8 @@ pub struct Rtt {
     decay_10: Duration,
     failures: u32,
     failure_rate: u32,
+    window: [Duration; RTT_WINDOW],
+    samples: usize,
 }
 
 impl Rtt {
@@ -165,11 +220,15 @@ impl Rtt {
             decay_10: current,
             failures: 0,
             failure_rate: 0,
+            window: [current; RTT_WINDOW],
+            samples: 1,
         }
     }
 
     pub fn register(&mut self, current: Duration) {
         self.current = current;
+        self.window[self.samples % RTT_WINDOW] = current;
+        self.samples += 1;
         self.decay_3 = self.decay_3 * 7 / 10 + current * 3 / 10;
         self.decay_10 = self.decay_10 * 9 / 10 + current / 10;
         self.failures = 0;
@@ -201,7 +260,24 @@ impl Rtt {
         self.decay_10
     }
 
-    /// Get the rtt's failure counter.
+    /// Get the minimum of the recent rtt values.
+    pub fn min(&self) -> Duration {
+        self.recent().iter().min().copied().unwrap_or(self.current)
+    }
+
+    /// Get the average of the recent rtt values.
+    pub fn avg(&self) -> Duration {
+        let recent = self.recent();
+        recent.iter().sum::<Duration>() / recent.len() as u32
+    }
+
+    /// Get the recent rtt values, at most the last eight in no particular
+    /// order.
+    pub fn recent(&self) -> &[Duration] {
+        &self.window[..self.samples.min(RTT_WINDOW)]
+    }
+
+    /// Get the rtt's failure counter, the number of consecutive failures.
     pub fn failures(&self) -> u32 {
         self.failures
     }
@@ -246,6 +322,26 @@ impl AddressSource {
     }
 }
 
+#[test]
+fn rtt_window() {
+    let ms = Duration::from_millis;
+    let mut rtt = Rtt::new(ms(10));
+    rtt.register(ms(30));
+    assert_eq!(rtt.min(), ms(10));
+    assert_eq!(rtt.avg(), ms(20));
+    assert_eq!(rtt.current(), ms(30));
+    rtt.register_failure();
+    rtt.register_failure();
+    assert_eq!(rtt.failures(), 2);
+    for _ in 0..RTT_WINDOW {
+        rtt.register(ms(50));
+    }
+    assert_eq!(rtt.failures(), 0);
+    assert_eq!(rtt.recent().len(), RTT_WINDOW);
+    assert_eq!(rtt.min(), ms(50));
+    assert_eq!(rtt.avg(), ms(50));
+}
+
 #[test]
 fn address_source_order() {
     use AddressSource::*;
@@ -270,6 +366,19 @@ pub enum ConnectionFailureKind {
     DialError,
     PeerDisconnected,
     WeDisconnected,
+    /// The peer isn't part of the private network of `NetworkConfig::psk`.
+    PskMismatch,
+}
+
+/// Returns the kind of a failed dial.
+fn dial_kind<'a>(
+    mut errors: impl Iterator<Item = &'a TransportError<io::Error>>,
+) -> ConnectionFailureKind {
+    if errors.any(|error| matches!(error, TransportError::Other(err) if is_psk_mismatch(err))) {
+        ConnectionFailureKind::PskMismatch
+    } else {
+        ConnectionFailureKind::DialError
+    }
 }
 
 fn without_peer(a: &Multiaddr) -> Cow<'_, Multiaddr> {
@@ -284,6 +393,10 @@ fn without_peer(a: &Multiaddr) -> Cow<'_, Multiaddr> {
 
 impl ConnectionFailure {
     pub(crate) fn dial(addr: Multiaddr, error: &DialError) -> Self {
+        let kind = match error {
+            DialError::Transport(e) => dial_kind(e.iter().map(|(_, err)| err)),
+            _ => ConnectionFailureKind::DialError,
+        };
         let display = match error {
             DialError::ConnectionIo(e) => format!("I/O error: {}", e),
             DialError::Transport(e) => {
@@ -302,7 +415,7 @@ impl ConnectionFailure {
             x => x.to_string(),
         };
         Self {
-            kind: ConnectionFailureKind::DialError,
+            kind,
             addr: without_peer(&addr).into_owned(),
             time: Utc::now(),
             display,
@@ -312,7 +425,7 @@ impl ConnectionFailure {
 
     pub(crate) fn transport(addr: Multiaddr, error: &TransportError<std::io::Error>) -> Self {
         Self {
-            kind: ConnectionFailureKind::DialError,
+            kind: dial_kind(std::iter::once(error)),
             addr: without_peer(&addr).into_owned(),
             time: Utc::now(),
             display: format!("transport error: {}", D(error)),
@@ -1,39 +1,46 @@
 use super::{
     address_handler::IntoAddressHandler,
-    behaviour::MyHandlerError,
-    peer_info::{AddressSource, Direction, PeerInfo},
+    behaviour::{MyHandlerError, QueryId},
+    gate::ConnectionGated,
+    peer_info::{AddressSource, Direction, PeerInfo, Rtt},
 };
-use crate::{net::peer_info::ConnectionFailure, variable::Writer};
+use crate::{net::peer_info::ConnectionFailure, variable::Variable};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use fnv::{FnvHashMap, FnvHashSet};
 use futures::{
-    channel::mpsc::{self, UnboundedSender},
+    channel::{
+        mpsc::{self, UnboundedSender},
+        oneshot,
+    },
     future::BoxFuture,
     stream::{FuturesUnordered, Stream},
     FutureExt, StreamExt,
 };
 use futures_timer::Delay;
 use lazy_static::lazy_static;
+use libipld::Cid;
 use libp2p::{
+    autonat::NatStatus,
     core::{
         connection::{ConnectedPoint, ConnectionId},
         either::EitherError,
         transport::{timeout::TransportTimeoutError, ListenerId},
         UpgradeError,
     },
-    dns::DnsErr,
     identify,
     multiaddr::Protocol,
     noise::NoiseError,
     swarm::{
         dial_opts::{DialOpts, PeerCondition},
-        AddressRecord, ConnectionError, DialError, NetworkBehaviour, NetworkBehaviourAction,
-        PollParameters,
+        AddressRecord, CloseConnection, ConnectionError, DialError, IntoConnectionHandler,
+        NetworkBehaviour, NetworkBehaviourAction, PollParameters,
     },
     Multiaddr, PeerId, TransportError,
 };
-use prometheus::{IntCounter, IntGauge, Registry};
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
 use std::{
     borrow::Cow,
     collections::VecDeque,
@@ -41,6 +48,10 @@ use std::{
     io::ErrorKind,
     net::IpAddr,
     pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
     task::{Context, Poll},
     time::Duration,
 };
@@ -65,12 +76,19 @@ pub enum Event {
     /// an address was added for the given peer, following a successful dailling
     /// attempt
     Discovered(PeerId),
+    /// mdns discovered the given peer at this address on the local network
+    MdnsDiscovered(PeerId, Multiaddr),
+    /// the mdns record of the given peer expired
+    MdnsExpired(PeerId),
     /// a dialling attempt for the given peer has failed
     DialFailure(PeerId, Multiaddr, String),
     /// a peer could not be reached by any known address
     Unreachable(PeerId),
     /// a new connection has been opened to the given peer
     ConnectionEstablished(PeerId, ConnectedPoint),
+    /// the given peer failed to answer `NetworkConfig::ping_max_failures`
+    /// pings in a row, its connections are closed
+    PeerUnresponsive(PeerId),
     /// a connection to the given peer has been closed
     // FIXME add termination reason
     ConnectionClosed(PeerId, ConnectedPoint),
@@ -84,9 +102,63 @@ pub enum Event {
     Subscribed(PeerId, String),
     /// the given peer unsubscribed from the given gossipsub or broadcast topic
     Unsubscribed(PeerId, String),
+    /// the gossipsub score of the given peer became negative, it is pruned
+    /// from the mesh of all topics
+    GossipPruned(PeerId),
+    /// the gossipsub score of the given peer fell below the graylist
+    /// threshold, its messages are ignored
+    GossipGraylisted(PeerId),
     Bootstrapped,
+    /// the routing table ran low, bootstrapping again from
+    /// `NetworkConfig::bootstrap`
+    RebootstrapStarted,
+    /// bootstrapping again succeeded
+    RebootstrapSucceeded,
+    /// bootstrapping again failed for the given reason, it is retried with
+    /// exponential backoff
+    RebootstrapFailed(String),
+    /// the reservation on the given relay changed its status
+    RelayReservation(PeerId, ReservationStatus),
+    /// AutoNAT changed its assessment of the reachability of this node
+    NatStatusChanged(NatStatus),
+    /// a connection in the given direction was rejected because the given
+    /// limit of `NetworkConfig::connection_limits` was reached; the peer is
+    /// known for outgoing connections, the address for incoming ones
+    ConnectionLimitReached(Direction, Option<PeerId>, Option<Multiaddr>, u32),
+    /// a connection in the given direction was rejected by the
+    /// `ConnectionGate` for the given reason; the peer is known if it was
+    /// rejected after the handshake
+    ConnectionGated(Direction, Option<PeerId>, Multiaddr, String),
+    /// a relayed connection to the given peer was upgraded to a direct one
+    DirectConnectionUpgraded(PeerId),
+    /// hole punching to the given peer failed, the connection stays relayed
+    DirectConnectionUpgradeFailed(PeerId, String),
     /// the peer-info for the given peer has been updated with new information
     NewInfo(PeerId),
+    /// a query started by `providers`, `get_record`, `fetch` or `sync` was
+    /// started, its tracing span records the query id
+    QueryStarted(QueryId),
+    /// the given query completed or was cancelled
+    QueryFinished(QueryId),
+    /// a block with the given cid and size was received over bitswap and
+    /// written to the store
+    BlockReceived(Cid, usize),
+    /// a block with the given cid and size was read from the store to be
+    /// sent to a peer over bitswap
+    BlockSent(Cid, usize),
+}
+
+/// Status of a reservation on a relay configured in `NetworkConfig::relays`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ReservationStatus {
+    /// the reservation was accepted or renewed, the relayed address is
+    /// advertised as an external address
+    Active,
+    /// the reservation was lost, e.g. because the connection to the relay
+    /// was closed
+    Expired,
+    /// the relay refused the reservation or could not be reached
+    Failed(String),
 }
 
 lazy_static! {
@@ -114,6 +186,119 @@ lazy_static! {
     .unwrap();
     pub static ref DIAL_FAILURE: IntCounter =
         IntCounter::new("peers_dial_failure", "Number of dial failures.").unwrap();
+    pub static ref RELAY_RESERVATIONS: IntGauge = IntGauge::new(
+        "relay_reservations",
+        "Number of active reservations on the relay server."
+    )
+    .unwrap();
+    pub static ref RELAY_CIRCUITS: IntGauge = IntGauge::new(
+        "relay_circuits",
+        "Number of active circuits on the relay server."
+    )
+    .unwrap();
+    pub static ref RELAY_CIRCUITS_TOTAL: IntCounter = IntCounter::new(
+        "relay_circuits_total",
+        "Number of circuits accepted by the relay server."
+    )
+    .unwrap();
+    pub static ref GOSSIP_PEER_SCORES: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "gossipsub_peer_scores",
+            "Gossipsub scores of known peers, sampled once a second."
+        )
+        .buckets(vec![-1000.0, -100.0, -10.0, -1.0, 0.0, 1.0, 10.0, 100.0])
+    )
+    .unwrap();
+    pub static ref GOSSIP_MESH_PEERS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "gossipsub_mesh_peers",
+            "Number of peers in the mesh of a subscribed topic, sampled once a second."
+        ),
+        &["topic"]
+    )
+    .unwrap();
+    pub static ref GOSSIP_MESSAGES_RECEIVED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "gossipsub_messages_received_total",
+            "Number of gossipsub messages received, including rejected ones."
+        ),
+        &["topic"]
+    )
+    .unwrap();
+    pub static ref GOSSIP_MESSAGES_REJECTED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "gossipsub_messages_rejected_total",
+            "Number of gossipsub messages rejected or ignored by the validator."
+        ),
+        &["topic"]
+    )
+    .unwrap();
+    pub static ref GOSSIP_MESSAGES_PUBLISHED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "gossipsub_messages_published_total",
+            "Number of gossipsub messages published by this node."
+        ),
+        &["topic"]
+    )
+    .unwrap();
+    pub static ref BITSWAP_RUNNING_QUERIES: IntGauge = IntGauge::new(
+        "bitswap_running_queries",
+        "Number of running bitswap fetch and sync queries."
+    )
+    .unwrap();
+    pub static ref BITSWAP_OUTSTANDING_WANTS: IntGauge = IntGauge::new(
+        "bitswap_outstanding_wants",
+        "Number of block requests of syncs outstanding in the sync scheduler."
+    )
+    .unwrap();
+    pub static ref BITSWAP_BLOCKS_RECEIVED: IntCounter = IntCounter::new(
+        "bitswap_store_blocks_received",
+        "Number of blocks received from peers."
+    )
+    .unwrap();
+    pub static ref BITSWAP_BYTES_RECEIVED: IntCounter = IntCounter::new(
+        "bitswap_store_bytes_received",
+        "Number of bytes of blocks received from peers."
+    )
+    .unwrap();
+    pub static ref BITSWAP_BLOCKS_SENT: IntCounter = IntCounter::new(
+        "bitswap_store_blocks_sent",
+        "Number of blocks sent to peers."
+    )
+    .unwrap();
+    pub static ref BITSWAP_BYTES_SENT: IntCounter = IntCounter::new(
+        "bitswap_store_bytes_sent",
+        "Number of bytes of blocks sent to peers."
+    )
+    .unwrap();
+    pub static ref BITSWAP_REQUESTS_SERVED: IntCounter = IntCounter::new(
+        "bitswap_store_requests_served",
+        "Number of bitswap requests of peers answered."
+    )
+    .unwrap();
+    pub static ref BITSWAP_SERVE_DENIED: IntCounter = IntCounter::new(
+        "bitswap_store_serve_denied",
+        "Number of bitswap requests of peers denied by the serve policy."
+    )
+    .unwrap();
+    pub static ref BITSWAP_SERVE_THROTTLED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "bitswap_store_serve_throttled",
+            "Number of requests and writes delayed or refused by the serve rate limit."
+        ),
+        &["budget"]
+    )
+    .unwrap();
+    pub static ref NETWORK_BYTES_RECEIVED: IntCounter = IntCounter::new(
+        "network_bytes_received",
+        "Number of bytes received over the substreams of all connections."
+    )
+    .unwrap();
+    pub static ref NETWORK_BYTES_SENT: IntCounter = IntCounter::new(
+        "network_bytes_sent",
+        "Number of bytes sent over the substreams of all connections."
+    )
+    .unwrap();
 }
 
 const SIM_OPEN_RETRIES: u8 = 10;
@@ -196,11 +381,18 @@ pub struct AddressBook {
     enable_loopback: bool,
     keep_alive: bool,
     local_peer_id: PeerId,
-    listeners: Writer<FnvHashSet<Multiaddr>>,
-    peers: Writer<FnvHashMap<PeerId, PeerInfo>>,
-    external: Writer<Vec<AddressRecord>>,
+    listeners: Variable<FnvHashSet<Multiaddr>>,
+    peers: Variable<FnvHashMap<PeerId, PeerInfo>>,
+    external: Variable<Vec<AddressRecord>>,
     refresh_external: bool,
-    event_stream: Vec<mpsc::UnboundedSender<Event>>,
+    event_stream: Vec<Subscriber>,
+    /// Remote address of every established connection.
+    connection_ids: FnvHashMap<PeerId, FnvHashMap<ConnectionId, Multiaddr>>,
+    /// Connections being closed by `disconnect`, the sender is notified once
+    /// all of them are closed.
+    closing: Vec<(FnvHashSet<ConnectionId>, oneshot::Sender<()>)>,
+    /// Senders of `closing` to notify once the `PeerInfo` is updated.
+    closed: Vec<oneshot::Sender<()>>,
     pub(crate) actions: VecDeque<NetworkBehaviourAction<void::Void, IntoAddressHandler>>,
     deferred: FuturesUnordered<
         BoxFuture<'static, NetworkBehaviourAction<void::Void, IntoAddressHandler>>,
@@ -213,9 +405,9 @@ impl AddressBook {
         port_reuse: bool,
         enable_loopback: bool,
         keep_alive: bool,
-        listeners: Writer<FnvHashSet<Multiaddr>>,
-        peers: Writer<FnvHashMap<PeerId, PeerInfo>>,
-        external: Writer<Vec<AddressRecord>>,
+        listeners: Variable<FnvHashSet<Multiaddr>>,
+        peers: Variable<FnvHashMap<PeerId, PeerInfo>>,
+        external: Variable<Vec<AddressRecord>>,
     ) -> Self {
         Self {
             port_reuse,
@@ -227,6 +419,9 @@ impl AddressBook {
             external,
             refresh_external: true,
             event_stream: Default::default(),
+            connection_ids: Default::default(),
+            closing: Default::default(),
+            closed: Default::default(),
             actions: Default::default(),
             deferred: Default::default(),
         }
@@ -249,6 +444,26 @@ impl AddressBook {
         });
     }
 
+    /// Dials the confirmed addresses of `peer` and probes the others.
+    /// Returns the number of dial attempts.
+    pub fn connect(&mut self, peer: &PeerId) -> usize {
+        let (confirmed, to_probe) = match self.peers.read().get(peer) {
+            Some(info) => (
+                info.confirmed_addresses().next().is_some(),
+                info.addresses_to_probe().cloned().collect::<Vec<_>>(),
+            ),
+            None => (false, vec![]),
+        };
+        if confirmed {
+            self.dial(peer);
+        }
+        let dials = usize::from(confirmed) + to_probe.len();
+        for addr in to_probe {
+            self.dial_address(peer, addr);
+        }
+        dials
+    }
+
     pub fn dial_address(&mut self, peer: &PeerId, addr: Multiaddr) {
         if peer == self.local_peer_id() {
             tracing::error!("attempting to dial self");
@@ -274,6 +489,33 @@ impl AddressBook {
         });
     }
 
+    /// Closes the connections to `peer`, or only those to `addr` if given.
+    /// `tx` is notified once they are closed.
+    pub fn disconnect(&mut self, peer: &PeerId, addr: Option<&Multiaddr>, tx: oneshot::Sender<()>) {
+        let addr = addr.map(|addr| normalize_addr_ref(addr, peer));
+        let ids = self
+            .connection_ids
+            .get(peer)
+            .into_iter()
+            .flatten()
+            .filter(|(_, a)| addr.as_deref().map(|addr| addr == *a).unwrap_or(true))
+            .map(|(id, _)| *id)
+            .collect::<FnvHashSet<_>>();
+        if ids.is_empty() {
+            tx.send(()).ok();
+            return;
+        }
+        tracing::debug!(peer = %peer, "closing {} connections", ids.len());
+        for id in &ids {
+            self.actions
+                .push_back(NetworkBehaviourAction::CloseConnection {
+                    peer_id: *peer,
+                    connection: CloseConnection::One(*id),
+                });
+        }
+        self.closing.push((ids, tx));
+    }
+
     pub fn add_address(&mut self, peer: &PeerId, mut address: Multiaddr, source: AddressSource) {
         if peer == self.local_peer_id() {
             return;
@@ -383,16 +625,27 @@ impl AddressBook {
 
         let debug = format!("{:?}", error);
         let (reason, peer_closed) = match error {
-            Some(ConnHandler(A(A(A(A(A(A(A(e))))))))) => void::unreachable(e),
-            Some(ConnHandler(A(A(A(A(A(A(B(e))))))))) => {
+            Some(ConnHandler(A(A(A(A(A(A(A(A(A(A(A(A(e)))))))))))))) => void::unreachable(e),
+            Some(ConnHandler(A(A(A(A(A(A(A(A(A(A(A(B(e)))))))))))))) => {
                 (format!("Kademlia I/O error: {}", e), false)
             }
-            Some(ConnHandler(A(A(A(A(A(B(e)))))))) => void::unreachable(e),
-            Some(ConnHandler(A(A(A(A(B(e))))))) => (format!("Ping failure: {}", e), false),
-            Some(ConnHandler(A(A(A(B(e)))))) => (format!("Identify I/O error: {}", e), false),
-            Some(ConnHandler(A(A(B(e))))) => (format!("Bitswap error: {}", e), false),
-            Some(ConnHandler(A(B(e)))) => (format!("Gossipsub error: {}", e), false),
-            Some(ConnHandler(B(e))) => (format!("Broadcast error: {}", e), false),
+            Some(ConnHandler(A(A(A(A(A(A(A(A(A(A(B(e))))))))))))) => void::unreachable(e),
+            Some(ConnHandler(A(A(A(A(A(A(A(A(A(B(e)))))))))))) => {
+                (format!("Ping failure: {}", e), false)
+            }
+            Some(ConnHandler(A(A(A(A(A(A(A(A(B(e))))))))))) => {
+                (format!("Identify I/O error: {}", e), false)
+            }
+            Some(ConnHandler(A(A(A(A(A(A(A(B(e)))))))))) => {
+                (format!("Bitswap error: {}", e), false)
+            }
+            Some(ConnHandler(A(A(A(A(A(A(B(e))))))))) => (format!("Gossipsub error: {}", e), false),
+            Some(ConnHandler(A(A(A(A(A(B(e)))))))) => (format!("Broadcast error: {}", e), false),
+            Some(ConnHandler(A(A(A(A(B(e))))))) => (format!("Relay client error: {:?}", e), false),
+            Some(ConnHandler(A(A(A(B(e)))))) => (format!("Relay server error: {:?}", e), false),
+            Some(ConnHandler(A(A(B(e))))) => (format!("AutoNAT error: {:?}", e), false),
+            Some(ConnHandler(A(B(e)))) => (format!("DCUtR error: {:?}", e), false),
+            Some(ConnHandler(B(e))) => (format!("Request response error: {}", e), false),
             Some(ConnectionError::IO(e)) => (format!("connection I/O error: {}", e), true),
             Some(ConnectionError::KeepAliveTimeout) => {
                 ("we closed due to missing keepalive".to_owned(), false)
@@ -425,6 +678,9 @@ impl AddressBook {
             self.notify(Event::Disconnected(peer));
         }
         self.notify(Event::NewInfo(peer));
+        for tx in self.closed.drain(..) {
+            tx.send(()).ok();
+        }
     }
 
     #[cfg(test)]
@@ -432,20 +688,50 @@ impl AddressBook {
         self.peers.read().keys().copied().collect()
     }
 
-    #[cfg(test)]
     pub fn info(&self, peer_id: &PeerId) -> Option<PeerInfo> {
         self.peers.read().get(peer_id).cloned()
     }
 
-    pub fn set_rtt(&mut self, peer_id: &PeerId, rtt: Option<Duration>) {
+    pub fn is_connected(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .read()
+            .get(peer_id)
+            .map_or(false, |info| !info.connections.is_empty())
+    }
+
+    /// Records a ping result and returns the number of consecutive failures.
+    pub fn set_rtt(&mut self, peer_id: &PeerId, rtt: Option<Duration>) -> u32 {
         let mut peers = self.peers.write();
         if let Some(info) = peers.get_mut(peer_id) {
             info.set_rtt(rtt);
+            let failures = info
+                .full_rtt()
+                .map(|rtt| rtt.failures())
+                .unwrap_or_default();
             drop(peers);
             self.notify(Event::NewInfo(*peer_id));
+            failures
+        } else {
+            0
         }
     }
 
+    pub fn rtt(&self, peer_id: &PeerId) -> Option<Rtt> {
+        self.peers
+            .read()
+            .get(peer_id)
+            .and_then(|info| info.full_rtt())
+    }
+
+    /// Closes all connections to `peer_id`.
+    pub fn close_connections(&mut self, peer_id: &PeerId) {
+        self.actions
+            .push_back(NetworkBehaviourAction::CloseConnection {
+                peer_id: *peer_id,
+                connection: CloseConnection::All,
+            });
+    }
+
     pub fn set_info(&mut self, peer_id: &PeerId, identify: identify::Info) {
         let _span = tracing::trace_span!("set_info", peer = %peer_id).entered();
         let mut peers = self.peers.write();
@@ -558,13 +844,27 @@ impl AddressBook {
     }
 
     pub fn swarm_events(&mut self, tx: UnboundedSender<Event>) {
-        self.event_stream.push(tx);
+        self.event_stream.push(Subscriber {
+            tx,
+            filter: EventFilter::all(),
+            state: None,
+        });
+    }
+
+    pub fn swarm_events_filtered(&mut self, filter: EventFilter) -> SwarmEvents {
+        let (tx, rx) = mpsc::unbounded();
+        let state = Arc::new(SubscriptionState::default());
+        self.event_stream.push(Subscriber {
+            tx,
+            filter,
+            state: Some(state.clone()),
+        });
+        SwarmEvents(rx, Some(state))
     }
 
     pub fn notify(&mut self, event: Event) {
         tracing::trace!("{:?}", event);
-        self.event_stream
-            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+        self.event_stream.retain(|sub| sub.send(&event));
     }
 }
 
@@ -578,6 +878,25 @@ pub fn register_metrics(registry: &Registry) -> Result<()> {
     registry.register(Box::new(LISTENER_ERROR.clone()))?;
     registry.register(Box::new(ADDRESS_REACH_FAILURE.clone()))?;
     registry.register(Box::new(DIAL_FAILURE.clone()))?;
+    registry.register(Box::new(RELAY_RESERVATIONS.clone()))?;
+    registry.register(Box::new(RELAY_CIRCUITS.clone()))?;
+    registry.register(Box::new(RELAY_CIRCUITS_TOTAL.clone()))?;
+    registry.register(Box::new(GOSSIP_PEER_SCORES.clone()))?;
+    registry.register(Box::new(GOSSIP_MESH_PEERS.clone()))?;
+    registry.register(Box::new(GOSSIP_MESSAGES_RECEIVED.clone()))?;
+    registry.register(Box::new(GOSSIP_MESSAGES_REJECTED.clone()))?;
+    registry.register(Box::new(GOSSIP_MESSAGES_PUBLISHED.clone()))?;
+    registry.register(Box::new(BITSWAP_RUNNING_QUERIES.clone()))?;
+    registry.register(Box::new(BITSWAP_OUTSTANDING_WANTS.clone()))?;
+    registry.register(Box::new(BITSWAP_BLOCKS_RECEIVED.clone()))?;
+    registry.register(Box::new(BITSWAP_BYTES_RECEIVED.clone()))?;
+    registry.register(Box::new(BITSWAP_BLOCKS_SENT.clone()))?;
+    registry.register(Box::new(BITSWAP_BYTES_SENT.clone()))?;
+    registry.register(Box::new(BITSWAP_REQUESTS_SERVED.clone()))?;
+    registry.register(Box::new(BITSWAP_SERVE_DENIED.clone()))?;
+    registry.register(Box::new(BITSWAP_SERVE_THROTTLED.clone()))?;
+    registry.register(Box::new(NETWORK_BYTES_RECEIVED.clone()))?;
+    registry.register(Box::new(NETWORK_BYTES_SENT.clone()))?;
     Ok(())
 }
 
@@ -602,12 +921,196 @@ fn diff_time(former: DateTime<Utc>, latter: DateTime<Utc>) -> Duration {
         .unwrap_or(Duration::ZERO)
 }
 
+/// Selects the categories of `Event`s delivered to a `SwarmEvents`
+/// subscription, see `Ipfs::swarm_events_filtered`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EventFilter {
+    connections: bool,
+    listeners: bool,
+    discovery: bool,
+    bitswap: bool,
+    gossip: bool,
+    dht: bool,
+    buffer: usize,
+}
+
+impl Default for EventFilter {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+impl EventFilter {
+    /// Selects no events.
+    pub fn none() -> Self {
+        Self {
+            connections: false,
+            listeners: false,
+            discovery: false,
+            bitswap: false,
+            gossip: false,
+            dht: false,
+            buffer: 1024,
+        }
+    }
+
+    /// Selects all events.
+    pub fn all() -> Self {
+        Self {
+            connections: true,
+            listeners: true,
+            discovery: true,
+            bitswap: true,
+            gossip: true,
+            dht: true,
+            ..Self::none()
+        }
+    }
+
+    /// Selects events about dialing, establishing and closing connections.
+    pub fn connections(mut self) -> Self {
+        self.connections = true;
+        self
+    }
+
+    /// Selects events about listeners, external addresses and reachability.
+    pub fn listeners(mut self) -> Self {
+        self.listeners = true;
+        self
+    }
+
+    /// Selects events about discovered peers and updates of their info.
+    pub fn discovery(mut self) -> Self {
+        self.discovery = true;
+        self
+    }
+
+    /// Selects events about bitswap queries and transferred blocks.
+    pub fn bitswap(mut self) -> Self {
+        self.bitswap = true;
+        self
+    }
+
+    /// Selects events about the gossipsub and broadcast subscriptions of
+    /// peers.
+    pub fn gossip(mut self) -> Self {
+        self.gossip = true;
+        self
+    }
+
+    /// Selects events of the dht.
+    pub fn dht(mut self) -> Self {
+        self.dht = true;
+        self
+    }
+
+    /// Sets the maximum number of events queued for the subscriber, further
+    /// events are dropped until it catches up. Defaults to 1024.
+    pub fn buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    /// Returns true if the event is selected.
+    pub fn matches(&self, event: &Event) -> bool {
+        match event {
+            Event::ConnectionEstablished(..)
+            | Event::ConnectionClosed(..)
+            | Event::AddressChanged(..)
+            | Event::Connected(_)
+            | Event::Disconnected(_)
+            | Event::DialFailure(..)
+            | Event::PeerUnresponsive(_)
+            | Event::ConnectionLimitReached(..)
+            | Event::ConnectionGated(..)
+            | Event::DirectConnectionUpgraded(_)
+            | Event::DirectConnectionUpgradeFailed(..) => self.connections,
+            Event::NewListener(_)
+            | Event::NewListenAddr(..)
+            | Event::ExpiredListenAddr(..)
+            | Event::ListenerError(..)
+            | Event::ListenerClosed(_)
+            | Event::NewExternalAddr(_)
+            | Event::ExpiredExternalAddr(_)
+            | Event::RelayReservation(..)
+            | Event::NatStatusChanged(_) => self.listeners,
+            Event::Discovered(_)
+            | Event::MdnsDiscovered(..)
+            | Event::MdnsExpired(_)
+            | Event::Unreachable(_)
+            | Event::NewInfo(_) => self.discovery,
+            Event::Subscribed(..)
+            | Event::Unsubscribed(..)
+            | Event::GossipPruned(_)
+            | Event::GossipGraylisted(_) => self.gossip,
+            Event::Bootstrapped
+            | Event::RebootstrapStarted
+            | Event::RebootstrapSucceeded
+            | Event::RebootstrapFailed(_) => self.dht,
+            Event::BlockReceived(..) | Event::BlockSent(..) => self.bitswap,
+            Event::QueryStarted(id) | Event::QueryFinished(id) => {
+                if id.is_bitswap() {
+                    self.bitswap
+                } else {
+                    self.dht
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SubscriptionState {
+    queued: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// A subscriber of `AddressBook::notify`. Subscriptions without state are
+/// unbounded.
 #[derive(Debug)]
-pub struct SwarmEvents(mpsc::UnboundedReceiver<Event>);
+struct Subscriber {
+    tx: UnboundedSender<Event>,
+    filter: EventFilter,
+    state: Option<Arc<SubscriptionState>>,
+}
+
+impl Subscriber {
+    /// Sends the event if it matches the filter, returns false once the
+    /// subscriber went away.
+    fn send(&self, event: &Event) -> bool {
+        if !self.filter.matches(event) {
+            return !self.tx.is_closed();
+        }
+        if let Some(state) = &self.state {
+            if state.queued.load(Ordering::Relaxed) >= self.filter.buffer {
+                state.dropped.fetch_add(1, Ordering::Relaxed);
+                return !self.tx.is_closed();
+            }
+            state.queued.fetch_add(1, Ordering::Relaxed);
+        }
+        self.tx.unbounded_send(event.clone()).is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub struct SwarmEvents(
+    mpsc::UnboundedReceiver<Event>,
+    Option<Arc<SubscriptionState>>,
+);
 
 impl SwarmEvents {
     pub fn new(channel: mpsc::UnboundedReceiver<Event>) -> Self {
-        Self(channel)
+        Self(channel, None)
+    }
+
+    /// Returns the number of events dropped because the subscriber didn't
+    /// keep up, see `EventFilter::buffer`. Unfiltered subscriptions never
+    /// drop events.
+    pub fn dropped_events(&self) -> u64 {
+        self.1
+            .as_ref()
+            .map(|state| state.dropped.load(Ordering::Relaxed))
+            .unwrap_or_default()
     }
 }
 
@@ -615,7 +1118,11 @@ impl Stream for SwarmEvents {
     type Item = Event;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.0).poll_next(cx)
+        let poll = Pin::new(&mut self.0).poll_next(cx);
+        if let (Poll::Ready(Some(_)), Some(state)) = (&poll, &self.1) {
+            state.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        poll
     }
 }
 
@@ -658,13 +1165,17 @@ impl NetworkBehaviour for AddressBook {
     fn inject_connection_established(
         &mut self,
         peer_id: &PeerId,
-        _: &ConnectionId,
+        id: &ConnectionId,
         conn: &ConnectedPoint,
         _failures: Option<&Vec<Multiaddr>>,
         other_established: usize,
     ) {
         let conn = normalize_connected_point(conn, &self.local_peer_id, peer_id);
         let address = conn.get_remote_address();
+        self.connection_ids
+            .entry(*peer_id)
+            .or_default()
+            .insert(*id, address.clone());
         tracing::debug!(
             addr = %address,
             out = conn.is_dialer(),
@@ -681,17 +1192,43 @@ impl NetworkBehaviour for AddressBook {
             .entry(*peer_id)
             .or_default()
             .connections
-            .insert(address.clone(), (Utc::now(), Direction::from(&conn)));
+            .insert(address.clone(), (Utc::now(), conn.clone()));
         if other_established == 0 {
             self.notify(Event::Connected(*peer_id));
         }
         self.notify(Event::ConnectionEstablished(*peer_id, conn));
     }
 
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        _: &ConnectedPoint,
+        _: <Self::ConnectionHandler as IntoConnectionHandler>::Handler,
+        remaining_established: usize,
+    ) {
+        if remaining_established == 0 {
+            self.connection_ids.remove(peer_id);
+        } else if let Some(ids) = self.connection_ids.get_mut(peer_id) {
+            ids.remove(id);
+        }
+        let mut i = 0;
+        while i < self.closing.len() {
+            self.closing[i].0.remove(id);
+            if self.closing[i].0.is_empty() {
+                // `connection_closed` is called next with the swarm event
+                let (_, tx) = self.closing.swap_remove(i);
+                self.closed.push(tx);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
     fn inject_address_change(
         &mut self,
         peer_id: &PeerId,
-        _: &ConnectionId,
+        id: &ConnectionId,
         old: &ConnectedPoint,
         new: &ConnectedPoint,
     ) {
@@ -699,6 +1236,9 @@ impl NetworkBehaviour for AddressBook {
         let new = normalize_connected_point(new, &self.local_peer_id, peer_id);
         let old_addr = old.get_remote_address();
         let new_addr = new.get_remote_address();
+        if let Some(ids) = self.connection_ids.get_mut(peer_id) {
+            ids.insert(*id, new_addr.clone());
+        }
         tracing::debug!(
             old = %old.get_remote_address(),
             new = %new_addr,
@@ -716,7 +1256,7 @@ impl NetworkBehaviour for AddressBook {
         entry.connections.remove(old_addr);
         entry
             .connections
-            .insert(new_addr.clone(), (Utc::now(), Direction::from(&new)));
+            .insert(new_addr.clone(), (Utc::now(), new.clone()));
         drop(peers);
 
         self.notify(Event::AddressChanged(*peer_id, old, new));
@@ -888,21 +1428,14 @@ fn is_sim_open(error: &TransportError<std::io::Error>) -> bool {
     match error {
         libp2p::TransportError::MultiaddrNotSupported(_x) => false,
         libp2p::TransportError::Other(err) => {
-            let err = err
-                .get_ref()
-                .and_then(|e| e.downcast_ref::<DnsErr<std::io::Error>>());
-            if let Some(DnsErr::Transport(err)) = err {
-                let err = err
-                    .get_ref()
-                    .and_then(|e| e.downcast_ref::<super::TransportError>());
-                if let Some(TransportTimeoutError::Other(EitherError::A(EitherError::B(
-                    UpgradeError::Apply(NoiseError::Io(err)),
-                )))) = err
-                {
-                    err.kind() == ErrorKind::InvalidData
-                } else {
-                    false
-                }
+            let err = err.get_ref().and_then(|e| {
+                e.downcast_ref::<EitherError<super::TransportError, ConnectionGated>>()
+            });
+            if let Some(EitherError::A(TransportTimeoutError::Other(EitherError::A(
+                EitherError::B(UpgradeError::Apply(NoiseError::Io(err))),
+            )))) = err
+            {
+                err.kind() == ErrorKind::InvalidData
             } else {
                 false
             }
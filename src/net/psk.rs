@@ -0,0 +1,157 @@
+//! Private networks using a pre shared key.
+//!
+//! The pnet handshake only exchanges nonces, a peer with a different key
+//! reads garbage afterwards. Since both sides start with the
+//! multistream-select header once the stream is encrypted, the first bytes
+//! read tell whether the peer uses the same key, so a mismatch fails with
+//! `PskMismatch` instead of a timeout of the following handshakes.
+use anyhow::Context as _;
+use futures::io::{AsyncRead, AsyncWrite};
+use libipld::Result;
+use libp2p::pnet::PreSharedKey;
+use pin_project::pin_project;
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use thiserror::Error;
+
+/// The multistream-select header, sent first by both sides.
+const MULTISTREAM_HEADER: &[u8] = b"\x13/multistream/1.0.0\n";
+
+/// The peer doesn't use the pre shared key of `NetworkConfig::psk`.
+#[derive(Clone, Debug, Error)]
+#[error("the peer uses a different pre shared key")]
+pub struct PskMismatch;
+
+/// Parses a key in the `/key/swarm/psk/1.0.0/` format of `swarm.key` files.
+pub fn parse_psk(s: &str) -> Result<PreSharedKey> {
+    Ok(s.trim().parse::<PreSharedKey>()?)
+}
+
+/// Reads a key in the `/key/swarm/psk/1.0.0/` format from a file.
+pub fn read_psk_file(path: &Path) -> Result<PreSharedKey> {
+    let s = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read pre shared key {}", path.display()))?;
+    parse_psk(&s).with_context(|| format!("invalid pre shared key in {}", path.display()))
+}
+
+/// Returns whether `error` of a failed connection is caused by a
+/// `PskMismatch`.
+pub(crate) fn is_psk_mismatch(error: &io::Error) -> bool {
+    let mut next = error
+        .get_ref()
+        .map(|err| err as &(dyn std::error::Error + 'static));
+    while let Some(err) = next {
+        if err.is::<PskMismatch>() {
+            return true;
+        }
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            if is_psk_mismatch(err) {
+                return true;
+            }
+        }
+        next = err.source();
+    }
+    false
+}
+
+fn mismatch() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, PskMismatch)
+}
+
+/// A pnet stream checking that the peer starts with the multistream-select
+/// header. The peer closing the stream before is treated as a mismatch too,
+/// since that is how the other side reacts to one.
+#[pin_project]
+pub(crate) struct PskChecked<S> {
+    #[pin]
+    inner: S,
+    /// Number of header bytes received so far.
+    checked: usize,
+}
+
+impl<S> PskChecked<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner, checked: 0 }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for PskChecked<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let checked = *this.checked;
+        if checked == MULTISTREAM_HEADER.len() || buf.is_empty() {
+            return this.inner.poll_read(cx, buf);
+        }
+        let n = match futures::ready!(this.inner.poll_read(cx, buf)) {
+            Ok(0) => return Poll::Ready(Err(mismatch())),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::ConnectionReset => {
+                return Poll::Ready(Err(mismatch()))
+            }
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+        let len = n.min(MULTISTREAM_HEADER.len() - checked);
+        if buf[..len] != MULTISTREAM_HEADER[checked..checked + len] {
+            return Poll::Ready(Err(mismatch()));
+        }
+        *this.checked += len;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for PskChecked<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::AsyncReadExt};
+
+    const KEY: &str = "/key/swarm/psk/1.0.0/\n/base16/\n\
+        6189c5cf0b87fb800c1a9feeda73c6ab5e998db48fb9e6a978575c770ceef683\n";
+
+    #[test]
+    fn test_parse_psk() {
+        let psk = parse_psk(KEY).unwrap();
+        assert_eq!(parse_psk(&psk.to_string()).unwrap(), psk);
+        assert!(parse_psk("/key/swarm/psk/1.0.0/\n/base16/\n00").is_err());
+    }
+
+    #[test]
+    fn test_check_header() {
+        let read = |data: &[u8]| {
+            let mut stream = PskChecked::new(data);
+            let mut buf = vec![];
+            block_on(stream.read_to_end(&mut buf)).map(|_| buf)
+        };
+        let mut data = MULTISTREAM_HEADER.to_vec();
+        data.extend_from_slice(b"\x07/noise\n");
+        assert_eq!(read(&data).unwrap(), data);
+
+        let err = read(b"\x13/multistream/2.0.0\n").unwrap_err();
+        assert!(is_psk_mismatch(&err));
+        // closed before the header was complete
+        let err = read(&MULTISTREAM_HEADER[..5]).unwrap_err();
+        assert!(is_psk_mismatch(&err));
+        assert!(!is_psk_mismatch(&io::Error::other("other")));
+    }
+}
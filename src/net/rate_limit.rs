@@ -0,0 +1,257 @@
+//! Enforcement of `NetworkConfig::serve_rate_limit`.
+//!
+//! Budgets are token buckets holding up to one second worth of requests or
+//! bytes. Taking tokens never fails, a bucket going into debt tells the
+//! caller how long to wait until the debt is paid off, so a request larger
+//! than the budget is still served eventually.
+use crate::net::{
+    config::{Budget, RateLimit, Throttle},
+    peers::BITSWAP_SERVE_THROTTLED,
+};
+use parking_lot::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Longest delay imposed at once. A budget with a rate of zero never pays
+/// off its debt, so it delays every response by this much.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    at: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.at = now;
+    }
+
+    /// Returns the tokens after refilling, negative while in debt.
+    fn available(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    /// Takes `n` tokens and returns the time until the bucket is out of
+    /// debt again.
+    fn take(&mut self, n: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else if self.rate == 0 {
+            MAX_DELAY
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64).min(MAX_DELAY)
+        }
+    }
+}
+
+/// The buckets of a `Budget`, recreated when its rates change.
+#[derive(Debug, Default)]
+pub(crate) struct Buckets {
+    requests: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl Buckets {
+    fn update(&mut self, budget: &Budget, now: Instant) {
+        fn update(bucket: &mut Option<Bucket>, rate: Option<u64>, now: Instant) {
+            if bucket.as_ref().map(|bucket| bucket.rate) != rate {
+                *bucket = rate.map(|rate| Bucket::new(rate, now));
+            }
+        }
+        update(
+            &mut self.requests,
+            budget.requests_per_sec.map(u64::from),
+            now,
+        );
+        update(&mut self.bytes, budget.bytes_per_sec, now);
+    }
+
+    /// Returns whether the budget is not in debt, the request being
+    /// admitted is already charged.
+    fn admits(&mut self, budget: &Budget, now: Instant) -> bool {
+        self.update(budget, now);
+        let requests = self
+            .requests
+            .as_mut()
+            .map_or(true, |b| b.available(now) >= 0.0);
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map_or(true, |b| b.available(now) >= 0.0);
+        requests && bytes
+    }
+
+    fn take(&mut self, budget: &Budget, requests: u64, bytes: u64, now: Instant) -> Duration {
+        self.update(budget, now);
+        let requests = self
+            .requests
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.take(requests, now));
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.take(bytes, now));
+        requests.max(bytes)
+    }
+}
+
+/// The current `RateLimit` and the buckets of its global budget, shared by
+/// the bitswap store, the connections and the `NetworkService`.
+#[derive(Debug)]
+pub(crate) struct ServeLimiter {
+    limit: RwLock<Option<RateLimit>>,
+    global: Mutex<Buckets>,
+}
+
+impl ServeLimiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit: RwLock::new(limit),
+            global: Default::default(),
+        }
+    }
+
+    pub fn get(&self) -> Option<RateLimit> {
+        *self.limit.read()
+    }
+
+    pub fn set(&self, limit: Option<RateLimit>) {
+        *self.limit.write() = limit;
+    }
+
+    /// Returns whether a request is to be answered with don't-have because
+    /// the global budget is used up. Only with `Throttle::DontHave`, the
+    /// substreams delay the responses otherwise.
+    pub fn refuses(&self) -> bool {
+        let limit = match self.get() {
+            Some(limit) if limit.throttle == Throttle::DontHave => limit,
+            _ => return false,
+        };
+        if self.global.lock().admits(&limit.global, Instant::now()) {
+            return false;
+        }
+        BITSWAP_SERVE_THROTTLED.with_label_values(&["global"]).inc();
+        true
+    }
+
+    /// Charges requests of a peer and the bytes of the responses to the
+    /// global and per peer budgets. Returns the time to wait before writing
+    /// more of the responses, with `Throttle::DontHave` the global debt
+    /// refuses the following requests instead.
+    pub fn serve(&self, peer: &Mutex<Buckets>, requests: u64, bytes: u64) -> Duration {
+        let limit = match self.get() {
+            Some(limit) => limit,
+            None => return Duration::ZERO,
+        };
+        let now = Instant::now();
+        let global = self.global.lock().take(&limit.global, requests, bytes, now);
+        let peer = peer.lock().take(&limit.per_peer, requests, bytes, now);
+        throttled("peer", peer);
+        match limit.throttle {
+            Throttle::Delay => {
+                throttled("global", global);
+                global.max(peer)
+            }
+            Throttle::DontHave => peer,
+        }
+    }
+}
+
+fn throttled(budget: &str, wait: Duration) {
+    if wait > Duration::ZERO {
+        BITSWAP_SERVE_THROTTLED.with_label_values(&[budget]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(10, start);
+        // a burst of one second worth of tokens
+        assert_eq!(bucket.take(10, start), Duration::ZERO);
+        assert_eq!(bucket.take(5, start), Duration::from_millis(500));
+        // the debt is paid off over time
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.available(later), 0.0);
+        // the tokens don't exceed the rate
+        assert_eq!(bucket.available(start + Duration::from_secs(10)), 10.0);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let budget = Budget {
+            requests_per_sec: Some(2),
+            bytes_per_sec: Some(100),
+        };
+        let limit = RateLimit {
+            global: budget,
+            ..Default::default()
+        };
+        let limiter = ServeLimiter::new(Some(limit));
+        let peer = Mutex::new(Buckets::default());
+        assert_eq!(limiter.serve(&peer, 1, 0), Duration::ZERO);
+        assert_eq!(limiter.serve(&peer, 1, 0), Duration::ZERO);
+        assert!(limiter.serve(&peer, 1, 0) > Duration::ZERO);
+        assert!(limiter.serve(&peer, 0, 200) > Duration::from_millis(500));
+        assert!(!limiter.refuses());
+
+        limiter.set(Some(RateLimit {
+            throttle: Throttle::DontHave,
+            ..limit
+        }));
+        assert!(limiter.refuses());
+        assert_eq!(limiter.serve(&peer, 1, 1000), Duration::ZERO);
+
+        // per peer budgets are independent of the global one
+        let peer = Mutex::new(Buckets::default());
+        limiter.set(Some(RateLimit {
+            per_peer: budget,
+            ..Default::default()
+        }));
+        assert_eq!(limiter.serve(&peer, 1, 100), Duration::ZERO);
+        assert!(limiter.serve(&peer, 1, 100) > Duration::ZERO);
+
+        limiter.set(None);
+        assert!(!limiter.refuses());
+        assert_eq!(limiter.serve(&peer, 100, 100_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_zero_rate() {
+        let limit = RateLimit {
+            global: Budget {
+                requests_per_sec: None,
+                bytes_per_sec: Some(0),
+            },
+            ..Default::default()
+        };
+        let limiter = ServeLimiter::new(Some(limit));
+        let peer = Mutex::new(Buckets::default());
+        assert_eq!(limiter.serve(&peer, 1, 0), Duration::ZERO);
+        assert_eq!(limiter.serve(&peer, 0, 1), MAX_DELAY);
+
+        limiter.set(Some(RateLimit {
+            throttle: Throttle::DontHave,
+            ..limit
+        }));
+        assert!(limiter.refuses());
+    }
+}
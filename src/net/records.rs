@@ -0,0 +1,55 @@
+use libp2p::kad::{Quorum, Record};
+use std::time::{Duration, Instant};
+
+/// Default time to live of records on remote nodes, the same as kademlia uses.
+pub const DEFAULT_RECORD_TTL: Duration = Duration::from_secs(36 * 60 * 60);
+
+/// Options for `Ipfs::put_record_with`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PutOptions {
+    /// How long remote nodes keep the record, defaults to 36 hours.
+    pub ttl: Option<Duration>,
+    /// Republish the record whenever half of the `ttl` has passed, until it
+    /// is removed with `Ipfs::remove_record` or `Record::expires` is reached.
+    pub republish: bool,
+}
+
+/// A record published by this node that is kept alive in the dht.
+#[derive(Clone, Debug)]
+pub struct PublishedRecord {
+    /// The record, `Record::expires` ends republishing.
+    pub record: Record,
+    /// The quorum of each publication.
+    pub quorum: Quorum,
+    /// How long remote nodes keep each publication.
+    pub ttl: Duration,
+    /// When the record is published next.
+    pub republish_at: Instant,
+}
+
+impl PublishedRecord {
+    pub(crate) fn new(record: Record, quorum: Quorum, ttl: Duration) -> Self {
+        Self {
+            record,
+            quorum,
+            ttl,
+            republish_at: Instant::now(),
+        }
+    }
+
+    /// Returns the copy of the record to publish now, which expires on
+    /// remote nodes after the `ttl` but not later than the record itself.
+    pub(crate) fn copy(&self, now: Instant) -> Record {
+        let mut record = self.record.clone();
+        let expires = now + self.ttl;
+        record.expires = Some(record.expires.map_or(expires, |e| e.min(expires)));
+        record
+    }
+
+    /// Schedules the next publication and returns the delay until then.
+    pub(crate) fn schedule(&mut self, now: Instant) -> Duration {
+        let delay = self.ttl / 2;
+        self.republish_at = now + delay;
+        delay
+    }
+}
@@ -0,0 +1,381 @@
+//! Requests of application protocols, see `Ipfs::request`.
+//!
+//! All application protocols share one libp2p protocol, since the protocols
+//! of a behaviour are fixed once the swarm is built. A request starts with
+//! the name of the application protocol, a response with a status telling
+//! whether the peer answers requests of that protocol.
+use crate::db::Shutdown;
+use async_trait::async_trait;
+use fnv::FnvHashMap;
+use futures::{
+    channel::{mpsc, oneshot},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    stream::StreamExt,
+};
+use libipld::Result;
+use libp2p::{
+    core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName},
+    request_response::{RequestId, RequestResponseCodec, ResponseChannel},
+    PeerId,
+};
+use std::{
+    io,
+    task::{Context, Poll},
+};
+use thiserror::Error;
+
+/// Maximum length of the name of an application protocol.
+const MAX_PROTOCOL_LEN: usize = 256;
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 1;
+
+/// The request response protocol did not receive a response in time.
+#[derive(Debug, Error)]
+#[error("Request to {0} timed out.")]
+pub struct RequestTimeout(pub PeerId);
+
+/// The request couldn't be sent or the response couldn't be read.
+#[derive(Debug, Error)]
+#[error("Request to {peer} failed: {reason}")]
+pub struct RequestFailed {
+    pub peer: PeerId,
+    pub reason: String,
+}
+
+/// The peer doesn't answer requests of the protocol.
+#[derive(Debug, Error)]
+#[error("Peer {peer} doesn't answer requests of protocol {protocol}.")]
+pub struct UnsupportedProtocol {
+    pub peer: PeerId,
+    pub protocol: String,
+}
+
+/// A request or response payload exceeds the limit of the
+/// `RequestResponseConfig`.
+#[derive(Debug, Error)]
+#[error("Payload of {size} bytes exceeds the limit of {max} bytes.")]
+pub struct PayloadTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestProtocol;
+
+impl ProtocolName for RequestProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/ipfs-embed/request/1.0.0"
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub protocol: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Response {
+    Data(Vec<u8>),
+    /// No handler is registered for the protocol of the request.
+    Unsupported,
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestCodec {
+    max_request_size: usize,
+    max_response_size: usize,
+}
+
+impl RequestCodec {
+    pub fn new(max_request_size: usize, max_response_size: usize) -> Self {
+        Self {
+            max_request_size,
+            max_response_size,
+        }
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for RequestCodec {
+    type Protocol = RequestProtocol;
+    type Request = Request;
+    type Response = Response;
+
+    async fn read_request<T>(&mut self, _: &RequestProtocol, io: &mut T) -> io::Result<Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let protocol = read_length_prefixed(io, MAX_PROTOCOL_LEN).await?;
+        let protocol = String::from_utf8(protocol)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let data = read_length_prefixed(io, self.max_request_size).await?;
+        Ok(Request { protocol, data })
+    }
+
+    async fn read_response<T>(&mut self, _: &RequestProtocol, io: &mut T) -> io::Result<Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut status = [0];
+        io.read_exact(&mut status).await?;
+        match status[0] {
+            STATUS_OK => Ok(Response::Data(
+                read_length_prefixed(io, self.max_response_size).await?,
+            )),
+            STATUS_UNSUPPORTED => Ok(Response::Unsupported),
+            status => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid response status {}", status),
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &RequestProtocol,
+        io: &mut T,
+        request: Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, request.protocol).await?;
+        write_length_prefixed(io, request.data).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &RequestProtocol,
+        io: &mut T,
+        response: Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match response {
+            Response::Data(data) => {
+                io.write_all(&[STATUS_OK]).await?;
+                write_length_prefixed(io, data).await?;
+            }
+            Response::Unsupported => io.write_all(&[STATUS_UNSUPPORTED]).await?,
+        }
+        io.close().await
+    }
+}
+
+type Responses = mpsc::UnboundedSender<(ResponseChannel<Response>, Response)>;
+type OutboundRequests = FnvHashMap<RequestId, (PeerId, String, oneshot::Sender<Result<Vec<u8>>>)>;
+
+/// A request received from a peer, see `Ipfs::requests`. Dropping it
+/// without responding fails the request on the remote side.
+pub struct InboundRequest {
+    peer: PeerId,
+    protocol: String,
+    data: Vec<u8>,
+    channel: ResponseChannel<Response>,
+    max_response_size: usize,
+    responses: Responses,
+}
+
+impl std::fmt::Debug for InboundRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InboundRequest")
+            .field("peer", &self.peer)
+            .field("protocol", &self.protocol)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+impl InboundRequest {
+    /// Returns the peer that sent the request.
+    pub fn peer(&self) -> PeerId {
+        self.peer
+    }
+
+    /// Returns the application protocol of the request.
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    /// Returns the payload of the request.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Sends `data` as response. Fails with `PayloadTooLarge` if it exceeds
+    /// `RequestResponseConfig::max_response_size`, which fails the request on
+    /// the remote side too.
+    pub fn respond(self, data: Vec<u8>) -> Result<()> {
+        if data.len() > self.max_response_size {
+            return Err(PayloadTooLarge {
+                size: data.len(),
+                max: self.max_response_size,
+            }
+            .into());
+        }
+        self.responses
+            .unbounded_send((self.channel, Response::Data(data)))
+            .map_err(|_| Shutdown)?;
+        Ok(())
+    }
+}
+
+/// The state of the request response protocol kept by `poll_swarm`.
+pub(crate) struct PendingRequests {
+    /// Sent requests waiting for their response.
+    outbound: OutboundRequests,
+    /// Receivers of inbound requests by protocol.
+    handlers: FnvHashMap<String, mpsc::UnboundedSender<InboundRequest>>,
+    max_response_size: usize,
+    responses_tx: Responses,
+    responses_rx: mpsc::UnboundedReceiver<(ResponseChannel<Response>, Response)>,
+}
+
+impl PendingRequests {
+    pub fn new(max_response_size: usize) -> Self {
+        let (responses_tx, responses_rx) = mpsc::unbounded();
+        Self {
+            outbound: Default::default(),
+            handlers: Default::default(),
+            max_response_size,
+            responses_tx,
+            responses_rx,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        id: RequestId,
+        peer: PeerId,
+        protocol: String,
+        tx: oneshot::Sender<Result<Vec<u8>>>,
+    ) {
+        self.outbound.insert(id, (peer, protocol, tx));
+    }
+
+    /// Completes the sent request `id`.
+    pub fn complete(
+        &mut self,
+        id: &RequestId,
+        response: std::result::Result<Response, RequestFailure>,
+    ) {
+        if let Some((peer, protocol, tx)) = self.outbound.remove(id) {
+            let result = match response {
+                Ok(Response::Data(data)) => Ok(data),
+                Ok(Response::Unsupported) => Err(UnsupportedProtocol { peer, protocol }.into()),
+                Err(RequestFailure::Timeout) => Err(RequestTimeout(peer).into()),
+                Err(RequestFailure::Other(reason)) => Err(RequestFailed { peer, reason }.into()),
+            };
+            tx.send(result).ok();
+        }
+    }
+
+    /// Delivers inbound requests of `protocol` to `tx`, replacing the
+    /// previous receiver.
+    pub fn register(&mut self, protocol: String, tx: mpsc::UnboundedSender<InboundRequest>) {
+        self.handlers.insert(protocol, tx);
+    }
+
+    /// Hands an inbound request to the receiver of its protocol, returns the
+    /// channel if there is none.
+    pub fn dispatch(
+        &mut self,
+        peer: PeerId,
+        request: Request,
+        channel: ResponseChannel<Response>,
+    ) -> std::result::Result<(), ResponseChannel<Response>> {
+        let handler = match self.handlers.get(&request.protocol) {
+            Some(handler) => handler,
+            None => return Err(channel),
+        };
+        let protocol = request.protocol.clone();
+        let inbound = InboundRequest {
+            peer,
+            protocol: request.protocol,
+            data: request.data,
+            channel,
+            max_response_size: self.max_response_size,
+            responses: self.responses_tx.clone(),
+        };
+        if let Err(err) = handler.unbounded_send(inbound) {
+            // all streams of the protocol were dropped
+            self.handlers.remove(&protocol);
+            return Err(err.into_inner().channel);
+        }
+        Ok(())
+    }
+
+    /// Returns the next response passed to `InboundRequest::respond`.
+    pub fn poll_response(
+        &mut self,
+        cx: &mut Context,
+    ) -> Poll<(ResponseChannel<Response>, Response)> {
+        match self.responses_rx.poll_next_unpin(cx) {
+            Poll::Ready(Some(response)) => Poll::Ready(response),
+            // a sender is kept in `responses_tx`
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Why a sent request failed.
+pub(crate) enum RequestFailure {
+    Timeout,
+    Other(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn roundtrip_request(codec: &mut RequestCodec, request: Request) -> io::Result<Request> {
+        let mut buf = vec![];
+        block_on(codec.write_request(&RequestProtocol, &mut buf, request))?;
+        block_on(codec.read_request(&RequestProtocol, &mut &buf[..]))
+    }
+
+    fn roundtrip_response(codec: &mut RequestCodec, response: Response) -> io::Result<Response> {
+        let mut buf = vec![];
+        block_on(codec.write_response(&RequestProtocol, &mut buf, response))?;
+        block_on(codec.read_response(&RequestProtocol, &mut &buf[..]))
+    }
+
+    #[test]
+    fn test_codec() {
+        let mut codec = RequestCodec::new(4, 8);
+        let request = roundtrip_request(
+            &mut codec,
+            Request {
+                protocol: "echo".into(),
+                data: b"ping".to_vec(),
+            },
+        )
+        .unwrap();
+        assert_eq!(request.protocol, "echo");
+        assert_eq!(request.data, b"ping");
+        let response = Response::Data(b"pong".to_vec());
+        assert_eq!(
+            roundtrip_response(&mut codec, response.clone()).unwrap(),
+            response
+        );
+        assert_eq!(
+            roundtrip_response(&mut codec, Response::Unsupported).unwrap(),
+            Response::Unsupported
+        );
+
+        // the limits are enforced by the receiving side
+        assert!(roundtrip_request(
+            &mut codec,
+            Request {
+                protocol: "echo".into(),
+                data: vec![0; 5],
+            },
+        )
+        .is_err());
+        assert!(roundtrip_response(&mut codec, Response::Data(vec![0; 9])).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+//! Scheduling of the block requests of syncs, see
+//! `NetworkConfig::sync_scheduler`.
+//!
+//! Every sync holds a `SyncTicket` and each of its block requests waits for
+//! a `WantPermit`. Permits are granted while the outstanding requests stay
+//! below the global limit and the limits of the providers. Syncs waiting
+//! for a permit take turns: a sync that got one goes to the back of the
+//! queue, so a sync with many missing blocks can't starve the ones started
+//! after it. A sync blocked by the limits of its providers doesn't hold up
+//! the syncs behind it.
+//!
+//! Only syncs go through the scheduler, `get` and `fetch` request their
+//! block from bitswap directly and aren't counted.
+//!
+//! The limit of a provider is halved when its responses are slow or fail
+//! and grows back by one with every timely response.
+use crate::net::{config::SyncSchedulerConfig, peers::BITSWAP_OUTSTANDING_WANTS};
+use fnv::FnvHashMap;
+use futures::future::{self, Future};
+use libp2p::PeerId;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Provider {
+    limit: usize,
+    outstanding: usize,
+}
+
+/// A sync waiting for a permit.
+#[derive(Debug)]
+struct Waiting {
+    sync: u64,
+    /// Providers of the last request that asked.
+    providers: Vec<PeerId>,
+    wakers: Vec<Waker>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    outstanding: usize,
+    /// Providers with outstanding requests or a reduced limit.
+    providers: FnvHashMap<PeerId, Provider>,
+    waiting: VecDeque<Waiting>,
+    next_sync: u64,
+}
+
+impl State {
+    fn has_room(&self, config: &SyncSchedulerConfig, providers: &[PeerId]) -> bool {
+        self.outstanding < config.max_outstanding
+            && providers.iter().all(|peer| {
+                self.providers
+                    .get(peer)
+                    .map_or(true, |provider| provider.outstanding < provider.limit)
+            })
+    }
+
+    /// Wakes all waiting requests, they keep the place of their sync.
+    fn wake_all(&mut self) {
+        for waiting in &mut self.waiting {
+            for waker in waiting.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SyncScheduler {
+    config: SyncSchedulerConfig,
+    state: Mutex<State>,
+}
+
+impl SyncScheduler {
+    pub fn new(config: SyncSchedulerConfig) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    pub fn ticket(self: &Arc<Self>) -> SyncTicket {
+        let mut state = self.state.lock();
+        let id = state.next_sync;
+        state.next_sync += 1;
+        SyncTicket {
+            scheduler: self.clone(),
+            id,
+        }
+    }
+
+    /// Returns the number of outstanding block requests.
+    pub fn outstanding(&self) -> usize {
+        self.state.lock().outstanding
+    }
+
+    fn poll_acquire(
+        self: &Arc<Self>,
+        sync: u64,
+        providers: &[PeerId],
+        cx: &mut Context,
+    ) -> Poll<WantPermit> {
+        let mut state = self.state.lock();
+        let position = state
+            .waiting
+            .iter()
+            .position(|waiting| waiting.sync == sync);
+        let ahead = position.unwrap_or_else(|| state.waiting.len());
+        let turn = state
+            .waiting
+            .iter()
+            .take(ahead)
+            .all(|waiting| !state.has_room(&self.config, &waiting.providers));
+        if turn && state.has_room(&self.config, providers) {
+            if let Some(position) = position {
+                // the other requests of the sync queue up again at the back
+                let waiting = state.waiting.remove(position).unwrap();
+                for waker in waiting.wakers {
+                    waker.wake();
+                }
+            }
+            state.outstanding += 1;
+            for peer in providers {
+                state
+                    .providers
+                    .entry(*peer)
+                    .or_insert(Provider {
+                        limit: self.config.max_per_peer,
+                        outstanding: 0,
+                    })
+                    .outstanding += 1;
+            }
+            BITSWAP_OUTSTANDING_WANTS.set(state.outstanding as i64);
+            // the requests of the next sync may fit as well
+            state.wake_all();
+            return Poll::Ready(WantPermit {
+                scheduler: self.clone(),
+                providers: providers.to_vec(),
+                started: Instant::now(),
+                success: None,
+            });
+        }
+        let waiting = match position {
+            Some(position) => &mut state.waiting[position],
+            None => {
+                state.waiting.push_back(Waiting {
+                    sync,
+                    providers: vec![],
+                    wakers: vec![],
+                });
+                state.waiting.back_mut().unwrap()
+            }
+        };
+        waiting.providers = providers.to_vec();
+        if !waiting.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            waiting.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn release(&self, providers: &[PeerId], elapsed: Duration, success: Option<bool>) {
+        let max = self.config.max_per_peer;
+        let mut state = self.state.lock();
+        state.outstanding -= 1;
+        for peer in providers {
+            if let Some(provider) = state.providers.get_mut(peer) {
+                provider.outstanding -= 1;
+                match success {
+                    Some(true) if elapsed <= self.config.slow_response => {
+                        provider.limit = (provider.limit + 1).min(max);
+                    }
+                    Some(_) => provider.limit = (provider.limit / 2).max(1),
+                    // cancelled requests don't tell anything about the provider
+                    None => {}
+                }
+                if provider.outstanding == 0 && provider.limit == max {
+                    state.providers.remove(peer);
+                }
+            }
+        }
+        BITSWAP_OUTSTANDING_WANTS.set(state.outstanding as i64);
+        state.wake_all();
+    }
+}
+
+/// The place of a sync in the scheduler, it leaves the queue when dropped.
+#[derive(Debug)]
+pub(crate) struct SyncTicket {
+    scheduler: Arc<SyncScheduler>,
+    id: u64,
+}
+
+impl SyncTicket {
+    /// Waits for the turn of the sync to request a block from `providers`.
+    pub fn acquire(&self, providers: Vec<PeerId>) -> impl Future<Output = WantPermit> + '_ {
+        future::poll_fn(move |cx| self.scheduler.poll_acquire(self.id, &providers, cx))
+    }
+}
+
+impl Drop for SyncTicket {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock();
+        state.waiting.retain(|waiting| waiting.sync != self.id);
+        state.wake_all();
+    }
+}
+
+/// An outstanding block request, released when dropped.
+#[derive(Debug)]
+pub(crate) struct WantPermit {
+    scheduler: Arc<SyncScheduler>,
+    providers: Vec<PeerId>,
+    started: Instant,
+    success: Option<bool>,
+}
+
+impl WantPermit {
+    /// Releases the permit of a request that got a response or failed.
+    pub fn complete(mut self, success: bool) {
+        self.success = Some(success);
+    }
+}
+
+impl Drop for WantPermit {
+    fn drop(&mut self) {
+        self.scheduler
+            .release(&self.providers, self.started.elapsed(), self.success);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn scheduler(max_outstanding: usize, max_per_peer: usize) -> Arc<SyncScheduler> {
+        Arc::new(SyncScheduler::new(SyncSchedulerConfig {
+            max_outstanding,
+            max_per_peer,
+            slow_response: Duration::from_secs(60),
+        }))
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let scheduler = scheduler(1, 8);
+        let peer = PeerId::random();
+        let (a, b) = (scheduler.ticket(), scheduler.ticket());
+        let permit = a.acquire(vec![peer]).now_or_never().unwrap();
+        // both syncs queue up behind the outstanding request
+        let mut a2 = a.acquire(vec![peer]).boxed();
+        let mut b2 = b.acquire(vec![peer]).boxed();
+        assert!((&mut a2).now_or_never().is_none());
+        assert!((&mut b2).now_or_never().is_none());
+        permit.complete(true);
+        // it is the turn of `a`, which asked first
+        assert!((&mut b2).now_or_never().is_none());
+        let permit = a2.now_or_never().unwrap();
+        assert_eq!(scheduler.outstanding(), 1);
+        // `a` goes to the back of the queue
+        let mut a3 = a.acquire(vec![peer]).boxed();
+        assert!((&mut a3).now_or_never().is_none());
+        drop(permit);
+        assert!((&mut a3).now_or_never().is_none());
+        assert!(b2.now_or_never().is_some());
+    }
+
+    #[test]
+    fn test_provider_backoff() {
+        let scheduler = scheduler(8, 4);
+        let (slow, fast) = (PeerId::random(), PeerId::random());
+        let ticket = scheduler.ticket();
+        for _ in 0..2 {
+            let permit = ticket.acquire(vec![slow]).now_or_never().unwrap();
+            permit.complete(false);
+        }
+        // the limit of the slow provider went from 4 to 1
+        let permit = ticket.acquire(vec![slow]).now_or_never().unwrap();
+        assert!(ticket.acquire(vec![slow]).now_or_never().is_none());
+        // other providers are not affected
+        let other = ticket.acquire(vec![fast]).now_or_never().unwrap();
+        assert_eq!(scheduler.outstanding(), 2);
+        drop((permit, other));
+        assert_eq!(scheduler.outstanding(), 0);
+    }
+}
@@ -0,0 +1,63 @@
+use super::{behaviour::QueryId, peers::Event};
+use fnv::FnvHashMap;
+use libp2p::kad::KademliaEvent;
+use libp2p_bitswap::BitswapEvent;
+use tracing::Span;
+
+/// Spans of the running queries. The span of a query is a child of the span
+/// of the api call that started it, so handling the query in the swarm task
+/// is part of the trace of the call.
+#[derive(Default)]
+pub(crate) struct QuerySpans(FnvHashMap<QueryId, Span>);
+
+impl QuerySpans {
+    /// Creates the span of a started query and returns the event announcing
+    /// it. Calls joining a running query are linked to its span instead.
+    pub fn start(&mut self, id: QueryId, parent: &Span) -> Option<Event> {
+        if let Some(span) = self.0.get(&id) {
+            span.follows_from(parent.id());
+            return None;
+        }
+        let span = tracing::debug_span!(parent: parent, "query", id = ?id);
+        span.in_scope(|| tracing::debug!("query started"));
+        self.0.insert(id, span);
+        Some(Event::QueryStarted(id))
+    }
+
+    /// Returns the span of the query, or a disabled span if it has none.
+    pub fn get(&self, id: Option<QueryId>) -> Span {
+        id.and_then(|id| self.0.get(&id))
+            .cloned()
+            .unwrap_or_else(Span::none)
+    }
+
+    /// Closes the spans of the queries that are no longer `running` and
+    /// returns the events announcing their end.
+    pub fn finish(&mut self, running: impl Fn(&QueryId) -> bool) -> Vec<Event> {
+        let mut finished = vec![];
+        self.0.retain(|id, span| {
+            if running(id) {
+                return true;
+            }
+            span.in_scope(|| tracing::debug!("query finished"));
+            finished.push(Event::QueryFinished(*id));
+            false
+        });
+        finished
+    }
+}
+
+/// Returns the query a kademlia event belongs to.
+pub(crate) fn kad_query(event: &KademliaEvent) -> Option<QueryId> {
+    match event {
+        KademliaEvent::OutboundQueryCompleted { id, .. } => Some((*id).into()),
+        _ => None,
+    }
+}
+
+/// Returns the query a bitswap event belongs to.
+pub(crate) fn bitswap_query(event: &BitswapEvent) -> Option<QueryId> {
+    match event {
+        BitswapEvent::Progress(id, _) | BitswapEvent::Complete(id, _) => Some((*id).into()),
+    }
+}
@@ -49,9 +49,9 @@ fn test_dial_basic() {
         false,
         false,
         false,
-        Writer::new(HashSet::default()),
-        Writer::new(HashMap::default()),
-        Writer::new(vec![]),
+        Variable::new(HashSet::default()),
+        Variable::new(HashMap::default()),
+        Variable::new(vec![]),
     );
 
     let events = Default::default();
@@ -221,9 +221,9 @@ fn from_docker_host() {
         false,
         false,
         false,
-        Writer::new(HashSet::default()),
-        Writer::new(HashMap::default()),
-        Writer::new(vec![]),
+        Variable::new(HashSet::default()),
+        Variable::new(HashMap::default()),
+        Variable::new(vec![]),
     );
     let events = Default::default();
     let (tx, rx) = mpsc::unbounded();
@@ -345,9 +345,9 @@ fn from_docker_container() {
         false,
         false,
         false,
-        Writer::new(HashSet::default()),
-        Writer::new(HashMap::default()),
-        Writer::new(vec![]),
+        Variable::new(HashSet::default()),
+        Variable::new(HashMap::default()),
+        Variable::new(vec![]),
     );
     let events = Default::default();
     let (tx, rx) = mpsc::unbounded();
@@ -0,0 +1,64 @@
+//! Minimal protobuf encoding and decoding for the few messages ipfs-embed
+//! reads and writes by hand.
+use crate::car::write_varint;
+use libipld::Result;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("Invalid protobuf: {0}.")]
+pub struct InvalidProtobuf(&'static str);
+
+/// A protobuf field value.
+pub(crate) enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Reads a field number and value.
+pub(crate) fn read_field<'a>(buf: &mut &'a [u8]) -> Result<(u64, Field<'a>)> {
+    let key = read_varint(buf)?;
+    let value = match key & 7 {
+        VARINT => Field::Varint(read_varint(buf)?),
+        LEN => {
+            let len = read_varint(buf)? as usize;
+            if len > buf.len() {
+                return Err(InvalidProtobuf("truncated field").into());
+            }
+            let (bytes, rest) = buf.split_at(len);
+            *buf = rest;
+            Field::Bytes(bytes)
+        }
+        _ => return Err(InvalidProtobuf("unsupported wire type").into()),
+    };
+    Ok((key >> 3, value))
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut n = 0u64;
+    for i in 0..10 {
+        let (byte, rest) = buf
+            .split_first()
+            .ok_or(InvalidProtobuf("truncated varint"))?;
+        *buf = rest;
+        n |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(InvalidProtobuf("varint overflow").into())
+}
+
+/// Protobuf wire types.
+const VARINT: u64 = 0;
+const LEN: u64 = 2;
+
+pub(crate) fn write_varint_field(field: u64, n: u64, buf: &mut Vec<u8>) {
+    write_varint((field << 3) | VARINT, buf);
+    write_varint(n, buf);
+}
+
+pub(crate) fn write_bytes_field(field: u64, bytes: &[u8], buf: &mut Vec<u8>) {
+    write_varint((field << 3) | LEN, buf);
+    write_varint(bytes.len() as u64, buf);
+    buf.extend_from_slice(bytes);
+}
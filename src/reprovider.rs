@@ -0,0 +1,158 @@
+use crate::{
+    db::StorageService,
+    executor::{Executor, JoinHandle},
+    net::{Event, EventFilter, NetworkService, ReprovidePolicy},
+};
+use futures::stream::{self, StreamExt};
+use lazy_static::lazy_static;
+use libipld::{codec::References, store::StoreParams, Cid, Ipld, Result};
+use libp2p::kad::record::Key;
+use parking_lot::Mutex;
+use prometheus::{IntCounter, Registry};
+use std::{collections::HashSet, time::Duration};
+
+/// Number of provider announcements in flight at the same time.
+const REPROVIDE_CONCURRENCY: usize = 8;
+
+/// Announces the blocks selected by a `ReprovidePolicy` on the dht once the
+/// node is bootstrapped and again after every interval.
+pub(crate) struct Reprovider {
+    policy: ReprovidePolicy,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Reprovider {
+    pub fn new<P: StoreParams>(
+        policy: ReprovidePolicy,
+        interval: Duration,
+        storage: StorageService<P>,
+        mut network: NetworkService,
+        executor: &Executor,
+    ) -> Self
+    where
+        Ipld: References<P::Codecs>,
+    {
+        let task = if policy == ReprovidePolicy::None {
+            None
+        } else {
+            Some(executor.spawn(async move {
+                let mut events = match network
+                    .swarm_events_filtered(EventFilter::none().dht())
+                    .await
+                {
+                    Ok(events) => events,
+                    Err(err) => {
+                        tracing::warn!("reprovider failed to subscribe to events: {}", err);
+                        return;
+                    }
+                };
+                while !network.is_bootstrapped() {
+                    match events.next().await {
+                        Some(Event::Bootstrapped) => break,
+                        Some(_) => {}
+                        None => return,
+                    }
+                }
+                drop(events);
+                loop {
+                    if network.is_bootstrapped() {
+                        match reprovide(policy, &storage, &network).await {
+                            Ok(n) => tracing::debug!("reprovided {} blocks", n),
+                            Err(err) => tracing::warn!("reprovide failed: {}", err),
+                        }
+                    } else {
+                        tracing::debug!("skipping reprovide, not bootstrapped");
+                    }
+                    futures_timer::Delay::new(interval).await;
+                }
+            }))
+        };
+        Self {
+            policy,
+            task: Mutex::new(task),
+        }
+    }
+
+    pub fn policy(&self) -> ReprovidePolicy {
+        self.policy
+    }
+
+    /// Stops the periodic announcements.
+    pub fn stop(&self) {
+        if let Some(task) = self.task.lock().take() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Reprovider {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Announces the blocks selected by `policy` and returns the number of
+/// successful announcements.
+pub(crate) async fn reprovide<P: StoreParams>(
+    policy: ReprovidePolicy,
+    storage: &StorageService<P>,
+    network: &NetworkService,
+) -> Result<usize>
+where
+    Ipld: References<P::Codecs>,
+{
+    let cids: Vec<Cid> = match policy {
+        ReprovidePolicy::None => return Ok(0),
+        ReprovidePolicy::Roots => storage
+            .aliases()?
+            .into_iter()
+            .map(|(_, cid)| cid)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect(),
+        ReprovidePolicy::All => storage.iter()?.collect(),
+    };
+    let announced = stream::iter(cids)
+        .map(|cid| {
+            let mut network = network.clone();
+            async move {
+                let res = network.provide(Key::new(&cid.to_bytes())).await;
+                (cid, res)
+            }
+        })
+        .buffer_unordered(REPROVIDE_CONCURRENCY)
+        .fold(0, |announced, (cid, res)| async move {
+            match res {
+                Ok(()) => {
+                    REPROVIDED.inc();
+                    announced + 1
+                }
+                Err(err) => {
+                    REPROVIDE_FAILURES.inc();
+                    tracing::debug!("failed to provide {}: {}", cid, err);
+                    announced
+                }
+            }
+        })
+        .await;
+    Ok(announced)
+}
+
+lazy_static! {
+    pub static ref REPROVIDED: IntCounter = IntCounter::new(
+        "reprovider_announced",
+        "Number of blocks announced on the dht by the reprovider."
+    )
+    .unwrap();
+    pub static ref REPROVIDE_FAILURES: IntCounter = IntCounter::new(
+        "reprovider_failures",
+        "Number of failed provider announcements of the reprovider."
+    )
+    .unwrap();
+}
+
+pub(crate) fn register_metrics(registry: &Registry) -> Result<()> {
+    registry.register(Box::new(REPROVIDED.clone()))?;
+    registry.register(Box::new(REPROVIDE_FAILURES.clone()))?;
+    Ok(())
+}
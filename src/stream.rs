@@ -0,0 +1,345 @@
+//! Storing content larger than a block as a tree of chunks, and reading it
+//! back.
+//!
+//! The chunks are linked by dag-cbor index nodes of the form
+//! `{"height": h, "size": n, "links": [...]}`, where `size` is the number of
+//! bytes below the node. The links of an index of height 0 point to chunks,
+//! those of higher indexes to indexes one level lower. The root is always an
+//! index, so empty content and content fitting in a single chunk have the
+//! same structure as everything else.
+use crate::{backend::TempPin, db::StorageService};
+use anyhow::anyhow;
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, References},
+    error::BlockNotFound,
+    multihash::MultihashDigest,
+    store::StoreParams,
+    Block, Cid, Ipld, Result,
+};
+use std::collections::BTreeMap;
+
+/// Codec of the index nodes.
+const DAG_CBOR: u64 = 0x71;
+/// Maximum number of links of an index node.
+const MAX_LINKS: usize = 1024;
+/// Upper bound of the encoded size of a link, used to keep index nodes
+/// within the maximum block size.
+const MAX_LINK_SIZE: usize = 128;
+
+/// Splits written bytes in to chunks of the maximum block size and inserts
+/// them together with their index nodes. All blocks are temp pinned until
+/// the writer is dropped, so aborting a write by dropping the writer leaves
+/// nothing behind that isn't garbage collected.
+pub struct BlockWriter<P: StoreParams> {
+    storage: StorageService<P>,
+    tmp: TempPin,
+    codec: u64,
+    hasher: P::Hashes,
+    buffer: Vec<u8>,
+    /// The links of the index nodes that aren't full yet, with the number of
+    /// bytes below them, by height.
+    levels: Vec<Vec<(Cid, u64)>>,
+}
+
+impl<P: StoreParams> BlockWriter<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub(crate) fn new(storage: StorageService<P>, codec: u64, hasher: P::Hashes) -> Result<Self> {
+        Ok(Self {
+            tmp: storage.create_temp_pin()?,
+            storage,
+            codec,
+            hasher,
+            buffer: Vec::with_capacity(P::MAX_BLOCK_SIZE),
+            levels: vec![vec![]],
+        })
+    }
+
+    /// Appends `bytes`, inserting every chunk that is complete.
+    pub fn write(&mut self, mut bytes: &[u8]) -> Result<()> {
+        while !bytes.is_empty() {
+            let n = bytes.len().min(P::MAX_BLOCK_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            if self.buffer.len() == P::MAX_BLOCK_SIZE {
+                self.push_chunk()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts the last chunk and the remaining index nodes and returns the
+    /// root. Like with `Ipfs::add_file` the root needs to be aliased to keep
+    /// the content.
+    pub fn finish(mut self) -> Result<Cid> {
+        if !self.buffer.is_empty() {
+            self.push_chunk()?;
+        }
+        let mut height = 0;
+        loop {
+            let links = std::mem::take(&mut self.levels[height]);
+            if height + 1 == self.levels.len() {
+                // the only link of the top level is an index already
+                if height > 0 && links.len() == 1 {
+                    return Ok(links[0].0);
+                }
+                return Ok(self.push_index(height, links)?.0);
+            }
+            if !links.is_empty() {
+                let index = self.push_index(height, links)?;
+                self.push_link(height + 1, index)?;
+            }
+            height += 1;
+        }
+    }
+
+    fn push_chunk(&mut self) -> Result<()> {
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(P::MAX_BLOCK_SIZE));
+        let size = chunk.len() as u64;
+        let cid = Cid::new_v1(self.codec, self.hasher.digest(&chunk));
+        self.insert(Block::new_unchecked(cid, chunk))?;
+        self.push_link(0, (cid, size))
+    }
+
+    fn push_link(&mut self, height: usize, link: (Cid, u64)) -> Result<()> {
+        if self.levels.len() == height {
+            self.levels.push(vec![]);
+        }
+        self.levels[height].push(link);
+        if self.levels[height].len() == max_links::<P>() {
+            let links = std::mem::take(&mut self.levels[height]);
+            let index = self.push_index(height, links)?;
+            self.push_link(height + 1, index)?;
+        }
+        Ok(())
+    }
+
+    fn push_index(&mut self, height: usize, links: Vec<(Cid, u64)>) -> Result<(Cid, u64)> {
+        let size: u64 = links.iter().map(|(_, size)| size).sum();
+        let mut node = BTreeMap::new();
+        node.insert("height".to_string(), Ipld::Integer(height as i128));
+        node.insert("size".to_string(), Ipld::Integer(size as i128));
+        node.insert(
+            "links".to_string(),
+            Ipld::List(links.into_iter().map(|(cid, _)| Ipld::Link(cid)).collect()),
+        );
+        let data = DagCborCodec.encode(&Ipld::Map(node))?;
+        let cid = Cid::new_v1(DAG_CBOR, self.hasher.digest(&data));
+        self.insert(Block::new_unchecked(cid, data))?;
+        Ok((cid, size))
+    }
+
+    fn insert(&mut self, block: Block<P>) -> Result<()> {
+        let Self { storage, tmp, .. } = self;
+        storage.rw("insert_stream", |batch| {
+            let cid = *block.cid();
+            batch.insert(block)?;
+            batch.temp_pin(tmp, std::iter::once(cid))
+        })
+    }
+}
+
+fn max_links<P: StoreParams>() -> usize {
+    (P::MAX_BLOCK_SIZE / MAX_LINK_SIZE).clamp(2, MAX_LINKS)
+}
+
+/// Reads the content written by a `BlockWriter`, yielding the chunks in
+/// order. Blocks are only read when the chunks below them are reached.
+pub struct BlockReader<P: StoreParams> {
+    storage: StorageService<P>,
+    size: u64,
+    /// Blocks left to read in reverse order, `true` for chunks.
+    stack: Vec<(Cid, bool)>,
+}
+
+impl<P: StoreParams> BlockReader<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    pub(crate) fn new(storage: StorageService<P>, root: &Cid) -> Result<Self> {
+        let (size, stack) = read_index(&storage, root)?;
+        Ok(Self {
+            storage,
+            size,
+            stack,
+        })
+    }
+
+    /// Returns the total number of bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some((cid, chunk)) = self.stack.pop() {
+            if chunk {
+                let data = self.storage.get(&cid)?.ok_or(BlockNotFound(cid))?;
+                return Ok(Some(data));
+            }
+            let (_, links) = read_index(&self.storage, &cid)?;
+            self.stack.extend(links);
+        }
+        Ok(None)
+    }
+}
+
+impl<P: StoreParams> Iterator for BlockReader<P>
+where
+    Ipld: References<P::Codecs>,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+/// Returns the size of an index node and its links in reverse order.
+fn read_index<P: StoreParams>(
+    storage: &StorageService<P>,
+    cid: &Cid,
+) -> Result<(u64, Vec<(Cid, bool)>)>
+where
+    Ipld: References<P::Codecs>,
+{
+    let data = storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+    let invalid = || anyhow!("{} is not an index node", cid);
+    let node = match DagCborCodec.decode::<Ipld>(&data) {
+        Ok(Ipld::Map(node)) => node,
+        _ => return Err(invalid()),
+    };
+    let (height, size, links) = match (node.get("height"), node.get("size"), node.get("links")) {
+        (Some(Ipld::Integer(height)), Some(Ipld::Integer(size)), Some(Ipld::List(links))) => {
+            (*height, *size as u64, links)
+        }
+        _ => return Err(invalid()),
+    };
+    let links = links
+        .iter()
+        .rev()
+        .map(|link| match link {
+            Ipld::Link(cid) => Ok((*cid, height == 0)),
+            _ => Err(invalid()),
+        })
+        .collect::<Result<_>>()?;
+    Ok((size, links))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::StorageConfig, executor::Executor};
+    use libipld::{multihash::Code, IpldCodec};
+    use std::time::Duration;
+
+    const RAW: u64 = 0x55;
+
+    /// Small blocks, so the trees get deep quickly.
+    #[derive(Clone, Debug, Default)]
+    struct SmallParams;
+
+    impl StoreParams for SmallParams {
+        const MAX_BLOCK_SIZE: usize = 1024;
+        type Codecs = IpldCodec;
+        type Hashes = Code;
+    }
+
+    const MAX: usize = SmallParams::MAX_BLOCK_SIZE;
+
+    fn create_store() -> StorageService<SmallParams> {
+        let config = StorageConfig::new(None, None, 0, Duration::from_secs(100));
+        StorageService::open(config, Executor::new()).unwrap()
+    }
+
+    fn writer(storage: &StorageService<SmallParams>) -> BlockWriter<SmallParams> {
+        BlockWriter::new(storage.clone(), RAW, Code::Blake3_256).unwrap()
+    }
+
+    fn write(storage: &StorageService<SmallParams>, data: &[u8]) -> Cid {
+        let mut writer = writer(storage);
+        // odd sized writes cross the chunk boundaries
+        for part in data.chunks(300) {
+            writer.write(part).unwrap();
+        }
+        let root = writer.finish().unwrap();
+        storage.alias(b"root", Some(&root)).unwrap();
+        root
+    }
+
+    fn read(storage: &StorageService<SmallParams>, root: &Cid) -> Vec<u8> {
+        let reader = BlockReader::new(storage.clone(), root).unwrap();
+        let size = reader.size();
+        let data = reader.collect::<Result<Vec<_>>>().unwrap().concat();
+        assert_eq!(data.len() as u64, size);
+        data
+    }
+
+    #[async_std::test]
+    async fn test_roundtrip() {
+        let storage = create_store();
+        let links = max_links::<SmallParams>();
+        for len in [
+            0,
+            1,
+            MAX - 1,
+            MAX + 1,
+            links * MAX,
+            links * MAX + 1,
+            links * links * MAX + 3 * MAX + 17,
+        ] {
+            let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let root = write(&storage, &data);
+            storage.evict().await.unwrap();
+            assert_eq!(read(&storage, &root), data, "len {}", len);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_single_chunk() {
+        let storage = create_store();
+        let root = write(&storage, &[1; MAX]);
+        // the chunk and the root index
+        assert_eq!(storage.iter().unwrap().count(), 2);
+        assert_eq!(read(&storage, &root), vec![1; MAX]);
+    }
+
+    #[async_std::test]
+    async fn test_empty() {
+        let storage = create_store();
+        let root = write(&storage, &[]);
+        assert_eq!(storage.iter().unwrap().collect::<Vec<_>>(), vec![root]);
+        let reader = BlockReader::new(storage.clone(), &root).unwrap();
+        assert_eq!(reader.size(), 0);
+        assert_eq!(reader.count(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_lazy() {
+        let storage = create_store();
+        let data = (0..3 * MAX).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let root = write(&storage, &data);
+        let mut reader = BlockReader::new(storage.clone(), &root).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), &data[..MAX]);
+        let last = reader.stack[0].0;
+        storage.alias(b"root", None).unwrap();
+        storage.alias(b"last", Some(&last)).unwrap();
+        storage.evict().await.unwrap();
+        // the second chunk is only read now
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[async_std::test]
+    async fn test_abort() {
+        let storage = create_store();
+        let mut writer = writer(&storage);
+        writer.write(&[1; 2 * MAX + 1]).unwrap();
+        storage.evict().await.unwrap();
+        // the complete chunks are pinned while writing
+        assert_eq!(storage.iter().unwrap().count(), 1);
+        drop(writer);
+        storage.evict().await.unwrap();
+        assert_eq!(storage.iter().unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,1079 @@
+//! Import of files and directories as UnixFS dags producing the same cids as
+//! `ipfs add`, and listing of UnixFS directories.
+use crate::{
+    backend::TempPin,
+    db::StorageService,
+    protobuf::{read_field, write_bytes_field, write_varint_field, Field},
+};
+use anyhow::anyhow;
+use futures::io::{AsyncRead, AsyncReadExt};
+use libipld::{
+    codec::References,
+    error::BlockNotFound,
+    multihash::{Code, MultihashDigest},
+    store::StoreParams,
+    Block, Cid, Ipld, Result,
+};
+use std::{collections::BTreeMap, convert::TryInto};
+use thiserror::Error;
+
+/// Codec of dag-pb blocks.
+const DAG_PB: u64 = 0x70;
+/// Codec of raw blocks.
+const RAW: u64 = 0x55;
+/// Maximum number of links of a file node, the go-ipfs default.
+const MAX_LINKS: usize = 174;
+/// Number of blocks inserted in a single transaction when adding.
+const ADD_BATCH_SIZE: usize = 256;
+/// Fanout of HAMT shards, the go-ipfs default.
+const HAMT_FANOUT: u64 = 256;
+/// Multicodec of the murmur3 x64 64 bit hash used by HAMT shards.
+const HAMT_HASH_TYPE: u64 = 0x22;
+
+/// UnixFS node types.
+const UNIXFS_RAW: u64 = 0;
+const UNIXFS_DIRECTORY: u64 = 1;
+const UNIXFS_FILE: u64 = 2;
+const UNIXFS_SYMLINK: u64 = 4;
+const UNIXFS_HAMT_SHARD: u64 = 5;
+
+/// Options of `Ipfs::add_file` and `Ipfs::add_dir`. The defaults produce the
+/// same cids as `ipfs add` with its default settings.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AddOptions {
+    /// Size of the chunks the file is split into, 256 KiB by default.
+    pub chunk_size: usize,
+    /// Stores the chunks as raw blocks instead of UnixFS nodes, like
+    /// `ipfs add --raw-leaves`.
+    pub raw_leaves: bool,
+    /// Uses version 1 cids for the dag-pb nodes, like
+    /// `ipfs add --cid-version 1`. Raw leaves always use version 1 cids.
+    /// Note that `ipfs add --cid-version 1` also enables raw leaves.
+    pub cid_v1: bool,
+    /// Directories with more entries are stored as HAMT shards, 1000 by
+    /// default. go-ipfs shards based on the estimated size of the directory
+    /// node instead, so the cids of large directories may differ.
+    pub shard_threshold: usize,
+}
+
+impl Default for AddOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 256 * 1024,
+            raw_leaves: false,
+            cid_v1: false,
+            shard_threshold: 1000,
+        }
+    }
+}
+
+/// An entry of a directory added with `Ipfs::add_dir`. Paths are relative to
+/// the directory and separated by `/`, missing parent directories are
+/// created.
+pub enum AddEntry<R> {
+    /// A file with the contents of the reader.
+    File(String, R),
+    /// A directory, which stays empty unless other entries are added to it.
+    Dir(String),
+}
+
+/// Type of a directory entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// The block of the entry is not in the store or not a UnixFS node.
+    Unknown,
+}
+
+/// An entry of a UnixFS directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub cid: Cid,
+    /// The file size for files, the size of the dag otherwise.
+    pub size: u64,
+    pub kind: EntryKind,
+}
+
+#[derive(Debug, Error)]
+#[error("Chunk size {0} is zero or exceeds the maximum block size.")]
+pub struct InvalidChunkSize(pub usize);
+
+#[derive(Debug, Error)]
+#[error("Invalid path {0:?}.")]
+pub struct InvalidPath(pub String);
+
+#[derive(Debug, Error)]
+#[error("Duplicate directory entry {0:?}.")]
+pub struct DuplicateEntry(pub String);
+
+#[derive(Debug, Error)]
+#[error("Block {0} is not a UnixFS directory.")]
+pub struct NotADirectory(pub Cid);
+
+#[derive(Debug, Error)]
+#[error("Invalid UnixFS node: {0}.")]
+pub struct InvalidUnixFs(&'static str);
+
+#[derive(Debug, Error)]
+#[error("Invalid range {0}..{1}.")]
+pub struct InvalidRange(pub u64, pub u64);
+
+/// A link to a child of a file node.
+#[derive(Clone, Copy, Debug)]
+struct FileLink {
+    cid: Cid,
+    /// Size of the dag of the child in bytes.
+    tsize: u64,
+    /// Number of file bytes in the dag of the child.
+    filesize: u64,
+}
+
+/// A link of a dag-pb node.
+#[derive(Clone, Debug)]
+struct PbLink {
+    cid: Cid,
+    name: String,
+    tsize: u64,
+}
+
+/// A directory being added.
+enum DirChild {
+    File(FileLink),
+    Dir(BTreeMap<String, DirChild>),
+}
+
+/// Chunks `reader` and adds it as a file with a balanced layout. Returns the
+/// root of the file.
+pub(crate) async fn add_file<P, R>(
+    storage: &StorageService<P>,
+    reader: R,
+    opts: AddOptions,
+) -> Result<Cid>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+    R: AsyncRead + Unpin,
+{
+    let mut builder = Builder::new(storage, opts)?;
+    let link = builder.add_file(reader).await?;
+    builder.insert_blocks()?;
+    Ok(link.cid)
+}
+
+/// Adds the files of `entries` and the directories containing them. Returns
+/// the root directory.
+pub(crate) async fn add_dir<P, R, I>(
+    storage: &StorageService<P>,
+    entries: I,
+    opts: AddOptions,
+) -> Result<Cid>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+    R: AsyncRead + Unpin,
+    I: IntoIterator<Item = AddEntry<R>>,
+{
+    let mut builder = Builder::new(storage, opts)?;
+    let mut root = BTreeMap::new();
+    for entry in entries {
+        match entry {
+            AddEntry::File(path, reader) => {
+                let components = split_path(&path)?;
+                let (name, parents) = match components.split_last() {
+                    Some((name, parents)) => (name.to_string(), parents),
+                    None => return Err(InvalidPath(path.clone()).into()),
+                };
+                let dir = parent_dir(&mut root, parents, &path)?;
+                if dir.contains_key(&name) {
+                    return Err(DuplicateEntry(path.clone()).into());
+                }
+                let link = builder.add_file(reader).await?;
+                dir.insert(name, DirChild::File(link));
+            }
+            AddEntry::Dir(path) => {
+                let components = split_path(&path)?;
+                parent_dir(&mut root, &components, &path)?;
+            }
+        }
+    }
+    let (cid, _) = builder.add_dir(root)?;
+    builder.insert_blocks()?;
+    Ok(cid)
+}
+
+/// Splits a relative path in to its components.
+fn split_path(path: &str) -> Result<Vec<&str>> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Ok(vec![]);
+    }
+    let components = path.split('/').collect::<Vec<_>>();
+    if components
+        .iter()
+        .any(|c| c.is_empty() || *c == "." || *c == "..")
+    {
+        return Err(InvalidPath(path.to_string()).into());
+    }
+    Ok(components)
+}
+
+/// Returns the directory at `components`, creating missing directories.
+fn parent_dir<'a>(
+    mut dir: &'a mut BTreeMap<String, DirChild>,
+    components: &[&str],
+    path: &str,
+) -> Result<&'a mut BTreeMap<String, DirChild>> {
+    for component in components {
+        let child = dir
+            .entry(component.to_string())
+            .or_insert_with(|| DirChild::Dir(BTreeMap::new()));
+        dir = match child {
+            DirChild::Dir(dir) => dir,
+            DirChild::File(_) => return Err(DuplicateEntry(path.to_string()).into()),
+        };
+    }
+    Ok(dir)
+}
+
+/// Builds UnixFS dags, inserting their blocks in batches. The blocks are
+/// temp pinned until the builder is dropped.
+struct Builder<'a, P: StoreParams> {
+    storage: &'a StorageService<P>,
+    tmp: TempPin,
+    opts: AddOptions,
+    blocks: Vec<Block<P>>,
+}
+
+impl<'a, P> Builder<'a, P>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    fn new(storage: &'a StorageService<P>, opts: AddOptions) -> Result<Self> {
+        // leaves carry a few bytes of UnixFS and dag-pb framing
+        if opts.chunk_size == 0 || opts.chunk_size + 64 > P::MAX_BLOCK_SIZE {
+            return Err(InvalidChunkSize(opts.chunk_size).into());
+        }
+        Ok(Self {
+            storage,
+            tmp: storage.create_temp_pin()?,
+            opts,
+            blocks: Vec::with_capacity(ADD_BATCH_SIZE),
+        })
+    }
+
+    /// Builds the balanced layout of go-ipfs bottom up. Each level holds the
+    /// links of a node that isn't full yet. A file consisting of a single
+    /// leaf has that leaf as root.
+    async fn add_file<R: AsyncRead + Unpin>(&mut self, mut reader: R) -> Result<FileLink> {
+        let mut levels = vec![];
+        let mut first = true;
+        loop {
+            let mut chunk = Vec::with_capacity(self.opts.chunk_size);
+            (&mut reader)
+                .take(self.opts.chunk_size as u64)
+                .read_to_end(&mut chunk)
+                .await?;
+            // an empty file is stored as a single empty leaf
+            if chunk.is_empty() && !first {
+                break;
+            }
+            first = false;
+            let full = chunk.len() == self.opts.chunk_size;
+            let leaf = self.push_leaf(chunk)?;
+            self.push_link(&mut levels, 0, leaf)?;
+            if !full {
+                break;
+            }
+        }
+        let mut level = 0;
+        loop {
+            let links = std::mem::take(&mut levels[level]);
+            if level + 1 == levels.len() && links.len() == 1 {
+                return Ok(links[0]);
+            }
+            if !links.is_empty() {
+                let stem = self.push_stem(&links)?;
+                self.push_link(&mut levels, level + 1, stem)?;
+            }
+            level += 1;
+        }
+    }
+
+    fn push_leaf(&mut self, chunk: Vec<u8>) -> Result<FileLink> {
+        let filesize = chunk.len() as u64;
+        if self.opts.raw_leaves {
+            let cid = Cid::new_v1(RAW, Code::Sha2_256.digest(&chunk));
+            self.push_block(Block::new_unchecked(cid, chunk))?;
+            Ok(FileLink {
+                cid,
+                tsize: filesize,
+                filesize,
+            })
+        } else {
+            let data = pb_node(&[], &unixfs_file(&chunk, filesize, &[]));
+            let tsize = data.len() as u64;
+            Ok(FileLink {
+                cid: self.push_node(data)?,
+                tsize,
+                filesize,
+            })
+        }
+    }
+
+    fn push_link(
+        &mut self,
+        levels: &mut Vec<Vec<FileLink>>,
+        level: usize,
+        link: FileLink,
+    ) -> Result<()> {
+        if levels.len() == level {
+            levels.push(vec![]);
+        }
+        levels[level].push(link);
+        if levels[level].len() == MAX_LINKS {
+            let links = std::mem::take(&mut levels[level]);
+            let stem = self.push_stem(&links)?;
+            self.push_link(levels, level + 1, stem)?;
+        }
+        Ok(())
+    }
+
+    fn push_stem(&mut self, links: &[FileLink]) -> Result<FileLink> {
+        let blocksizes = links.iter().map(|link| link.filesize).collect::<Vec<_>>();
+        let filesize = blocksizes.iter().sum();
+        let pb_links = links
+            .iter()
+            .map(|link| PbLink {
+                cid: link.cid,
+                name: String::new(),
+                tsize: link.tsize,
+            })
+            .collect::<Vec<_>>();
+        let (cid, tsize) = self.push_dag_pb(&pb_links, &unixfs_file(&[], filesize, &blocksizes))?;
+        Ok(FileLink {
+            cid,
+            tsize,
+            filesize,
+        })
+    }
+
+    /// Adds a directory and returns its cid and the size of its dag.
+    fn add_dir(&mut self, entries: BTreeMap<String, DirChild>) -> Result<(Cid, u64)> {
+        let mut links = Vec::with_capacity(entries.len());
+        for (name, child) in entries {
+            let (cid, tsize) = match child {
+                DirChild::File(link) => (link.cid, link.tsize),
+                DirChild::Dir(entries) => self.add_dir(entries)?,
+            };
+            links.push(PbLink { cid, name, tsize });
+        }
+        if links.len() > self.opts.shard_threshold {
+            let links = links
+                .into_iter()
+                .map(|link| (murmur3_64(link.name.as_bytes()), link))
+                .collect();
+            self.push_shard(links, 0)
+        } else {
+            // the entries are sorted by name, which dag-pb requires
+            self.push_dag_pb(&links, &unixfs_dir())
+        }
+    }
+
+    /// Adds a HAMT shard. The entries are placed in the slot given by the
+    /// byte of their name hash at `depth`, slots with several entries become
+    /// shards of their own.
+    fn push_shard(&mut self, entries: Vec<([u8; 8], PbLink)>, depth: usize) -> Result<(Cid, u64)> {
+        if depth == 8 {
+            return Err(anyhow!("hash collision in HAMT shard"));
+        }
+        let mut slots = BTreeMap::<u8, Vec<([u8; 8], PbLink)>>::new();
+        for (hash, link) in entries {
+            slots.entry(hash[depth]).or_default().push((hash, link));
+        }
+        let mut bitfield = [0u8; HAMT_FANOUT as usize / 8];
+        let mut links = Vec::with_capacity(slots.len());
+        for (slot, mut entries) in slots {
+            bitfield[bitfield.len() - 1 - slot as usize / 8] |= 1 << (slot % 8);
+            if entries.len() == 1 {
+                let (_, link) = entries.pop().unwrap();
+                links.push(PbLink {
+                    name: format!("{:02X}{}", slot, link.name),
+                    ..link
+                });
+            } else {
+                let (cid, tsize) = self.push_shard(entries, depth + 1)?;
+                links.push(PbLink {
+                    cid,
+                    name: format!("{:02X}", slot),
+                    tsize,
+                });
+            }
+        }
+        self.push_dag_pb(&links, &unixfs_shard(&bitfield))
+    }
+
+    /// Adds a dag-pb node and returns its cid and the size of its dag.
+    fn push_dag_pb(&mut self, links: &[PbLink], data: &[u8]) -> Result<(Cid, u64)> {
+        let data = pb_node(links, data);
+        let tsize = data.len() as u64 + links.iter().map(|link| link.tsize).sum::<u64>();
+        Ok((self.push_node(data)?, tsize))
+    }
+
+    fn push_node(&mut self, data: Vec<u8>) -> Result<Cid> {
+        let hash = Code::Sha2_256.digest(&data);
+        let cid = if self.opts.cid_v1 {
+            Cid::new_v1(DAG_PB, hash)
+        } else {
+            Cid::new_v0(hash)?
+        };
+        self.push_block(Block::new_unchecked(cid, data))?;
+        Ok(cid)
+    }
+
+    fn push_block(&mut self, block: Block<P>) -> Result<()> {
+        self.blocks.push(block);
+        if self.blocks.len() >= ADD_BATCH_SIZE {
+            self.insert_blocks()?;
+        }
+        Ok(())
+    }
+
+    fn insert_blocks(&mut self) -> Result<()> {
+        let Self {
+            storage,
+            tmp,
+            blocks,
+            ..
+        } = self;
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        storage.rw("add", |batch| {
+            let cids = blocks.iter().map(|block| *block.cid()).collect::<Vec<_>>();
+            for block in blocks.drain(..) {
+                batch.insert(block)?;
+            }
+            batch.temp_pin(tmp, cids)
+        })
+    }
+}
+
+/// Lists the entries of a UnixFS directory sorted by name. The type and size
+/// of entries whose block isn't in the store are unknown.
+pub(crate) fn ls<P>(storage: &StorageService<P>, cid: &Cid) -> Result<Vec<DirEntry>>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    let mut entries = vec![];
+    let (links, unixfs) = match decode_unixfs(storage, cid)? {
+        Some((links, unixfs)) => (links, unixfs),
+        None => return Err(NotADirectory(*cid).into()),
+    };
+    match unixfs.kind {
+        UNIXFS_DIRECTORY => {
+            for link in links {
+                entries.push(dir_entry(storage, link)?);
+            }
+        }
+        UNIXFS_HAMT_SHARD => ls_shard(storage, links, &mut entries)?,
+        _ => return Err(NotADirectory(*cid).into()),
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn ls_shard<P>(
+    storage: &StorageService<P>,
+    links: Vec<PbLink>,
+    entries: &mut Vec<DirEntry>,
+) -> Result<()>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    for mut link in links {
+        if link.name.len() > 2 {
+            link.name = link
+                .name
+                .get(2..)
+                .ok_or(InvalidUnixFs("invalid shard link"))?
+                .into();
+            entries.push(dir_entry(storage, link)?);
+        } else {
+            match decode_unixfs(storage, &link.cid)? {
+                Some((links, unixfs)) if unixfs.kind == UNIXFS_HAMT_SHARD => {
+                    ls_shard(storage, links, entries)?
+                }
+                _ => return Err(InvalidUnixFs("invalid shard link").into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn dir_entry<P>(storage: &StorageService<P>, link: PbLink) -> Result<DirEntry>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    let (kind, size) = if link.cid.codec() == RAW {
+        match storage.get(&link.cid)? {
+            Some(data) => (EntryKind::File, data.len() as u64),
+            None => (EntryKind::Unknown, link.tsize),
+        }
+    } else {
+        match decode_unixfs(storage, &link.cid) {
+            Ok(Some((_, unixfs))) => match unixfs.kind {
+                UNIXFS_RAW | UNIXFS_FILE => (EntryKind::File, unixfs.filesize),
+                UNIXFS_DIRECTORY | UNIXFS_HAMT_SHARD => (EntryKind::Directory, link.tsize),
+                UNIXFS_SYMLINK => (EntryKind::Symlink, link.tsize),
+                _ => (EntryKind::Unknown, link.tsize),
+            },
+            Ok(None) | Err(_) => (EntryKind::Unknown, link.tsize),
+        }
+    };
+    Ok(DirEntry {
+        name: link.name,
+        cid: link.cid,
+        size,
+        kind,
+    })
+}
+
+/// Returns the size of a UnixFS file or `None` if `cid` is not a file.
+pub(crate) fn file_size<P>(storage: &StorageService<P>, cid: &Cid) -> Result<Option<u64>>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    if cid.codec() == RAW {
+        let data = storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+        return Ok(Some(data.len() as u64));
+    }
+    Ok(match decode_unixfs(storage, cid)? {
+        Some((_, unixfs)) if unixfs.kind == UNIXFS_FILE || unixfs.kind == UNIXFS_RAW => {
+            Some(unixfs.filesize.max(unixfs.data.len() as u64))
+        }
+        _ => None,
+    })
+}
+
+/// Reads the bytes in `start..end` of a UnixFS file, the range is clamped to
+/// the size of the file. Only the blocks overlapping the range are read.
+/// Fails if the sizes recorded in a node don't match its children.
+pub(crate) fn read_file<P>(
+    storage: &StorageService<P>,
+    cid: &Cid,
+    start: u64,
+    end: u64,
+) -> Result<Vec<u8>>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    if start > end {
+        return Err(InvalidRange(start, end).into());
+    }
+    // the sizes come from the blocks, so the buffer only grows by what is read
+    let mut buf = Vec::new();
+    read_range(storage, cid, start, end, None, &mut buf)?;
+    Ok(buf)
+}
+
+/// Appends the bytes in `start..end` of the dag at `cid` to `buf`. `size` is
+/// the number of file bytes the parent expects the dag to contain.
+fn read_range<P>(
+    storage: &StorageService<P>,
+    cid: &Cid,
+    start: u64,
+    end: u64,
+    size: Option<u64>,
+    buf: &mut Vec<u8>,
+) -> Result<()>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    let slice = |data: &[u8], buf: &mut Vec<u8>| {
+        let end = end.min(data.len() as u64);
+        if start < end {
+            buf.extend_from_slice(&data[start as usize..end as usize]);
+        }
+    };
+    if cid.codec() == RAW {
+        let data = storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+        if size.map_or(false, |size| size != data.len() as u64) {
+            return Err(InvalidUnixFs("blocksize doesn't match the raw leaf").into());
+        }
+        slice(&data, buf);
+        return Ok(());
+    }
+    let (links, unixfs) = match decode_unixfs(storage, cid)? {
+        Some((links, unixfs)) if unixfs.kind == UNIXFS_FILE || unixfs.kind == UNIXFS_RAW => {
+            (links, unixfs)
+        }
+        _ => return Err(InvalidUnixFs("not a file").into()),
+    };
+    if links.len() != unixfs.blocksizes.len() {
+        return Err(InvalidUnixFs("blocksizes don't match the links").into());
+    }
+    // the data of the node precedes the data of its children
+    let total = unixfs
+        .blocksizes
+        .iter()
+        .try_fold(unixfs.data.len() as u64, |total, size| {
+            total.checked_add(*size)
+        })
+        .ok_or(InvalidUnixFs("blocksizes overflow"))?;
+    // leaves may omit the filesize
+    if unixfs.filesize != total && (unixfs.filesize != 0 || !links.is_empty()) {
+        return Err(InvalidUnixFs("filesize doesn't match the blocksizes").into());
+    }
+    if size.map_or(false, |size| size != total) {
+        return Err(InvalidUnixFs("blocksize doesn't match the child").into());
+    }
+    slice(&unixfs.data, buf);
+    let mut offset = unixfs.data.len() as u64;
+    for (link, size) in links.iter().zip(unixfs.blocksizes) {
+        let next = offset + size;
+        if offset < end && start < next {
+            read_range(
+                storage,
+                &link.cid,
+                start.saturating_sub(offset),
+                end.min(next) - offset,
+                Some(size),
+                buf,
+            )?;
+        }
+        offset = next;
+    }
+    Ok(())
+}
+
+/// Decoded UnixFS `Data` message.
+struct UnixFsData {
+    kind: u64,
+    data: Vec<u8>,
+    filesize: u64,
+    blocksizes: Vec<u64>,
+}
+
+/// Decodes a dag-pb UnixFS node. Returns `None` if the block is not a dag-pb
+/// node, fails if it is missing.
+fn decode_unixfs<P>(
+    storage: &StorageService<P>,
+    cid: &Cid,
+) -> Result<Option<(Vec<PbLink>, UnixFsData)>>
+where
+    P: StoreParams,
+    Ipld: References<P::Codecs>,
+{
+    if cid.codec() != DAG_PB {
+        return Ok(None);
+    }
+    let block = storage.get(cid)?.ok_or(BlockNotFound(*cid))?;
+    let mut links = vec![];
+    let mut data = &[][..];
+    let mut buf = &block[..];
+    while !buf.is_empty() {
+        match read_field(&mut buf)? {
+            (1, Field::Bytes(bytes)) => data = bytes,
+            (2, Field::Bytes(mut bytes)) => {
+                let (mut cid, mut name, mut tsize) = (None, String::new(), 0);
+                while !bytes.is_empty() {
+                    match read_field(&mut bytes)? {
+                        (1, Field::Bytes(bytes)) => cid = Some(Cid::read_bytes(bytes)?),
+                        (2, Field::Bytes(bytes)) => name = String::from_utf8(bytes.to_vec())?,
+                        (3, Field::Varint(n)) => tsize = n,
+                        _ => {}
+                    }
+                }
+                let cid = cid.ok_or(InvalidUnixFs("link without hash"))?;
+                links.push(PbLink { cid, name, tsize });
+            }
+            _ => {}
+        }
+    }
+    let mut unixfs = UnixFsData {
+        kind: u64::MAX,
+        data: vec![],
+        filesize: 0,
+        blocksizes: vec![],
+    };
+    while !data.is_empty() {
+        match read_field(&mut data)? {
+            (1, Field::Varint(kind)) => unixfs.kind = kind,
+            (2, Field::Bytes(bytes)) => unixfs.data = bytes.to_vec(),
+            (3, Field::Varint(filesize)) => unixfs.filesize = filesize,
+            (4, Field::Varint(size)) => unixfs.blocksizes.push(size),
+            _ => {}
+        }
+    }
+    Ok(Some((links, unixfs)))
+}
+
+/// Encodes the UnixFS `Data` message of a file node. `blocksizes` are the
+/// file sizes of the children.
+fn unixfs_file(data: &[u8], filesize: u64, blocksizes: &[u64]) -> Vec<u8> {
+    let mut buf = vec![];
+    write_varint_field(1, UNIXFS_FILE, &mut buf);
+    if !data.is_empty() {
+        write_bytes_field(2, data, &mut buf);
+    }
+    write_varint_field(3, filesize, &mut buf);
+    for size in blocksizes {
+        write_varint_field(4, *size, &mut buf);
+    }
+    buf
+}
+
+/// Encodes the UnixFS `Data` message of a directory.
+fn unixfs_dir() -> Vec<u8> {
+    let mut buf = vec![];
+    write_varint_field(1, UNIXFS_DIRECTORY, &mut buf);
+    buf
+}
+
+/// Encodes the UnixFS `Data` message of a HAMT shard with the bitfield of
+/// its occupied slots.
+fn unixfs_shard(bitfield: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    write_varint_field(1, UNIXFS_HAMT_SHARD, &mut buf);
+    write_bytes_field(2, bitfield, &mut buf);
+    write_varint_field(5, HAMT_HASH_TYPE, &mut buf);
+    write_varint_field(6, HAMT_FANOUT, &mut buf);
+    buf
+}
+
+/// Encodes a dag-pb node. The links are written before the data and always
+/// have a name, like go-ipfs does.
+fn pb_node(links: &[PbLink], data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    for link in links {
+        let mut pb_link = vec![];
+        write_bytes_field(1, &link.cid.to_bytes(), &mut pb_link);
+        write_bytes_field(2, link.name.as_bytes(), &mut pb_link);
+        write_varint_field(3, link.tsize, &mut pb_link);
+        write_bytes_field(2, &pb_link, &mut buf);
+    }
+    write_bytes_field(1, data, &mut buf);
+    buf
+}
+
+/// The first 64 bits of the murmur3 x64 128 bit hash with seed 0 in big
+/// endian byte order, as used by go-ipfs for HAMT shards.
+fn murmur3_64(data: &[u8]) -> [u8; 8] {
+    const C1: u64 = 0x87c3_7b91_1142_53d5;
+    const C2: u64 = 0x4cf5_ad43_2745_937f;
+    fn fmix(mut k: u64) -> u64 {
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        k ^= k >> 33;
+        k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        k ^ (k >> 33)
+    }
+    let mix_k1 = |k1: u64| k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    let mix_k2 = |k2: u64| k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+    let (mut h1, mut h2) = (0u64, 0u64);
+    let mut chunks = data.chunks_exact(16);
+    for chunk in &mut chunks {
+        let k1 = u64::from_le_bytes(chunk[..8].try_into().unwrap());
+        let k2 = u64::from_le_bytes(chunk[8..].try_into().unwrap());
+        h1 ^= mix_k1(k1);
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dc_e729);
+        h2 ^= mix_k2(k2);
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x3849_5ab5);
+    }
+    let tail = chunks.remainder();
+    let (mut k1, mut k2) = (0u64, 0u64);
+    for (i, byte) in tail.iter().enumerate() {
+        if i < 8 {
+            k1 ^= u64::from(*byte) << (8 * i);
+        } else {
+            k2 ^= u64::from(*byte) << (8 * (i - 8));
+        }
+    }
+    if tail.len() > 8 {
+        h2 ^= mix_k2(k2);
+    }
+    if !tail.is_empty() {
+        h1 ^= mix_k1(k1);
+    }
+    h1 ^= data.len() as u64;
+    h2 ^= data.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix(h1);
+    h2 = fmix(h2);
+    h1.wrapping_add(h2).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::StorageConfig, executor::Executor};
+    use libipld::store::DefaultParams;
+    use std::time::Duration;
+
+    fn create_store() -> StorageService<DefaultParams> {
+        let config = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+        StorageService::open(config, Executor::new()).unwrap()
+    }
+
+    async fn add(data: &[u8], opts: AddOptions) -> Result<String> {
+        let store = create_store();
+        let cid = add_file(&store, data, opts).await?;
+        assert!(store.missing_blocks(&cid)?.is_empty());
+        Ok(cid.to_string())
+    }
+
+    #[async_std::test]
+    async fn test_add_file_go_ipfs_cids() -> Result<()> {
+        // `echo "hello world" | ipfs add` with the respective flags
+        let hello = b"hello world\n";
+        assert_eq!(
+            add(hello, AddOptions::default()).await?,
+            "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o"
+        );
+        let opts = AddOptions {
+            cid_v1: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            add(hello, opts).await?,
+            "bafybeicg2rebjoofv4kbyovkw7af3rpiitvnl6i7ckcywaq6xjcxnc2mby"
+        );
+        let opts = AddOptions {
+            raw_leaves: true,
+            cid_v1: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            add(hello, opts).await?,
+            "bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4"
+        );
+        assert_eq!(
+            add(b"", AddOptions::default()).await?,
+            "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_file_balanced_layout() -> Result<()> {
+        let store = create_store();
+        let opts = AddOptions {
+            chunk_size: 1,
+            raw_leaves: true,
+            ..Default::default()
+        };
+        // one more leaf than fits in a node adds a level
+        let data = [1; MAX_LINKS + 1];
+        let root = add_file(&store, &data[..], opts).await?;
+        let children = store.refs(&root)?.unwrap();
+        assert_eq!(children.len(), 2);
+        assert_eq!(store.refs(&children[0])?.unwrap().len(), MAX_LINKS);
+        assert_eq!(store.refs(&children[1])?.unwrap().len(), 1);
+        // all leaves have the same content
+        assert_eq!(store.dag_stat(&root)?.blocks, 4);
+
+        // a full node is the root
+        let root = add_file(&store, &data[..MAX_LINKS], opts).await?;
+        assert_eq!(store.refs(&root)?.unwrap().len(), MAX_LINKS);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_file_invalid_chunk_size() -> Result<()> {
+        let store = create_store();
+        let opts = AddOptions {
+            chunk_size: 0,
+            ..Default::default()
+        };
+        let err = add_file(&store, &b"data"[..], opts).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidChunkSize>().unwrap().0, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_read_file() -> Result<()> {
+        let store = create_store();
+        let opts = AddOptions {
+            chunk_size: 3,
+            ..Default::default()
+        };
+        let data = (0..100).collect::<Vec<u8>>();
+        let root = add_file(&store, &data[..], opts).await?;
+        assert_eq!(file_size(&store, &root)?, Some(100));
+        assert_eq!(read_file(&store, &root, 0, 100)?, data);
+        assert_eq!(read_file(&store, &root, 10, 20)?, &data[10..20]);
+        assert_eq!(read_file(&store, &root, 99, 200)?, &data[99..]);
+        assert_eq!(read_file(&store, &root, 0, u64::MAX)?, data);
+        assert!(read_file(&store, &root, 200, u64::MAX)?.is_empty());
+        let err = read_file(&store, &root, 20, 10).unwrap_err();
+        assert!(err.downcast_ref::<InvalidRange>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_read_file_invalid_sizes() -> Result<()> {
+        let store = create_store();
+        let leaf = Cid::new_v1(RAW, Code::Sha2_256.digest(b"hello"));
+        store.insert(Block::new_unchecked(leaf, b"hello".to_vec()))?;
+        let node = |filesize, blocksizes: &[u64]| -> Result<Cid> {
+            let link = PbLink {
+                cid: leaf,
+                name: String::new(),
+                tsize: 5,
+            };
+            let data = pb_node(&[link], &unixfs_file(&[], filesize, blocksizes));
+            let cid = Cid::new_v1(DAG_PB, Code::Sha2_256.digest(&data));
+            store.insert(Block::new_unchecked(cid, data))?;
+            Ok(cid)
+        };
+        let valid = node(5, &[5])?;
+        assert_eq!(read_file(&store, &valid, 0, u64::MAX)?, b"hello");
+        for (filesize, blocksizes) in [(6, &[5][..]), (3, &[3]), (u64::MAX, &[u64::MAX])] {
+            let cid = node(filesize, blocksizes)?;
+            let err = read_file(&store, &cid, 0, u64::MAX).unwrap_err();
+            assert!(err.downcast_ref::<InvalidUnixFs>().is_some());
+        }
+        Ok(())
+    }
+
+    async fn add_entries(
+        store: &StorageService<DefaultParams>,
+        entries: Vec<AddEntry<&[u8]>>,
+    ) -> Result<Cid> {
+        let cid = add_dir(store, entries, AddOptions::default()).await?;
+        assert!(store.missing_blocks(&cid)?.is_empty());
+        Ok(cid)
+    }
+
+    #[async_std::test]
+    async fn test_add_empty_dir() -> Result<()> {
+        let store = create_store();
+        // `ipfs object new unixfs-dir`
+        let root = add_entries(&store, vec![]).await?;
+        assert_eq!(
+            root.to_string(),
+            "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn"
+        );
+        assert!(ls(&store, &root)?.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_dir_ls() -> Result<()> {
+        let store = create_store();
+        let hello = &b"hello world\n"[..];
+        let entries = vec![
+            AddEntry::File("b/hello.txt".into(), hello),
+            AddEntry::File("a.txt".into(), &b"a"[..]),
+            AddEntry::Dir("b/empty".into()),
+        ];
+        let root = add_entries(&store, entries).await?;
+        let entries = ls(&store, &root)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "a.txt");
+        assert_eq!(entries[0].kind, EntryKind::File);
+        assert_eq!(entries[0].size, 1);
+        assert_eq!(entries[1].name, "b");
+        assert_eq!(entries[1].kind, EntryKind::Directory);
+
+        let entries = ls(&store, &entries[1].cid)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "empty");
+        assert_eq!(
+            entries[0].cid.to_string(),
+            "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn"
+        );
+        assert_eq!(entries[1].name, "hello.txt");
+        assert_eq!(
+            entries[1].cid.to_string(),
+            "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o"
+        );
+        assert_eq!(entries[1].size, hello.len() as u64);
+
+        // files are not directories
+        let err = ls(&store, &entries[1].cid).unwrap_err();
+        assert!(err.downcast_ref::<NotADirectory>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_dir_duplicate() -> Result<()> {
+        let store = create_store();
+        let entries = vec![
+            AddEntry::File("a".into(), &b"a"[..]),
+            AddEntry::File("/a".into(), &b"b"[..]),
+        ];
+        let err = add_dir(&store, entries, AddOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<DuplicateEntry>().unwrap().0, "/a");
+
+        // a file can't be a directory
+        let entries = vec![
+            AddEntry::File("a".into(), &b"a"[..]),
+            AddEntry::Dir("a/b".into()),
+        ];
+        let err = add_dir(&store, entries, AddOptions::default())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<DuplicateEntry>().is_some());
+
+        let entries = vec![AddEntry::File("a/../b".into(), &b"a"[..])];
+        let err = add_dir(&store, entries, AddOptions::default())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<InvalidPath>().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_dir_sharded() -> Result<()> {
+        let store = create_store();
+        let names = (0..1001).map(|i| format!("file{}", i)).collect::<Vec<_>>();
+        let entries = names
+            .iter()
+            .map(|name| AddEntry::File(name.clone(), name.as_bytes()))
+            .collect();
+        let root = add_entries(&store, entries).await?;
+        let block = store.get(&root)?.unwrap();
+        let (_, unixfs) = decode_unixfs(&store, &root)?.unwrap();
+        assert_eq!(unixfs.kind, UNIXFS_HAMT_SHARD);
+        assert!(block.len() < DefaultParams::MAX_BLOCK_SIZE);
+
+        let entries = ls(&store, &root)?;
+        let mut names = names;
+        names.sort();
+        assert_eq!(
+            entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>(),
+            names
+        );
+        assert!(entries.iter().all(|e| e.kind == EntryKind::File));
+        assert_eq!(entries[0].size, entries[0].name.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_murmur3_64() {
+        let hash = murmur3_64(b"The quick brown fox jumps over the lazy dog");
+        assert_eq!(u64::from_be_bytes(hash), 0xe34b_bc7b_bc07_1b6c);
+    }
+}
@@ -1,32 +1,126 @@
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use std::sync::Arc;
+//! Observable values.
+//!
+//! A [`Variable`] holds a value that is the single source of truth for some
+//! state, like the nat status of the node. [`Reader`]s give cheap read
+//! access to the latest value, [`Observer`]s are streams yielding it every
+//! time it changes.
+//!
+//! Observers have last-value semantics: the first poll yields the current
+//! value, later polls yield the latest value once it was written again.
+//! Writes in between two polls are coalesced. Once all `Variable` handles
+//! are dropped, observers end after yielding the last value they missed.
+//!
+//! Writes don't allocate, waking observers only drains the list of
+//! observers that are currently waiting.
+//!
+//! ```
+//! # futures::executor::block_on(async {
+//! use futures::StreamExt;
+//! use ipfs_embed::variable::Variable;
+//!
+//! let status = Variable::new("starting");
+//! let mut observer = status.observe();
+//! assert_eq!(observer.next().await, Some("starting"));
+//! *status.write() = "running";
+//! assert_eq!(observer.next().await, Some("running"));
+//! drop(status);
+//! assert_eq!(observer.next().await, None);
+//! # });
+//! ```
+use futures::Stream;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+};
 
 struct Inner<T> {
     value: RwLock<T>,
+    /// Incremented by every write while the value is still locked.
+    version: AtomicU64,
+    /// Number of `Variable` handles.
+    writers: AtomicUsize,
+    /// Observers waiting for the next write.
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl<T> Inner<T> {
+    fn wake(&self) {
+        for waker in self.wakers.lock().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Resolves with the new version once it differs from `seen`, or with
+    /// `None` once all writers are dropped.
+    fn poll_changed(&self, seen: u64, cx: &mut Context) -> Poll<Option<u64>> {
+        let mut wakers = self.wakers.lock();
+        let version = self.version.load(Ordering::Relaxed);
+        if version != seen {
+            return Poll::Ready(Some(version));
+        }
+        if self.writers.load(Ordering::Relaxed) == 0 {
+            return Poll::Ready(None);
+        }
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
 }
 
 /// Write-side of a variable with read capability to use as single source of truth
 ///
 /// Usage of [`read`] and [`write`] should be non-blocking so that readers can always
 /// quickly access the latest value.
-#[derive(Clone)]
-pub struct Writer<T>(Arc<Inner<T>>);
+///
+/// [`read`]: Variable::read
+/// [`write`]: Variable::write
+pub struct Variable<T>(Arc<Inner<T>>);
 
-impl<T> std::fmt::Debug for Writer<T> {
+impl<T> std::fmt::Debug for Variable<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("Writer").finish()
+        f.debug_tuple("Variable").finish()
     }
 }
 
-impl<T> Writer<T> {
+impl<T> Clone for Variable<T> {
+    fn clone(&self) -> Self {
+        self.0.writers.fetch_add(1, Ordering::Relaxed);
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for Variable<T> {
+    fn drop(&mut self) {
+        if self.0.writers.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.0.wake();
+        }
+    }
+}
+
+impl<T> Variable<T> {
     pub fn new(value: T) -> Self {
         Self(Arc::new(Inner {
             value: RwLock::new(value),
+            version: AtomicU64::new(0),
+            writers: AtomicUsize::new(1),
+            wakers: Mutex::new(vec![]),
         }))
     }
 
-    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
-        self.0.value.write()
+    /// Locks the value for writing. Observers are woken when the guard is
+    /// dropped, even if the value wasn't changed.
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        WriteGuard {
+            guard: Some(self.0.value.write()),
+            inner: &self.0,
+        }
     }
 
     pub fn read(&self) -> RwLockReadGuard<'_, T> {
@@ -36,6 +130,45 @@ impl<T> Writer<T> {
     pub fn reader(&self) -> Reader<T> {
         Reader(self.0.clone())
     }
+
+    /// Returns a stream of the value, see the [module docs](self).
+    pub fn observe(&self) -> Observer<T> {
+        Observer::new(self.0.clone())
+    }
+
+    /// Returns the number of readers, observers and other variable handles
+    /// sharing the value.
+    pub fn reader_count(&self) -> usize {
+        Arc::strong_count(&self.0) - 1
+    }
+}
+
+/// Write access to a variable, waking the waiting observers when dropped.
+pub struct WriteGuard<'a, T> {
+    guard: Option<RwLockWriteGuard<'a, T>>,
+    inner: &'a Inner<T>,
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for WriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.inner.version.fetch_add(1, Ordering::Relaxed);
+        drop(self.guard.take());
+        self.inner.wake();
+    }
 }
 
 /// Read-side of a variable, intentionally limited to avoid blocking the writer
@@ -47,6 +180,11 @@ impl<T> Reader<T> {
         let value = self.0.value.read();
         f(&*value)
     }
+
+    /// Returns a stream of the value, see the [module docs](self).
+    pub fn observe(&self) -> Observer<T> {
+        Observer::new(self.0.clone())
+    }
 }
 
 impl<T: Copy> Reader<T> {
@@ -60,3 +198,138 @@ impl<T: Clone> Reader<T> {
         self.0.value.read().clone()
     }
 }
+
+/// A stream yielding the value of a variable when it changes, see the
+/// [module docs](self).
+pub struct Observer<T> {
+    inner: Arc<Inner<T>>,
+    /// Version of the last yielded value, `None` before the first.
+    seen: Option<u64>,
+}
+
+impl<T> Observer<T> {
+    fn new(inner: Arc<Inner<T>>) -> Self {
+        Self { inner, seen: None }
+    }
+}
+
+impl<T> std::fmt::Debug for Observer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Observer")
+            .field("seen", &self.seen)
+            .finish()
+    }
+}
+
+impl<T: Clone> Stream for Observer<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(seen) = self.seen {
+            match self.inner.poll_changed(seen, cx) {
+                Poll::Ready(Some(_)) => {}
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let (value, version) = {
+            let value = self.inner.value.read();
+            (value.clone(), self.inner.version.load(Ordering::Relaxed))
+        };
+        self.seen = Some(version);
+        Poll::Ready(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{FutureExt, StreamExt};
+
+    #[test]
+    fn test_observe() {
+        let var = Variable::new(0);
+        let mut observer = var.observe();
+        assert_eq!(observer.next().now_or_never(), Some(Some(0)));
+        assert_eq!(observer.next().now_or_never(), None);
+        *var.write() = 1;
+        *var.write() = 2;
+        // coalesced to the latest value
+        assert_eq!(observer.next().now_or_never(), Some(Some(2)));
+        assert_eq!(observer.next().now_or_never(), None);
+        // a late observer starts with the current value
+        let mut late = var.reader().observe();
+        assert_eq!(late.next().now_or_never(), Some(Some(2)));
+    }
+
+    #[test]
+    fn test_writer_drop() {
+        let var = Variable::new(0);
+        let clone = var.clone();
+        let mut observer = var.observe();
+        assert_eq!(observer.next().now_or_never(), Some(Some(0)));
+        drop(var);
+        assert_eq!(observer.next().now_or_never(), None);
+        *clone.write() = 1;
+        drop(clone);
+        // the missed value is yielded before the end
+        assert_eq!(observer.next().now_or_never(), Some(Some(1)));
+        assert_eq!(observer.next().now_or_never(), Some(None));
+    }
+
+    #[test]
+    fn test_writer_drop_wakes() {
+        let var = Variable::new(0);
+        let observer = var.observe();
+        let handle =
+            std::thread::spawn(move || futures::executor::block_on(observer.collect::<Vec<_>>()));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(var);
+        assert_eq!(handle.join().unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_observer_drop() {
+        let var = Variable::new(0);
+        let mut observer = var.observe();
+        assert_eq!(observer.next().now_or_never(), Some(Some(0)));
+        assert_eq!(observer.next().now_or_never(), None);
+        assert_eq!(var.0.wakers.lock().len(), 1);
+        drop(observer);
+        assert_eq!(var.reader_count(), 0);
+        // the waker of the dropped observer is released by the next write
+        *var.write() = 1;
+        assert!(var.0.wakers.lock().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_updates() {
+        const WRITERS: u64 = 4;
+        const WRITES: u64 = 1000;
+        let var = Variable::new((0..WRITERS).map(|_| 0).collect::<Vec<u64>>());
+        let observer = var.observe();
+        let reader =
+            std::thread::spawn(move || futures::executor::block_on(observer.collect::<Vec<_>>()));
+        let writers = (0..WRITERS as usize)
+            .map(|i| {
+                let var = var.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..WRITES {
+                        var.write()[i] += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(var);
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let values = reader.join().unwrap();
+        // every observed value is newer than the one before
+        for pair in values.windows(2) {
+            assert!(pair[0].iter().zip(&pair[1]).all(|(a, b)| a <= b));
+            assert_ne!(pair[0], pair[1]);
+        }
+        assert_eq!(values.last().unwrap(), &vec![WRITES; WRITERS as usize]);
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use futures::StreamExt;
+use ipfs_embed::{
+    identity::ed25519::Keypair, Block, Config, DefaultParams, Event, EventFilter, Ipfs,
+    NetworkConfig, StorageConfig,
+};
+use libipld::{multihash::Code, raw::RawCodec};
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer, Registry};
+
+#[derive(Clone, Debug)]
+struct RecordedSpan {
+    id: u64,
+    name: &'static str,
+    parent: Option<u64>,
+    /// The `id` field of query spans.
+    query: Option<String>,
+}
+
+/// Records the spans created on any thread, the swarm runs on its own task.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<RecordedSpan>>>);
+
+impl Recorder {
+    fn spans(&self) -> Vec<RecordedSpan> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+struct QueryField(Option<String>);
+
+impl Visit for QueryField {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "id" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut query = QueryField(None);
+        attrs.record(&mut query);
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .map(|parent| parent.id().into_u64());
+        self.0.lock().unwrap().push(RecordedSpan {
+            id: id.into_u64(),
+            name: attrs.metadata().name(),
+            parent,
+            query: query.0,
+        });
+    }
+}
+
+async fn create_node() -> Result<Ipfs<DefaultParams>> {
+    let storage = StorageConfig::new(None, None, 10, Duration::from_secs(100));
+    let mut network = NetworkConfig::new(Keypair::generate());
+    network.mdns = None;
+    let mut ipfs = Ipfs::<DefaultParams>::new(Config { storage, network }).await?;
+    ipfs.listen_on("/ip4/127.0.0.1/tcp/0".parse()?).next().await;
+    Ok(ipfs)
+}
+
+/// Returns the query started by the call with the span `name` and the span
+/// of the query.
+fn query_of(spans: &[RecordedSpan], name: &str) -> (RecordedSpan, RecordedSpan) {
+    let call = spans
+        .iter()
+        .rev()
+        .find(|span| span.name == name)
+        .unwrap_or_else(|| panic!("no {} span", name))
+        .clone();
+    let query = spans
+        .iter()
+        .find(|span| span.name == "query" && span.parent == Some(call.id))
+        .unwrap_or_else(|| panic!("no query span in {}", name))
+        .clone();
+    (call, query)
+}
+
+#[async_std::test]
+async fn query_spans() -> Result<()> {
+    let recorder = Recorder::default();
+    tracing::subscriber::set_global_default(Registry::default().with(recorder.clone()))?;
+
+    let a = create_node().await?;
+    let mut b = create_node().await?;
+    b.add_address(a.local_peer_id(), a.listeners()[0].clone());
+    b.connect(a.local_peer_id()).await?;
+    let mut events = b
+        .swarm_events_filtered(EventFilter::none().bitswap())
+        .await?;
+
+    let block = Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &b"query_spans"[..])?;
+    a.insert(block.clone())?;
+    b.fetch(block.cid(), vec![a.local_peer_id()]).await?;
+
+    let spans = recorder.spans();
+    let (get, query) = query_of(&spans, "get");
+    let fetch = spans.iter().find(|span| span.name == "fetch").unwrap();
+    assert_eq!(get.parent, Some(fetch.id));
+
+    // the events carry the id recorded in the span
+    let started = match events.next().await {
+        Some(Event::QueryStarted(id)) => id,
+        event => panic!("unexpected event {:?}", event),
+    };
+    assert_eq!(query.query, Some(format!("{:?}", started)));
+    loop {
+        match events.next().await {
+            Some(Event::QueryFinished(id)) => {
+                assert_eq!(id, started);
+                break;
+            }
+            Some(Event::BlockReceived(cid, _)) => assert_eq!(cid, *block.cid()),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    let block =
+        Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &b"query_spans_sync"[..])?;
+    a.insert(block.clone())?;
+    b.sync(block.cid(), vec![a.local_peer_id()]).await?.await?;
+    let (_, query) = query_of(&recorder.spans(), "sync");
+    assert!(query.query.is_some());
+    Ok(())
+}